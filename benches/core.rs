@@ -0,0 +1,139 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Benchmarks of the core RPC paths
+//
+// Run with cargo bench --features bench
+// Tenants are filled with seeded customers, so runs are comparable.
+// STORAGE_BACKEND selects the storage, memory by default.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use customer_microservice::fixture::Fixture;
+use customer_microservice::proto::customer::customer_server::Customer;
+use customer_microservice::proto::customer::*;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+use tonic::Request;
+
+// Customers of the benchmarked tenants
+const SIZES: [usize; 2] = [10_000, 100_000];
+
+fn fixture(runtime: &Runtime, count: usize) -> Fixture {
+  let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "memory".into());
+  let f = Fixture::without_outbox(&backend);
+  runtime.block_on(f.seed(count));
+  f
+}
+
+fn create(c: &mut Criterion) {
+  let runtime = Runtime::new().unwrap();
+  let mut group = c.benchmark_group("create");
+  for count in SIZES {
+    let f = fixture(&runtime, count);
+    group.bench_function(BenchmarkId::from_parameter(count), |b| {
+      b.iter(|| {
+        let request = Request::new(NewCustomerObj {
+          name: "Kiss Béla".to_string(),
+          phone: "+36301234567".to_string(),
+          created_by: 1,
+          ..NewCustomerObj::default()
+        });
+        runtime
+          .block_on(Customer::create_new(&f.service, request))
+          .unwrap()
+      })
+    });
+  }
+  group.finish();
+}
+
+fn get_by_id(c: &mut Criterion) {
+  let runtime = Runtime::new().unwrap();
+  let mut group = c.benchmark_group("get_by_id");
+  for count in SIZES {
+    let f = fixture(&runtime, count);
+    let mut id = 0;
+    group.bench_function(BenchmarkId::from_parameter(count), |b| {
+      b.iter(|| {
+        id = id % count as u32 + 1;
+        let request = Request::new(GetByIdRequest {
+          customer_id: id,
+          expanded: false,
+        });
+        runtime
+          .block_on(Customer::get_by_id(&f.service, request))
+          .unwrap()
+      })
+    });
+  }
+  group.finish();
+}
+
+fn find_customer(c: &mut Criterion) {
+  let runtime = Runtime::new().unwrap();
+  let mut group = c.benchmark_group("find_customer");
+  for count in SIZES {
+    let f = fixture(&runtime, count);
+    group.bench_function(BenchmarkId::from_parameter(count), |b| {
+      b.iter(|| {
+        let request = Request::new(FindCustomerRequest {
+          query: "kiss".to_string(),
+          ..FindCustomerRequest::default()
+        });
+        runtime
+          .block_on(Customer::find_customer(&f.service, request))
+          .unwrap()
+      })
+    });
+  }
+  group.finish();
+}
+
+// Streams every customer of the tenant
+fn get_bulk(c: &mut Criterion) {
+  let runtime = Runtime::new().unwrap();
+  let mut group = c.benchmark_group("get_bulk");
+  group.sample_size(10);
+  for count in SIZES {
+    let f = fixture(&runtime, count);
+    let customer_ids = (1..=count as u32).collect::<Vec<u32>>();
+    group.bench_function(BenchmarkId::from_parameter(count), |b| {
+      b.iter(|| {
+        runtime.block_on(async {
+          let request = Request::new(GetBulkRequest {
+            customer_ids: customer_ids.clone(),
+            chunk_size: 0,
+          });
+          let mut stream = Customer::get_bulk(&f.service, request)
+            .await
+            .unwrap()
+            .into_inner();
+          let mut received = 0;
+          while let Some(customer) = stream.next().await {
+            customer.unwrap();
+            received += 1;
+          }
+          assert_eq!(received, count);
+        })
+      })
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, create, get_by_id, find_customer, get_bulk);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+// Customer messages returned by the REST gateway as JSON
+const REST_RESPONSES: &[&str] = &[
+  ".customer.CustomerIds",
+  ".customer.CustomerObj",
+  ".customer.StatusChangeObj",
+  ".customer.PreviousContactObj",
+  ".customer.ValidationWarning",
+  ".customer.PrivacyFlagsObj",
+  ".customer.VatPeriodObj",
+];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  // File descriptor set for the gRPC reflection service
+  let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+  let mut customer = tonic_build::configure()
+    .file_descriptor_set_path(out_dir.join("customer_descriptor.bin"))
+    // Request of the REST gateway, see src/rest.rs
+    .type_attribute(
+      ".customer.NewCustomerObj",
+      "#[derive(serde::Deserialize)] #[serde(default, deny_unknown_fields)]",
+    );
+  // Responses of the REST gateway
+  for message in REST_RESPONSES {
+    customer = customer.type_attribute(message, "#[derive(serde::Serialize)]");
+  }
+  customer.compile(&["proto/customer.proto"], &["proto"])?;
+  // Client of the shipping service, notified about address changes
+  tonic_build::configure()
+    .build_server(false)
+    .compile(&["proto/shipping.proto"], &["proto"])?;
+  // Client of the remote audit service
+  tonic_build::configure()
+    .build_server(false)
+    .compile(&["proto/audit.proto"], &["proto"])?;
+  // Client of the invoice service, asked before tax number changes
+  // and for the invoice statistics of customers
+  tonic_build::configure()
+    .build_server(false)
+    .compile(&["proto/invoice.proto"], &["proto"])?;
+  // Client of the purchase service, asked for the purchase statistics of customers
+  tonic_build::configure()
+    .build_server(false)
+    .compile(&["proto/purchase.proto"], &["proto"])?;
+  // Client of the document store, asked to delete the documents
+  // of anonymized customers
+  tonic_build::configure()
+    .build_server(false)
+    .compile(&["proto/document.proto"], &["proto"])?;
+  // Client of the user service, asked for the names of customer creators
+  tonic_build::configure()
+    .build_server(false)
+    .compile(&["proto/user.proto"], &["proto"])?;
+  // gRPC health service, server side only
+  tonic_build::configure()
+    .build_client(false)
+    .compile(&["proto/health.proto"], &["proto"])?;
+  Ok(())
+}
@@ -0,0 +1,251 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Suspicious changes
+//
+// Updates are checked against soft rate of change rules. Updates
+// breaking a rule are still saved, but an alert is queued for
+// admin review, to catch fraud and fat-finger mistakes. Change
+// history and alerts are kept in memory only.
+
+use crate::customer::Customer;
+use crate::prelude::*;
+use chrono::prelude::*;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+// Tax number changes of a customer allowed within a day
+pub const MAX_TAX_NUMBER_CHANGES: usize = 2;
+// Customers whose address one editor can change within an hour
+pub const MAX_ADDRESS_EDITS: usize = 20;
+// Alerts kept per tenant, the oldest ones are dropped first
+pub const MAX_ALERTS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeReason {
+  // Tax number changed too many times within a day
+  TaxNumberChurn,
+  // One editor changed the address of too many customers within an hour
+  MassAddressEdits,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuspiciousChange {
+  pub id: u32,
+  pub date: DateTime<Utc>,
+  pub reason: ChangeReason,
+  // 0 if the update had no editor
+  pub editor_uid: u32,
+  // Customers involved in the changes
+  pub customer_ids: Vec<u32>,
+  pub reviewed_by: Option<u32>,
+  pub reviewed_at: Option<DateTime<Utc>>,
+}
+
+// Tenant and customer or editor ID
+type Key = (String, u32);
+
+#[derive(Default)]
+pub struct Anomalies {
+  // (tenant, customer ID) -> tax number change dates
+  tax_number_changes: HashMap<Key, Vec<DateTime<Utc>>>,
+  // (tenant, editor) -> (address change date, customer ID)
+  address_edits: HashMap<Key, Vec<(DateTime<Utc>, u32)>>,
+  // Alerts per tenant, oldest first
+  alerts: HashMap<String, VecDeque<SuspiciousChange>>,
+  next_id: u32,
+}
+
+impl Anomalies {
+  // Check an update of a customer, and queue alerts for the broken rules
+  pub fn observe(
+    &mut self,
+    tenant: &str,
+    editor_uid: u32,
+    before: &Customer,
+    after: &Customer,
+    now: DateTime<Utc>,
+  ) -> Vec<SuspiciousChange> {
+    let mut res = Vec::new();
+    if before.tax_number != after.tax_number {
+      let key = (tenant.to_string(), after.id);
+      let changes = self.tax_number_changes.entry(key.clone()).or_default();
+      changes.retain(|d| *d > now - chrono::Duration::days(1));
+      changes.push(now);
+      if changes.len() > MAX_TAX_NUMBER_CHANGES {
+        self.tax_number_changes.remove(&key);
+        res.push((ChangeReason::TaxNumberChurn, vec![after.id]));
+      }
+    }
+    let address = |c: &Customer| {
+      (
+        c.address_zip.clone(),
+        c.address_location.clone(),
+        c.address_street.clone(),
+      )
+    };
+    if address(before) != address(after) {
+      let key = (tenant.to_string(), editor_uid);
+      let edits = self.address_edits.entry(key.clone()).or_default();
+      edits.retain(|(d, _)| *d > now - chrono::Duration::hours(1));
+      edits.push((now, after.id));
+      let customer_ids = edits.iter().map(|(_, id)| *id).collect::<BTreeSet<u32>>();
+      if customer_ids.len() > MAX_ADDRESS_EDITS {
+        self.address_edits.remove(&key);
+        res.push((
+          ChangeReason::MassAddressEdits,
+          customer_ids.into_iter().collect(),
+        ));
+      }
+    }
+    self.prune(now);
+    res
+      .into_iter()
+      .map(|(reason, customer_ids)| {
+        self.next_id += 1;
+        let alert = SuspiciousChange {
+          id: self.next_id,
+          date: now,
+          reason,
+          editor_uid,
+          customer_ids,
+          reviewed_by: None,
+          reviewed_at: None,
+        };
+        let alerts = self.alerts.entry(tenant.to_string()).or_default();
+        if alerts.len() >= MAX_ALERTS {
+          alerts.pop_front();
+        }
+        alerts.push_back(alert.clone());
+        alert
+      })
+      .collect()
+  }
+  // Drop change history outside of the rule windows
+  fn prune(&mut self, now: DateTime<Utc>) {
+    self
+      .tax_number_changes
+      .retain(|_, c| c.iter().any(|d| *d > now - chrono::Duration::days(1)));
+    self
+      .address_edits
+      .retain(|_, e| e.iter().any(|(d, _)| *d > now - chrono::Duration::hours(1)));
+  }
+  // Alerts of a tenant, oldest first
+  pub fn list(&self, tenant: &str, include_reviewed: bool) -> Vec<SuspiciousChange> {
+    self
+      .alerts
+      .get(tenant)
+      .map(|alerts| {
+        alerts
+          .iter()
+          .filter(|a| include_reviewed || a.reviewed_by.is_none())
+          .cloned()
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+  // Mark alert as reviewed
+  pub fn review(
+    &mut self,
+    tenant: &str,
+    id: u32,
+    reviewed_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<SuspiciousChange> {
+    let alert = self
+      .alerts
+      .get_mut(tenant)
+      .and_then(|alerts| alerts.iter_mut().find(|a| a.id == id))
+      .ok_or_else(|| ServiceError::not_found("Nem található ilyen gyanús módosítás"))?;
+    alert.reviewed_by = Some(reviewed_by);
+    alert.reviewed_at = Some(now);
+    Ok(alert.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::taxnumber::TaxNumber;
+
+  fn customer(id: u32) -> Customer {
+    Customer {
+      id,
+      name: "Kiss Béla".to_string(),
+      ..Customer::default()
+    }
+  }
+
+  #[test]
+  fn test_tax_number_churn() {
+    let mut anomalies = Anomalies::default();
+    let now = Utc::now();
+    let before = customer(1);
+    let mut after = customer(1);
+    after.tax_number = Some(TaxNumber::new("23127182-2-15").unwrap());
+    let changes = |a: &mut Anomalies, hours| {
+      a.observe("", 2, &before, &after, now + chrono::Duration::hours(hours))
+    };
+    assert!(changes(&mut anomalies, 0).is_empty());
+    assert!(changes(&mut anomalies, 1).is_empty());
+    // Other tenants are counted separately
+    assert!(anomalies
+      .observe("shop_a", 2, &before, &after, now)
+      .is_empty());
+    let alerts = changes(&mut anomalies, 2);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].reason, ChangeReason::TaxNumberChurn);
+    assert_eq!(alerts[0].customer_ids, [1]);
+    // Counting restarts after an alert
+    assert!(changes(&mut anomalies, 3).is_empty());
+    assert!(changes(&mut anomalies, 30).is_empty());
+    // Unchanged tax number is not counted
+    assert!(anomalies.observe("", 2, &after, &after, now).is_empty());
+    assert_eq!(anomalies.list("", false).len(), 1);
+    assert!(anomalies.list("shop_a", false).is_empty());
+  }
+
+  #[test]
+  fn test_mass_address_edits() {
+    let mut anomalies = Anomalies::default();
+    let now = Utc::now();
+    let edit = |a: &mut Anomalies, editor_uid, id| {
+      let mut after = customer(id);
+      after.address_street = "Fő utca 1".to_string();
+      a.observe("", editor_uid, &customer(id), &after, now)
+    };
+    for id in 1..=MAX_ADDRESS_EDITS as u32 {
+      assert!(edit(&mut anomalies, 2, id).is_empty());
+      assert!(edit(&mut anomalies, 3, id).is_empty());
+    }
+    // Same customer again is not counted twice
+    assert!(edit(&mut anomalies, 2, 1).is_empty());
+    let alerts = edit(&mut anomalies, 2, 100);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].reason, ChangeReason::MassAddressEdits);
+    assert_eq!(alerts[0].editor_uid, 2);
+    assert_eq!(alerts[0].customer_ids.len(), MAX_ADDRESS_EDITS + 1);
+    // Review
+    let id = alerts[0].id;
+    assert!(anomalies.review("shop_a", id, 9, now).is_err());
+    assert_eq!(
+      anomalies.review("", id, 9, now).unwrap().reviewed_by,
+      Some(9)
+    );
+    assert!(anomalies.list("", false).is_empty());
+    assert_eq!(anomalies.list("", true).len(), 1);
+  }
+}
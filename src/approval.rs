@@ -0,0 +1,382 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Change approval
+//
+// Billing critical fields, the tax number, the legal name of
+// companies and institutions and the invoice name and address, cannot
+// be changed by non-admin users alone. Their changes are kept as pending changes until an admin
+// approves or rejects them. A customer has one pending change at a
+// time, a new one replaces the previous one. Pending changes are
+// stored next to the customer storage of the tenant.
+
+use crate::customer::{Customer, CustomerKind, InvoiceAddress};
+use crate::prelude::*;
+use crate::taxnumber::TaxNumber;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingChange {
+  pub id: u32,
+  pub customer_id: u32,
+  pub requested_by: u32,
+  pub date_requested: DateTime<Utc>,
+  // New name, None if not changed
+  pub name: Option<String>,
+  // New tax number, None if not changed
+  // Some(None) removes the tax number
+  pub tax_number: Option<Option<TaxNumber>>,
+  // New invoice overrides, None if not changed
+  // Some(None) removes the override
+  pub invoice_name: Option<Option<String>>,
+  pub invoice_address: Option<Option<InvoiceAddress>>,
+}
+
+// Pending change layout before the invoice overrides
+// Stored changes are read with it once, then saved in the new layout
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PendingChangeDataV1 {
+  changes: Vec<PendingChangeV1>,
+  next_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PendingChangeV1 {
+  id: u32,
+  customer_id: u32,
+  requested_by: u32,
+  date_requested: DateTime<Utc>,
+  name: Option<String>,
+  tax_number: Option<Option<TaxNumber>>,
+}
+
+impl From<PendingChangeDataV1> for PendingChangeData {
+  fn from(data: PendingChangeDataV1) -> Self {
+    Self {
+      changes: data
+        .changes
+        .into_iter()
+        .map(|c| PendingChange {
+          id: c.id,
+          customer_id: c.customer_id,
+          requested_by: c.requested_by,
+          date_requested: c.date_requested,
+          name: c.name,
+          tax_number: c.tax_number,
+          invoice_name: None,
+          invoice_address: None,
+        })
+        .collect(),
+      next_id: data.next_id,
+    }
+  }
+}
+
+impl TryFrom for PendingChangeData {
+  type TryFrom = PendingChangeDataV1;
+}
+
+// Sensitive field changes taken out of an update
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SensitiveChange {
+  pub name: Option<String>,
+  pub tax_number: Option<Option<TaxNumber>>,
+  pub invoice_name: Option<Option<String>>,
+  pub invoice_address: Option<Option<InvoiceAddress>>,
+}
+
+impl PendingChange {
+  // Apply change to the customer
+  pub fn apply(&self, customer: &mut Customer) {
+    if let Some(name) = &self.name {
+      customer.name = name.clone();
+    }
+    if let Some(tax_number) = &self.tax_number {
+      customer.tax_number = tax_number.clone();
+    }
+    if let Some(invoice_name) = &self.invoice_name {
+      customer.invoice_name = invoice_name.clone();
+    }
+    if let Some(invoice_address) = &self.invoice_address {
+      customer.invoice_address = invoice_address.clone();
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PendingChangeData {
+  changes: Vec<PendingChange>,
+  next_id: u32,
+}
+
+// Take the sensitive field changes out of an updated customer
+// The updated customer gets the current values back, the changes
+// are returned, if there are any
+pub fn split_sensitive(current: &Customer, updated: &mut Customer) -> Option<SensitiveChange> {
+  // Names of private customers are not legal names
+  let legal_name = current.kind != CustomerKind::Private || updated.kind != CustomerKind::Private;
+  let name = match legal_name && updated.name != current.name {
+    true => Some(std::mem::replace(&mut updated.name, current.name.clone())),
+    false => None,
+  };
+  let tax_number = match updated.tax_number != current.tax_number {
+    true => Some(std::mem::replace(
+      &mut updated.tax_number,
+      current.tax_number.clone(),
+    )),
+    false => None,
+  };
+  let invoice_name = match updated.invoice_name != current.invoice_name {
+    true => Some(std::mem::replace(
+      &mut updated.invoice_name,
+      current.invoice_name.clone(),
+    )),
+    false => None,
+  };
+  let invoice_address = match updated.invoice_address != current.invoice_address {
+    true => Some(std::mem::replace(
+      &mut updated.invoice_address,
+      current.invoice_address.clone(),
+    )),
+    false => None,
+  };
+  let change = SensitiveChange {
+    name,
+    tax_number,
+    invoice_name,
+    invoice_address,
+  };
+  match change == SensitiveChange::default() {
+    true => None,
+    false => Some(change),
+  }
+}
+
+// Pending changes of every tenant
+pub struct PendingChanges {
+  data_dir: PathBuf,
+  packs: HashMap<String, Pack<PendingChangeData>>,
+}
+
+impl PendingChanges {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+      packs: HashMap::new(),
+    }
+  }
+  // Pending changes pack of a tenant, loaded on first use
+  fn pack(&mut self, tenant: &str) -> ServiceResult<&mut Pack<PendingChangeData>> {
+    if !self.packs.contains_key(tenant) {
+      let path = tenant_path(&self.data_dir, tenant).with_file_name("pending_changes");
+      // Changes stored in the previous layout are migrated
+      let file = path.join("pending_changes");
+      let pack = match file.exists() {
+        true => Pack::try_load_from_path(file)?,
+        false => Pack::load_or_init(path, "pending_changes")?,
+      };
+      self.packs.insert(tenant.to_string(), pack);
+    }
+    Ok(self.packs.get_mut(tenant).expect("Loaded above"))
+  }
+  // Store change waiting for approval
+  // Replaces the previous pending change of the customer
+  pub fn request(
+    &mut self,
+    tenant: &str,
+    customer_id: u32,
+    requested_by: u32,
+    change: SensitiveChange,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<PendingChange> {
+    let change = self.pack(tenant)?.update(|data| {
+      data.next_id += 1;
+      let change = PendingChange {
+        id: data.next_id,
+        customer_id,
+        requested_by,
+        date_requested: now,
+        name: change.name,
+        tax_number: change.tax_number,
+        invoice_name: change.invoice_name,
+        invoice_address: change.invoice_address,
+      };
+      data.changes.retain(|c| c.customer_id != customer_id);
+      data.changes.push(change.clone());
+      change
+    })?;
+    Ok(change)
+  }
+  // Pending changes, oldest first
+  // All of them if customer_id is 0
+  pub fn list(&mut self, tenant: &str, customer_id: u32) -> ServiceResult<Vec<PendingChange>> {
+    Ok(
+      self
+        .pack(tenant)?
+        .unpack()
+        .changes
+        .iter()
+        .filter(|c| customer_id == 0 || c.customer_id == customer_id)
+        .cloned()
+        .collect(),
+    )
+  }
+  pub fn get(&mut self, tenant: &str, id: u32) -> ServiceResult<PendingChange> {
+    self
+      .pack(tenant)?
+      .unpack()
+      .changes
+      .iter()
+      .find(|c| c.id == id)
+      .cloned()
+      .ok_or_else(|| ServiceError::not_found("Nem található ilyen jóváhagyásra váró módosítás"))
+  }
+  // Remove change once it is approved or rejected
+  pub fn remove(&mut self, tenant: &str, id: u32) -> ServiceResult<PendingChange> {
+    let change = self.get(tenant, id)?;
+    self
+      .pack(tenant)?
+      .update(|data| data.changes.retain(|c| c.id != id))?;
+    Ok(change)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn customer(kind: CustomerKind) -> Customer {
+    Customer {
+      id: 1,
+      name: "Kiss Kert Kft".to_string(),
+      kind,
+      tax_number: TaxNumber::new("23127182-2-15").ok(),
+      ..Customer::default()
+    }
+  }
+
+  fn name_change(name: &str) -> SensitiveChange {
+    SensitiveChange {
+      name: Some(name.to_string()),
+      ..SensitiveChange::default()
+    }
+  }
+
+  #[test]
+  fn test_split_sensitive() {
+    let current = customer(CustomerKind::Company);
+    let mut updated = current.clone();
+    updated.name = "Nagy Kert Kft".to_string();
+    updated.tax_number = None;
+    updated.email = "info@example.com".to_string();
+    let change = split_sensitive(&current, &mut updated).unwrap();
+    assert_eq!(change.name.as_deref(), Some("Nagy Kert Kft"));
+    assert_eq!(change.tax_number, Some(None));
+    assert_eq!(change.invoice_name, None);
+    // Other changes stay
+    assert_eq!(updated.name, current.name);
+    assert_eq!(updated.tax_number, current.tax_number);
+    assert_eq!(updated.email, "info@example.com");
+    // Names of private customers can be changed
+    let current = Customer {
+      tax_number: None,
+      ..customer(CustomerKind::Private)
+    };
+    let mut updated = current.clone();
+    updated.name = "Kiss Béla".to_string();
+    assert!(split_sensitive(&current, &mut updated).is_none());
+    assert_eq!(updated.name, "Kiss Béla");
+    // Invoice overrides of every kind wait for approval
+    let mut updated = current.clone();
+    updated.invoice_name = Some("Kiss Béla ev.".to_string());
+    let change = split_sensitive(&current, &mut updated).unwrap();
+    assert_eq!(change.invoice_name, Some(Some("Kiss Béla ev.".to_string())));
+    assert_eq!(change.invoice_address, None);
+    assert_eq!(updated.invoice_name, None);
+  }
+
+  #[test]
+  fn test_pending_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let mut changes = PendingChanges::new(dir.path());
+    let first = changes
+      .request("", 1, 5, name_change("Új név Kft"), now)
+      .unwrap();
+    let tax_number_removal = SensitiveChange {
+      tax_number: Some(None),
+      ..SensitiveChange::default()
+    };
+    changes.request("", 2, 5, tax_number_removal, now).unwrap();
+    assert_eq!(changes.list("", 0).unwrap().len(), 2);
+    // A new change of the customer replaces the previous one
+    let second = changes
+      .request("", 1, 6, name_change("Másik név Kft"), now)
+      .unwrap();
+    assert_ne!(first.id, second.id);
+    assert_eq!(changes.list("", 1).unwrap(), std::slice::from_ref(&second));
+    assert!(changes.get("", first.id).is_err());
+    // Tenants are separate
+    assert!(changes.list("shop_a", 0).unwrap().is_empty());
+    // Changes survive restart
+    let mut changes = PendingChanges::new(dir.path());
+    assert_eq!(changes.get("", second.id).unwrap(), second);
+    let mut c = customer(CustomerKind::Company);
+    changes.remove("", second.id).unwrap().apply(&mut c);
+    assert_eq!(c.name, "Másik név Kft");
+    assert!(changes.remove("", second.id).is_err());
+    assert_eq!(changes.list("", 0).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_legacy_pending_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    // Changes stored before the invoice overrides
+    let path = tenant_path(dir.path(), "").with_file_name("pending_changes");
+    let mut legacy: Pack<PendingChangeDataV1> =
+      Pack::load_or_init(path, "pending_changes").unwrap();
+    legacy
+      .update(|data| {
+        data.next_id = 3;
+        data.changes.push(PendingChangeV1 {
+          id: 3,
+          customer_id: 1,
+          requested_by: 5,
+          date_requested: now,
+          name: Some("Új név Kft".to_string()),
+          tax_number: None,
+        });
+      })
+      .unwrap();
+    drop(legacy);
+    let mut changes = PendingChanges::new(dir.path());
+    let change = changes.get("", 3).unwrap();
+    assert_eq!(change.name.as_deref(), Some("Új név Kft"));
+    assert_eq!(change.invoice_name, None);
+    // IDs continue after the migrated changes
+    let next = changes
+      .request("", 2, 5, name_change("Kert Kft"), now)
+      .unwrap();
+    assert_eq!(next.id, 4);
+  }
+}
@@ -0,0 +1,128 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Audit trail
+//
+// Append only log of the actions done on customers without
+// a user request, e.g. by retention runs and backup restores,
+// and of admin decisions, one JSON entry per line. Stored next to the customer storage
+// of the tenant, in audit.jsonl.
+
+use crate::prelude::*;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+  Anonymize,
+  Restore,
+  ApproveChange,
+  RejectChange,
+  ApproveErasure,
+  RejectErasure,
+  // Tax number change of an invoiced customer
+  ForceTaxNumberChange,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+  pub date: DateTime<Utc>,
+  pub customer_id: u32,
+  pub action: AuditAction,
+  // Who did the action, e.g. retention:<rule name> or restore:cli
+  pub actor: String,
+}
+
+pub struct AuditLog {
+  data_dir: PathBuf,
+}
+
+impl AuditLog {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+    }
+  }
+  fn path(&self, tenant: &str) -> PathBuf {
+    tenant_path(&self.data_dir, tenant).with_file_name("audit.jsonl")
+  }
+  // Append entry and sync it to disk
+  pub fn append(&self, tenant: &str, entry: &AuditEntry) -> ServiceResult<()> {
+    let path = self.path(tenant);
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let line = serde_json::to_string(entry)
+      .map_err(|e| ServiceError::internal_error(&format!("Audit entry error: {}", e)))?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+    Ok(())
+  }
+  // Read all entries of a tenant, oldest first
+  pub fn read(&self, tenant: &str) -> ServiceResult<Vec<AuditEntry>> {
+    let path = self.path(tenant);
+    if !path.exists() {
+      return Ok(Vec::new());
+    }
+    let mut res = Vec::new();
+    for line in BufReader::new(std::fs::File::open(path)?).lines() {
+      let entry = serde_json::from_str(&line?)
+        .map_err(|e| ServiceError::internal_error(&format!("Broken audit entry: {}", e)))?;
+      res.push(entry);
+    }
+    Ok(res)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_audit_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = AuditLog::new(dir.path());
+    assert!(log.read("").unwrap().is_empty());
+    let entry = |customer_id| AuditEntry {
+      date: Utc::now(),
+      customer_id,
+      action: AuditAction::Anonymize,
+      actor: "retention:legal".to_string(),
+    };
+    log.append("", &entry(1)).unwrap();
+    log.append("", &entry(2)).unwrap();
+    log.append("shop_a", &entry(3)).unwrap();
+    let ids = |tenant| {
+      log
+        .read(tenant)
+        .unwrap()
+        .iter()
+        .map(|e| e.customer_id)
+        .collect::<Vec<u32>>()
+    };
+    assert_eq!(ids(""), [1, 2]);
+    assert_eq!(ids("shop_a"), [3]);
+    assert!(dir.path().join("audit.jsonl").is_file());
+    assert!(dir.path().join("shop_a/audit.jsonl").is_file());
+  }
+}
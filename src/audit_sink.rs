@@ -0,0 +1,514 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Mutation audit log
+//
+// Beyond the audit trail of audit.rs, every customer change stored by
+// the service is written to a global append only log: who changed what,
+// when, and from which peer. The log is written to one sink, selected
+// by AUDIT_SINK:
+//
+//  - file: JSON lines, rotated by size
+//  - syslog: JSON messages over the local syslog socket
+//  - grpc: records sent to a remote audit service, in order, retrying
+//    failed calls. Records that cannot be sent are written to a
+//    local overflow file instead, to be handled by hand.
+//
+// The RPC method and the client address come from the request being
+// handled, see Scope. Changes outside of requests, e.g. by retention
+// runs, are logged with empty method and peer.
+
+use crate::customer::Customer;
+use crate::history::FieldDiff;
+use crate::prelude::*;
+use crate::proto::audit::audit_client::AuditClient;
+use crate::proto::audit::FieldChange;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::transport::{Channel, Endpoint, NamedService};
+
+// Service name in syslog messages and remote records
+const SERVICE_NAME: &str = "customer_microservice";
+
+// syslog facility authpriv, severity info
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+// Records waiting to be sent to the remote audit service
+const QUEUE_SIZE: usize = 10_000;
+
+// Failed calls before a record is written to the overflow file
+pub const MAX_ATTEMPTS: u32 = 10;
+
+// Retry delay after the first failed call, doubled after every further failure
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+// Call fails if the audit service does not respond in time
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MutationAction {
+  Create,
+  Update,
+}
+
+impl MutationAction {
+  fn as_str(&self) -> &'static str {
+    match self {
+      MutationAction::Create => "create",
+      MutationAction::Update => "update",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MutationRecord {
+  pub date: DateTime<Utc>,
+  pub tenant: String,
+  pub customer_id: u32,
+  pub action: MutationAction,
+  // Changed fields, every field set for creates
+  pub changes: Vec<FieldDiff>,
+  // User making the change, 0 for the service itself
+  pub user_id: u32,
+  // RPC method, empty for changes outside of requests
+  pub method: String,
+  // Client address, empty if not known, e.g. on Unix sockets
+  pub peer: String,
+}
+
+impl MutationRecord {
+  // Record of a stored customer, previous is its version before the change
+  pub fn new(
+    tenant: &str,
+    previous: Option<&Customer>,
+    customer: &Customer,
+    changes: Vec<FieldDiff>,
+  ) -> Self {
+    let (action, user_id) = match previous {
+      Some(_) => (MutationAction::Update, customer.last_modified_by),
+      None => (MutationAction::Create, customer.created_by),
+    };
+    let (method, peer) = REQUEST
+      .try_with(|request| {
+        let request = request.borrow();
+        (request.method.clone(), request.peer.clone())
+      })
+      .unwrap_or_default();
+    Self {
+      date: customer.last_modified,
+      tenant: tenant.to_string(),
+      customer_id: customer.id,
+      action,
+      changes,
+      user_id,
+      method,
+      peer,
+    }
+  }
+}
+
+// Where the records are written
+pub trait AuditSink: Send + Sync {
+  fn write(&self, record: &MutationRecord) -> ServiceResult<()>;
+}
+
+// The mutation log of every tenant
+pub struct MutationLog {
+  sink: Box<dyn AuditSink>,
+}
+
+impl MutationLog {
+  pub fn new(sink: impl AuditSink + 'static) -> Self {
+    Self {
+      sink: Box::new(sink),
+    }
+  }
+  // Log stored customer
+  // The customer is already stored, so errors are only reported
+  pub fn record(
+    &self,
+    tenant: &str,
+    previous: Option<&Customer>,
+    customer: &Customer,
+    changes: Vec<FieldDiff>,
+  ) {
+    let record = MutationRecord::new(tenant, previous, customer, changes);
+    if let Err(error) = self.sink.write(&record) {
+      eprintln!(
+        "Error while writing audit record of customer {}: {}",
+        customer.id, error
+      );
+    }
+  }
+}
+
+fn to_line(record: &MutationRecord) -> ServiceResult<String> {
+  serde_json::to_string(record)
+    .map_err(|e| ServiceError::internal_error(&format!("Audit record error: {}", e)))
+}
+
+// JSON lines file
+// When the file would grow over max_bytes, it is renamed to
+// <path>.1, the older ones shifted to <path>.2 and so on,
+// keeping the last keep rotated files
+pub struct FileSink {
+  path: PathBuf,
+  max_bytes: u64,
+  keep: usize,
+  // Writes and rotations one at a time
+  lock: Mutex<()>,
+}
+
+impl FileSink {
+  pub fn new(path: PathBuf, max_bytes: u64, keep: usize) -> Self {
+    Self {
+      path,
+      max_bytes,
+      keep,
+      lock: Mutex::new(()),
+    }
+  }
+  fn rotated(&self, n: usize) -> PathBuf {
+    let mut path = self.path.clone().into_os_string();
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+  }
+  fn rotate(&self) -> ServiceResult<()> {
+    if self.keep == 0 {
+      std::fs::remove_file(&self.path)?;
+      return Ok(());
+    }
+    for n in (1..self.keep).rev() {
+      if self.rotated(n).exists() {
+        std::fs::rename(self.rotated(n), self.rotated(n + 1))?;
+      }
+    }
+    std::fs::rename(&self.path, self.rotated(1))?;
+    Ok(())
+  }
+}
+
+impl AuditSink for FileSink {
+  fn write(&self, record: &MutationRecord) -> ServiceResult<()> {
+    let line = to_line(record)?;
+    let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(dir) = self.path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let size = std::fs::metadata(&self.path).map_or(0, |m| m.len());
+    if size > 0 && size + line.len() as u64 + 1 > self.max_bytes {
+      self.rotate()?;
+    }
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+    Ok(())
+  }
+}
+
+// Local syslog socket, e.g. /dev/log
+// Messages are sent in the BSD syslog format
+pub struct SyslogSink {
+  socket: UnixDatagram,
+  path: PathBuf,
+}
+
+impl SyslogSink {
+  pub fn new(path: PathBuf) -> ServiceResult<Self> {
+    Ok(Self {
+      socket: UnixDatagram::unbound()?,
+      path,
+    })
+  }
+}
+
+// <PRI>Mmm dd hh:mm:ss tag[pid]: message
+fn syslog_message(record: &MutationRecord) -> ServiceResult<String> {
+  Ok(format!(
+    "<{}>{} {}[{}]: {}",
+    SYSLOG_PRIORITY,
+    Local::now().format("%b %e %H:%M:%S"),
+    SERVICE_NAME,
+    std::process::id(),
+    to_line(record)?
+  ))
+}
+
+impl AuditSink for SyslogSink {
+  fn write(&self, record: &MutationRecord) -> ServiceResult<()> {
+    // Sent to the path every time, so syslog restarts are survived
+    self
+      .socket
+      .send_to(syslog_message(record)?.as_bytes(), &self.path)?;
+    Ok(())
+  }
+}
+
+// Remote audit service
+// Records are queued and sent one by one by a background task
+pub struct GrpcSink {
+  queue: mpsc::Sender<MutationRecord>,
+  overflow: Arc<FileSink>,
+}
+
+impl GrpcSink {
+  // Start sending records to endpoint
+  // Records that cannot be sent are written to overflow_path
+  // Must be called from the tokio runtime
+  pub fn start(endpoint: &str, overflow_path: PathBuf) -> ServiceResult<Self> {
+    let channel = Endpoint::from_shared(endpoint.to_string())
+      .map_err(|e| ServiceError::internal_error(&format!("Invalid audit endpoint: {}", e)))?
+      .connect_lazy()
+      .map_err(|e| ServiceError::internal_error(&format!("Invalid audit endpoint: {}", e)))?;
+    let (queue, records) = mpsc::channel(QUEUE_SIZE);
+    // Overflow file is never rotated
+    let overflow = Arc::new(FileSink::new(overflow_path, u64::MAX, 0));
+    tokio::spawn(send_records(
+      AuditClient::new(channel),
+      records,
+      overflow.clone(),
+    ));
+    Ok(Self { queue, overflow })
+  }
+}
+
+impl AuditSink for GrpcSink {
+  fn write(&self, record: &MutationRecord) -> ServiceResult<()> {
+    match self.queue.try_send(record.clone()) {
+      Ok(()) => Ok(()),
+      // Audit service is down for a long time
+      Err(_) => self.overflow.write(record),
+    }
+  }
+}
+
+fn request(record: &MutationRecord) -> crate::proto::audit::MutationRecord {
+  crate::proto::audit::MutationRecord {
+    date: record.date.to_rfc3339(),
+    service: SERVICE_NAME.to_string(),
+    tenant: record.tenant.clone(),
+    customer_id: record.customer_id,
+    action: record.action.as_str().to_string(),
+    fields: record.changes.iter().map(|c| c.path.clone()).collect(),
+    changes: record
+      .changes
+      .iter()
+      .map(|c| FieldChange {
+        path: c.path.clone(),
+        old_value: c.old.to_string(),
+        new_value: c.new.to_string(),
+      })
+      .collect(),
+    user_id: record.user_id,
+    method: record.method.clone(),
+    peer: record.peer.clone(),
+  }
+}
+
+// Send queued records in order, retrying failed calls with backoff
+async fn send_records(
+  client: AuditClient<Channel>,
+  mut records: mpsc::Receiver<MutationRecord>,
+  overflow: Arc<FileSink>,
+) {
+  while let Some(record) = records.recv().await {
+    let mut delay = RETRY_BASE_DELAY;
+    for attempt in 1..=MAX_ATTEMPTS {
+      // Clients share the channel
+      let mut client = client.clone();
+      let error = match tokio::time::timeout(CALL_TIMEOUT, client.record(request(&record))).await {
+        Ok(Ok(_)) => break,
+        Ok(Err(status)) => format!("Audit service: {}", status),
+        Err(_) => "Audit service timed out".to_string(),
+      };
+      if attempt == MAX_ATTEMPTS {
+        eprintln!(
+          "Giving up audit record of customer {}: {}",
+          record.customer_id, error
+        );
+        if let Err(error) = overflow.write(&record) {
+          eprintln!("Error while writing audit overflow file: {}", error);
+        }
+        break;
+      }
+      tokio::time::sleep(delay).await;
+      delay = (delay * 2).min(RETRY_MAX_DELAY);
+    }
+  }
+}
+
+// Request being handled
+#[derive(Clone, Debug, Default)]
+struct RequestInfo {
+  method: String,
+  peer: String,
+}
+
+::tokio::task_local! {
+  static REQUEST: RefCell<RequestInfo>;
+}
+
+// Set the client address of the request being handled
+// Called by the auth interceptor, which sees the connection
+pub fn set_peer(peer: Option<SocketAddr>) {
+  let _ = REQUEST.try_with(|request| {
+    request.borrow_mut().peer = peer.map(|p| p.to_string()).unwrap_or_default();
+  });
+}
+
+// Run every RPC handler with the request info, see Negotiate in locale.rs
+#[derive(Clone)]
+pub struct Scope<S> {
+  inner: S,
+}
+
+impl<S> Scope<S> {
+  pub fn new(inner: S) -> Self {
+    Self { inner }
+  }
+}
+
+impl<S: NamedService> NamedService for Scope<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Scope<S>
+where
+  S: Service<http::Request<B>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  B: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    let info = RequestInfo {
+      method: request
+        .uri()
+        .path()
+        .rsplit('/')
+        .next()
+        .unwrap_or("")
+        .to_string(),
+      peer: String::new(),
+    };
+    // Take the service that was polled ready
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    Box::pin(REQUEST.scope(RefCell::new(info), async move { inner.call(request).await }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn customer() -> Customer {
+    Customer {
+      id: 4,
+      name: "Kiss Béla".to_string(),
+      created_by: 2,
+      last_modified_by: 2,
+      ..Customer::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_mutation_record() {
+    let before = customer();
+    let record = MutationRecord::new("shop_a", None, &before, Vec::new());
+    assert_eq!(record.action, MutationAction::Create);
+    assert_eq!(record.user_id, 2);
+    assert_eq!(record.method, "");
+    let mut after = before.clone();
+    after.email = "kiss.bela@example.com".to_string();
+    after.last_modified_by = 3;
+    let info = RequestInfo {
+      method: "UpdateById".to_string(),
+      peer: String::new(),
+    };
+    let record = REQUEST
+      .scope(RefCell::new(info), async {
+        set_peer("127.0.0.1:4000".parse().ok());
+        MutationRecord::new("shop_a", Some(&before), &after, Vec::new())
+      })
+      .await;
+    assert_eq!(record.action, MutationAction::Update);
+    assert_eq!(record.user_id, 3);
+    assert_eq!(record.method, "UpdateById");
+    assert_eq!(record.peer, "127.0.0.1:4000");
+  }
+
+  #[test]
+  fn test_file_sink() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mutations.jsonl");
+    let record = MutationRecord::new("", None, &customer(), Vec::new());
+    let size = to_line(&record).unwrap().len() as u64 + 1;
+    // Two records fit in a file
+    let sink = FileSink::new(path.clone(), 2 * size, 2);
+    for _ in 0..7 {
+      sink.write(&record).unwrap();
+    }
+    let lines = |path: PathBuf| std::fs::read_to_string(path).unwrap().lines().count();
+    assert_eq!(lines(path.clone()), 1);
+    assert_eq!(lines(sink.rotated(1)), 2);
+    assert_eq!(lines(sink.rotated(2)), 2);
+    assert!(!sink.rotated(3).exists());
+    let line = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+      serde_json::from_str::<MutationRecord>(line.trim()).unwrap(),
+      record
+    );
+  }
+
+  #[test]
+  fn test_syslog_sink() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("log");
+    let server = UnixDatagram::bind(&path).unwrap();
+    let record = MutationRecord::new("", None, &customer(), Vec::new());
+    SyslogSink::new(path).unwrap().write(&record).unwrap();
+    let mut buf = [0; 4096];
+    let n = server.recv(&mut buf).unwrap();
+    let message = String::from_utf8_lossy(&buf[..n]).to_string();
+    assert!(message.starts_with("<86>"));
+    assert!(message.contains(" customer_microservice["));
+    assert!(message.ends_with(&to_line(&record).unwrap()));
+  }
+}
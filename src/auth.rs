@@ -0,0 +1,266 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::audit_sink;
+use crate::policy::Field;
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tonic::{metadata::MetadataMap, Request, Status};
+
+// Request metadata key of the caller role
+pub const ROLE_METADATA_KEY: &str = "caller-role";
+
+// Request metadata key of the caller token
+pub const TOKEN_METADATA_KEY: &str = "caller-token";
+
+// Caller role, from the least to the most privileged
+// The role is given by the caller token. Callers without token
+// get the most restrictive one. The caller-role metadata can only
+// ask for a role the token grants, e.g. a billing caller can ask
+// for sales responses.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Default)]
+pub enum Role {
+  #[default]
+  Sales,
+  Billing,
+}
+
+// Role of every caller token, set at startup
+static ROLE_TOKENS: OnceLock<HashMap<String, Role>> = OnceLock::new();
+
+// Parse caller tokens with their roles, e.g. billing:secret1,sales:secret2
+pub fn parse_role_tokens(value: &str) -> ServiceResult<HashMap<String, Role>> {
+  let mut tokens = HashMap::new();
+  for item in value.split(',').map(|i| i.trim()).filter(|i| !i.is_empty()) {
+    let (role, token) = item.split_once(':').ok_or_else(|| {
+      ServiceError::internal_error(&format!("Wrong role token, expected role:token: {}", item))
+    })?;
+    let role = parse_role(role.trim())
+      .ok_or_else(|| ServiceError::internal_error(&format!("Unknown role: {}", role)))?;
+    if token.trim().is_empty() {
+      return Err(ServiceError::internal_error("Empty role token"));
+    }
+    tokens.insert(token.trim().to_string(), role);
+  }
+  Ok(tokens)
+}
+
+// Set the caller tokens, only the first call has effect
+pub fn set_role_tokens(tokens: HashMap<String, Role>) {
+  let _ = ROLE_TOKENS.set(tokens);
+}
+
+fn parse_role(name: &str) -> Option<Role> {
+  match name {
+    "sales" => Some(Role::Sales),
+    "billing" => Some(Role::Billing),
+    _ => None,
+  }
+}
+
+// How a customer field is shown to a role
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Visibility {
+  Full,
+  Masked,
+  Truncated,
+}
+
+// Field policy table
+// Fields not listed here are shown in full
+const FIELD_POLICY: [(Role, Field, Visibility); 2] = [
+  (Role::Sales, Field::TaxNumber, Visibility::Masked),
+  (Role::Sales, Field::AddressStreet, Visibility::Truncated),
+];
+
+// Get field visibility for a role
+pub fn visibility(role: Role, field: Field) -> Visibility {
+  FIELD_POLICY
+    .iter()
+    .find(|(r, f, _)| *r == role && *f == field)
+    .map_or(Visibility::Full, |(_, _, visibility)| *visibility)
+}
+
+// Shape field value for a role
+pub fn shape(role: Role, field: Field, value: String) -> String {
+  match visibility(role, field) {
+    Visibility::Full => value,
+    Visibility::Masked => mask(&value),
+    Visibility::Truncated => truncate_street(&value),
+  }
+}
+
+// Get caller role from request metadata
+pub fn role_from_metadata(metadata: &MetadataMap) -> ServiceResult<Role> {
+  let empty = HashMap::new();
+  role_for(metadata, ROLE_TOKENS.get().unwrap_or(&empty))
+}
+
+fn role_for(metadata: &MetadataMap, tokens: &HashMap<String, Role>) -> ServiceResult<Role> {
+  let granted = match metadata.get(TOKEN_METADATA_KEY) {
+    None => Role::default(),
+    Some(token) => match token.to_str().ok().and_then(|t| tokens.get(t.trim())) {
+      Some(role) => *role,
+      None => return Err(ServiceError::permission_denied("Ismeretlen hívó token")),
+    },
+  };
+  let asked = match metadata.get(ROLE_METADATA_KEY) {
+    None => return Ok(granted),
+    Some(value) => value.to_str().ok().and_then(|v| parse_role(v.trim())),
+  };
+  match asked {
+    Some(role) if role <= granted => Ok(role),
+    Some(_) => Err(ServiceError::permission_denied(
+      "A szerepkör nem engedélyezett a hívó számára",
+    )),
+    None => Err(ServiceError::bad_request(
+      "Ismeretlen felhasználói szerepkör",
+    )),
+  }
+}
+
+// Check that the caller has a known caller token
+// Any role is accepted, callers without token are rejected
+pub fn check_staff(metadata: &MetadataMap) -> ServiceResult<()> {
+  let empty = HashMap::new();
+  staff_for(metadata, ROLE_TOKENS.get().unwrap_or(&empty))
+}
+
+fn staff_for(metadata: &MetadataMap, tokens: &HashMap<String, Role>) -> ServiceResult<()> {
+  match metadata
+    .get(TOKEN_METADATA_KEY)
+    .and_then(|t| t.to_str().ok())
+    .map(|t| tokens.contains_key(t.trim()))
+  {
+    Some(true) => Ok(()),
+    _ => Err(ServiceError::permission_denied(
+      "Ez a művelet csak hitelesített munkatárs számára engedélyezett",
+    )),
+  }
+}
+
+// Auth interceptor
+// Rejects requests with unknown caller role,
+// and notes the client address for the mutation audit log
+// Signature is given by tonic
+#[allow(clippy::result_large_err)]
+pub fn interceptor(request: Request<()>) -> Result<Request<()>, Status> {
+  role_from_metadata(request.metadata())?;
+  audit_sink::set_peer(request.remote_addr());
+  Ok(request)
+}
+
+// Mask value keeping only its last 2 letters or digits
+// 12345678-1-23 => ********-*-23
+pub fn mask(value: &str) -> String {
+  let digits = value.chars().filter(|c| c.is_alphanumeric()).count();
+  let mut seen = 0;
+  value
+    .chars()
+    .map(|c| match c.is_alphanumeric() {
+      true => {
+        seen += 1;
+        match seen > digits.saturating_sub(2) {
+          true => c,
+          false => '*',
+        }
+      }
+      false => c,
+    })
+    .collect()
+}
+
+// Truncate street address to the street name
+// Fő utca 12. 2/4 => Fő utca
+pub fn truncate_street(street: &str) -> String {
+  street
+    .split(|c: char| c.is_ascii_digit())
+    .next()
+    .unwrap_or("")
+    .trim()
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_visibility() {
+    assert_eq!(
+      visibility(Role::Sales, Field::TaxNumber),
+      Visibility::Masked
+    );
+    assert_eq!(
+      visibility(Role::Sales, Field::AddressStreet),
+      Visibility::Truncated
+    );
+    assert_eq!(visibility(Role::Sales, Field::Email), Visibility::Full);
+    assert_eq!(
+      visibility(Role::Billing, Field::TaxNumber),
+      Visibility::Full
+    );
+  }
+
+  #[test]
+  fn test_role_from_metadata() {
+    let tokens = parse_role_tokens("billing:secret1, sales:secret2").unwrap();
+    let role = |token: Option<&str>, role: Option<&str>| {
+      let mut metadata = MetadataMap::new();
+      if let Some(token) = token {
+        metadata.insert(TOKEN_METADATA_KEY, token.parse().unwrap());
+      }
+      if let Some(role) = role {
+        metadata.insert(ROLE_METADATA_KEY, role.parse().unwrap());
+      }
+      role_for(&metadata, &tokens)
+    };
+    // Without token the most restrictive role
+    assert_eq!(role(None, None).unwrap(), Role::Sales);
+    assert_eq!(role(None, Some("sales")).unwrap(), Role::Sales);
+    assert!(role(None, Some("billing")).is_err());
+    assert_eq!(role(Some("secret1"), None).unwrap(), Role::Billing);
+    assert_eq!(role(Some("secret1"), Some("sales")).unwrap(), Role::Sales);
+    assert!(role(Some("secret2"), Some("billing")).is_err());
+    assert!(role(Some("wrong"), None).is_err());
+    assert!(role(Some("secret1"), Some("admin")).is_err());
+    assert!(parse_role_tokens("admin:secret").is_err());
+    assert!(parse_role_tokens("secret").is_err());
+    assert!(parse_role_tokens("").unwrap().is_empty());
+    // Staff check needs a known token
+    let staff = |token: Option<&str>| {
+      let mut metadata = MetadataMap::new();
+      if let Some(token) = token {
+        metadata.insert(TOKEN_METADATA_KEY, token.parse().unwrap());
+      }
+      staff_for(&metadata, &tokens).is_ok()
+    };
+    assert!(staff(Some("secret1")));
+    assert!(staff(Some("secret2")));
+    assert!(!staff(Some("wrong")));
+    assert!(!staff(None));
+  }
+
+  #[test]
+  fn test_masking() {
+    assert_eq!(mask("12345678-1-23"), "********-*-23");
+    assert_eq!(mask(""), "");
+    assert_eq!(truncate_street("Fő utca 12. 2/4"), "Fő utca");
+    assert_eq!(truncate_street("12. utca 3."), "");
+  }
+}
@@ -0,0 +1,460 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Admin CLI
+//
+// Emergency ops working directly on the customer storage
+// without starting the gRPC server. Do not use them while
+// the service is running on the same data directory.
+
+use crate::audit::AuditLog;
+use crate::customer::Customer;
+use crate::import::{self, ImportKey, ImportRow, RowAction};
+use crate::policy::ValidationPolicy;
+use crate::prelude::*;
+use crate::restore::*;
+use crate::tenant::*;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "Usage: customer_microservice [OPTIONS] [COMMAND]
+
+Options:
+  --tenant <tenant>  Tenant of the admin command
+  --verify           Check customers of all tenants at startup
+  --quarantine       Check customers at startup, and move invalid ones
+                     into the quarantine pack of their tenant
+  --migrate-from <file>
+                     Before serving, store the customers of a legacy
+                     CSV or JSON dump with their IDs, if the storage of
+                     the tenant is empty
+
+Commands:
+  serve            Start gRPC server (default)
+  list             List customers
+  show <id>        Show customer as YAML
+  import <file> [--upsert-by external-id|tax-number]
+                   Import customers from YAML file, existing IDs are skipped,
+                   or with --upsert-by customers matched by the key are
+                   updated, the others are created with new IDs
+  export [file]    Export customers as YAML to file or stdout
+  verify           Check customers against the current validation rules
+  audit            Show the audit trail
+  restore <file> [--newer-wins]
+                   Restore customers from a YAML export, asks which version
+                   to keep for changed customers, or keeps the newer one
+  seed <count> [--seed <n>]
+                   Generate fake customers for staging and load tests,
+                   the same seed generates the same customers";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  Serve,
+  List,
+  Show(u32),
+  // Import file, upsert key
+  Import(PathBuf, Option<ImportKey>),
+  Export(Option<PathBuf>),
+  Verify,
+  Audit,
+  // Backup file, newer wins instead of asking
+  Restore(PathBuf, bool),
+  // Customer count, random seed
+  Seed(usize, u64),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+  pub tenant: String,
+  pub command: Command,
+  // Startup integrity check
+  pub verify: bool,
+  pub quarantine: bool,
+  // Legacy dump migrated before serving
+  pub migrate_from: Option<PathBuf>,
+}
+
+impl Args {
+  // Writes the data directory, so it needs the instance lock
+  pub fn writes(&self) -> bool {
+    self.quarantine
+      || matches!(
+        self.command,
+        Command::Serve | Command::Import(..) | Command::Restore(..) | Command::Seed(..)
+      )
+  }
+}
+
+// Parse command line arguments
+// First item is expected to be the program name
+pub fn parse(args: impl Iterator<Item = String>) -> Result<Args, String> {
+  let mut tenant = DEFAULT_TENANT.to_string();
+  let (mut verify, mut quarantine) = (false, false);
+  let mut migrate_from = None;
+  let mut rest: Vec<String> = Vec::new();
+  let mut args = args.skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--tenant" => {
+        tenant = args.next().ok_or("Missing tenant after --tenant")?;
+        validate_tenant(&tenant).map_err(|e| e.to_string())?;
+      }
+      "--verify" => verify = true,
+      "--quarantine" => {
+        verify = true;
+        quarantine = true;
+      }
+      "--migrate-from" => {
+        migrate_from = Some(PathBuf::from(
+          args.next().ok_or("Missing file after --migrate-from")?,
+        ));
+      }
+      _ => rest.push(arg),
+    }
+  }
+  let rest = rest.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
+  let command = match rest.as_slice() {
+    [] | ["serve"] => Command::Serve,
+    ["list"] => Command::List,
+    ["show", id] => Command::Show(id.parse().map_err(|_| format!("Wrong ID: {}", id))?),
+    ["import", file] => Command::Import(PathBuf::from(file), None),
+    ["import", file, "--upsert-by", key] => {
+      let key = match *key {
+        "external-id" => ImportKey::ExternalId,
+        "tax-number" => ImportKey::TaxNumber,
+        _ => return Err(format!("Unknown upsert key: {}", key)),
+      };
+      Command::Import(PathBuf::from(file), Some(key))
+    }
+    ["export"] => Command::Export(None),
+    ["export", file] => Command::Export(Some(PathBuf::from(file))),
+    ["verify"] => Command::Verify,
+    ["audit"] => Command::Audit,
+    ["restore", file] => Command::Restore(PathBuf::from(file), false),
+    ["restore", file, "--newer-wins"] => Command::Restore(PathBuf::from(file), true),
+    ["seed", count] => Command::Seed(parse_number(count)?, 1),
+    ["seed", count, "--seed", seed] => Command::Seed(parse_number(count)?, parse_number(seed)?),
+    _ => return Err(format!("Unknown command: {}", rest.join(" "))),
+  };
+  if migrate_from.is_some() && command != Command::Serve {
+    return Err("--migrate-from only works with serve".to_string());
+  }
+  Ok(Args {
+    tenant,
+    command,
+    verify,
+    quarantine,
+    migrate_from,
+  })
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+  s.parse().map_err(|_| format!("Wrong number: {}", s))
+}
+
+// Run admin command
+// Changes are written to the shadow storage too, if there is one
+pub fn run(
+  data_dir: &Path,
+  backend: Backend,
+  shadow: Option<Backend>,
+  args: Args,
+  policy: &ValidationPolicy,
+) -> ServiceResult<()> {
+  let mut db = open_db(data_dir, &args.tenant, backend, shadow)?;
+  let mut customers = db.iter().cloned().collect::<Vec<Customer>>();
+  customers.sort_by_key(|c| c.id);
+  match args.command {
+    Command::Serve => unreachable!("serve is handled by main"),
+    Command::List => {
+      for c in customers {
+        println!(
+          "{}\t{}\t{:?}\t{}\t{}\t{}",
+          c.id,
+          c.name,
+          c.kind,
+          c.tax_number.map(|t| t.to_string()).unwrap_or_default(),
+          c.email,
+          c.phone
+        );
+      }
+    }
+    Command::Show(id) => {
+      let customer = db.find_id(&id)?;
+      print!("{}", to_yaml(customer)?);
+    }
+    Command::Import(path, Some(key)) => {
+      let content = std::fs::read_to_string(&path)?;
+      let imported: Vec<Customer> = serde_yaml::from_str(&content)
+        .map_err(|e| ServiceError::bad_request(&format!("Wrong import file: {}", e)))?;
+      let rows = imported.into_iter().map(ImportRow::from).collect();
+      let next_id = customers.last().map_or(0, |c| c.id) + 1;
+      let res = import::run(&mut db, key, rows, policy, 0, next_id, false)?;
+      for r in &res {
+        println!(
+          "{}\t{:?}\t{}\t{}",
+          r.row + 1,
+          r.action,
+          r.customer_id,
+          r.error.as_ref().map(|e| e.to_string()).unwrap_or_default()
+        );
+      }
+      let count = |action| res.iter().filter(|r| r.action == action).count();
+      println!(
+        "Created: {}, updated: {}, skipped: {}, errors: {}",
+        count(RowAction::Created),
+        count(RowAction::Updated),
+        count(RowAction::Skipped),
+        count(RowAction::Error)
+      );
+    }
+    Command::Import(path, None) => {
+      let content = std::fs::read_to_string(&path)?;
+      let imported: Vec<Customer> = serde_yaml::from_str(&content)
+        .map_err(|e| ServiceError::bad_request(&format!("Wrong import file: {}", e)))?;
+      let (mut created, mut skipped) = (0, 0);
+      for customer in imported {
+        if db.check_id_available(&customer.id) {
+          db.insert(customer)?;
+          created += 1;
+        } else {
+          eprintln!("Customer ID {} already exists, skipped", customer.id);
+          skipped += 1;
+        }
+      }
+      println!("Imported: {}, skipped: {}", created, skipped);
+    }
+    Command::Export(path) => {
+      let yaml = to_yaml(&customers)?;
+      match path {
+        Some(path) => std::fs::write(path, yaml)?,
+        None => print!("{}", yaml),
+      }
+    }
+    Command::Verify => {
+      let invalid = verify(&customers, policy);
+      for (id, error) in &invalid {
+        println!("{}\t{}", id, error);
+      }
+      println!("Checked: {}, invalid: {}", customers.len(), invalid.len());
+      if !invalid.is_empty() {
+        return Err(ServiceError::internal_error(&format!(
+          "{} invalid customer(s) found",
+          invalid.len()
+        )));
+      }
+    }
+    Command::Audit => {
+      for e in AuditLog::new(data_dir).read(&args.tenant)? {
+        println!(
+          "{}\t{}\t{:?}\t{}",
+          e.date.to_rfc3339(),
+          e.customer_id,
+          e.action,
+          e.actor
+        );
+      }
+    }
+    Command::Restore(path, newer) => {
+      let backup = parse_backup(&std::fs::read_to_string(&path)?)?;
+      let audit = AuditLog::new(data_dir);
+      let mut lines = std::io::stdin().lock().lines();
+      let ask = |c: &RestoreConflict| {
+        println!(
+          "Customer {} changed: {} (backup: {}, current: {})",
+          c.customer_id,
+          c.fields.join(", "),
+          c.backup_modified.to_rfc3339(),
+          c.current_modified.to_rfc3339()
+        );
+        println!("Restore backup version? [y/N]");
+        matches!(lines.next(), Some(Ok(line)) if line.trim() == "y")
+      };
+      let actor = Some((&audit, args.tenant.as_str(), "restore:cli"));
+      let (report, _, _) = match newer {
+        true => restore(&mut db, backup, newer_wins, false, actor)?,
+        false => restore(&mut db, backup, ask, false, actor)?,
+      };
+      for (id, reason) in &report.skipped {
+        eprintln!("Customer ID {} skipped: {}", id, reason);
+      }
+      println!(
+        "Restored: {}, replaced: {}, kept: {}, skipped: {}",
+        report.restored_ids.len(),
+        report.replaced_ids.len(),
+        report.conflicts.iter().filter(|c| !c.took_backup).count(),
+        report.skipped.len()
+      );
+    }
+    Command::Seed(count, seed) => {
+      let first_id = customers.last().map(|c| c.id + 1).unwrap_or(1);
+      for customer in crate::seed::generate(count, seed, first_id) {
+        db.insert(customer)?;
+      }
+      println!("Seeded: {}, first ID: {}", count, first_id);
+    }
+  }
+  Ok(())
+}
+
+// Check customers against the current validation rules
+// Returns (customer ID, error) pairs
+pub fn verify(customers: &[Customer], policy: &ValidationPolicy) -> Vec<(u32, ServiceError)> {
+  customers
+    .iter()
+    .filter_map(|c| c.validate(policy).err().map(|e| (c.id, e)))
+    .collect()
+}
+
+fn to_yaml<T: serde::Serialize>(value: &T) -> ServiceResult<String> {
+  serde_yaml::to_string(value)
+    .map_err(|e| ServiceError::internal_error(&format!("YAML error: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(s: &str) -> Result<Args, String> {
+    parse(s.split_whitespace().map(|s| s.to_string()))
+  }
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(args("bin").unwrap().command, Command::Serve);
+    assert_eq!(args("bin serve").unwrap().command, Command::Serve);
+    assert_eq!(args("bin show 12").unwrap().command, Command::Show(12));
+    assert_eq!(
+      args("bin --tenant shop_a export").unwrap(),
+      Args {
+        tenant: "shop_a".to_string(),
+        command: Command::Export(None),
+        verify: false,
+        quarantine: false,
+        migrate_from: None,
+      }
+    );
+    let startup = args("bin --quarantine serve").unwrap();
+    assert!(startup.verify && startup.quarantine);
+    assert_eq!(startup.command, Command::Serve);
+    assert_eq!(
+      args("bin --tenant shop_a --migrate-from legacy.csv")
+        .unwrap()
+        .migrate_from,
+      Some(PathBuf::from("legacy.csv"))
+    );
+    assert!(args("bin --migrate-from legacy.csv list").is_err());
+    assert!(args("bin --migrate-from").is_err());
+    assert!(args("bin --verify").unwrap().verify);
+    assert_eq!(args("bin audit").unwrap().command, Command::Audit);
+    assert_eq!(
+      args("bin restore c.yaml --newer-wins").unwrap().command,
+      Command::Restore(PathBuf::from("c.yaml"), true)
+    );
+    assert_eq!(
+      args("bin import c.yaml --tenant shop_a").unwrap().command,
+      Command::Import(PathBuf::from("c.yaml"), None)
+    );
+    assert_eq!(
+      args("bin import c.yaml --upsert-by tax-number")
+        .unwrap()
+        .command,
+      Command::Import(PathBuf::from("c.yaml"), Some(ImportKey::TaxNumber))
+    );
+    assert!(args("bin import c.yaml --upsert-by id").is_err());
+    assert_eq!(
+      args("bin seed 100 --seed 7").unwrap().command,
+      Command::Seed(100, 7)
+    );
+    assert!(args("bin seed many").is_err());
+    assert!(args("bin show x").is_err());
+    assert!(args("bin --tenant").is_err());
+    assert!(args("bin --tenant ../x list").is_err());
+    assert!(args("bin delete 1").is_err());
+    // Commands writing the data directory
+    assert!(args("bin").unwrap().writes());
+    assert!(args("bin seed 10").unwrap().writes());
+    assert!(!args("bin export").unwrap().writes());
+    assert!(!args("bin --verify list").unwrap().writes());
+    assert!(args("bin --quarantine list").unwrap().writes());
+  }
+
+  #[test]
+  fn test_export_import() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = load_db(dir.path(), DEFAULT_TENANT).unwrap();
+    db.insert(Customer {
+      id: 1,
+      name: "Kiss Béla".to_string(),
+      ..Customer::default()
+    })
+    .unwrap();
+    let file = dir.path().join("export.yaml");
+    let export = |file: &Path| Args {
+      tenant: DEFAULT_TENANT.to_string(),
+      command: Command::Export(Some(file.to_path_buf())),
+      verify: false,
+      quarantine: false,
+      migrate_from: None,
+    };
+    run(
+      dir.path(),
+      Backend::VecPack,
+      None,
+      export(&file),
+      &ValidationPolicy::default(),
+    )
+    .unwrap();
+    let import = Args {
+      tenant: "shop_a".to_string(),
+      command: Command::Import(file.clone(), None),
+      verify: false,
+      quarantine: false,
+      migrate_from: None,
+    };
+    run(
+      dir.path(),
+      Backend::VecPack,
+      None,
+      import,
+      &ValidationPolicy::default(),
+    )
+    .unwrap();
+    let db = load_db(dir.path(), "shop_a").unwrap();
+    assert_eq!(db.find_id(&1).unwrap().name, "Kiss Béla");
+  }
+
+  #[test]
+  fn test_verify() {
+    let customers = vec![
+      Customer {
+        id: 1,
+        name: "Kiss Béla".to_string(),
+        ..Customer::default()
+      },
+      Customer {
+        id: 2,
+        name: "Nagy Anna".to_string(),
+        email: "nagy.anna".to_string(),
+        ..Customer::default()
+      },
+    ];
+    let invalid = verify(&customers, &ValidationPolicy::default());
+    assert_eq!(invalid.len(), 1);
+    assert_eq!(invalid[0].0, 2);
+  }
+}
@@ -0,0 +1,183 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Communication log
+//
+// Email and SMS services, and the staff, log what was sent to or
+// received from a customer, so every interaction can be seen in one
+// place. Only a short summary is kept, not the message itself.
+// Stored next to the customer storage of the tenant, in
+// communications.jsonl, one JSON entry per logged communication.
+
+use crate::customer::ContactChannel;
+use crate::prelude::*;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// Max summary length in characters
+const MAX_SUMMARY_LEN: usize = 1000;
+// Default and max number of communications returned
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+  // Sent to the customer
+  Outbound,
+  // Received from the customer
+  Inbound,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Communication {
+  pub date: DateTime<Utc>,
+  pub customer_id: u32,
+  pub channel: ContactChannel,
+  pub direction: Direction,
+  pub summary: String,
+  // User or service logging it
+  pub created_by: u32,
+}
+
+impl Communication {
+  pub fn new(
+    customer_id: u32,
+    channel: ContactChannel,
+    direction: Direction,
+    summary: &str,
+    created_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<Self> {
+    let summary = summary.trim();
+    if summary.is_empty() || summary.chars().count() > MAX_SUMMARY_LEN {
+      return Err(ServiceError::invalid_field(
+        "summary",
+        &format!("Az összefoglaló kötelező, max {} karakter", MAX_SUMMARY_LEN),
+      ));
+    }
+    Ok(Self {
+      date: now,
+      customer_id,
+      channel,
+      direction,
+      summary: summary.to_string(),
+      created_by,
+    })
+  }
+}
+
+// Communication log of every tenant
+pub struct CommunicationLog {
+  data_dir: PathBuf,
+}
+
+impl CommunicationLog {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+    }
+  }
+  fn path(&self, tenant: &str) -> PathBuf {
+    tenant_path(&self.data_dir, tenant).with_file_name("communications.jsonl")
+  }
+  // Append communication and sync it to disk
+  pub fn append(&self, tenant: &str, communication: &Communication) -> ServiceResult<()> {
+    let path = self.path(tenant);
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let line = serde_json::to_string(communication)
+      .map_err(|e| ServiceError::internal_error(&format!("Communication log error: {}", e)))?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+    Ok(())
+  }
+  // Communications of a customer, newest first, max limit of them
+  pub fn customer(
+    &self,
+    tenant: &str,
+    customer_id: u32,
+    limit: usize,
+  ) -> ServiceResult<Vec<Communication>> {
+    let path = self.path(tenant);
+    if !path.exists() {
+      return Ok(Vec::new());
+    }
+    let mut res = Vec::new();
+    for line in BufReader::new(std::fs::File::open(path)?).lines() {
+      let communication: Communication = serde_json::from_str(&line?).map_err(|e| {
+        ServiceError::internal_error(&format!("Broken communication log entry: {}", e))
+      })?;
+      if communication.customer_id == customer_id {
+        res.push(communication);
+      }
+    }
+    res.reverse();
+    res.truncate(limit);
+    Ok(res)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_communication_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let log = CommunicationLog::new(dir.path());
+    assert!(log.customer("", 3, 10).unwrap().is_empty());
+    let new = |customer_id: u32, summary: &str| {
+      Communication::new(
+        customer_id,
+        ContactChannel::Email,
+        Direction::Outbound,
+        summary,
+        9,
+        now,
+      )
+    };
+    assert!(new(3, " ").is_err());
+    assert!(new(3, &"x".repeat(MAX_SUMMARY_LEN + 1)).is_err());
+    for (customer_id, summary) in [
+      (3, "Számla elküldve"),
+      (4, "Hírlevél"),
+      (3, " Fizetési emlékeztető "),
+    ] {
+      log.append("", &new(customer_id, summary).unwrap()).unwrap();
+    }
+    let communications = log.customer("", 3, 10).unwrap();
+    assert_eq!(
+      communications
+        .iter()
+        .map(|c| c.summary.as_str())
+        .collect::<Vec<&str>>(),
+      ["Fizetési emlékeztető", "Számla elküldve"]
+    );
+    assert_eq!(communications[0].created_by, 9);
+    assert_eq!(log.customer("", 3, 1).unwrap().len(), 1);
+    // Tenants are separate
+    assert!(log.customer("shop_a", 3, 10).unwrap().is_empty());
+  }
+}
@@ -0,0 +1,316 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// gRPC response compression
+//
+// tonic 0.4 has no message compression, so responses are compressed
+// by wrapping the generated service. Responses are compressed when
+// the client accepts gzip or deflate in grpc-accept-encoding.
+// gRPC sets the compressed flag per message, so small messages
+// are sent as they are. Compressed requests are not supported.
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use hyper::body::{Bytes, HttpBody};
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::transport::{Body, NamedService};
+use tonic::Status;
+
+// Messages smaller than this are not compressed
+const MIN_COMPRESS_LEN: usize = 1024;
+
+// Length prefixed message header size
+// 1 byte compressed flag and 4 bytes message length
+const HEADER_LEN: usize = 5;
+
+const ACCEPT_ENCODING_HEADER: &str = "grpc-accept-encoding";
+const ENCODING_HEADER: &str = "grpc-encoding";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+  Gzip,
+  Deflate,
+}
+
+impl Encoding {
+  // Pick encoding from the grpc-accept-encoding header
+  // gzip is preferred
+  pub fn from_accept(header: Option<&str>) -> Option<Self> {
+    let accepted = header
+      .unwrap_or("")
+      .split(',')
+      .map(|e| e.trim().to_lowercase())
+      .collect::<Vec<String>>();
+    if accepted.iter().any(|e| e == "gzip") {
+      Some(Encoding::Gzip)
+    } else if accepted.iter().any(|e| e == "deflate") {
+      Some(Encoding::Deflate)
+    } else {
+      None
+    }
+  }
+  fn name(&self) -> &'static str {
+    match self {
+      Encoding::Gzip => "gzip",
+      Encoding::Deflate => "deflate",
+    }
+  }
+  fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let level = flate2::Compression::default();
+    match self {
+      Encoding::Gzip => {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(data)?;
+        encoder.finish()
+      }
+      Encoding::Deflate => {
+        let mut encoder = ZlibEncoder::new(Vec::new(), level);
+        encoder.write_all(data)?;
+        encoder.finish()
+      }
+    }
+  }
+}
+
+// Service compressing the responses of the inner service
+#[derive(Clone)]
+pub struct Compression<S> {
+  inner: S,
+}
+
+impl<S> Compression<S> {
+  pub fn new(inner: S) -> Self {
+    Self { inner }
+  }
+}
+
+impl<S: NamedService> NamedService for Compression<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for Compression<S>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>>,
+  S::Future: Send + 'static,
+{
+  type Response = http::Response<BoxBody>;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+    let encoding = Encoding::from_accept(
+      request
+        .headers()
+        .get(ACCEPT_ENCODING_HEADER)
+        .and_then(|v| v.to_str().ok()),
+    );
+    let response = self.inner.call(request);
+    Box::pin(async move {
+      let response = response.await?;
+      Ok(match encoding {
+        Some(encoding) => compress_response(response, encoding),
+        None => response,
+      })
+    })
+  }
+}
+
+fn compress_response(
+  mut response: http::Response<BoxBody>,
+  encoding: Encoding,
+) -> http::Response<BoxBody> {
+  response.headers_mut().insert(
+    ENCODING_HEADER,
+    http::HeaderValue::from_static(encoding.name()),
+  );
+  response.map(|body| BoxBody::new(CompressedBody::new(body, encoding)))
+}
+
+// Response body re-framing the messages of the inner body
+struct CompressedBody {
+  inner: BoxBody,
+  encoding: Encoding,
+  // Received bytes of the next message
+  buf: Vec<u8>,
+}
+
+impl CompressedBody {
+  fn new(inner: BoxBody, encoding: Encoding) -> Self {
+    Self {
+      inner,
+      encoding,
+      buf: Vec::new(),
+    }
+  }
+  // Take the next complete message from the buffer
+  fn next_message(&mut self) -> std::io::Result<Option<Bytes>> {
+    if self.buf.len() < HEADER_LEN {
+      return Ok(None);
+    }
+    let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+    if self.buf.len() < HEADER_LEN + len {
+      return Ok(None);
+    }
+    let message = self.buf.drain(..HEADER_LEN + len).collect::<Vec<u8>>();
+    let (header, payload) = message.split_at(HEADER_LEN);
+    // Already compressed, or too small to compress
+    if header[0] != 0 || len < MIN_COMPRESS_LEN {
+      return Ok(Some(Bytes::from(message)));
+    }
+    let compressed = self.encoding.compress(payload)?;
+    if compressed.len() >= len {
+      return Ok(Some(Bytes::from(message)));
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + compressed.len());
+    frame.push(1);
+    frame.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&compressed);
+    Ok(Some(Bytes::from(frame)))
+  }
+}
+
+impl HttpBody for CompressedBody {
+  type Data = Bytes;
+  type Error = Status;
+
+  fn poll_data(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    loop {
+      match self.next_message() {
+        Ok(Some(message)) => return Poll::Ready(Some(Ok(message))),
+        Ok(None) => (),
+        Err(error) => {
+          let msg = format!("Compression error: {}", error);
+          return Poll::Ready(Some(Err(Status::internal(msg))));
+        }
+      }
+      match Pin::new(&mut self.inner).poll_data(cx) {
+        Poll::Ready(Some(Ok(data))) => self.buf.extend_from_slice(&data),
+        Poll::Ready(Some(Err(error))) => return Poll::Ready(Some(Err(error))),
+        // Pass on an incomplete message as it is
+        Poll::Ready(None) if !self.buf.is_empty() => {
+          let rest = std::mem::take(&mut self.buf);
+          return Poll::Ready(Some(Ok(Bytes::from(rest))));
+        }
+        Poll::Ready(None) => return Poll::Ready(None),
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+  }
+
+  fn poll_trailers(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+    Pin::new(&mut self.inner).poll_trailers(cx)
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.buf.is_empty() && self.inner.is_end_stream()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flate2::read::{GzDecoder, ZlibDecoder};
+  use std::io::Read;
+
+  fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  // Split body into (compressed flag, payload) messages
+  fn messages(mut data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut res = Vec::new();
+    while !data.is_empty() {
+      let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+      res.push((data[0], data[HEADER_LEN..HEADER_LEN + len].to_vec()));
+      data = &data[HEADER_LEN + len..];
+    }
+    res
+  }
+
+  // Inner body is sent in small chunks, splitting the messages
+  async fn compress(data: Vec<u8>, encoding: Encoding) -> Vec<u8> {
+    let chunks = data
+      .chunks(7)
+      .map(|c| Ok::<Vec<u8>, std::io::Error>(c.to_vec()))
+      .collect::<Vec<_>>();
+    let body = BoxBody::map_from(Body::wrap_stream(tokio_stream::iter(chunks)));
+    hyper::body::to_bytes(CompressedBody::new(body, encoding))
+      .await
+      .unwrap()
+      .to_vec()
+  }
+
+  #[test]
+  fn test_encoding_from_accept() {
+    assert_eq!(
+      Encoding::from_accept(Some("identity, deflate, gzip")),
+      Some(Encoding::Gzip)
+    );
+    assert_eq!(
+      Encoding::from_accept(Some("deflate")),
+      Some(Encoding::Deflate)
+    );
+    assert_eq!(Encoding::from_accept(Some("identity")), None);
+    assert_eq!(Encoding::from_accept(None), None);
+  }
+
+  #[tokio::test]
+  async fn test_compressed_body() {
+    let large = "Kiss Béla, 6000 Kecskemét, Fő utca 1. ".repeat(100);
+    let small = b"Kiss Bela".to_vec();
+    let mut data = frame(large.as_bytes());
+    data.extend(frame(&small));
+    data.extend(frame(large.as_bytes()));
+
+    let res = messages(&compress(data.clone(), Encoding::Gzip).await);
+    assert_eq!(res.len(), 3);
+    // Small messages are not compressed
+    assert_eq!(res[1], (0, small));
+    for (flag, payload) in [&res[0], &res[2]] {
+      assert_eq!(*flag, 1);
+      assert!(payload.len() < large.len());
+      let mut decoded = String::new();
+      GzDecoder::new(&payload[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+      assert_eq!(decoded, large);
+    }
+
+    let res = messages(&compress(data, Encoding::Deflate).await);
+    let mut decoded = String::new();
+    ZlibDecoder::new(&res[0].1[..])
+      .read_to_string(&mut decoded)
+      .unwrap();
+    assert_eq!(decoded, large);
+  }
+}
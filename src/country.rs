@@ -0,0 +1,241 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+
+// Default country of customers
+pub const DEFAULT_COUNTRY: &str = "HU";
+
+// Address line order
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Layout {
+  // Location, street, zip (Hungarian postal standard)
+  Hungarian,
+  // Street, "zip location"
+  ZipFirst,
+  // Street, "location zip"
+  ZipLast,
+  // Street, location, zip
+  ZipLine,
+}
+
+struct Country {
+  code: &'static str,
+  name: &'static str,
+  // Zip formats, 9 is a digit, A is a letter
+  // Empty means zip is not validated
+  zip_formats: &'static [&'static str],
+  layout: Layout,
+}
+
+const COUNTRIES: [Country; 16] = [
+  country("HU", "HUNGARY", &["9999"], Layout::Hungarian),
+  country("AT", "AUSTRIA", &["9999"], Layout::ZipFirst),
+  country("SK", "SLOVAKIA", &["999 99", "99999"], Layout::ZipFirst),
+  country(
+    "CZ",
+    "CZECH REPUBLIC",
+    &["999 99", "99999"],
+    Layout::ZipFirst,
+  ),
+  country("RO", "ROMANIA", &["999999"], Layout::ZipFirst),
+  country("HR", "CROATIA", &["99999"], Layout::ZipFirst),
+  country("SI", "SLOVENIA", &["9999"], Layout::ZipFirst),
+  country("RS", "SERBIA", &["99999"], Layout::ZipFirst),
+  country("UA", "UKRAINE", &["99999"], Layout::ZipFirst),
+  country("DE", "GERMANY", &["99999"], Layout::ZipFirst),
+  country("PL", "POLAND", &["99-999"], Layout::ZipFirst),
+  country("IT", "ITALY", &["99999"], Layout::ZipFirst),
+  country("FR", "FRANCE", &["99999"], Layout::ZipFirst),
+  country(
+    "NL",
+    "NETHERLANDS",
+    &["9999 AA", "9999AA"],
+    Layout::ZipFirst,
+  ),
+  country(
+    "US",
+    "UNITED STATES",
+    &["99999", "99999-9999"],
+    Layout::ZipLast,
+  ),
+  country("GB", "UNITED KINGDOM", &[], Layout::ZipLine),
+];
+
+const fn country(
+  code: &'static str,
+  name: &'static str,
+  zip_formats: &'static [&'static str],
+  layout: Layout,
+) -> Country {
+  Country {
+    code,
+    name,
+    zip_formats,
+    layout,
+  }
+}
+
+fn find(code: &str) -> Option<&'static Country> {
+  COUNTRIES.iter().find(|c| c.code == code)
+}
+
+// Member states of the European Union
+const EU_COUNTRIES: [&str; 27] = [
+  "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GR", "HR", "HU", "IE", "IT",
+  "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+pub fn is_eu(code: &str) -> bool {
+  EU_COUNTRIES.contains(&code)
+}
+
+// Normalize country code
+// ISO 3166-1 alpha-2, empty means the default country
+pub fn normalize_country(code: &str) -> ServiceResult<String> {
+  let code = code.trim().to_uppercase();
+  if code.is_empty() {
+    return Ok(DEFAULT_COUNTRY.to_string());
+  }
+  if code.len() != 2 || !code.chars().all(|c| c.is_ascii_uppercase()) {
+    return Err(ServiceError::bad_request(&format!(
+      "Hibás országkód: {}. Kétbetűs ISO 3166 kód szükséges",
+      code
+    )));
+  }
+  Ok(code)
+}
+
+fn matches_format(zip: &str, format: &str) -> bool {
+  zip.chars().count() == format.chars().count()
+    && zip.chars().zip(format.chars()).all(|(z, f)| match f {
+      '9' => z.is_ascii_digit(),
+      'A' => z.is_ascii_alphabetic(),
+      _ => z == f,
+    })
+}
+
+// Check zip code format of the country
+// Skipped when zip is not provided or the country is unknown
+pub fn validate_zip(country: &str, zip: &str) -> ServiceResult<()> {
+  let zip = zip.trim();
+  match find(country) {
+    Some(c) if !zip.is_empty() && !c.zip_formats.is_empty() => {
+      if c.zip_formats.iter().any(|f| matches_format(zip, f)) {
+        Ok(())
+      } else {
+        Err(ServiceError::bad_request(&format!(
+          "Hibás irányítószám formátum ({}): {}",
+          c.code, zip
+        )))
+      }
+    }
+    _ => Ok(()),
+  }
+}
+
+// Format address as printed on invoices
+// Country name is added for foreign addresses
+pub fn format_address(country: &str, zip: &str, location: &str, street: &str) -> Vec<String> {
+  let (zip, location, street) = (zip.trim(), location.trim(), street.trim());
+  let join = |a: &str, b: &str| format!("{} {}", a, b).trim().to_string();
+  let layout = find(country).map_or(Layout::ZipFirst, |c| c.layout);
+  let mut lines = match layout {
+    Layout::Hungarian => vec![location.to_string(), street.to_string(), zip.to_string()],
+    Layout::ZipFirst => vec![street.to_string(), join(zip, location)],
+    Layout::ZipLast => vec![street.to_string(), join(location, zip)],
+    Layout::ZipLine => vec![street.to_string(), location.to_string(), zip.to_string()],
+  };
+  if country != DEFAULT_COUNTRY {
+    lines.push(find(country).map_or(country.to_string(), |c| c.name.to_string()));
+  }
+  lines.retain(|line| !line.is_empty());
+  lines
+}
+
+// Postal label lines, the name and the address
+// None if the address has no zip or location
+pub fn format_label(
+  name: &str,
+  country: &str,
+  zip: &str,
+  location: &str,
+  street: &str,
+) -> Option<Vec<String>> {
+  if zip.trim().is_empty() || location.trim().is_empty() {
+    return None;
+  }
+  let mut lines = vec![name.trim().to_string()];
+  lines.extend(format_address(country, zip, location, street));
+  Some(lines)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_normalize_country() {
+    assert_eq!(normalize_country("").unwrap(), "HU");
+    assert_eq!(normalize_country(" at ").unwrap(), "AT");
+    assert!(normalize_country("AUT").is_err());
+    assert!(normalize_country("A1").is_err());
+  }
+
+  #[test]
+  fn test_validate_zip() {
+    assert!(validate_zip("HU", "6000").is_ok());
+    assert!(validate_zip("HU", "60000").is_err());
+    assert!(validate_zip("SK", "931 01").is_ok());
+    assert!(validate_zip("SK", "93101").is_ok());
+    assert!(validate_zip("PL", "00-950").is_ok());
+    assert!(validate_zip("PL", "00950").is_err());
+    assert!(validate_zip("NL", "1234 AB").is_ok());
+    assert!(validate_zip("GB", "SW1A 1AA").is_ok());
+    assert!(validate_zip("XX", "anything").is_ok());
+    assert!(validate_zip("DE", "").is_ok());
+  }
+
+  #[test]
+  fn test_format_address() {
+    assert_eq!(
+      format_address("HU", "6000", "Kecskemét", "Fő utca 1."),
+      ["Kecskemét", "Fő utca 1.", "6000"]
+    );
+    assert_eq!(
+      format_address("AT", "1010", "Wien", "Stephansplatz 1"),
+      ["Stephansplatz 1", "1010 Wien", "AUSTRIA"]
+    );
+    assert_eq!(
+      format_address("US", "10001", "New York", "5th Avenue 1"),
+      ["5th Avenue 1", "New York 10001", "UNITED STATES"]
+    );
+    assert_eq!(
+      format_address("XX", "", "Somewhere", ""),
+      ["Somewhere", "XX"]
+    );
+  }
+
+  #[test]
+  fn test_format_label() {
+    assert_eq!(
+      format_label("Kiss Béla ", "HU", "6000", "Kecskemét", "Fő utca 1.").unwrap(),
+      ["Kiss Béla", "Kecskemét", "Fő utca 1.", "6000"]
+    );
+    assert_eq!(format_label("Kiss Béla", "HU", "", "Kecskemét", ""), None);
+  }
+}
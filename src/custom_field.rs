@@ -0,0 +1,427 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Custom fields
+//
+// Deployments need extra customer attributes, e.g. the size of a
+// greenhouse or the preferred delivery day. Admins define them per
+// tenant, with a type their values are checked against. Values are
+// kept on the customer as text, in the canonical form of their type,
+// e.g. 12.5 or 2021-03-01. Definitions are stored next to the customer
+// storage of the tenant.
+
+use crate::prelude::*;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+// Max custom fields per tenant
+pub const MAX_CUSTOM_FIELDS: usize = 50;
+// Max field name length
+const MAX_NAME_LEN: usize = 40;
+// Max value length in characters
+const MAX_VALUE_LEN: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CustomFieldType {
+  Text,
+  Number,
+  Bool,
+  // YYYY-MM-DD
+  Date,
+  // One of the defined choices
+  Choice,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CustomFieldDef {
+  // Lowercase letters, digits and _, e.g. greenhouse_size
+  pub name: String,
+  // Shown by the clients
+  pub label: String,
+  pub field_type: CustomFieldType,
+  // Allowed values of choice fields
+  pub choices: Vec<String>,
+  pub created_by: u32,
+  pub date_created: DateTime<Utc>,
+}
+
+impl CustomFieldDef {
+  // Check value by the field type, returns it in canonical form
+  pub fn normalize(&self, value: &str) -> ServiceResult<String> {
+    let value = value.trim();
+    if value.chars().count() > MAX_VALUE_LEN {
+      return Err(ServiceError::bad_request(&format!(
+        "Az egyedi mező értéke max {} karakter lehet",
+        MAX_VALUE_LEN
+      )));
+    }
+    let res = match self.field_type {
+      CustomFieldType::Text => Some(value.to_string()),
+      // Decimal comma is accepted as well
+      CustomFieldType::Number => value
+        .replace(',', ".")
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+        .map(|n| n.to_string()),
+      CustomFieldType::Bool => match value.to_lowercase().as_str() {
+        "true" => Some("true".to_string()),
+        "false" => Some("false".to_string()),
+        _ => None,
+      },
+      CustomFieldType::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .map(|d| d.format("%Y-%m-%d").to_string()),
+      CustomFieldType::Choice => self
+        .choices
+        .iter()
+        .find(|c| c.to_lowercase() == value.to_lowercase())
+        .cloned(),
+    };
+    res.ok_or_else(|| {
+      ServiceError::bad_request(&format!(
+        "Hibás érték a(z) {} egyedi mezőhöz: {}",
+        self.name, value
+      ))
+    })
+  }
+}
+
+// Set custom field values of a customer
+// Empty values remove the field. Every field must be defined.
+pub fn set_values(
+  defs: &[CustomFieldDef],
+  current: &mut BTreeMap<String, String>,
+  values: HashMap<String, String>,
+) -> ServiceResult<()> {
+  let mut violations = Violations::default();
+  let mut res = current.clone();
+  for (name, value) in values {
+    let field = format!("values.{}", name);
+    let def = match defs.iter().find(|d| d.name == name) {
+      Some(def) => def,
+      None => {
+        violations.add(&field, &format!("Ismeretlen egyedi mező: {}", name));
+        continue;
+      }
+    };
+    if value.trim().is_empty() {
+      res.remove(&name);
+      continue;
+    }
+    match def.normalize(&value) {
+      Ok(value) => {
+        res.insert(name, value);
+      }
+      Err(error) => violations.add(&field, &error.to_string()),
+    }
+  }
+  violations.into_result()?;
+  *current = res;
+  Ok(())
+}
+
+fn validate_name(name: &str) -> ServiceResult<()> {
+  let valid = name.len() <= MAX_NAME_LEN
+    && name.starts_with(|c: char| c.is_ascii_lowercase())
+    && name
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+  match valid {
+    true => Ok(()),
+    false => Err(ServiceError::invalid_field(
+      "name",
+      &format!(
+        "Az egyedi mező neve kisbetűvel kezdődik, csak kisbetűt, számot és _ jelet tartalmazhat, max {} karakter",
+        MAX_NAME_LEN
+      ),
+    )),
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CustomFieldData {
+  fields: Vec<CustomFieldDef>,
+}
+
+// Custom field definitions of every tenant
+pub struct CustomFields {
+  data_dir: PathBuf,
+  packs: HashMap<String, Pack<CustomFieldData>>,
+}
+
+impl CustomFields {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+      packs: HashMap::new(),
+    }
+  }
+  // Definitions pack of a tenant, loaded on first use
+  fn pack(&mut self, tenant: &str) -> ServiceResult<&mut Pack<CustomFieldData>> {
+    if !self.packs.contains_key(tenant) {
+      let path = tenant_path(&self.data_dir, tenant).with_file_name("custom_fields");
+      let pack = Pack::load_or_init(path, "custom_fields")?;
+      self.packs.insert(tenant.to_string(), pack);
+    }
+    Ok(self.packs.get_mut(tenant).expect("Loaded above"))
+  }
+  // Define new field, or change the label and choices of an existing one
+  // The type of a field cannot be changed, as its values are stored
+  #[allow(clippy::too_many_arguments)]
+  pub fn define(
+    &mut self,
+    tenant: &str,
+    name: &str,
+    label: &str,
+    field_type: CustomFieldType,
+    choices: Vec<String>,
+    created_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<CustomFieldDef> {
+    let name = name.trim();
+    validate_name(name)?;
+    let mut unique = Vec::new();
+    for choice in choices.iter().map(|c| c.trim()).filter(|c| !c.is_empty()) {
+      if !unique
+        .iter()
+        .any(|c: &String| c.to_lowercase() == choice.to_lowercase())
+      {
+        unique.push(choice.to_string());
+      }
+    }
+    match (field_type, unique.is_empty()) {
+      (CustomFieldType::Choice, true) => {
+        return Err(ServiceError::invalid_field(
+          "choices",
+          "Választó mezőhöz legalább egy választás megadása kötelező",
+        ))
+      }
+      (CustomFieldType::Choice, false) | (_, true) => (),
+      (_, false) => {
+        return Err(ServiceError::invalid_field(
+          "choices",
+          "Választások csak választó mezőhöz adhatók meg",
+        ))
+      }
+    }
+    let mut def = CustomFieldDef {
+      name: name.to_string(),
+      label: match label.trim() {
+        "" => name.to_string(),
+        label => label.to_string(),
+      },
+      field_type,
+      choices: unique,
+      created_by,
+      date_created: now,
+    };
+    let pack = self.pack(tenant)?;
+    let fields = &pack.unpack().fields;
+    match fields.iter().find(|d| d.name == def.name) {
+      Some(existing) if existing.field_type != field_type => {
+        return Err(ServiceError::failed_precondition(&format!(
+          "A(z) {} egyedi mező típusa nem módosítható",
+          def.name
+        )))
+      }
+      Some(existing) => {
+        def.created_by = existing.created_by;
+        def.date_created = existing.date_created;
+      }
+      None if fields.len() >= MAX_CUSTOM_FIELDS => {
+        return Err(ServiceError::resource_exhausted(&format!(
+          "Max {} egyedi mező adható meg",
+          MAX_CUSTOM_FIELDS
+        )))
+      }
+      None => (),
+    }
+    pack.update(
+      |data| match data.fields.iter_mut().find(|d| d.name == def.name) {
+        Some(existing) => *existing = def.clone(),
+        None => data.fields.push(def.clone()),
+      },
+    )?;
+    Ok(def)
+  }
+  // Definitions, in definition order
+  pub fn list(&mut self, tenant: &str) -> ServiceResult<Vec<CustomFieldDef>> {
+    Ok(self.pack(tenant)?.unpack().fields.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_normalize() {
+    let def = |field_type: CustomFieldType, choices: &[&str]| CustomFieldDef {
+      name: "x".to_string(),
+      label: "X".to_string(),
+      field_type,
+      choices: choices.iter().map(|c| c.to_string()).collect(),
+      created_by: 1,
+      date_created: Utc::now(),
+    };
+    let number = def(CustomFieldType::Number, &[]);
+    assert_eq!(number.normalize(" 12,50 ").unwrap(), "12.5");
+    assert_eq!(number.normalize("100").unwrap(), "100");
+    assert!(number.normalize("sok").is_err());
+    assert!(number.normalize("inf").is_err());
+    let date = def(CustomFieldType::Date, &[]);
+    assert_eq!(date.normalize("2021-03-01").unwrap(), "2021-03-01");
+    assert!(date.normalize("2021-02-30").is_err());
+    let bool = def(CustomFieldType::Bool, &[]);
+    assert_eq!(bool.normalize("TRUE").unwrap(), "true");
+    assert!(bool.normalize("igen").is_err());
+    let choice = def(CustomFieldType::Choice, &["Hétfő", "Kedd"]);
+    assert_eq!(choice.normalize("hétfő").unwrap(), "Hétfő");
+    assert!(choice.normalize("Szerda").is_err());
+    let text = def(CustomFieldType::Text, &[]);
+    assert!(text.normalize(&"x".repeat(MAX_VALUE_LEN + 1)).is_err());
+  }
+
+  #[test]
+  fn test_custom_fields() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let mut fields = CustomFields::new(dir.path());
+    for name in ["", "Size", "1size", "greenhouse size"] {
+      assert!(fields
+        .define("", name, "", CustomFieldType::Number, vec![], 1, now)
+        .is_err());
+    }
+    let size = fields
+      .define(
+        "",
+        "greenhouse_size",
+        "",
+        CustomFieldType::Number,
+        vec![],
+        1,
+        now,
+      )
+      .unwrap();
+    assert_eq!(size.label, "greenhouse_size");
+    // Choices are required for choice fields only
+    assert!(fields
+      .define("", "day", "Nap", CustomFieldType::Choice, vec![], 1, now)
+      .is_err());
+    assert!(fields
+      .define(
+        "",
+        "note",
+        "",
+        CustomFieldType::Text,
+        vec!["a".to_string()],
+        1,
+        now
+      )
+      .is_err());
+    let day = fields
+      .define(
+        "",
+        "day",
+        "Szállítási nap",
+        CustomFieldType::Choice,
+        vec![
+          "Hétfő".to_string(),
+          "hétfő".to_string(),
+          " Kedd ".to_string(),
+        ],
+        1,
+        now,
+      )
+      .unwrap();
+    assert_eq!(day.choices, ["Hétfő", "Kedd"]);
+    // Label and choices can be changed, the type cannot
+    let later = now + chrono::Duration::days(1);
+    fields
+      .define(
+        "",
+        "greenhouse_size",
+        "Üvegház (m2)",
+        CustomFieldType::Number,
+        vec![],
+        2,
+        later,
+      )
+      .unwrap();
+    assert!(fields
+      .define(
+        "",
+        "greenhouse_size",
+        "",
+        CustomFieldType::Text,
+        vec![],
+        2,
+        later
+      )
+      .is_err());
+    // Tenants are separate, definitions survive restart
+    assert!(fields.list("shop_a").unwrap().is_empty());
+    let mut fields = CustomFields::new(dir.path());
+    let defs = fields.list("").unwrap();
+    assert_eq!(defs.len(), 2);
+    assert_eq!(
+      (
+        defs[0].label.as_str(),
+        defs[0].created_by,
+        defs[0].date_created
+      ),
+      ("Üvegház (m2)", 1, now)
+    );
+    // Values
+    let mut values = BTreeMap::new();
+    let set = |values: &mut BTreeMap<String, String>, changes: &[(&str, &str)]| {
+      let changes = changes
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+      set_values(&defs, values, changes)
+    };
+    set(
+      &mut values,
+      &[("greenhouse_size", "120,5"), ("day", "kedd")],
+    )
+    .unwrap();
+    assert_eq!(values["greenhouse_size"], "120.5");
+    assert_eq!(values["day"], "Kedd");
+    // Nothing is set if a value is invalid
+    match set(
+      &mut values,
+      &[
+        ("day", "Hétfő"),
+        ("color", "zöld"),
+        ("greenhouse_size", "x"),
+      ],
+    ) {
+      Err(ServiceError::InvalidFields(violations)) => assert_eq!(violations.len(), 2),
+      _ => panic!("Invalid values are set"),
+    }
+    assert_eq!(values["day"], "Kedd");
+    set(&mut values, &[("day", " ")]).unwrap();
+    assert!(!values.contains_key("day"));
+  }
+}
@@ -15,14 +15,163 @@
 // You should have received a copy of the GNU General Public License
 // along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::country::*;
+use crate::display_name;
+use crate::policy::*;
 use crate::prelude::ServiceError::*;
 use crate::prelude::*;
+use crate::sanitize::{self, Sanitizer};
+use crate::search;
 use crate::taxnumber::*;
+use crate::vat::{self, VatPeriod, VatTreatment};
 use chrono::prelude::*;
 use packman::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
+// Customer kind
+// Invoicing rules differ per kind
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum CustomerKind {
+  #[default]
+  Private,
+  Company,
+  Institution,
+}
+
+// How the customer wants to be reached
+// by the notification service
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ContactChannel {
+  Email,
+  Phone,
+  Sms,
+  Post,
+}
+
+// Attached document kind
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DocumentKind {
+  Contract,
+  IdScan,
+  Avatar,
+  Other,
+}
+
+// Document linked to a customer
+// Document content is stored in the object storage,
+// here we only keep its key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+  pub id: u32,
+  pub document_kind: DocumentKind,
+  pub storage_key: String,
+  pub uploaded_by: u32,
+  pub date_uploaded: DateTime<Utc>,
+}
+
+// Deactivation reason code
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ReasonCode {
+  Debt,
+  Fraud,
+  Duplicate,
+  CustomerRequest,
+  Other,
+}
+
+// Customer activation or deactivation
+// reason is set only for deactivations
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatusChange {
+  pub active: bool,
+  pub reason: Option<ReasonCode>,
+  pub comment: String,
+  pub changed_by: u32,
+  pub date_changed: DateTime<Utc>,
+}
+
+// Customer activity reported by other services
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ActivityKind {
+  Purchase,
+  Invoice,
+  Other,
+}
+
+// Invoice address override
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InvoiceAddress {
+  pub zip: String,
+  pub location: String,
+  pub street: String,
+}
+
+// Privacy restrictions requested by the customer
+// Stored only, the services using the customer data
+// look them up with GetPrivacyFlags
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PrivacyFlags {
+  // Must not appear on event photos
+  pub do_not_photograph: bool,
+  // Must not be profiled, e.g. for marketing segments
+  pub do_not_profile: bool,
+  // Data can be stored, but not processed otherwise
+  pub processing_restricted: bool,
+}
+
+impl PrivacyFlags {
+  // Most restrictive flags of both
+  pub fn union(self, other: PrivacyFlags) -> PrivacyFlags {
+    PrivacyFlags {
+      do_not_photograph: self.do_not_photograph || other.do_not_photograph,
+      do_not_profile: self.do_not_profile || other.do_not_profile,
+      processing_restricted: self.processing_restricted || other.processing_restricted,
+    }
+  }
+}
+
+// Replaced contact detail kind
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ContactKind {
+  Email,
+  Phone,
+}
+
+// Email or phone number replaced by an update
+// Kept to find customers by their old contact details
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PreviousContact {
+  pub kind: ContactKind,
+  pub value: String,
+  pub date_replaced: DateTime<Utc>,
+}
+
+// Name of anonymized customers
+pub const ANONYMIZED_NAME: &str = "Anonimizált ügyfél";
+
+// Max number of aliases per customer
+pub const MAX_ALIASES: usize = 10;
+
+// Max number of tags per customer, and max length
+// of a tag and a group name
+pub const MAX_TAGS: usize = 50;
+pub const MAX_TAG_LEN: usize = 50;
+
+// Max number of customers updated by one bulk request
+pub const MAX_BULK_UPDATE: usize = 10_000;
+
+// Max number of previous contacts kept per customer
+// The oldest ones are dropped first
+pub const MAX_PREVIOUS_CONTACTS: usize = 10;
+
+// Max default discount percent
+pub const MAX_DISCOUNT_PERCENT: u32 = 100;
+
+// Plain fields are derived for JSON, binary records are versioned,
+// see the Serialize and Deserialize implementations below
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub struct Customer {
   pub id: u32,
   pub name: String,
@@ -32,12 +181,360 @@ pub struct Customer {
   pub address_zip: String,
   pub address_location: String,
   pub address_street: String,
+  pub country: String,
   pub date_created: DateTime<Utc>,
   pub created_by: u32,
+  pub kind: CustomerKind,
+  pub attachments: Vec<Attachment>,
+  pub loyalty_card_id: Option<String>,
+  // ID of the customer in an external system, e.g. the webshop
+  // Unique in the tenant, set by imports
+  pub external_id: Option<String>,
+  pub active: bool,
+  pub status_history: Vec<StatusChange>,
+  pub last_activity: Option<DateTime<Utc>>,
+  pub last_purchase_at: Option<DateTime<Utc>>,
+  pub last_invoice_at: Option<DateTime<Utc>>,
+  // Invoices are issued under the main name and address,
+  // unless they are overridden
+  pub invoice_name: Option<String>,
+  pub invoice_address: Option<InvoiceAddress>,
+  // Set by the customer db on every update
+  pub last_modified: DateTime<Utc>,
+  // User who made the last change, the creator until the first update
+  // 0 if unknown, e.g. for self-service changes, reported activity
+  // and changes made by the service itself
+  // Set by the customer db on every update
+  pub last_modified_by: u32,
+  // Oldest first
+  pub previous_contacts: Vec<PreviousContact>,
+  // Created with QuickCreate, required fields are not checked
+  // until the customer is updated with the full details
+  pub incomplete_profile: bool,
+  // Set when personal data is removed by a retention rule
+  pub anonymized_at: Option<DateTime<Utc>>,
+  // Nicknames the customer is known by
+  pub aliases: Vec<String>,
+  // Customer we issue invoices to
+  // In strict invoicing mode it must be ready for invoicing
+  pub invoiceable: bool,
+  // Its contact detail must be set
+  pub preferred_contact: Option<ContactChannel>,
+  // Marketing segments, lowercase
+  pub tags: Vec<String>,
+  pub group: Option<String>,
+  // Normalized name used in listings
+  // Set by the customer db on every update
+  pub display_name: String,
+  // Display name in Hungarian alphabetical order
+  // Set by the customer db on every update
+  pub sort_key: String,
+  pub privacy: PrivacyFlags,
+  // Special VAT treatment periods, by start date
+  pub vat_periods: Vec<VatPeriod>,
+  // Values of the custom fields of the tenant, in canonical form
+  pub custom_fields: BTreeMap<String, String>,
+  // Discount applied to the carts of the customer, 0-100
+  pub default_discount_percent: u32,
+  // User who set the discount, 0 if it was never set
+  pub discount_set_by: u32,
+  pub discount_set_at: Option<DateTime<Utc>>,
+}
+
+// Binary customer records
+//
+// Storages, replication and the event outbox write customers with
+// bincode, which stores no field names, so a record can only be read
+// with the layout it was written with. Binary records start with
+// SCHEMA_MAGIC and the schema version, followed by the fields of that
+// version. JSON uses the plain fields.
+// Before changing the fields of Customer (or of the types it stores),
+// freeze the current layout as CustomerV<SCHEMA_VERSION> with a From
+// into Customer, decode it in Versioned, then bump SCHEMA_VERSION.
+const SCHEMA_MAGIC: u32 = 0x475a_4355;
+const SCHEMA_VERSION: u32 = 2;
+
+impl Serialize for Customer {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match serializer.is_human_readable() {
+      true => Customer::serialize(self, serializer),
+      false => (SCHEMA_MAGIC, SCHEMA_VERSION, Fields(self)).serialize(serializer),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Customer {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    match deserializer.is_human_readable() {
+      true => Customer::deserialize(deserializer),
+      false => deserializer.deserialize_tuple(3, Versioned),
+    }
+  }
+}
+
+// Fields of the current schema version
+struct Fields<'a>(&'a Customer);
+
+impl Serialize for Fields<'_> {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    Customer::serialize(self.0, serializer)
+  }
+}
+
+struct CurrentFields(Customer);
+
+impl<'de> Deserialize<'de> for CurrentFields {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    Customer::deserialize(deserializer).map(CurrentFields)
+  }
+}
+
+// Binary record of any known schema version
+struct Versioned;
+
+impl<'de> serde::de::Visitor<'de> for Versioned {
+  type Value = Customer;
+  fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str("versioned customer record")
+  }
+  fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Customer, A::Error> {
+    use serde::de::Error;
+    let truncated = || A::Error::custom("Truncated customer record");
+    if seq.next_element::<u32>()?.ok_or_else(truncated)? != SCHEMA_MAGIC {
+      return Err(A::Error::custom("Customer record without schema version"));
+    }
+    match seq.next_element::<u32>()?.ok_or_else(truncated)? {
+      SCHEMA_VERSION => Ok(
+        seq
+          .next_element::<CurrentFields>()?
+          .ok_or_else(truncated)?
+          .0,
+      ),
+      version => Err(A::Error::custom(format!(
+        "Unknown customer schema version {}",
+        version
+      ))),
+    }
+  }
+}
+
+// Customer ID of a binary record without decoding it
+// The ID is the first field of every layout
+pub fn record_id(data: &[u8]) -> Option<u32> {
+  let u32_at = |i: usize| {
+    let bytes = data.get(i..i + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  };
+  match u32_at(0)? {
+    SCHEMA_MAGIC => u32_at(8),
+    id => Some(id),
+  }
+}
+
+// Decode a customer record written without schema version
+// The layout is the one that reads every byte of the record,
+// so a record of an unknown layout fails instead of losing fields.
+pub fn decode_unversioned(data: &[u8]) -> Option<Customer> {
+  use bincode::Options;
+  let options = || {
+    bincode::DefaultOptions::new()
+      .with_fixint_encoding()
+      .reject_trailing_bytes()
+  };
+  options()
+    .deserialize::<CustomerV1>(data)
+    .map(Customer::from)
+    .or_else(|_| {
+      options()
+        .deserialize::<CustomerOld>(data)
+        .map(Customer::from)
+    })
+    .ok()
+}
+
+// Decode a batch of customer records written without schema version
+// Batches were added after the baseline layout
+pub fn decode_unversioned_batch(data: &[u8]) -> Option<Vec<Customer>> {
+  use bincode::Options;
+  bincode::DefaultOptions::new()
+    .with_fixint_encoding()
+    .reject_trailing_bytes()
+    .deserialize::<Vec<CustomerV1>>(data)
+    .map(|batch| batch.into_iter().map(Customer::from).collect())
+    .ok()
+}
+
+// Customer as it was stored before schema versions
+#[derive(Serialize, Deserialize)]
+pub struct CustomerV1 {
+  id: u32,
+  name: String,
+  email: String,
+  phone: String,
+  tax_number: Option<TaxNumber>,
+  address_zip: String,
+  address_location: String,
+  address_street: String,
+  country: String,
+  date_created: DateTime<Utc>,
+  created_by: u32,
+  kind: CustomerKind,
+  attachments: Vec<Attachment>,
+  loyalty_card_id: Option<String>,
+  external_id: Option<String>,
+  active: bool,
+  status_history: Vec<StatusChange>,
+  last_activity: Option<DateTime<Utc>>,
+  last_purchase_at: Option<DateTime<Utc>>,
+  last_invoice_at: Option<DateTime<Utc>>,
+  invoice_name: Option<String>,
+  invoice_address: Option<InvoiceAddress>,
+  last_modified: DateTime<Utc>,
+  last_modified_by: u32,
+  previous_contacts: Vec<PreviousContact>,
+  incomplete_profile: bool,
+  anonymized_at: Option<DateTime<Utc>>,
+  aliases: Vec<String>,
+  invoiceable: bool,
+  preferred_contact: Option<ContactChannel>,
+  tags: Vec<String>,
+  group: Option<String>,
+  display_name: String,
+  sort_key: String,
+  privacy: PrivacyFlags,
+  vat_periods: Vec<VatPeriod>,
+  custom_fields: BTreeMap<String, String>,
+  default_discount_percent: u32,
+  discount_set_by: u32,
+  discount_set_at: Option<DateTime<Utc>>,
+}
+
+impl From<CustomerV1> for Customer {
+  fn from(c: CustomerV1) -> Self {
+    Self {
+      id: c.id,
+      name: c.name,
+      email: c.email,
+      phone: c.phone,
+      tax_number: c.tax_number,
+      address_zip: c.address_zip,
+      address_location: c.address_location,
+      address_street: c.address_street,
+      country: c.country,
+      date_created: c.date_created,
+      created_by: c.created_by,
+      kind: c.kind,
+      attachments: c.attachments,
+      loyalty_card_id: c.loyalty_card_id,
+      external_id: c.external_id,
+      active: c.active,
+      status_history: c.status_history,
+      last_activity: c.last_activity,
+      last_purchase_at: c.last_purchase_at,
+      last_invoice_at: c.last_invoice_at,
+      invoice_name: c.invoice_name,
+      invoice_address: c.invoice_address,
+      last_modified: c.last_modified,
+      last_modified_by: c.last_modified_by,
+      previous_contacts: c.previous_contacts,
+      incomplete_profile: c.incomplete_profile,
+      anonymized_at: c.anonymized_at,
+      aliases: c.aliases,
+      invoiceable: c.invoiceable,
+      preferred_contact: c.preferred_contact,
+      tags: c.tags,
+      group: c.group,
+      display_name: c.display_name,
+      sort_key: c.sort_key,
+      privacy: c.privacy,
+      vat_periods: c.vat_periods,
+      custom_fields: c.custom_fields,
+      default_discount_percent: c.default_discount_percent,
+      discount_set_by: c.discount_set_by,
+      discount_set_at: c.discount_set_at,
+    }
+  }
+}
+
+// Customer as it was stored
+// before customer kinds, attachments, loyalty cards,
+// deactivation, countries, activity tracking, invoice overrides,
+// modification dates, previous contacts, incomplete profiles,
+// data retention, aliases, invoiceable flags, preferred contacts,
+// tags, groups, name sort keys, display names, privacy flags,
+// VAT periods, modifying users, external IDs, custom fields
+// and default discounts
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CustomerOld {
+  pub id: u32,
+  pub name: String,
+  pub email: String,
+  pub phone: String,
+  pub tax_number: Option<TaxNumber>,
+  pub address_zip: String,
+  pub address_location: String,
+  pub address_street: String,
+  pub date_created: DateTime<Utc>,
+  pub created_by: u32,
+}
+
+impl From<CustomerOld> for Customer {
+  fn from(c: CustomerOld) -> Self {
+    // Customers with tax number are considered as companies
+    let kind = match c.tax_number {
+      Some(_) => CustomerKind::Company,
+      None => CustomerKind::Private,
+    };
+    let display_name = display_name::derive(&c.name);
+    let sort_key = search::sort_key(&display_name);
+    Self {
+      id: c.id,
+      name: c.name,
+      email: c.email,
+      phone: c.phone,
+      tax_number: c.tax_number,
+      address_zip: c.address_zip,
+      address_location: c.address_location,
+      address_street: c.address_street,
+      country: DEFAULT_COUNTRY.to_string(),
+      date_created: c.date_created,
+      created_by: c.created_by,
+      kind,
+      attachments: Vec::new(),
+      loyalty_card_id: None,
+      external_id: None,
+      active: true,
+      status_history: Vec::new(),
+      last_activity: None,
+      last_purchase_at: None,
+      last_invoice_at: None,
+      invoice_name: None,
+      invoice_address: None,
+      last_modified: c.date_created,
+      last_modified_by: c.created_by,
+      previous_contacts: Vec::new(),
+      incomplete_profile: false,
+      anonymized_at: None,
+      aliases: Vec::new(),
+      invoiceable: false,
+      preferred_contact: None,
+      tags: Vec::new(),
+      group: None,
+      display_name,
+      sort_key,
+      privacy: PrivacyFlags::default(),
+      vat_periods: Vec::new(),
+      custom_fields: BTreeMap::new(),
+      default_discount_percent: 0,
+      discount_set_by: 0,
+      discount_set_at: None,
+    }
+  }
 }
 
 impl Default for Customer {
   fn default() -> Self {
+    let now = Utc::now();
     Self {
       id: 0,
       name: String::default(),
@@ -47,17 +544,44 @@ impl Default for Customer {
       address_zip: String::default(),
       address_location: String::default(),
       address_street: String::default(),
-      date_created: Utc::now(),
+      country: DEFAULT_COUNTRY.to_string(),
+      date_created: now,
       created_by: 0,
+      kind: CustomerKind::default(),
+      attachments: Vec::new(),
+      loyalty_card_id: None,
+      external_id: None,
+      active: true,
+      status_history: Vec::new(),
+      last_activity: None,
+      last_purchase_at: None,
+      last_invoice_at: None,
+      invoice_name: None,
+      invoice_address: None,
+      last_modified: now,
+      last_modified_by: 0,
+      previous_contacts: Vec::new(),
+      incomplete_profile: false,
+      anonymized_at: None,
+      aliases: Vec::new(),
+      invoiceable: false,
+      preferred_contact: None,
+      tags: Vec::new(),
+      group: None,
+      display_name: String::new(),
+      sort_key: String::new(),
+      privacy: PrivacyFlags::default(),
+      vat_periods: Vec::new(),
+      custom_fields: BTreeMap::new(),
+      default_discount_percent: 0,
+      discount_set_by: 0,
+      discount_set_at: None,
     }
   }
 }
 
-impl TryFrom for Customer {
-  type TryFrom = Customer;
-}
-
 impl Customer {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
     id: u32,
     name: String,
@@ -67,27 +591,28 @@ impl Customer {
     address_zip: String,
     address_location: String,
     address_street: String,
+    country: String,
+    loyalty_card_id: Option<String>,
     created_by: u32,
+    kind: CustomerKind,
+    invoiceable: bool,
+    preferred_contact: Option<ContactChannel>,
+    policy: &ValidationPolicy,
   ) -> ServiceResult<Self> {
-    // Validate Email content
-    if email.len() > 0 {
-      // If there is any provided email text
-      if !email.contains('@') || !email.contains('.') {
-        return Err(BadRequest(
-          "Nem megfelelő email cím. Legalább @ jelet és pontot kell tartalmaznia".to_string(),
-        ));
-      }
-    }
-
-    // Validate Name length
-    if name.len() > 200 || name.len() < 2 {
-      return Err(BadRequest(format!(
-        "A név hosszúsága legalább {} max {} karakter",
-        2, 200
-      )));
-    }
-
-    Ok(Self {
+    let mut sanitizer = Sanitizer::default();
+    let name = sanitizer.name("name", name);
+    let email = sanitizer.line(Field::Email.path(), email);
+    let phone = sanitizer.line(Field::Phone.path(), phone);
+    let address_zip = sanitizer.line(Field::AddressZip.path(), address_zip);
+    let address_location = sanitizer.line(Field::AddressLocation.path(), address_location);
+    let address_street = sanitizer.line(Field::AddressStreet.path(), address_street);
+    let country = sanitizer.line("country", country);
+    let loyalty_card_id = sanitizer.opt_line("loyalty_card_id", loyalty_card_id);
+    sanitizer.finish()?;
+    let now = Utc::now();
+    let display_name = display_name::derive(&name);
+    let sort_key = search::sort_key(&display_name);
+    let customer = Self {
       id,
       name,
       email,
@@ -96,13 +621,340 @@ impl Customer {
       address_zip,
       address_location,
       address_street,
-      date_created: Utc::now(),
+      country,
+      date_created: now,
       created_by,
-    })
+      kind,
+      attachments: Vec::new(),
+      loyalty_card_id,
+      external_id: None,
+      active: true,
+      status_history: Vec::new(),
+      last_activity: None,
+      last_purchase_at: None,
+      last_invoice_at: None,
+      invoice_name: None,
+      invoice_address: None,
+      last_modified: now,
+      last_modified_by: created_by,
+      previous_contacts: Vec::new(),
+      incomplete_profile: false,
+      anonymized_at: None,
+      aliases: Vec::new(),
+      invoiceable,
+      preferred_contact,
+      tags: Vec::new(),
+      group: None,
+      display_name,
+      sort_key,
+      privacy: PrivacyFlags::default(),
+      vat_periods: Vec::new(),
+      custom_fields: BTreeMap::new(),
+      default_discount_percent: 0,
+      discount_set_by: 0,
+      discount_set_at: None,
+    };
+    customer.validate(policy)?;
+    Ok(customer)
+  }
+  // New customer with name and phone only
+  // Flagged as incomplete profile, so the required fields
+  // of the policy are not checked
+  pub fn new_quick(
+    id: u32,
+    name: String,
+    phone: String,
+    created_by: u32,
+    policy: &ValidationPolicy,
+  ) -> ServiceResult<Self> {
+    let mut sanitizer = Sanitizer::default();
+    let name = sanitizer.name("name", name);
+    let phone = sanitizer.line(Field::Phone.path(), phone);
+    sanitizer.finish()?;
+    if phone.is_empty() {
+      return Err(ServiceError::invalid_field(
+        Field::Phone.path(),
+        &format!("A(z) {} megadása kötelező", Field::Phone.display_name()),
+      ));
+    }
+    let customer = Self {
+      id,
+      name,
+      phone,
+      created_by,
+      last_modified_by: created_by,
+      incomplete_profile: true,
+      ..Self::default()
+    };
+    customer.validate(policy)?;
+    Ok(customer)
+  }
+  // Validate customer against the current validation rules
+  // Returns all the field violations found
+  pub fn validate(&self, policy: &ValidationPolicy) -> ServiceResult<()> {
+    let mut violations = Violations::default();
+
+    // Validate Email content
+    // If there is any provided email text
+    if !self.email.is_empty() && (!self.email.contains('@') || !self.email.contains('.')) {
+      violations.add(
+        Field::Email.path(),
+        "Nem megfelelő email cím. Legalább @ jelet és pontot kell tartalmaznia",
+      );
+    }
+
+    // Validate Name length
+    let name_len = self.name.chars().count();
+    if name_len > policy.name_max_len || name_len < policy.name_min_len {
+      violations.add(
+        "name",
+        &format!(
+          "A név hosszúsága legalább {} max {} karakter",
+          policy.name_min_len, policy.name_max_len
+        ),
+      );
+    }
+
+    // Validate field lengths
+    for (field, value, max_len) in [
+      (Field::Email, &self.email, policy.email_max_len),
+      (Field::Phone, &self.phone, policy.phone_max_len),
+      (
+        Field::AddressZip,
+        &self.address_zip,
+        policy.address_zip_max_len,
+      ),
+      (
+        Field::AddressLocation,
+        &self.address_location,
+        policy.address_location_max_len,
+      ),
+      (
+        Field::AddressStreet,
+        &self.address_street,
+        policy.address_street_max_len,
+      ),
+    ] {
+      violations.check(
+        field.path(),
+        check_max_len(value, max_len, field.display_name()),
+      );
+    }
+
+    // Validate required fields
+    // Incomplete profiles get them later
+    for field in policy
+      .required_fields
+      .iter()
+      .filter(|_| !self.incomplete_profile)
+    {
+      let is_missing = match field {
+        Field::Email => self.email.trim().is_empty(),
+        Field::Phone => self.phone.trim().is_empty(),
+        Field::TaxNumber => self.tax_number.is_none(),
+        Field::AddressZip => self.address_zip.trim().is_empty(),
+        Field::AddressLocation => self.address_location.trim().is_empty(),
+        Field::AddressStreet => self.address_street.trim().is_empty(),
+      };
+      if is_missing {
+        violations.add(
+          field.path(),
+          &format!("A(z) {} megadása kötelező", field.display_name()),
+        );
+      }
+    }
+
+    // Validate preferred contact is reachable
+    if let Some(channel) = self.preferred_contact {
+      let (is_missing, msg) = match channel {
+        ContactChannel::Email => (
+          self.email.trim().is_empty(),
+          "E-mailes kapcsolattartáshoz az email cím megadása kötelező",
+        ),
+        ContactChannel::Phone | ContactChannel::Sms => (
+          self.phone.trim().is_empty(),
+          "Telefonos kapcsolattartáshoz a telefonszám megadása kötelező",
+        ),
+        ContactChannel::Post => (
+          [
+            &self.address_zip,
+            &self.address_location,
+            &self.address_street,
+          ]
+          .iter()
+          .any(|v| v.trim().is_empty()),
+          "Postai kapcsolattartáshoz a teljes cím megadása kötelező",
+        ),
+      };
+      if is_missing {
+        violations.add("preferred_contact", msg);
+      }
+    }
+
+    // Validate country and zip format
+    match normalize_country(&self.country) {
+      Ok(country) if country == self.country => {
+        violations.check(
+          Field::AddressZip.path(),
+          validate_zip(&self.country, &self.address_zip),
+        );
+      }
+      _ => violations.add("country", &format!("Hibás országkód: {}", self.country)),
+    }
+
+    // Validate loyalty card ID
+    if let Some(loyalty_card_id) = &self.loyalty_card_id {
+      violations.check("loyalty_card_id", validate_loyalty_card_id(loyalty_card_id));
+    }
+
+    // Validate aliases
+    if self.aliases.len() > MAX_ALIASES {
+      violations.add(
+        "aliases",
+        &format!("Legfeljebb {} becenév adható meg", MAX_ALIASES),
+      );
+    }
+    for alias in &self.aliases {
+      let len = alias.chars().count();
+      if len > policy.name_max_len || len < policy.name_min_len {
+        violations.add(
+          "aliases",
+          &format!(
+            "A becenév hosszúsága legalább {} max {} karakter",
+            policy.name_min_len, policy.name_max_len
+          ),
+        );
+      }
+    }
+
+    // Validate tags and group
+    if self.tags.len() > MAX_TAGS {
+      violations.add("tags", &format!("Legfeljebb {} címke adható meg", MAX_TAGS));
+    }
+    for tag in &self.tags {
+      violations.check("tags", validate_tag(tag, "címke"));
+    }
+    if let Some(group) = &self.group {
+      violations.check("group", validate_tag(group, "csoport"));
+    }
+
+    // Validate invoice overrides
+    if let Some(invoice_name) = &self.invoice_name {
+      let len = invoice_name.chars().count();
+      if len > policy.name_max_len || len < policy.name_min_len {
+        violations.add(
+          "invoice_name",
+          &format!(
+            "A számlázási név hosszúsága legalább {} max {} karakter",
+            policy.name_min_len, policy.name_max_len
+          ),
+        );
+      }
+    }
+    if let Some(address) = &self.invoice_address {
+      for (field, value, max_len, name) in [
+        (
+          "invoice_address_zip",
+          &address.zip,
+          policy.address_zip_max_len,
+          "számlázási irányítószám",
+        ),
+        (
+          "invoice_address_location",
+          &address.location,
+          policy.address_location_max_len,
+          "számlázási település",
+        ),
+        (
+          "invoice_address_street",
+          &address.street,
+          policy.address_street_max_len,
+          "számlázási utca",
+        ),
+      ] {
+        match value.trim().is_empty() {
+          true => violations.add(field, &format!("A(z) {} megadása kötelező", name)),
+          false => violations.check(field, check_max_len(value, max_len, name)),
+        }
+      }
+      if !address.zip.trim().is_empty() && normalize_country(&self.country).is_ok() {
+        violations.check(
+          "invoice_address_zip",
+          validate_zip(&self.country, &address.zip),
+        );
+      }
+    }
+
+    // Validate stored tax number
+    if let Some(tax_number) = &self.tax_number {
+      violations.check(
+        Field::TaxNumber.path(),
+        TaxNumber::new(&tax_number.to_string())
+          .map(|_| ())
+          .map_err(|e| e.into()),
+      );
+    }
+
+    // Validate kind related rules
+    violations.check(
+      Field::TaxNumber.path(),
+      validate_kind(&self.kind, &self.tax_number, policy),
+    );
+
+    // Invoiceable companies and institutions need a tax number
+    if policy.strict_invoicing
+      && self.invoiceable
+      && self.kind != CustomerKind::Private
+      && self.tax_number.is_none()
+    {
+      violations.add(
+        Field::TaxNumber.path(),
+        "Számlázható ügyfél esetén az adószám megadása kötelező",
+      );
+    }
+
+    violations.into_result()
+  }
+  // Validate the updated version of before
+  // With lenient updates, the violations before already had are
+  // ignored on the fields the update does not change
+  pub fn validate_update(&self, before: &Customer, policy: &ValidationPolicy) -> ServiceResult<()> {
+    let violations = match self.validate(policy) {
+      Err(InvalidFields(violations)) if policy.lenient_updates => violations,
+      res => return res,
+    };
+    let existing = match before.validate(policy) {
+      Err(InvalidFields(existing)) => existing,
+      _ => Vec::new(),
+    };
+    let (old, new) = (serde_json::to_value(before), serde_json::to_value(self));
+    let changed = |field: &str| {
+      // Invoice address violations are reported per address field
+      let key = match field.starts_with("invoice_address") {
+        true => "invoice_address",
+        false => field,
+      };
+      match (&old, &new) {
+        (Ok(old), Ok(new)) => old.get(key).is_none() || old.get(key) != new.get(key),
+        _ => true,
+      }
+    };
+    let violations = violations
+      .into_iter()
+      .filter(|v| changed(&v.field) || !existing.contains(v))
+      .collect::<Vec<FieldViolation>>();
+    match violations.is_empty() {
+      true => Ok(()),
+      false => Err(InvalidFields(violations)),
+    }
   }
 }
 
 impl Customer {
+  // Update customer
+  // Updated customer is validated as a whole,
+  // and nothing is changed if validation fails.
+  #[allow(clippy::too_many_arguments)]
   pub fn update(
     &mut self,
     name: String,
@@ -112,30 +964,533 @@ impl Customer {
     address_zip: String,
     address_location: String,
     address_street: String,
+    country: Option<String>,
+    loyalty_card_id: Option<String>,
+    kind: CustomerKind,
+    invoiceable: bool,
+    preferred_contact: Option<ContactChannel>,
+    policy: &ValidationPolicy,
   ) -> ServiceResult<&Self> {
-    self.set_email(email)?;
-    self.name = name;
-    self.phone = phone;
-    self.tax_number = tax_number;
-    self.address_zip = address_zip;
-    self.address_location = address_location;
-    self.address_street = address_street;
+    let mut sanitizer = Sanitizer::default();
+    let name = sanitizer.name("name", name);
+    let email = sanitizer.line(Field::Email.path(), email);
+    let phone = sanitizer.line(Field::Phone.path(), phone);
+    let address_zip = sanitizer.line(Field::AddressZip.path(), address_zip);
+    let address_location = sanitizer.line(Field::AddressLocation.path(), address_location);
+    let address_street = sanitizer.line(Field::AddressStreet.path(), address_street);
+    let country = sanitizer.opt_line("country", country);
+    let loyalty_card_id = sanitizer.opt_line("loyalty_card_id", loyalty_card_id);
+    sanitizer.finish()?;
+    let updated = Self {
+      name,
+      // Empty email keeps the current one
+      email: match email.is_empty() {
+        true => self.email.clone(),
+        false => email,
+      },
+      phone,
+      tax_number,
+      address_zip,
+      address_location,
+      address_street,
+      // No country keeps the current one
+      country: country.unwrap_or_else(|| self.country.clone()),
+      // No loyalty card ID keeps the current one
+      loyalty_card_id: loyalty_card_id.or_else(|| self.loyalty_card_id.clone()),
+      kind,
+      invoiceable,
+      // No preferred contact keeps the current one
+      preferred_contact: preferred_contact.or(self.preferred_contact),
+      // Updated with the full details
+      incomplete_profile: false,
+      ..self.clone()
+    };
+    let mut updated = updated;
+    updated.validate_update(self, policy)?;
+    let now = Utc::now();
+    updated.replace_contact(ContactKind::Email, &self.email, now);
+    updated.replace_contact(ContactKind::Phone, &self.phone, now);
+    *self = updated;
     Ok(self)
   }
-  pub fn set_email(&mut self, email: String) -> ServiceResult<&Self> {
-    if email.len() > 0 {
-      if email.contains('@') && email.contains('.') && email.len() > 5 {
-        self.email = email;
-        return Ok(self);
-      } else {
-        return Err(BadRequest(
-          "Rossz email formátum. Legyen legalább 5 karakter, és tartalmazzon @ jelet és pontot"
-            .into(),
-        ));
+  // Update the details customers can correct themselves
+  // Name, tax number and the internal fields are kept
+  #[allow(clippy::too_many_arguments)]
+  pub fn update_profile(
+    &mut self,
+    email: String,
+    phone: String,
+    address_zip: String,
+    address_location: String,
+    address_street: String,
+    preferred_contact: Option<ContactChannel>,
+    policy: &ValidationPolicy,
+  ) -> ServiceResult<&Self> {
+    let mut sanitizer = Sanitizer::default();
+    let email = sanitizer.line(Field::Email.path(), email);
+    let phone = sanitizer.line(Field::Phone.path(), phone);
+    let address_zip = sanitizer.line(Field::AddressZip.path(), address_zip);
+    let address_location = sanitizer.line(Field::AddressLocation.path(), address_location);
+    let address_street = sanitizer.line(Field::AddressStreet.path(), address_street);
+    sanitizer.finish()?;
+    let mut updated = Self {
+      email,
+      phone,
+      address_zip,
+      address_location,
+      address_street,
+      // No preferred contact keeps the current one
+      preferred_contact: preferred_contact.or(self.preferred_contact),
+      ..self.clone()
+    };
+    updated.validate_update(self, policy)?;
+    let now = Utc::now();
+    updated.replace_contact(ContactKind::Email, &self.email, now);
+    updated.replace_contact(ContactKind::Phone, &self.phone, now);
+    *self = updated;
+    Ok(self)
+  }
+  // Current contact detail of a kind
+  fn contact(&self, kind: ContactKind) -> &str {
+    match kind {
+      ContactKind::Email => &self.email,
+      ContactKind::Phone => &self.phone,
+    }
+  }
+  // Keep the replaced contact detail in the previous contacts
+  fn replace_contact(&mut self, kind: ContactKind, old_value: &str, now: DateTime<Utc>) {
+    let current = self.contact(kind).trim().to_string();
+    let old_value = old_value.trim();
+    // A contact set again is not a previous one anymore
+    self
+      .previous_contacts
+      .retain(|c| !(c.kind == kind && (c.value == current || c.value == old_value)));
+    if !old_value.is_empty() && old_value != current {
+      self.previous_contacts.push(PreviousContact {
+        kind,
+        value: old_value.to_string(),
+        date_replaced: now,
+      });
+    }
+    let overflow = self
+      .previous_contacts
+      .len()
+      .saturating_sub(MAX_PREVIOUS_CONTACTS);
+    self.previous_contacts.drain(..overflow);
+  }
+  // Check if the current or a previous email or phone number
+  // matches the search query
+  pub fn matches_contact(&self, query: &search::TextQuery) -> bool {
+    let matches = |kind: ContactKind, value: &str| match kind {
+      ContactKind::Email => !query.is_empty() && query.contained_in(value),
+      ContactKind::Phone => query.matches_phone(value),
+    };
+    [ContactKind::Email, ContactKind::Phone]
+      .iter()
+      .any(|kind| matches(*kind, self.contact(*kind)))
+      || self
+        .previous_contacts
+        .iter()
+        .any(|c| matches(c.kind, &c.value))
+  }
+  // Attach document to customer
+  pub fn attach_document(
+    &mut self,
+    document_kind: DocumentKind,
+    storage_key: String,
+    uploaded_by: u32,
+  ) -> ServiceResult<&Attachment> {
+    let storage_key = storage_key.trim().to_string();
+    if storage_key.is_empty() || storage_key.len() > 1024 {
+      return Err(BadRequest(
+        "A dokumentum tárolási kulcsa nem lehet üres, és max 1024 karakter lehet".to_string(),
+      ));
+    }
+    if self
+      .attachments
+      .iter()
+      .any(|a| a.storage_key == storage_key)
+    {
+      return Err(AlreadyExists(
+        "Ez a dokumentum már csatolva van az ügyfélhez".to_string(),
+      ));
+    }
+    let id = self.attachments.iter().map(|a| a.id).max().unwrap_or(0) + 1;
+    self.attachments.push(Attachment {
+      id,
+      document_kind,
+      storage_key,
+      uploaded_by,
+      date_uploaded: Utc::now(),
+    });
+    Ok(self.attachments.last().unwrap())
+  }
+  // Record customer activity
+  // Activities older than the stored ones are ignored,
+  // as services may report them out of order
+  pub fn record_activity(&mut self, kind: ActivityKind, at: DateTime<Utc>) -> &Self {
+    if self.last_activity.is_none_or(|last| last < at) {
+      self.last_activity = Some(at);
+    }
+    if kind == ActivityKind::Purchase && self.last_purchase_at.is_none_or(|last| last < at) {
+      self.last_purchase_at = Some(at);
+    }
+    if kind == ActivityKind::Invoice && self.last_invoice_at.is_none_or(|last| last < at) {
+      self.last_invoice_at = Some(at);
+    }
+    self
+  }
+  // Remove personal data of the customer
+  // ID, kind, dates and the status history are kept,
+  // so records referring to the customer stay valid.
+  // Active customers are deactivated.
+  pub fn anonymize(&mut self, comment: &str, now: DateTime<Utc>) -> &Self {
+    self.name = ANONYMIZED_NAME.to_string();
+    self.email = String::new();
+    self.phone = String::new();
+    self.tax_number = None;
+    self.address_zip = String::new();
+    self.address_location = String::new();
+    self.address_street = String::new();
+    self.attachments = Vec::new();
+    self.loyalty_card_id = None;
+    self.invoice_name = None;
+    self.invoice_address = None;
+    self.previous_contacts = Vec::new();
+    self.aliases = Vec::new();
+    self.invoiceable = false;
+    self.preferred_contact = None;
+    // Custom fields and comments may contain personal data as well
+    self.custom_fields = BTreeMap::new();
+    for status in self.status_history.iter_mut() {
+      status.comment = String::new();
+    }
+    if self.active {
+      self.set_active(false, Some(ReasonCode::Other), comment.to_string(), 0);
+    }
+    self.anonymized_at = Some(now);
+    self
+  }
+  // Name to issue invoices under
+  pub fn invoice_name(&self) -> &str {
+    self.invoice_name.as_deref().unwrap_or(&self.name)
+  }
+  // Address to issue invoices to
+  pub fn invoice_address(&self) -> InvoiceAddress {
+    match &self.invoice_address {
+      Some(address) => address.clone(),
+      None => InvoiceAddress {
+        zip: self.address_zip.clone(),
+        location: self.address_location.clone(),
+        street: self.address_street.clone(),
+      },
+    }
+  }
+  // Set VAT treatment for a date range, None is normal VAT
+  pub fn set_vat_status(
+    &mut self,
+    treatment: Option<VatTreatment>,
+    valid_from: NaiveDate,
+    valid_until: Option<NaiveDate>,
+  ) -> ServiceResult<&Self> {
+    if let Some(treatment) = treatment {
+      vat::check_treatment(treatment, &self.country, self.kind == CustomerKind::Private)?;
+    }
+    vat::set_period(&mut self.vat_periods, treatment, valid_from, valid_until)?;
+    Ok(self)
+  }
+  // Set the default discount, with the user setting it
+  pub fn set_discount(
+    &mut self,
+    percent: u32,
+    set_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<&Self> {
+    if percent > MAX_DISCOUNT_PERCENT {
+      return Err(ServiceError::invalid_field(
+        "default_discount_percent",
+        &format!(
+          "A kedvezmény 0 és {} százalék között lehet",
+          MAX_DISCOUNT_PERCENT
+        ),
+      ));
+    }
+    self.default_discount_percent = percent;
+    self.discount_set_by = set_by;
+    self.discount_set_at = Some(now);
+    Ok(self)
+  }
+  // Set invoice name and address overrides
+  // None clears the override
+  pub fn set_invoice_details(
+    &mut self,
+    invoice_name: Option<String>,
+    invoice_address: Option<InvoiceAddress>,
+    policy: &ValidationPolicy,
+  ) -> ServiceResult<&Self> {
+    let mut sanitizer = Sanitizer::default();
+    let invoice_name = invoice_name.map(|name| sanitizer.name("invoice_name", name));
+    let invoice_address = invoice_address.map(|address| InvoiceAddress {
+      zip: sanitizer.line("invoice_address_zip", address.zip),
+      location: sanitizer.line("invoice_address_location", address.location),
+      street: sanitizer.line("invoice_address_street", address.street),
+    });
+    sanitizer.finish()?;
+    let candidate = Self {
+      invoice_name,
+      invoice_address,
+      ..self.clone()
+    };
+    candidate.validate_update(self, policy)?;
+    *self = candidate;
+    Ok(self)
+  }
+  // Fields missing to issue an invoice to the customer
+  // Proto field names. Invoice address overrides are
+  // always complete, otherwise the main address is used.
+  pub fn invoice_missing_fields(&self) -> Vec<&'static str> {
+    let mut res = Vec::new();
+    if self.kind != CustomerKind::Private && self.tax_number.is_none() {
+      res.push(Field::TaxNumber.path());
+    }
+    if self.invoice_address.is_none() {
+      for (field, value) in [
+        (Field::AddressZip, &self.address_zip),
+        (Field::AddressLocation, &self.address_location),
+        (Field::AddressStreet, &self.address_street),
+      ] {
+        if value.trim().is_empty() {
+          res.push(field.path());
+        }
+      }
+    }
+    res
+  }
+  // Derive display name and sort key from the name
+  // Returns true if any of them changed
+  pub fn derive_names(&mut self) -> bool {
+    let display_name = display_name::derive(&self.name);
+    let sort_key = search::sort_key(&display_name);
+    let changed = display_name != self.display_name || sort_key != self.sort_key;
+    self.display_name = display_name;
+    self.sort_key = sort_key;
+    changed
+  }
+  // Fields missing for invoicing and marketing
+  // The invoicing fields, then email and phone to reach the customer
+  pub fn completeness_missing_fields(&self) -> Vec<&'static str> {
+    let mut res = self.invoice_missing_fields();
+    for (field, value) in [(Field::Email, &self.email), (Field::Phone, &self.phone)] {
+      if value.trim().is_empty() {
+        res.push(field.path());
       }
     }
+    res
+  }
+  // Percent of the invoicing and marketing fields filled, 0-100
+  pub fn completeness_score(&self) -> u32 {
+    let total = match self.kind {
+      CustomerKind::Private => 5,
+      _ => 6,
+    };
+    let missing = self.completeness_missing_fields().len() as u32;
+    (total - missing) * 100 / total
+  }
+  // Add alias the customer is known by
+  pub fn add_alias(&mut self, alias: &str, policy: &ValidationPolicy) -> ServiceResult<&Self> {
+    let alias = sanitize::name(alias).map_err(|e| e.on_field("aliases"))?;
+    let key = search::normalize(&alias);
+    if self.aliases.iter().any(|a| search::normalize(a) == key) {
+      return Err(AlreadyExists(
+        "Ez a becenév már szerepel az ügyfélnél".to_string(),
+      ));
+    }
+    let mut candidate = self.clone();
+    candidate.aliases.push(alias);
+    candidate.validate_update(self, policy)?;
+    *self = candidate;
     Ok(self)
   }
+  // Remove alias
+  // Matched without case and accents
+  pub fn remove_alias(&mut self, alias: &str) -> ServiceResult<&Self> {
+    let key = search::normalize(alias);
+    match self
+      .aliases
+      .iter()
+      .position(|a| search::normalize(a) == key)
+    {
+      Some(i) => {
+        self.aliases.remove(i);
+        Ok(self)
+      }
+      None => Err(NotFound("Nincs ilyen becenév az ügyfélnél".to_string())),
+    }
+  }
+  // Add tag, returns false if the customer already has it
+  pub fn add_tag(&mut self, tag: &str, policy: &ValidationPolicy) -> ServiceResult<bool> {
+    let tag = normalize_tag(tag);
+    if self.tags.contains(&tag) {
+      return Ok(false);
+    }
+    let mut candidate = self.clone();
+    candidate.tags.push(tag);
+    candidate.validate_update(self, policy)?;
+    *self = candidate;
+    Ok(true)
+  }
+  // Remove tag, returns false if the customer does not have it
+  pub fn remove_tag(&mut self, tag: &str) -> bool {
+    let tag = normalize_tag(tag);
+    let len = self.tags.len();
+    self.tags.retain(|t| *t != tag);
+    self.tags.len() != len
+  }
+  // Set group, empty group removes the customer from its group
+  // Returns false if the group is not changed
+  pub fn set_group(&mut self, group: &str, policy: &ValidationPolicy) -> ServiceResult<bool> {
+    let group = match normalize_tag(group) {
+      g if g.is_empty() => None,
+      g => Some(g),
+    };
+    if self.group == group {
+      return Ok(false);
+    }
+    let mut candidate = self.clone();
+    candidate.group = group;
+    candidate.validate_update(self, policy)?;
+    *self = candidate;
+    Ok(true)
+  }
+  // Check if an alias matches the search query
+  // Matched without case and accents
+  // Check name, contacts or aliases contain the query
+  // An empty query matches every customer
+  pub fn matches_query(&self, query: &search::TextQuery) -> bool {
+    query.matches_name(&self.name) || self.matches_contact(query) || self.matches_alias(query)
+  }
+  pub fn matches_alias(&self, query: &search::TextQuery) -> bool {
+    !query.is_empty() && self.aliases.iter().any(|a| query.matches_name(a))
+  }
+  // Check customer had no activity since the given date
+  // Customers without activity count from their creation
+  pub fn is_inactive_since(&self, date: DateTime<Utc>) -> bool {
+    self.last_seen() < date
+  }
+  // Date of the last activity, or the creation date
+  pub fn last_seen(&self) -> DateTime<Utc> {
+    self.last_activity.unwrap_or(self.date_created)
+  }
+  // Deactivate customer
+  pub fn deactivate(
+    &mut self,
+    reason: ReasonCode,
+    comment: String,
+    changed_by: u32,
+  ) -> ServiceResult<&Self> {
+    if !self.active {
+      return Err(BadRequest("Az ügyfél már inaktív".to_string()));
+    }
+    let comment = sanitize::line(&comment).map_err(|e| e.on_field("comment"))?;
+    self.set_active(false, Some(reason), comment, changed_by);
+    Ok(self)
+  }
+  // Reactivate customer
+  pub fn reactivate(&mut self, comment: String, changed_by: u32) -> ServiceResult<&Self> {
+    if self.active {
+      return Err(BadRequest("Az ügyfél már aktív".to_string()));
+    }
+    let comment = sanitize::line(&comment).map_err(|e| e.on_field("comment"))?;
+    self.set_active(true, None, comment, changed_by);
+    Ok(self)
+  }
+  fn set_active(
+    &mut self,
+    active: bool,
+    reason: Option<ReasonCode>,
+    comment: String,
+    changed_by: u32,
+  ) {
+    self.active = active;
+    self.status_history.push(StatusChange {
+      active,
+      reason,
+      comment,
+      changed_by,
+      date_changed: Utc::now(),
+    });
+  }
+  // Remove attached document by ID
+  // Returns the removed attachment
+  pub fn remove_document(&mut self, attachment_id: u32) -> ServiceResult<Attachment> {
+    match self.attachments.iter().position(|a| a.id == attachment_id) {
+      Some(index) => Ok(self.attachments.remove(index)),
+      None => Err(NotFound("A dokumentum nem található".to_string())),
+    }
+  }
+}
+
+// Kind specific validation
+// Companies must have a tax number (if the policy requires),
+// private persons must not have one
+fn validate_kind(
+  kind: &CustomerKind,
+  tax_number: &Option<TaxNumber>,
+  policy: &ValidationPolicy,
+) -> ServiceResult<()> {
+  match (kind, tax_number) {
+    (CustomerKind::Company, None) if policy.company_tax_number_required => Err(BadRequest(
+      "Cég esetén az adószám megadása kötelező".to_string(),
+    )),
+    (CustomerKind::Private, Some(_)) => Err(BadRequest(
+      "Magánszemély esetén nem adható meg adószám".to_string(),
+    )),
+    _ => Ok(()),
+  }
+}
+
+// Tags and groups are matched as lowercase, single spaced
+pub fn normalize_tag(tag: &str) -> String {
+  tag
+    .to_lowercase()
+    .split_whitespace()
+    .collect::<Vec<&str>>()
+    .join(" ")
+}
+
+// Tag or group name
+// Letters, numbers, spaces, '-' and '_', max MAX_TAG_LEN characters
+fn validate_tag(tag: &str, name: &str) -> ServiceResult<()> {
+  let len = tag.chars().count();
+  if len == 0
+    || len > MAX_TAG_LEN
+    || !tag
+      .chars()
+      .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_')
+  {
+    return Err(BadRequest(format!(
+      "Hibás {}. Csak betűt, számot, szóközt, kötőjelet és aláhúzást tartalmazhat, max {} karakter",
+      name, MAX_TAG_LEN
+    )));
+  }
+  Ok(())
+}
+
+// Loyalty card ID as printed in the card barcode
+// Letters, numbers and '-', max 64 characters
+fn validate_loyalty_card_id(loyalty_card_id: &str) -> ServiceResult<()> {
+  if loyalty_card_id.is_empty()
+    || loyalty_card_id.len() > 64
+    || !loyalty_card_id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-')
+  {
+    return Err(BadRequest(
+      "Hibás hűségkártya azonosító. Csak betűt, számot és kötőjelet tartalmazhat, max 64 karakter"
+        .to_string(),
+    ));
+  }
+  Ok(())
 }
 
 impl VecPackMember for Customer {
@@ -144,3 +1499,928 @@ impl VecPackMember for Customer {
     &self.id
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::search::TextQuery;
+  use proptest::prelude::*;
+
+  fn new_customer(kind: CustomerKind, tax_number: Option<TaxNumber>) -> ServiceResult<Customer> {
+    Customer::new(
+      1,
+      "Kiss Béla".to_string(),
+      "".to_string(),
+      "".to_string(),
+      tax_number,
+      "".to_string(),
+      "".to_string(),
+      "".to_string(),
+      "HU".to_string(),
+      None,
+      1,
+      kind,
+      false,
+      None,
+      &ValidationPolicy::default(),
+    )
+  }
+
+  #[test]
+  fn test_schema_versions() {
+    let tax_number = TaxNumber::new("23127182-2-15").ok();
+    let mut customer = new_customer(CustomerKind::Company, tax_number).unwrap();
+    customer.tags = vec!["vip".to_string()];
+    // Binary records are versioned
+    let data = bincode::serialize(&customer).unwrap();
+    assert_eq!(data[..4], SCHEMA_MAGIC.to_le_bytes());
+    assert_eq!(record_id(&data), Some(1));
+    let decoded: Customer = bincode::deserialize(&data).unwrap();
+    assert_eq!(decoded.tags, ["vip"]);
+    assert_eq!(decoded.kind, CustomerKind::Company);
+    // JSON has the plain fields
+    let json = serde_json::to_value(&customer).unwrap();
+    assert_eq!(json["name"], "Kiss Béla");
+    let decoded: Customer = serde_json::from_value(json).unwrap();
+    assert_eq!(decoded.tags, ["vip"]);
+
+    // Previous layout, written without schema version
+    let data = bincode::serialize(&Fields(&customer)).unwrap();
+    assert!(bincode::deserialize::<Customer>(&data).is_err());
+    assert_eq!(record_id(&data), Some(1));
+    let decoded = decode_unversioned(&data).unwrap();
+    assert_eq!(decoded.tags, ["vip"]);
+    assert_eq!(decoded.kind, CustomerKind::Company);
+    let batch = bincode::serialize(&vec![Fields(&customer)]).unwrap();
+    assert!(bincode::deserialize::<Vec<Customer>>(&batch).is_err());
+    assert_eq!(decode_unversioned_batch(&batch).unwrap()[0].tags, ["vip"]);
+    // Baseline layout
+    let old = CustomerOld {
+      id: 2,
+      name: "Nagy Anna".to_string(),
+      ..CustomerOld::default()
+    };
+    let decoded = decode_unversioned(&bincode::serialize(&old).unwrap()).unwrap();
+    assert_eq!(decoded.name, "Nagy Anna");
+    assert_eq!(decoded.display_name, "Nagy Anna");
+    // Unknown layouts are not read as the baseline one
+    let data = bincode::serialize(&(old, CustomerKind::Company)).unwrap();
+    assert!(decode_unversioned(&data).is_none());
+  }
+
+  #[test]
+  fn test_kind_validation() {
+    let tax_number = TaxNumber::new("23127182-2-15").ok();
+    assert!(new_customer(CustomerKind::Company, tax_number.clone()).is_ok());
+    assert!(new_customer(CustomerKind::Company, None).is_err());
+    assert!(new_customer(CustomerKind::Private, None).is_ok());
+    assert!(new_customer(CustomerKind::Private, tax_number.clone()).is_err());
+    assert!(new_customer(CustomerKind::Institution, tax_number).is_ok());
+    assert!(new_customer(CustomerKind::Institution, None).is_ok());
+  }
+
+  #[test]
+  fn test_kind_update() {
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    let res = customer.update(
+      "Kiss Béla".to_string(),
+      "".to_string(),
+      "".to_string(),
+      None,
+      "".to_string(),
+      "".to_string(),
+      "".to_string(),
+      None,
+      None,
+      CustomerKind::Company,
+      false,
+      None,
+      &ValidationPolicy::default(),
+    );
+    assert!(res.is_err());
+    assert_eq!(customer.kind, CustomerKind::Private);
+  }
+
+  #[test]
+  fn test_previous_contacts() {
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    let update = |customer: &mut Customer, email: &str, phone: &str| {
+      customer
+        .update(
+          "Kiss Béla".to_string(),
+          email.to_string(),
+          phone.to_string(),
+          None,
+          "".to_string(),
+          "".to_string(),
+          "".to_string(),
+          None,
+          None,
+          CustomerKind::Private,
+          false,
+          None,
+          &ValidationPolicy::default(),
+        )
+        .unwrap();
+    };
+    update(&mut customer, "kiss@example.com", "+36 30 123 4567");
+    assert!(customer.previous_contacts.is_empty());
+    update(&mut customer, "bela@example.com", "+36 20 765 4321");
+    let values = |customer: &Customer| {
+      customer
+        .previous_contacts
+        .iter()
+        .map(|c| (c.kind, c.value.clone()))
+        .collect::<Vec<(ContactKind, String)>>()
+    };
+    assert_eq!(
+      values(&customer),
+      [
+        (ContactKind::Email, "kiss@example.com".to_string()),
+        (ContactKind::Phone, "+36 30 123 4567".to_string())
+      ]
+    );
+    // Old and current contacts are both found
+    assert!(customer.matches_contact(&TextQuery::new("06301234567")));
+    assert!(customer.matches_contact(&TextQuery::new("30/123-4567")));
+    assert!(customer.matches_contact(&TextQuery::new("207654321")));
+    assert!(customer.matches_contact(&TextQuery::new("KISS@example")));
+    assert!(!customer.matches_contact(&TextQuery::new("1234")));
+    // Setting a previous contact again removes it from the list
+    update(&mut customer, "kiss@example.com", "+36 20 765 4321");
+    assert_eq!(
+      values(&customer),
+      [
+        (ContactKind::Phone, "+36 30 123 4567".to_string()),
+        (ContactKind::Email, "bela@example.com".to_string())
+      ]
+    );
+    // List is capped
+    for i in 0..MAX_PREVIOUS_CONTACTS {
+      update(
+        &mut customer,
+        "kiss@example.com",
+        &format!("+36 30 000 000{}", i),
+      );
+    }
+    assert_eq!(customer.previous_contacts.len(), MAX_PREVIOUS_CONTACTS);
+    assert_eq!(customer.previous_contacts[0].value, "+36 20 765 4321");
+  }
+
+  #[test]
+  fn test_policy() {
+    let policy = ValidationPolicy {
+      required_fields: vec![Field::Phone],
+      company_tax_number_required: false,
+      ..ValidationPolicy::default()
+    };
+    let customer = Customer {
+      name: "Kiss Béla".to_string(),
+      kind: CustomerKind::Company,
+      ..Customer::default()
+    };
+    // Phone is required
+    assert!(customer.validate(&policy).is_err());
+    let customer = Customer {
+      phone: "+36301234567".to_string(),
+      ..customer
+    };
+    assert!(customer.validate(&policy).is_ok());
+    // Company without tax number is invalid by default
+    assert!(customer.validate(&ValidationPolicy::default()).is_err());
+    // Name length limits count characters
+    let customer = Customer {
+      name: "Á".repeat(200),
+      ..customer
+    };
+    assert!(customer.validate(&policy).is_ok());
+  }
+
+  #[test]
+  fn test_lenient_update() {
+    let strict = ValidationPolicy::default();
+    let lenient = ValidationPolicy {
+      lenient_updates: true,
+      ..ValidationPolicy::default()
+    };
+    // Legacy record failing the name and email rules
+    let legacy = Customer {
+      name: "K".to_string(),
+      email: "kiss.hu".to_string(),
+      country: DEFAULT_COUNTRY.to_string(),
+      ..Customer::default()
+    };
+    let fix_phone = |policy: &ValidationPolicy, phone: &str| {
+      let mut customer = legacy.clone();
+      customer
+        .update_profile(
+          "kiss.hu".to_string(),
+          phone.to_string(),
+          String::new(),
+          String::new(),
+          String::new(),
+          None,
+          policy,
+        )
+        .map(|c| c.phone.clone())
+    };
+    assert!(fix_phone(&strict, "+36301234567").is_err());
+    assert_eq!(fix_phone(&lenient, "+36301234567").unwrap(), "+36301234567");
+    // The changed fields are still checked
+    match fix_phone(&lenient, &"1".repeat(51)) {
+      Err(InvalidFields(violations)) => {
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].field, "phone");
+      }
+      res => panic!("{:?}", res),
+    }
+    let mut changed = legacy.clone();
+    changed.email = "kiss@".to_string();
+    assert!(changed.validate_update(&legacy, &lenient).is_err());
+    // New violations of unchanged fields are not ignored
+    let mut hungarian = legacy.clone();
+    hungarian.address_zip = "6000".to_string();
+    let mut moved = hungarian.clone();
+    moved.country = "SK".to_string();
+    match moved.validate_update(&hungarian, &lenient) {
+      Err(InvalidFields(violations)) => {
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].field, "address_zip");
+      }
+      res => panic!("{:?}", res),
+    }
+  }
+
+  #[test]
+  fn test_quick_create() {
+    let policy = ValidationPolicy {
+      required_fields: vec![Field::Email, Field::Phone],
+      ..ValidationPolicy::default()
+    };
+    let new_quick =
+      |phone: &str| Customer::new_quick(1, "Kiss Béla".to_string(), phone.to_string(), 1, &policy);
+    // Phone is required
+    assert!(new_quick(" ").is_err());
+    // Email is not required for incomplete profiles
+    let mut customer = new_quick("+36301234567").unwrap();
+    assert!(customer.incomplete_profile);
+    assert_eq!(customer.kind, CustomerKind::Private);
+    let update = |customer: &mut Customer, email: &str| {
+      customer
+        .update(
+          "Kiss Béla".to_string(),
+          email.to_string(),
+          "+36301234567".to_string(),
+          None,
+          "".to_string(),
+          "".to_string(),
+          "".to_string(),
+          None,
+          None,
+          CustomerKind::Private,
+          false,
+          None,
+          &policy,
+        )
+        .map(|_| ())
+    };
+    // Update without the required fields fails
+    assert!(update(&mut customer, "").is_err());
+    assert!(customer.incomplete_profile);
+    // Full update completes the profile
+    assert!(update(&mut customer, "kiss@example.com").is_ok());
+    assert!(!customer.incomplete_profile);
+  }
+
+  #[test]
+  fn test_loyalty_card_id() {
+    let customer = new_customer(CustomerKind::Private, None).unwrap();
+    for (loyalty_card_id, is_ok) in [
+      ("GZ-0001234", true),
+      ("", false),
+      ("GZ 0001234", false),
+      ("Á123", false),
+    ] {
+      let customer = Customer {
+        loyalty_card_id: Some(loyalty_card_id.to_string()),
+        ..customer.clone()
+      };
+      assert_eq!(
+        customer.validate(&ValidationPolicy::default()).is_ok(),
+        is_ok
+      );
+    }
+  }
+
+  #[test]
+  fn test_field_violations() {
+    let customer = Customer {
+      name: "K".to_string(),
+      email: "nincs".to_string(),
+      kind: CustomerKind::Company,
+      ..Customer::default()
+    };
+    match customer.validate(&ValidationPolicy::default()) {
+      Err(InvalidFields(violations)) => assert_eq!(
+        violations
+          .iter()
+          .map(|v| v.field.as_str())
+          .collect::<Vec<&str>>(),
+        ["email", "name", "tax_number"]
+      ),
+      _ => panic!("field violations expected"),
+    }
+  }
+
+  #[test]
+  fn test_sanitized_inputs() {
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    customer
+      .update(
+        "  Kiss \t Béla ".to_string(),
+        " bela@kiss.hu\n".to_string(),
+        "+36301234567 ".to_string(),
+        None,
+        " 1111".to_string(),
+        "Budapest ".to_string(),
+        "Fő  utca 1.".to_string(),
+        None,
+        None,
+        CustomerKind::Private,
+        false,
+        None,
+        &ValidationPolicy::default(),
+      )
+      .unwrap();
+    assert_eq!(
+      (
+        customer.name.as_str(),
+        customer.email.as_str(),
+        customer.address_zip.as_str(),
+        customer.address_street.as_str()
+      ),
+      ("Kiss Béla", "bela@kiss.hu", "1111", "Fő  utca 1.")
+    );
+    let res = customer.update_profile(
+      "bela\u{200B}@kiss.hu".to_string(),
+      "+36301234567".to_string(),
+      "1111".to_string(),
+      "Budapest".to_string(),
+      "\u{202E}Fő utca 1.".to_string(),
+      None,
+      &ValidationPolicy::default(),
+    );
+    match res {
+      Err(InvalidFields(violations)) => assert_eq!(
+        violations
+          .iter()
+          .map(|v| v.field.as_str())
+          .collect::<Vec<&str>>(),
+        ["email", "address_street"]
+      ),
+      _ => panic!("field violations expected"),
+    }
+    assert_eq!(customer.email, "bela@kiss.hu");
+  }
+
+  #[test]
+  fn test_country() {
+    let customer = new_customer(CustomerKind::Private, None).unwrap();
+    let policy = ValidationPolicy::default();
+    let with = |country: &str, zip: &str| Customer {
+      country: country.to_string(),
+      address_zip: zip.to_string(),
+      ..customer.clone()
+    };
+    assert!(with("HU", "6000").validate(&policy).is_ok());
+    assert!(with("HU", "931 01").validate(&policy).is_err());
+    assert!(with("SK", "931 01").validate(&policy).is_ok());
+    assert!(with("sk", "931 01").validate(&policy).is_err());
+  }
+
+  #[test]
+  fn test_invoice_details() {
+    let policy = ValidationPolicy::default();
+    let mut customer = Customer {
+      name: "Kiss Béla".to_string(),
+      address_zip: "6000".to_string(),
+      address_location: "Kecskemét".to_string(),
+      address_street: "Fő utca 1.".to_string(),
+      ..Customer::default()
+    };
+    assert_eq!(customer.invoice_name(), "Kiss Béla");
+    assert_eq!(customer.invoice_address().zip, "6000");
+    let address = InvoiceAddress {
+      zip: "1051".to_string(),
+      location: "Budapest".to_string(),
+      street: "Nádor utca 2.".to_string(),
+    };
+    customer
+      .set_invoice_details(
+        Some("Kiss Béla e.v.".to_string()),
+        Some(address.clone()),
+        &policy,
+      )
+      .unwrap();
+    assert_eq!(customer.invoice_name(), "Kiss Béla e.v.");
+    assert_eq!(customer.invoice_address(), address);
+    // Partial address is rejected, and nothing is changed
+    let partial = InvoiceAddress {
+      street: "".to_string(),
+      ..address.clone()
+    };
+    match customer.set_invoice_details(None, Some(partial), &policy) {
+      Err(InvalidFields(violations)) => {
+        assert_eq!(violations[0].field, "invoice_address_street")
+      }
+      _ => panic!("partial invoice address accepted"),
+    }
+    assert_eq!(customer.invoice_name(), "Kiss Béla e.v.");
+    // Clear overrides
+    customer.set_invoice_details(None, None, &policy).unwrap();
+    assert_eq!(customer.invoice_name(), "Kiss Béla");
+    assert_eq!(customer.invoice_address().location, "Kecskemét");
+  }
+
+  #[test]
+  fn test_record_activity() {
+    let date = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let mut customer = Customer {
+      date_created: date("2021-01-01T00:00:00Z"),
+      ..Customer::default()
+    };
+    assert!(customer.is_inactive_since(date("2021-02-01T00:00:00Z")));
+    customer.record_activity(ActivityKind::Purchase, date("2021-03-01T00:00:00Z"));
+    customer.record_activity(ActivityKind::Invoice, date("2021-03-05T00:00:00Z"));
+    // Older activity is ignored
+    customer.record_activity(ActivityKind::Purchase, date("2021-02-01T00:00:00Z"));
+    assert_eq!(customer.last_activity, Some(date("2021-03-05T00:00:00Z")));
+    assert_eq!(
+      customer.last_purchase_at,
+      Some(date("2021-03-01T00:00:00Z"))
+    );
+    assert_eq!(customer.last_invoice_at, Some(date("2021-03-05T00:00:00Z")));
+    assert!(!customer.is_inactive_since(date("2021-03-05T00:00:00Z")));
+    assert!(customer.is_inactive_since(date("2021-03-06T00:00:00Z")));
+  }
+
+  #[test]
+  fn test_anonymize() {
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    customer.email = "kiss@example.com".to_string();
+    customer.loyalty_card_id = Some("GZ-1".to_string());
+    customer
+      .custom_fields
+      .insert("contact_person".to_string(), "Kiss Anna".to_string());
+    customer
+      .attach_document(DocumentKind::Other, "doc1".to_string(), 1)
+      .unwrap();
+    customer
+      .deactivate(ReasonCode::Debt, "Kiss Béla tartozása".to_string(), 2)
+      .unwrap();
+    customer.reactivate("".to_string(), 2).unwrap();
+    let now = Utc::now();
+    customer.anonymize("Adatmegőrzési szabály: legal", now);
+    assert_eq!(customer.id, 1);
+    assert_eq!(customer.name, ANONYMIZED_NAME);
+    assert!(customer.email.is_empty());
+    assert!(customer.loyalty_card_id.is_none());
+    assert!(customer.attachments.is_empty());
+    assert!(customer.custom_fields.is_empty());
+    assert!(!customer.active);
+    assert_eq!(customer.status_history.len(), 3);
+    assert!(customer.status_history[0].comment.is_empty());
+    assert_eq!(customer.anonymized_at, Some(now));
+  }
+
+  #[test]
+  fn test_matches_query() {
+    let customer = Customer {
+      name: "Kertész Béla".to_string(),
+      email: "Bela.Kertesz@example.com".to_string(),
+      aliases: vec!["Öreg kertész".to_string()],
+      ..Customer::default()
+    };
+    // Uppercase queries found nothing before
+    for query in [
+      "KERTÉSZ",
+      "kertesz bela",
+      " Kertész  Béla ",
+      "BELA.KERTESZ@",
+      "oreg",
+    ] {
+      assert!(customer.matches_query(&TextQuery::new(query)));
+    }
+    assert!(customer.matches_query(&TextQuery::new("")));
+    assert!(!customer.matches_query(&TextQuery::new("Kovács")));
+  }
+
+  #[test]
+  fn test_aliases() {
+    let policy = ValidationPolicy::default();
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    customer
+      .add_alias(" Józsi bácsi a sarokról ", &policy)
+      .unwrap();
+    assert_eq!(customer.aliases, ["Józsi bácsi a sarokról"]);
+    // Same alias without accents
+    assert!(customer
+      .add_alias("jozsi bacsi a sarokrol", &policy)
+      .is_err());
+    assert!(customer.add_alias("J", &policy).is_err());
+    assert!(customer.matches_alias(&TextQuery::new("józsi")));
+    assert!(customer.matches_alias(&TextQuery::new("JOZSI BACSI")));
+    assert!(!customer.matches_alias(&TextQuery::new("pista")));
+    assert!(!customer.matches_alias(&TextQuery::new(" ")));
+    for i in 1..MAX_ALIASES {
+      customer
+        .add_alias(&format!("Józsi {}", i), &policy)
+        .unwrap();
+    }
+    assert!(customer.add_alias("Józsi bá", &policy).is_err());
+    assert_eq!(customer.aliases.len(), MAX_ALIASES);
+    customer.remove_alias("józsi bácsi a sarokról").unwrap();
+    assert!(!customer.matches_alias(&TextQuery::new("sarok")));
+    assert!(customer.remove_alias("józsi bácsi a sarokról").is_err());
+  }
+
+  #[test]
+  fn test_update_profile() {
+    let policy = ValidationPolicy::default();
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    customer.email = "regi@example.com".to_string();
+    let before = customer.clone();
+    customer
+      .update_profile(
+        "uj@example.com".to_string(),
+        before.phone.clone(),
+        "6000".to_string(),
+        "Kecskemét".to_string(),
+        "Fő utca 2.".to_string(),
+        None,
+        &policy,
+      )
+      .unwrap();
+    assert_eq!(customer.name, before.name);
+    assert_eq!(customer.email, "uj@example.com");
+    assert_eq!(customer.address_street, "Fő utca 2.");
+    assert_eq!(customer.previous_contacts.len(), 1);
+    assert_eq!(customer.previous_contacts[0].value, before.email);
+    // Invalid data keeps the customer unchanged
+    assert!(customer
+      .update_profile(
+        "nem email".to_string(),
+        "".to_string(),
+        "".to_string(),
+        "".to_string(),
+        "".to_string(),
+        None,
+        &policy,
+      )
+      .is_err());
+    assert_eq!(customer.email, "uj@example.com");
+  }
+
+  #[test]
+  fn test_tags_and_group() {
+    let policy = ValidationPolicy::default();
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    assert!(customer.add_tag(" Tavaszi  Akció ", &policy).unwrap());
+    assert_eq!(customer.tags, ["tavaszi akció"]);
+    // Already tagged
+    assert!(!customer.add_tag("TAVASZI AKCIÓ", &policy).unwrap());
+    assert!(customer.add_tag("", &policy).is_err());
+    assert!(customer.add_tag("akció!", &policy).is_err());
+    assert!(customer
+      .add_tag(&"a".repeat(MAX_TAG_LEN + 1), &policy)
+      .is_err());
+    for i in 1..MAX_TAGS {
+      customer.add_tag(&format!("tag_{}", i), &policy).unwrap();
+    }
+    assert!(customer.add_tag("egy még", &policy).is_err());
+    assert_eq!(customer.tags.len(), MAX_TAGS);
+    assert!(customer.remove_tag("Tavaszi akció"));
+    assert!(!customer.remove_tag("tavaszi akció"));
+    // Group
+    assert!(customer.set_group(" Nagykert ", &policy).unwrap());
+    assert_eq!(customer.group.as_deref(), Some("nagykert"));
+    assert!(!customer.set_group("nagykert", &policy).unwrap());
+    assert!(customer.set_group("nagy/kert", &policy).is_err());
+    assert!(customer.set_group(" ", &policy).unwrap());
+    assert_eq!(customer.group, None);
+  }
+
+  #[test]
+  fn test_deactivation() {
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    assert!(customer.active);
+    assert!(customer.reactivate("".to_string(), 2).is_err());
+    customer
+      .deactivate(ReasonCode::Debt, " Tartozás ".to_string(), 2)
+      .unwrap();
+    assert!(!customer.active);
+    assert!(customer
+      .deactivate(ReasonCode::Other, "".to_string(), 2)
+      .is_err());
+    customer.reactivate("Rendezve".to_string(), 3).unwrap();
+    assert!(customer.active);
+    assert_eq!(customer.status_history.len(), 2);
+    assert_eq!(customer.status_history[0].reason, Some(ReasonCode::Debt));
+    assert_eq!(customer.status_history[0].comment, "Tartozás");
+    assert_eq!(customer.status_history[1].reason, None);
+  }
+
+  #[test]
+  fn test_attachments() {
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    let id = customer
+      .attach_document(DocumentKind::Contract, "contracts/1.pdf".to_string(), 2)
+      .unwrap()
+      .id;
+    assert_eq!(id, 1);
+    let id = customer
+      .attach_document(DocumentKind::IdScan, " ids/1.jpg ".to_string(), 2)
+      .unwrap()
+      .id;
+    assert_eq!(id, 2);
+    assert_eq!(customer.attachments[1].storage_key, "ids/1.jpg");
+    // Same key cannot be attached twice
+    assert!(customer
+      .attach_document(DocumentKind::Other, "ids/1.jpg".to_string(), 2)
+      .is_err());
+    assert!(customer
+      .attach_document(DocumentKind::Other, " ".to_string(), 2)
+      .is_err());
+    assert_eq!(
+      customer.remove_document(1).unwrap().storage_key,
+      "contracts/1.pdf"
+    );
+    assert!(customer.remove_document(1).is_err());
+    // IDs are not reused while later ones exist
+    let id = customer
+      .attach_document(DocumentKind::Avatar, "avatars/1.png".to_string(), 2)
+      .unwrap()
+      .id;
+    assert_eq!(id, 3);
+  }
+
+  #[test]
+  fn test_preferred_contact() {
+    let policy = ValidationPolicy::default();
+    let mut customer = Customer {
+      name: "Kiss Béla".to_string(),
+      phone: "+36301234567".to_string(),
+      preferred_contact: Some(ContactChannel::Sms),
+      ..Customer::default()
+    };
+    assert!(customer.validate(&policy).is_ok());
+    customer.preferred_contact = Some(ContactChannel::Email);
+    assert!(customer.validate(&policy).is_err());
+    customer.email = "kiss.bela@example.com".to_string();
+    assert!(customer.validate(&policy).is_ok());
+    customer.preferred_contact = Some(ContactChannel::Post);
+    customer.address_zip = "6000".to_string();
+    customer.address_location = "Kecskemét".to_string();
+    assert!(customer.validate(&policy).is_err());
+    customer.address_street = "Fő utca 1".to_string();
+    assert!(customer.validate(&policy).is_ok());
+    // Not specified on update keeps the current one
+    let update = |customer: &mut Customer, phone: &str, channel| {
+      customer
+        .update(
+          "Kiss Béla".to_string(),
+          "".to_string(),
+          phone.to_string(),
+          None,
+          "".to_string(),
+          "".to_string(),
+          "".to_string(),
+          None,
+          None,
+          CustomerKind::Private,
+          false,
+          channel,
+          &policy,
+        )
+        .map(|c| c.preferred_contact)
+    };
+    assert!(update(&mut customer, "+36301234567", None).is_err());
+    assert_eq!(
+      update(&mut customer, "+36301234567", Some(ContactChannel::Phone)).unwrap(),
+      Some(ContactChannel::Phone)
+    );
+    assert_eq!(
+      update(&mut customer, "+36307654321", None).unwrap(),
+      Some(ContactChannel::Phone)
+    );
+    assert!(update(&mut customer, "", None).is_err());
+    customer.anonymize("", Utc::now());
+    assert!(customer.validate(&policy).is_ok());
+  }
+
+  #[test]
+  fn test_strict_invoicing() {
+    let policy = ValidationPolicy {
+      strict_invoicing: true,
+      company_tax_number_required: false,
+      ..ValidationPolicy::default()
+    };
+    let mut customer = Customer {
+      name: "Kert Kft".to_string(),
+      kind: CustomerKind::Company,
+      ..Customer::default()
+    };
+    assert!(customer.validate(&policy).is_ok());
+    customer.invoiceable = true;
+    assert!(customer.validate(&policy).is_err());
+    assert!(customer.validate(&ValidationPolicy::default()).is_err());
+    customer.kind = CustomerKind::Private;
+    assert!(customer.validate(&policy).is_ok());
+    assert_eq!(
+      customer.invoice_missing_fields(),
+      ["address_zip", "address_location", "address_street"]
+    );
+    customer.kind = CustomerKind::Company;
+    customer.invoice_address = Some(InvoiceAddress {
+      zip: "6000".to_string(),
+      location: "Kecskemét".to_string(),
+      street: "Fő utca 1".to_string(),
+    });
+    assert_eq!(customer.invoice_missing_fields(), ["tax_number"]);
+  }
+
+  #[test]
+  fn test_completeness() {
+    let mut customer = Customer {
+      name: "Kiss Béla".to_string(),
+      ..Customer::default()
+    };
+    assert_eq!(customer.completeness_score(), 0);
+    assert_eq!(
+      customer.completeness_missing_fields(),
+      [
+        "address_zip",
+        "address_location",
+        "address_street",
+        "email",
+        "phone"
+      ]
+    );
+    customer.email = "kiss.bela@example.com".to_string();
+    customer.address_zip = "6000".to_string();
+    assert_eq!(customer.completeness_score(), 40);
+    customer.address_location = "Kecskemét".to_string();
+    customer.address_street = "Fő utca 1".to_string();
+    customer.phone = "+36 30 123 4567".to_string();
+    assert_eq!(customer.completeness_score(), 100);
+    // Companies need a tax number as well
+    customer.kind = CustomerKind::Company;
+    assert_eq!(customer.completeness_score(), 83);
+    assert_eq!(customer.completeness_missing_fields(), ["tax_number"]);
+  }
+
+  #[test]
+  fn test_set_discount() {
+    let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+    assert_eq!(customer.discount_set_at, None);
+    let now = Utc::now();
+    customer.set_discount(15, 7, now).unwrap();
+    assert_eq!(
+      (
+        customer.default_discount_percent,
+        customer.discount_set_by,
+        customer.discount_set_at
+      ),
+      (15, 7, Some(now))
+    );
+    match customer.set_discount(101, 8, now) {
+      Err(InvalidFields(violations)) => {
+        assert_eq!(violations[0].field, "default_discount_percent")
+      }
+      _ => panic!("Discount over 100% must be rejected"),
+    }
+    // Failed changes keep the discount
+    assert_eq!(
+      (customer.default_discount_percent, customer.discount_set_by),
+      (15, 7)
+    );
+    customer.set_discount(0, 8, now).unwrap();
+    assert_eq!(customer.default_discount_percent, 0);
+  }
+
+  // Gateway input, short text or long text with control,
+  // invisible and multi-byte characters
+  fn gateway_text() -> impl Strategy<Value = String> {
+    prop_oneof![
+      "\\PC{0,40}",
+      proptest::collection::vec(
+        prop_oneof![
+          any::<char>(),
+          Just('\u{202E}'),
+          Just('\u{200B}'),
+          Just('\n'),
+          Just('\0'),
+          Just('ő'),
+        ],
+        0..800
+      )
+      .prop_map(|chars| chars.into_iter().collect::<String>()),
+    ]
+  }
+
+  // Stored text is sanitized and fits the byte limit
+  fn is_clean(value: &str) -> bool {
+    sanitize::line(value).ok().as_deref() == Some(value)
+  }
+
+  fn is_clean_customer(customer: &Customer) -> bool {
+    [
+      &customer.name,
+      &customer.email,
+      &customer.phone,
+      &customer.address_zip,
+      &customer.address_location,
+      &customer.address_street,
+      &customer.country,
+    ]
+    .iter()
+    .all(|value| is_clean(value))
+      && customer.loyalty_card_id.iter().all(|id| is_clean(id))
+      && customer.invoice_name.iter().all(|name| is_clean(name))
+      && customer.aliases.iter().all(|alias| is_clean(alias))
+  }
+
+  proptest! {
+    // Every case runs the whole create and update path
+    #![proptest_config(ProptestConfig::with_cases(64))]
+    #[test]
+    fn prop_create_update_never_store_unclean_text(
+      name in gateway_text(),
+      email in gateway_text(),
+      phone in gateway_text(),
+      address in gateway_text(),
+      alias in gateway_text(),
+    ) {
+      let policy = ValidationPolicy::default();
+      if let Ok(customer) = Customer::new(
+        1,
+        name.clone(),
+        email.clone(),
+        phone.clone(),
+        None,
+        address.clone(),
+        address.clone(),
+        address.clone(),
+        address.clone(),
+        Some(phone.clone()),
+        1,
+        CustomerKind::Private,
+        false,
+        None,
+        &policy,
+      ) {
+        prop_assert!(is_clean_customer(&customer));
+      }
+      if let Ok(customer) = Customer::new_quick(1, name.clone(), phone.clone(), 1, &policy) {
+        prop_assert!(is_clean_customer(&customer));
+      }
+      let mut customer = new_customer(CustomerKind::Private, None).unwrap();
+      let _ = customer.update(
+        name.clone(),
+        email.clone(),
+        phone.clone(),
+        None,
+        address.clone(),
+        address.clone(),
+        address.clone(),
+        Some(address.clone()),
+        Some(phone.clone()),
+        CustomerKind::Private,
+        false,
+        None,
+        &policy,
+      );
+      let _ = customer.update_profile(
+        email,
+        phone,
+        address.clone(),
+        address.clone(),
+        address.clone(),
+        None,
+        &policy,
+      );
+      let _ = customer.set_invoice_details(
+        Some(name),
+        Some(InvoiceAddress {
+          zip: address.clone(),
+          location: address.clone(),
+          street: address,
+        }),
+        &policy,
+      );
+      let _ = customer.add_alias(&alias, &policy);
+      // Failed changes keep the customer as it was
+      prop_assert!(is_clean_customer(&customer));
+    }
+  }
+}
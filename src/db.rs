@@ -0,0 +1,1457 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::audit_sink::MutationLog;
+use crate::customer::{Customer, ReasonCode};
+use crate::events::CustomerEventKind;
+use crate::history::{self, FieldHistory, HistoryEntry};
+use crate::outbox::Outbox;
+use crate::prelude::*;
+use crate::replication::Journal;
+use crate::search;
+use crate::shipping;
+use crate::storage::{CompactReport, CustomerStore, MemoryStore};
+use crate::taxnumber::TaxNumber;
+use chrono::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::Arc;
+
+// Customer db
+//
+// Customer storage with its in-memory indexes.
+// Every mutation must go through CustomerDb
+// to keep the indexes in sync.
+pub struct CustomerDb {
+  customers: Box<dyn CustomerStore>,
+  id_index: BTreeSet<u32>,                               // ids
+  created_index: BTreeSet<(DateTime<Utc>, u32)>,         // (date_created, id)
+  created_by_index: BTreeSet<(u32, DateTime<Utc>, u32)>, // (created_by, date_created, id)
+  name_index: BTreeSet<(String, u32)>,                   // (sort key, id)
+  zip_index: BTreeSet<(String, u32)>,                    // (address_zip, id)
+  location_index: HashMap<String, BTreeSet<u32>>,        // folded address_location => ids
+  street_index: HashMap<String, BTreeSet<u32>>,          // trigram of folded address_street => ids
+  modified_index: BTreeSet<(DateTime<Utc>, u32)>,        // (last_modified, id)
+  loyalty_index: HashMap<String, u32>,                   // loyalty_card_id => id
+  external_id_index: HashMap<String, u32>,               // external_id => id
+  email_index: HashMap<String, BTreeSet<u32>>,           // lowercase email => ids
+  tax_number_index: HashMap<String, BTreeSet<u32>>,      // tax number => ids
+  blocked_index: BTreeMap<u32, Blocked>,                 // inactive id => last deactivation
+  journal: Option<(String, Arc<Journal>)>,               // (tenant, replication journal)
+  mutation_log: Option<(String, Arc<MutationLog>)>,      // (tenant, audit log of changes)
+  history: Option<(String, Arc<FieldHistory>)>,          // (tenant, field history)
+  outbox: Option<(String, Arc<Outbox>)>,                 // (tenant, event outbox)
+  shipping: Option<(String, Arc<Outbox>)>,               // (tenant, shipping notifications)
+  read_only: bool,                                       // Standby, customers come from the primary
+}
+
+// Customer address filter
+// Empty parts match every customer
+#[derive(Clone, Debug, Default)]
+pub struct AddressFilter {
+  pub zip_prefix: String,
+  pub location: String,
+  pub street: String,
+}
+
+impl AddressFilter {
+  pub fn is_empty(&self) -> bool {
+    self.zip_prefix.trim().is_empty()
+      && search::fold(&self.location).is_empty()
+      && search::fold(&self.street).is_empty()
+  }
+}
+
+// Last deactivation of a blocked customer
+// None if the customer was stored inactive
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Blocked {
+  pub reason: Option<ReasonCode>,
+  pub since: Option<DateTime<Utc>>,
+}
+
+impl Blocked {
+  fn of(customer: &Customer) -> Self {
+    match customer.status_history.iter().rev().find(|c| !c.active) {
+      Some(change) => Self {
+        reason: change.reason,
+        since: Some(change.date_changed),
+      },
+      None => Self::default(),
+    }
+  }
+}
+
+// Sort order of customer lists
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+  Name,
+  Created,
+  Zip,
+  Modified,
+}
+
+impl CustomerDb {
+  // Init customer db and build its indexes
+  #[cfg(test)]
+  pub fn new(customers: impl CustomerStore + 'static) -> Self {
+    Self::from_store(Box::new(customers))
+  }
+  pub fn from_store(customers: Box<dyn CustomerStore>) -> Self {
+    let mut db = Self {
+      customers,
+      id_index: BTreeSet::new(),
+      created_index: BTreeSet::new(),
+      created_by_index: BTreeSet::new(),
+      name_index: BTreeSet::new(),
+      zip_index: BTreeSet::new(),
+      location_index: HashMap::new(),
+      street_index: HashMap::new(),
+      modified_index: BTreeSet::new(),
+      loyalty_index: HashMap::new(),
+      external_id_index: HashMap::new(),
+      email_index: HashMap::new(),
+      tax_number_index: HashMap::new(),
+      blocked_index: BTreeMap::new(),
+      journal: None,
+      mutation_log: None,
+      history: None,
+      outbox: None,
+      shipping: None,
+      read_only: false,
+    };
+    db.rebuild_indexes();
+    db
+  }
+  // Rebuild all in-memory indexes from storage
+  pub fn rebuild_indexes(&mut self) {
+    self.id_index = BTreeSet::new();
+    self.created_index = BTreeSet::new();
+    self.created_by_index = BTreeSet::new();
+    self.name_index = BTreeSet::new();
+    self.zip_index = BTreeSet::new();
+    self.location_index = HashMap::new();
+    self.street_index = HashMap::new();
+    self.modified_index = BTreeSet::new();
+    self.loyalty_index = HashMap::new();
+    self.external_id_index = HashMap::new();
+    self.email_index = HashMap::new();
+    self.tax_number_index = HashMap::new();
+    self.blocked_index = BTreeMap::new();
+    // Storage is moved out while scanning, so customers are not cloned
+    let customers = std::mem::replace(&mut self.customers, Box::new(MemoryStore::new()));
+    customers.scan(&mut |c| self.add_to_indexes(c));
+    self.customers = customers;
+  }
+  fn add_to_indexes(&mut self, c: &Customer) {
+    self.id_index.insert(c.id);
+    self.created_index.insert((c.date_created, c.id));
+    self
+      .created_by_index
+      .insert((c.created_by, c.date_created, c.id));
+    self.name_index.insert((name_key(c), c.id));
+    self.zip_index.insert((c.address_zip.clone(), c.id));
+    index_add(&mut self.location_index, location_key(c), c.id);
+    for trigram in search::trigrams(&search::fold(&c.address_street)) {
+      index_add(&mut self.street_index, Some(trigram), c.id);
+    }
+    self.modified_index.insert((c.last_modified, c.id));
+    if let Some(card) = &c.loyalty_card_id {
+      self.loyalty_index.insert(card.clone(), c.id);
+    }
+    if let Some(external_id) = &c.external_id {
+      self.external_id_index.insert(external_id.clone(), c.id);
+    }
+    index_add(&mut self.email_index, email_key(c), c.id);
+    index_add(&mut self.tax_number_index, tax_number_key(c), c.id);
+    if !c.active {
+      self.blocked_index.insert(c.id, Blocked::of(c));
+    }
+  }
+  fn remove_from_indexes(&mut self, c: &Customer) {
+    self.id_index.remove(&c.id);
+    self.created_index.remove(&(c.date_created, c.id));
+    self
+      .created_by_index
+      .remove(&(c.created_by, c.date_created, c.id));
+    self.name_index.remove(&(name_key(c), c.id));
+    self.zip_index.remove(&(c.address_zip.clone(), c.id));
+    index_remove(&mut self.location_index, location_key(c), c.id);
+    for trigram in search::trigrams(&search::fold(&c.address_street)) {
+      index_remove(&mut self.street_index, Some(trigram), c.id);
+    }
+    self.modified_index.remove(&(c.last_modified, c.id));
+    if let Some(card) = &c.loyalty_card_id {
+      if self.loyalty_index.get(card) == Some(&c.id) {
+        self.loyalty_index.remove(card);
+      }
+    }
+    if let Some(external_id) = &c.external_id {
+      if self.external_id_index.get(external_id) == Some(&c.id) {
+        self.external_id_index.remove(external_id);
+      }
+    }
+    index_remove(&mut self.email_index, email_key(c), c.id);
+    index_remove(&mut self.tax_number_index, tax_number_key(c), c.id);
+    self.blocked_index.remove(&c.id);
+  }
+  // Customers in storage order
+  pub fn iter(&self) -> impl Iterator<Item = &Customer> + '_ {
+    self.customers.iter()
+  }
+  pub fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    self.customers.find_id(id)
+  }
+  // Check ID is not taken
+  pub fn check_id_available(&self, id: &u32) -> bool {
+    self.customers.find_id(id).is_err()
+  }
+  pub fn len(&self) -> usize {
+    self.customers.len()
+  }
+  // Divergences of the shadow storage, if there is one
+  pub fn shadow_divergences(&self) -> Option<u64> {
+    self.customers.shadow_divergences()
+  }
+  // Compact storage and rebuild indexes
+  pub fn compact(&mut self) -> ServiceResult<CompactReport> {
+    self.check_writable()?;
+    let report = self.customers.compact()?;
+    self.rebuild_indexes();
+    Ok(report)
+  }
+  // Record stored customers in the replication journal
+  pub fn set_journal(&mut self, tenant: &str, journal: Arc<Journal>) {
+    self.journal = Some((tenant.to_string(), journal));
+  }
+  // Log inserts and updates in the mutation audit log
+  // Replicated customers are logged by the primary
+  pub fn set_mutation_log(&mut self, tenant: &str, mutation_log: Arc<MutationLog>) {
+    self.mutation_log = Some((tenant.to_string(), mutation_log));
+  }
+  // Record the field diffs of inserts and updates
+  pub fn set_history(&mut self, tenant: &str, history: Arc<FieldHistory>) {
+    self.history = Some((tenant.to_string(), history));
+  }
+  // Stage a Created or Updated event of every insert and update
+  // in the commit storing it, so the event is delivered if and only
+  // if the change is stored
+  pub fn set_outbox(&mut self, tenant: &str, outbox: Arc<Outbox>) {
+    self.outbox = Some((tenant.to_string(), outbox));
+  }
+  // Stage a notification of every address change in the commit
+  // storing it, like the events of set_outbox
+  pub fn set_shipping(&mut self, tenant: &str, outbox: Arc<Outbox>) {
+    self.shipping = Some((tenant.to_string(), outbox));
+  }
+  // Reject inserts and updates, only put() can change customers
+  pub fn set_read_only(&mut self, read_only: bool) {
+    self.read_only = read_only;
+  }
+  fn check_writable(&self) -> ServiceResult<()> {
+    check_writable(self.read_only)
+  }
+  fn stored(&self, customer: &Customer) {
+    if let Some((tenant, journal)) = &self.journal {
+      journal.record(tenant, customer);
+    }
+  }
+  // Start a transaction
+  // Nothing is stored until it is committed
+  pub fn transaction(&mut self) -> Transaction<'_> {
+    Transaction {
+      db: self,
+      staged: BTreeMap::new(),
+    }
+  }
+  // Run f in a transaction, and commit it if f succeeds
+  pub fn atomic<F, R>(&mut self, f: F) -> ServiceResult<R>
+  where
+    F: FnOnce(&mut Transaction) -> ServiceResult<R>,
+  {
+    self.check_writable()?;
+    let mut tx = self.transaction();
+    let res = f(&mut tx)?;
+    tx.commit()?;
+    Ok(res)
+  }
+  // Insert new customer
+  pub fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    self.atomic(|tx| tx.insert(customer))
+  }
+  // Store customer as it is, replacing the one with its ID
+  // Used by replication, the customer is not checked,
+  // and its modification date is kept
+  pub fn put(&mut self, customer: Customer) -> ServiceResult<()> {
+    match self.customers.find_id(&customer.id) {
+      Ok(current) => {
+        let current = current.clone();
+        self.customers.update(customer.clone())?;
+        self.remove_from_indexes(&current);
+      }
+      Err(_) => self.customers.insert(customer.clone())?,
+    }
+    self.add_to_indexes(&customer);
+    self.stored(&customer);
+    Ok(())
+  }
+  // Update customer by ID
+  // modified_by is the user making the change, 0 for the service itself
+  // If the update closure fails, or the updated customer
+  // conflicts with an other one, the customer is rolled back
+  pub fn update<F, R>(&mut self, id: &u32, modified_by: u32, f: F) -> ServiceResult<R>
+  where
+    F: FnOnce(&mut Customer) -> ServiceResult<R>,
+  {
+    self.atomic(|tx| tx.update(id, modified_by, f))
+  }
+  // Derive display names and sort keys of every customer
+  // again, e.g. after the rules changed
+  // Returns the updated customers
+  pub fn rederive_names(&mut self) -> ServiceResult<Vec<Customer>> {
+    let ids = self
+      .customers
+      .iter()
+      .filter(|c| Customer::clone(c).derive_names())
+      .map(|c| c.id)
+      .collect::<Vec<u32>>();
+    self.atomic(|tx| {
+      let mut res = Vec::new();
+      for id in ids {
+        tx.update(&id, 0, |_| Ok(()))?;
+        res.push(tx.find_id(&id)?.clone());
+      }
+      Ok(res)
+    })
+  }
+  // Customers whose name starts with prefix, in name order
+  // Matched without case and accents
+  pub fn name_prefix(&self, prefix: &str) -> Vec<u32> {
+    let mut res = search::sort_key_prefixes(prefix)
+      .into_iter()
+      .flat_map(|p| {
+        self
+          .name_index
+          .range((p.clone(), 0)..)
+          .take_while(move |(key, _)| key.starts_with(p.as_str()))
+      })
+      .collect::<Vec<&(String, u32)>>();
+    res.sort();
+    res.into_iter().map(|(_, id)| *id).collect()
+  }
+  // Customers matching every part of the address filter, in zip order
+  // Zips match by prefix, settlements as a whole and streets by
+  // substring, settlements and streets without case and accents
+  pub fn find_by_address(&self, filter: &AddressFilter) -> Vec<u32> {
+    let zip_prefix = filter.zip_prefix.trim();
+    let location = search::fold(&filter.location);
+    let street = search::fold(&filter.street);
+    // Candidates found by every index used
+    let mut candidates: Option<BTreeSet<u32>> = None;
+    let mut narrow = |ids: BTreeSet<u32>| {
+      candidates = Some(match candidates.take() {
+        Some(candidates) => candidates.intersection(&ids).copied().collect(),
+        None => ids,
+      });
+    };
+    if !zip_prefix.is_empty() {
+      narrow(
+        self
+          .zip_index
+          .range((zip_prefix.to_string(), 0)..)
+          .take_while(|(zip, _)| zip.starts_with(zip_prefix))
+          .map(|(_, id)| *id)
+          .collect(),
+      );
+    }
+    if !location.is_empty() {
+      narrow(
+        self
+          .location_index
+          .get(&location)
+          .cloned()
+          .unwrap_or_default(),
+      );
+    }
+    // Streets shorter than a trigram are checked one by one
+    for trigram in search::trigrams(&street) {
+      narrow(self.street_index.get(&trigram).cloned().unwrap_or_default());
+    }
+    let ids = match candidates {
+      Some(ids) => ids.into_iter().collect(),
+      None => self.customers.iter().map(|c| c.id).collect::<Vec<u32>>(),
+    };
+    // Trigrams can be found apart from each other
+    let ids = ids
+      .into_iter()
+      .filter(|id| {
+        self
+          .find_id(id)
+          .is_ok_and(|c| search::fold(&c.address_street).contains(&street))
+      })
+      .collect();
+    self.sort_ids(ids, SortBy::Zip, false)
+  }
+  // Sort customer IDs by the given index
+  // IDs not found in storage are dropped
+  pub fn sort_ids(&self, ids: Vec<u32>, sort_by: SortBy, descending: bool) -> Vec<u32> {
+    let ids = ids.into_iter().collect::<HashSet<u32>>();
+    let sorted: Box<dyn DoubleEndedIterator<Item = u32> + '_> = match sort_by {
+      SortBy::Name => Box::new(self.name_index.iter().map(|(_, id)| *id)),
+      SortBy::Created => Box::new(self.created_index.iter().map(|(_, id)| *id)),
+      SortBy::Zip => Box::new(self.zip_index.iter().map(|(_, id)| *id)),
+      SortBy::Modified => Box::new(self.modified_index.iter().map(|(_, id)| *id)),
+    };
+    match descending {
+      true => sorted.rev().filter(|id| ids.contains(id)).collect(),
+      false => sorted.filter(|id| ids.contains(id)).collect(),
+    }
+  }
+  // Get an other customer ID with the same email
+  pub fn email_conflict(&self, customer: &Customer) -> Option<u32> {
+    index_conflict(&self.email_index, email_key(customer), customer.id)
+  }
+  // Get an other customer ID with the same tax number
+  pub fn tax_number_conflict(&self, customer: &Customer) -> Option<u32> {
+    index_conflict(
+      &self.tax_number_index,
+      tax_number_key(customer),
+      customer.id,
+    )
+  }
+  // Get customer ID by loyalty card ID
+  pub fn find_loyalty_card(&self, loyalty_card_id: &str) -> Option<u32> {
+    self.loyalty_index.get(loyalty_card_id).copied()
+  }
+  // Get customer ID by external ID
+  pub fn find_external_id(&self, external_id: &str) -> Option<u32> {
+    self.external_id_index.get(external_id).copied()
+  }
+  // Deactivation of a blocked customer, None if it is active
+  // Served from the index, the customer is not read
+  pub fn blocked(&self, id: &u32) -> Option<Blocked> {
+    self.blocked_index.get(id).copied()
+  }
+  // Blocked customers in ID order
+  pub fn blocked_customers(&self) -> impl Iterator<Item = (u32, Blocked)> + '_ {
+    self.blocked_index.iter().map(|(id, b)| (*id, *b))
+  }
+  // Get customer IDs with the tax number
+  pub fn find_tax_number(&self, tax_number: &TaxNumber) -> Vec<u32> {
+    self
+      .tax_number_index
+      .get(&tax_number.to_string())
+      .map(|ids| ids.iter().copied().collect())
+      .unwrap_or_default()
+  }
+  // Get customer IDs created in the given date range
+  // from is inclusive, to is exclusive
+  // Result is sorted by created date
+  pub fn created_between(
+    &self,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+  ) -> Vec<u32> {
+    let lower = match from {
+      Some(from) => Bound::Included((from, 0)),
+      None => Bound::Unbounded,
+    };
+    let upper = match to {
+      Some(to) => Bound::Excluded((to, 0)),
+      None => Bound::Unbounded,
+    };
+    if let (Bound::Included(l), Bound::Excluded(u)) = (&lower, &upper) {
+      if l >= u {
+        return Vec::new();
+      }
+    }
+    self
+      .created_index
+      .range((lower, upper))
+      .map(|(_, id)| *id)
+      .collect()
+  }
+  // Get (created_by, date_created, customer ID) of the customers
+  // created by the given user, or by anyone, in the given date range
+  // from is inclusive, to is exclusive
+  // Result is sorted by creator and created date
+  pub fn created_by(
+    &self,
+    created_by: Option<u32>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+  ) -> Vec<(u32, DateTime<Utc>, u32)> {
+    let in_range = |date: &DateTime<Utc>| {
+      from.is_none_or(|from| *date >= from) && to.is_none_or(|to| *date < to)
+    };
+    let items: Box<dyn Iterator<Item = &(u32, DateTime<Utc>, u32)>> = match created_by {
+      Some(uid) => Box::new(self.created_by_index.range(
+        (uid, from.unwrap_or(DateTime::<Utc>::MIN_UTC), 0)
+          ..=(uid, DateTime::<Utc>::MAX_UTC, u32::MAX),
+      )),
+      None => Box::new(self.created_by_index.iter()),
+    };
+    items
+      .filter(|(_, date, _)| in_range(date))
+      .copied()
+      .collect()
+  }
+  // Get the next chunk of the given customers by ID, after
+  // the last ID of the previous chunk, or from the start if None.
+  // Chunks are not shifted by customers stored in the meantime.
+  // Returns at most limit customers and the last ID looked at,
+  // the next chunk should start after.
+  pub fn chunk_from(
+    &self,
+    after: Option<u32>,
+    ids: &BTreeSet<u32>,
+    limit: usize,
+  ) -> (Vec<Customer>, Option<u32>) {
+    let mut res = Vec::new();
+    let mut last = after;
+    for id in ids.range(id_range(after)) {
+      if res.len() >= limit {
+        break;
+      }
+      last = Some(*id);
+      if let Ok(c) = self.customers.find_id(id) {
+        res.push(c.clone());
+      }
+    }
+    (res, last)
+  }
+  // IDs of the customers f matches, scanning at most count
+  // customers by ID after the given one, until limit matches.
+  // Returns the IDs and the last ID scanned, the next scan
+  // should start after.
+  pub fn scan_from(
+    &self,
+    after: Option<u32>,
+    count: usize,
+    limit: usize,
+    f: impl Fn(&Customer) -> bool,
+  ) -> (Vec<u32>, Option<u32>) {
+    let mut res = Vec::new();
+    let mut last = after;
+    for id in self.id_index.range(id_range(after)).take(count) {
+      if res.len() >= limit {
+        break;
+      }
+      last = Some(*id);
+      if self.customers.find_id(id).is_ok_and(&f) {
+        res.push(*id);
+      }
+    }
+    (res, last)
+  }
+}
+
+// Multi-record change
+//
+// Inserts and updates are staged on copies, and checked against
+// the stored and the other staged customers. Commit stores them
+// as one storage batch, and updates the indexes only after it
+// succeeded, so either every staged change is applied or none
+// of them. Dropping the transaction discards the staged changes.
+pub struct Transaction<'a> {
+  db: &'a mut CustomerDb,
+  staged: BTreeMap<u32, Customer>,
+}
+
+impl<'a> Transaction<'a> {
+  // Staged version of the customer, or else the stored one
+  pub fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    match self.staged.get(id) {
+      Some(customer) => Ok(customer),
+      None => self.db.find_id(id),
+    }
+  }
+  // Stage new customer
+  pub fn insert(&mut self, mut customer: Customer) -> ServiceResult<()> {
+    if self.find_id(&customer.id).is_ok() {
+      return Err(ServiceError::customer_exists(customer.id));
+    }
+    self.check_unique_keys(&customer)?;
+    customer.derive_names();
+    self.staged.insert(customer.id, customer);
+    Ok(())
+  }
+  // Stage update of a customer
+  // Nothing is staged if the update closure fails,
+  // or the updated customer conflicts with an other one
+  pub fn update<F, R>(&mut self, id: &u32, modified_by: u32, f: F) -> ServiceResult<R>
+  where
+    F: FnOnce(&mut Customer) -> ServiceResult<R>,
+  {
+    let mut customer = self.find_id(id)?.clone();
+    let res = f(&mut customer)?;
+    self.check_unique_keys(&customer)?;
+    customer.last_modified = Utc::now();
+    customer.last_modified_by = modified_by;
+    customer.derive_names();
+    self.staged.insert(customer.id, customer);
+    Ok(res)
+  }
+  // Store every staged change
+  pub fn commit(self) -> ServiceResult<()> {
+    let db = self.db;
+    db.check_writable()?;
+    let batch = self.staged.into_values().collect::<Vec<Customer>>();
+    if batch.is_empty() {
+      return Ok(());
+    }
+    let previous = batch
+      .iter()
+      .filter_map(|c| db.customers.find_id(&c.id).ok().cloned())
+      .collect::<Vec<Customer>>();
+    // Both are in ID order
+    let before = |id: u32| {
+      previous
+        .binary_search_by_key(&id, |c| c.id)
+        .ok()
+        .map(|i| &previous[i])
+    };
+    let mut outboxes = Vec::new();
+    if let Some((tenant, outbox)) = &db.outbox {
+      let events = batch
+        .iter()
+        .map(|c| match before(c.id) {
+          Some(_) => (CustomerEventKind::Updated, c),
+          None => (CustomerEventKind::Created, c),
+        })
+        .collect::<Vec<(CustomerEventKind, &Customer)>>();
+      outboxes.push((tenant, outbox, events));
+    }
+    if let Some((tenant, outbox)) = &db.shipping {
+      let events = batch
+        .iter()
+        .filter(|c| before(c.id).is_some_and(|b| shipping::address_changed(b, c)))
+        .map(|c| (CustomerEventKind::Updated, c))
+        .collect::<Vec<(CustomerEventKind, &Customer)>>();
+      outboxes.push((tenant, outbox, events));
+    }
+    let mut staged = Vec::new();
+    for (tenant, outbox, events) in outboxes {
+      if events.is_empty() {
+        continue;
+      }
+      match outbox.stage(tenant, &events) {
+        Ok(ids) => staged.push((outbox, ids)),
+        Err(error) => {
+          for (outbox, ids) in &staged {
+            outbox.cancel(ids);
+          }
+          return Err(error);
+        }
+      }
+    }
+    if let Err(error) = db.customers.write_batch(batch.clone()) {
+      for (outbox, ids) in &staged {
+        outbox.cancel(ids);
+      }
+      return Err(error);
+    }
+    for (outbox, ids) in &staged {
+      outbox.confirm(ids);
+    }
+    for customer in &previous {
+      db.remove_from_indexes(customer);
+    }
+    let mut entries = Vec::new();
+    for customer in &batch {
+      db.add_to_indexes(customer);
+      db.stored(customer);
+      if db.mutation_log.is_none() && db.history.is_none() {
+        continue;
+      }
+      let before = before(customer.id);
+      // The customers are already stored, so errors are only reported
+      let changes = match history::diff(before, customer) {
+        Ok(changes) => changes,
+        Err(error) => {
+          eprintln!(
+            "Error while comparing versions of customer {}: {}",
+            customer.id, error
+          );
+          continue;
+        }
+      };
+      if let Some((tenant, mutation_log)) = &db.mutation_log {
+        mutation_log.record(tenant, before, customer, changes.clone());
+      }
+      if db.history.is_some() {
+        entries.push(HistoryEntry::new(before, customer, changes));
+      }
+    }
+    if let Some((tenant, history)) = &db.history {
+      if let Err(error) = history.append(tenant, &entries) {
+        eprintln!("Error while writing field history: {}", error);
+      }
+    }
+    Ok(())
+  }
+  // Check loyalty card and external IDs are not used by an other customer
+  fn check_unique_keys(&self, customer: &Customer) -> ServiceResult<()> {
+    self.check_unique_key(
+      customer,
+      |c| c.loyalty_card_id.as_ref(),
+      &self.db.loyalty_index,
+      "Ez a hűségkártya már egy másik ügyfélhez tartozik",
+    )?;
+    self.check_unique_key(
+      customer,
+      |c| c.external_id.as_ref(),
+      &self.db.external_id_index,
+      "Ez a külső azonosító már egy másik ügyfélhez tartozik",
+    )
+  }
+  fn check_unique_key(
+    &self,
+    customer: &Customer,
+    key_of: fn(&Customer) -> Option<&String>,
+    index: &HashMap<String, u32>,
+    message: &str,
+  ) -> ServiceResult<()> {
+    let key = match key_of(customer) {
+      Some(key) => key,
+      None => return Ok(()),
+    };
+    let staged = self
+      .staged
+      .values()
+      .find(|c| c.id != customer.id && key_of(c) == Some(key))
+      .map(|c| c.id);
+    // Staged customers are checked by their staged version
+    let stored = index
+      .get(key)
+      .copied()
+      .filter(|id| *id != customer.id && !self.staged.contains_key(id));
+    match staged.or(stored) {
+      Some(id) => Err(ServiceError::conflict(message, id)),
+      None => Ok(()),
+    }
+  }
+}
+
+// Error of writes on read only instances
+pub fn check_writable(read_only: bool) -> ServiceResult<()> {
+  match read_only {
+    true => Err(ServiceError::failed_precondition(
+      "Az ügyféladatok ezen a példányon csak olvashatók",
+    )),
+    false => Ok(()),
+  }
+}
+
+// Name index key, in Hungarian alphabetical order
+fn name_key(customer: &Customer) -> String {
+  customer.sort_key.clone()
+}
+
+// Email index key, emails are case insensitive
+pub fn email_key(customer: &Customer) -> Option<String> {
+  match customer.email.trim() {
+    "" => None,
+    email => Some(email.to_lowercase()),
+  }
+}
+
+// Settlements are matched without case and accents
+fn location_key(customer: &Customer) -> Option<String> {
+  match search::fold(&customer.address_location) {
+    location if location.is_empty() => None,
+    location => Some(location),
+  }
+}
+
+pub fn tax_number_key(customer: &Customer) -> Option<String> {
+  customer.tax_number.as_ref().map(|t| t.to_string())
+}
+
+// IDs after the given one, every ID if None
+fn id_range(after: Option<u32>) -> (Bound<u32>, Bound<u32>) {
+  match after {
+    Some(id) => (Bound::Excluded(id), Bound::Unbounded),
+    None => (Bound::Unbounded, Bound::Unbounded),
+  }
+}
+
+fn index_add(index: &mut HashMap<String, BTreeSet<u32>>, key: Option<String>, id: u32) {
+  if let Some(key) = key {
+    index.entry(key).or_default().insert(id);
+  }
+}
+
+fn index_remove(index: &mut HashMap<String, BTreeSet<u32>>, key: Option<String>, id: u32) {
+  if let Some(key) = key {
+    if let Some(ids) = index.get_mut(&key) {
+      ids.remove(&id);
+      if ids.is_empty() {
+        index.remove(&key);
+      }
+    }
+  }
+}
+
+// Get the first other customer ID with the same key
+fn index_conflict(
+  index: &HashMap<String, BTreeSet<u32>>,
+  key: Option<String>,
+  id: u32,
+) -> Option<u32> {
+  index
+    .get(&key?)
+    .and_then(|ids| ids.iter().find(|i| **i != id).copied())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::MemoryStore;
+
+  fn date(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  #[test]
+  fn test_created_between() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for (id, created) in [
+      (1, "2021-01-01T00:00:00Z"),
+      (2, "2021-02-15T10:00:00Z"),
+      (3, "2021-03-31T23:59:59Z"),
+      (4, "2021-04-01T00:00:00Z"),
+    ] {
+      db.insert(Customer {
+        id,
+        date_created: date(created),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let q1 = db.created_between(
+      Some(date("2021-01-01T00:00:00Z")),
+      Some(date("2021-04-01T00:00:00Z")),
+    );
+    assert_eq!(q1, vec![1, 2, 3]);
+    assert_eq!(
+      db.created_between(Some(date("2021-03-01T00:00:00Z")), None),
+      vec![3, 4]
+    );
+    assert_eq!(
+      db.created_between(None, Some(date("2021-02-01T00:00:00Z"))),
+      vec![1]
+    );
+    assert!(db
+      .created_between(
+        Some(date("2021-04-01T00:00:00Z")),
+        Some(date("2021-01-01T00:00:00Z"))
+      )
+      .is_empty());
+  }
+
+  #[test]
+  fn test_created_by() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for (id, created_by, created) in [
+      (1, 7, "2021-01-10T00:00:00Z"),
+      (2, 8, "2021-01-15T00:00:00Z"),
+      (3, 7, "2021-02-01T00:00:00Z"),
+      (4, 7, "2021-03-01T00:00:00Z"),
+    ] {
+      db.insert(Customer {
+        id,
+        created_by,
+        date_created: date(created),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let ids = |res: Vec<(u32, DateTime<Utc>, u32)>| res.iter().map(|r| r.2).collect::<Vec<u32>>();
+    assert_eq!(ids(db.created_by(Some(7), None, None)), [1, 3, 4]);
+    assert_eq!(
+      ids(db.created_by(
+        Some(7),
+        Some(date("2021-02-01T00:00:00Z")),
+        Some(date("2021-03-01T00:00:00Z"))
+      )),
+      [3]
+    );
+    assert_eq!(
+      ids(db.created_by(None, None, Some(date("2021-02-01T00:00:00Z")))),
+      [1, 2]
+    );
+    assert!(db.created_by(Some(9), None, None).is_empty());
+    // Creator changed by a merge
+    db.update(&2, 1, |c| {
+      c.created_by = 7;
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(ids(db.created_by(Some(7), None, None)), [1, 2, 3, 4]);
+  }
+
+  #[test]
+  fn test_scan_from() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for id in 1..=6 {
+      db.insert(Customer {
+        id,
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let odd = |c: &Customer| c.id % 2 == 1;
+    assert_eq!(db.scan_from(None, 4, 10, odd), (vec![1, 3], Some(4)));
+    // Customers stored between scans do not shift the next one
+    db.insert(Customer {
+      id: 9,
+      ..Customer::default()
+    })
+    .unwrap();
+    assert_eq!(db.scan_from(Some(4), 4, 10, odd), (vec![5, 9], Some(9)));
+    assert_eq!(db.scan_from(Some(9), 4, 10, odd), (vec![], Some(9)));
+    // Scan stops at the limit
+    assert_eq!(db.scan_from(None, 10, 2, odd), (vec![1, 3], Some(3)));
+  }
+
+  #[test]
+  fn test_chunk_from() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for id in 1..=5 {
+      db.insert(Customer {
+        id,
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let ids = BTreeSet::from([1, 3, 4, 5, 6]);
+    let (chunk, next) = db.chunk_from(None, &ids, 2);
+    assert_eq!(chunk.iter().map(|c| c.id).collect::<Vec<u32>>(), [1, 3]);
+    assert_eq!(next, Some(3));
+    // Customers stored between chunks do not shift the next one
+    db.insert(Customer {
+      id: 6,
+      ..Customer::default()
+    })
+    .unwrap();
+    let (chunk, next) = db.chunk_from(next, &ids, 2);
+    assert_eq!(chunk.iter().map(|c| c.id).collect::<Vec<u32>>(), [4, 5]);
+    let (chunk, next) = db.chunk_from(next, &ids, 2);
+    assert_eq!(chunk.iter().map(|c| c.id).collect::<Vec<u32>>(), [6]);
+    let (chunk, next) = db.chunk_from(next, &ids, 2);
+    assert!(chunk.is_empty());
+    assert_eq!(next, Some(6));
+  }
+
+  #[test]
+  fn test_loyalty_card_unique() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    let card = |id: &str| Some(id.to_string());
+    db.insert(Customer {
+      id: 1,
+      loyalty_card_id: card("A1"),
+      ..Customer::default()
+    })
+    .unwrap();
+    assert!(db
+      .insert(Customer {
+        id: 2,
+        loyalty_card_id: card("A1"),
+        ..Customer::default()
+      })
+      .is_err());
+    db.insert(Customer {
+      id: 2,
+      ..Customer::default()
+    })
+    .unwrap();
+    // Card of an other customer
+    assert!(db
+      .update(&2, 1, |c| {
+        c.loyalty_card_id = card("A1");
+        Ok(())
+      })
+      .is_err());
+    assert_eq!(db.find_id(&2).unwrap().loyalty_card_id, None);
+    // Card moved to an other customer
+    db.update(&1, 1, |c| {
+      c.loyalty_card_id = card("B1");
+      Ok(())
+    })
+    .unwrap();
+    db.update(&2, 1, |c| {
+      c.loyalty_card_id = card("A1");
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(db.find_loyalty_card("A1"), Some(2));
+    assert_eq!(db.find_loyalty_card("B1"), Some(1));
+    db.rebuild_indexes();
+    assert_eq!(db.find_loyalty_card("A1"), Some(2));
+  }
+
+  #[test]
+  fn test_email_conflict() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    let customer = |id: u32, email: &str| Customer {
+      id,
+      email: email.to_string(),
+      ..Customer::default()
+    };
+    db.insert(customer(1, "kiss@example.com")).unwrap();
+    db.insert(customer(2, "nagy@example.com")).unwrap();
+    assert_eq!(
+      db.email_conflict(&customer(3, " KISS@example.com")),
+      Some(1)
+    );
+    assert_eq!(db.email_conflict(&customer(1, "kiss@example.com")), None);
+    assert_eq!(db.email_conflict(&customer(3, "")), None);
+    // Email changed
+    db.update(&1, 1, |c| {
+      c.email = "kiss.bela@example.com".to_string();
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(db.email_conflict(&customer(3, "kiss@example.com")), None);
+    assert_eq!(
+      db.email_conflict(&customer(3, "kiss.bela@example.com")),
+      Some(1)
+    );
+    db.rebuild_indexes();
+    assert_eq!(db.email_conflict(&customer(3, "nagy@example.com")), Some(2));
+  }
+
+  #[test]
+  fn test_sort_ids() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for (id, name, zip, created) in [
+      (1, "Nagy Anna", "6000", "2021-01-01T00:00:00Z"),
+      (2, "Ádám Béla", "1111", "2021-02-01T00:00:00Z"),
+      (3, "kiss Péter", "2000", "2021-03-01T00:00:00Z"),
+    ] {
+      db.insert(Customer {
+        id,
+        name: name.to_string(),
+        address_zip: zip.to_string(),
+        date_created: date(created),
+        last_modified: date(created),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let all = vec![1, 2, 3];
+    assert_eq!(db.sort_ids(all.clone(), SortBy::Name, false), [2, 3, 1]);
+    assert_eq!(db.sort_ids(all.clone(), SortBy::Zip, false), [2, 3, 1]);
+    assert_eq!(db.sort_ids(all.clone(), SortBy::Created, true), [3, 2, 1]);
+    assert_eq!(db.sort_ids(vec![1, 3], SortBy::Name, true), [1, 3]);
+    // Updates move customers in the indexes
+    db.update(&1, 5, |c| {
+      c.name = "Antal Anna".to_string();
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(db.find_id(&1).map(|c| c.last_modified_by).ok(), Some(5));
+    assert_eq!(db.sort_ids(all.clone(), SortBy::Name, false), [2, 1, 3]);
+    assert_eq!(db.sort_ids(all, SortBy::Modified, true), [1, 3, 2]);
+  }
+
+  #[test]
+  fn test_find_by_address() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for (id, zip, location, street) in [
+      (1, "1114", "Budapest", "Bartók Béla út 12."),
+      (2, "6000", "Kecskemét", "Petőfi Sándor utca 5."),
+      (3, "1111", "Budapest", "Petőfi híd 1."),
+      (4, "6000", "KECSKEMÉT", "Fő tér 1."),
+      (5, "", "", ""),
+    ] {
+      db.insert(Customer {
+        id,
+        address_zip: zip.to_string(),
+        address_location: location.to_string(),
+        address_street: street.to_string(),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let find = |db: &CustomerDb, zip_prefix: &str, location: &str, street: &str| {
+      db.find_by_address(&AddressFilter {
+        zip_prefix: zip_prefix.to_string(),
+        location: location.to_string(),
+        street: street.to_string(),
+      })
+    };
+    // In zip order
+    assert_eq!(find(&db, "11", "", ""), [3, 1]);
+    assert_eq!(find(&db, "1114", "", ""), [1]);
+    assert_eq!(find(&db, "", "kecskemet", ""), [2, 4]);
+    // Settlements match as a whole
+    assert!(find(&db, "", "Kecske", "").is_empty());
+    assert_eq!(find(&db, "", "", "PETŐFI"), [3, 2]);
+    assert_eq!(find(&db, "", "kecskemét", "petofi"), [2]);
+    // Shorter than a trigram
+    assert_eq!(find(&db, "", "", "fő"), [4]);
+    // Trigrams apart from each other do not match
+    assert!(find(&db, "", "", "petőfi utca").is_empty());
+    assert!(AddressFilter::default().is_empty());
+    // Updates move customers in the indexes
+    db.update(&3, 1, |c| {
+      c.address_location = "Kecskemét".to_string();
+      c.address_street = "Petőfi utca 2.".to_string();
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(find(&db, "", "budapest", ""), [1]);
+    assert_eq!(find(&db, "", "", "petőfi utca"), [3]);
+  }
+
+  #[test]
+  fn test_name_prefix() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for (id, name) in [
+      (1, "Csonka Ödön"),
+      (2, "Cukor Ede"),
+      (3, "Czene Attila"),
+      (4, "Oláh Ferenc"),
+      (5, "Öveges József"),
+    ] {
+      db.insert(Customer {
+        id,
+        name: name.to_string(),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    // C names before Cs names
+    assert_eq!(db.name_prefix("c"), [2, 3, 1]);
+    assert_eq!(db.name_prefix("CS"), [1]);
+    assert_eq!(db.name_prefix("o"), [4]);
+    assert_eq!(db.name_prefix("ö"), [5]);
+    assert!(db.name_prefix("x").is_empty());
+    // Sort key follows name changes
+    db.update(&5, 1, |c| {
+      c.name = "Oszlopos Simeon".to_string();
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(db.name_prefix("o"), [4, 5]);
+    assert_eq!(
+      db.find_id(&5).unwrap().sort_key,
+      search::sort_key("oszlopos simeon")
+    );
+  }
+
+  #[test]
+  fn test_rederive_names() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for (id, name) in [(1, "Kft. Zöld Kert"), (2, "Alma Bt")] {
+      db.insert(Customer {
+        id,
+        name: name.to_string(),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    assert_eq!(db.find_id(&1).unwrap().display_name, "Zöld Kert Kft.");
+    assert_eq!(db.name_prefix("z"), [1]);
+    assert!(db.rederive_names().unwrap().is_empty());
+    // Names derived by earlier rules
+    let mut stale = db.find_id(&2).unwrap().clone();
+    stale.display_name = "Alma Bt".to_string();
+    stale.sort_key = search::sort_key("Alma Bt");
+    db.customers.update(stale).unwrap();
+    let updated = db.rederive_names().unwrap();
+    assert_eq!(updated.len(), 1);
+    assert_eq!(updated[0].display_name, "Alma Bt.");
+    assert_eq!(db.find_id(&2).unwrap().display_name, "Alma Bt.");
+    assert!(db.rederive_names().unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_transaction() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    let card = |id: &str| Some(id.to_string());
+    for (id, loyalty_card_id) in [(1, card("A1")), (2, card("B1"))] {
+      db.insert(Customer {
+        id,
+        loyalty_card_id,
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    // Cards swapped in one transaction
+    // The intermediate state is not stored
+    let swap = |tx: &mut Transaction| {
+      for (id, loyalty_card_id) in [(1, None), (2, card("A1")), (1, card("B1"))] {
+        tx.update(&id, 1, |c| {
+          c.loyalty_card_id = loyalty_card_id;
+          Ok(())
+        })?;
+      }
+      Ok(())
+    };
+    assert!(db.atomic(swap).is_ok());
+    assert_eq!(db.find_loyalty_card("A1"), Some(2));
+    assert_eq!(db.find_loyalty_card("B1"), Some(1));
+    // Staged customers are checked against each other
+    let mut tx = db.transaction();
+    tx.insert(Customer {
+      id: 3,
+      name: "Kiss Béla".to_string(),
+      loyalty_card_id: card("C1"),
+      ..Customer::default()
+    })
+    .unwrap();
+    assert_eq!(tx.find_id(&3).unwrap().display_name, "Kiss Béla");
+    assert!(tx
+      .insert(Customer {
+        id: 4,
+        loyalty_card_id: card("C1"),
+        ..Customer::default()
+      })
+      .is_err());
+    assert!(tx
+      .insert(Customer {
+        id: 3,
+        ..Customer::default()
+      })
+      .is_err());
+    // Dropped transaction stores nothing
+    drop(tx);
+    assert!(db.find_id(&3).is_err());
+    assert_eq!(db.find_loyalty_card("C1"), None);
+    // Failed transaction stores nothing
+    let res: ServiceResult<()> = db.atomic(|tx| {
+      tx.insert(Customer {
+        id: 3,
+        ..Customer::default()
+      })?;
+      tx.update(&1, 1, |c| {
+        c.loyalty_card_id = card("A1");
+        Ok(())
+      })
+    });
+    assert!(res.is_err());
+    assert!(db.find_id(&3).is_err());
+    assert_eq!(db.find_loyalty_card("B1"), Some(1));
+    // Read only db commits nothing
+    db.set_read_only(true);
+    let mut tx = db.transaction();
+    tx.insert(Customer {
+      id: 3,
+      ..Customer::default()
+    })
+    .unwrap();
+    assert!(tx.commit().is_err());
+    assert!(db.find_id(&3).is_err());
+  }
+
+  #[test]
+  fn test_update_rollback() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    db.insert(Customer {
+      id: 1,
+      name: "Kiss Béla".to_string(),
+      ..Customer::default()
+    })
+    .unwrap();
+    let res: ServiceResult<()> = db.update(&1, 1, |c| {
+      c.name = "Nagy Béla".to_string();
+      Err(ServiceError::bad_request("hiba"))
+    });
+    assert!(res.is_err());
+    assert_eq!(db.find_id(&1).unwrap().name, "Kiss Béla");
+  }
+
+  #[test]
+  fn test_blocked() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    for id in 1..=3 {
+      db.insert(Customer {
+        id,
+        name: "Kiss Béla".to_string(),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    assert_eq!(db.blocked(&1), None);
+    db.update(&2, 5, |c| {
+      c.deactivate(ReasonCode::Debt, String::new(), 5).map(|_| ())
+    })
+    .unwrap();
+    let blocked = db.blocked(&2).unwrap();
+    assert_eq!(blocked.reason, Some(ReasonCode::Debt));
+    assert!(blocked.since.is_some());
+    // Stored inactive, without status history
+    db.put(Customer {
+      id: 4,
+      active: false,
+      ..Customer::default()
+    })
+    .unwrap();
+    assert_eq!(
+      db.blocked_customers().collect::<Vec<(u32, Blocked)>>(),
+      vec![(2, blocked), (4, Blocked::default())]
+    );
+    db.update(&2, 5, |c| c.reactivate(String::new(), 5).map(|_| ()))
+      .unwrap();
+    assert_eq!(db.blocked(&2), None);
+    db.rebuild_indexes();
+    assert_eq!(
+      db.blocked_customers()
+        .map(|(id, _)| id)
+        .collect::<Vec<u32>>(),
+      vec![4]
+    );
+  }
+
+  #[test]
+  fn test_mutation_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mutations.jsonl");
+    let sink = crate::audit_sink::FileSink::new(path.clone(), u64::MAX, 0);
+    let mut db = CustomerDb::new(MemoryStore::new());
+    db.set_mutation_log("shop_a", Arc::new(MutationLog::new(sink)));
+    db.insert(Customer {
+      id: 1,
+      created_by: 2,
+      ..Customer::default()
+    })
+    .unwrap();
+    db.update(&1, 3, |c| {
+      c.phone = "+36 30 123 4567".to_string();
+      Ok(())
+    })
+    .unwrap();
+    // Failed updates are not logged
+    assert!(db
+      .update(&1, 3, |_| Err::<(), _>(ServiceError::bad_request("hiba")))
+      .is_err());
+    let records = std::fs::read_to_string(&path)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect::<Vec<crate::audit_sink::MutationRecord>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].tenant, "shop_a");
+    assert_eq!(records[0].user_id, 2);
+    assert_eq!(records[1].changes.len(), 1);
+    assert_eq!(records[1].changes[0].path, "phone");
+    assert_eq!(records[1].user_id, 3);
+  }
+
+  #[tokio::test]
+  async fn test_outbox() {
+    let dir = tempfile::tempdir().unwrap();
+    let outbox = Arc::new(Outbox::load(dir.path()).unwrap());
+    let events = Arc::new(crate::events::Events::new());
+    let mut receiver = events.subscribe();
+    let path = dir.path().join("customers");
+    let mut db = CustomerDb::new(packman::VecPack::<Customer>::load_or_init(path.clone()).unwrap());
+    db.set_outbox("shop_a", outbox.clone());
+    db.insert(Customer {
+      id: 1,
+      ..Customer::default()
+    })
+    .unwrap();
+    db.update(&1, 3, |c| {
+      c.phone = "+36 30 123 4567".to_string();
+      Ok(())
+    })
+    .unwrap();
+    // Changes failing before or while storing publish nothing
+    assert!(db
+      .update(&1, 3, |_| Err::<(), _>(ServiceError::bad_request("hiba")))
+      .is_err());
+    std::fs::remove_dir_all(&path).unwrap();
+    assert!(db
+      .insert(Customer {
+        id: 2,
+        ..Customer::default()
+      })
+      .is_err());
+    let sinks: Vec<Arc<dyn crate::outbox::EventSink>> = vec![events];
+    assert!(outbox
+      .deliver_due(&sinks, Utc::now())
+      .await
+      .unwrap()
+      .is_none());
+    let mut delivered = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+      assert_eq!(event.tenant, "shop_a");
+      delivered.push((event.kind, event.customer.id, event.customer.phone));
+    }
+    assert_eq!(
+      delivered,
+      [
+        (CustomerEventKind::Created, 1, String::new()),
+        (CustomerEventKind::Updated, 1, "+36 30 123 4567".to_string())
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_shipping() {
+    let dir = tempfile::tempdir().unwrap();
+    let shipping = Arc::new(Outbox::load_from_path(dir.path().join("shipping_outbox")).unwrap());
+    let events = Arc::new(crate::events::Events::new());
+    let mut receiver = events.subscribe();
+    let path = dir.path().join("customers");
+    let mut db = CustomerDb::new(packman::VecPack::<Customer>::load_or_init(path.clone()).unwrap());
+    db.set_shipping("shop_a", shipping.clone());
+    db.insert(Customer {
+      id: 1,
+      ..Customer::default()
+    })
+    .unwrap();
+    // Only address changes are notified
+    db.update(&1, 3, |c| {
+      c.phone = "+36 30 123 4567".to_string();
+      Ok(())
+    })
+    .unwrap();
+    db.update(&1, 3, |c| {
+      c.address_street = "Fő utca 2.".to_string();
+      Ok(())
+    })
+    .unwrap();
+    // Address changes failing to store notify nothing
+    std::fs::remove_dir_all(&path).unwrap();
+    assert!(db
+      .update(&1, 3, |c| {
+        c.address_street = "Fő utca 3.".to_string();
+        Ok(())
+      })
+      .is_err());
+    let sinks: Vec<Arc<dyn crate::outbox::EventSink>> = vec![events];
+    assert!(shipping
+      .deliver_due(&sinks, Utc::now())
+      .await
+      .unwrap()
+      .is_none());
+    let mut delivered = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+      assert_eq!(event.tenant, "shop_a");
+      delivered.push((event.kind, event.customer.id, event.customer.address_street));
+    }
+    assert_eq!(
+      delivered,
+      [(CustomerEventKind::Updated, 1, "Fő utca 2.".to_string())]
+    );
+  }
+}
@@ -0,0 +1,118 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use std::time::{Duration, Instant};
+use tonic::metadata::MetadataMap;
+
+// Request metadata key of the client deadline
+// set by gRPC clients as timeout
+pub const TIMEOUT_METADATA_KEY: &str = "grpc-timeout";
+
+// Check deadline only at every Nth scanned item
+const CHECK_INTERVAL: usize = 256;
+
+// Client deadline of a request
+//
+// Long scans check it cooperatively, so they stop
+// and release the storage lock once the client gave up.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deadline {
+  at: Option<Instant>,
+}
+
+impl Deadline {
+  pub fn after(timeout: Duration) -> Self {
+    Self {
+      at: Some(Instant::now() + timeout),
+    }
+  }
+  // Get deadline from the grpc-timeout request metadata
+  // Missing or malformed timeout means no deadline
+  pub fn from_metadata(metadata: &MetadataMap) -> Self {
+    metadata
+      .get(TIMEOUT_METADATA_KEY)
+      .and_then(|value| value.to_str().ok())
+      .and_then(parse_timeout)
+      .map_or(Self::default(), Self::after)
+  }
+  // Check whether the deadline is exceeded
+  pub fn check(&self) -> ServiceResult<()> {
+    match self.at {
+      Some(at) if Instant::now() >= at => Err(ServiceError::deadline_exceeded(
+        "A kérés időkorlátja lejárt",
+      )),
+      _ => Ok(()),
+    }
+  }
+  // Check deadline in a scan loop at item i
+  pub fn check_at(&self, i: usize) -> ServiceResult<()> {
+    match i % CHECK_INTERVAL {
+      0 => self.check(),
+      _ => Ok(()),
+    }
+  }
+}
+
+// Parse gRPC timeout value
+// Max 8 digits and a unit: H, M, S, m (milli), u (micro), n (nano)
+fn parse_timeout(value: &str) -> Option<Duration> {
+  let value = value.trim();
+  if value.len() < 2 || value.len() > 9 {
+    return None;
+  }
+  let (amount, unit) = value.split_at(value.len() - 1);
+  let amount: u64 = amount.parse().ok()?;
+  match unit {
+    "H" => Some(Duration::from_secs(amount * 60 * 60)),
+    "M" => Some(Duration::from_secs(amount * 60)),
+    "S" => Some(Duration::from_secs(amount)),
+    "m" => Some(Duration::from_millis(amount)),
+    "u" => Some(Duration::from_micros(amount)),
+    "n" => Some(Duration::from_nanos(amount)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_timeout() {
+    assert_eq!(parse_timeout("5S"), Some(Duration::from_secs(5)));
+    assert_eq!(parse_timeout("100m"), Some(Duration::from_millis(100)));
+    assert_eq!(parse_timeout("2H"), Some(Duration::from_secs(7200)));
+    assert_eq!(parse_timeout("S"), None);
+    assert_eq!(parse_timeout("5x"), None);
+    assert_eq!(parse_timeout("123456789S"), None);
+  }
+
+  #[test]
+  fn test_check() {
+    assert!(Deadline::default().check().is_ok());
+    assert!(Deadline::after(Duration::from_secs(60)).check().is_ok());
+    let deadline = Deadline::after(Duration::from_secs(0));
+    assert!(deadline.check().is_err());
+    assert!(deadline.check_at(1).is_ok());
+    assert!(deadline.check_at(CHECK_INTERVAL).is_err());
+    let mut metadata = MetadataMap::new();
+    metadata.insert(TIMEOUT_METADATA_KEY, "1n".parse().unwrap());
+    std::thread::sleep(Duration::from_millis(1));
+    assert!(Deadline::from_metadata(&metadata).check().is_err());
+  }
+}
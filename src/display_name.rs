@@ -0,0 +1,85 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Display names
+//
+// Names are stored as entered, listings use a normalized display
+// name derived from them. Whitespace is collapsed, company forms
+// are spelled the same way, and always placed at the end of the
+// name, so the companies are sorted by their names.
+
+// Company forms, in their canonical spelling
+const COMPANY_FORMS: &[&str] = &["Kft.", "Bt.", "Zrt.", "Nyrt.", "Kkt.", "Kht.", "Rt."];
+
+// Canonical company form of a word, e.g. KFT, kft. or Kft, => Kft.
+fn company_form(word: &str) -> Option<&'static str> {
+  let key = word.trim_end_matches(&['.', ','][..]).to_lowercase();
+  COMPANY_FORMS
+    .iter()
+    .find(|form| form.trim_end_matches('.').to_lowercase() == key)
+    .copied()
+}
+
+// Derive display name from the stored name
+pub fn derive(name: &str) -> String {
+  let mut words = name
+    .split_whitespace()
+    .map(|w| w.to_string())
+    .collect::<Vec<String>>();
+  if words.len() < 2 {
+    return words.join(" ");
+  }
+  // Kft. Kert Világ => Kert Világ Kft.
+  if let Some(form) = company_form(&words[0]) {
+    if company_form(&words[words.len() - 1]).is_none() {
+      words.remove(0);
+      words.push(form.to_string());
+    }
+  }
+  // Kert Világ, kft => Kert Világ Kft.
+  let last = words.len() - 1;
+  if let Some(form) = company_form(&words[last]) {
+    words[last] = form.to_string();
+    let before = words[last - 1].trim_end_matches(',').len();
+    words[last - 1].truncate(before);
+    if words[last - 1].is_empty() {
+      words.remove(last - 1);
+    }
+  }
+  words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_derive() {
+    assert_eq!(derive("  Kiss   Béla "), "Kiss Béla");
+    assert_eq!(derive("Kert Világ kft"), "Kert Világ Kft.");
+    assert_eq!(derive("Kert Világ, KFT."), "Kert Világ Kft.");
+    assert_eq!(derive("Kert Világ , Bt"), "Kert Világ Bt.");
+    assert_eq!(derive("Zrt. Magyar Kert"), "Magyar Kert Zrt.");
+    assert_eq!(derive("Kert Nonprofit Kft."), "Kert Nonprofit Kft.");
+    // Only names with an other word are changed
+    assert_eq!(derive("kft"), "kft");
+    assert_eq!(derive(""), "");
+    // Words in the middle are kept
+    assert_eq!(derive("Bt Kert Kft"), "Bt Kert Kft.");
+    assert_eq!(derive("Kiss Btamás"), "Kiss Btamás");
+  }
+}
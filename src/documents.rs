@@ -0,0 +1,112 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Stored documents
+//
+// Documents attached to customers are stored by the document store,
+// customers only keep their storage keys. Anonymized customers lose
+// their documents, so they are deleted from the document store before
+// the keys are removed. Without a document store endpoint documents
+// cannot be deleted, and customers having documents are not anonymized.
+
+use crate::customer::Customer;
+use crate::prelude::*;
+use crate::proto::document::document_store_client::DocumentStoreClient;
+use crate::proto::document::DeleteDocumentsRequest;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+
+// Call fails if the document store does not respond in time
+// The tenant is locked while waiting
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct DocumentStore {
+  // None if no document store is set
+  client: Option<DocumentStoreClient<Channel>>,
+}
+
+impl DocumentStore {
+  // Must be called from the tokio runtime when endpoint is set
+  pub fn new(endpoint: Option<&str>) -> ServiceResult<Self> {
+    let client = match endpoint {
+      Some(endpoint) => Some(DocumentStoreClient::new(
+        Endpoint::from_shared(endpoint.to_string())
+          .map_err(|e| ServiceError::internal_error(&format!("Invalid document endpoint: {}", e)))?
+          .connect_lazy()
+          .map_err(|e| {
+            ServiceError::internal_error(&format!("Invalid document endpoint: {}", e))
+          })?,
+      )),
+      None => None,
+    };
+    Ok(Self { client })
+  }
+  // Delete the stored documents of a customer
+  pub async fn delete(&self, tenant: &str, customer: &Customer) -> ServiceResult<()> {
+    if customer.attachments.is_empty() {
+      return Ok(());
+    }
+    let mut client = match &self.client {
+      // Clients share the channel
+      Some(client) => client.clone(),
+      None => {
+        return Err(ServiceError::internal_error(&format!(
+          "No document store is set, documents of customer {} cannot be deleted",
+          customer.id
+        )))
+      }
+    };
+    let request = DeleteDocumentsRequest {
+      tenant: tenant.to_string(),
+      customer_id: customer.id,
+      storage_keys: customer
+        .attachments
+        .iter()
+        .map(|a| a.storage_key.clone())
+        .collect(),
+    };
+    tokio::time::timeout(CALL_TIMEOUT, client.delete_documents(request))
+      .await
+      .map_err(|_| ServiceError::internal_error("Document store timed out"))?
+      .map_err(|e| ServiceError::internal_error(&format!("Document store: {}", e)))?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::DocumentKind;
+
+  #[tokio::test]
+  async fn test_delete_documents() {
+    let mut customer = Customer {
+      id: 1,
+      ..Customer::default()
+    };
+    // Nothing to delete
+    let store = DocumentStore::new(None).unwrap();
+    assert!(store.delete("", &customer).await.is_ok());
+    customer
+      .attach_document(DocumentKind::IdScan, "ids/1.jpg".to_string(), 1)
+      .unwrap();
+    assert!(store.delete("", &customer).await.is_err());
+    // Nothing listens on the port
+    let store = DocumentStore::new(Some("http://127.0.0.1:9")).unwrap();
+    assert!(store.delete("", &customer).await.is_err());
+  }
+}
@@ -0,0 +1,164 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use chrono::prelude::*;
+use std::collections::HashMap;
+use tonic::metadata::MetadataMap;
+
+// Request metadata key of the editor user ID
+pub const EDITOR_METADATA_KEY: &str = "editor-uid";
+
+// Default and max lock TTL in seconds
+pub const DEFAULT_TTL_SECS: u32 = 300;
+pub const MAX_TTL_SECS: u32 = 3600;
+
+// Edit lock of a customer
+#[derive(Clone, Debug, PartialEq)]
+pub struct EditLock {
+  pub customer_id: u32,
+  pub editor_uid: u32,
+  pub expires_at: DateTime<Utc>,
+}
+
+// Customer edit locks
+//
+// Locks are optional and kept in memory only. While a customer
+// is locked, only its editor can update it. Locks expire after
+// their TTL, so an abandoned edit does not block the others.
+#[derive(Default)]
+pub struct EditLocks {
+  locks: HashMap<(String, u32), EditLock>,
+}
+
+impl EditLocks {
+  // Lock customer for an editor, or renew the editor's lock
+  pub fn lock(
+    &mut self,
+    tenant: &str,
+    customer_id: u32,
+    editor_uid: u32,
+    ttl_secs: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<EditLock> {
+    // 0 is used for requests without editor
+    if editor_uid == 0 {
+      return Err(ServiceError::invalid_field(
+        "editor_uid",
+        "A szerkesztő azonosító megadása kötelező",
+      ));
+    }
+    self.check(tenant, customer_id, editor_uid, now)?;
+    let ttl_secs = match ttl_secs {
+      0 => DEFAULT_TTL_SECS,
+      x => x.min(MAX_TTL_SECS),
+    };
+    let lock = EditLock {
+      customer_id,
+      editor_uid,
+      expires_at: now + chrono::Duration::seconds(ttl_secs as i64),
+    };
+    self
+      .locks
+      .insert((tenant.to_string(), customer_id), lock.clone());
+    Ok(lock)
+  }
+  // Release the editor's lock
+  // Releasing a not locked customer is not an error
+  pub fn release(
+    &mut self,
+    tenant: &str,
+    customer_id: u32,
+    editor_uid: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<()> {
+    self.check(tenant, customer_id, editor_uid, now)?;
+    self.locks.remove(&(tenant.to_string(), customer_id));
+    Ok(())
+  }
+  // Check the editor can update the customer
+  pub fn check(
+    &mut self,
+    tenant: &str,
+    customer_id: u32,
+    editor_uid: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<()> {
+    self.locks.retain(|_, lock| lock.expires_at > now);
+    match self.locks.get(&(tenant.to_string(), customer_id)) {
+      Some(lock) if lock.editor_uid != editor_uid => {
+        Err(ServiceError::failed_precondition(&format!(
+          "Az ügyfelet jelenleg a(z) {} azonosítójú felhasználó szerkeszti",
+          lock.editor_uid
+        )))
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
+// Get editor user ID from request metadata
+// Returns 0 if there is no editor provided
+pub fn editor_from_metadata(metadata: &MetadataMap) -> ServiceResult<u32> {
+  match metadata.get(EDITOR_METADATA_KEY) {
+    Some(value) => value
+      .to_str()
+      .ok()
+      .and_then(|v| v.trim().parse().ok())
+      .ok_or_else(|| ServiceError::bad_request("Hibás szerkesztő azonosító")),
+    None => Ok(0),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_edit_locks() {
+    let now = Utc::now();
+    let mut locks = EditLocks::default();
+    assert!(locks.check("", 1, 10, now).is_ok());
+    assert!(locks.lock("", 1, 0, 0, now).is_err());
+    let lock = locks.lock("", 1, 10, 0, now).unwrap();
+    assert_eq!(lock.expires_at, now + chrono::Duration::seconds(300));
+    // Other editors are rejected
+    assert!(locks.check("", 1, 11, now).is_err());
+    assert!(locks.lock("", 1, 11, 60, now).is_err());
+    assert!(locks.release("", 1, 11, now).is_err());
+    // Locks are per tenant and customer
+    assert!(locks.check("shop_a", 1, 11, now).is_ok());
+    assert!(locks.check("", 2, 11, now).is_ok());
+    // Holder can renew and release
+    assert!(locks.lock("", 1, 10, 60, now).is_ok());
+    locks.release("", 1, 10, now).unwrap();
+    assert!(locks.check("", 1, 11, now).is_ok());
+  }
+
+  #[test]
+  fn test_edit_lock_expiry() {
+    let now = Utc::now();
+    let mut locks = EditLocks::default();
+    locks.lock("", 1, 10, 60, now).unwrap();
+    let later = now + chrono::Duration::seconds(61);
+    assert!(locks.check("", 1, 11, later).is_ok());
+    assert!(locks.locks.is_empty());
+    // TTL is capped
+    let lock = locks.lock("", 1, 10, 100_000, now).unwrap();
+    assert_eq!(lock.expires_at, now + chrono::Duration::seconds(3600));
+  }
+}
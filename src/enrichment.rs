@@ -0,0 +1,222 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Customer statistics enrichment
+//
+// GetCustomerWithStats returns a customer with its purchase and
+// invoice statistics, so the POS gets them in one call instead of
+// asking three services. The purchase and invoice services are asked
+// at the same time, the tenant is not locked while waiting. A service
+// without endpoint, or not responding in time, is listed as
+// unavailable, and the activity dates recorded for the customer are
+// returned instead of its statistics.
+
+use crate::customer::Customer;
+use crate::prelude::*;
+use crate::proto::invoice::invoice_client::InvoiceClient;
+use crate::proto::invoice::GetCustomerInvoiceStatsRequest;
+use crate::proto::purchase::purchase_client::PurchaseClient;
+use crate::proto::purchase::GetCustomerPurchaseStatsRequest;
+use chrono::prelude::*;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+
+// Calls not answered in time count as unavailable
+const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub const PURCHASE_SERVICE: &str = "purchase";
+pub const INVOICE_SERVICE: &str = "invoice";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CustomerStats {
+  pub purchase_count: u32,
+  // Gross total in HUF
+  pub lifetime_spend: u64,
+  pub last_purchase_at: Option<DateTime<Utc>>,
+  pub invoice_count: u32,
+  pub last_invoice_at: Option<DateTime<Utc>>,
+  // Services the statistics are missing from
+  pub unavailable: Vec<&'static str>,
+}
+
+pub struct Enrichment {
+  purchase: Option<PurchaseClient<Channel>>,
+  invoice: Option<InvoiceClient<Channel>>,
+}
+
+// Lazy channel, connected on the first call
+pub fn channel(service: &str, endpoint: &str) -> ServiceResult<Channel> {
+  let error = |e: &dyn std::fmt::Display| {
+    ServiceError::internal_error(&format!("Invalid {} endpoint: {}", service, e))
+  };
+  Endpoint::from_shared(endpoint.to_string())
+    .map_err(|e| error(&e))?
+    .connect_lazy()
+    .map_err(|e| error(&e))
+}
+
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+  DateTime::parse_from_rfc3339(date)
+    .ok()
+    .map(|d| d.with_timezone(&Utc))
+}
+
+impl Enrichment {
+  // Must be called from the tokio runtime when an endpoint is set
+  pub fn new(
+    purchase_endpoint: Option<&str>,
+    invoice_endpoint: Option<&str>,
+  ) -> ServiceResult<Self> {
+    Ok(Self {
+      purchase: match purchase_endpoint {
+        Some(endpoint) => Some(PurchaseClient::new(channel(PURCHASE_SERVICE, endpoint)?)),
+        None => None,
+      },
+      invoice: match invoice_endpoint {
+        Some(endpoint) => Some(InvoiceClient::new(channel(INVOICE_SERVICE, endpoint)?)),
+        None => None,
+      },
+    })
+  }
+  async fn purchase_stats(
+    &self,
+    tenant: &str,
+    customer_id: u32,
+  ) -> Result<(u32, u64, Option<DateTime<Utc>>), String> {
+    let mut client = match &self.purchase {
+      // Clients share the channel
+      Some(client) => client.clone(),
+      None => return Err("No purchase service endpoint".to_string()),
+    };
+    let request = GetCustomerPurchaseStatsRequest {
+      tenant: tenant.to_string(),
+      customer_id,
+    };
+    let res = tokio::time::timeout(CALL_TIMEOUT, client.get_customer_purchase_stats(request))
+      .await
+      .map_err(|_| "Purchase service timed out".to_string())?
+      .map_err(|e| format!("Purchase service: {}", e))?
+      .into_inner();
+    Ok((
+      res.purchase_count,
+      res.lifetime_spend,
+      parse_date(&res.last_purchase_at),
+    ))
+  }
+  async fn invoice_stats(
+    &self,
+    tenant: &str,
+    customer_id: u32,
+  ) -> Result<(u32, Option<DateTime<Utc>>), String> {
+    let mut client = match &self.invoice {
+      Some(client) => client.clone(),
+      None => return Err("No invoice service endpoint".to_string()),
+    };
+    let request = GetCustomerInvoiceStatsRequest {
+      tenant: tenant.to_string(),
+      customer_id,
+    };
+    let res = tokio::time::timeout(CALL_TIMEOUT, client.get_customer_invoice_stats(request))
+      .await
+      .map_err(|_| "Invoice service timed out".to_string())?
+      .map_err(|e| format!("Invoice service: {}", e))?
+      .into_inner();
+    Ok((res.invoice_count, parse_date(&res.last_invoice_at)))
+  }
+  // Statistics of a customer
+  // Recorded activity dates are kept if they are later
+  pub async fn stats(&self, tenant: &str, customer: &Customer) -> CustomerStats {
+    let mut res = CustomerStats {
+      last_purchase_at: customer.last_purchase_at,
+      last_invoice_at: customer.last_invoice_at,
+      ..CustomerStats::default()
+    };
+    let (purchase, invoice) = tokio::join!(
+      self.purchase_stats(tenant, customer.id),
+      self.invoice_stats(tenant, customer.id)
+    );
+    match purchase {
+      Ok((purchase_count, lifetime_spend, last_purchase_at)) => {
+        res.purchase_count = purchase_count;
+        res.lifetime_spend = lifetime_spend;
+        res.last_purchase_at = res.last_purchase_at.max(last_purchase_at);
+      }
+      Err(error) => {
+        if self.purchase.is_some() {
+          eprintln!(
+            "Error while getting purchase stats of customer {}: {}",
+            customer.id, error
+          );
+        }
+        res.unavailable.push(PURCHASE_SERVICE);
+      }
+    }
+    match invoice {
+      Ok((invoice_count, last_invoice_at)) => {
+        res.invoice_count = invoice_count;
+        res.last_invoice_at = res.last_invoice_at.max(last_invoice_at);
+      }
+      Err(error) => {
+        if self.invoice.is_some() {
+          eprintln!(
+            "Error while getting invoice stats of customer {}: {}",
+            customer.id, error
+          );
+        }
+        res.unavailable.push(INVOICE_SERVICE);
+      }
+    }
+    res
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_stats_without_services() {
+    let now = Utc::now();
+    let customer = Customer {
+      id: 1,
+      last_purchase_at: Some(now),
+      ..Customer::default()
+    };
+    let stats = Enrichment::new(None, None)
+      .unwrap()
+      .stats("", &customer)
+      .await;
+    assert_eq!(
+      stats,
+      CustomerStats {
+        last_purchase_at: Some(now),
+        unavailable: vec![PURCHASE_SERVICE, INVOICE_SERVICE],
+        ..CustomerStats::default()
+      }
+    );
+  }
+
+  #[tokio::test]
+  async fn test_services_down() {
+    // Nothing listens on the port
+    let enrichment =
+      Enrichment::new(Some("http://127.0.0.1:9"), Some("http://127.0.0.1:9")).unwrap();
+    let stats = enrichment.stats("", &Customer::default()).await;
+    assert_eq!(stats.unavailable, [PURCHASE_SERVICE, INVOICE_SERVICE]);
+    assert!(Enrichment::new(Some("nem url"), None).is_err());
+  }
+}
@@ -0,0 +1,277 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Erasure requests
+//
+// Customers can ask for their personal data to be erased. Requests
+// are queued until an admin approves or rejects them, approved
+// requests are executed by the retention run, once the legally
+// required retention window of the customer is over. A customer has
+// one open request at a time. Closed requests are kept as a record,
+// next to the customer storage of the tenant.
+
+use crate::prelude::*;
+use crate::sanitize;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErasureStatus {
+  // Waiting for admin review
+  Pending,
+  // Waiting for the retention window to pass
+  Approved,
+  Rejected,
+  // Customer is anonymized
+  Done,
+}
+
+impl ErasureStatus {
+  pub fn is_open(&self) -> bool {
+    matches!(self, ErasureStatus::Pending | ErasureStatus::Approved)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ErasureRequest {
+  pub id: u32,
+  pub customer_id: u32,
+  pub requested_by: u32,
+  pub reason: String,
+  pub date_requested: DateTime<Utc>,
+  // Earliest execution date, the end of the retention window
+  pub execute_after: DateTime<Utc>,
+  pub status: ErasureStatus,
+  pub decided_by: Option<u32>,
+  pub date_decided: Option<DateTime<Utc>>,
+  pub date_done: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ErasureRequestData {
+  requests: Vec<ErasureRequest>,
+  next_id: u32,
+}
+
+// Erasure requests of every tenant
+pub struct ErasureRequests {
+  data_dir: PathBuf,
+  packs: HashMap<String, Pack<ErasureRequestData>>,
+}
+
+impl ErasureRequests {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+      packs: HashMap::new(),
+    }
+  }
+  // Erasure requests pack of a tenant, loaded on first use
+  fn pack(&mut self, tenant: &str) -> ServiceResult<&mut Pack<ErasureRequestData>> {
+    if !self.packs.contains_key(tenant) {
+      let path = tenant_path(&self.data_dir, tenant).with_file_name("erasure_requests");
+      let pack = Pack::load_or_init(path, "erasure_requests")?;
+      self.packs.insert(tenant.to_string(), pack);
+    }
+    Ok(self.packs.get_mut(tenant).expect("Loaded above"))
+  }
+  // Queue request for admin review
+  pub fn request(
+    &mut self,
+    tenant: &str,
+    customer_id: u32,
+    requested_by: u32,
+    reason: &str,
+    execute_after: DateTime<Utc>,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<ErasureRequest> {
+    let reason = sanitize::line(reason).map_err(|e| e.on_field("reason"))?;
+    if reason.is_empty() {
+      return Err(ServiceError::invalid_field(
+        "reason",
+        "A törlési kérelem indoka kötelező",
+      ));
+    }
+    let pack = self.pack(tenant)?;
+    if pack
+      .unpack()
+      .requests
+      .iter()
+      .any(|r| r.customer_id == customer_id && r.status.is_open())
+    {
+      return Err(ServiceError::already_exist(
+        "Az ügyfélnek már van folyamatban lévő törlési kérelme",
+      ));
+    }
+    let request = pack.update(|data| {
+      data.next_id += 1;
+      let request = ErasureRequest {
+        id: data.next_id,
+        customer_id,
+        requested_by,
+        reason: reason.clone(),
+        date_requested: now,
+        execute_after,
+        status: ErasureStatus::Pending,
+        decided_by: None,
+        date_decided: None,
+        date_done: None,
+      };
+      data.requests.push(request.clone());
+      request
+    })?;
+    Ok(request)
+  }
+  // Requests, oldest first
+  // Only the open ones, unless include_closed is set
+  pub fn list(&mut self, tenant: &str, include_closed: bool) -> ServiceResult<Vec<ErasureRequest>> {
+    Ok(
+      self
+        .pack(tenant)?
+        .unpack()
+        .requests
+        .iter()
+        .filter(|r| include_closed || r.status.is_open())
+        .cloned()
+        .collect(),
+    )
+  }
+  // Approved requests whose retention window is over
+  pub fn due(&mut self, tenant: &str, now: DateTime<Utc>) -> ServiceResult<Vec<ErasureRequest>> {
+    Ok(
+      self
+        .list(tenant, false)?
+        .into_iter()
+        .filter(|r| r.status == ErasureStatus::Approved && r.execute_after <= now)
+        .collect(),
+    )
+  }
+  // Change request with the given ID
+  fn change<F>(&mut self, tenant: &str, id: u32, f: F) -> ServiceResult<ErasureRequest>
+  where
+    F: FnOnce(&mut ErasureRequest) -> ServiceResult<()>,
+  {
+    self.pack(tenant)?.update(|data| {
+      let request = data
+        .requests
+        .iter_mut()
+        .find(|r| r.id == id)
+        .ok_or_else(|| ServiceError::not_found("Nem található ilyen törlési kérelem"))?;
+      // Change a copy, so failed changes are not saved
+      let mut changed = request.clone();
+      f(&mut changed)?;
+      *request = changed.clone();
+      Ok(changed)
+    })?
+  }
+  // Approve or reject pending request
+  pub fn decide(
+    &mut self,
+    tenant: &str,
+    id: u32,
+    approve: bool,
+    decided_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<ErasureRequest> {
+    self.change(tenant, id, |r| {
+      if r.status != ErasureStatus::Pending {
+        return Err(ServiceError::failed_precondition(
+          "A törlési kérelemről már döntöttek",
+        ));
+      }
+      r.status = match approve {
+        true => ErasureStatus::Approved,
+        false => ErasureStatus::Rejected,
+      };
+      r.decided_by = Some(decided_by);
+      r.date_decided = Some(now);
+      Ok(())
+    })
+  }
+  // Move execution date, e.g. after a new invoice of the customer
+  pub fn reschedule(
+    &mut self,
+    tenant: &str,
+    id: u32,
+    execute_after: DateTime<Utc>,
+  ) -> ServiceResult<ErasureRequest> {
+    self.change(tenant, id, |r| {
+      r.execute_after = execute_after;
+      Ok(())
+    })
+  }
+  // Close request once the customer is anonymized
+  pub fn done(
+    &mut self,
+    tenant: &str,
+    id: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<ErasureRequest> {
+    self.change(tenant, id, |r| {
+      r.status = ErasureStatus::Done;
+      r.date_done = Some(now);
+      Ok(())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_erasure_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let later = now + chrono::Duration::days(10);
+    let mut requests = ErasureRequests::new(dir.path());
+    assert!(requests.request("", 1, 5, " ", now, now).is_err());
+    let first = requests.request("", 1, 5, "Kérte", now, now).unwrap();
+    assert_eq!(first.status, ErasureStatus::Pending);
+    // One open request per customer
+    assert!(requests.request("", 1, 5, "Újra kérte", now, now).is_err());
+    let second = requests.request("", 2, 5, "Kérte", later, now).unwrap();
+    // Tenants are separate
+    assert!(requests.list("shop_a", true).unwrap().is_empty());
+    // Only approved requests are due
+    assert!(requests.due("", later).unwrap().is_empty());
+    requests.decide("", first.id, false, 9, now).unwrap();
+    assert!(requests.decide("", first.id, true, 9, now).is_err());
+    let approved = requests.decide("", second.id, true, 9, now).unwrap();
+    assert_eq!(approved.decided_by, Some(9));
+    assert!(requests.due("", now).unwrap().is_empty());
+    assert_eq!(
+      requests.due("", later).unwrap(),
+      std::slice::from_ref(&approved)
+    );
+    // Requests survive restart
+    let mut requests = ErasureRequests::new(dir.path());
+    assert_eq!(requests.list("", false).unwrap(), [approved]);
+    assert_eq!(requests.list("", true).unwrap().len(), 2);
+    requests.done("", second.id, later).unwrap();
+    assert!(requests.list("", false).unwrap().is_empty());
+    assert!(requests.due("", later).unwrap().is_empty());
+    // A closed request does not block a new one
+    requests.request("", 1, 5, "Ismét kérte", now, now).unwrap();
+    assert!(requests.done("", 99, now).is_err());
+  }
+}
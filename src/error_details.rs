@@ -0,0 +1,136 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// gRPC rich error details
+//
+// Minimal google.rpc.Status and google.rpc.BadRequest messages,
+// sent in the grpc-status-details-bin trailer, so clients can
+// map validation errors to the exact form field.
+
+use crate::prelude::FieldViolation;
+use prost::Message;
+use tonic::{Code, Status};
+
+const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
+
+// google.protobuf.Any
+#[derive(Clone, PartialEq, Message)]
+pub struct Any {
+  #[prost(string, tag = "1")]
+  pub type_url: String,
+  #[prost(bytes = "vec", tag = "2")]
+  pub value: Vec<u8>,
+}
+
+// google.rpc.Status
+#[derive(Clone, PartialEq, Message)]
+pub struct RpcStatus {
+  #[prost(int32, tag = "1")]
+  pub code: i32,
+  #[prost(string, tag = "2")]
+  pub message: String,
+  #[prost(message, repeated, tag = "3")]
+  pub details: Vec<Any>,
+}
+
+// google.rpc.BadRequest
+#[derive(Clone, PartialEq, Message)]
+pub struct BadRequest {
+  #[prost(message, repeated, tag = "1")]
+  pub field_violations: Vec<bad_request::FieldViolation>,
+}
+
+pub mod bad_request {
+  // google.rpc.BadRequest.FieldViolation
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct FieldViolation {
+    #[prost(string, tag = "1")]
+    pub field: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+  }
+}
+
+fn encode<M: Message>(message: &M) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(message.encoded_len());
+  // Vec grows as needed, so encoding cannot fail
+  message.encode(&mut buf).unwrap();
+  buf
+}
+
+// INVALID_ARGUMENT status with BadRequest details
+pub fn bad_request_status(msg: &str, violations: &[FieldViolation]) -> Status {
+  let bad_request = BadRequest {
+    field_violations: violations
+      .iter()
+      .map(|v| bad_request::FieldViolation {
+        field: v.field.clone(),
+        description: v.description.clone(),
+      })
+      .collect(),
+  };
+  let status = RpcStatus {
+    code: Code::InvalidArgument as i32,
+    message: msg.to_string(),
+    details: vec![Any {
+      type_url: BAD_REQUEST_TYPE_URL.to_string(),
+      value: encode(&bad_request),
+    }],
+  };
+  Status::with_details(Code::InvalidArgument, msg, encode(&status).into())
+}
+
+// Field violations of a status with BadRequest details
+pub fn field_violations(status: &Status) -> Vec<FieldViolation> {
+  let details = match RpcStatus::decode(status.details()) {
+    Ok(details) => details,
+    Err(_) => return Vec::new(),
+  };
+  details
+    .details
+    .iter()
+    .filter(|any| any.type_url == BAD_REQUEST_TYPE_URL)
+    .filter_map(|any| BadRequest::decode(any.value.as_slice()).ok())
+    .flat_map(|bad_request| bad_request.field_violations)
+    .map(|v| FieldViolation {
+      field: v.field,
+      description: v.description,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bad_request_status() {
+    let violations = [FieldViolation {
+      field: "email".to_string(),
+      description: "Nem megfelelő email cím".to_string(),
+    }];
+    let status = bad_request_status("Nem megfelelő email cím", &violations);
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let details = RpcStatus::decode(status.details()).unwrap();
+    assert_eq!(details.code, Code::InvalidArgument as i32);
+    assert_eq!(details.details[0].type_url, BAD_REQUEST_TYPE_URL);
+    let bad_request = BadRequest::decode(details.details[0].value.as_slice()).unwrap();
+    assert_eq!(bad_request.field_violations[0].field, "email");
+    assert_eq!(field_violations(&status), violations);
+    assert!(field_violations(&Status::not_found("")).is_empty());
+  }
+}
@@ -0,0 +1,108 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::customer::Customer;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+// Event buffer size per subscriber
+// Slow subscribers lose the oldest events
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CustomerEventKind {
+  Created,
+  Updated,
+  // Customer started or stopped matching the segment with the ID,
+  // found by the scheduled segment evaluation
+  SegmentEntered(u32),
+  SegmentLeft(u32),
+}
+
+impl CustomerEventKind {
+  // Check a subscribed kind covers the event kind
+  // Segment kinds cover the events of every segment
+  pub fn covers(&self, kind: &CustomerEventKind) -> bool {
+    std::mem::discriminant(self) == std::mem::discriminant(kind)
+  }
+}
+
+// Customer change event
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CustomerEvent {
+  pub tenant: String,
+  pub kind: CustomerEventKind,
+  pub customer: Customer,
+}
+
+// Customer event bus
+// Publishes customer changes to all the subscribers
+pub struct Events {
+  sender: broadcast::Sender<CustomerEvent>,
+}
+
+impl Events {
+  pub fn new() -> Self {
+    let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+    Self { sender }
+  }
+  // Publish event
+  // It is fine to have no subscribers
+  pub fn publish(&self, tenant: &str, kind: CustomerEventKind, customer: &Customer) {
+    let _ = self.sender.send(CustomerEvent {
+      tenant: tenant.to_string(),
+      kind,
+      customer: customer.clone(),
+    });
+  }
+  pub fn subscribe(&self) -> broadcast::Receiver<CustomerEvent> {
+    self.sender.subscribe()
+  }
+  #[cfg(test)]
+  pub fn subscriber_count(&self) -> usize {
+    self.sender.receiver_count()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_publish_subscribe() {
+    let events = Events::new();
+    // Publish without subscribers
+    events.publish("", CustomerEventKind::Created, &Customer::default());
+    let mut receiver = events.subscribe();
+    let customer = Customer {
+      id: 7,
+      ..Customer::default()
+    };
+    events.publish("shop_a", CustomerEventKind::Updated, &customer);
+    let event = receiver.recv().await.unwrap();
+    assert_eq!(event.tenant, "shop_a");
+    assert_eq!(event.kind, CustomerEventKind::Updated);
+    assert_eq!(event.customer.id, 7);
+  }
+
+  #[test]
+  fn test_covers() {
+    assert!(CustomerEventKind::SegmentEntered(0).covers(&CustomerEventKind::SegmentEntered(3)));
+    assert!(!CustomerEventKind::SegmentEntered(3).covers(&CustomerEventKind::SegmentLeft(3)));
+    assert!(!CustomerEventKind::Created.covers(&CustomerEventKind::Updated));
+  }
+}
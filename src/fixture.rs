@@ -0,0 +1,84 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Service fixture of the tests and the benchmarks
+//
+// The service runs on a temporary data directory, with the
+// admin token "admin" and without external services.
+
+use super::*;
+
+pub struct Fixture {
+  pub service: CustomerService,
+  // Removed with the fixture
+  pub dir: tempfile::TempDir,
+}
+
+impl Fixture {
+  // Service with empty storage of the named backend,
+  // and with the validation policy, if given
+  pub fn new(backend: &str, policy: Option<&str>) -> Self {
+    Self::build(backend, policy, true)
+  }
+  // Service without outbox, nothing delivers its events,
+  // so the pending events would fill it up
+  pub fn without_outbox(backend: &str) -> Self {
+    Self::build(backend, None, false)
+  }
+  fn build(backend: &str, policy: Option<&str>, outbox: bool) -> Self {
+    let dir = tempfile::tempdir().unwrap();
+    let policy_path = dir.path().join("validation_policy.yaml");
+    if let Some(policy) = policy {
+      std::fs::write(&policy_path, policy).unwrap();
+    }
+    let backend = Backend::from_name(backend).unwrap();
+    let mut tenants = Tenants::load(dir.path().to_path_buf(), backend).unwrap();
+    if outbox {
+      tenants.set_outbox(Arc::new(outbox::Outbox::load(dir.path()).unwrap()));
+    }
+    let service = CustomerService::init(
+      Arc::new(tenants),
+      zip::ZipDb::default(),
+      Some("admin".to_string()),
+      Arc::new(events::Events::new()),
+      Arc::new(webhook::Webhooks::load(dir.path(), true).unwrap()),
+      None,
+      enrichment::Enrichment::new(None, None).unwrap(),
+      user_names::UserNames::new(None, user_names::DEFAULT_TTL).unwrap(),
+      Arc::new(Mutex::new(erasure::ErasureRequests::new(dir.path()))),
+      Arc::new(Mutex::new(custom_field::CustomFields::new(dir.path()))),
+      Arc::new(Mutex::new(segment::Segments::new(dir.path()))),
+      Duration::from_secs(60),
+      Arc::new(policy::PolicyHolder::load(&policy_path).unwrap()),
+      Arc::new(retention::RetentionPolicy::default()),
+      Arc::new(quota::QuotaPolicy::default()),
+      Arc::new(metrics::Metrics::new(None)),
+      Arc::new(replication::Journal::new()),
+      100,
+    );
+    Self { service, dir }
+  }
+  // Fill the default tenant with count seeded customers,
+  // so benchmark runs are comparable
+  pub async fn seed(&self, count: usize) {
+    let customers = self.service.tenants.get(DEFAULT_TENANT).await.unwrap();
+    let mut customers = customers.lock().await;
+    for customer in seed::generate(count, 1, 1) {
+      customers.insert(customer).unwrap();
+    }
+  }
+}
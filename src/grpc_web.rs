@@ -0,0 +1,465 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// gRPC-web
+//
+// Browsers cannot send HTTP/2 trailers, so the front-end speaks
+// gRPC-web over HTTP/1.1, served on GRPC_WEB_LISTEN_ADDR. tonic 0.4
+// has no gRPC-web support, and its server is HTTP/2 only, so the
+// service is wrapped: gRPC-web requests (binary or base64 text) are
+// turned into gRPC requests, and the response trailers are sent as
+// the last message of the body, flagged with 0x80. Streams are
+// translated as they are sent. CORS preflight requests of the
+// allowed origins are answered without calling the service.
+// Request bodies are limited like in PayloadGuard, before they
+// are decoded.
+
+use crate::payload;
+use crate::prelude::*;
+use hyper::body::{Bytes, HttpBody};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::transport::{Body, NamedService};
+use tonic::Status;
+
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+// Trailer frame flag
+const TRAILER_FLAG: u8 = 0x80;
+
+// Response headers the browser can read
+const EXPOSED_HEADERS: &str =
+  "grpc-status, grpc-message, grpc-status-details-bin, conflicting-customer-id";
+
+// How long browsers can cache preflight responses, in seconds
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+// Body encoding of a gRPC-web request, None for other requests
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+  Binary,
+  Text,
+}
+
+impl Mode {
+  fn from_content_type(content_type: Option<&str>) -> Option<Self> {
+    let content_type = content_type?.split(';').next()?.trim();
+    let base = content_type.split('+').next()?;
+    match base {
+      GRPC_WEB => Some(Mode::Binary),
+      GRPC_WEB_TEXT => Some(Mode::Text),
+      _ => None,
+    }
+  }
+  fn content_type(&self) -> &'static str {
+    match self {
+      Mode::Binary => "application/grpc-web+proto",
+      Mode::Text => "application/grpc-web-text+proto",
+    }
+  }
+}
+
+// Origins allowed to call the service from a browser
+// Empty allows no cross-origin calls, * allows every origin
+#[derive(Clone, Debug, Default)]
+pub struct AllowedOrigins(Vec<String>);
+
+impl AllowedOrigins {
+  // Parse comma separated origins, e.g. https://gardenzilla.hu
+  pub fn parse(value: &str) -> Self {
+    Self(
+      value
+        .split(',')
+        .map(|o| o.trim().trim_end_matches('/').to_string())
+        .filter(|o| !o.is_empty())
+        .collect(),
+    )
+  }
+  // Allow-Origin header value for a request origin
+  fn allow(&self, origin: Option<&http::HeaderValue>) -> Option<http::HeaderValue> {
+    let origin = origin?;
+    match self
+      .0
+      .iter()
+      .any(|o| o == "*" || o.as_bytes() == origin.as_bytes())
+    {
+      true => Some(origin.clone()),
+      false => None,
+    }
+  }
+}
+
+// Service translating gRPC-web calls of the inner service
+#[derive(Clone)]
+pub struct GrpcWeb<S> {
+  inner: S,
+  origins: Arc<AllowedOrigins>,
+  max_request_bytes: usize,
+}
+
+impl<S> GrpcWeb<S> {
+  pub fn new(inner: S, origins: AllowedOrigins, max_request_bytes: usize) -> Self {
+    Self {
+      inner,
+      origins: Arc::new(origins),
+      max_request_bytes,
+    }
+  }
+}
+
+impl<S: NamedService> NamedService for GrpcWeb<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+fn add_cors_headers(headers: &mut http::HeaderMap, allow_origin: Option<http::HeaderValue>) {
+  if let Some(origin) = allow_origin {
+    headers.insert("access-control-allow-origin", origin);
+    headers.insert(
+      "access-control-expose-headers",
+      http::HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    headers.append("vary", http::HeaderValue::from_static("origin"));
+  }
+}
+
+// Answer to a CORS preflight request
+fn preflight(
+  request: &http::Request<Body>,
+  allow_origin: Option<http::HeaderValue>,
+) -> http::Response<BoxBody> {
+  let mut response = http::Response::new(BoxBody::empty());
+  *response.status_mut() = http::StatusCode::NO_CONTENT;
+  if allow_origin.is_some() {
+    let headers = response.headers_mut();
+    headers.insert(
+      "access-control-allow-methods",
+      http::HeaderValue::from_static("POST, OPTIONS"),
+    );
+    if let Some(requested) = request.headers().get("access-control-request-headers") {
+      headers.insert("access-control-allow-headers", requested.clone());
+    }
+    headers.insert(
+      "access-control-max-age",
+      http::HeaderValue::from_static(PREFLIGHT_MAX_AGE),
+    );
+  }
+  add_cors_headers(response.headers_mut(), allow_origin);
+  response
+}
+
+// Error response sent without calling the service
+fn error_response(error: ServiceError, mode: Mode) -> http::Response<BoxBody> {
+  let mut response = Status::from(error).to_http();
+  response.headers_mut().insert(
+    http::header::CONTENT_TYPE,
+    http::HeaderValue::from_static(mode.content_type()),
+  );
+  response
+}
+
+impl<S> Service<http::Request<Body>> for GrpcWeb<S>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  type Response = http::Response<BoxBody>;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+    let allow_origin = self.origins.allow(request.headers().get("origin"));
+    let max_request_bytes = self.max_request_bytes;
+    if request.method() == http::Method::OPTIONS {
+      let response = preflight(&request, allow_origin);
+      return Box::pin(async move { Ok(response) });
+    }
+    let mode = Mode::from_content_type(
+      request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok()),
+    );
+    // Errors of the translation run outside of the locale negotiation
+    let locale = Locale::from_accept_language(
+      request
+        .headers()
+        .get(ACCEPT_LANGUAGE_METADATA_KEY)
+        .and_then(|v| v.to_str().ok()),
+    );
+    // Take the service that was polled ready
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    Box::pin(with_locale(locale, async move {
+      // Other requests, e.g. gRPC over HTTP/2, are served as they are
+      let mode = match mode {
+        Some(mode) => mode,
+        None => return inner.call(request).await,
+      };
+      let (mut parts, body) = request.into_parts();
+      // Base64 text is 4 bytes for every 3 bytes of the message
+      let max_bytes = match mode {
+        Mode::Binary => max_request_bytes,
+        Mode::Text => max_request_bytes.saturating_add(2) / 3 * 4,
+      };
+      let too_large = || {
+        let mut response = error_response(payload::too_large(max_request_bytes), mode);
+        add_cors_headers(response.headers_mut(), allow_origin.clone());
+        response
+      };
+      if payload::declared_too_large(&parts.headers, max_bytes) {
+        return Ok(too_large());
+      }
+      // Requests are unary, so they are read as a whole
+      let body = match payload::read_body(body, max_bytes).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return Ok(too_large()),
+        Err(error) => return Ok(payload::read_error(error)),
+      };
+      let body = match mode {
+        Mode::Binary => body,
+        Mode::Text => {
+          let text = body
+            .iter()
+            .filter(|b| !b.is_ascii_whitespace())
+            .copied()
+            .collect::<Vec<u8>>();
+          match base64::decode(text) {
+            Ok(body) => body,
+            Err(_) => {
+              let error = ServiceError::bad_request("A gRPC-web kérés nem dekódolható");
+              let mut response = error_response(error, mode);
+              add_cors_headers(response.headers_mut(), allow_origin);
+              return Ok(response);
+            }
+          }
+        }
+      };
+      let headers = &mut parts.headers;
+      headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/grpc"),
+      );
+      headers.insert(http::header::TE, http::HeaderValue::from_static("trailers"));
+      headers.remove(http::header::CONTENT_LENGTH);
+      // The inner layers do not read the decoded body again
+      let body = Bytes::from(body);
+      parts.extensions.insert(payload::BufferedBody(body.clone()));
+      let response = inner
+        .call(http::Request::from_parts(parts, Body::from(body)))
+        .await?;
+      let mut response = response.map(|body| BoxBody::new(WebBody::new(body, mode)));
+      let headers = response.headers_mut();
+      headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(mode.content_type()),
+      );
+      headers.remove(http::header::CONTENT_LENGTH);
+      add_cors_headers(headers, allow_origin);
+      Ok(response)
+    }))
+  }
+}
+
+// Trailers as a gRPC-web trailer frame
+fn trailer_frame(trailers: &http::HeaderMap) -> Vec<u8> {
+  let mut block = Vec::new();
+  for (name, value) in trailers {
+    block.extend_from_slice(name.as_str().as_bytes());
+    block.push(b':');
+    block.extend_from_slice(value.as_bytes());
+    block.extend_from_slice(b"\r\n");
+  }
+  let mut frame = Vec::with_capacity(5 + block.len());
+  frame.push(TRAILER_FLAG);
+  frame.extend_from_slice(&(block.len() as u32).to_be_bytes());
+  frame.extend_from_slice(&block);
+  frame
+}
+
+// Response body with the trailers as its last frame
+// In text mode the body is base64 encoded, bytes not filling
+// a 3 byte group wait for the next chunk, so only the end is padded
+struct WebBody {
+  inner: BoxBody,
+  mode: Mode,
+  // Bytes of text mode waiting to be encoded
+  pending: Vec<u8>,
+  done: bool,
+}
+
+impl WebBody {
+  fn new(inner: BoxBody, mode: Mode) -> Self {
+    Self {
+      inner,
+      mode,
+      pending: Vec::new(),
+      done: false,
+    }
+  }
+  fn encode(&mut self, data: &[u8], last: bool) -> Bytes {
+    match self.mode {
+      Mode::Binary => Bytes::copy_from_slice(data),
+      Mode::Text => {
+        self.pending.extend_from_slice(data);
+        let len = match last {
+          true => self.pending.len(),
+          false => self.pending.len() / 3 * 3,
+        };
+        let chunk = self.pending.drain(..len).collect::<Vec<u8>>();
+        Bytes::from(base64::encode(chunk))
+      }
+    }
+  }
+}
+
+impl HttpBody for WebBody {
+  type Data = Bytes;
+  type Error = Status;
+
+  fn poll_data(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    loop {
+      if self.done {
+        return Poll::Ready(None);
+      }
+      match Pin::new(&mut self.inner).poll_data(cx) {
+        Poll::Ready(Some(Ok(data))) => {
+          let chunk = self.encode(&data, false);
+          // Nothing to send until a 3 byte group is filled
+          if !chunk.is_empty() {
+            return Poll::Ready(Some(Ok(chunk)));
+          }
+        }
+        Poll::Ready(Some(Err(error))) => return Poll::Ready(Some(Err(error))),
+        Poll::Ready(None) => {
+          let frame = match Pin::new(&mut self.inner).poll_trailers(cx) {
+            Poll::Ready(Ok(Some(trailers))) => trailer_frame(&trailers),
+            // Trailers-only responses have the status in the headers
+            Poll::Ready(Ok(None)) => Vec::new(),
+            Poll::Ready(Err(error)) => return Poll::Ready(Some(Err(error))),
+            Poll::Pending => return Poll::Pending,
+          };
+          self.done = true;
+          let chunk = self.encode(&frame, true);
+          if !chunk.is_empty() {
+            return Poll::Ready(Some(Ok(chunk)));
+          }
+        }
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+  }
+
+  fn poll_trailers(
+    self: Pin<&mut Self>,
+    _cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+    // Sent in the body
+    Poll::Ready(Ok(None))
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.done
+  }
+}
+
+// Serve gRPC-web until shutdown
+pub async fn serve<S, F>(addr: SocketAddr, service: GrpcWeb<S>, shutdown: F) -> hyper::Result<()>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: std::error::Error + Send + Sync + 'static,
+  F: std::future::Future<Output = ()>,
+{
+  let make_service = hyper::service::make_service_fn(move |_| {
+    let service = service.clone();
+    async move { Ok::<_, Infallible>(service) }
+  });
+  hyper::Server::try_bind(&addr)?
+    .serve(make_service)
+    .with_graceful_shutdown(shutdown)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_mode() {
+    let mode = |content_type| Mode::from_content_type(Some(content_type));
+    assert_eq!(mode("application/grpc-web"), Some(Mode::Binary));
+    assert_eq!(mode("application/grpc-web+proto"), Some(Mode::Binary));
+    assert_eq!(mode("application/grpc-web-text"), Some(Mode::Text));
+    assert_eq!(
+      mode("application/grpc-web-text+proto; charset=utf-8"),
+      Some(Mode::Text)
+    );
+    assert_eq!(mode("application/grpc"), None);
+    assert_eq!(Mode::from_content_type(None), None);
+  }
+
+  #[test]
+  fn test_allowed_origins() {
+    let origin = http::HeaderValue::from_static("https://gardenzilla.hu");
+    let origins = AllowedOrigins::parse("https://gardenzilla.hu/, https://admin.gardenzilla.hu");
+    assert_eq!(origins.allow(Some(&origin)), Some(origin.clone()));
+    let other = http::HeaderValue::from_static("https://example.com");
+    assert_eq!(origins.allow(Some(&other)), None);
+    assert_eq!(origins.allow(None), None);
+    assert_eq!(
+      AllowedOrigins::parse("*").allow(Some(&other)),
+      Some(other.clone())
+    );
+    assert_eq!(AllowedOrigins::default().allow(Some(&other)), None);
+  }
+
+  #[test]
+  fn test_trailer_frame() {
+    let mut trailers = http::HeaderMap::new();
+    trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+    assert_eq!(
+      trailer_frame(&trailers),
+      [&[0x80, 0, 0, 0, 15][..], b"grpc-status:0\r\n"].concat()
+    );
+  }
+
+  #[test]
+  fn test_text_encoding() {
+    let mut body = WebBody::new(BoxBody::empty(), Mode::Text);
+    // Only whole 3 byte groups are encoded before the end
+    assert_eq!(body.encode(b"abcd", false), "YWJj");
+    assert_eq!(body.encode(b"e", false), "");
+    assert_eq!(body.encode(b"f", true), "ZGVm");
+    let mut body = WebBody::new(BoxBody::empty(), Mode::Text);
+    assert_eq!(body.encode(b"ab", false), "");
+    assert_eq!(body.encode(b"", true), "YWI=");
+  }
+}
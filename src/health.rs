@@ -0,0 +1,142 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// gRPC health service
+//
+// Orchestration checks it to see whether the service is ready.
+// The customer service reports NOT_SERVING until the startup
+// warm-up has loaded every tenant, SERVING after that.
+
+use crate::proto::health::health_check_response::ServingStatus;
+use crate::proto::health::health_server::Health;
+use crate::proto::health::*;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+// Service names the health service knows, "" is the whole server
+const SERVICES: &[&str] = &["", "customer.Customer"];
+
+#[derive(Clone)]
+pub struct HealthService {
+  ready: watch::Receiver<bool>,
+}
+
+impl HealthService {
+  pub fn new(ready: watch::Receiver<bool>) -> Self {
+    Self { ready }
+  }
+}
+
+fn response(ready: bool) -> HealthCheckResponse {
+  HealthCheckResponse {
+    status: match ready {
+      true => ServingStatus::Serving,
+      false => ServingStatus::NotServing,
+    } as i32,
+  }
+}
+
+fn unknown_service(service: &str) -> Status {
+  Status::not_found(format!("Unknown service: {}", service))
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+  async fn check(
+    &self,
+    request: Request<HealthCheckRequest>,
+  ) -> Result<Response<HealthCheckResponse>, Status> {
+    let service = request.into_inner().service;
+    if !SERVICES.contains(&service.as_str()) {
+      return Err(unknown_service(&service));
+    }
+    Ok(Response::new(response(*self.ready.borrow())))
+  }
+
+  type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
+
+  // Sends the current status, then every change
+  async fn watch(
+    &self,
+    request: Request<HealthCheckRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    let service = request.into_inner().service;
+    if !SERVICES.contains(&service.as_str()) {
+      return Err(unknown_service(&service));
+    }
+    let mut ready = self.ready.clone();
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+      loop {
+        let status = *ready.borrow_and_update();
+        if tx.send(Ok(response(status))).await.is_err() || ready.changed().await.is_err() {
+          break;
+        }
+      }
+    });
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio_stream::StreamExt;
+
+  fn request(service: &str) -> Request<HealthCheckRequest> {
+    Request::new(HealthCheckRequest {
+      service: service.to_string(),
+    })
+  }
+
+  #[tokio::test]
+  async fn test_health() {
+    let (tx, rx) = watch::channel(false);
+    let health = HealthService::new(rx);
+    let status = |r: HealthCheckResponse| r.status;
+    assert_eq!(
+      status(health.check(request("")).await.unwrap().into_inner()),
+      ServingStatus::NotServing as i32
+    );
+    assert!(health.check(request("shipping.Shipping")).await.is_err());
+    let mut stream = health
+      .watch(request("customer.Customer"))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(
+      status(stream.next().await.unwrap().unwrap()),
+      ServingStatus::NotServing as i32
+    );
+    tx.send(true).unwrap();
+    assert_eq!(
+      status(stream.next().await.unwrap().unwrap()),
+      ServingStatus::Serving as i32
+    );
+    assert_eq!(
+      status(
+        health
+          .check(request("customer.Customer"))
+          .await
+          .unwrap()
+          .into_inner()
+      ),
+      ServingStatus::Serving as i32
+    );
+  }
+}
@@ -0,0 +1,362 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Field history
+//
+// Every stored customer change is recorded as a list of field diffs,
+// the path of the changed field with its old and new value, instead
+// of the whole customer. Nested objects, e.g. the invoice address, are
+// compared field by field (invoice_address.zip), lists as a whole.
+// Creates list every field set, with null old values.
+// Stored next to the customer storage of the tenant, in history.jsonl,
+// one JSON entry per stored customer.
+
+use crate::audit_sink::MutationAction;
+use crate::customer::Customer;
+use crate::prelude::*;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// Fields set by the customer db with every change
+const SKIPPED_FIELDS: &[&str] = &[
+  "last_modified",
+  "last_modified_by",
+  "display_name",
+  "sort_key",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldDiff {
+  // Dot separated, e.g. invoice_address.zip
+  pub path: String,
+  pub old: Value,
+  pub new: Value,
+}
+
+// Field diffs of a stored customer, previous is its version before the change
+pub fn diff(previous: Option<&Customer>, customer: &Customer) -> ServiceResult<Vec<FieldDiff>> {
+  let old = match previous {
+    Some(previous) => to_map(previous)?,
+    None => Map::new(),
+  };
+  let mut res = Vec::new();
+  diff_maps("", &old, &to_map(customer)?, &mut res);
+  // Creates only list the fields set
+  if previous.is_none() {
+    res.retain(|d| !is_empty(&d.new));
+  }
+  Ok(res)
+}
+
+// Customer as JSON object, with tax number as text
+fn to_map(customer: &Customer) -> ServiceResult<Map<String, Value>> {
+  let mut map = match serde_json::to_value(customer) {
+    Ok(Value::Object(map)) => map,
+    _ => {
+      return Err(ServiceError::internal_error(
+        "Error while comparing customers",
+      ))
+    }
+  };
+  map.insert(
+    "tax_number".to_string(),
+    match &customer.tax_number {
+      Some(tax_number) => Value::String(tax_number.to_string()),
+      None => Value::Null,
+    },
+  );
+  for field in SKIPPED_FIELDS {
+    map.remove(*field);
+  }
+  Ok(map)
+}
+
+fn diff_maps(
+  prefix: &str,
+  old: &Map<String, Value>,
+  new: &Map<String, Value>,
+  res: &mut Vec<FieldDiff>,
+) {
+  let mut keys = old.keys().chain(new.keys()).collect::<Vec<&String>>();
+  keys.sort();
+  keys.dedup();
+  for key in keys {
+    let path = match prefix {
+      "" => key.clone(),
+      prefix => format!("{}.{}", prefix, key),
+    };
+    let (old, new) = (
+      old.get(key).unwrap_or(&Value::Null),
+      new.get(key).unwrap_or(&Value::Null),
+    );
+    match (old, new) {
+      (Value::Object(old), Value::Object(new)) => diff_maps(&path, old, new, res),
+      // Set or removed objects are listed field by field
+      (Value::Null, Value::Object(new)) => diff_maps(&path, &Map::new(), new, res),
+      (Value::Object(old), Value::Null) => diff_maps(&path, old, &Map::new(), res),
+      _ if old != new => res.push(FieldDiff {
+        path,
+        old: old.clone(),
+        new: new.clone(),
+      }),
+      _ => (),
+    }
+  }
+}
+
+fn is_empty(value: &Value) -> bool {
+  match value {
+    Value::Null => true,
+    Value::Bool(b) => !b,
+    Value::String(s) => s.is_empty(),
+    Value::Array(a) => a.is_empty(),
+    _ => false,
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+  pub date: DateTime<Utc>,
+  pub customer_id: u32,
+  pub action: MutationAction,
+  // User making the change, 0 for the service itself
+  pub user_id: u32,
+  pub changes: Vec<FieldDiff>,
+}
+
+impl HistoryEntry {
+  pub fn new(previous: Option<&Customer>, customer: &Customer, changes: Vec<FieldDiff>) -> Self {
+    Self {
+      date: customer.last_modified,
+      customer_id: customer.id,
+      action: match previous {
+        Some(_) => MutationAction::Update,
+        None => MutationAction::Create,
+      },
+      user_id: match previous {
+        Some(_) => customer.last_modified_by,
+        None => customer.created_by,
+      },
+      changes,
+    }
+  }
+}
+
+// One change of a field
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldChange {
+  pub date: DateTime<Utc>,
+  pub action: MutationAction,
+  pub user_id: u32,
+  pub diff: FieldDiff,
+}
+
+// Field history of every tenant
+pub struct FieldHistory {
+  data_dir: PathBuf,
+}
+
+impl FieldHistory {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+    }
+  }
+  fn path(&self, tenant: &str) -> PathBuf {
+    tenant_path(&self.data_dir, tenant).with_file_name("history.jsonl")
+  }
+  // Append entries of a stored batch and sync them to disk
+  pub fn append(&self, tenant: &str, entries: &[HistoryEntry]) -> ServiceResult<()> {
+    if entries.is_empty() {
+      return Ok(());
+    }
+    let path = self.path(tenant);
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let mut lines = String::new();
+    for entry in entries {
+      let line = serde_json::to_string(entry)
+        .map_err(|e| ServiceError::internal_error(&format!("History entry error: {}", e)))?;
+      lines.push_str(&line);
+      lines.push('\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+  }
+  // Changes of a customer field, oldest first
+  // A field with nested fields, e.g. invoice_address, has their changes too
+  pub fn field(
+    &self,
+    tenant: &str,
+    customer_id: u32,
+    field: &str,
+  ) -> ServiceResult<Vec<FieldChange>> {
+    let path = self.path(tenant);
+    if !path.exists() {
+      return Ok(Vec::new());
+    }
+    let nested = format!("{}.", field);
+    let mut res = Vec::new();
+    for line in BufReader::new(std::fs::File::open(path)?).lines() {
+      let entry: HistoryEntry = serde_json::from_str(&line?)
+        .map_err(|e| ServiceError::internal_error(&format!("Broken history entry: {}", e)))?;
+      if entry.customer_id != customer_id {
+        continue;
+      }
+      for diff in entry.changes {
+        if diff.path == field || diff.path.starts_with(&nested) {
+          res.push(FieldChange {
+            date: entry.date,
+            action: entry.action,
+            user_id: entry.user_id,
+            diff,
+          });
+        }
+      }
+    }
+    Ok(res)
+  }
+}
+
+// Check the first part of a field path is a customer field
+pub fn validate_field(field: &str) -> ServiceResult<()> {
+  let name = field.split('.').next().unwrap_or_default();
+  match to_map(&Customer::default())?.contains_key(name) {
+    true => Ok(()),
+    false => Err(ServiceError::invalid_field(
+      "field",
+      &format!("Ismeretlen ügyfél mező: {}", field),
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::InvoiceAddress;
+  use crate::taxnumber::TaxNumber;
+  use serde_json::json;
+
+  fn customer() -> Customer {
+    Customer {
+      id: 3,
+      name: "Kiss Kert Kft".to_string(),
+      created_by: 2,
+      ..Customer::default()
+    }
+  }
+
+  fn paths(diffs: &[FieldDiff]) -> Vec<&str> {
+    diffs.iter().map(|d| d.path.as_str()).collect()
+  }
+
+  #[test]
+  fn test_diff() {
+    let before = customer();
+    let created = diff(None, &before).unwrap();
+    assert!(paths(&created).contains(&"name"));
+    assert!(created.iter().all(|d| d.old.is_null()));
+    // Empty fields are not listed
+    assert!(!paths(&created).contains(&"email"));
+    let mut after = before.clone();
+    after.tax_number = TaxNumber::new("66064590-2-35").ok();
+    after.invoice_address = Some(InvoiceAddress {
+      zip: "6000".to_string(),
+      location: "Kecskemét".to_string(),
+      street: String::new(),
+    });
+    after.last_modified_by = 9;
+    let diffs = diff(Some(&before), &after).unwrap();
+    assert_eq!(
+      paths(&diffs),
+      [
+        "invoice_address.location",
+        "invoice_address.street",
+        "invoice_address.zip",
+        "tax_number"
+      ]
+    );
+    assert_eq!(diffs[3].old, Value::Null);
+    assert_eq!(diffs[3].new, json!("66064590-2-35"));
+    let mut later = after.clone();
+    later.invoice_address.as_mut().unwrap().zip = "6001".to_string();
+    let diffs = diff(Some(&after), &later).unwrap();
+    assert_eq!(
+      diffs,
+      [FieldDiff {
+        path: "invoice_address.zip".to_string(),
+        old: json!("6000"),
+        new: json!("6001"),
+      }]
+    );
+    assert!(diff(Some(&later), &later).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_field_history() {
+    let dir = tempfile::tempdir().unwrap();
+    let history = FieldHistory::new(dir.path());
+    assert!(history.field("", 3, "tax_number").unwrap().is_empty());
+    let before = customer();
+    let mut after = before.clone();
+    after.tax_number = TaxNumber::new("66064590-2-35").ok();
+    after.last_modified_by = 9;
+    let mut other = before.clone();
+    other.id = 4;
+    history
+      .append(
+        "",
+        &[
+          HistoryEntry::new(None, &before, diff(None, &before).unwrap()),
+          HistoryEntry::new(None, &other, diff(None, &other).unwrap()),
+        ],
+      )
+      .unwrap();
+    history
+      .append(
+        "",
+        &[HistoryEntry::new(
+          Some(&before),
+          &after,
+          diff(Some(&before), &after).unwrap(),
+        )],
+      )
+      .unwrap();
+    let changes = history.field("", 3, "tax_number").unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].action, MutationAction::Update);
+    assert_eq!(changes[0].user_id, 9);
+    assert_eq!(changes[0].diff.new, json!("66064590-2-35"));
+    let changes = history.field("", 3, "name").unwrap();
+    assert_eq!(
+      (changes.len(), changes[0].action, changes[0].user_id),
+      (1, MutationAction::Create, 2)
+    );
+    // Tenants are separate
+    assert!(history.field("shop_a", 3, "name").unwrap().is_empty());
+    assert!(validate_field("invoice_address.zip").is_ok());
+    assert!(validate_field("adoszam").is_err());
+  }
+}
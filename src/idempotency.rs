@@ -0,0 +1,109 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tonic::metadata::MetadataMap;
+
+// Request metadata key of the idempotency key
+pub const IDEMPOTENCY_METADATA_KEY: &str = "idempotency-key";
+
+// Max idempotency key length
+const MAX_KEY_LEN: usize = 128;
+
+// Recently used idempotency keys
+//
+// Maps (tenant, idempotency key) to the ID of the customer
+// created by the first request with that key. Keys expire after TTL.
+pub struct IdempotencyCache {
+  ttl: Duration,
+  entries: HashMap<(String, String), (u32, Instant)>,
+}
+
+impl IdempotencyCache {
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      entries: HashMap::new(),
+    }
+  }
+  // Get customer ID created with the given key
+  pub fn get(&mut self, tenant: &str, key: &str) -> Option<u32> {
+    self.remove_expired();
+    self
+      .entries
+      .get(&(tenant.to_string(), key.to_string()))
+      .map(|(customer_id, _)| *customer_id)
+  }
+  // Store customer ID created with the given key
+  pub fn insert(&mut self, tenant: &str, key: &str, customer_id: u32) {
+    self.entries.insert(
+      (tenant.to_string(), key.to_string()),
+      (customer_id, Instant::now()),
+    );
+  }
+  fn remove_expired(&mut self) {
+    let ttl = self.ttl;
+    self
+      .entries
+      .retain(|_, (_, created)| created.elapsed() < ttl);
+  }
+}
+
+// Get idempotency key from request metadata
+pub fn idempotency_key_from_metadata(metadata: &MetadataMap) -> ServiceResult<Option<String>> {
+  match metadata.get(IDEMPOTENCY_METADATA_KEY) {
+    Some(value) => {
+      let key = value
+        .to_str()
+        .map_err(|_| ServiceError::bad_request("Hibás idempotencia kulcs"))?
+        .trim();
+      if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(ServiceError::bad_request(&format!(
+          "Az idempotencia kulcs hossza 1 és {} karakter között lehet",
+          MAX_KEY_LEN
+        )));
+      }
+      Ok(Some(key.to_string()))
+    }
+    None => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cache() {
+    let mut cache = IdempotencyCache::new(Duration::from_secs(60));
+    assert_eq!(cache.get("", "key1"), None);
+    cache.insert("", "key1", 1);
+    assert_eq!(cache.get("", "key1"), Some(1));
+    // Keys are per tenant
+    assert_eq!(cache.get("shop_a", "key1"), None);
+  }
+
+  #[test]
+  fn test_cache_expiry() {
+    let mut cache = IdempotencyCache::new(Duration::from_millis(0));
+    cache.insert("", "key1", 1);
+    assert_eq!(cache.get("", "key1"), None);
+    assert!(cache.entries.is_empty());
+  }
+}
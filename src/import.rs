@@ -0,0 +1,526 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Customer import with upsert
+//
+// Rows of an import are matched to the existing customers by their
+// external ID, e.g. the webshop customer ID, or by their tax number.
+// Matched customers are updated, the others are created, and rows
+// without changes are skipped, so the same import can be run again
+// safely, e.g. by nightly syncs. Every row gets its own result, a
+// failing row does not stop the others.
+
+use crate::country::DEFAULT_COUNTRY;
+use crate::customer::{ContactChannel, Customer, CustomerKind};
+use crate::db::{email_key, tax_number_key, CustomerDb};
+use crate::policy::{Field, ValidationPolicy};
+use crate::prelude::*;
+use crate::restore::changed_fields;
+use crate::sanitize;
+use crate::taxnumber::TaxNumber;
+use std::collections::{HashMap, HashSet};
+
+// Max number of rows per import
+pub const MAX_IMPORT_ROWS: usize = 10_000;
+
+// Field the rows are matched by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportKey {
+  ExternalId,
+  TaxNumber,
+}
+
+impl ImportKey {
+  pub fn field(&self) -> &'static str {
+    match self {
+      ImportKey::ExternalId => "external_id",
+      ImportKey::TaxNumber => Field::TaxNumber.path(),
+    }
+  }
+}
+
+// Customer data of an import row
+#[derive(Clone, Debug, Default)]
+pub struct ImportRow {
+  // Empty keeps the current external ID
+  pub external_id: String,
+  pub name: String,
+  pub email: String,
+  pub phone: String,
+  pub tax_number: Option<TaxNumber>,
+  pub address_zip: String,
+  pub address_location: String,
+  pub address_street: String,
+  // None keeps the current country, HU for new customers
+  pub country: Option<String>,
+  // None keeps the current kind, new customers with
+  // tax number are considered as companies
+  pub kind: Option<CustomerKind>,
+  // None keeps the current loyalty card
+  pub loyalty_card_id: Option<String>,
+  pub invoiceable: bool,
+  // None keeps the current preferred contact
+  pub preferred_contact: Option<ContactChannel>,
+  pub created_by: u32,
+}
+
+impl From<Customer> for ImportRow {
+  fn from(c: Customer) -> Self {
+    Self {
+      external_id: c.external_id.unwrap_or_default(),
+      name: c.name,
+      email: c.email,
+      phone: c.phone,
+      tax_number: c.tax_number,
+      address_zip: c.address_zip,
+      address_location: c.address_location,
+      address_street: c.address_street,
+      country: Some(c.country),
+      kind: Some(c.kind),
+      loyalty_card_id: c.loyalty_card_id,
+      invoiceable: c.invoiceable,
+      preferred_contact: c.preferred_contact,
+      created_by: c.created_by,
+    }
+  }
+}
+
+// What an import row does
+// Plans are short lived, their size does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum Planned {
+  Create(Customer),
+  // Current and updated customer
+  Update(Customer, Customer),
+  // Customer ID, the row has no changes
+  Unchanged(u32),
+}
+
+// Plans the rows of one import
+pub struct Importer<'a> {
+  db: &'a CustomerDb,
+  key: ImportKey,
+  policy: &'a ValidationPolicy,
+  // ID of the next new customer
+  next_id: u32,
+  // Keys of the rows planned so far
+  seen: HashSet<String>,
+  // Emails and tax numbers of the rows planned so far,
+  // with the ID of their customer
+  emails: HashMap<String, u32>,
+  tax_numbers: HashMap<String, u32>,
+}
+
+impl<'a> Importer<'a> {
+  pub fn new(
+    db: &'a CustomerDb,
+    key: ImportKey,
+    policy: &'a ValidationPolicy,
+    next_id: u32,
+  ) -> Self {
+    Self {
+      db,
+      key,
+      policy,
+      next_id,
+      seen: HashSet::new(),
+      emails: HashMap::new(),
+      tax_numbers: HashMap::new(),
+    }
+  }
+  // Check the customer does not conflict with the customer of an
+  // earlier row on the fields the policy requires to be unique,
+  // the stored customers are checked by the service
+  fn check_planned(&self, customer: &Customer) -> ServiceResult<()> {
+    let planned = |index: &HashMap<String, u32>, key: Option<String>| {
+      key
+        .and_then(|key| index.get(&key).copied())
+        .filter(|id| *id != customer.id)
+    };
+    if self.policy.unique_email {
+      if let Some(customer_id) = planned(&self.emails, email_key(customer)) {
+        return Err(ServiceError::conflict(
+          "Ez az email cím már az import egy korábbi sorában szerepel",
+          customer_id,
+        ));
+      }
+    }
+    if self.policy.unique_tax_number {
+      if let Some(customer_id) = planned(&self.tax_numbers, tax_number_key(customer)) {
+        return Err(ServiceError::conflict(
+          "Ez az adószám már az import egy korábbi sorában szerepel",
+          customer_id,
+        ));
+      }
+    }
+    Ok(())
+  }
+  // Key of the row, and the customer it matches
+  fn find(&self, row: &ImportRow) -> ServiceResult<(String, Option<u32>)> {
+    match self.key {
+      ImportKey::ExternalId => match row.external_id.trim() {
+        "" => Err(ServiceError::invalid_field(
+          self.key.field(),
+          "A külső azonosító megadása kötelező",
+        )),
+        external_id => Ok((
+          external_id.to_string(),
+          self.db.find_external_id(external_id),
+        )),
+      },
+      ImportKey::TaxNumber => {
+        let tax_number = row.tax_number.as_ref().ok_or_else(|| {
+          ServiceError::invalid_field(
+            self.key.field(),
+            &format!("A(z) {} megadása kötelező", Field::TaxNumber.display_name()),
+          )
+        })?;
+        match self.db.find_tax_number(tax_number).as_slice() {
+          [] => Ok((tax_number.to_string(), None)),
+          [id] => Ok((tax_number.to_string(), Some(*id))),
+          _ => Err(ServiceError::invalid_field(
+            self.key.field(),
+            "Az adószám több ügyfélhez is tartozik, a sor nem azonosítható",
+          )),
+        }
+      }
+    }
+  }
+  // Plan the row
+  // Nothing is changed until the planned changes are stored
+  pub fn plan(&mut self, row: ImportRow) -> ServiceResult<Planned> {
+    let (key, found) = self.find(&row)?;
+    if self.seen.contains(&key) {
+      return Err(ServiceError::invalid_field(
+        self.key.field(),
+        "Ez az ügyfél már szerepel az import egy korábbi sorában",
+      ));
+    }
+    let external_id = match sanitize::line(&row.external_id) {
+      Ok(external_id) if external_id.is_empty() => None,
+      Ok(external_id) => Some(external_id),
+      Err(e) => return Err(e.on_field("external_id")),
+    };
+    let planned = match found {
+      Some(id) => {
+        let current = self.db.find_id(&id)?;
+        let mut updated = current.clone();
+        updated.update(
+          row.name,
+          row.email,
+          row.phone,
+          row.tax_number,
+          row.address_zip,
+          row.address_location,
+          row.address_street,
+          row.country,
+          row.loyalty_card_id,
+          row.kind.unwrap_or(current.kind),
+          row.invoiceable,
+          row.preferred_contact,
+          self.policy,
+        )?;
+        if external_id.is_some() {
+          updated.external_id = external_id;
+        }
+        match changed_fields(&updated, current)?.is_empty() {
+          true => Planned::Unchanged(id),
+          false => Planned::Update(current.clone(), updated),
+        }
+      }
+      None => {
+        let kind = row.kind.unwrap_or(match row.tax_number {
+          Some(_) => CustomerKind::Company,
+          None => CustomerKind::Private,
+        });
+        let mut customer = Customer::new(
+          self.next_id,
+          row.name,
+          row.email,
+          row.phone,
+          row.tax_number,
+          row.address_zip,
+          row.address_location,
+          row.address_street,
+          row.country.unwrap_or_else(|| DEFAULT_COUNTRY.to_string()),
+          row.loyalty_card_id,
+          row.created_by,
+          kind,
+          row.invoiceable,
+          row.preferred_contact,
+          self.policy,
+        )?;
+        customer.external_id = external_id;
+        Planned::Create(customer)
+      }
+    };
+    if let Planned::Create(customer) | Planned::Update(_, customer) = &planned {
+      self.check_planned(customer)?;
+      if let Some(email) = email_key(customer) {
+        self.emails.insert(email, customer.id);
+      }
+      if let Some(tax_number) = tax_number_key(customer) {
+        self.tax_numbers.insert(tax_number, customer.id);
+      }
+    }
+    if let Planned::Create(_) = planned {
+      self.next_id += 1;
+    }
+    self.seen.insert(key);
+    Ok(planned)
+  }
+}
+
+// Store the planned changes of the rows together
+// Changes conflicting with an other customer, e.g. by their loyalty
+// card, are not stored and get their error. Returns the stored versions.
+pub fn store(
+  db: &mut CustomerDb,
+  modified_by: u32,
+  planned: Vec<(usize, Planned)>,
+) -> ServiceResult<Vec<(usize, ServiceResult<Planned>)>> {
+  db.atomic(|tx| {
+    let mut res = Vec::new();
+    for (row, planned) in planned {
+      let stored = match planned {
+        Planned::Create(customer) => {
+          let id = customer.id;
+          tx.insert(customer)
+            .and_then(|_| Ok(Planned::Create(tx.find_id(&id)?.clone())))
+        }
+        Planned::Update(current, updated) => {
+          let id = updated.id;
+          tx.update(&id, modified_by, |customer| {
+            *customer = updated;
+            Ok(())
+          })
+          .and_then(|_| Ok(Planned::Update(current, tx.find_id(&id)?.clone())))
+        }
+        Planned::Unchanged(id) => Ok(Planned::Unchanged(id)),
+      };
+      res.push((row, stored));
+    }
+    Ok(res)
+  })
+}
+
+// Action done by an import row
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RowAction {
+  Created,
+  Updated,
+  // No changes
+  Skipped,
+  Error,
+}
+
+// Result of an import row, row is its index
+pub struct RowResult {
+  pub row: usize,
+  pub action: RowAction,
+  // 0 for errors
+  pub customer_id: u32,
+  pub error: Option<ServiceError>,
+}
+
+impl RowResult {
+  pub fn new(row: usize, res: ServiceResult<&Planned>) -> Self {
+    let (action, customer_id, error) = match res {
+      Ok(Planned::Create(customer)) => (RowAction::Created, customer.id, None),
+      Ok(Planned::Update(_, customer)) => (RowAction::Updated, customer.id, None),
+      Ok(Planned::Unchanged(id)) => (RowAction::Skipped, *id, None),
+      Err(error) => (RowAction::Error, 0, Some(error)),
+    };
+    Self {
+      row,
+      action,
+      customer_id,
+      error,
+    }
+  }
+}
+
+// Plan and store rows, without the checks of the service
+// New customers get IDs from next_id, with dry_run nothing is stored
+pub fn run(
+  db: &mut CustomerDb,
+  key: ImportKey,
+  rows: Vec<ImportRow>,
+  policy: &ValidationPolicy,
+  modified_by: u32,
+  next_id: u32,
+  dry_run: bool,
+) -> ServiceResult<Vec<RowResult>> {
+  let mut res = Vec::new();
+  let mut planned = Vec::new();
+  let mut importer = Importer::new(db, key, policy, next_id);
+  for (row, data) in rows.into_iter().enumerate() {
+    match importer.plan(data) {
+      Ok(p) => planned.push((row, p)),
+      Err(error) => res.push(RowResult::new(row, Err(error))),
+    }
+  }
+  match dry_run {
+    true => res.extend(planned.iter().map(|(row, p)| RowResult::new(*row, Ok(p)))),
+    false => res.extend(
+      store(db, modified_by, planned)?
+        .into_iter()
+        .map(|(row, p)| match p {
+          Ok(p) => RowResult::new(row, Ok(&p)),
+          Err(error) => RowResult::new(row, Err(error)),
+        }),
+    ),
+  }
+  res.sort_by_key(|r| r.row);
+  Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::MemoryStore;
+  use RowAction::*;
+
+  fn row(external_id: &str, name: &str) -> ImportRow {
+    ImportRow {
+      external_id: external_id.to_string(),
+      name: name.to_string(),
+      phone: "+36 30 123 4567".to_string(),
+      created_by: 9,
+      ..ImportRow::default()
+    }
+  }
+
+  fn import(db: &mut CustomerDb, key: ImportKey, rows: Vec<ImportRow>) -> Vec<RowAction> {
+    import_with(db, key, rows, &ValidationPolicy::default())
+      .iter()
+      .map(|r| r.action)
+      .collect()
+  }
+
+  fn import_with(
+    db: &mut CustomerDb,
+    key: ImportKey,
+    rows: Vec<ImportRow>,
+    policy: &ValidationPolicy,
+  ) -> Vec<RowResult> {
+    let next_id = db.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+    run(db, key, rows, policy, 1, next_id, false).unwrap()
+  }
+
+  #[test]
+  fn test_upsert_by_external_id() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    let rows = vec![
+      row("ws-1", "Kiss Béla"),
+      row("ws-2", "Nagy Anna"),
+      row("", "Kovács Éva"),
+      row("ws-1", "Kiss Béla"),
+    ];
+    assert_eq!(
+      import(&mut db, ImportKey::ExternalId, rows.clone()),
+      [Created, Created, Error, Error]
+    );
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.find_external_id("ws-2"), Some(2));
+    // Running it again changes nothing
+    assert_eq!(
+      import(&mut db, ImportKey::ExternalId, rows[..2].to_vec()),
+      [Skipped, Skipped]
+    );
+    // Changed rows update the matched customer
+    let mut changed = row("ws-2", "Nagy Anna");
+    changed.email = "nagy.anna@example.com".to_string();
+    assert_eq!(
+      import(
+        &mut db,
+        ImportKey::ExternalId,
+        vec![changed, row("ws-3", "X")]
+      ),
+      [Updated, Error]
+    );
+    let customer = db.find_id(&2).unwrap();
+    assert_eq!(customer.email, "nagy.anna@example.com");
+    assert_eq!(customer.last_modified_by, 1);
+  }
+
+  #[test]
+  fn test_upsert_by_tax_number() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    let company = |external_id: &str, name: &str, tax_number: &str| ImportRow {
+      kind: Some(CustomerKind::Company),
+      tax_number: Some(TaxNumber::new(tax_number).unwrap()),
+      ..row(external_id, name)
+    };
+    let rows = vec![
+      company("", "Kiss Kert Kft", "23127182-2-15"),
+      row("", "Kiss Béla"),
+    ];
+    assert_eq!(
+      import(&mut db, ImportKey::TaxNumber, rows),
+      [Created, Error]
+    );
+    // The external ID is set on the matched customer
+    let rows = vec![company("ws-7", "Kiss Kert Kft", "23127182-2-15")];
+    assert_eq!(import(&mut db, ImportKey::TaxNumber, rows), [Updated]);
+    assert_eq!(db.find_external_id("ws-7"), Some(1));
+    // External IDs are unique
+    let rows = vec![company("ws-7", "Nagy Kert Kft", "66064590-2-35")];
+    assert_eq!(import(&mut db, ImportKey::TaxNumber, rows), [Error]);
+    assert_eq!(db.len(), 1);
+  }
+
+  #[test]
+  fn test_unique_in_batch() {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    let policy = ValidationPolicy {
+      unique_email: true,
+      ..ValidationPolicy::default()
+    };
+    let with_email = |external_id: &str, name: &str, email: &str| ImportRow {
+      email: email.to_string(),
+      ..row(external_id, name)
+    };
+    let rows = vec![
+      with_email("ws-1", "Kiss Béla", "kiss@example.com"),
+      with_email("ws-2", "Nagy Anna", " KISS@example.com"),
+      with_email("ws-3", "Kovács Éva", "kovacs@example.com"),
+    ];
+    let res = import_with(&mut db, ImportKey::ExternalId, rows, &policy);
+    assert_eq!(
+      res.iter().map(|r| r.action).collect::<Vec<_>>(),
+      [Created, Error, Created]
+    );
+    // Conflict with the customer of the earlier row
+    match &res[1].error {
+      Some(ServiceError::Conflict(_, customer_id)) => assert_eq!(*customer_id, 1),
+      _ => panic!("Duplicate email is not a conflict"),
+    }
+    // The failed row does not use up an ID
+    assert_eq!(db.find_external_id("ws-3"), Some(2));
+    // Emails may be shared without the policy
+    let rows = vec![
+      with_email("ws-4", "Szabó Péter", "szabo@example.com"),
+      with_email("ws-5", "Szabó Anna", "szabo@example.com"),
+    ];
+    assert_eq!(
+      import(&mut db, ImportKey::ExternalId, rows),
+      [Created, Created]
+    );
+  }
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Single writer guard
+//
+// Two instances writing the same data directory would corrupt its
+// storages, so the server locks data/instance.lock while it runs.
+// The lock is released by the OS when the process stops, even if it
+// crashes. An instance finding the data directory locked refuses to
+// start, or with DATA_DIR_LOCKED=read_only starts read only: it
+// serves reads, rejects writes, and runs no background jobs.
+
+use crate::prelude::*;
+use chrono::prelude::*;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// What to do when the data directory is locked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhenLocked {
+  Refuse,
+  ReadOnly,
+}
+
+impl WhenLocked {
+  pub fn from_name(name: &str) -> ServiceResult<Self> {
+    match name {
+      "refuse" => Ok(WhenLocked::Refuse),
+      "read_only" => Ok(WhenLocked::ReadOnly),
+      _ => Err(ServiceError::internal_error(&format!(
+        "Unknown DATA_DIR_LOCKED value: {}",
+        name
+      ))),
+    }
+  }
+}
+
+// Held lock of the data directory
+pub struct InstanceLock {
+  _file: File,
+}
+
+pub fn lock_path(data_dir: &Path) -> PathBuf {
+  data_dir.join("instance.lock")
+}
+
+// Lock data directory
+// Returns the holder of the lock if an other process has it
+pub fn acquire(data_dir: &Path) -> ServiceResult<Result<InstanceLock, String>> {
+  std::fs::create_dir_all(data_dir)?;
+  let mut file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(lock_path(data_dir))?;
+  match file.try_lock() {
+    Ok(()) => (),
+    Err(TryLockError::WouldBlock) => {
+      let mut holder = String::new();
+      file.read_to_string(&mut holder)?;
+      return Ok(Err(holder.trim().to_string()));
+    }
+    Err(TryLockError::Error(error)) => return Err(error.into()),
+  }
+  // Holder info, for the error message of the next instance
+  file.set_len(0)?;
+  file.seek(SeekFrom::Start(0))?;
+  writeln!(
+    file,
+    "pid {} since {}",
+    std::process::id(),
+    Utc::now().to_rfc3339()
+  )?;
+  Ok(Ok(InstanceLock { _file: file }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_acquire() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    let lock = acquire(&data_dir).unwrap().unwrap();
+    // File locks are per open file, so a second open
+    // in the same process is locked out as well
+    let holder = acquire(&data_dir).unwrap().err().unwrap();
+    assert!(holder.starts_with(&format!("pid {} since", std::process::id())));
+    drop(lock);
+    assert!(acquire(&data_dir).unwrap().is_ok());
+  }
+
+  #[test]
+  fn test_when_locked() {
+    assert_eq!(WhenLocked::from_name("refuse").unwrap(), WhenLocked::Refuse);
+    assert_eq!(
+      WhenLocked::from_name("read_only").unwrap(),
+      WhenLocked::ReadOnly
+    );
+    assert!(WhenLocked::from_name("ignore").is_err());
+  }
+}
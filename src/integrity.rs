@@ -0,0 +1,325 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Startup integrity check
+//
+// Reads the customer files one by one before the storage is loaded,
+// as VecPack loading panics on duplicate IDs. Every customer is checked
+// against the current validation rules. Invalid records can be moved
+// into a quarantine pack next to the customer storage, to repair them
+// manually.
+//
+// Unreadable records are skipped by the storage loaders, so the service
+// can start with the rest. They are copied into the corrupt directory
+// next to the customer storage, and listed in customers_corrupt.jsonl.
+
+use crate::customer::Customer;
+use crate::policy::ValidationPolicy;
+use crate::prelude::*;
+use crate::storage::{read_customer_file, BrokenRecord};
+use crate::tenant::*;
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// Quarantined customer record
+// Its own ID is a sequence number, as quarantined
+// customers can have the same customer ID
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct QuarantineEntry {
+  pub id: u32,
+  pub file_name: String,
+  pub reason: String,
+  pub date_quarantined: DateTime<Utc>,
+  pub customer: Customer,
+}
+
+impl VecPackMember for QuarantineEntry {
+  type Out = u32;
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+// Unreadable record skipped at load
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CorruptRecord {
+  pub date: DateTime<Utc>,
+  // Storage file relative to the tenant directory,
+  // e.g. customers/12 or customers.log
+  pub source: String,
+  // Byte offset of the record in the source
+  pub offset: u64,
+  pub size: u64,
+  pub reason: String,
+  // Copy of the record in the corrupt directory
+  pub file_name: String,
+}
+
+// Invalid customer record
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+  pub file_name: String,
+  pub customer_id: u32,
+  pub reason: String,
+}
+
+// Integrity check report of a tenant
+pub struct Report {
+  pub checked: usize,
+  pub problems: Vec<Problem>,
+  pub quarantined: usize,
+}
+
+// Check customers of a tenant
+// If quarantine is set, invalid records are moved
+// into the quarantine pack of the tenant
+pub fn verify_tenant(
+  data_dir: &Path,
+  tenant: &str,
+  policy: &ValidationPolicy,
+  quarantine: bool,
+) -> ServiceResult<Report> {
+  let dir = tenant_path(data_dir, tenant);
+  let mut report = Report {
+    checked: 0,
+    problems: Vec::new(),
+    quarantined: 0,
+  };
+  if !dir.is_dir() {
+    return Ok(report);
+  }
+  // Load files grouped by customer ID
+  let mut files: BTreeMap<u32, Vec<(String, Customer)>> = BTreeMap::new();
+  for entry in std::fs::read_dir(&dir)? {
+    let path = entry?.path();
+    let file_name = path
+      .file_name()
+      .unwrap_or_default()
+      .to_string_lossy()
+      .to_string();
+    // Unreadable files are quarantined when the storage is loaded
+    let customer = match read_customer_file(&path) {
+      Ok((customer, _)) => customer,
+      Err(error) => {
+        report.checked += 1;
+        report.problems.push(Problem {
+          file_name,
+          customer_id: 0,
+          reason: format!("Cannot read: {}", error),
+        });
+        continue;
+      }
+    };
+    files
+      .entry(customer.id)
+      .or_default()
+      .push((file_name, customer));
+  }
+  let mut invalid: Vec<(String, Customer, String)> = Vec::new();
+  for (id, mut records) in files {
+    report.checked += records.len();
+    // Keep the record stored under its own ID,
+    // the others are duplicates
+    records.sort_by_key(|(file_name, _)| *file_name != id.to_string());
+    let mut records = records.into_iter();
+    if let Some((file_name, customer)) = records.next() {
+      if let Err(error) = customer.validate(policy) {
+        invalid.push((file_name, customer, error.to_string()));
+      }
+    }
+    for (file_name, customer) in records {
+      invalid.push((file_name, customer, "Duplikált ügyfél ID".to_string()));
+    }
+  }
+  if quarantine && !invalid.is_empty() {
+    let mut pack: VecPack<QuarantineEntry> = VecPack::load_or_init(quarantine_path(&dir))?;
+    let next_id = pack.iter().map(|e| e.unpack().id).max().unwrap_or(0) + 1;
+    for (id, (file_name, customer, reason)) in (next_id..).zip(&invalid) {
+      pack.insert(QuarantineEntry {
+        id,
+        file_name: file_name.clone(),
+        reason: reason.clone(),
+        date_quarantined: Utc::now(),
+        customer: customer.clone(),
+      })?;
+      std::fs::remove_file(dir.join(file_name))?;
+      report.quarantined += 1;
+    }
+  }
+  report.problems.extend(
+    invalid
+      .into_iter()
+      .map(|(file_name, customer, reason)| Problem {
+        file_name,
+        customer_id: customer.id,
+        reason,
+      }),
+  );
+  Ok(report)
+}
+
+// Copy unreadable record into the corrupt directory of the tenant
+// before the storage loader drops it
+pub fn quarantine_corrupt(
+  data_dir: &Path,
+  tenant: &str,
+  record: BrokenRecord,
+) -> ServiceResult<CorruptRecord> {
+  let storage = tenant_path(data_dir, tenant);
+  let base = storage.parent().unwrap_or(data_dir);
+  let dir = storage.with_file_name("customers_corrupt");
+  std::fs::create_dir_all(&dir)?;
+  let file_name = (corrupt_records(data_dir, tenant)?.len() + 1).to_string();
+  std::fs::write(dir.join(&file_name), &record.data)?;
+  let entry = CorruptRecord {
+    date: Utc::now(),
+    source: record
+      .path
+      .strip_prefix(base)
+      .unwrap_or(&record.path)
+      .to_string_lossy()
+      .to_string(),
+    offset: record.offset,
+    size: record.data.len() as u64,
+    reason: record.reason,
+    file_name,
+  };
+  let line = serde_json::to_string(&entry)
+    .map_err(|e| ServiceError::internal_error(&format!("Corrupt record entry error: {}", e)))?;
+  let mut file = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(corrupt_index_path(data_dir, tenant))?;
+  writeln!(file, "{}", line)?;
+  file.sync_data()?;
+  eprintln!(
+    "Tenant '{}': unreadable record in {} at offset {} ({} bytes) quarantined as customers_corrupt/{}: {}",
+    tenant, entry.source, entry.offset, entry.size, entry.file_name, entry.reason
+  );
+  Ok(entry)
+}
+
+// Unreadable records of a tenant skipped at load, oldest first
+pub fn corrupt_records(data_dir: &Path, tenant: &str) -> ServiceResult<Vec<CorruptRecord>> {
+  let path = corrupt_index_path(data_dir, tenant);
+  if !path.exists() {
+    return Ok(Vec::new());
+  }
+  let mut res = Vec::new();
+  for line in BufReader::new(std::fs::File::open(path)?).lines() {
+    let entry = serde_json::from_str(&line?)
+      .map_err(|e| ServiceError::internal_error(&format!("Broken corrupt record entry: {}", e)))?;
+    res.push(entry);
+  }
+  Ok(res)
+}
+
+fn corrupt_index_path(data_dir: &Path, tenant: &str) -> PathBuf {
+  tenant_path(data_dir, tenant).with_file_name("customers_corrupt.jsonl")
+}
+
+// Check customers of all the tenants, and print the results
+pub fn verify_all(
+  data_dir: &Path,
+  policy: &ValidationPolicy,
+  quarantine: bool,
+) -> ServiceResult<()> {
+  for tenant in tenant_ids(data_dir)? {
+    let report = verify_tenant(data_dir, &tenant, policy, quarantine)?;
+    for problem in &report.problems {
+      eprintln!(
+        "{}\t{}\t{}\t{}",
+        tenant, problem.file_name, problem.customer_id, problem.reason
+      );
+    }
+    eprintln!(
+      "Tenant '{}' checked: {}, invalid: {}, quarantined: {}",
+      tenant,
+      report.checked,
+      report.problems.len(),
+      report.quarantined
+    );
+  }
+  Ok(())
+}
+
+// Quarantine pack is stored next to the customer storage
+fn quarantine_path(dir: &Path) -> PathBuf {
+  let mut name = dir.file_name().unwrap_or_default().to_os_string();
+  name.push("_quarantine");
+  dir.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_verify_tenant() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = load_db(dir.path(), DEFAULT_TENANT).unwrap();
+    for (id, email) in [(1, "kiss@example.com"), (2, "nagy"), (3, "")] {
+      db.insert(Customer {
+        id,
+        name: "Kiss Béla".to_string(),
+        email: email.to_string(),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    drop(db);
+    // Copy of customer 1 stored under an other file name
+    let customers_dir = dir.path().join("customers");
+    std::fs::copy(customers_dir.join("1"), customers_dir.join("7")).unwrap();
+
+    let policy = ValidationPolicy::default();
+    let report = verify_tenant(dir.path(), DEFAULT_TENANT, &policy, false).unwrap();
+    assert_eq!(report.checked, 4);
+    assert_eq!(report.quarantined, 0);
+    let invalid = |report: &Report| {
+      report
+        .problems
+        .iter()
+        .map(|p| (p.file_name.clone(), p.customer_id))
+        .collect::<Vec<(String, u32)>>()
+    };
+    assert_eq!(
+      invalid(&report),
+      [("7".to_string(), 1), ("2".to_string(), 2)]
+    );
+    assert!(customers_dir.join("7").exists());
+
+    let report = verify_tenant(dir.path(), DEFAULT_TENANT, &policy, true).unwrap();
+    assert_eq!(report.quarantined, 2);
+    assert!(!customers_dir.join("7").exists());
+    assert!(!customers_dir.join("2").exists());
+    let quarantine: VecPack<QuarantineEntry> =
+      VecPack::load_or_init(dir.path().join("customers_quarantine")).unwrap();
+    assert_eq!(quarantine.len(), 2);
+    assert_eq!(quarantine.find_id(&2).unwrap().unpack().customer.id, 2);
+    // Storage is clean now
+    let db = load_db(dir.path(), DEFAULT_TENANT).unwrap();
+    assert_eq!(db.len(), 2);
+    let report = verify_tenant(dir.path(), DEFAULT_TENANT, &policy, true).unwrap();
+    assert!(report.problems.is_empty());
+  }
+}
@@ -0,0 +1,5536 @@
+// Copyright (C) 2020 peter
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+mod anomaly;
+mod approval;
+mod audit;
+mod audit_sink;
+mod auth;
+mod cli;
+mod communication;
+mod compression;
+mod country;
+mod custom_field;
+mod customer;
+mod db;
+mod deadline;
+mod display_name;
+mod documents;
+mod edit_lock;
+mod enrichment;
+mod erasure;
+mod error_details;
+mod events;
+#[cfg(any(test, feature = "bench"))]
+pub mod fixture;
+mod grpc_web;
+mod health;
+mod history;
+mod idempotency;
+mod import;
+mod instance_lock;
+mod integrity;
+mod listener;
+mod locale;
+mod merge;
+mod metrics;
+mod migrate;
+mod outbox;
+mod payload;
+mod policy;
+mod prelude;
+mod profile_token;
+pub mod proto;
+mod query;
+mod quota;
+mod replication;
+mod reservation;
+mod rest;
+mod restore;
+mod retention;
+mod sanitize;
+mod search;
+mod seed;
+mod segment;
+mod shipping;
+mod stats;
+mod storage;
+mod tax_guard;
+mod tax_report;
+mod taxnumber;
+mod tenant;
+mod throttle;
+mod user_names;
+mod vat;
+mod warning;
+mod webhook;
+mod zip;
+
+use auth::*;
+use deadline::Deadline;
+use edit_lock::*;
+use idempotency::*;
+use prelude::*;
+use proto::customer::customer_server::*;
+use proto::customer::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use taxnumber::*;
+use tenant::*;
+use tokio::sync::{watch, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{metadata::MetadataMap, transport::Server, Request, Response, Status};
+
+// Customer service
+//
+// Related to manage all customer related
+// tasks. Create, list, update, lookup, etc.
+//
+// Every RPC is scoped to the tenant provided
+// in the request metadata.
+//
+// Important
+// =========
+// As customer has a key role systemwide,
+// we cannot remove a customer object anyway.
+pub struct CustomerService {
+  tenants: Arc<Tenants>,                               // Customers db per tenant
+  zip_db: zip::ZipDb,                                  // Zip code db
+  admin_token: Option<String>,                         // Token required by admin RPCs
+  events: Arc<events::Events>,                         // Customer change events
+  webhooks: Arc<webhook::Webhooks>,                    // Webhook subscriptions
+  tax_guard: Option<tax_guard::TaxNumberGuard>,        // Tax number changes of invoiced customers
+  enrichment: enrichment::Enrichment,                  // Purchase and invoice statistics
+  user_names: user_names::UserNames,                   // Names of referenced users
+  idempotency: Mutex<IdempotencyCache>,                // Recent create_new idempotency keys
+  edit_locks: Mutex<EditLocks>,                        // Customers locked for editing
+  reservations: Mutex<reservation::IdReservations>,    // Customer IDs reserved by offline clients
+  profile_tokens: Mutex<profile_token::ProfileTokens>, // Customer self-service tokens
+  pending_changes: Mutex<approval::PendingChanges>,    // Changes waiting for admin approval
+  custom_fields: Arc<Mutex<custom_field::CustomFields>>, // Custom field definitions
+  segments: Arc<Mutex<segment::Segments>>,             // Saved customer filters
+  erasures: Arc<Mutex<erasure::ErasureRequests>>,      // Erasure requests of customers
+  policy: Arc<policy::PolicyHolder>,                   // Customer validation policy
+  retention: Arc<retention::RetentionPolicy>,          // Data retention rules
+  quota: Arc<quota::QuotaPolicy>,                      // Customer count limits per tenant
+  audit: audit::AuditLog,                              // Audit trail of admin actions
+  history: history::FieldHistory,                      // Field diffs of customer changes
+  communications: communication::CommunicationLog,     // Communications with customers
+  anomalies: Mutex<anomaly::Anomalies>,                // Suspicious changes waiting for review
+  metrics: Arc<metrics::Metrics>,                      // RPC metrics
+  journal: Arc<replication::Journal>,                  // Customer writes for standbys
+  stream_buffer: usize,                                // Stream response channel size
+  started_at: chrono::DateTime<chrono::Utc>,           // Service start date
+}
+
+// Init customer service
+// Load database, load related service clients
+// set alias lookup table and next id
+impl CustomerService {
+  // Init CustomerService
+  #[allow(clippy::too_many_arguments)]
+  fn init(
+    tenants: Arc<Tenants>,                          // Customers db per tenant
+    zip_db: zip::ZipDb,                             // Zip code db
+    admin_token: Option<String>,                    // Token required by admin RPCs
+    events: Arc<events::Events>,                    // Customer change events
+    webhooks: Arc<webhook::Webhooks>,               // Webhook subscriptions
+    tax_guard: Option<tax_guard::TaxNumberGuard>,   // Tax number changes of invoiced customers
+    enrichment: enrichment::Enrichment,             // Purchase and invoice statistics
+    user_names: user_names::UserNames,              // Names of referenced users
+    erasures: Arc<Mutex<erasure::ErasureRequests>>, // Erasure requests of customers
+    custom_fields: Arc<Mutex<custom_field::CustomFields>>, // Custom field definitions
+    segments: Arc<Mutex<segment::Segments>>,        // Saved customer filters
+    idempotency_ttl: Duration,                      // How long idempotency keys are kept
+    policy: Arc<policy::PolicyHolder>,              // Customer validation policy
+    retention: Arc<retention::RetentionPolicy>,     // Data retention rules
+    quota: Arc<quota::QuotaPolicy>,                 // Customer count limits per tenant
+    metrics: Arc<metrics::Metrics>,                 // RPC metrics
+    journal: Arc<replication::Journal>,             // Customer writes for standbys
+    stream_buffer: usize,                           // Stream response channel size
+  ) -> CustomerService {
+    let reservations = reservation::IdReservations::new(tenants.data_dir());
+    let profile_tokens = profile_token::ProfileTokens::new(tenants.data_dir());
+    let pending_changes = approval::PendingChanges::new(tenants.data_dir());
+    let audit = audit::AuditLog::new(tenants.data_dir());
+    let history = history::FieldHistory::new(tenants.data_dir());
+    let communications = communication::CommunicationLog::new(tenants.data_dir());
+    CustomerService {
+      tenants,
+      zip_db,
+      admin_token,
+      events,
+      webhooks,
+      tax_guard,
+      enrichment,
+      user_names,
+      idempotency: Mutex::new(IdempotencyCache::new(idempotency_ttl)),
+      edit_locks: Mutex::new(EditLocks::default()),
+      reservations: Mutex::new(reservations),
+      profile_tokens: Mutex::new(profile_tokens),
+      pending_changes: Mutex::new(pending_changes),
+      custom_fields,
+      segments,
+      erasures,
+      policy,
+      retention,
+      quota,
+      audit,
+      history,
+      communications,
+      anomalies: Mutex::new(anomaly::Anomalies::default()),
+      metrics,
+      journal,
+      stream_buffer,
+      started_at: chrono::Utc::now(),
+    }
+  }
+  // Reject writes on read only instances
+  fn check_writable(&self) -> ServiceResult<()> {
+    db::check_writable(self.tenants.read_only())
+  }
+  // Changes of billing critical fields wait for an admin approval
+  // Without admin token nobody could approve them, so they are rejected
+  fn check_approvable(&self) -> ServiceResult<()> {
+    match self.admin_token {
+      Some(_) => Ok(()),
+      None => Err(ServiceError::permission_denied(
+        "Számlázási adat csak adminisztrátori jóváhagyással módosítható, de nincs admin token beállítva",
+      )),
+    }
+  }
+  // Check admin token in request metadata
+  // If no admin token is set, admin RPCs are disabled
+  fn check_admin(&self, metadata: &MetadataMap) -> ServiceResult<()> {
+    if metadata.get(listener::ADMIN_DENIED_METADATA_KEY).is_some() {
+      return Err(ServiceError::permission_denied(
+        "Adminisztrációs művelet csak az admin porton érhető el",
+      ));
+    }
+    let token = metadata.get("admin-token").and_then(|t| t.to_str().ok());
+    match (&self.admin_token, token) {
+      (Some(admin_token), Some(token)) if admin_token == token => Ok(()),
+      _ => Err(ServiceError::permission_denied(
+        "Ez a művelet csak adminisztrátor számára engedélyezett",
+      )),
+    }
+  }
+  // Check zip and location consistency
+  // Zip db is available only for Hungary
+  fn validate_zip(
+    &self,
+    country: &str,
+    zip: &str,
+    location: &str,
+    location_field: &str,
+  ) -> ServiceResult<()> {
+    // Mismatches are only warnings if zip checks are not strict
+    match country {
+      country::DEFAULT_COUNTRY if self.policy.get().strict_zip => self
+        .zip_db
+        .validate(zip, location)
+        .map_err(|e| e.on_field(location_field)),
+      _ => Ok(()),
+    }
+  }
+  // Customer response with its validation warnings
+  fn with_warnings(&self, customer: customer::Customer, role: Role) -> CustomerObj {
+    let warnings = warning::check(&customer, &self.zip_db, &self.policy.get());
+    CustomerObj {
+      warnings: warnings.into_iter().map(|w| w.into()).collect(),
+      ..customer_to_obj(customer, role)
+    }
+  }
+  // Note fields waiting for approval in the response warnings
+  fn with_pending(&self, res: &mut CustomerObj, pending: &approval::PendingChange) {
+    let msg = translate(
+      "A módosítás adminisztrátori jóváhagyásra vár",
+      current_locale(),
+    );
+    let fields = [
+      ("name", pending.name.is_some()),
+      ("tax_number", pending.tax_number.is_some()),
+      ("invoice_name", pending.invoice_name.is_some()),
+      ("invoice_address", pending.invoice_address.is_some()),
+    ];
+    for (field, _) in fields.iter().filter(|(_, changed)| *changed) {
+      res.warnings.push(ValidationWarning {
+        field: field.to_string(),
+        description: msg.clone(),
+      });
+    }
+  }
+  // Check customer is not locked by an other editor
+  async fn check_edit_lock(
+    &self,
+    tenant: &str,
+    customer_id: u32,
+    editor_uid: u32,
+  ) -> ServiceResult<()> {
+    self
+      .edit_locks
+      .lock()
+      .await
+      .check(tenant, customer_id, editor_uid, chrono::Utc::now())
+  }
+  // Check tax number change of a customer, before is the stored version
+  // Returns true if the change is allowed only because it is forced
+  async fn guard_tax_number(
+    &self,
+    tenant: &str,
+    before: &customer::Customer,
+    after: &customer::Customer,
+    force: bool,
+  ) -> ServiceResult<bool> {
+    match &self.tax_guard {
+      Some(guard) => guard.check(tenant, before, after, force).await,
+      None => Ok(false),
+    }
+  }
+  // Check the customer is still the version a change was
+  // planned on, while the customers were not locked
+  fn check_unchanged(customers: &db::CustomerDb, before: &customer::Customer) -> ServiceResult<()> {
+    match restore::changed_fields(customers.find_id(&before.id)?, before)?.is_empty() {
+      true => Ok(()),
+      false => Err(ServiceError::failed_precondition(
+        "Az ügyfelet időközben módosították, kérjük próbálja újra",
+      )),
+    }
+  }
+  // Log forced tax number change to the audit trail
+  fn audit_tax_number_change(
+    &self,
+    tenant: &str,
+    customer_id: u32,
+    actor: &str,
+  ) -> ServiceResult<()> {
+    self.audit.append(
+      tenant,
+      &audit::AuditEntry {
+        date: chrono::Utc::now(),
+        customer_id,
+        action: audit::AuditAction::ForceTaxNumberChange,
+        actor: actor.to_string(),
+      },
+    )
+  }
+  // Sort result IDs if sorting is requested
+  fn sort_ids(
+    customers: &db::CustomerDb,
+    ids: Vec<u32>,
+    sort_by: Option<db::SortBy>,
+    descending: bool,
+  ) -> Vec<u32> {
+    match sort_by {
+      Some(sort_by) => customers.sort_ids(ids, sort_by, descending),
+      None => ids,
+    }
+  }
+  // Check the customer does not conflict with an other one
+  // on the fields the policy requires to be unique
+  fn check_unique(
+    &self,
+    customers: &db::CustomerDb,
+    customer: &customer::Customer,
+  ) -> ServiceResult<()> {
+    let policy = self.policy.get();
+    if policy.unique_email {
+      if let Some(customer_id) = customers.email_conflict(customer) {
+        return Err(ServiceError::conflict(
+          "Ez az email cím már egy másik ügyfélhez tartozik",
+          customer_id,
+        ));
+      }
+    }
+    if policy.unique_tax_number {
+      if let Some(customer_id) = customers.tax_number_conflict(customer) {
+        return Err(ServiceError::conflict(
+          "Ez az adószám már egy másik ügyfélhez tartozik",
+          customer_id,
+        ));
+      }
+    }
+    Ok(())
+  }
+  // Get next customer ID
+  fn next_customer_id(customers: &db::CustomerDb) -> u32 {
+    let mut latest_id: u32 = 0;
+    customers.iter().for_each(|customer| {
+      let id: u32 = customer.id;
+      if id > latest_id {
+        latest_id = id;
+      }
+    });
+    latest_id + 1
+  }
+  // Reserve customer IDs for an offline client
+  async fn reserve_customer_ids(
+    &self,
+    tenant: &str,
+    r: ReserveCustomerIdsRequest,
+  ) -> ServiceResult<reservation::IdReservation> {
+    self.check_writable()?;
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let next_id = Self::next_customer_id(&customers);
+    self.reservations.lock().await.reserve(
+      tenant,
+      next_id,
+      r.count,
+      r.ttl_secs,
+      r.reserved_by,
+      chrono::Utc::now(),
+    )
+  }
+  // Create new customer
+  async fn create_new(
+    &self,
+    tenant: &str,
+    idempotency_key: Option<String>,
+    u: NewCustomerObj,
+  ) -> ServiceResult<customer::Customer> {
+    // Check taxnumber
+    let taxnumber = match u.tax_number.len() {
+      x if x > 0 => Some(
+        TaxNumber::new(&u.tax_number).map_err(|e| ServiceError::from(e).on_field("tax_number"))?,
+      ),
+      _ => None,
+    };
+    // Check kind
+    // If not specified, customers with tax number are considered as companies
+    let kind = match customer_kind_from_proto(u.kind).map_err(|e| e.on_field("kind"))? {
+      Some(kind) => kind,
+      None => match taxnumber {
+        Some(_) => customer::CustomerKind::Company,
+        None => customer::CustomerKind::Private,
+      },
+    };
+    let preferred_contact = preferred_contact_from_proto(u.preferred_contact)
+      .map_err(|e| e.on_field("preferred_contact"))?;
+    // Check country, and zip and location consistency
+    let country = country::normalize_country(&u.country).map_err(|e| e.on_field("country"))?;
+    self.validate_zip(
+      &country,
+      &u.address_zip,
+      &u.address_location,
+      "address_location",
+    )?;
+    self
+      .insert_new(tenant, idempotency_key, u.customer_id, |id| {
+        customer::Customer::new(
+          id,
+          u.name,
+          u.email,
+          u.phone,
+          taxnumber,
+          u.address_zip,
+          u.address_location,
+          u.address_street,
+          country,
+          loyalty_card_id_from_proto(&u.loyalty_card_id),
+          u.created_by,
+          kind,
+          u.invoiceable,
+          preferred_contact,
+          &self.policy.get(),
+        )
+      })
+      .await
+  }
+  // Create customer with name and phone only
+  // The customer is flagged as incomplete profile,
+  // until it is updated with the full details
+  async fn quick_create(
+    &self,
+    tenant: &str,
+    idempotency_key: Option<String>,
+    r: QuickCreateRequest,
+  ) -> ServiceResult<customer::Customer> {
+    self
+      .insert_new(tenant, idempotency_key, r.customer_id, |id| {
+        customer::Customer::new_quick(id, r.name, r.phone, r.created_by, &self.policy.get())
+      })
+      .await
+  }
+  // Store new customer built with its new ID
+  // reserved_id is used if set, otherwise a new ID is allocated.
+  // If the idempotency key was used recently, returns the customer
+  // created by that request instead of creating a new one
+  async fn insert_new<F>(
+    &self,
+    tenant: &str,
+    idempotency_key: Option<String>,
+    reserved_id: u32,
+    new: F,
+  ) -> ServiceResult<customer::Customer>
+  where
+    F: FnOnce(u32) -> ServiceResult<customer::Customer>,
+  {
+    // Lock tenant customers
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    // Check idempotency key
+    if let Some(key) = &idempotency_key {
+      if let Some(customer_id) = self.idempotency.lock().await.get(tenant, key) {
+        return Ok(customers.find_id(&customer_id)?.clone());
+      }
+    }
+    // Get the next customer ID, or check the reserved one
+    self.check_writable()?;
+    self.quota.check_new(tenant, customers.len())?;
+    let now = chrono::Utc::now();
+    let mut reservations = self.reservations.lock().await;
+    let next_customer_id = match reserved_id {
+      0 => Self::next_customer_id(&customers).max(reservations.next_free_id(tenant, now)?),
+      id => {
+        reservations.check_reserved(tenant, id, now)?;
+        if !customers.check_id_available(&id) {
+          return Err(ServiceError::conflict(
+            "A foglalt ügyfél ID már használatban van",
+            id,
+          ));
+        }
+        id
+      }
+    };
+    drop(reservations);
+
+    // Create customer object
+    let new_customer = new(next_customer_id)?;
+
+    // Check unique fields
+    self.check_unique(&customers, &new_customer)?;
+
+    // Store new customer into storage
+    customers.insert(new_customer.clone())?;
+    self.alert_quota(tenant, customers.len());
+
+    // Store idempotency key
+    if let Some(key) = &idempotency_key {
+      self
+        .idempotency
+        .lock()
+        .await
+        .insert(tenant, key, new_customer.id);
+    }
+
+    // Returns the new customer
+    Ok(new_customer)
+  }
+  // Log when a new customer reaches the warning level
+  // or the limit of the tenant quota
+  fn alert_quota(&self, tenant: &str, customer_count: usize) {
+    let usage = self.quota.usage(tenant, customer_count);
+    if let Some(limit) = usage.limit {
+      if usage.warn_at() == Some(customer_count) || limit == customer_count {
+        eprintln!(
+          "Tenant '{}' uses {}% of its customer quota ({} / {})",
+          tenant,
+          usage.used_percent(),
+          customer_count,
+          limit
+        );
+      }
+    }
+  }
+  // Customer count of the tenant compared to its quota
+  async fn quota_usage(&self, tenant: &str) -> ServiceResult<quota::QuotaUsage> {
+    let customers = self.tenants.get(tenant).await?;
+    let customer_count = customers.lock().await.len();
+    Ok(self.quota.usage(tenant, customer_count))
+  }
+  // Version, uptime, startup progress and storage stats
+  async fn server_info(&self) -> ServiceResult<ServerInfo> {
+    let warm_up = self.tenants.warm_up_state();
+    let mut tenants = Vec::new();
+    for (tenant, pack) in self.tenants.loaded().await {
+      let (customer_count, shadow_divergences) = {
+        let db = pack.lock().await;
+        (db.len() as u32, db.shadow_divergences().unwrap_or(0))
+      };
+      let progress = warm_up.tenants.iter().find(|t| t.tenant == tenant);
+      tenants.push(TenantInfo {
+        customer_count,
+        storage_bytes: self.tenants.storage_bytes(&tenant)?,
+        load_ms: progress
+          .map(|p| p.load_time.as_millis() as u64)
+          .unwrap_or(0),
+        index_ms: progress
+          .map(|p| p.index_time.as_millis() as u64)
+          .unwrap_or(0),
+        shadow_divergences,
+        tenant,
+      });
+    }
+    Ok(ServerInfo {
+      version: env!("CARGO_PKG_VERSION").to_string(),
+      started_at: self.started_at.to_rfc3339(),
+      uptime_secs: (chrono::Utc::now() - self.started_at).num_seconds().max(0) as u64,
+      ready: warm_up.ready,
+      tenants_total: warm_up.tenants_total as u32,
+      tenants_loaded: warm_up.tenants.len() as u32,
+      storage_backend: self.tenants.backend().name().to_string(),
+      read_only: self.tenants.read_only(),
+      customer_count: tenants.iter().map(|t| t.customer_count).sum(),
+      tenants,
+      shadow_backend: self
+        .tenants
+        .shadow()
+        .map(|b| b.name().to_string())
+        .unwrap_or_default(),
+    })
+  }
+  // New customer response with its validation and quota warnings
+  async fn with_quota_warning(
+    &self,
+    tenant: &str,
+    customer: customer::Customer,
+    role: Role,
+  ) -> ServiceResult<CustomerObj> {
+    let mut res = self.with_warnings(customer, role);
+    if let Some(warning) = self.quota_usage(tenant).await?.warning() {
+      res.warnings.push(warning.into());
+    }
+    Ok(res)
+  }
+  // Get all customer IDs
+  async fn get_all(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    r: GetAllRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let kind = customer_kind_from_proto(r.kind)?;
+    let sort_by = sort_by_from_proto(r.sort_by).map_err(|e| e.on_field("sort_by"))?;
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut res = Vec::new();
+    for (i, c) in customers.iter().enumerate() {
+      deadline.check_at(i)?;
+      if kind.is_none_or(|kind| c.kind == kind) {
+        res.push(c.id);
+      }
+    }
+    Ok(Self::sort_ids(&customers, res, sort_by, r.descending))
+  }
+  // Get IDs of customers with incomplete profile
+  async fn get_incomplete_profiles(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    r: GetIncompleteProfilesRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let sort_by = sort_by_from_proto(r.sort_by).map_err(|e| e.on_field("sort_by"))?;
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut res = Vec::new();
+    for (i, c) in customers.iter().enumerate() {
+      deadline.check_at(i)?;
+      if c.incomplete_profile {
+        res.push(c.id);
+      }
+    }
+    Ok(Self::sort_ids(&customers, res, sort_by, r.descending))
+  }
+  // Get customer by ID
+  async fn get_by_id(&self, tenant: &str, r: GetByIdRequest) -> ServiceResult<customer::Customer> {
+    let res = self
+      .tenants
+      .get(tenant)
+      .await?
+      .lock()
+      .await
+      .find_id(&r.customer_id)?
+      .clone();
+    Ok(res)
+  }
+  // Names of the creators for the expanded view
+  async fn created_by_names(
+    &self,
+    tenant: &str,
+    expanded: bool,
+    uids: &[u32],
+  ) -> HashMap<u32, String> {
+    match expanded {
+      true => self.user_names.names(tenant, uids).await,
+      false => HashMap::new(),
+    }
+  }
+  // Get the fields missing to issue an invoice
+  async fn get_invoice_readiness(
+    &self,
+    tenant: &str,
+    r: GetByIdRequest,
+  ) -> ServiceResult<InvoiceReadiness> {
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let customer = customers.find_id(&r.customer_id)?;
+    let missing_fields = customer.invoice_missing_fields();
+    Ok(InvoiceReadiness {
+      customer_id: customer.id,
+      invoiceable: customer.invoiceable,
+      ready: missing_fields.is_empty(),
+      missing_fields: missing_fields.iter().map(|f| f.to_string()).collect(),
+    })
+  }
+  // Set VAT treatment of a customer for a date range
+  async fn set_vat_status(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: SetVatStatusRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let treatment = vat_treatment_from_proto(r.treatment)?;
+    let valid_from = parse_date_opt(&r.valid_from)
+      .map_err(|e| e.on_field("valid_from"))?
+      .ok_or_else(|| ServiceError::invalid_field("valid_from", "Az időszak kezdete kötelező"))?
+      .date_naive();
+    let valid_until = parse_date_opt(&r.valid_until)
+      .map_err(|e| e.on_field("valid_until"))?
+      .map(|date| date.date_naive());
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    self
+      .check_edit_lock(tenant, r.customer_id, editor_uid)
+      .await?;
+    let res = customers.update(&r.customer_id, editor_uid, |customer| {
+      Ok(
+        customer
+          .set_vat_status(treatment, valid_from, valid_until)?
+          .clone(),
+      )
+    })?;
+    Ok(res)
+  }
+  // Set default discount of a customer
+  async fn set_discount(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: SetDiscountRequest,
+  ) -> ServiceResult<customer::Customer> {
+    if r.set_by == 0 {
+      return Err(ServiceError::invalid_field(
+        "set_by",
+        "A kedvezményt beállító felhasználó megadása kötelező",
+      ));
+    }
+    // The editor holding the lock cannot record the change as an other user
+    if editor_uid != 0 && r.set_by != editor_uid {
+      return Err(ServiceError::permission_denied(
+        "A kedvezményt csak a szerkesztő felhasználó nevében lehet beállítani",
+      ));
+    }
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    self
+      .check_edit_lock(tenant, r.customer_id, editor_uid)
+      .await?;
+    let res = customers.update(&r.customer_id, r.set_by, |customer| {
+      Ok(
+        customer
+          .set_discount(r.default_discount_percent, r.set_by, chrono::Utc::now())?
+          .clone(),
+      )
+    })?;
+    Ok(res)
+  }
+  // VAT treatment of a customer on a date
+  async fn get_vat_status(
+    &self,
+    tenant: &str,
+    r: GetVatStatusRequest,
+  ) -> ServiceResult<VatStatusObj> {
+    let date = parse_date_opt(&r.date)
+      .map_err(|e| e.on_field("date"))?
+      .unwrap_or_else(chrono::Utc::now)
+      .date_naive();
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let customer = customers.find_id(&r.customer_id)?;
+    let status = vat::status_at(&customer.vat_periods, date);
+    Ok(VatStatusObj {
+      customer_id: customer.id,
+      treatment: status
+        .treatment
+        .map_or(VatTreatment::VatNormal, VatTreatment::from) as i32,
+      valid_from: vat_date_to_proto(status.valid_from),
+      valid_until: vat_date_to_proto(status.valid_until),
+    })
+  }
+  // Get active customers below the completeness threshold
+  async fn get_incomplete(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    r: GetIncompleteRequest,
+  ) -> ServiceResult<Vec<IncompleteCustomerObj>> {
+    let threshold = match r.threshold {
+      0 => 100,
+      x if x > 100 => {
+        return Err(ServiceError::invalid_field(
+          "threshold",
+          "A küszöbérték 1 és 100 között lehet",
+        ))
+      }
+      x => x,
+    };
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut res = Vec::new();
+    for (i, c) in customers.iter().enumerate() {
+      deadline.check_at(i)?;
+      let completeness_score = c.completeness_score();
+      if c.active && completeness_score < threshold {
+        res.push(IncompleteCustomerObj {
+          customer_id: c.id,
+          completeness_score,
+          missing_fields: c
+            .completeness_missing_fields()
+            .iter()
+            .map(|f| f.to_string())
+            .collect(),
+        });
+      }
+    }
+    res.sort_by_key(|c| (c.completeness_score, c.customer_id));
+    Ok(res)
+  }
+  // Get a page of customers with tax data issues
+  async fn get_tax_data_issues(
+    &self,
+    tenant: &str,
+    role: Role,
+    deadline: &Deadline,
+    r: GetTaxDataIssuesRequest,
+  ) -> ServiceResult<TaxDataIssueReport> {
+    let limit = match r.limit {
+      0 => tax_report::DEFAULT_PAGE_SIZE,
+      x => (x as usize).min(tax_report::MAX_PAGE_SIZE),
+    };
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    // Duplicates are only issues if tax numbers must be unique
+    let shared = match self.policy.get().unique_tax_number {
+      true => tax_report::shared_tax_numbers(customers.iter()),
+      false => HashSet::new(),
+    };
+    let mut found = Vec::new();
+    for (i, c) in customers.iter().enumerate() {
+      deadline.check_at(i)?;
+      let issues = tax_report::issues(c, &shared);
+      if !issues.is_empty() {
+        found.push(tax_report::CustomerIssues {
+          customer_id: c.id,
+          issues,
+        });
+      }
+    }
+    let page = tax_report::page(found, r.after_customer_id, limit);
+    let mut res = Vec::new();
+    for c in page.customers {
+      let customer = customers.find_id(&c.customer_id)?;
+      res.push(tax_data_issues_to_obj(customer, c.issues, role));
+    }
+    Ok(TaxDataIssueReport {
+      customers: res,
+      total: page.total as u32,
+      next_after_customer_id: page.next_after_id,
+    })
+  }
+  // Get customer with its purchase and invoice statistics
+  // The tenant is not locked while the services are asked
+  async fn get_customer_with_stats(
+    &self,
+    tenant: &str,
+    role: Role,
+    r: GetByIdRequest,
+  ) -> ServiceResult<CustomerWithStats> {
+    let expanded = r.expanded;
+    let customer = self.get_by_id(tenant, r).await?;
+    let creators = [customer.created_by];
+    let (stats, names) = tokio::join!(
+      self.enrichment.stats(tenant, &customer),
+      self.created_by_names(tenant, expanded, &creators)
+    );
+    let rfc3339 = |date: Option<chrono::DateTime<chrono::Utc>>| {
+      date.map(|d| d.to_rfc3339()).unwrap_or_default()
+    };
+    Ok(CustomerWithStats {
+      customer: Some(with_created_by_name(
+        customer_to_obj(customer, role),
+        &names,
+      )),
+      purchase_count: stats.purchase_count,
+      lifetime_spend: stats.lifetime_spend,
+      last_purchase_at: rfc3339(stats.last_purchase_at),
+      invoice_count: stats.invoice_count,
+      last_invoice_at: rfc3339(stats.last_invoice_at),
+      unavailable: stats.unavailable.iter().map(|s| s.to_string()).collect(),
+    })
+  }
+  // Get customer by loyalty card ID
+  async fn get_by_loyalty_card(
+    &self,
+    tenant: &str,
+    r: GetByLoyaltyCardRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    match customers.find_loyalty_card(r.loyalty_card_id.trim()) {
+      Some(customer_id) => Ok(customers.find_id(&customer_id)?.clone()),
+      None => Err(ServiceError::not_found(
+        "Nem található ügyfél ezzel a hűségkártyával",
+      )),
+    }
+  }
+  // Stream customers in bulk
+  // Customers are read from storage in chunks, and the storage
+  // is locked only while a chunk is read. Stops when the client
+  // disconnects or its deadline is exceeded.
+  async fn send_bulk(
+    customers: CustomerPack,
+    r: GetBulkRequest,
+    role: Role,
+    deadline: Deadline,
+    tx: tokio::sync::mpsc::Sender<Result<CustomerObj, Status>>,
+  ) {
+    let chunk_size = match r.chunk_size {
+      0 => 100,
+      x => (x as usize).min(1000),
+    };
+    let ids = r.customer_ids.into_iter().collect::<BTreeSet<u32>>();
+    let mut position = None;
+    loop {
+      if let Err(error) = deadline.check() {
+        let _ = tx.send(Err(error.into())).await;
+        return;
+      }
+      let (chunk, next) = customers
+        .lock()
+        .await
+        .chunk_from(position, &ids, chunk_size);
+      if chunk.is_empty() {
+        break;
+      }
+      position = next;
+      for customer in chunk {
+        if tx.send(Ok(customer_to_obj(customer, role))).await.is_err() {
+          return;
+        }
+      }
+    }
+  }
+  // Stream summaries of customers in the given order
+  // Customers are read in chunks, so writes are not blocked for the
+  // whole stream. Customers removed in the meantime are skipped.
+  async fn send_summaries(
+    customers: CustomerPack,
+    ids: Vec<u32>,
+    role: Role,
+    deadline: Deadline,
+    tx: tokio::sync::mpsc::Sender<Result<CustomerSummary, Status>>,
+  ) {
+    const SUMMARY_CHUNK: usize = 1000;
+    for ids in ids.chunks(SUMMARY_CHUNK) {
+      if let Err(error) = deadline.check() {
+        let _ = tx.send(Err(error.into())).await;
+        return;
+      }
+      let chunk = {
+        let customers = customers.lock().await;
+        ids
+          .iter()
+          .filter_map(|id| customers.find_id(id).ok())
+          .map(|c| customer_to_summary(c, role))
+          .collect::<Vec<CustomerSummary>>()
+      };
+      for summary in chunk {
+        if tx.send(Ok(summary)).await.is_err() {
+          return;
+        }
+      }
+    }
+  }
+  // Update customer by ID
+  async fn update_by_id(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    admin: bool,
+    r: CustomerObj,
+  ) -> ServiceResult<(customer::Customer, Option<approval::PendingChange>)> {
+    let force_tax_number_change = r.force_tax_number_change;
+    // Check taxnumber
+    let taxnumber = match r.tax_number.len() {
+      x if x > 0 => Some(
+        TaxNumber::new(&r.tax_number).map_err(|e| ServiceError::from(e).on_field("tax_number"))?,
+      ),
+      _ => None,
+    };
+    let kind = customer_kind_from_proto(r.kind).map_err(|e| e.on_field("kind"))?;
+    let preferred_contact = preferred_contact_from_proto(r.preferred_contact)
+      .map_err(|e| e.on_field("preferred_contact"))?;
+    let country = match r.country.trim() {
+      "" => None,
+      x => Some(country::normalize_country(x).map_err(|e| e.on_field("country"))?),
+    };
+    let customers = self.tenants.get(tenant).await?;
+    let customer_id = r.id;
+    let before = customers.lock().await.find_id(&customer_id)?.clone();
+    // If kind is not specified, keep the current one
+    let kind = kind.unwrap_or(before.kind);
+    // Check zip and location consistency
+    self.validate_zip(
+      country.as_deref().unwrap_or(&before.country),
+      &r.address_zip,
+      &r.address_location,
+      "address_location",
+    )?;
+    // Update a copy first, to check unique fields
+    let mut updated = before.clone();
+    updated.update(
+      r.name,
+      r.email,
+      r.phone,
+      taxnumber,
+      r.address_zip,
+      r.address_location,
+      r.address_street,
+      country,
+      loyalty_card_id_from_proto(&r.loyalty_card_id),
+      kind,
+      r.invoiceable,
+      preferred_contact,
+      &self.policy.get(),
+    )?;
+    // Tax number and legal name changes of non-admins wait for approval
+    let sensitive = match admin {
+      true => None,
+      false => approval::split_sensitive(&before, &mut updated),
+    };
+    if sensitive.is_some() {
+      self.check_approvable()?;
+      updated.validate_update(&before, &self.policy.get())?;
+    }
+    // The invoice service is called without the customers locked
+    let forced = self
+      .guard_tax_number(tenant, &before, &updated, force_tax_number_change)
+      .await?;
+    let mut customers = customers.lock().await;
+    self
+      .check_edit_lock(tenant, customer_id, editor_uid)
+      .await?;
+    Self::check_unchanged(&customers, &before)?;
+    self.check_unique(&customers, &updated)?;
+    // Request approval first, it is removed if the update is not stored
+    let pending = match sensitive {
+      Some(sensitive) => Some(self.pending_changes.lock().await.request(
+        tenant,
+        customer_id,
+        editor_uid,
+        sensitive,
+        chrono::Utc::now(),
+      )?),
+      None => None,
+    };
+    // Update customer
+    let res = match customers.update(&customer_id, editor_uid, |customer| {
+      *customer = updated;
+      Ok(customer.clone())
+    }) {
+      Ok(res) => res,
+      Err(error) => {
+        if let Some(pending) = &pending {
+          if let Err(e) = self.pending_changes.lock().await.remove(tenant, pending.id) {
+            eprintln!(
+              "Error while removing pending change {} of customer {}: {}",
+              pending.id, customer_id, e
+            );
+          }
+        }
+        return Err(error);
+      }
+    };
+    if forced {
+      self.audit_tax_number_change(tenant, customer_id, &format!("admin:{}", editor_uid))?;
+    }
+    // Queue alert if the change looks suspicious
+    self
+      .anomalies
+      .lock()
+      .await
+      .observe(tenant, editor_uid, &before, &res, chrono::Utc::now());
+    Ok((res, pending))
+  }
+  // Generate self-service token for a customer
+  async fn generate_profile_token(
+    &self,
+    tenant: &str,
+    r: GenerateProfileTokenRequest,
+  ) -> ServiceResult<ProfileTokenObj> {
+    self.check_writable()?;
+    // Check customer exists
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    customers.find_id(&r.customer_id)?;
+    let (token, profile_token) = self.profile_tokens.lock().await.generate(
+      tenant,
+      r.customer_id,
+      r.ttl_secs,
+      r.created_by,
+      chrono::Utc::now(),
+    )?;
+    Ok(ProfileTokenObj {
+      token,
+      expires_at: profile_token.expires_at.to_rfc3339(),
+    })
+  }
+  // Get customer by self-service token
+  async fn get_by_profile_token(
+    &self,
+    tenant: &str,
+    token: &str,
+  ) -> ServiceResult<customer::Customer> {
+    let customer_id =
+      self
+        .profile_tokens
+        .lock()
+        .await
+        .resolve(tenant, token, chrono::Utc::now())?;
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    Ok(customers.find_id(&customer_id)?.clone())
+  }
+  // Update customer details by self-service token
+  // Self-service changes have no editor
+  async fn update_by_profile_token(
+    &self,
+    tenant: &str,
+    r: UpdateProfileRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let preferred_contact = preferred_contact_from_proto(r.preferred_contact)
+      .map_err(|e| e.on_field("preferred_contact"))?;
+    let customer_id =
+      self
+        .profile_tokens
+        .lock()
+        .await
+        .resolve(tenant, &r.token, chrono::Utc::now())?;
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    self.check_edit_lock(tenant, customer_id, 0).await?;
+    let before = customers.find_id(&customer_id)?.clone();
+    // Check zip and location consistency
+    self.validate_zip(
+      &before.country,
+      &r.address_zip,
+      &r.address_location,
+      "address_location",
+    )?;
+    // Update a copy first, to check unique fields
+    let mut updated = before.clone();
+    updated.update_profile(
+      r.email,
+      r.phone,
+      r.address_zip,
+      r.address_location,
+      r.address_street,
+      preferred_contact,
+      &self.policy.get(),
+    )?;
+    self.check_unique(&customers, &updated)?;
+    let res = customers.update(&customer_id, 0, |customer| {
+      *customer = updated;
+      Ok(customer.clone())
+    })?;
+    // Queue alert if the change looks suspicious
+    self
+      .anomalies
+      .lock()
+      .await
+      .observe(tenant, 0, &before, &res, chrono::Utc::now());
+    Ok(res)
+  }
+  // Apply or drop pending change
+  // Decisions are logged to the audit trail
+  async fn decide_change(
+    &self,
+    tenant: &str,
+    r: DecideChangeRequest,
+    approve: bool,
+  ) -> ServiceResult<(approval::PendingChange, Option<customer::Customer>)> {
+    self.check_writable()?;
+    let customers = self.tenants.get(tenant).await?;
+    let change = self.pending_changes.lock().await.get(tenant, r.change_id)?;
+    let planned = match approve {
+      true => {
+        // Update a copy first, to check unique fields
+        let before = customers.lock().await.find_id(&change.customer_id)?.clone();
+        let mut updated = before.clone();
+        change.apply(&mut updated);
+        updated.validate_update(&before, &self.policy.get())?;
+        // The invoice service is called without the customers locked
+        let forced = self
+          .guard_tax_number(tenant, &before, &updated, r.force_tax_number_change)
+          .await?;
+        Some((before, updated, forced))
+      }
+      false => None,
+    };
+    let mut customers = customers.lock().await;
+    let mut pending_changes = self.pending_changes.lock().await;
+    // The change may be decided or replaced meanwhile
+    let change = pending_changes.get(tenant, change.id)?;
+    let res = match planned {
+      Some((before, updated, forced)) => {
+        Self::check_unchanged(&customers, &before)?;
+        self.check_unique(&customers, &updated)?;
+        let res = customers.update(&change.customer_id, r.decided_by, |customer| {
+          *customer = updated;
+          Ok(customer.clone())
+        })?;
+        if forced {
+          self.audit_tax_number_change(
+            tenant,
+            change.customer_id,
+            &format!("admin:{}", r.decided_by),
+          )?;
+        }
+        Some(res)
+      }
+      None => None,
+    };
+    pending_changes.remove(tenant, change.id)?;
+    self.audit.append(
+      tenant,
+      &audit::AuditEntry {
+        date: chrono::Utc::now(),
+        customer_id: change.customer_id,
+        action: match approve {
+          true => audit::AuditAction::ApproveChange,
+          false => audit::AuditAction::RejectChange,
+        },
+        actor: format!("admin:{}", r.decided_by),
+      },
+    )?;
+    Ok((change, res))
+  }
+  // Find customers by query
+  async fn find_customer(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    r: FindCustomerRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let kind = customer_kind_from_proto(r.kind)?;
+    let sort_by = sort_by_from_proto(r.sort_by).map_err(|e| e.on_field("sort_by"))?;
+    let limit = match r.max_results {
+      0 => usize::MAX,
+      x => x as usize,
+    };
+    let query = search::TextQuery::new(&r.query);
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut res = Vec::new();
+    for (i, c) in customers.iter().enumerate() {
+      deadline.check_at(i)?;
+      // Sorted results are the first ones of every match
+      if sort_by.is_none() && res.len() >= limit {
+        break;
+      }
+      if kind.is_none_or(|kind| c.kind == kind) && c.matches_query(&query) {
+        res.push(c.id);
+      }
+    }
+    let mut res = Self::sort_ids(&customers, res, sort_by, r.descending);
+    res.truncate(limit);
+    Ok(res)
+  }
+  // Send IDs of the matching customers as they are found
+  // Storage is scanned in chunks, so writes are not blocked
+  // for the whole scan, and it stops at max_results
+  async fn send_found(
+    customers: CustomerPack,
+    r: FindCustomerRequest,
+    kind: Option<customer::CustomerKind>,
+    deadline: Deadline,
+    tx: tokio::sync::mpsc::Sender<Result<FoundCustomer, Status>>,
+  ) {
+    const SCAN_CHUNK: usize = 1000;
+    let mut left = match r.max_results {
+      0 => usize::MAX,
+      x => x as usize,
+    };
+    let query = search::TextQuery::new(&r.query);
+    let mut position = None;
+    while left > 0 {
+      if let Err(error) = deadline.check() {
+        let _ = tx.send(Err(error.into())).await;
+        return;
+      }
+      let (ids, next) = customers
+        .lock()
+        .await
+        .scan_from(position, SCAN_CHUNK, left, |c| {
+          kind.is_none_or(|kind| c.kind == kind) && c.matches_query(&query)
+        });
+      if next == position {
+        break;
+      }
+      position = next;
+      left -= ids.len();
+      for customer_id in ids {
+        if tx.send(Ok(FoundCustomer { customer_id })).await.is_err() {
+          return;
+        }
+      }
+    }
+  }
+  // Find customers by the start of their name
+  async fn find_by_name_prefix(
+    &self,
+    tenant: &str,
+    r: FindByNamePrefixRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    if search::normalize(&r.prefix).is_empty() {
+      return Err(ServiceError::invalid_field(
+        "prefix",
+        "A névkezdet megadása kötelező",
+      ));
+    }
+    let kind = customer_kind_from_proto(r.kind)?;
+    let limit = match r.limit {
+      0 => usize::MAX,
+      x => x as usize,
+    };
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    Ok(
+      customers
+        .name_prefix(&r.prefix)
+        .into_iter()
+        .filter(|id| kind.is_none_or(|kind| customers.find_id(id).is_ok_and(|c| c.kind == kind)))
+        .take(limit)
+        .collect(),
+    )
+  }
+  // Find customers by address parts, e.g. for delivery routes
+  async fn find_by_address(
+    &self,
+    tenant: &str,
+    role: Role,
+    r: FindByAddressRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let filter = db::AddressFilter {
+      zip_prefix: r.zip_prefix,
+      location: r.location,
+      street: r.street,
+    };
+    if filter.is_empty() {
+      return Err(ServiceError::bad_request(
+        "Legalább egy címrész megadása kötelező",
+      ));
+    }
+    // Streets are truncated for some roles
+    let street = policy::Field::AddressStreet;
+    if !search::fold(&filter.street).is_empty() && visibility(role, street) != Visibility::Full {
+      return Err(ServiceError::permission_denied(&format!(
+        "A(z) {} mezőre nem lehet szűrni",
+        street.display_name()
+      )));
+    }
+    let limit = match r.limit {
+      0 => usize::MAX,
+      x => x as usize,
+    };
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut res = customers.find_by_address(&filter);
+    res.truncate(limit);
+    Ok(res)
+  }
+  // Find customers by filter expression
+  async fn query_customers(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    role: Role,
+    r: QueryCustomersRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let custom_fields = self.custom_fields.lock().await.list(tenant)?;
+    let query = query::Query::parse(&r.query, &custom_fields).map_err(|e| e.on_field("query"))?;
+    query.check_role(role)?;
+    let sort_by = sort_by_from_proto(r.sort_by).map_err(|e| e.on_field("sort_by"))?;
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut res = Vec::new();
+    for (i, c) in customers.iter().enumerate() {
+      deadline.check_at(i)?;
+      if query.matches(c) {
+        res.push(c.id);
+      }
+    }
+    Ok(Self::sort_ids(&customers, res, sort_by, r.descending))
+  }
+  // Save filter expression as a segment
+  // The query is checked against the role of the creator
+  async fn create_segment(
+    &self,
+    tenant: &str,
+    role: Role,
+    r: CreateSegmentRequest,
+  ) -> ServiceResult<segment::Segment> {
+    self.check_writable()?;
+    let custom_fields = self.custom_fields.lock().await.list(tenant)?;
+    let query = query::Query::parse(&r.query, &custom_fields).map_err(|e| e.on_field("query"))?;
+    query.check_role(role)?;
+    self.segments.lock().await.create(
+      tenant,
+      &r.name,
+      &r.description,
+      &r.query,
+      r.created_by,
+      chrono::Utc::now(),
+    )
+  }
+  // Customers matching the segment filter now,
+  // or at the last scheduled evaluation if cached is set
+  async fn segment_members(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    role: Role,
+    r: GetSegmentMembersRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let (segment, membership) = {
+      let mut segments = self.segments.lock().await;
+      let segment = segments.get(tenant, r.segment_id)?;
+      (segment, segments.membership(tenant, r.segment_id)?)
+    };
+    if r.cached {
+      let membership = membership.ok_or_else(|| {
+        ServiceError::failed_precondition("A szegmens tagjai még nincsenek kiértékelve")
+      })?;
+      // Same role rules as evaluating the filter
+      let custom_fields = self.custom_fields.lock().await.list(tenant)?;
+      query::Query::parse(&segment.query, &custom_fields)?.check_role(role)?;
+      let sort_by = sort_by_from_proto(r.sort_by).map_err(|e| e.on_field("sort_by"))?;
+      let customers = self.tenants.get(tenant).await?;
+      let customers = customers.lock().await;
+      return Ok(Self::sort_ids(
+        &customers,
+        membership.members,
+        sort_by,
+        r.descending,
+      ));
+    }
+    self
+      .query_customers(
+        tenant,
+        deadline,
+        role,
+        QueryCustomersRequest {
+          query: segment.query,
+          sort_by: r.sort_by,
+          descending: r.descending,
+        },
+      )
+      .await
+  }
+  // Resolve names to best match customer candidates
+  async fn resolve_names(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    r: ResolveNamesRequest,
+  ) -> ServiceResult<Vec<ResolvedName>> {
+    if r.names.len() > 1000 {
+      return Err(ServiceError::bad_request(
+        "Egy kérésben max 1000 név adható meg",
+      ));
+    }
+    let max_candidates = match r.max_candidates {
+      0 => 3,
+      x => x as usize,
+    };
+    // Normalize customer names only once
+    let customers = self
+      .tenants
+      .get(tenant)
+      .await?
+      .lock()
+      .await
+      .iter()
+      .map(|c| (c.id, (c.name.clone(), search::normalize(&c.name))))
+      .collect::<HashMap<u32, (String, String)>>();
+    let mut res = Vec::new();
+    for name in r.names {
+      deadline.check()?;
+      let candidates = search::best_matches(
+        &name,
+        customers
+          .iter()
+          .map(|(id, (_, normalized))| (*id, normalized.as_str())),
+        max_candidates,
+      )
+      .into_iter()
+      .map(|(customer_id, confidence)| NameCandidate {
+        customer_id,
+        name: customers[&customer_id].0.clone(),
+        confidence,
+      })
+      .collect();
+      res.push(ResolvedName { name, candidates });
+    }
+    Ok(res)
+  }
+  // Format customer address as printed on invoices
+  // Address is shaped by the field policy of the caller role
+  async fn format_address(
+    &self,
+    tenant: &str,
+    role: Role,
+    r: FormatAddressRequest,
+  ) -> ServiceResult<FormatAddressResponse> {
+    let customer = self
+      .tenants
+      .get(tenant)
+      .await?
+      .lock()
+      .await
+      .find_id(&r.customer_id)?
+      .clone();
+    let customer = customer_to_obj(customer, role);
+    let lines = country::format_address(
+      &customer.country,
+      &customer.address_zip,
+      &customer.address_location,
+      &customer.address_street,
+    );
+    Ok(FormatAddressResponse {
+      single_line: lines.join(", "),
+      lines,
+    })
+  }
+  // Postal labels of the listed customers, or of the ones matching the query
+  // Returns the labels and the IDs of the customers without address
+  async fn mailing_labels(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    role: Role,
+    r: GetMailingLabelsRequest,
+  ) -> ServiceResult<(Vec<MailingLabel>, Vec<u32>)> {
+    let custom_fields = self.custom_fields.lock().await.list(tenant)?;
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let ids = match (r.customer_ids.is_empty(), r.query.trim().is_empty()) {
+      (false, true) => r.customer_ids,
+      (true, false) => {
+        let query =
+          query::Query::parse(&r.query, &custom_fields).map_err(|e| e.on_field("query"))?;
+        query.check_role(role)?;
+        let mut res = Vec::new();
+        for (i, c) in customers.iter().enumerate() {
+          deadline.check_at(i)?;
+          if query.matches(c) {
+            res.push(c.id);
+          }
+        }
+        Self::sort_ids(&customers, res, Some(db::SortBy::Zip), false)
+      }
+      _ => {
+        return Err(ServiceError::bad_request(
+          "Ügyfél ID lista vagy szűrőfeltétel megadása kötelező, de csak az egyik",
+        ))
+      }
+    };
+    let (mut labels, mut skipped) = (Vec::new(), Vec::new());
+    for (i, id) in ids.into_iter().enumerate() {
+      deadline.check_at(i)?;
+      // Unknown IDs are dropped, as in GetBulk
+      let customer = match customers.find_id(&id) {
+        Ok(c) => customer_to_obj(c.clone(), role),
+        Err(_) => continue,
+      };
+      match country::format_label(
+        &customer.name,
+        &customer.country,
+        &customer.address_zip,
+        &customer.address_location,
+        &customer.address_street,
+      ) {
+        Some(lines) => labels.push(MailingLabel {
+          customer_id: id,
+          lines,
+        }),
+        None => skipped.push(id),
+      }
+    }
+    Ok((labels, skipped))
+  }
+  // Lookup settlements by zip code
+  async fn lookup_zip(&self, r: LookupZipRequest) -> ServiceResult<LookupZipResponse> {
+    match self.zip_db.lookup(&r.zip) {
+      Some(settlements) => Ok(LookupZipResponse {
+        zip: r.zip.trim().to_string(),
+        settlements: settlements.clone(),
+      }),
+      None => Err(ServiceError::not_found(&format!(
+        "Ismeretlen irányítószám: {}",
+        r.zip.trim()
+      ))),
+    }
+  }
+  // Get customer statistics
+  async fn get_stats(&self, tenant: &str, r: GetStatsRequest) -> ServiceResult<StatsResponse> {
+    let zip_prefix_len = match r.zip_prefix_len {
+      0 => 1,
+      x => x as usize,
+    };
+    let res = stats::compute(
+      self.tenants.get(tenant).await?.lock().await.iter(),
+      zip_prefix_len,
+    );
+    Ok(res.into())
+  }
+  // Get customers created in the given date range
+  async fn get_created_between(
+    &self,
+    tenant: &str,
+    r: GetCreatedBetweenRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let from = parse_date_opt(&r.from)?;
+    let to = parse_date_opt(&r.to)?;
+    let res = self
+      .tenants
+      .get(tenant)
+      .await?
+      .lock()
+      .await
+      .created_between(from, to);
+    Ok(res)
+  }
+  // Get customers created by a user, or by anyone
+  async fn get_created_by(
+    &self,
+    tenant: &str,
+    r: GetCreatedByRequest,
+  ) -> ServiceResult<CreatedByResponse> {
+    let from = parse_date_opt(&r.from)?;
+    let to = parse_date_opt(&r.to)?;
+    let created_by = match r.created_by {
+      0 => None,
+      uid => Some(uid),
+    };
+    let res = self
+      .tenants
+      .get(tenant)
+      .await?
+      .lock()
+      .await
+      .created_by(created_by, from, to);
+    let mut counts: Vec<CreatedByCount> = Vec::new();
+    for (uid, date, _) in &res {
+      let month = date.format("%Y-%m").to_string();
+      match counts.last_mut() {
+        Some(c) if c.created_by == *uid && c.month == month => c.count += 1,
+        _ => counts.push(CreatedByCount {
+          created_by: *uid,
+          month,
+          count: 1,
+        }),
+      }
+    }
+    let mut customers = res
+      .into_iter()
+      .map(|(_, date, id)| (date, id))
+      .collect::<Vec<_>>();
+    customers.sort();
+    Ok(CreatedByResponse {
+      customer_ids: customers.into_iter().map(|(_, id)| id).collect(),
+      counts,
+    })
+  }
+  // Record customer activity
+  async fn record_activity(
+    &self,
+    tenant: &str,
+    r: RecordActivityRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let kind = activity_kind_from_proto(r.kind)?;
+    let now = chrono::Utc::now();
+    let at = parse_date_opt(&r.timestamp)?.unwrap_or(now);
+    if at > now + chrono::Duration::minutes(5) {
+      return Err(ServiceError::bad_request(
+        "Az aktivitás időpontja nem lehet a jövőben",
+      ));
+    }
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let res = customers.update(&r.customer_id, 0, |customer| {
+      Ok(customer.record_activity(kind, at).clone())
+    })?;
+    Ok(res)
+  }
+  // Get customers without activity since the given date
+  async fn get_inactive_since(
+    &self,
+    tenant: &str,
+    deadline: &Deadline,
+    r: GetInactiveSinceRequest,
+  ) -> ServiceResult<Vec<u32>> {
+    let date = match parse_date_opt(&r.date)? {
+      Some(date) => date,
+      None => return Err(ServiceError::bad_request("A dátum megadása kötelező")),
+    };
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut res = Vec::new();
+    for (i, c) in customers.iter().enumerate() {
+      deadline.check_at(i)?;
+      if c.is_inactive_since(date) {
+        res.push(c.id);
+      }
+    }
+    Ok(res)
+  }
+  // Blocked status from the index of inactive customers
+  async fn is_blocked(&self, tenant: &str, r: IsBlockedRequest) -> ServiceResult<BlockedStatusObj> {
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    if customers.check_id_available(&r.customer_id) {
+      return Err(ServiceError::customer_not_found(r.customer_id));
+    }
+    Ok(blocked_to_obj(
+      r.customer_id,
+      customers.blocked(&r.customer_id),
+    ))
+  }
+  async fn get_blocked_customers(&self, tenant: &str) -> ServiceResult<Vec<BlockedStatusObj>> {
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    Ok(
+      customers
+        .blocked_customers()
+        .map(|(id, blocked)| blocked_to_obj(id, Some(blocked)))
+        .collect(),
+    )
+  }
+  // Queue erasure request for admin review
+  async fn request_erasure(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: RequestErasureRequest,
+  ) -> ServiceResult<erasure::ErasureRequest> {
+    self.check_writable()?;
+    let now = chrono::Utc::now();
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let customer = customers.find_id(&r.customer_id)?;
+    if customer.anonymized_at.is_some() {
+      return Err(ServiceError::failed_precondition(
+        "Az ügyfél adatai már anonimizálva vannak",
+      ));
+    }
+    let execute_after = self.retention.erasure_due(customer, now);
+    self.erasures.lock().await.request(
+      tenant,
+      r.customer_id,
+      editor_uid,
+      &r.reason,
+      execute_after,
+      now,
+    )
+  }
+  // Approve or reject erasure request
+  async fn decide_erasure(
+    &self,
+    tenant: &str,
+    r: DecideErasureRequest,
+    approve: bool,
+  ) -> ServiceResult<erasure::ErasureRequest> {
+    self.check_writable()?;
+    let now = chrono::Utc::now();
+    let res =
+      self
+        .erasures
+        .lock()
+        .await
+        .decide(tenant, r.request_id, approve, r.decided_by, now)?;
+    self.audit.append(
+      tenant,
+      &audit::AuditEntry {
+        date: now,
+        customer_id: res.customer_id,
+        action: match approve {
+          true => audit::AuditAction::ApproveErasure,
+          false => audit::AuditAction::RejectErasure,
+        },
+        actor: format!("admin:{}", r.decided_by),
+      },
+    )?;
+    Ok(res)
+  }
+  // Deactivate customer
+  async fn deactivate(
+    &self,
+    tenant: &str,
+    r: DeactivateRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let reason = reason_code_from_proto(r.reason_code)?;
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let customer_id = r.customer_id;
+    let res = customers.update(&customer_id, r.changed_by, |customer| {
+      Ok(
+        customer
+          .deactivate(reason, r.comment, r.changed_by)?
+          .clone(),
+      )
+    })?;
+    Ok(res)
+  }
+  // Reactivate customer
+  async fn reactivate(
+    &self,
+    tenant: &str,
+    r: ReactivateRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let customer_id = r.customer_id;
+    let res = customers.update(&customer_id, r.changed_by, |customer| {
+      Ok(customer.reactivate(r.comment, r.changed_by)?.clone())
+    })?;
+    Ok(res)
+  }
+  // Compute merged customer without writing it
+  async fn preview_merge(
+    &self,
+    tenant: &str,
+    r: PreviewMergeRequest,
+  ) -> ServiceResult<merge::Merge> {
+    if r.customer_id == r.duplicate_id {
+      return Err(ServiceError::bad_request(
+        "Az ügyfél nem vonható össze saját magával",
+      ));
+    }
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let kept = customers.find_id(&r.customer_id)?;
+    let duplicate = customers.find_id(&r.duplicate_id)?;
+    Ok(merge::merge(kept, duplicate))
+  }
+  // Set invoice name and address overrides
+  async fn set_invoice_details(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    admin: bool,
+    r: SetInvoiceDetailsRequest,
+  ) -> ServiceResult<(customer::Customer, Option<approval::PendingChange>)> {
+    let (invoice_name, invoice_address) = invoice_details_from_proto(&r);
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let customer_id = r.customer_id;
+    self
+      .check_edit_lock(tenant, customer_id, editor_uid)
+      .await?;
+    // Check invoice zip and location consistency
+    if let Some(address) = &invoice_address {
+      let current = customers.find_id(&customer_id)?;
+      self.validate_zip(
+        &current.country,
+        &address.zip,
+        &address.location,
+        "invoice_address_location",
+      )?;
+    }
+    // Update a copy first, invoice overrides of non-admins
+    // wait for approval the same way as the legal name
+    let before = customers.find_id(&customer_id)?.clone();
+    let mut updated = before.clone();
+    updated.set_invoice_details(invoice_name, invoice_address, &self.policy.get())?;
+    let pending = match admin {
+      true => None,
+      false => approval::split_sensitive(&before, &mut updated),
+    };
+    if pending.is_some() {
+      self.check_approvable()?;
+    }
+    let pending = match pending {
+      Some(sensitive) => Some(self.pending_changes.lock().await.request(
+        tenant,
+        customer_id,
+        editor_uid,
+        sensitive,
+        chrono::Utc::now(),
+      )?),
+      None => None,
+    };
+    // Nothing else is changed by the request
+    if pending.is_some() {
+      return Ok((before, pending));
+    }
+    let res = customers.update(&customer_id, editor_uid, |customer| {
+      *customer = updated;
+      Ok(customer.clone())
+    })?;
+    Ok((res, None))
+  }
+  // Add or remove customer alias
+  async fn set_alias(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: AliasRequest,
+    add: bool,
+  ) -> ServiceResult<customer::Customer> {
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    self
+      .check_edit_lock(tenant, r.customer_id, editor_uid)
+      .await?;
+    let res = customers.update(&r.customer_id, editor_uid, |customer| {
+      let res = match add {
+        true => customer.add_alias(&r.alias, &self.policy.get()),
+        false => customer.remove_alias(&r.alias),
+      };
+      Ok(res?.clone())
+    })?;
+    Ok(res)
+  }
+  // Set privacy restrictions of a customer
+  async fn set_privacy_flags(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: SetPrivacyFlagsRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let flags = r.flags.map(customer::PrivacyFlags::from).ok_or_else(|| {
+      ServiceError::invalid_field("flags", "Az adatvédelmi beállítások megadása kötelező")
+    })?;
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    self
+      .check_edit_lock(tenant, r.customer_id, editor_uid)
+      .await?;
+    let res = customers.update(&r.customer_id, editor_uid, |customer| {
+      customer.privacy = flags;
+      Ok(customer.clone())
+    })?;
+    Ok(res)
+  }
+  // Privacy restrictions of many customers
+  // Customers not found are left out
+  async fn get_privacy_flags(
+    &self,
+    tenant: &str,
+    r: GetPrivacyFlagsRequest,
+  ) -> ServiceResult<Vec<CustomerPrivacyFlags>> {
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    let mut seen = HashSet::new();
+    Ok(
+      r.customer_ids
+        .into_iter()
+        .filter(|id| seen.insert(*id))
+        .filter_map(|id| customers.find_id(&id).ok())
+        .map(|c| CustomerPrivacyFlags {
+          customer_id: c.id,
+          flags: Some(c.privacy.into()),
+        })
+        .collect(),
+    )
+  }
+  // Apply change to many customers
+  // f returns false if the customer is unchanged, those are not saved
+  // Customers not found, locked or invalid are skipped with the reason
+  async fn update_bulk<F>(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    customer_ids: Vec<u32>,
+    f: F,
+  ) -> ServiceResult<BulkUpdateReport>
+  where
+    F: Fn(&mut customer::Customer) -> ServiceResult<bool>,
+  {
+    if customer_ids.len() > customer::MAX_BULK_UPDATE {
+      return Err(ServiceError::invalid_field(
+        "customer_ids",
+        &format!(
+          "Egyszerre legfeljebb {} ügyfél módosítható",
+          customer::MAX_BULK_UPDATE
+        ),
+      ));
+    }
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let mut report = BulkUpdateReport::default();
+    let skip = |report: &mut BulkUpdateReport, customer_id, error: ServiceError| {
+      report.skipped.push(BulkSkippedObj {
+        customer_id,
+        reason: translate(&error.to_string(), current_locale()),
+      })
+    };
+    // Changes are collected first, as edit locks are checked async
+    let mut candidates = Vec::new();
+    let mut seen = HashSet::new();
+    for customer_id in customer_ids {
+      if !seen.insert(customer_id) {
+        continue;
+      }
+      let mut candidate = match customers.find_id(&customer_id) {
+        Ok(customer) => customer.clone(),
+        Err(error) => {
+          skip(&mut report, customer_id, error);
+          continue;
+        }
+      };
+      match f(&mut candidate) {
+        Ok(true) => (),
+        Ok(false) => {
+          report.unchanged_ids.push(customer_id);
+          continue;
+        }
+        Err(error) => {
+          skip(&mut report, customer_id, error);
+          continue;
+        }
+      }
+      match self.check_edit_lock(tenant, customer_id, editor_uid).await {
+        Ok(_) => candidates.push(candidate),
+        Err(error) => skip(&mut report, customer_id, error),
+      }
+    }
+    // The valid changes are stored together, or none of them
+    let updated = customers.atomic(|tx| {
+      let mut updated = Vec::new();
+      for candidate in candidates {
+        let customer_id = candidate.id;
+        match tx.update(&customer_id, editor_uid, |customer| {
+          *customer = candidate;
+          Ok(())
+        }) {
+          Ok(()) => updated.push(tx.find_id(&customer_id)?.clone()),
+          Err(error) => skip(&mut report, customer_id, error),
+        }
+      }
+      Ok(updated)
+    })?;
+    for customer in &updated {
+      report.updated_ids.push(customer.id);
+    }
+    Ok(report)
+  }
+  // Lock customer for editing
+  async fn lock_for_edit(&self, tenant: &str, r: LockForEditRequest) -> ServiceResult<EditLock> {
+    // Check customer exists
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    customers.find_id(&r.customer_id)?;
+    self.edit_locks.lock().await.lock(
+      tenant,
+      r.customer_id,
+      r.editor_uid,
+      r.ttl_secs,
+      chrono::Utc::now(),
+    )
+  }
+  // Release edit lock
+  async fn release_lock(&self, tenant: &str, r: ReleaseLockRequest) -> ServiceResult<()> {
+    self
+      .edit_locks
+      .lock()
+      .await
+      .release(tenant, r.customer_id, r.editor_uid, chrono::Utc::now())
+  }
+  // Attach document to customer
+  async fn attach_document(
+    &self,
+    tenant: &str,
+    r: AttachDocumentRequest,
+  ) -> ServiceResult<AttachmentObj> {
+    let document_kind = document_kind_from_proto(r.document_kind)?;
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let customer_id = r.customer_id;
+    let res = customers.update(&customer_id, r.uploaded_by, |customer| {
+      Ok(
+        customer
+          .attach_document(document_kind, r.storage_key, r.uploaded_by)?
+          .clone(),
+      )
+    })?;
+    Ok(res.into())
+  }
+  // List documents attached to customer
+  async fn list_documents(
+    &self,
+    tenant: &str,
+    r: ListDocumentsRequest,
+  ) -> ServiceResult<Vec<AttachmentObj>> {
+    let res = self
+      .tenants
+      .get(tenant)
+      .await?
+      .lock()
+      .await
+      .find_id(&r.customer_id)?
+      .attachments
+      .iter()
+      .map(|a| a.clone().into())
+      .collect::<Vec<AttachmentObj>>();
+    Ok(res)
+  }
+  // Remove document from customer
+  async fn remove_document(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: RemoveDocumentRequest,
+  ) -> ServiceResult<AttachmentObj> {
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let res = customers.update(&r.customer_id, editor_uid, |customer| {
+      customer.remove_document(r.attachment_id)
+    })?;
+    Ok(res.into())
+  }
+  // Compact customer storage files
+  // and rebuild in-memory indexes
+  async fn compact_storage(&self, tenant: &str) -> ServiceResult<CompactStorageResponse> {
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let report = customers.compact()?;
+    Ok(CompactStorageResponse {
+      record_count: report.record_count,
+      size_before: report.size_before,
+      size_after: report.size_after,
+    })
+  }
+  // Reload storage from disk
+  async fn reload_storage(&self, tenant: &str) -> ServiceResult<ReloadStorageResponse> {
+    let record_count = self.tenants.reload(tenant).await?;
+    Ok(ReloadStorageResponse {
+      record_count: record_count as u32,
+    })
+  }
+  // Derive display names of the tenant again
+  // Returns the IDs of the updated customers
+  async fn rederive_display_names(&self, tenant: &str) -> ServiceResult<Vec<u32>> {
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let updated = customers.rederive_names()?;
+    Ok(updated.into_iter().map(|c| c.id).collect())
+  }
+  // Reload validation policy from its file
+  fn reload_validation_policy(&self) -> ServiceResult<ValidationPolicyObj> {
+    let policy = self.policy.reload()?;
+    Ok(ValidationPolicyObj::from(&*policy))
+  }
+  // Merge backup into the customers of a tenant
+  async fn restore_backup(
+    &self,
+    tenant: &str,
+    r: RestoreBackupRequest,
+  ) -> ServiceResult<restore::RestoreReport> {
+    let backup = restore::parse_backup(&r.backup)?;
+    let strategy = RestoreStrategy::from_i32(r.strategy)
+      .ok_or_else(|| ServiceError::invalid_field("strategy", "Ismeretlen visszaállítási mód"))?;
+    let take_backup_ids = r.take_backup_ids.into_iter().collect::<HashSet<u32>>();
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let (report, _, _) = restore::restore(
+      &mut customers,
+      backup,
+      |c| match strategy {
+        RestoreStrategy::RestoreNewerWins => restore::newer_wins(c),
+        RestoreStrategy::RestoreSelected => take_backup_ids.contains(&c.customer_id),
+      },
+      r.dry_run,
+      Some((&self.audit, tenant, "restore:rpc")),
+    )?;
+    Ok(report)
+  }
+  // Create or update customers matched by external ID or tax number
+  // Rows are checked like CreateNew and UpdateById, and the valid ones
+  // are stored together. Failing rows are reported with their error.
+  async fn import_customers(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: ImportCustomersRequest,
+  ) -> ServiceResult<Vec<import::RowResult>> {
+    if r.rows.len() > import::MAX_IMPORT_ROWS {
+      return Err(ServiceError::invalid_field(
+        "rows",
+        &format!(
+          "Egyszerre legfeljebb {} sor importálható",
+          import::MAX_IMPORT_ROWS
+        ),
+      ));
+    }
+    let key = import_key_from_proto(r.key)?;
+    if !r.dry_run {
+      self.check_writable()?;
+    }
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    let policy = self.policy.get();
+    let next_id = Self::next_customer_id(&customers).max(
+      self
+        .reservations
+        .lock()
+        .await
+        .next_free_id(tenant, chrono::Utc::now())?,
+    );
+    let mut res = Vec::new();
+    let mut planned = Vec::new();
+    // Rows are planned first, as edit locks are checked async
+    {
+      let mut importer = import::Importer::new(&customers, key, &policy, next_id);
+      let mut created = 0;
+      for (row, data) in r.rows.into_iter().enumerate() {
+        let checked = import_row_from_proto(data)
+          .and_then(|data| importer.plan(data))
+          .and_then(|p| {
+            let customer = match &p {
+              import::Planned::Create(customer) => {
+                self.quota.check_new(tenant, customers.len() + created)?;
+                customer
+              }
+              import::Planned::Update(_, customer) => customer,
+              import::Planned::Unchanged(_) => return Ok(p),
+            };
+            self.validate_zip(
+              &customer.country,
+              &customer.address_zip,
+              &customer.address_location,
+              "address_location",
+            )?;
+            self.check_unique(&customers, customer)?;
+            Ok(p)
+          });
+        match checked {
+          Ok(p) => {
+            if let import::Planned::Create(_) = p {
+              created += 1;
+            }
+            planned.push((row, p));
+          }
+          Err(error) => res.push(import::RowResult::new(row, Err(error))),
+        }
+      }
+    }
+    let mut unlocked = Vec::new();
+    // Customers whose tax number change is forced
+    let mut forced = HashSet::new();
+    for (row, p) in planned {
+      let checked = match &p {
+        import::Planned::Update(before, customer) => {
+          match self.check_edit_lock(tenant, customer.id, editor_uid).await {
+            Ok(()) => self
+              .guard_tax_number(tenant, before, customer, r.force_tax_number_change)
+              .await
+              .map(|f| {
+                if f {
+                  forced.insert(customer.id);
+                }
+              }),
+            Err(error) => Err(error),
+          }
+        }
+        _ => Ok(()),
+      };
+      match checked {
+        Ok(()) => unlocked.push((row, p)),
+        Err(error) => res.push(import::RowResult::new(row, Err(error))),
+      }
+    }
+    if r.dry_run {
+      res.extend(
+        unlocked
+          .iter()
+          .map(|(row, p)| import::RowResult::new(*row, Ok(p))),
+      );
+      res.sort_by_key(|r| r.row);
+      return Ok(res);
+    }
+    let stored = import::store(&mut customers, editor_uid, unlocked)?;
+    self.alert_quota(tenant, customers.len());
+    for (row, p) in stored {
+      // A failed audit fails the row only, the others are stored already
+      let p = p.and_then(|p| match &p {
+        import::Planned::Update(_, customer) if forced.contains(&customer.id) => self
+          .audit_tax_number_change(tenant, customer.id, &format!("admin:{}", editor_uid))
+          .map(|_| p),
+        _ => Ok(p),
+      });
+      res.push(match p {
+        Ok(p) => import::RowResult::new(row, Ok(&p)),
+        Err(error) => import::RowResult::new(row, Err(error)),
+      });
+    }
+    res.sort_by_key(|r| r.row);
+    Ok(res)
+  }
+  // Actions the next retention run would do
+  async fn preview_retention_run(
+    &self,
+    tenant: &str,
+  ) -> ServiceResult<Vec<retention::RetentionAction>> {
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    Ok(self.retention.plan(customers.iter(), chrono::Utc::now()))
+  }
+  // Changes of a customer field, oldest first
+  async fn get_field_history(
+    &self,
+    tenant: &str,
+    r: GetFieldHistoryRequest,
+  ) -> ServiceResult<Vec<history::FieldChange>> {
+    let field = r.field.trim();
+    history::validate_field(field)?;
+    let customers = self.tenants.get(tenant).await?;
+    // History is appended while the customers are locked
+    let customers = customers.lock().await;
+    customers.find_id(&r.customer_id)?;
+    self.history.field(tenant, r.customer_id, field)
+  }
+  // Log communication with a customer
+  async fn append_communication(
+    &self,
+    tenant: &str,
+    r: AppendCommunicationRequest,
+  ) -> ServiceResult<communication::Communication> {
+    self.check_writable()?;
+    let channel = communication_channel_from_proto(r.channel).map_err(|e| e.on_field("channel"))?;
+    let direction = direction_from_proto(r.direction).map_err(|e| e.on_field("direction"))?;
+    let communication = communication::Communication::new(
+      r.customer_id,
+      channel,
+      direction,
+      &r.summary,
+      r.created_by,
+      chrono::Utc::now(),
+    )?;
+    // Appended while the customers are locked, so the customer cannot go missing
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    customers.find_id(&r.customer_id)?;
+    self.communications.append(tenant, &communication)?;
+    Ok(communication)
+  }
+  // Logged communications of a customer, newest first
+  async fn get_communications(
+    &self,
+    tenant: &str,
+    r: GetCommunicationsRequest,
+  ) -> ServiceResult<Vec<communication::Communication>> {
+    let limit = match r.limit as usize {
+      0 => communication::DEFAULT_LIMIT,
+      x => x.min(communication::MAX_LIMIT),
+    };
+    let customers = self.tenants.get(tenant).await?;
+    let customers = customers.lock().await;
+    customers.find_id(&r.customer_id)?;
+    self.communications.customer(tenant, r.customer_id, limit)
+  }
+  // Define custom field, or change a defined one
+  async fn define_custom_field(
+    &self,
+    tenant: &str,
+    r: DefineCustomFieldRequest,
+  ) -> ServiceResult<custom_field::CustomFieldDef> {
+    self.check_writable()?;
+    let field_type =
+      custom_field_type_from_proto(r.field_type).map_err(|e| e.on_field("field_type"))?;
+    self.custom_fields.lock().await.define(
+      tenant,
+      &r.name,
+      &r.label,
+      field_type,
+      r.choices,
+      r.created_by,
+      chrono::Utc::now(),
+    )
+  }
+  // Set custom field values of a customer
+  async fn set_custom_fields(
+    &self,
+    tenant: &str,
+    editor_uid: u32,
+    r: SetCustomFieldsRequest,
+  ) -> ServiceResult<customer::Customer> {
+    let defs = self.custom_fields.lock().await.list(tenant)?;
+    let customers = self.tenants.get(tenant).await?;
+    let mut customers = customers.lock().await;
+    self
+      .check_edit_lock(tenant, r.customer_id, editor_uid)
+      .await?;
+    let values = r.values;
+    let res = customers.update(&r.customer_id, editor_uid, |customer| {
+      custom_field::set_values(&defs, &mut customer.custom_fields, values)?;
+      Ok(customer.clone())
+    })?;
+    Ok(res)
+  }
+  // Register webhook
+  async fn register_webhook(
+    &self,
+    tenant: &str,
+    r: RegisterWebhookRequest,
+  ) -> ServiceResult<webhook::Webhook> {
+    self.check_writable()?;
+    let event_kinds = r
+      .event_kinds
+      .iter()
+      .map(|kind| event_kind_from_proto(*kind))
+      .collect::<ServiceResult<Vec<events::CustomerEventKind>>>()
+      .map_err(|e| e.on_field("event_kinds"))?;
+    self
+      .webhooks
+      .register(tenant, r.url, r.secret, event_kinds, r.created_by)
+      .await
+  }
+}
+
+#[tonic::async_trait]
+impl Customer for CustomerService {
+  async fn create_new(
+    &self,
+    request: Request<NewCustomerObj>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let idempotency_key = idempotency_key_from_metadata(request.metadata())?;
+    let resp = self
+      .create_new(&tenant, idempotency_key, request.into_inner())
+      .await?;
+    Ok(Response::new(
+      self.with_quota_warning(&tenant, resp, role).await?,
+    ))
+  }
+
+  async fn quick_create(
+    &self,
+    request: Request<QuickCreateRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let idempotency_key = idempotency_key_from_metadata(request.metadata())?;
+    let resp = self
+      .quick_create(&tenant, idempotency_key, request.into_inner())
+      .await?;
+    Ok(Response::new(
+      self.with_quota_warning(&tenant, resp, role).await?,
+    ))
+  }
+
+  async fn get_incomplete_profiles(
+    &self,
+    request: Request<GetIncompleteProfilesRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .get_incomplete_profiles(&tenant, &deadline, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn reserve_customer_ids(
+    &self,
+    request: Request<ReserveCustomerIdsRequest>,
+  ) -> Result<Response<IdReservationObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .reserve_customer_ids(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn get_all(
+    &self,
+    request: Request<GetAllRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .get_all(&tenant, &deadline, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn get_by_id(
+    &self,
+    request: Request<GetByIdRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let r = request.into_inner();
+    let expanded = r.expanded;
+    let res = self.get_by_id(&tenant, r).await?;
+    // The tenant is not locked while the user service is asked
+    let names = self
+      .created_by_names(&tenant, expanded, &[res.created_by])
+      .await;
+    Ok(Response::new(with_created_by_name(
+      customer_to_obj(res, role),
+      &names,
+    )))
+  }
+
+  async fn get_customer_with_stats(
+    &self,
+    request: Request<GetByIdRequest>,
+  ) -> Result<Response<CustomerWithStats>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self
+      .get_customer_with_stats(&tenant, role, request.into_inner())
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_by_loyalty_card(
+    &self,
+    request: Request<GetByLoyaltyCardRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self
+      .get_by_loyalty_card(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  type GetBulkStream = ReceiverStream<Result<CustomerObj, Status>>;
+
+  async fn get_bulk(
+    &self,
+    request: Request<GetBulkRequest>,
+  ) -> Result<Response<Self::GetBulkStream>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let customers = self.tenants.get(&tenant).await?;
+
+    // Create channel for stream response
+    // Bounded, so slow clients slow down reading
+    let (tx, rx) = tokio::sync::mpsc::channel(self.stream_buffer);
+
+    // Send the result items through the channel
+    let deadline = Deadline::from_metadata(request.metadata());
+    // Stream errors are sent in the locale of the request
+    tokio::spawn(with_locale(
+      current_locale(),
+      Self::send_bulk(customers, request.into_inner(), role, deadline, tx),
+    ));
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  type GetAllSummariesStream = ReceiverStream<Result<CustomerSummary, Status>>;
+
+  async fn get_all_summaries(
+    &self,
+    request: Request<GetAllRequest>,
+  ) -> Result<Response<Self::GetAllSummariesStream>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    // IDs are listed and sorted as in GetAll
+    let ids = self
+      .get_all(&tenant, &deadline, request.into_inner())
+      .await?;
+    let customers = self.tenants.get(&tenant).await?;
+    let (tx, rx) = tokio::sync::mpsc::channel(self.stream_buffer);
+    // Stream errors are sent in the locale of the request
+    tokio::spawn(with_locale(
+      current_locale(),
+      Self::send_summaries(customers, ids, role, deadline, tx),
+    ));
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn update_by_id(
+    &self,
+    request: Request<CustomerObj>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    // Sensitive changes of non-admins wait for approval
+    let admin = self.check_admin(request.metadata()).is_ok();
+    let (res, pending) = self
+      .update_by_id(&tenant, editor_uid, admin, request.into_inner())
+      .await?;
+    let mut res = self.with_warnings(res, role);
+    if let Some(pending) = pending {
+      self.with_pending(&mut res, &pending);
+    }
+    Ok(Response::new(res))
+  }
+
+  async fn find_customer(
+    &self,
+    request: Request<FindCustomerRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .find_customer(&tenant, &deadline, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  type FindCustomerStreamStream = ReceiverStream<Result<FoundCustomer, Status>>;
+
+  async fn find_customer_stream(
+    &self,
+    request: Request<FindCustomerRequest>,
+  ) -> Result<Response<Self::FindCustomerStreamStream>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let r = request.into_inner();
+    let kind = customer_kind_from_proto(r.kind)?;
+    if sort_by_from_proto(r.sort_by)?.is_some() || r.descending {
+      return Err(
+        ServiceError::invalid_field("sort_by", "Folyamatos keresés találatai nem rendezhetők")
+          .into(),
+      );
+    }
+    let customers = self.tenants.get(&tenant).await?;
+    let (tx, rx) = tokio::sync::mpsc::channel(self.stream_buffer);
+    // Stream errors are sent in the locale of the request
+    tokio::spawn(with_locale(
+      current_locale(),
+      Self::send_found(customers, r, kind, deadline, tx),
+    ));
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn find_by_name_prefix(
+    &self,
+    request: Request<FindByNamePrefixRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .find_by_name_prefix(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn find_by_address(
+    &self,
+    request: Request<FindByAddressRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self
+      .find_by_address(&tenant, role, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn query_customers(
+    &self,
+    request: Request<QueryCustomersRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .query_customers(&tenant, &deadline, role, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn resolve_names(
+    &self,
+    request: Request<ResolveNamesRequest>,
+  ) -> Result<Response<ResolveNamesResponse>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .resolve_names(&tenant, &deadline, request.into_inner())
+      .await?;
+    Ok(Response::new(ResolveNamesResponse { results: res }))
+  }
+
+  async fn format_address(
+    &self,
+    request: Request<FormatAddressRequest>,
+  ) -> Result<Response<FormatAddressResponse>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self
+      .format_address(&tenant, role, request.into_inner())
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn lookup_zip(
+    &self,
+    request: Request<LookupZipRequest>,
+  ) -> Result<Response<LookupZipResponse>, Status> {
+    let res = self.lookup_zip(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_stats(
+    &self,
+    request: Request<GetStatsRequest>,
+  ) -> Result<Response<StatsResponse>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.get_stats(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_created_between(
+    &self,
+    request: Request<GetCreatedBetweenRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .get_created_between(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn get_created_by(
+    &self,
+    request: Request<GetCreatedByRequest>,
+  ) -> Result<Response<CreatedByResponse>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.get_created_by(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_privacy_flags(
+    &self,
+    request: Request<SetPrivacyFlagsRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .set_privacy_flags(&tenant, editor_uid, request.into_inner())
+      .await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn get_privacy_flags(
+    &self,
+    request: Request<GetPrivacyFlagsRequest>,
+  ) -> Result<Response<PrivacyFlagsList>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .get_privacy_flags(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(PrivacyFlagsList { customers: res }))
+  }
+
+  async fn record_activity(
+    &self,
+    request: Request<RecordActivityRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self.record_activity(&tenant, request.into_inner()).await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn get_inactive_since(
+    &self,
+    request: Request<GetInactiveSinceRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .get_inactive_since(&tenant, &deadline, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn is_blocked(
+    &self,
+    request: Request<IsBlockedRequest>,
+  ) -> Result<Response<BlockedStatusObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.is_blocked(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_blocked_customers(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<BlockedCustomers>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let customers = self.get_blocked_customers(&tenant).await?;
+    Ok(Response::new(BlockedCustomers { customers }))
+  }
+
+  async fn request_erasure(
+    &self,
+    request: Request<RequestErasureRequest>,
+  ) -> Result<Response<ErasureRequestObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .request_erasure(&tenant, editor_uid, request.into_inner())
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn deactivate(
+    &self,
+    request: Request<DeactivateRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self.deactivate(&tenant, request.into_inner()).await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn reactivate(
+    &self,
+    request: Request<ReactivateRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self.reactivate(&tenant, request.into_inner()).await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn preview_merge(
+    &self,
+    request: Request<PreviewMergeRequest>,
+  ) -> Result<Response<PreviewMergeResponse>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self.preview_merge(&tenant, request.into_inner()).await?;
+    Ok(Response::new(merge_to_obj(res, role)))
+  }
+
+  async fn get_incomplete(
+    &self,
+    request: Request<GetIncompleteRequest>,
+  ) -> Result<Response<IncompleteCustomerList>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .get_incomplete(&tenant, &deadline, request.into_inner())
+      .await?;
+    Ok(Response::new(IncompleteCustomerList { customers: res }))
+  }
+
+  async fn get_tax_data_issues(
+    &self,
+    request: Request<GetTaxDataIssuesRequest>,
+  ) -> Result<Response<TaxDataIssueReport>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .get_tax_data_issues(&tenant, role, &deadline, request.into_inner())
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_invoice_readiness(
+    &self,
+    request: Request<GetByIdRequest>,
+  ) -> Result<Response<InvoiceReadiness>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .get_invoice_readiness(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_vat_status(
+    &self,
+    request: Request<SetVatStatusRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .set_vat_status(&tenant, editor_uid, request.into_inner())
+      .await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn set_discount(
+    &self,
+    request: Request<SetDiscountRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .set_discount(&tenant, editor_uid, request.into_inner())
+      .await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn get_vat_status(
+    &self,
+    request: Request<GetVatStatusRequest>,
+  ) -> Result<Response<VatStatusObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.get_vat_status(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_invoice_details(
+    &self,
+    request: Request<SetInvoiceDetailsRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    // Overrides of non-admins wait for approval, see UpdateById
+    let admin = self.check_admin(request.metadata()).is_ok();
+    let (res, pending) = self
+      .set_invoice_details(&tenant, editor_uid, admin, request.into_inner())
+      .await?;
+    let mut res = self.with_warnings(res, role);
+    if let Some(pending) = pending {
+      self.with_pending(&mut res, &pending);
+    }
+    Ok(Response::new(res))
+  }
+
+  async fn add_alias(
+    &self,
+    request: Request<AliasRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .set_alias(&tenant, editor_uid, request.into_inner(), true)
+      .await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn remove_alias(
+    &self,
+    request: Request<AliasRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .set_alias(&tenant, editor_uid, request.into_inner(), false)
+      .await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn generate_profile_token(
+    &self,
+    request: Request<GenerateProfileTokenRequest>,
+  ) -> Result<Response<ProfileTokenObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    // Tokens give access to customer data, only staff can ask for them
+    self
+      .check_admin(request.metadata())
+      .or_else(|_| auth::check_staff(request.metadata()))?;
+    let res = self
+      .generate_profile_token(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_by_profile_token(
+    &self,
+    request: Request<ProfileTokenRequest>,
+  ) -> Result<Response<ProfileObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .get_by_profile_token(&tenant, &request.into_inner().token)
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn update_by_profile_token(
+    &self,
+    request: Request<UpdateProfileRequest>,
+  ) -> Result<Response<ProfileObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .update_by_profile_token(&tenant, request.into_inner())
+      .await?;
+    let warnings = warning::check(&res, &self.zip_db, &self.policy.get());
+    Ok(Response::new(ProfileObj {
+      warnings: warnings.into_iter().map(|w| w.into()).collect(),
+      ..res.into()
+    }))
+  }
+
+  async fn add_tag_bulk(
+    &self,
+    request: Request<TagBulkRequest>,
+  ) -> Result<Response<BulkUpdateReport>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let TagBulkRequest { customer_ids, tag } = request.into_inner();
+    let policy = self.policy.get();
+    let res = self
+      .update_bulk(&tenant, editor_uid, customer_ids, |customer| {
+        customer.add_tag(&tag, &policy)
+      })
+      .await
+      .map_err(|e| e.on_field("tag"))?;
+    Ok(Response::new(res))
+  }
+
+  async fn remove_tag_bulk(
+    &self,
+    request: Request<TagBulkRequest>,
+  ) -> Result<Response<BulkUpdateReport>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let TagBulkRequest { customer_ids, tag } = request.into_inner();
+    let res = self
+      .update_bulk(&tenant, editor_uid, customer_ids, |customer| {
+        Ok(customer.remove_tag(&tag))
+      })
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn assign_group_bulk(
+    &self,
+    request: Request<AssignGroupBulkRequest>,
+  ) -> Result<Response<BulkUpdateReport>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let AssignGroupBulkRequest {
+      customer_ids,
+      group,
+    } = request.into_inner();
+    let policy = self.policy.get();
+    let res = self
+      .update_bulk(&tenant, editor_uid, customer_ids, |customer| {
+        customer.set_group(&group, &policy)
+      })
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn lock_for_edit(
+    &self,
+    request: Request<LockForEditRequest>,
+  ) -> Result<Response<EditLockObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.lock_for_edit(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn release_lock(
+    &self,
+    request: Request<ReleaseLockRequest>,
+  ) -> Result<Response<()>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    self.release_lock(&tenant, request.into_inner()).await?;
+    Ok(Response::new(()))
+  }
+
+  async fn attach_document(
+    &self,
+    request: Request<AttachDocumentRequest>,
+  ) -> Result<Response<AttachmentObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.attach_document(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn list_documents(
+    &self,
+    request: Request<ListDocumentsRequest>,
+  ) -> Result<Response<AttachmentList>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.list_documents(&tenant, request.into_inner()).await?;
+    Ok(Response::new(AttachmentList { attachments: res }))
+  }
+
+  async fn remove_document(
+    &self,
+    request: Request<RemoveDocumentRequest>,
+  ) -> Result<Response<AttachmentObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .remove_document(&tenant, editor_uid, request.into_inner())
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  type GetMailingLabelsStream = ReceiverStream<Result<MailingLabel, Status>>;
+
+  async fn get_mailing_labels(
+    &self,
+    request: Request<GetMailingLabelsRequest>,
+  ) -> Result<Response<Self::GetMailingLabelsStream>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let (labels, _) = self
+      .mailing_labels(&tenant, &deadline, role, request.into_inner())
+      .await?;
+
+    // Send the labels through the channel
+    let (tx, rx) = tokio::sync::mpsc::channel(self.stream_buffer);
+    tokio::spawn(async move {
+      for label in labels {
+        if tx.send(Ok(label)).await.is_err() {
+          return;
+        }
+      }
+    });
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn get_mailing_label_text(
+    &self,
+    request: Request<GetMailingLabelsRequest>,
+  ) -> Result<Response<MailingLabelText>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let (labels, skipped) = self
+      .mailing_labels(&tenant, &deadline, role, request.into_inner())
+      .await?;
+    Ok(Response::new(MailingLabelText {
+      text: labels
+        .iter()
+        .map(|l| l.lines.join("\n"))
+        .collect::<Vec<String>>()
+        .join("\n\n"),
+      label_count: labels.len() as u32,
+      skipped_customer_ids: skipped,
+    }))
+  }
+
+  type WatchStream = ReceiverStream<Result<CustomerEvent, Status>>;
+
+  async fn watch(&self, request: Request<()>) -> Result<Response<Self::WatchStream>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(self.stream_buffer);
+
+    // Subscribe to customer events
+    let mut events = self.events.subscribe();
+
+    // Forward events of the tenant until the client disconnects
+    // Idle watchers are dropped on disconnect too, not only
+    // when their next event cannot be sent
+    tokio::spawn(async move {
+      loop {
+        let received = tokio::select! {
+          received = events.recv() => received,
+          _ = tx.closed() => break,
+        };
+        let event = match received {
+          Ok(event) => event,
+          // Slow client, some events are lost
+          Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        if event.tenant != tenant {
+          continue;
+        }
+        if tx
+          .send(Ok(customer_event_to_obj(event, role)))
+          .await
+          .is_err()
+        {
+          break;
+        }
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn compact_storage(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<CompactStorageResponse>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.compact_storage(&tenant).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn reload_storage(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<ReloadStorageResponse>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.reload_storage(&tenant).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn rederive_display_names(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.rederive_display_names(&tenant).await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  type ReplicateStream = ReceiverStream<Result<ReplicationRecord, Status>>;
+
+  async fn replicate(
+    &self,
+    request: Request<ReplicateRequest>,
+  ) -> Result<Response<Self::ReplicateStream>, Status> {
+    self.check_admin(request.metadata())?;
+    let (tx, rx) = tokio::sync::mpsc::channel(self.stream_buffer);
+    tokio::spawn(replication::serve(
+      self.tenants.clone(),
+      self.journal.clone(),
+      request.into_inner(),
+      tx,
+    ));
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn reload_validation_policy(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<ValidationPolicyObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let res = self.reload_validation_policy()?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_quarantined_records(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<QuarantinedRecordList>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let records = integrity::corrupt_records(self.tenants.data_dir(), &tenant)?;
+    Ok(Response::new(QuarantinedRecordList {
+      records: records.into_iter().map(|r| r.into()).collect(),
+    }))
+  }
+
+  async fn preview_retention_run(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<RetentionPreview>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.preview_retention_run(&tenant).await?;
+    Ok(Response::new(RetentionPreview {
+      actions: res.into_iter().map(|a| a.into()).collect(),
+    }))
+  }
+
+  async fn get_field_history(
+    &self,
+    request: Request<GetFieldHistoryRequest>,
+  ) -> Result<Response<FieldHistoryObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .get_field_history(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(FieldHistoryObj {
+      changes: res.into_iter().map(|c| c.into()).collect(),
+    }))
+  }
+
+  async fn get_quota_usage(&self, request: Request<()>) -> Result<Response<QuotaUsageObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.quota_usage(&tenant).await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn restore_backup(
+    &self,
+    request: Request<RestoreBackupRequest>,
+  ) -> Result<Response<RestoreReport>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.restore_backup(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn import_customers(
+    &self,
+    request: Request<ImportCustomersRequest>,
+  ) -> Result<Response<ImportReport>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .import_customers(&tenant, editor_uid, request.into_inner())
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn get_rpc_metrics(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<RpcMetricsResponse>, Status> {
+    self.check_admin(request.metadata())?;
+    Ok(Response::new(RpcMetricsResponse {
+      methods: self
+        .metrics
+        .snapshot()
+        .into_iter()
+        .map(|m| m.into())
+        .collect(),
+    }))
+  }
+
+  async fn get_server_info(&self, request: Request<()>) -> Result<Response<ServerInfo>, Status> {
+    self.check_admin(request.metadata())?;
+    Ok(Response::new(self.server_info().await?))
+  }
+
+  async fn register_webhook(
+    &self,
+    request: Request<RegisterWebhookRequest>,
+  ) -> Result<Response<WebhookObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self.register_webhook(&tenant, request.into_inner()).await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn define_custom_field(
+    &self,
+    request: Request<DefineCustomFieldRequest>,
+  ) -> Result<Response<CustomFieldObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .define_custom_field(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn list_custom_fields(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<CustomFieldList>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let fields = self.custom_fields.lock().await.list(&tenant)?;
+    Ok(Response::new(CustomFieldList {
+      fields: fields.into_iter().map(|f| f.into()).collect(),
+    }))
+  }
+
+  async fn set_custom_fields(
+    &self,
+    request: Request<SetCustomFieldsRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let editor_uid = editor_from_metadata(request.metadata())?;
+    let res = self
+      .set_custom_fields(&tenant, editor_uid, request.into_inner())
+      .await?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn create_segment(
+    &self,
+    request: Request<CreateSegmentRequest>,
+  ) -> Result<Response<SegmentObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let res = self
+      .create_segment(&tenant, role, request.into_inner())
+      .await?;
+    Ok(Response::new(segment_to_obj(res, None)))
+  }
+
+  async fn list_segments(&self, request: Request<()>) -> Result<Response<SegmentList>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let mut segments = self.segments.lock().await;
+    let mut res = Vec::new();
+    for segment in segments.list(&tenant)? {
+      let membership = segments.membership(&tenant, segment.id)?;
+      res.push(segment_to_obj(segment, membership));
+    }
+    Ok(Response::new(SegmentList { segments: res }))
+  }
+
+  async fn get_segment_members(
+    &self,
+    request: Request<GetSegmentMembersRequest>,
+  ) -> Result<Response<CustomerIds>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let deadline = Deadline::from_metadata(request.metadata());
+    let res = self
+      .segment_members(&tenant, &deadline, role, request.into_inner())
+      .await?;
+    Ok(Response::new(CustomerIds { customer_ids: res }))
+  }
+
+  async fn append_communication(
+    &self,
+    request: Request<AppendCommunicationRequest>,
+  ) -> Result<Response<CommunicationObj>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .append_communication(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn get_communications(
+    &self,
+    request: Request<GetCommunicationsRequest>,
+  ) -> Result<Response<CommunicationList>, Status> {
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .get_communications(&tenant, request.into_inner())
+      .await?;
+    Ok(Response::new(CommunicationList {
+      communications: res.into_iter().map(|c| c.into()).collect(),
+    }))
+  }
+
+  async fn list_webhooks(&self, request: Request<()>) -> Result<Response<WebhookList>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let webhooks = self.webhooks.list(&tenant).await;
+    Ok(Response::new(WebhookList {
+      webhooks: webhooks.into_iter().map(|w| w.into()).collect(),
+    }))
+  }
+
+  async fn delete_webhook(
+    &self,
+    request: Request<DeleteWebhookRequest>,
+  ) -> Result<Response<WebhookObj>, Status> {
+    self.check_admin(request.metadata())?;
+    self.check_writable()?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .webhooks
+      .delete(&tenant, request.into_inner().webhook_id)
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn get_suspicious_changes(
+    &self,
+    request: Request<GetSuspiciousChangesRequest>,
+  ) -> Result<Response<SuspiciousChangeList>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let changes = self
+      .anomalies
+      .lock()
+      .await
+      .list(&tenant, request.into_inner().include_reviewed);
+    Ok(Response::new(SuspiciousChangeList {
+      changes: changes.into_iter().map(|c| c.into()).collect(),
+    }))
+  }
+
+  async fn list_pending_changes(
+    &self,
+    request: Request<ListPendingChangesRequest>,
+  ) -> Result<Response<PendingChangeList>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .pending_changes
+      .lock()
+      .await
+      .list(&tenant, request.into_inner().customer_id)?;
+    Ok(Response::new(PendingChangeList {
+      changes: res.into_iter().map(|c| c.into()).collect(),
+    }))
+  }
+
+  async fn approve_change(
+    &self,
+    request: Request<DecideChangeRequest>,
+  ) -> Result<Response<CustomerObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let role = role_from_metadata(request.metadata())?;
+    let (_, res) = self
+      .decide_change(&tenant, request.into_inner(), true)
+      .await?;
+    let res = res.ok_or_else(|| ServiceError::internal_error("Approved change has no customer"))?;
+    Ok(Response::new(customer_to_obj(res, role)))
+  }
+
+  async fn reject_change(
+    &self,
+    request: Request<DecideChangeRequest>,
+  ) -> Result<Response<PendingChangeObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let (res, _) = self
+      .decide_change(&tenant, request.into_inner(), false)
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn list_erasure_requests(
+    &self,
+    request: Request<ListErasureRequestsRequest>,
+  ) -> Result<Response<ErasureRequestList>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .erasures
+      .lock()
+      .await
+      .list(&tenant, request.into_inner().include_closed)?;
+    Ok(Response::new(ErasureRequestList {
+      requests: res.into_iter().map(|r| r.into()).collect(),
+    }))
+  }
+
+  async fn approve_erasure(
+    &self,
+    request: Request<DecideErasureRequest>,
+  ) -> Result<Response<ErasureRequestObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .decide_erasure(&tenant, request.into_inner(), true)
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn reject_erasure(
+    &self,
+    request: Request<DecideErasureRequest>,
+  ) -> Result<Response<ErasureRequestObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let res = self
+      .decide_erasure(&tenant, request.into_inner(), false)
+      .await?;
+    Ok(Response::new(res.into()))
+  }
+
+  async fn review_suspicious_change(
+    &self,
+    request: Request<ReviewSuspiciousChangeRequest>,
+  ) -> Result<Response<SuspiciousChangeObj>, Status> {
+    self.check_admin(request.metadata())?;
+    let tenant = tenant_from_metadata(request.metadata())?;
+    let r = request.into_inner();
+    let res = self.anomalies.lock().await.review(
+      &tenant,
+      r.change_id,
+      r.reviewed_by,
+      chrono::Utc::now(),
+    )?;
+    Ok(Response::new(res.into()))
+  }
+}
+
+// Run the service, or the admin CLI command given in the arguments
+pub async fn run() -> prelude::ServiceResult<()> {
+  let args = match cli::parse(std::env::args()) {
+    Ok(args) => args,
+    Err(error) => {
+      eprintln!("{}\n\n{}", error, cli::USAGE);
+      std::process::exit(2);
+    }
+  };
+
+  let data_dir = PathBuf::from("data");
+
+  // Load validation policy
+  let policy_path =
+    std::env::var("VALIDATION_POLICY_PATH").unwrap_or_else(|_| "validation_policy.yaml".into());
+  let policy = Arc::new(
+    policy::PolicyHolder::load(&PathBuf::from(policy_path))
+      .expect("Error while loading validation policy"),
+  );
+
+  // Load data retention policy
+  let retention_path =
+    std::env::var("RETENTION_POLICY_PATH").unwrap_or_else(|_| "retention_policy.yaml".into());
+  let retention = Arc::new(
+    retention::RetentionPolicy::load_or_default(&PathBuf::from(retention_path))
+      .expect("Error while loading retention policy"),
+  );
+
+  // Load customer quotas
+  let quota_path =
+    std::env::var("QUOTA_POLICY_PATH").unwrap_or_else(|_| "quota_policy.yaml".into());
+  let quota = Arc::new(
+    quota::QuotaPolicy::load_or_default(&PathBuf::from(quota_path))
+      .expect("Error while loading quota policy"),
+  );
+
+  // Customer IDs per shard of the sharded backend
+  let shard_size = std::env::var("STORAGE_SHARD_SIZE")
+    .map(|size| {
+      size
+        .parse()
+        .expect("Error while parsing STORAGE_SHARD_SIZE")
+    })
+    .unwrap_or(tenant::DEFAULT_SHARD_SIZE);
+  // Customer storage backend
+  let backend = tenant::Backend::from_name(
+    &std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "vecpack".into()),
+  )
+  .and_then(|backend| backend.with_shard_size(shard_size))
+  .expect("Error while selecting storage backend");
+  // Shadow storage validating a backend migration
+  let shadow = std::env::var("STORAGE_SHADOW_BACKEND").ok().map(|name| {
+    let shadow = tenant::Backend::from_name(&name)
+      .and_then(|shadow| shadow.with_shard_size(shard_size))
+      .expect("Error while selecting shadow storage");
+    backend
+      .check_shadow(shadow)
+      .expect("Error while selecting shadow storage");
+    shadow
+  });
+
+  // Single writer guard, the lock is held until the process stops
+  // A locked data directory only allows read only serving
+  let when_locked = instance_lock::WhenLocked::from_name(
+    &std::env::var("DATA_DIR_LOCKED").unwrap_or_else(|_| "refuse".into()),
+  )
+  .expect("Error while reading DATA_DIR_LOCKED");
+  let instance_lock = match instance_lock::acquire(&data_dir)
+    .expect("Error while locking data directory")
+  {
+    Ok(lock) => Some(lock),
+    Err(holder) => {
+      let read_only = when_locked == instance_lock::WhenLocked::ReadOnly
+        && args.command == cli::Command::Serve
+        && !args.quarantine
+        && args.migrate_from.is_none();
+      if args.writes() && !read_only {
+        eprintln!(
+          "Data directory {} is used by an other instance ({}). Stop it, or set DATA_DIR_LOCKED=read_only to start read only",
+          data_dir.display(),
+          holder
+        );
+        std::process::exit(1);
+      }
+      if read_only {
+        eprintln!(
+          "Data directory {} is used by an other instance ({}), starting read only",
+          data_dir.display(),
+          holder
+        );
+      }
+      None
+    }
+  };
+  let locked_out = instance_lock.is_none();
+
+  // Startup integrity check
+  if args.verify {
+    integrity::verify_all(&data_dir, &policy.get(), args.quarantine)
+      .expect("Error while checking customers storage");
+  }
+
+  // One-shot cutover from the legacy customer service
+  if let Some(path) = &args.migrate_from {
+    match migrate::migrate(&data_dir, &args.tenant, backend, shadow, path) {
+      Ok(migrate::Migration::Migrated(count)) => {
+        println!("Migrated {} customers from {}", count, path.display())
+      }
+      Ok(migrate::Migration::Skipped(count)) => println!(
+        "Storage already has {} customers, legacy dump {} is not migrated",
+        count,
+        path.display()
+      ),
+      Err(error) => {
+        eprintln!("Legacy migration failed: {}", error);
+        std::process::exit(1);
+      }
+    }
+  }
+
+  // Run admin command without starting the server
+  if args.command != cli::Command::Serve {
+    return cli::run(&data_dir, backend, shadow, args, &policy.get());
+  }
+
+  // Customers db for all tenants, loaded by the warm-up below
+  // Customer writes are journaled for standbys
+  let mut tenants = Tenants::new(data_dir.clone(), backend);
+  if let Some(shadow) = shadow {
+    tenants
+      .set_shadow(shadow)
+      .expect("Error while selecting shadow storage");
+  }
+  let journal = Arc::new(replication::Journal::new());
+  tenants.set_journal(journal.clone());
+  // Customer changes are written to the mutation audit log
+  match std::env::var("AUDIT_SINK").as_deref() {
+    Err(_) | Ok("none") => (),
+    Ok("file") => {
+      let path = std::env::var("AUDIT_FILE_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir.join("mutations.jsonl"));
+      let max_bytes = match std::env::var("AUDIT_FILE_MAX_BYTES") {
+        Ok(size) => size.parse().expect("AUDIT_FILE_MAX_BYTES must be a number"),
+        Err(_) => 100 * 1024 * 1024,
+      };
+      let keep = match std::env::var("AUDIT_FILE_KEEP") {
+        Ok(keep) => keep.parse().expect("AUDIT_FILE_KEEP must be a number"),
+        Err(_) => 10,
+      };
+      let sink = audit_sink::FileSink::new(path, max_bytes, keep);
+      tenants.set_mutation_log(Arc::new(audit_sink::MutationLog::new(sink)));
+    }
+    Ok("syslog") => {
+      let path = std::env::var("AUDIT_SYSLOG_PATH").unwrap_or_else(|_| "/dev/log".into());
+      let sink = audit_sink::SyslogSink::new(PathBuf::from(path))
+        .expect("Error while opening syslog socket");
+      tenants.set_mutation_log(Arc::new(audit_sink::MutationLog::new(sink)));
+    }
+    Ok("grpc") => {
+      let endpoint =
+        std::env::var("AUDIT_ENDPOINT").expect("AUDIT_ENDPOINT must be set for the grpc sink");
+      let sink = audit_sink::GrpcSink::start(&endpoint, data_dir.join("audit_overflow.jsonl"))
+        .expect("Error while starting audit service client");
+      tenants.set_mutation_log(Arc::new(audit_sink::MutationLog::new(sink)));
+    }
+    Ok(sink) => panic!(
+      "Unknown AUDIT_SINK: {}, use none, file, syslog or grpc",
+      sink
+    ),
+  }
+  // Field diffs of customer changes, for GetFieldHistory
+  tenants.set_history(Arc::new(history::FieldHistory::new(&data_dir)));
+  // Customer changes stage their events in the outbox
+  let outbox_max_pending = match std::env::var("OUTBOX_MAX_PENDING") {
+    Ok(max) => max.parse().expect("OUTBOX_MAX_PENDING must be a number"),
+    Err(_) => outbox::DEFAULT_MAX_PENDING,
+  };
+  let outbox = Arc::new(
+    outbox::Outbox::load(&data_dir)
+      .expect("Error while loading event outbox")
+      .with_max_pending(outbox_max_pending),
+  );
+  tenants.set_outbox(outbox.clone());
+  // Standby mode, customers are replicated from the primary
+  let replicate_from = std::env::var("REPLICATE_FROM").ok();
+  let read_only = replicate_from.is_some() || locked_out;
+  tenants.set_read_only(read_only);
+  // Address changes stage their notifications to the shipping service
+  if let Ok(endpoint) = std::env::var("SHIPPING_ENDPOINT") {
+    tenants.set_shipping(
+      shipping::load(&data_dir, &endpoint, !read_only)
+        .expect("Error while loading shipping notifications"),
+    );
+  }
+  let tenants = Arc::new(tenants);
+
+  // Load the customers of every tenant while the listeners are up
+  // Requests wait for it, the health service reports NOT_SERVING until then
+  let warm_up = tenants.clone();
+  tokio::spawn(async move {
+    if let Err(error) = warm_up.warm_up().await {
+      eprintln!("Error while loading customers storage: {}", error);
+      std::process::exit(1);
+    }
+  });
+  let health = health::HealthService::new(tenants.ready());
+
+  // Reload storage from disk and validation policy on SIGHUP
+  tokio::spawn(reload_on_hangup(tenants.clone()));
+  tokio::spawn(policy::reload_on_hangup(policy.clone()));
+
+  // Load zip code db
+  let zip_db_path = std::env::var("ZIP_DB_PATH").unwrap_or_else(|_| "data/zip_codes.csv".into());
+  let zip_db = zip::ZipDb::load_or_empty(&PathBuf::from(zip_db_path))
+    .expect("Error while loading zip code db");
+
+  // Caller tokens granting roles
+  auth::set_role_tokens(
+    auth::parse_role_tokens(&std::env::var("ROLE_TOKENS").unwrap_or_default())
+      .expect("Error while parsing ROLE_TOKENS"),
+  );
+
+  // Admin token
+  let admin_token = std::env::var("ADMIN_TOKEN").ok();
+
+  // Follow the primary in standby mode
+  // The admin token of the primary defaults to our own
+  if let Some(primary) = replicate_from.clone() {
+    let primary_token = std::env::var("REPLICATE_ADMIN_TOKEN")
+      .ok()
+      .or_else(|| admin_token.clone());
+    tokio::spawn(replication::follow(primary, primary_token, tenants.clone()));
+  }
+
+  // Idempotency key TTL
+  let idempotency_ttl = match std::env::var("IDEMPOTENCY_TTL_SECS") {
+    Ok(ttl) => Duration::from_secs(ttl.parse().expect("IDEMPOTENCY_TTL_SECS must be a number")),
+    Err(_) => Duration::from_secs(24 * 60 * 60),
+  };
+
+  // Stream response channel size
+  let stream_buffer = match std::env::var("STREAM_BUFFER_SIZE") {
+    Ok(size) => match size.parse::<usize>() {
+      Ok(size) if size > 0 => size,
+      _ => panic!("STREAM_BUFFER_SIZE must be a positive number"),
+    },
+    Err(_) => 100,
+  };
+
+  // RPC metrics and slow query log
+  let slow_query_threshold = match std::env::var("SLOW_QUERY_THRESHOLD_MS") {
+    Ok(ms) => ms
+      .parse::<u64>()
+      .expect("SLOW_QUERY_THRESHOLD_MS must be a number"),
+    Err(_) => 1000,
+  };
+  let slow_query_log = match slow_query_threshold {
+    0 => None,
+    ms => Some(metrics::SlowQueryLog {
+      path: std::env::var("SLOW_QUERY_LOG_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir.join("slow_queries.jsonl")),
+      threshold: Duration::from_millis(ms),
+    }),
+  };
+  let metrics = Arc::new(metrics::Metrics::new(slow_query_log));
+
+  // Load webhooks and start their deliveries
+  let webhooks =
+    Arc::new(webhook::Webhooks::load(&data_dir, !read_only).expect("Error while loading webhooks"));
+
+  // Deliver stored events to Watch subscribers and webhooks
+  let events = Arc::new(events::Events::new());
+  let sinks: Vec<Arc<dyn outbox::EventSink>> = vec![events.clone(), webhooks.clone()];
+  if !read_only {
+    tokio::spawn(outbox::run_delivery(outbox.clone(), sinks));
+  }
+
+  // Guard tax number changes of invoiced customers
+  let tax_guard = match std::env::var("TAX_NUMBER_GUARD").as_deref() {
+    Err(_) | Ok("off") => None,
+    Ok("on") => Some(
+      tax_guard::TaxNumberGuard::new(std::env::var("INVOICE_ENDPOINT").ok().as_deref())
+        .expect("Error while starting invoice service client"),
+    ),
+    Ok(guard) => panic!("Unknown TAX_NUMBER_GUARD: {}, use on or off", guard),
+  };
+
+  // Purchase and invoice statistics of GetCustomerWithStats
+  let enrichment = enrichment::Enrichment::new(
+    std::env::var("PURCHASE_ENDPOINT").ok().as_deref(),
+    std::env::var("INVOICE_ENDPOINT").ok().as_deref(),
+  )
+  .expect("Error while starting purchase and invoice service clients");
+
+  // Creator names of the expanded customer view
+  let user_names_ttl = match std::env::var("USER_NAMES_TTL_SECS") {
+    Ok(ttl) => Duration::from_secs(ttl.parse().expect("USER_NAMES_TTL_SECS must be a number")),
+    Err(_) => user_names::DEFAULT_TTL,
+  };
+  let user_names = user_names::UserNames::new(
+    std::env::var("USER_ENDPOINT").ok().as_deref(),
+    user_names_ttl,
+  )
+  .expect("Error while starting user service client");
+
+  // Erasure requests, shared with the retention runs
+  let erasures = Arc::new(Mutex::new(erasure::ErasureRequests::new(&data_dir)));
+
+  // Custom field definitions and segments, shared with the segment evaluation
+  let custom_fields = Arc::new(Mutex::new(custom_field::CustomFields::new(&data_dir)));
+  let segments = Arc::new(Mutex::new(segment::Segments::new(&data_dir)));
+
+  // Evaluate segments periodically, 0 turns it off
+  let segment_refresh_secs = match std::env::var("SEGMENT_REFRESH_SECS") {
+    Ok(secs) => secs
+      .parse::<u64>()
+      .expect("SEGMENT_REFRESH_SECS must be a number"),
+    Err(_) => 60 * 60,
+  };
+  if !read_only && segment_refresh_secs > 0 {
+    tokio::spawn(segment::run(
+      Duration::from_secs(segment_refresh_secs),
+      tenants.clone(),
+      custom_fields.clone(),
+      segments.clone(),
+      outbox.clone(),
+    ));
+  }
+
+  // Apply data retention rules and erasure requests periodically
+  // Standbys get the results from the primary
+  if !read_only {
+    // Deletes the documents of anonymized customers
+    let documents =
+      documents::DocumentStore::new(std::env::var("DOCUMENT_ENDPOINT").ok().as_deref())
+        .expect("Error while starting document store client");
+    tokio::spawn(retention::run(
+      retention.clone(),
+      tenants.clone(),
+      erasures.clone(),
+      Arc::new(documents),
+      audit::AuditLog::new(&data_dir),
+    ));
+  }
+
+  // Init customer service
+  let customer_service = CustomerService::init(
+    tenants,
+    zip_db,
+    admin_token,
+    events,
+    webhooks,
+    tax_guard,
+    enrichment,
+    user_names,
+    erasures,
+    custom_fields,
+    segments,
+    idempotency_ttl,
+    policy,
+    retention,
+    quota,
+    metrics.clone(),
+    journal,
+    stream_buffer,
+  );
+
+  // Listen addresses
+  // Admin RPCs are served only on the admin listeners, if there are any
+  let listen_addrs = listener::parse_addrs(
+    &std::env::var("LISTEN_ADDRS").unwrap_or_else(|_| listener::DEFAULT_LISTEN_ADDRS.into()),
+  )
+  .expect("Error while parsing LISTEN_ADDRS");
+  let admin_listen_addrs =
+    listener::parse_addrs(&std::env::var("ADMIN_LISTEN_ADDRS").unwrap_or_default())
+      .expect("Error while parsing ADMIN_LISTEN_ADDRS");
+  let separate_admin = !admin_listen_addrs.is_empty();
+  // Unix socket for clients on the same host, same rules as LISTEN_ADDRS
+  let unix_socket_path = std::env::var("UNIX_SOCKET_PATH").ok().map(PathBuf::from);
+  let listeners = listen_addrs
+    .iter()
+    .map(|addr| (listener::ListenAddr::Tcp(*addr), !separate_admin))
+    .chain(
+      unix_socket_path
+        .iter()
+        .map(|path| (listener::ListenAddr::Unix(path.clone()), !separate_admin)),
+    )
+    .chain(
+      admin_listen_addrs
+        .iter()
+        .map(|addr| (listener::ListenAddr::Tcp(*addr), true)),
+    )
+    .collect::<Vec<_>>();
+  if listeners.is_empty() {
+    panic!("LISTEN_ADDRS or UNIX_SOCKET_PATH must be set");
+  }
+
+  // Max request size, larger requests are rejected before they are read
+  let max_request_bytes = match std::env::var("MAX_REQUEST_BYTES") {
+    Ok(size) => match size.parse::<usize>() {
+      Ok(size) if size > 0 => size,
+      _ => panic!("MAX_REQUEST_BYTES must be a positive number"),
+    },
+    Err(_) => payload::DEFAULT_MAX_REQUEST_BYTES,
+  };
+
+  // Write throttling per caller, 0 writes per second turns it off
+  let write_rate = match std::env::var("WRITE_RATE_PER_SEC") {
+    Ok(rate) => match rate.parse::<f64>() {
+      Ok(rate) if rate >= 0.0 && rate.is_finite() => rate,
+      _ => panic!("WRITE_RATE_PER_SEC must be a non-negative number"),
+    },
+    Err(_) => 0.0,
+  };
+  let write_burst = match std::env::var("WRITE_BURST") {
+    Ok(burst) => match burst.parse::<u32>() {
+      Ok(burst) if burst > 0 => burst,
+      _ => panic!("WRITE_BURST must be a positive number"),
+    },
+    Err(_) => throttle::DEFAULT_BURST,
+  };
+  let limiter = match write_rate > 0.0 {
+    true => Some(Arc::new(throttle::Limiter::new(write_rate, write_burst))),
+    false => None,
+  };
+
+  // Create shutdown channel
+  let (tx, rx) = watch::channel(());
+
+  // Reflection service
+  // so grpcurl and others can introspect the API
+  let reflection_service = tonic_reflection::server::Builder::configure()
+    .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+    .build()
+    .expect("Error while building reflection service");
+
+  let service = compression::Compression::new(payload::PayloadGuard::new(
+    metrics::Instrument::new(
+      throttle::Throttle::new(
+        locale::Negotiate::new(audit_sink::Scope::new(CustomerServer::with_interceptor(
+          customer_service,
+          auth::interceptor,
+        ))),
+        limiter,
+        metrics.clone(),
+      ),
+      metrics,
+    ),
+    max_request_bytes,
+  ));
+
+  // Spawn a server for every listener into the runtime
+  for (addr, admin_allowed) in listeners {
+    let incoming = addr
+      .bind()
+      .unwrap_or_else(|e| panic!("Error while binding {}: {}", addr, e));
+    println!(
+      "Listening on {}{}",
+      addr,
+      if admin_allowed {
+        ""
+      } else {
+        " (no admin RPCs)"
+      }
+    );
+    let router = Server::builder()
+      .add_service(listener::Listener::new(service.clone(), admin_allowed))
+      .add_service(reflection_service.clone())
+      .add_service(proto::health::health_server::HealthServer::new(
+        health.clone(),
+      ));
+    let mut rx = rx.clone();
+    tokio::task::spawn(async move {
+      let shutdown = async move {
+        let _ = rx.changed().await;
+      };
+      let res = match incoming {
+        listener::Incoming::Tcp(incoming) => {
+          router
+            .serve_with_incoming_shutdown(listener::tcp_connections(incoming), shutdown)
+            .await
+        }
+        listener::Incoming::Unix(incoming) => {
+          router
+            .serve_with_incoming_shutdown(listener::unix_connections(incoming), shutdown)
+            .await
+        }
+      };
+      if let Err(error) = res {
+        eprintln!("Error while serving {}: {}", addr, error);
+      }
+    });
+  }
+
+  // REST/JSON gateway, off by default
+  // Admin RPCs are not served, as on listeners without them
+  if let Ok(addr) = std::env::var("REST_LISTEN_ADDR") {
+    let addr: std::net::SocketAddr = addr
+      .trim()
+      .parse()
+      .expect("REST_LISTEN_ADDR must be a socket address");
+    let service = listener::Listener::new(service.clone(), false);
+    let mut rx = rx.clone();
+    println!("REST gateway listening on {}", addr);
+    tokio::task::spawn(async move {
+      let shutdown = async move {
+        let _ = rx.changed().await;
+      };
+      if let Err(error) = rest::serve(addr, service, max_request_bytes, shutdown).await {
+        eprintln!("Error while serving REST gateway on {}: {}", addr, error);
+      }
+    });
+  }
+
+  // gRPC-web for browser clients, off by default
+  // Admin RPCs are not served, as on listeners without them
+  if let Ok(addr) = std::env::var("GRPC_WEB_LISTEN_ADDR") {
+    let addr: std::net::SocketAddr = addr
+      .trim()
+      .parse()
+      .expect("GRPC_WEB_LISTEN_ADDR must be a socket address");
+    let origins = grpc_web::AllowedOrigins::parse(
+      &std::env::var("GRPC_WEB_ALLOWED_ORIGINS").unwrap_or_default(),
+    );
+    let service = grpc_web::GrpcWeb::new(
+      listener::Listener::new(service.clone(), false),
+      origins,
+      max_request_bytes,
+    );
+    let mut rx = rx.clone();
+    println!("gRPC-web listening on {}", addr);
+    tokio::task::spawn(async move {
+      let shutdown = async move {
+        let _ = rx.changed().await;
+      };
+      if let Err(error) = grpc_web::serve(addr, service, shutdown).await {
+        eprintln!("Error while serving gRPC-web on {}: {}", addr, error);
+      }
+    });
+  }
+
+  tokio::signal::ctrl_c().await.unwrap();
+
+  println!("SIGINT");
+
+  // Send shutdown signal after SIGINT received
+  let _ = tx.send(());
+
+  // Remove socket file, so no client connects to a stopped server
+  if let Some(path) = unix_socket_path {
+    let _ = std::fs::remove_file(path);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use fixture::Fixture;
+  use tonic::codegen::http;
+  use tonic::Code;
+
+  fn fixture() -> Fixture {
+    Fixture::new("memory", Some("unique_email: true\n"))
+  }
+
+  fn admin<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+      .metadata_mut()
+      .insert("admin-token", "admin".parse().unwrap());
+    request
+  }
+
+  fn code<T>(res: Result<Response<T>, Status>) -> Option<Code> {
+    res.err().map(|status| status.code())
+  }
+
+  async fn create(f: &Fixture, email: &str) -> Result<Response<CustomerObj>, Status> {
+    Customer::quick_create(
+      &f.service,
+      Request::new(QuickCreateRequest {
+        name: "Kiss Béla".to_string(),
+        phone: "+36301234567".to_string(),
+        created_by: 1,
+        customer_id: 0,
+      }),
+    )
+    .await?;
+    let mut customer = Customer::get_by_id(
+      &f.service,
+      Request::new(GetByIdRequest {
+        customer_id: 1,
+        expanded: false,
+      }),
+    )
+    .await?
+    .into_inner();
+    customer.email = email.to_string();
+    Customer::update_by_id(&f.service, admin(customer)).await
+  }
+
+  #[tokio::test]
+  async fn test_get_all_summaries() {
+    use tokio_stream::StreamExt;
+    let f = fixture();
+    let s = &f.service;
+    for (name, phone) in [("Nagy Anna", "+36307654321"), ("Kiss Béla", "+36301234567")] {
+      Customer::quick_create(
+        s,
+        Request::new(QuickCreateRequest {
+          name: name.to_string(),
+          phone: phone.to_string(),
+          created_by: 1,
+          customer_id: 0,
+        }),
+      )
+      .await
+      .unwrap();
+    }
+    let summaries = |sort_by: SortBy| async move {
+      let request = Request::new(GetAllRequest {
+        sort_by: sort_by as i32,
+        ..Default::default()
+      });
+      Customer::get_all_summaries(s, request)
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|summary| summary.unwrap())
+        .collect::<Vec<CustomerSummary>>()
+        .await
+    };
+    let by_id = summaries(SortBy::SortUnspecified).await;
+    assert_eq!(by_id.iter().map(|c| c.id).collect::<Vec<u32>>(), [1, 2]);
+    assert_eq!(
+      (by_id[0].name.as_str(), by_id[0].phone.as_str()),
+      ("Nagy Anna", "+36307654321")
+    );
+    // Sorted as GetAll
+    let by_name = summaries(SortBy::SortName).await;
+    assert_eq!(by_name.iter().map(|c| c.id).collect::<Vec<u32>>(), [2, 1]);
+  }
+
+  #[tokio::test]
+  async fn test_find_customer_sorted_limit() {
+    let f = fixture();
+    let s = &f.service;
+    let names = ["Szabó Péter", "Nagy Péter", "Ács Péter"];
+    for (i, name) in names.iter().enumerate() {
+      Customer::quick_create(
+        s,
+        Request::new(QuickCreateRequest {
+          name: name.to_string(),
+          phone: format!("+3630123456{}", i),
+          created_by: 1,
+          customer_id: 0,
+        }),
+      )
+      .await
+      .unwrap();
+    }
+    // The first stored matches are not the first sorted ones
+    let request = FindCustomerRequest {
+      query: "péter".to_string(),
+      max_results: 2,
+      sort_by: SortBy::SortName as i32,
+      ..Default::default()
+    };
+    let found = Customer::find_customer(s, Request::new(request))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(found.customer_ids, [3, 2]);
+  }
+
+  #[tokio::test]
+  async fn test_custom_fields() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    let define = |name: &str, field_type: CustomFieldType| DefineCustomFieldRequest {
+      name: name.to_string(),
+      field_type: field_type as i32,
+      created_by: 1,
+      ..Default::default()
+    };
+    // Defining is admin only
+    let request = Request::new(define(
+      "greenhouse_size",
+      CustomFieldType::CustomFieldNumber,
+    ));
+    assert_eq!(
+      code(Customer::define_custom_field(s, request).await),
+      Some(Code::PermissionDenied)
+    );
+    for (name, field_type) in [
+      ("greenhouse_size", CustomFieldType::CustomFieldNumber),
+      ("organic", CustomFieldType::CustomFieldBool),
+    ] {
+      Customer::define_custom_field(s, admin(define(name, field_type)))
+        .await
+        .unwrap();
+    }
+    let fields = Customer::list_custom_fields(s, Request::new(()))
+      .await
+      .unwrap()
+      .into_inner()
+      .fields;
+    assert_eq!(fields.len(), 2);
+    let set = |values: &[(&str, &str)]| SetCustomFieldsRequest {
+      customer_id: 1,
+      values: values
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect(),
+    };
+    let customer =
+      Customer::set_custom_fields(s, Request::new(set(&[("greenhouse_size", "120,5")])))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(customer.custom_fields["greenhouse_size"], "120.5");
+    assert_eq!(
+      code(Customer::set_custom_fields(s, Request::new(set(&[("organic", "talán")]))).await),
+      Some(Code::InvalidArgument)
+    );
+    // Updates keep the custom fields
+    let mut update = customer.clone();
+    update.custom_fields.clear();
+    let customer = Customer::update_by_id(s, admin(update))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(customer.custom_fields.len(), 1);
+    let query = |query: &str| QueryCustomersRequest {
+      query: query.to_string(),
+      ..Default::default()
+    };
+    let ids = Customer::query_customers(s, Request::new(query("custom.greenhouse_size > 100")))
+      .await
+      .unwrap()
+      .into_inner()
+      .customer_ids;
+    assert_eq!(ids, [1]);
+    assert_eq!(
+      code(Customer::query_customers(s, Request::new(query("custom.color = zold"))).await),
+      Some(Code::InvalidArgument)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_segments() {
+    let f = fixture();
+    let s = &f.service;
+    let quick_create = |name: &str| QuickCreateRequest {
+      name: name.to_string(),
+      phone: "+36301234567".to_string(),
+      created_by: 1,
+      customer_id: 0,
+    };
+    Customer::quick_create(s, Request::new(quick_create("Kiss Kert Kft")))
+      .await
+      .unwrap();
+    Customer::quick_create(s, Request::new(quick_create("Nagy Anna")))
+      .await
+      .unwrap();
+    let segment = |name: &str, query: &str| CreateSegmentRequest {
+      name: name.to_string(),
+      query: query.to_string(),
+      created_by: 1,
+      ..Default::default()
+    };
+    let kft = Customer::create_segment(s, Request::new(segment("Kft-k", "name ~ kft")))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(
+      code(Customer::create_segment(s, Request::new(segment("Hibás", "name ~"))).await),
+      Some(Code::InvalidArgument)
+    );
+    assert_eq!(
+      code(Customer::create_segment(s, Request::new(segment("KFT-K", "active = true"))).await),
+      Some(Code::AlreadyExists)
+    );
+    // The query is checked against the role of the creator
+    let mut request = Request::new(segment("Adószámosak", "tax_number ~ 2"));
+    request
+      .metadata_mut()
+      .insert(auth::ROLE_METADATA_KEY, "sales".parse().unwrap());
+    assert_eq!(
+      code(Customer::create_segment(s, request).await),
+      Some(Code::PermissionDenied)
+    );
+    let segments = Customer::list_segments(s, Request::new(()))
+      .await
+      .unwrap()
+      .into_inner()
+      .segments;
+    assert_eq!(segments, std::slice::from_ref(&kft));
+    let members = |segment_id: u32| {
+      Customer::get_segment_members(
+        s,
+        Request::new(GetSegmentMembersRequest {
+          segment_id,
+          ..Default::default()
+        }),
+      )
+    };
+    assert_eq!(
+      members(kft.id).await.unwrap().into_inner().customer_ids,
+      [1]
+    );
+    // Members are evaluated on demand
+    Customer::quick_create(s, Request::new(quick_create("Zöld Kft")))
+      .await
+      .unwrap();
+    assert_eq!(
+      members(kft.id).await.unwrap().into_inner().customer_ids,
+      [1, 3]
+    );
+    assert_eq!(code(members(99).await), Some(Code::NotFound));
+    // Cached members are the ones of the last scheduled evaluation
+    let cached = || {
+      Customer::get_segment_members(
+        s,
+        Request::new(GetSegmentMembersRequest {
+          segment_id: kft.id,
+          cached: true,
+          ..Default::default()
+        }),
+      )
+    };
+    assert_eq!(code(cached().await), Some(Code::FailedPrecondition));
+    {
+      let customers = s.tenants.get("").await.unwrap();
+      let mut customers = customers.lock().await;
+      let mut segments = s.segments.lock().await;
+      segment::refresh(
+        "",
+        &mut customers,
+        &[],
+        &mut segments,
+        &outbox::Outbox::load_from_path(s.tenants.data_dir().join("segment_outbox")).unwrap(),
+        chrono::Utc::now(),
+      )
+      .await
+      .unwrap();
+    }
+    Customer::quick_create(s, Request::new(quick_create("Tavasz Kft")))
+      .await
+      .unwrap();
+    // The new match is not cached yet
+    assert_eq!(cached().await.unwrap().into_inner().customer_ids, [1, 3]);
+    let segments = Customer::list_segments(s, Request::new(()))
+      .await
+      .unwrap()
+      .into_inner()
+      .segments;
+    assert_eq!(segments[0].member_count, 2);
+    assert!(!segments[0].last_evaluated.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_tax_data_issues() {
+    let f = fixture();
+    let s = &f.service;
+    let policy_path = f.dir.path().join("validation_policy.yaml");
+    std::fs::write(&policy_path, "company_tax_number_required: false\n").unwrap();
+    Customer::reload_validation_policy(s, admin(()))
+      .await
+      .unwrap();
+    create(&f, "bela@kiss.hu").await.unwrap();
+    for phone in ["+36307654321", "+36307654322"] {
+      Customer::quick_create(
+        s,
+        Request::new(QuickCreateRequest {
+          name: "Kiss Kert Kft".to_string(),
+          phone: phone.to_string(),
+          created_by: 1,
+          customer_id: 0,
+        }),
+      )
+      .await
+      .unwrap();
+    }
+    for (customer_id, tax_number) in [(1, ""), (2, "66064590-2-35"), (3, "66064590-2-35")] {
+      let mut customer = Customer::get_by_id(
+        s,
+        Request::new(GetByIdRequest {
+          customer_id,
+          expanded: false,
+        }),
+      )
+      .await
+      .unwrap()
+      .into_inner();
+      customer.kind = proto::customer::CustomerKind::KindCompany as i32;
+      customer.tax_number = tax_number.to_string();
+      customer.email = format!("{}@kiss.hu", customer_id);
+      Customer::update_by_id(s, admin(customer)).await.unwrap();
+    }
+    let report = |after_customer_id: u32, limit: u32| {
+      let mut request = Request::new(GetTaxDataIssuesRequest {
+        after_customer_id,
+        limit,
+      });
+      request
+        .metadata_mut()
+        .insert(auth::ROLE_METADATA_KEY, "sales".parse().unwrap());
+      Customer::get_tax_data_issues(s, request)
+    };
+    let page = report(0, 0).await.unwrap().into_inner();
+    assert_eq!((page.total, page.next_after_customer_id), (1, 0));
+    assert_eq!(
+      page.customers[0].issues[0].kind,
+      TaxDataIssueKind::TaxIssueMissingTaxNumber as i32
+    );
+    // Shared tax numbers are issues once they must be unique
+    std::fs::write(
+      &policy_path,
+      "company_tax_number_required: false\nunique_tax_number: true\n",
+    )
+    .unwrap();
+    Customer::reload_validation_policy(s, admin(()))
+      .await
+      .unwrap();
+    let first = report(0, 2).await.unwrap().into_inner();
+    assert_eq!((first.total, first.next_after_customer_id), (3, 2));
+    let ids = |page: &TaxDataIssueReport| {
+      page
+        .customers
+        .iter()
+        .map(|c| c.customer_id)
+        .collect::<Vec<u32>>()
+    };
+    assert_eq!(ids(&first), [1, 2]);
+    // Tax numbers are shaped for the role
+    assert_ne!(first.customers[1].tax_number, "66064590-2-35");
+    let last = report(first.next_after_customer_id, 2)
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!((ids(&last), last.next_after_customer_id), (vec![3], 0));
+    assert_eq!(
+      last.customers[0].issues[0].kind,
+      TaxDataIssueKind::TaxIssueDuplicateTaxNumber as i32
+    );
+  }
+
+  #[tokio::test]
+  async fn test_set_discount() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    let request = |default_discount_percent: u32, set_by: u32| {
+      Request::new(SetDiscountRequest {
+        customer_id: 1,
+        default_discount_percent,
+        set_by,
+      })
+    };
+    let customer = Customer::set_discount(s, request(12, 7))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(
+      (customer.default_discount_percent, customer.discount_set_by),
+      (12, 7)
+    );
+    assert!(!customer.discount_set_at.is_empty());
+    assert_eq!(
+      code(Customer::set_discount(s, request(101, 7)).await),
+      Some(Code::InvalidArgument)
+    );
+    assert_eq!(
+      code(Customer::set_discount(s, request(10, 0)).await),
+      Some(Code::InvalidArgument)
+    );
+    // Set by the editor only
+    let mut by_other = request(10, 8);
+    by_other
+      .metadata_mut()
+      .insert("editor-uid", "7".parse().unwrap());
+    assert_eq!(
+      code(Customer::set_discount(s, by_other).await),
+      Some(Code::PermissionDenied)
+    );
+    let mut by_editor = request(10, 7);
+    by_editor
+      .metadata_mut()
+      .insert("editor-uid", "7".parse().unwrap());
+    assert!(Customer::set_discount(s, by_editor).await.is_ok());
+    // Updates keep the discount
+    let mut customer = Customer::get_by_id(
+      s,
+      Request::new(GetByIdRequest {
+        customer_id: 1,
+        expanded: false,
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    assert_eq!(customer.default_discount_percent, 10);
+    customer.default_discount_percent = 50;
+    let customer = Customer::update_by_id(s, admin(customer))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(customer.default_discount_percent, 10);
+  }
+
+  #[tokio::test]
+  async fn test_blocked_customers() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    let is_blocked =
+      |customer_id: u32| Customer::is_blocked(s, Request::new(IsBlockedRequest { customer_id }));
+    let status = is_blocked(1).await.unwrap().into_inner();
+    assert!(!status.blocked);
+    assert_eq!(code(is_blocked(2).await), Some(Code::NotFound));
+    Customer::deactivate(
+      s,
+      Request::new(DeactivateRequest {
+        customer_id: 1,
+        reason_code: proto::customer::ReasonCode::ReasonDebt as i32,
+        comment: "Tartozás".to_string(),
+        changed_by: 2,
+      }),
+    )
+    .await
+    .unwrap();
+    let status = is_blocked(1).await.unwrap().into_inner();
+    assert!(status.blocked);
+    assert_eq!(
+      status.reason_code,
+      proto::customer::ReasonCode::ReasonDebt as i32
+    );
+    assert!(!status.blocked_since.is_empty());
+    let blocked = Customer::get_blocked_customers(s, Request::new(()))
+      .await
+      .unwrap()
+      .into_inner()
+      .customers;
+    assert_eq!(blocked, vec![status]);
+    Customer::reactivate(
+      s,
+      Request::new(ReactivateRequest {
+        customer_id: 1,
+        comment: String::new(),
+        changed_by: 2,
+      }),
+    )
+    .await
+    .unwrap();
+    assert!(!is_blocked(1).await.unwrap().into_inner().blocked);
+  }
+
+  #[tokio::test]
+  async fn test_expanded_view() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    // Without user service the customer is returned without the name
+    let customer = Customer::get_by_id(
+      s,
+      Request::new(GetByIdRequest {
+        customer_id: 1,
+        expanded: true,
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    assert_eq!(
+      (customer.created_by, customer.created_by_name.as_str()),
+      (1, "")
+    );
+    let names = vec![(1, "Kiss Béla".to_string())].into_iter().collect();
+    assert_eq!(
+      with_created_by_name(customer, &names).created_by_name,
+      "Kiss Béla"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_customer_with_stats() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    Customer::record_activity(
+      s,
+      Request::new(RecordActivityRequest {
+        customer_id: 1,
+        kind: ActivityKind::ActivityPurchase as i32,
+        ..Default::default()
+      }),
+    )
+    .await
+    .unwrap();
+    let res = Customer::get_customer_with_stats(
+      s,
+      Request::new(GetByIdRequest {
+        customer_id: 1,
+        expanded: false,
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    assert_eq!(res.customer.unwrap().email, "bela@kiss.hu");
+    // Without endpoints the recorded activity is returned
+    assert_eq!(res.unavailable, ["purchase", "invoice"]);
+    assert!(!res.last_purchase_at.is_empty());
+    assert!(res.last_invoice_at.is_empty());
+    assert_eq!(
+      code(
+        Customer::get_customer_with_stats(
+          s,
+          Request::new(GetByIdRequest {
+            customer_id: 9,
+            expanded: false,
+          })
+        )
+        .await
+      ),
+      Some(Code::NotFound)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_communications() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    let append = |customer_id: u32, channel: CommunicationChannel| AppendCommunicationRequest {
+      customer_id,
+      channel: channel as i32,
+      direction: CommunicationDirection::DirectionOutbound as i32,
+      summary: "Számla elküldve".to_string(),
+      created_by: 7,
+    };
+    let request = Request::new(append(1, CommunicationChannel::ChannelEmail));
+    Customer::append_communication(s, request).await.unwrap();
+    let request = Request::new(append(1, CommunicationChannel::ChannelUnspecified));
+    assert_eq!(
+      code(Customer::append_communication(s, request).await),
+      Some(Code::InvalidArgument)
+    );
+    let request = Request::new(append(9, CommunicationChannel::ChannelSms));
+    assert_eq!(
+      code(Customer::append_communication(s, request).await),
+      Some(Code::NotFound)
+    );
+    let request = Request::new(GetCommunicationsRequest {
+      customer_id: 1,
+      limit: 0,
+    });
+    let communications = Customer::get_communications(s, request)
+      .await
+      .unwrap()
+      .into_inner()
+      .communications;
+    assert_eq!(communications.len(), 1);
+    assert_eq!(
+      communications[0].channel,
+      CommunicationChannel::ChannelEmail as i32
+    );
+    assert_eq!(communications[0].created_by, 7);
+  }
+
+  #[tokio::test]
+  async fn test_invoice_details_approval() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    let request = || SetInvoiceDetailsRequest {
+      customer_id: 1,
+      invoice_name: "Kiss Béla ev.".to_string(),
+      ..Default::default()
+    };
+    // Non-admin changes wait for approval
+    let customer = Customer::set_invoice_details(s, Request::new(request()))
+      .await
+      .unwrap()
+      .into_inner();
+    assert!(!customer.invoice_name_override);
+    assert!(customer.warnings.iter().any(|w| w.field == "invoice_name"));
+    let changes =
+      Customer::list_pending_changes(s, admin(ListPendingChangesRequest { customer_id: 1 }))
+        .await
+        .unwrap()
+        .into_inner()
+        .changes;
+    assert_eq!(changes.len(), 1);
+    assert!(changes[0].invoice_name_changed && !changes[0].name_changed);
+    assert_eq!(changes[0].invoice_name, "Kiss Béla ev.");
+    let customer = Customer::approve_change(
+      s,
+      admin(DecideChangeRequest {
+        change_id: changes[0].id,
+        decided_by: 2,
+        force_tax_number_change: false,
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    assert_eq!(customer.invoice_name, "Kiss Béla ev.");
+    // Admins remove the override directly
+    let customer = Customer::set_invoice_details(
+      s,
+      admin(SetInvoiceDetailsRequest {
+        customer_id: 1,
+        ..Default::default()
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    assert!(!customer.invoice_name_override);
+    assert!(customer.warnings.iter().all(|w| w.field != "invoice_name"));
+  }
+
+  #[tokio::test]
+  async fn test_approval_without_admin_token() {
+    let mut f = fixture();
+    f.service.admin_token = None;
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    // Nobody could approve, so sensitive changes are rejected
+    let denied = Some(Code::PermissionDenied);
+    let request = SetInvoiceDetailsRequest {
+      customer_id: 1,
+      invoice_name: "Kiss Béla ev.".to_string(),
+      ..Default::default()
+    };
+    assert_eq!(
+      code(Customer::set_invoice_details(s, admin(request)).await),
+      denied
+    );
+    let customer = Customer::get_by_id(
+      s,
+      Request::new(GetByIdRequest {
+        customer_id: 1,
+        expanded: false,
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    let update = CustomerObj {
+      tax_number: "66064590-2-35".to_string(),
+      kind: CustomerKind::KindCompany as i32,
+      ..customer.clone()
+    };
+    assert_eq!(code(Customer::update_by_id(s, admin(update)).await), denied);
+    // Other fields are updated directly
+    let update = CustomerObj {
+      phone: "+36307654321".to_string(),
+      ..customer
+    };
+    let customer = Customer::update_by_id(s, Request::new(update))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(customer.phone, "+36307654321");
+    assert!(customer.tax_number.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_approval_of_failed_update() {
+    let f = Fixture::new("vecpack", Some("company_tax_number_required: false\n"));
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    let customer = Customer::get_by_id(
+      s,
+      Request::new(GetByIdRequest {
+        customer_id: 1,
+        expanded: false,
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    // Update failing to store requests no approval
+    std::fs::remove_dir_all(f.dir.path().join("customers")).unwrap();
+    let update = CustomerObj {
+      tax_number: "66064590-2-35".to_string(),
+      kind: CustomerKind::KindCompany as i32,
+      ..customer
+    };
+    assert!(Customer::update_by_id(s, Request::new(update))
+      .await
+      .is_err());
+    let changes =
+      Customer::list_pending_changes(s, admin(ListPendingChangesRequest { customer_id: 1 }))
+        .await
+        .unwrap()
+        .into_inner()
+        .changes;
+    assert!(changes.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_check_unchanged() {
+    let f = fixture();
+    create(&f, "bela@kiss.hu").await.unwrap();
+    let customers = f.service.tenants.get(DEFAULT_TENANT).await.unwrap();
+    let mut customers = customers.lock().await;
+    let before = customers.find_id(&1).unwrap().clone();
+    assert!(CustomerService::check_unchanged(&customers, &before).is_ok());
+    // Changes stored meanwhile fail the planned change
+    customers
+      .update(&1, 2, |c| {
+        c.phone = "+36307654321".to_string();
+        Ok(())
+      })
+      .unwrap();
+    assert!(matches!(
+      CustomerService::check_unchanged(&customers, &before),
+      Err(ServiceError::FailedPrecondition(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_not_found_codes() {
+    let f = fixture();
+    let s = &f.service;
+    let id = 99;
+    let not_found = Some(Code::NotFound);
+    let get = || GetByIdRequest {
+      customer_id: id,
+      expanded: false,
+    };
+    assert_eq!(
+      code(Customer::get_by_id(s, Request::new(get())).await),
+      not_found
+    );
+    assert_eq!(
+      code(Customer::get_invoice_readiness(s, Request::new(get())).await),
+      not_found
+    );
+    let missing = CustomerObj {
+      id,
+      name: "Kiss Béla".to_string(),
+      ..CustomerObj::default()
+    };
+    assert_eq!(
+      code(Customer::update_by_id(s, admin(missing)).await),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::format_address(s, Request::new(FormatAddressRequest { customer_id: id })).await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::get_vat_status(
+          s,
+          Request::new(GetVatStatusRequest {
+            customer_id: id,
+            ..Default::default()
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::reactivate(
+          s,
+          Request::new(ReactivateRequest {
+            customer_id: id,
+            ..Default::default()
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::list_documents(s, Request::new(ListDocumentsRequest { customer_id: id })).await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::preview_merge(
+          s,
+          Request::new(PreviewMergeRequest {
+            customer_id: id,
+            duplicate_id: id + 1,
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::set_invoice_details(
+          s,
+          Request::new(SetInvoiceDetailsRequest {
+            customer_id: id,
+            ..Default::default()
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::add_alias(
+          s,
+          Request::new(AliasRequest {
+            customer_id: id,
+            alias: "Béla".to_string(),
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::generate_profile_token(
+          s,
+          admin(GenerateProfileTokenRequest {
+            customer_id: id,
+            ..Default::default()
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    // Profile tokens are not given to unauthenticated callers
+    assert_eq!(
+      code(
+        Customer::generate_profile_token(
+          s,
+          Request::new(GenerateProfileTokenRequest {
+            customer_id: id,
+            ..Default::default()
+          })
+        )
+        .await
+      ),
+      Some(Code::PermissionDenied)
+    );
+    assert_eq!(
+      code(
+        Customer::record_activity(
+          s,
+          Request::new(RecordActivityRequest {
+            customer_id: id,
+            kind: ActivityKind::ActivityPurchase as i32,
+            ..Default::default()
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    assert_eq!(
+      code(
+        Customer::lock_for_edit(
+          s,
+          Request::new(LockForEditRequest {
+            customer_id: id,
+            editor_uid: 1,
+            ttl_secs: 0,
+          })
+        )
+        .await
+      ),
+      not_found
+    );
+    // Not found messages are translated
+    assert_eq!(
+      with_locale(Locale::En, async {
+        Status::from(ServiceError::customer_not_found(id))
+      })
+      .await
+      .message(),
+      "No customer found with ID: 99"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_conflict_validation_internal_codes() {
+    let f = fixture();
+    let s = &f.service;
+    create(&f, "bela@kiss.hu").await.unwrap();
+    // Conflict, with the conflicting customer ID
+    Customer::quick_create(
+      s,
+      Request::new(QuickCreateRequest {
+        name: "Nagy Anna".to_string(),
+        phone: "+36307654321".to_string(),
+        created_by: 1,
+        customer_id: 0,
+      }),
+    )
+    .await
+    .unwrap();
+    let mut other = Customer::get_by_id(
+      s,
+      Request::new(GetByIdRequest {
+        customer_id: 2,
+        expanded: false,
+      }),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    other.email = "bela@kiss.hu".to_string();
+    let status = Customer::update_by_id(s, admin(other.clone()))
+      .await
+      .err()
+      .unwrap();
+    assert_eq!(status.code(), Code::AlreadyExists);
+    assert_eq!(status.metadata().get(CONFLICT_METADATA_KEY).unwrap(), "1");
+    // Validation
+    other.email = String::new();
+    other.name = String::new();
+    assert_eq!(
+      code(Customer::update_by_id(s, admin(other)).await),
+      Some(Code::InvalidArgument)
+    );
+    assert_eq!(
+      code(
+        Customer::get_field_history(
+          s,
+          admin(GetFieldHistoryRequest {
+            customer_id: 1,
+            field: "adoszam".to_string(),
+          })
+        )
+        .await
+      ),
+      Some(Code::InvalidArgument)
+    );
+    // Internal
+    std::fs::write(
+      f.dir.path().join("validation_policy.yaml"),
+      "unique_email: [\n",
+    )
+    .unwrap();
+    assert_eq!(
+      code(Customer::reload_validation_policy(s, admin(())).await),
+      Some(Code::Internal)
+    );
+  }
+
+  // Length prefixed gRPC request of an encoded message
+  fn grpc_request(path: &str, message: &[u8]) -> http::Request<tonic::transport::Body> {
+    let mut body = vec![0];
+    body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    body.extend_from_slice(message);
+    http::Request::post(path)
+      .header("content-type", "application/grpc")
+      .header("te", "trailers")
+      .body(tonic::transport::Body::from(body))
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_payload_guard() {
+    use prost::Message;
+    use tonic::codegen::Service;
+    let f = fixture();
+    let mut s = payload::PayloadGuard::new(CustomerServer::new(f.service), 1024);
+    let grpc_status = |response: &http::Response<_>| {
+      Status::from_header_map(response.headers()).map(|status| status.code())
+    };
+    let mut valid = Vec::new();
+    QuickCreateRequest {
+      name: "Kiss Béla".to_string(),
+      phone: "+36301234567".to_string(),
+      created_by: 1,
+      customer_id: 0,
+    }
+    .encode(&mut valid)
+    .unwrap();
+    let response = s
+      .call(grpc_request("/customer.Customer/QuickCreate", &valid))
+      .await
+      .unwrap();
+    assert_eq!(grpc_status(&response), None);
+    // Name with invalid UTF-8, field 1 with 2 bytes
+    let response = s
+      .call(grpc_request(
+        "/customer.Customer/QuickCreate",
+        &[0x0a, 0x02, 0xff, 0xfe],
+      ))
+      .await
+      .unwrap();
+    assert_eq!(grpc_status(&response), Some(Code::InvalidArgument));
+    let status = Status::from_header_map(response.headers()).unwrap();
+    assert_eq!(
+      status.message(),
+      "A kérés érvénytelen UTF-8 szöveget tartalmaz"
+    );
+    // Broken message
+    let response = s
+      .call(grpc_request(
+        "/customer.Customer/QuickCreate",
+        &[0x0a, 0x7f],
+      ))
+      .await
+      .unwrap();
+    assert_eq!(grpc_status(&response), Some(Code::InvalidArgument));
+    // Too large
+    let response = s
+      .call(grpc_request(
+        "/customer.Customer/QuickCreate",
+        &[0x0a; 2048],
+      ))
+      .await
+      .unwrap();
+    assert_eq!(grpc_status(&response), Some(Code::ResourceExhausted));
+    let status = Status::from_header_map(response.headers()).unwrap();
+    assert_eq!(status.message(), "A kérés max 1024 bájt lehet");
+  }
+
+  #[tokio::test]
+  async fn test_rest_gateway() {
+    let f = fixture();
+    let s = CustomerServer::new(f.service);
+    let request = |method: &str, uri: &str, body: &str| {
+      http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("accept-language", "en")
+        .body(tonic::transport::Body::from(body.to_string()))
+        .unwrap()
+    };
+    let json = |response: http::Response<tonic::transport::Body>| async move {
+      let status = response.status();
+      let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+      (
+        status,
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+      )
+    };
+    let (status, created) = json(
+      rest::handle(
+        s.clone(),
+        request(
+          "POST",
+          "/customers",
+          r#"{"name": "Kiss Béla", "email": "kiss.bela@example.com", "phone": "+36301234567",
+            "address_zip": "6000", "address_location": "Kecskemét", "address_street": "Fő utca 1.",
+            "created_by": 1, "kind": 1}"#,
+        ),
+        1024,
+      )
+      .await,
+    )
+    .await;
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(created["id"], 1);
+    assert_eq!(created["name"], "Kiss Béla");
+    let (status, customer) =
+      json(rest::handle(s.clone(), request("GET", "/customers/1", ""), 1024).await).await;
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(customer["email"], "kiss.bela@example.com");
+    let (status, found) =
+      json(rest::handle(s.clone(), request("GET", "/customers?query=kiss", ""), 1024).await).await;
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(found["customer_ids"], serde_json::json!([1]));
+    // Service errors, in the language of the request
+    let (status, error) =
+      json(rest::handle(s.clone(), request("GET", "/customers/9", ""), 1024).await).await;
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+    assert_eq!(error["code"], "NotFound");
+    let (status, error) = json(
+      rest::handle(
+        s.clone(),
+        request(
+          "POST",
+          "/customers",
+          r#"{"name": "Nagy Éva", "email": "rossz"}"#,
+        ),
+        1024,
+      )
+      .await,
+    )
+    .await;
+    assert_eq!(status, http::StatusCode::BAD_REQUEST);
+    assert!(error["field_violations"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .any(|v| v["field"] == "email"));
+    // Gateway errors
+    let (status, error) = json(
+      rest::handle(
+        s.clone(),
+        request("POST", "/customers", r#"{"nev": "Nagy Éva"}"#),
+        1024,
+      )
+      .await,
+    )
+    .await;
+    assert_eq!(status, http::StatusCode::BAD_REQUEST);
+    assert!(error["message"]
+      .as_str()
+      .unwrap()
+      .starts_with("Invalid JSON request"));
+    let (status, _) =
+      json(rest::handle(s.clone(), request("GET", "/orders", ""), 1024).await).await;
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+    let (status, _) =
+      json(rest::handle(s.clone(), request("DELETE", "/customers/1", ""), 1024).await).await;
+    assert_eq!(status, http::StatusCode::BAD_REQUEST);
+    // Too large, with and without Content-Length
+    let name = "a".repeat(2048);
+    let body = format!(r#"{{"name": "{}"}}"#, name);
+    let (status, error) =
+      json(rest::handle(s.clone(), request("POST", "/customers", &body), 1024).await).await;
+    assert_eq!(status, http::StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error["code"], "ResourceExhausted");
+    assert_eq!(error["message"], "The request can be max 1024 bytes long");
+    let mut large = request("POST", "/customers", "");
+    large
+      .headers_mut()
+      .insert("content-length", http::HeaderValue::from_static("2048"));
+    let (status, _) = json(rest::handle(s.clone(), large, 1024).await).await;
+    assert_eq!(status, http::StatusCode::PAYLOAD_TOO_LARGE);
+  }
+
+  #[tokio::test]
+  async fn test_grpc_web() {
+    use hyper::body::HttpBody;
+    use prost::Message;
+    use tonic::codegen::Service;
+    let f = fixture();
+    let origins = grpc_web::AllowedOrigins::parse("https://gardenzilla.hu");
+    let mut s = grpc_web::GrpcWeb::new(CustomerServer::new(f.service), origins, 1024);
+    let mut message = Vec::new();
+    QuickCreateRequest {
+      name: "Kiss Béla".to_string(),
+      phone: "+36301234567".to_string(),
+      created_by: 1,
+      customer_id: 0,
+    }
+    .encode(&mut message)
+    .unwrap();
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&message);
+    let request = |content_type: &str, body: Vec<u8>| {
+      http::Request::post("/customer.Customer/QuickCreate")
+        .header("content-type", content_type)
+        .header("origin", "https://gardenzilla.hu")
+        .header("x-grpc-web", "1")
+        .body(tonic::transport::Body::from(body))
+        .unwrap()
+    };
+    // Response body with its trailer frame
+    let read = |response: http::Response<tonic::body::BoxBody>| async move {
+      let mut body = response.into_body();
+      let mut data = Vec::new();
+      while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.unwrap());
+      }
+      data
+    };
+    let response = s
+      .call(request("application/grpc-web+proto", frame.clone()))
+      .await
+      .unwrap();
+    assert_eq!(
+      response.headers()["content-type"],
+      "application/grpc-web+proto"
+    );
+    assert_eq!(
+      response.headers()["access-control-allow-origin"],
+      "https://gardenzilla.hu"
+    );
+    let data = read(response).await;
+    let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+    let customer = CustomerObj::decode(&data[5..5 + len]).unwrap();
+    assert_eq!(customer.name, "Kiss Béla");
+    let trailers = &data[5 + len..];
+    assert_eq!(trailers[0], 0x80);
+    assert!(String::from_utf8_lossy(&trailers[5..]).contains("grpc-status:0\r\n"));
+    // Base64 text
+    let response = s
+      .call(request(
+        "application/grpc-web-text",
+        base64::encode(&frame).into_bytes(),
+      ))
+      .await
+      .unwrap();
+    let data = base64::decode(read(response).await).unwrap();
+    assert_eq!(data[0], 0);
+    // Service errors are sent in the headers
+    let response = s
+      .call(request("application/grpc-web+proto", vec![0, 0, 0, 0, 0]))
+      .await
+      .unwrap();
+    assert_eq!(
+      Status::from_header_map(response.headers()).map(|s| s.code()),
+      Some(Code::InvalidArgument)
+    );
+    let response = s
+      .call(request(
+        "application/grpc-web-text",
+        b"nem base64!".to_vec(),
+      ))
+      .await
+      .unwrap();
+    let status = Status::from_header_map(response.headers()).unwrap();
+    assert_eq!(status.message(), "A gRPC-web kérés nem dekódolható");
+    // Too large, with and without Content-Length
+    let response = s
+      .call(request("application/grpc-web+proto", vec![0x0a; 2048]))
+      .await
+      .unwrap();
+    assert_eq!(
+      Status::from_header_map(response.headers()).map(|s| s.code()),
+      Some(Code::ResourceExhausted)
+    );
+    let mut large = request("application/grpc-web+proto", Vec::new());
+    large
+      .headers_mut()
+      .insert("content-length", http::HeaderValue::from_static("2048"));
+    let response = s.call(large).await.unwrap();
+    let status = Status::from_header_map(response.headers()).unwrap();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert_eq!(status.message(), "A kérés max 1024 bájt lehet");
+    assert_eq!(
+      response.headers()["access-control-allow-origin"],
+      "https://gardenzilla.hu"
+    );
+    // CORS preflight
+    let preflight = http::Request::builder()
+      .method("OPTIONS")
+      .uri("/customer.Customer/QuickCreate")
+      .header("origin", "https://gardenzilla.hu")
+      .header(
+        "access-control-request-headers",
+        "content-type,x-grpc-web,tenant-id",
+      )
+      .body(tonic::transport::Body::empty())
+      .unwrap();
+    let response = s.call(preflight).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+    assert_eq!(
+      response.headers()["access-control-allow-headers"],
+      "content-type,x-grpc-web,tenant-id"
+    );
+    let preflight = http::Request::builder()
+      .method("OPTIONS")
+      .uri("/customer.Customer/QuickCreate")
+      .header("origin", "https://example.com")
+      .body(tonic::transport::Body::empty())
+      .unwrap();
+    let response = s.call(preflight).await.unwrap();
+    assert!(response
+      .headers()
+      .get("access-control-allow-origin")
+      .is_none());
+  }
+
+  #[tokio::test]
+  async fn test_throttle() {
+    use prost::Message;
+    use tonic::codegen::Service;
+    let f = fixture();
+    let metrics = Arc::new(metrics::Metrics::new(None));
+    let limiter = Arc::new(throttle::Limiter::new(0.001, 1));
+    let mut s = metrics::Instrument::new(
+      throttle::Throttle::new(
+        CustomerServer::new(f.service),
+        Some(limiter),
+        metrics.clone(),
+      ),
+      metrics.clone(),
+    );
+    let quick_create = |created_by: u32| {
+      let mut message = Vec::new();
+      QuickCreateRequest {
+        name: "Kiss Béla".to_string(),
+        phone: "+36301234567".to_string(),
+        created_by,
+        customer_id: 0,
+      }
+      .encode(&mut message)
+      .unwrap();
+      grpc_request("/customer.Customer/QuickCreate", &message)
+    };
+    let grpc_status = |response: &http::Response<_>| {
+      Status::from_header_map(response.headers()).map(|status| status.code())
+    };
+    let response = s.call(quick_create(1)).await.unwrap();
+    assert_eq!(grpc_status(&response), None);
+    drop(response);
+    let response = s.call(quick_create(1)).await.unwrap();
+    assert_eq!(grpc_status(&response), Some(Code::ResourceExhausted));
+    drop(response);
+    // Other callers are not affected
+    let response = s.call(quick_create(2)).await.unwrap();
+    assert_eq!(grpc_status(&response), None);
+    drop(response);
+    // Queries are not throttled
+    let mut message = Vec::new();
+    GetByIdRequest {
+      customer_id: 1,
+      expanded: false,
+    }
+    .encode(&mut message)
+    .unwrap();
+    for _ in 0..2 {
+      let response = s
+        .call(grpc_request("/customer.Customer/GetById", &message))
+        .await
+        .unwrap();
+      assert_eq!(grpc_status(&response), None);
+    }
+    let stats = metrics.snapshot();
+    let quick_create = &stats.iter().find(|(m, _)| m == "QuickCreate").unwrap().1;
+    assert_eq!(
+      (
+        quick_create.calls,
+        quick_create.errors,
+        quick_create.throttled
+      ),
+      (3, 1, 1)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_watch_disconnect() {
+    let f = fixture();
+    let stream = Customer::watch(&f.service, Request::new(()))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(f.service.events.subscriber_count(), 1);
+    // Idle watcher, no event is sent after the disconnect
+    drop(stream);
+    for _ in 0..100 {
+      if f.service.events.subscriber_count() == 0 {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(f.service.events.subscriber_count(), 0);
+  }
+}
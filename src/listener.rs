@@ -0,0 +1,282 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Server listeners
+//
+// The API is served on every address of LISTEN_ADDRS. When separate
+// admin listeners are set with ADMIN_LISTEN_ADDRS, admin RPCs are only
+// allowed on those, so the admin port can be kept internal. IPv6
+// listeners are IPv6 only, list an IPv4 address as well for dual-stack.
+// The API can also be served on a Unix socket (UNIX_SOCKET_PATH) for
+// clients running on the same host.
+
+use crate::prelude::*;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::codegen::{http, Service};
+use tonic::transport::server::Connected;
+use tonic::transport::NamedService;
+
+pub const DEFAULT_LISTEN_ADDRS: &str = "[::1]:50055";
+
+// Set by the server on requests of listeners without admin RPCs
+// The value sent by the client is always replaced
+pub const ADMIN_DENIED_METADATA_KEY: &str = "x-admin-denied";
+
+// Parse comma separated listen addresses
+pub fn parse_addrs(value: &str) -> ServiceResult<Vec<SocketAddr>> {
+  let mut addrs: Vec<SocketAddr> = Vec::new();
+  for addr in value.split(',').map(|a| a.trim()).filter(|a| !a.is_empty()) {
+    let addr = addr
+      .parse()
+      .map_err(|_| ServiceError::internal_error(&format!("Invalid listen address: {}", addr)))?;
+    if addrs.contains(&addr) {
+      return Err(ServiceError::internal_error(&format!(
+        "Duplicate listen address: {}",
+        addr
+      )));
+    }
+    addrs.push(addr);
+  }
+  Ok(addrs)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+  Tcp(SocketAddr),
+  Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+      ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+    }
+  }
+}
+
+// Bound listener
+pub enum Incoming {
+  Tcp(TcpListener),
+  Unix(UnixListener),
+}
+
+impl ListenAddr {
+  // Bind listener
+  // Must be called from the runtime
+  pub fn bind(&self) -> io::Result<Incoming> {
+    match self {
+      ListenAddr::Tcp(addr) => Ok(Incoming::Tcp(bind_tcp(*addr)?)),
+      ListenAddr::Unix(path) => Ok(Incoming::Unix(bind_unix(path)?)),
+    }
+  }
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+  let socket = socket2::Socket::new(
+    socket2::Domain::for_address(addr),
+    socket2::Type::STREAM,
+    Some(socket2::Protocol::TCP),
+  )?;
+  // So 0.0.0.0 and [::] can be bound to the same port
+  if addr.is_ipv6() {
+    socket.set_only_v6(true)?;
+  }
+  socket.set_reuse_address(true)?;
+  socket.bind(&addr.into())?;
+  socket.listen(1024)?;
+  socket.set_nonblocking(true)?;
+  TcpListener::from_std(socket.into())
+}
+
+// Socket file left by a previous run is removed,
+// other files are never overwritten
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+  if let Ok(meta) = std::fs::symlink_metadata(path) {
+    if !meta.file_type().is_socket() {
+      return Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "file exists and is not a socket",
+      ));
+    }
+    std::fs::remove_file(path)?;
+  }
+  UnixListener::bind(path)
+}
+
+pub fn tcp_connections(listener: TcpListener) -> TcpListenerStream {
+  TcpListenerStream::new(listener)
+}
+
+pub fn unix_connections(listener: UnixListener) -> impl Stream<Item = io::Result<UnixConnection>> {
+  UnixListenerStream::new(listener).map(|stream| stream.map(UnixConnection))
+}
+
+// Unix socket connection served by the server
+pub struct UnixConnection(UnixStream);
+
+impl Connected for UnixConnection {}
+
+impl AsyncRead for UnixConnection {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_read(cx, buf)
+  }
+}
+
+impl AsyncWrite for UnixConnection {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.0).poll_write(cx, buf)
+  }
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_flush(cx)
+  }
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_shutdown(cx)
+  }
+}
+
+// Service of a listener
+// Marks the requests of listeners not allowed to call admin RPCs
+#[derive(Clone)]
+pub struct Listener<S> {
+  inner: S,
+  admin_allowed: bool,
+}
+
+impl<S> Listener<S> {
+  pub fn new(inner: S, admin_allowed: bool) -> Self {
+    Self {
+      inner,
+      admin_allowed,
+    }
+  }
+}
+
+impl<S: NamedService> NamedService for Listener<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Listener<S>
+where
+  S: Service<http::Request<B>>,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = S::Future;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+    mark(request.headers_mut(), self.admin_allowed);
+    self.inner.call(request)
+  }
+}
+
+fn mark(headers: &mut http::HeaderMap, admin_allowed: bool) {
+  headers.remove(ADMIN_DENIED_METADATA_KEY);
+  if !admin_allowed {
+    headers.insert(
+      ADMIN_DENIED_METADATA_KEY,
+      http::HeaderValue::from_static("1"),
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_addrs() {
+    assert_eq!(
+      parse_addrs("0.0.0.0:50055, [::]:50055,127.0.0.1:50056").unwrap(),
+      [
+        "0.0.0.0:50055".parse::<SocketAddr>().unwrap(),
+        "[::]:50055".parse().unwrap(),
+        "127.0.0.1:50056".parse().unwrap(),
+      ]
+    );
+    assert!(parse_addrs("").unwrap().is_empty());
+    assert!(parse_addrs("localhost:50055").is_err());
+    assert!(parse_addrs("0.0.0.0").is_err());
+    assert!(parse_addrs("0.0.0.0:1,0.0.0.0:1").is_err());
+  }
+
+  #[test]
+  fn test_mark() {
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+      ADMIN_DENIED_METADATA_KEY,
+      http::HeaderValue::from_static("0"),
+    );
+    mark(&mut headers, true);
+    assert!(headers.get(ADMIN_DENIED_METADATA_KEY).is_none());
+    mark(&mut headers, false);
+    assert_eq!(headers.get(ADMIN_DENIED_METADATA_KEY).unwrap(), "1");
+  }
+
+  #[tokio::test]
+  async fn test_bind_dual_stack() {
+    let v4 = bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+    let port = v4.local_addr().unwrap().port();
+    // IPv6 loopback may be missing in containers
+    if let Ok(v6) = bind_tcp(SocketAddr::new("::1".parse().unwrap(), port)) {
+      assert_eq!(v6.local_addr().unwrap().port(), port);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_bind_unix() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customer.sock");
+    let listener = bind_unix(&path).unwrap();
+    drop(listener);
+    // Socket of the previous run is replaced
+    let mut connections = unix_connections(bind_unix(&path).unwrap());
+    let _client = UnixStream::connect(&path).await.unwrap();
+    assert!(connections.next().await.unwrap().is_ok());
+    // Other files are kept
+    let file = dir.path().join("customers");
+    std::fs::write(&file, "").unwrap();
+    assert!(bind_unix(&file).is_err());
+    assert!(file.exists());
+    assert_eq!(
+      ListenAddr::Unix(path.clone()).to_string(),
+      format!("unix:{}", path.display())
+    );
+  }
+}
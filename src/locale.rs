@@ -0,0 +1,73 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Locale negotiation
+//
+// The generated service is wrapped, so every RPC handler runs
+// with the locale picked from the accept-language metadata.
+// Errors are translated when they are converted to Status.
+
+use crate::prelude::*;
+use std::task::{Context, Poll};
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::transport::NamedService;
+
+#[derive(Clone)]
+pub struct Negotiate<S> {
+  inner: S,
+}
+
+impl<S> Negotiate<S> {
+  pub fn new(inner: S) -> Self {
+    Self { inner }
+  }
+}
+
+impl<S: NamedService> NamedService for Negotiate<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Negotiate<S>
+where
+  S: Service<http::Request<B>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  B: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    let locale = Locale::from_accept_language(
+      request
+        .headers()
+        .get(ACCEPT_LANGUAGE_METADATA_KEY)
+        .and_then(|v| v.to_str().ok()),
+    );
+    // Take the service that was polled ready
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    Box::pin(with_locale(
+      locale,
+      async move { inner.call(request).await },
+    ))
+  }
+}
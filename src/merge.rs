@@ -0,0 +1,361 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Duplicate customer merge
+//
+// The kept customer wins every conflict, its empty fields
+// are filled from the duplicate. Documents and status history
+// of both customers are kept.
+
+use crate::customer::{Customer, MAX_ALIASES, MAX_PREVIOUS_CONTACTS, MAX_TAGS};
+use crate::policy::Field;
+use crate::search;
+
+// Field set in both customers with different values
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergeConflict {
+  // Proto field name
+  pub field: &'static str,
+  // Policy field, if the field is shaped by caller roles
+  pub policy_field: Option<Field>,
+  pub kept_value: String,
+  pub duplicate_value: String,
+}
+
+// Merge result
+pub struct Merge {
+  pub merged: Customer,
+  pub conflicts: Vec<MergeConflict>,
+}
+
+// Compute the merge of a duplicate into the kept customer
+pub fn merge(kept: &Customer, duplicate: &Customer) -> Merge {
+  let mut merged = kept.clone();
+  let mut conflicts = Vec::new();
+  let mut conflict = |field, policy_field, kept_value: String, duplicate_value: String| {
+    conflicts.push(MergeConflict {
+      field,
+      policy_field,
+      kept_value,
+      duplicate_value,
+    })
+  };
+
+  // Text fields
+  for (field, policy_field, value, other) in [
+    ("name", None, &mut merged.name, &duplicate.name),
+    (
+      "email",
+      Some(Field::Email),
+      &mut merged.email,
+      &duplicate.email,
+    ),
+    (
+      "phone",
+      Some(Field::Phone),
+      &mut merged.phone,
+      &duplicate.phone,
+    ),
+  ] {
+    if value.trim().is_empty() {
+      *value = other.clone();
+    } else if !other.trim().is_empty() && value.trim() != other.trim() {
+      conflict(field, policy_field, value.clone(), other.clone());
+    }
+  }
+
+  // Address is taken as a whole, not to mix two addresses
+  let address = |c: &Customer| {
+    [
+      (Field::AddressZip, c.address_zip.clone()),
+      (Field::AddressLocation, c.address_location.clone()),
+      (Field::AddressStreet, c.address_street.clone()),
+    ]
+  };
+  let is_empty = |c: &Customer| address(c).iter().all(|(_, v)| v.trim().is_empty());
+  if is_empty(kept) {
+    merged.address_zip = duplicate.address_zip.clone();
+    merged.address_location = duplicate.address_location.clone();
+    merged.address_street = duplicate.address_street.clone();
+    merged.country = duplicate.country.clone();
+  } else if !is_empty(duplicate) {
+    for ((field, value), (_, other)) in address(kept).iter().zip(&address(duplicate)) {
+      if value != other {
+        conflict(field.path(), Some(*field), value.clone(), other.clone());
+      }
+    }
+    if kept.country != duplicate.country {
+      conflict(
+        "country",
+        None,
+        kept.country.clone(),
+        duplicate.country.clone(),
+      );
+    }
+  }
+
+  // Optional fields
+  let to_string = |v: &Option<String>| v.clone().unwrap_or_default();
+  let tax_number = |c: &Customer| c.tax_number.as_ref().map(|t| t.to_string());
+  for (field, policy_field, value, other) in [
+    (
+      "tax_number",
+      Some(Field::TaxNumber),
+      tax_number(kept),
+      tax_number(duplicate),
+    ),
+    (
+      "loyalty_card_id",
+      None,
+      kept.loyalty_card_id.clone(),
+      duplicate.loyalty_card_id.clone(),
+    ),
+    (
+      "invoice_name",
+      None,
+      kept.invoice_name.clone(),
+      duplicate.invoice_name.clone(),
+    ),
+  ] {
+    if value.is_some() && other.is_some() && value != other {
+      conflict(field, policy_field, to_string(&value), to_string(&other));
+    }
+  }
+  if merged.tax_number.is_none() {
+    merged.tax_number = duplicate.tax_number.clone();
+  }
+  if merged.loyalty_card_id.is_none() {
+    merged.loyalty_card_id = duplicate.loyalty_card_id.clone();
+  }
+  if merged.invoice_name.is_none() {
+    merged.invoice_name = duplicate.invoice_name.clone();
+  }
+  match (&kept.invoice_address, &duplicate.invoice_address) {
+    (None, Some(address)) => merged.invoice_address = Some(address.clone()),
+    (Some(a), Some(b)) => {
+      for (field, policy_field, value, other) in [
+        ("invoice_address_zip", Field::AddressZip, &a.zip, &b.zip),
+        (
+          "invoice_address_location",
+          Field::AddressLocation,
+          &a.location,
+          &b.location,
+        ),
+        (
+          "invoice_address_street",
+          Field::AddressStreet,
+          &a.street,
+          &b.street,
+        ),
+      ] {
+        if value != other {
+          conflict(field, Some(policy_field), value.clone(), other.clone());
+        }
+      }
+    }
+    _ => (),
+  }
+
+  // Kind and status
+  if kept.kind != duplicate.kind {
+    conflict(
+      "kind",
+      None,
+      format!("{:?}", kept.kind),
+      format!("{:?}", duplicate.kind),
+    );
+  }
+  if kept.active != duplicate.active {
+    conflict(
+      "active",
+      None,
+      kept.active.to_string(),
+      duplicate.active.to_string(),
+    );
+  }
+
+  // The older customer is the original one
+  if duplicate.date_created < kept.date_created {
+    merged.date_created = duplicate.date_created;
+    merged.created_by = duplicate.created_by;
+  }
+  merged.last_activity = kept.last_activity.max(duplicate.last_activity);
+  merged.last_purchase_at = kept.last_purchase_at.max(duplicate.last_purchase_at);
+  merged.last_invoice_at = kept.last_invoice_at.max(duplicate.last_invoice_at);
+
+  // Documents of the duplicate get new IDs after the kept ones
+  let next_id = kept.attachments.iter().map(|a| a.id).max().unwrap_or(0);
+  merged
+    .attachments
+    .extend(duplicate.attachments.iter().cloned().map(|mut a| {
+      a.id += next_id;
+      a
+    }));
+  merged
+    .status_history
+    .extend(duplicate.status_history.iter().cloned());
+  merged.status_history.sort_by_key(|s| s.date_changed);
+  // Customer stays invoiceable if any of them was
+  merged.invoiceable = kept.invoiceable || duplicate.invoiceable;
+  // Privacy restrictions of both are kept
+  merged.privacy = kept.privacy.union(duplicate.privacy);
+  if merged.vat_periods.is_empty() {
+    merged.vat_periods = duplicate.vat_periods.clone();
+  }
+  // Discount of the duplicate, if the kept one has none set
+  if merged.discount_set_at.is_none() {
+    merged.default_discount_percent = duplicate.default_discount_percent;
+    merged.discount_set_by = duplicate.discount_set_by;
+    merged.discount_set_at = duplicate.discount_set_at;
+  }
+  merged.preferred_contact = kept.preferred_contact.or(duplicate.preferred_contact);
+  // Aliases of both customers are kept
+  for alias in &duplicate.aliases {
+    let key = search::normalize(alias);
+    if merged.aliases.len() < MAX_ALIASES
+      && !merged.aliases.iter().any(|a| search::normalize(a) == key)
+    {
+      merged.aliases.push(alias.clone());
+    }
+  }
+  // Tags of both customers are kept
+  for tag in &duplicate.tags {
+    if merged.tags.len() < MAX_TAGS && !merged.tags.contains(tag) {
+      merged.tags.push(tag.clone());
+    }
+  }
+  merged.group = kept.group.clone().or_else(|| duplicate.group.clone());
+  // Newest previous contacts of both customers are kept
+  merged
+    .previous_contacts
+    .extend(duplicate.previous_contacts.iter().cloned());
+  merged.previous_contacts.sort_by_key(|c| c.date_replaced);
+  let overflow = merged
+    .previous_contacts
+    .len()
+    .saturating_sub(MAX_PREVIOUS_CONTACTS);
+  merged.previous_contacts.drain(..overflow);
+
+  Merge { merged, conflicts }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::{Attachment, CustomerKind, DocumentKind, PrivacyFlags};
+  use chrono::prelude::*;
+
+  fn date(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  fn attachment(id: u32) -> Attachment {
+    Attachment {
+      id,
+      document_kind: DocumentKind::Other,
+      storage_key: format!("doc{}", id),
+      uploaded_by: 1,
+      date_uploaded: Utc::now(),
+    }
+  }
+
+  #[test]
+  fn test_merge() {
+    let kept = Customer {
+      id: 1,
+      name: "Kiss Béla".to_string(),
+      email: "kiss@example.com".to_string(),
+      date_created: date("2021-02-01T00:00:00Z"),
+      attachments: vec![attachment(1), attachment(2)],
+      privacy: PrivacyFlags {
+        do_not_photograph: true,
+        ..PrivacyFlags::default()
+      },
+      ..Customer::default()
+    };
+    let duplicate = Customer {
+      id: 2,
+      name: "Kiss Bela".to_string(),
+      phone: "+36301234567".to_string(),
+      address_zip: "6000".to_string(),
+      address_location: "Kecskemét".to_string(),
+      address_street: "Fő utca 1.".to_string(),
+      kind: CustomerKind::Company,
+      loyalty_card_id: Some("GZ-1".to_string()),
+      date_created: date("2021-01-01T00:00:00Z"),
+      created_by: 7,
+      last_activity: Some(date("2021-03-01T00:00:00Z")),
+      attachments: vec![attachment(1)],
+      privacy: PrivacyFlags {
+        processing_restricted: true,
+        ..PrivacyFlags::default()
+      },
+      ..Customer::default()
+    };
+    let res = merge(&kept, &duplicate);
+    let merged = res.merged;
+    assert_eq!(merged.id, 1);
+    assert_eq!(merged.name, "Kiss Béla");
+    assert_eq!(merged.email, "kiss@example.com");
+    assert_eq!(merged.phone, "+36301234567");
+    assert_eq!(merged.address_location, "Kecskemét");
+    assert_eq!(merged.loyalty_card_id, Some("GZ-1".to_string()));
+    assert_eq!(merged.kind, CustomerKind::Private);
+    assert_eq!(merged.date_created, date("2021-01-01T00:00:00Z"));
+    assert_eq!(merged.created_by, 7);
+    assert_eq!(merged.last_activity, duplicate.last_activity);
+    assert_eq!(
+      merged.privacy,
+      PrivacyFlags {
+        do_not_photograph: true,
+        do_not_profile: false,
+        processing_restricted: true,
+      }
+    );
+    assert_eq!(
+      merged
+        .attachments
+        .iter()
+        .map(|a| a.id)
+        .collect::<Vec<u32>>(),
+      [1, 2, 3]
+    );
+    assert_eq!(
+      res.conflicts.iter().map(|c| c.field).collect::<Vec<&str>>(),
+      ["name", "kind"]
+    );
+  }
+
+  #[test]
+  fn test_merge_address_conflict() {
+    let customer = |zip: &str, street: &str| Customer {
+      address_zip: zip.to_string(),
+      address_location: "Kecskemét".to_string(),
+      address_street: street.to_string(),
+      ..Customer::default()
+    };
+    let res = merge(
+      &customer("6000", "Fő utca 1."),
+      &customer("6000", "Fő utca 2."),
+    );
+    assert_eq!(res.merged.address_street, "Fő utca 1.");
+    assert_eq!(res.conflicts.len(), 1);
+    assert_eq!(res.conflicts[0].field, "address_street");
+    assert_eq!(res.conflicts[0].policy_field, Some(Field::AddressStreet));
+    assert_eq!(res.conflicts[0].duplicate_value, "Fő utca 2.");
+  }
+}
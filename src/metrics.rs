@@ -0,0 +1,483 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// RPC metrics and slow query log
+//
+// The generated service is wrapped, so every RPC is measured
+// from the request until its response body is dropped, streams
+// included. Payload sizes are the uncompressed gRPC frames.
+// Calls slower than the threshold are appended to the slow
+// query log, with the request parameters of query RPCs.
+// Stats are kept by the methods of the service descriptor, other
+// request paths are counted together, so clients cannot grow the
+// stats by calling made up paths.
+
+use crate::payload;
+use crate::proto::customer::*;
+use crate::tenant::TENANT_METADATA_KEY;
+use chrono::prelude::*;
+use hyper::body::{Bytes, HttpBody};
+use prost::Message;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::transport::{Body, NamedService};
+use tonic::Status;
+
+// Length prefixed message header size
+const HEADER_LEN: usize = 5;
+// Method name of the paths not in the service descriptor
+pub const UNKNOWN_METHOD: &str = "unknown";
+
+// Stats of an RPC method since startup
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MethodStats {
+  pub calls: u64,
+  pub errors: u64,
+  pub total_duration: Duration,
+  pub max_duration: Duration,
+  pub request_bytes: u64,
+  pub max_request_bytes: u64,
+  pub response_bytes: u64,
+  pub max_response_bytes: u64,
+  // Rejected by the write throttle, counted in errors too
+  pub throttled: u64,
+}
+
+// Finished RPC call
+#[derive(Clone, Debug)]
+pub struct Call {
+  pub method: String,
+  pub tenant: String,
+  // Request message of query RPCs
+  pub params: Option<String>,
+  pub request_bytes: usize,
+  pub response_bytes: usize,
+  pub duration: Duration,
+  pub error: bool,
+}
+
+// Slow query log entry, one JSON per line
+#[derive(Serialize)]
+struct SlowQuery<'a> {
+  date: DateTime<Utc>,
+  method: &'a str,
+  tenant: &'a str,
+  duration_ms: u128,
+  request_bytes: usize,
+  response_bytes: usize,
+  params: &'a Option<String>,
+}
+
+pub struct SlowQueryLog {
+  pub path: PathBuf,
+  pub threshold: Duration,
+}
+
+pub struct Metrics {
+  methods: Mutex<HashMap<String, MethodStats>>,
+  // Request paths of the methods in the service descriptor
+  // e.g. /customer.Customer/GetById
+  known: HashSet<String>,
+  slow_query_log: Option<SlowQueryLog>,
+}
+
+impl Metrics {
+  pub fn new(slow_query_log: Option<SlowQueryLog>) -> Self {
+    Self {
+      methods: Mutex::new(HashMap::new()),
+      known: method_paths(FILE_DESCRIPTOR_SET),
+      slow_query_log,
+    }
+  }
+  // Method name of a request path, UNKNOWN_METHOD
+  // if the path is not in the service descriptor
+  pub fn method(&self, path: &str) -> String {
+    match self.known.contains(path) {
+      true => path
+        .rsplit('/')
+        .next()
+        .unwrap_or(UNKNOWN_METHOD)
+        .to_string(),
+      false => UNKNOWN_METHOD.to_string(),
+    }
+  }
+  pub fn record(&self, call: &Call) {
+    {
+      let mut methods = self.methods.lock().unwrap();
+      let stats = methods.entry(call.method.clone()).or_default();
+      stats.calls += 1;
+      stats.errors += call.error as u64;
+      stats.total_duration += call.duration;
+      stats.max_duration = stats.max_duration.max(call.duration);
+      stats.request_bytes += call.request_bytes as u64;
+      stats.max_request_bytes = stats.max_request_bytes.max(call.request_bytes as u64);
+      stats.response_bytes += call.response_bytes as u64;
+      stats.max_response_bytes = stats.max_response_bytes.max(call.response_bytes as u64);
+    }
+    if let Some(log) = &self.slow_query_log {
+      if call.duration >= log.threshold {
+        if let Err(error) = log.append(call) {
+          eprintln!("Error while writing slow query log: {}", error);
+        }
+      }
+    }
+  }
+  // Call rejected by the write throttle, see throttle.rs
+  // The call itself is recorded by the instrument
+  pub fn record_throttled(&self, path: &str) {
+    let method = self.method(path);
+    let mut methods = self.methods.lock().unwrap();
+    methods.entry(method).or_default().throttled += 1;
+  }
+  // Stats of every called method, by method name
+  pub fn snapshot(&self) -> Vec<(String, MethodStats)> {
+    let mut res = self
+      .methods
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(method, stats)| (method.clone(), stats.clone()))
+      .collect::<Vec<(String, MethodStats)>>();
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    res
+  }
+}
+
+impl SlowQueryLog {
+  fn append(&self, call: &Call) -> std::io::Result<()> {
+    let line = serde_json::to_string(&SlowQuery {
+      date: Utc::now(),
+      method: &call.method,
+      tenant: &call.tenant,
+      duration_ms: call.duration.as_millis(),
+      request_bytes: call.request_bytes,
+      response_bytes: call.response_bytes,
+      params: &call.params,
+    })?;
+    if let Some(dir) = self.path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)?;
+    writeln!(file, "{}", line)
+  }
+}
+
+// Request paths of the methods of every service in an
+// encoded file descriptor set
+fn method_paths(file_descriptor_set: &[u8]) -> HashSet<String> {
+  let files = prost_types::FileDescriptorSet::decode(file_descriptor_set)
+    .expect("Error while decoding file descriptor set")
+    .file;
+  let mut res = HashSet::new();
+  for file in files {
+    for service in &file.service {
+      for method in &service.method {
+        res.insert(format!(
+          "/{}.{}/{}",
+          file.package(),
+          service.name(),
+          method.name()
+        ));
+      }
+    }
+  }
+  res
+}
+
+// Request message of query RPCs as text
+// Other requests may contain customer data, and are not logged
+fn query_params(method: &str, body: &[u8]) -> Option<String> {
+  // Compressed or incomplete messages are not decoded
+  if body.len() < HEADER_LEN || body[0] != 0 {
+    return None;
+  }
+  let message = &body[HEADER_LEN..];
+  match method {
+    "GetAll" => decode::<GetAllRequest>(message),
+    "GetBulk" => decode::<GetBulkRequest>(message),
+    "GetAllSummaries" => decode::<GetAllRequest>(message),
+    "FindCustomer" => decode::<FindCustomerRequest>(message),
+    "QueryCustomers" => decode::<QueryCustomersRequest>(message),
+    "GetSegmentMembers" => decode::<GetSegmentMembersRequest>(message),
+    "GetCommunications" => decode::<GetCommunicationsRequest>(message),
+    "GetTaxDataIssues" => decode::<GetTaxDataIssuesRequest>(message),
+    "GetCreatedBetween" => decode::<GetCreatedBetweenRequest>(message),
+    "GetCreatedBy" => decode::<GetCreatedByRequest>(message),
+    "GetInactiveSince" => decode::<GetInactiveSinceRequest>(message),
+    "GetIncompleteProfiles" => decode::<GetIncompleteProfilesRequest>(message),
+    "GetStats" => decode::<GetStatsRequest>(message),
+    _ => None,
+  }
+}
+
+fn decode<M: prost::Message + Default + Debug>(message: &[u8]) -> Option<String> {
+  M::decode(message).ok().map(|m| format!("{:?}", m))
+}
+
+// gRPC status of a response header or trailer map
+fn is_error(headers: &http::HeaderMap) -> bool {
+  headers
+    .get("grpc-status")
+    .is_some_and(|status| status.as_bytes() != b"0")
+}
+
+// Service measuring the calls of the inner service
+#[derive(Clone)]
+pub struct Instrument<S> {
+  inner: S,
+  metrics: Arc<Metrics>,
+}
+
+impl<S> Instrument<S> {
+  pub fn new(inner: S, metrics: Arc<Metrics>) -> Self {
+    Self { inner, metrics }
+  }
+}
+
+impl<S: NamedService> NamedService for Instrument<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for Instrument<S>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  type Response = http::Response<BoxBody>;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+    let start = Instant::now();
+    let metrics = self.metrics.clone();
+    // Take the service that was polled ready
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    Box::pin(async move {
+      let (mut parts, body) = request.into_parts();
+      let method = metrics.method(parts.uri.path());
+      let tenant = parts
+        .headers
+        .get(TENANT_METADATA_KEY)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+      // Requests are unary, so they are read as a whole
+      let body = match payload::buffered(&mut parts, body).await {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+      };
+      let call = Call {
+        params: query_params(&method, &body),
+        method,
+        tenant,
+        request_bytes: body.len(),
+        response_bytes: 0,
+        duration: Duration::default(),
+        error: false,
+      };
+      let request = http::Request::from_parts(parts, Body::from(body));
+      let response = inner.call(request).await?;
+      let error = is_error(response.headers());
+      Ok(response.map(|body| {
+        BoxBody::new(MeteredBody {
+          inner: body,
+          call: Some(Call { error, ..call }),
+          start,
+          metrics,
+        })
+      }))
+    })
+  }
+}
+
+// Response body counting its bytes
+// The call is recorded when the body is dropped
+struct MeteredBody {
+  inner: BoxBody,
+  call: Option<Call>,
+  start: Instant,
+  metrics: Arc<Metrics>,
+}
+
+impl HttpBody for MeteredBody {
+  type Data = Bytes;
+  type Error = Status;
+
+  fn poll_data(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    let res = Pin::new(&mut self.inner).poll_data(cx);
+    if let Poll::Ready(Some(Ok(data))) = &res {
+      let len = data.len();
+      if let Some(call) = self.call.as_mut() {
+        call.response_bytes += len;
+      }
+    }
+    res
+  }
+
+  fn poll_trailers(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+    let res = Pin::new(&mut self.inner).poll_trailers(cx);
+    if let Poll::Ready(Ok(Some(trailers))) = &res {
+      let error = is_error(trailers);
+      if let Some(call) = self.call.as_mut() {
+        call.error |= error;
+      }
+    }
+    res
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.inner.is_end_stream()
+  }
+}
+
+impl Drop for MeteredBody {
+  fn drop(&mut self) {
+    if let Some(mut call) = self.call.take() {
+      call.duration = self.start.elapsed();
+      self.metrics.record(&call);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn call(method: &str, duration_ms: u64) -> Call {
+    Call {
+      method: method.to_string(),
+      tenant: "shop_a".to_string(),
+      params: Some("FindCustomerRequest { query: \"kiss\" }".to_string()),
+      request_bytes: 10,
+      response_bytes: 100,
+      duration: Duration::from_millis(duration_ms),
+      error: false,
+    }
+  }
+
+  #[test]
+  fn test_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("slow_queries.jsonl");
+    let metrics = Metrics::new(Some(SlowQueryLog {
+      path: path.clone(),
+      threshold: Duration::from_millis(500),
+    }));
+    metrics.record(&call("FindCustomer", 100));
+    metrics.record(&call("FindCustomer", 700));
+    metrics.record(&Call {
+      error: true,
+      ..call("GetById", 10)
+    });
+    let stats = metrics.snapshot();
+    assert_eq!(stats[0].0, "FindCustomer");
+    assert_eq!(stats[0].1.calls, 2);
+    assert_eq!(stats[0].1.max_duration, Duration::from_millis(700));
+    assert_eq!(stats[0].1.response_bytes, 200);
+    assert_eq!((stats[1].1.calls, stats[1].1.errors), (1, 1));
+    // Only the slow call is logged
+    let log = std::fs::read_to_string(path).unwrap();
+    assert_eq!(log.lines().count(), 1);
+    let entry: serde_json::Value = serde_json::from_str(log.trim()).unwrap();
+    assert_eq!(entry["method"], "FindCustomer");
+    assert_eq!(entry["duration_ms"], 700);
+    assert_eq!(entry["tenant"], "shop_a");
+  }
+
+  #[test]
+  fn test_query_params() {
+    let request = FindCustomerRequest {
+      query: "Kiss".to_string(),
+      ..FindCustomerRequest::default()
+    };
+    let mut body = vec![0, 0, 0, 0, request.encoded_len() as u8];
+    request.encode(&mut body).unwrap();
+    assert!(query_params("FindCustomer", &body)
+      .unwrap()
+      .contains("\"Kiss\""));
+    // Other requests are not logged
+    assert_eq!(query_params("CreateNew", &body), None);
+    body[0] = 1;
+    assert_eq!(query_params("FindCustomer", &body), None);
+  }
+
+  #[tokio::test]
+  async fn test_metered_body() {
+    let metrics = Arc::new(Metrics::new(None));
+    let chunks = vec![Ok::<Vec<u8>, std::io::Error>(vec![0; 7]), Ok(vec![0; 3])];
+    let body = MeteredBody {
+      inner: BoxBody::map_from(Body::wrap_stream(tokio_stream::iter(chunks))),
+      call: Some(Call {
+        response_bytes: 0,
+        ..call("GetBulk", 0)
+      }),
+      start: Instant::now(),
+      metrics: metrics.clone(),
+    };
+    hyper::body::to_bytes(body).await.unwrap();
+    let stats = metrics.snapshot();
+    assert_eq!(stats[0].1.calls, 1);
+    assert_eq!(stats[0].1.response_bytes, 10);
+  }
+
+  #[test]
+  fn test_method() {
+    let metrics = Metrics::new(None);
+    assert_eq!(metrics.method("/customer.Customer/GetById"), "GetById");
+    // Made up paths are counted together
+    assert_eq!(metrics.method("/customer.Customer/Nope"), UNKNOWN_METHOD);
+    assert_eq!(metrics.method("/other.Service/GetById"), UNKNOWN_METHOD);
+    metrics.record_throttled("/customer.Customer/QuickCreate");
+    metrics.record_throttled("/x/1");
+    metrics.record_throttled("/x/2");
+    let stats = metrics.snapshot();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(
+      (stats[0].0.as_str(), stats[0].1.throttled),
+      ("QuickCreate", 1)
+    );
+    assert_eq!(
+      (stats[1].0.as_str(), stats[1].1.throttled),
+      (UNKNOWN_METHOD, 2)
+    );
+  }
+}
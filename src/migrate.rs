@@ -0,0 +1,434 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Legacy migration
+//
+// One-shot cutover from the legacy customer service. With
+// --migrate-from, the customers of a legacy CSV or JSON dump are
+// stored with their legacy IDs before the server starts, if the
+// storage of the tenant is still empty, so restarting with the same
+// option is safe. Every record is checked first, so a broken dump
+// leaves the storage empty. Customers are stored one by one, each in
+// a record of its own, and an interrupted migration is resumed on the
+// next start. The stored customers are counted again from the
+// reopened storage.
+
+use crate::customer::{Customer, CustomerKind};
+use crate::display_name;
+use crate::prelude::*;
+use crate::search;
+use crate::taxnumber::TaxNumber;
+use crate::tenant::{open_db, Backend};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+// Record of the legacy dump, the fields of the legacy customer
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LegacyRecord {
+  pub id: u32,
+  pub name: String,
+  pub email: String,
+  pub phone: String,
+  pub tax_number: String,
+  pub address_zip: String,
+  pub address_location: String,
+  pub address_street: String,
+  // RFC3339 or YYYY-MM-DD
+  pub date_created: String,
+  pub created_by: u32,
+}
+
+impl LegacyRecord {
+  fn set(&mut self, column: &str, value: String) -> Result<(), String> {
+    let number = |value: &str| {
+      value
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format!("wrong {}: {}", column, value))
+    };
+    match column {
+      "id" => self.id = number(&value)?,
+      "name" => self.name = value,
+      "email" => self.email = value,
+      "phone" => self.phone = value,
+      "tax_number" => self.tax_number = value,
+      "address_zip" => self.address_zip = value,
+      "address_location" => self.address_location = value,
+      "address_street" => self.address_street = value,
+      "date_created" => self.date_created = value,
+      "created_by" => self.created_by = number(&value)?,
+      _ => return Err(format!("unknown column: {}", column)),
+    }
+    Ok(())
+  }
+  // Customer with the legacy ID, as converted from the legacy storage
+  pub fn to_customer(&self) -> Result<Customer, String> {
+    if self.id == 0 {
+      return Err("missing id".to_string());
+    }
+    let tax_number = match self.tax_number.trim() {
+      "" => None,
+      tax_number => {
+        Some(TaxNumber::new(tax_number).map_err(|_| format!("wrong tax number: {}", tax_number))?)
+      }
+    };
+    let date_created = parse_date_opt(&self.date_created)
+      .map_err(|_| format!("wrong date_created: {}", self.date_created))?
+      .ok_or("missing date_created")?;
+    // Customers with tax number are companies, as in the legacy service
+    let kind = match tax_number {
+      Some(_) => CustomerKind::Company,
+      None => CustomerKind::Private,
+    };
+    let name = self.name.trim().to_string();
+    let display_name = display_name::derive(&name);
+    Ok(Customer {
+      id: self.id,
+      name,
+      email: self.email.trim().to_string(),
+      phone: self.phone.trim().to_string(),
+      tax_number,
+      address_zip: self.address_zip.trim().to_string(),
+      address_location: self.address_location.trim().to_string(),
+      address_street: self.address_street.trim().to_string(),
+      date_created,
+      created_by: self.created_by,
+      kind,
+      last_modified: date_created,
+      last_modified_by: self.created_by,
+      sort_key: search::sort_key(&display_name),
+      display_name,
+      ..Customer::default()
+    })
+  }
+}
+
+// Split CSV content into rows of fields
+// Fields can be quoted, with "" for quotes and line breaks inside
+fn csv_rows(content: &str, delimiter: char) -> Result<Vec<Vec<String>>, String> {
+  let mut rows = Vec::new();
+  let (mut row, mut field) = (Vec::new(), String::new());
+  let (mut quoted, mut was_quoted) = (false, false);
+  let mut chars = content.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted => match chars.peek() {
+        Some('"') => {
+          field.push('"');
+          chars.next();
+        }
+        _ => quoted = false,
+      },
+      '"' if field.is_empty() && !was_quoted => {
+        quoted = true;
+        was_quoted = true;
+      }
+      c if quoted => field.push(c),
+      c if c == delimiter => {
+        row.push(std::mem::take(&mut field));
+        was_quoted = false;
+      }
+      '\r' => (),
+      '\n' => {
+        row.push(std::mem::take(&mut field));
+        was_quoted = false;
+        rows.push(std::mem::take(&mut row));
+      }
+      c => field.push(c),
+    }
+  }
+  if quoted {
+    return Err("unclosed quote".to_string());
+  }
+  if !field.is_empty() || !row.is_empty() {
+    row.push(field);
+    rows.push(row);
+  }
+  // Empty lines
+  rows.retain(|r| r.len() > 1 || r.first().is_some_and(|f| !f.trim().is_empty()));
+  Ok(rows)
+}
+
+// CSV dump with a header of LegacyRecord field names
+// Fields are separated by commas, or by semicolons if the header has them
+fn parse_csv(content: &str) -> Result<Vec<LegacyRecord>, String> {
+  let content = content.trim_start_matches('\u{feff}');
+  let header = content.lines().next().unwrap_or("");
+  let delimiter = match header.contains(';') {
+    true => ';',
+    false => ',',
+  };
+  let mut rows = csv_rows(content, delimiter)?.into_iter();
+  let columns = match rows.next() {
+    Some(columns) => columns
+      .iter()
+      .map(|c| c.trim().to_lowercase())
+      .collect::<Vec<String>>(),
+    None => return Ok(Vec::new()),
+  };
+  if !columns.iter().any(|c| c == "id") {
+    return Err("missing id column".to_string());
+  }
+  let mut res = Vec::new();
+  for (i, row) in rows.enumerate() {
+    if row.len() != columns.len() {
+      return Err(format!(
+        "record {}: {} fields instead of {}",
+        i + 1,
+        row.len(),
+        columns.len()
+      ));
+    }
+    let mut record = LegacyRecord::default();
+    for (column, value) in columns.iter().zip(row) {
+      record
+        .set(column, value)
+        .map_err(|e| format!("record {}: {}", i + 1, e))?;
+    }
+    res.push(record);
+  }
+  Ok(res)
+}
+
+// Parse dump by its extension, .csv or .json (array of records)
+pub fn parse_dump(path: &Path, content: &str) -> ServiceResult<Vec<LegacyRecord>> {
+  let extension = path
+    .extension()
+    .and_then(|e| e.to_str())
+    .unwrap_or("")
+    .to_lowercase();
+  let res = match extension.as_str() {
+    "csv" => parse_csv(content),
+    "json" => serde_json::from_str(content).map_err(|e| e.to_string()),
+    _ => Err("unknown format, expected .csv or .json".to_string()),
+  };
+  res.map_err(|e| ServiceError::internal_error(&format!("Wrong legacy dump: {}", e)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Migration {
+  // Customer count of a storage that was not empty
+  Skipped(usize),
+  Migrated(usize),
+}
+
+// Migrate legacy dump into the empty storage of a tenant
+pub fn migrate(
+  data_dir: &Path,
+  tenant: &str,
+  backend: Backend,
+  shadow: Option<Backend>,
+  path: &Path,
+) -> ServiceResult<Migration> {
+  // Nothing would be left to check after reopening
+  if backend == Backend::Memory {
+    return Err(ServiceError::internal_error(
+      "Migration needs a persistent storage backend",
+    ));
+  }
+  let mut db = open_db(data_dir, tenant, backend, shadow)?;
+  let records = parse_dump(path, &std::fs::read_to_string(path)?)?;
+  let mut customers = Vec::with_capacity(records.len());
+  let mut ids = HashSet::new();
+  let mut broken = 0;
+  for (i, record) in records.iter().enumerate() {
+    let res = record
+      .to_customer()
+      .and_then(|customer| match ids.insert(customer.id) {
+        true => Ok(customer),
+        false => Err("duplicate id".to_string()),
+      });
+    match res {
+      Ok(customer) => customers.push(customer),
+      Err(error) => {
+        eprintln!("Legacy record {} (ID {}): {}", i + 1, record.id, error);
+        broken += 1;
+      }
+    }
+  }
+  // Customers not in the dump were created after the migration,
+  // otherwise the storage has a part of the dump from a migration
+  // that was interrupted
+  let migrated = db.len() == customers.len() || db.iter().any(|c| !ids.contains(&c.id));
+  if db.len() > 0 && migrated {
+    return Ok(Migration::Skipped(db.len()));
+  }
+  if broken > 0 {
+    return Err(ServiceError::internal_error(&format!(
+      "{} broken legacy record(s), nothing migrated",
+      broken
+    )));
+  }
+  // Dry run checking the customers against each other,
+  // so a conflict does not stop the migration halfway
+  let mut tx = db.transaction();
+  for customer in &customers {
+    if tx.find_id(&customer.id).is_err() {
+      tx.insert(customer.clone()).map_err(|e| {
+        ServiceError::internal_error(&format!(
+          "Legacy customer {} cannot be migrated: {}",
+          customer.id, e
+        ))
+      })?;
+    }
+  }
+  drop(tx);
+  let ids = customers.iter().map(|c| c.id).collect::<Vec<u32>>();
+  for customer in customers {
+    let id = customer.id;
+    if db.find_id(&id).is_ok() {
+      continue;
+    }
+    db.insert(customer).map_err(|e| {
+      ServiceError::internal_error(&format!("Legacy customer {} not migrated: {}", id, e))
+    })?;
+  }
+  drop(db);
+  // Count the customers read back from the storage
+  let db = open_db(data_dir, tenant, backend, shadow)?;
+  let missing = ids.iter().filter(|id| db.find_id(id).is_err()).count();
+  if db.len() != ids.len() || missing > 0 {
+    return Err(ServiceError::internal_error(&format!(
+      "Migration check failed: {} legacy customers, {} stored, {} missing",
+      ids.len(),
+      db.len(),
+      missing
+    )));
+  }
+  Ok(Migration::Migrated(ids.len()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::CustomerKind;
+
+  #[test]
+  fn test_parse_csv() {
+    let content = "\u{feff}id;name;tax_number;address_street;date_created;created_by\r\n\
+      7;Kiss Béla;;\"Fő utca 1.; 2/4\";2019-03-01;2\r\n\
+      \r\n\
+      12;\"Kert \"\"Zöld\"\" Kft\";66064590-2-35;;2019-04-01T10:00:00Z;3\r\n";
+    let records = parse_csv(content).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].id, 7);
+    assert_eq!(records[0].address_street, "Fő utca 1.; 2/4");
+    assert_eq!(records[1].name, "Kert \"Zöld\" Kft");
+    let customer = records[1].to_customer().unwrap();
+    assert_eq!(customer.id, 12);
+    assert_eq!(customer.kind, CustomerKind::Company);
+    assert_eq!(customer.created_by, 3);
+    assert!(parse_csv("id,nev\n1,Kiss\n").is_err());
+    assert!(parse_csv("id,name\n1\n").is_err());
+    assert!(parse_csv("name\nKiss\n").is_err());
+    assert!(parse_csv("id,name\n1,\"Kiss\n").is_err());
+    assert!(parse_csv("").unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_to_customer() {
+    let record = LegacyRecord {
+      id: 3,
+      name: " Kiss Béla ".to_string(),
+      date_created: "2019-03-01".to_string(),
+      ..LegacyRecord::default()
+    };
+    let customer = record.to_customer().unwrap();
+    assert_eq!(customer.name, "Kiss Béla");
+    assert_eq!(customer.kind, CustomerKind::Private);
+    assert_eq!(
+      customer.date_created.to_rfc3339(),
+      "2019-03-01T00:00:00+00:00"
+    );
+    assert!(LegacyRecord {
+      id: 0,
+      ..record.clone()
+    }
+    .to_customer()
+    .is_err());
+    assert!(LegacyRecord {
+      tax_number: "123".to_string(),
+      ..record.clone()
+    }
+    .to_customer()
+    .is_err());
+    assert!(LegacyRecord {
+      date_created: String::new(),
+      ..record
+    }
+    .to_customer()
+    .is_err());
+  }
+
+  #[test]
+  fn test_migrate() {
+    let dir = tempfile::tempdir().unwrap();
+    let dump = dir.path().join("legacy.json");
+    std::fs::write(
+      &dump,
+      r#"[{"id": 5, "name": "Kiss Béla", "date_created": "2019-03-01", "created_by": 2},
+        {"id": 9, "name": "Nagy Éva", "phone": "+36301234567", "date_created": "2019-03-02"}]"#,
+    )
+    .unwrap();
+    let data_dir = dir.path().join("data");
+    let run = |path: &Path| migrate(&data_dir, "", Backend::VecPack, None, path);
+    assert_eq!(run(&dump).unwrap(), Migration::Migrated(2));
+    let db = open_db(&data_dir, "", Backend::VecPack, None).unwrap();
+    assert_eq!(db.find_id(&9).unwrap().name, "Nagy Éva");
+    drop(db);
+    // The storage is not empty any more
+    assert_eq!(run(&dump).unwrap(), Migration::Skipped(2));
+    // Interrupted migration is resumed
+    let resumed = tempfile::tempdir().unwrap();
+    let resumed_dir = resumed.path().join("data");
+    let mut db = open_db(&resumed_dir, "", Backend::Log, None).unwrap();
+    let content = std::fs::read_to_string(&dump).unwrap();
+    let first = parse_dump(&dump, &content).unwrap()[0].to_customer();
+    db.insert(first.unwrap()).unwrap();
+    drop(db);
+    assert_eq!(
+      migrate(&resumed_dir, "", Backend::Log, None, &dump).unwrap(),
+      Migration::Migrated(2)
+    );
+    // Broken and duplicate records leave the storage empty
+    let other = tempfile::tempdir().unwrap();
+    let data_dir = other.path().join("data");
+    let broken = other.path().join("legacy.csv");
+    std::fs::write(
+      &broken,
+      "id,name,date_created\n1,Kiss Béla,2019-03-01\n2,Nagy Éva,tegnap\n",
+    )
+    .unwrap();
+    let run = |path: &Path| migrate(&data_dir, "", Backend::VecPack, None, path);
+    assert!(run(&broken).is_err());
+    std::fs::write(
+      &broken,
+      "id,name,date_created\n1,Kiss Béla,2019-03-01\n1,Nagy Éva,2019-03-01\n",
+    )
+    .unwrap();
+    assert!(run(&broken).is_err());
+    assert!(
+      open_db(&data_dir, "", Backend::VecPack, None)
+        .unwrap()
+        .len()
+        == 0
+    );
+    assert!(run(&other.path().join("legacy.xml")).is_err());
+  }
+}
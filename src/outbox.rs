@@ -0,0 +1,1004 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::customer::Customer;
+use crate::events::{CustomerEvent, CustomerEventKind, Events};
+use crate::prelude::*;
+use crate::storage::{self, RawRecord};
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// Retry delay after the first failed delivery
+// doubled after every further failure
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+// Max retry delay
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+// Default max number of pending events
+pub const DEFAULT_MAX_PENDING: usize = 100_000;
+
+// The log is compacted when it has more records than this,
+// and most of them are stale
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+// Event waiting for delivery
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxEntry {
+  pub id: u64,
+  pub event: CustomerEvent,
+  pub attempts: u32,
+  pub next_attempt: DateTime<Utc>,
+  // Positions of the sinks the event is delivered to,
+  // retries skip them
+  pub delivered: Vec<u32>,
+  // The customer change of the event is not stored yet,
+  // it is not delivered until it is confirmed
+  pub staged: bool,
+}
+
+// Event given up on, written to the dead letter log
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+  pub date: DateTime<Utc>,
+  pub attempts: u32,
+  pub error: String,
+  pub event: CustomerEvent,
+}
+
+// Where events go after too many failed deliveries
+struct DeadLetters {
+  max_attempts: u32,
+  path: PathBuf,
+}
+
+impl DeadLetters {
+  // Append JSON line to the log
+  fn write(&self, letter: &DeadLetter) -> ServiceResult<()> {
+    if let Some(dir) = self.path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let line = serde_json::to_string(letter)
+      .map_err(|e| ServiceError::internal_error(&format!("Dead letter error: {}", e)))?;
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+    Ok(())
+  }
+}
+
+// Pack file the events were stored in before the log
+// Its events are moved to the log once
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct OutboxData {
+  next_id: u64,
+  entries: Vec<OutboxEntryV2>,
+  // ID of the last event queued with push_once
+  last_source_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct OutboxEntryV2 {
+  id: u64,
+  event: CustomerEvent,
+  attempts: u32,
+  next_attempt: DateTime<Utc>,
+  delivered: Vec<u32>,
+}
+
+// Pack layout before the deliveries were tracked per sink
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct OutboxDataV1 {
+  next_id: u64,
+  entries: Vec<OutboxEntryV1>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct OutboxEntryV1 {
+  id: u64,
+  event: CustomerEvent,
+  attempts: u32,
+  next_attempt: DateTime<Utc>,
+}
+
+impl From<OutboxDataV1> for OutboxData {
+  fn from(data: OutboxDataV1) -> Self {
+    Self {
+      next_id: data.next_id,
+      entries: data
+        .entries
+        .into_iter()
+        .map(|e| OutboxEntryV2 {
+          id: e.id,
+          event: e.event,
+          attempts: e.attempts,
+          next_attempt: e.next_attempt,
+          delivered: Vec::new(),
+        })
+        .collect(),
+      last_source_id: 0,
+    }
+  }
+}
+
+impl TryFrom for OutboxData {
+  type TryFrom = OutboxDataV1;
+}
+
+// Record of the outbox log
+#[derive(Serialize, Deserialize, Debug)]
+enum LogRecord {
+  // Counters, first record of a compacted log
+  Ids {
+    next_id: u64,
+    last_source_id: u64,
+  },
+  // New events, with the source ID of a forwarded event
+  Stored {
+    entries: Vec<OutboxEntry>,
+    source_id: Option<u64>,
+  },
+  // Staged events whose customer changes are stored
+  Confirmed(Vec<u64>),
+  // Failed delivery of an event
+  Attempted {
+    id: u64,
+    attempts: u32,
+    next_attempt: DateTime<Utc>,
+    delivered: Vec<u32>,
+  },
+  // Delivered, given up on and cancelled events
+  Removed(Vec<u64>),
+}
+
+// Append only log of the outbox, with its pending events in memory
+//
+// Records are framed like the customer log records, a torn record
+// at the end is dropped on load. Every change appends a small record,
+// only new events carry their customer.
+struct OutboxLog {
+  path: PathBuf,
+  file: File,
+  record_count: usize,
+  next_id: u64,
+  last_source_id: u64,
+  // Pending events in the order they were stored
+  entries: BTreeMap<u64, OutboxEntry>,
+  // Events staged before the restart, resolved by recover
+  recovering: BTreeSet<u64>,
+}
+
+impl OutboxLog {
+  fn open(dir: &Path) -> ServiceResult<Self> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join("events.log");
+    let legacy = dir.join("events");
+    if legacy.exists() {
+      if !path.exists() {
+        migrate(&legacy, &path)?;
+      }
+      std::fs::remove_file(&legacy)?;
+    }
+    let data = match path.exists() {
+      true => std::fs::read(&path)?,
+      false => Vec::new(),
+    };
+    let mut log = Self {
+      file: OpenOptions::new().create(true).append(true).open(&path)?,
+      path,
+      record_count: 0,
+      next_id: 0,
+      last_source_id: 0,
+      entries: BTreeMap::new(),
+      recovering: BTreeSet::new(),
+    };
+    let mut position = 0;
+    let mut broken = false;
+    loop {
+      match storage::read_raw_record(&data, position) {
+        RawRecord::Data(record, _, next) => {
+          match bincode::deserialize::<LogRecord>(record) {
+            Ok(record) => log.apply(record),
+            Err(_) => {
+              eprintln!(
+                "Skipping undecodable outbox record at {} in {:?}",
+                position, log.path
+              );
+              broken = true;
+            }
+          }
+          log.record_count += 1;
+          position = next;
+        }
+        RawRecord::Broken(next, reason) => {
+          eprintln!(
+            "Skipping broken outbox record at {} in {:?}: {}",
+            position, log.path, reason
+          );
+          broken = true;
+          position = next;
+        }
+        RawRecord::End => break,
+      }
+    }
+    // Drop torn record
+    if position < data.len() {
+      eprintln!(
+        "Dropping torn outbox record at {} in {:?}",
+        position, log.path
+      );
+      log.file.set_len(position as u64)?;
+      log.file.sync_data()?;
+    }
+    log.recovering = log
+      .entries
+      .values()
+      .filter(|e| e.staged)
+      .map(|e| e.id)
+      .collect();
+    if broken || log.is_stale() {
+      log.compact()?;
+    }
+    Ok(log)
+  }
+  fn apply(&mut self, record: LogRecord) {
+    match record {
+      LogRecord::Ids {
+        next_id,
+        last_source_id,
+      } => {
+        self.next_id = next_id;
+        self.last_source_id = last_source_id;
+      }
+      LogRecord::Stored { entries, source_id } => {
+        for entry in entries {
+          self.next_id = self.next_id.max(entry.id);
+          self.entries.insert(entry.id, entry);
+        }
+        if let Some(source_id) = source_id {
+          self.last_source_id = source_id;
+        }
+      }
+      LogRecord::Confirmed(ids) => {
+        for id in ids {
+          if let Some(entry) = self.entries.get_mut(&id) {
+            entry.staged = false;
+          }
+          self.recovering.remove(&id);
+        }
+      }
+      LogRecord::Attempted {
+        id,
+        attempts,
+        next_attempt,
+        delivered,
+      } => {
+        if let Some(entry) = self.entries.get_mut(&id) {
+          entry.attempts = attempts;
+          entry.next_attempt = next_attempt;
+          entry.delivered = delivered;
+        }
+      }
+      LogRecord::Removed(ids) => {
+        for id in ids {
+          self.entries.remove(&id);
+          self.recovering.remove(&id);
+        }
+      }
+    }
+  }
+  // Append record and sync it
+  fn write(&mut self, record: &LogRecord) -> ServiceResult<()> {
+    let data = bincode::serialize(record).map_err(PackError::from)?;
+    storage::append_record(&mut self.file, &storage::with_header(data, 0))?;
+    self.record_count += 1;
+    Ok(())
+  }
+  // Apply record, then append it
+  // The change is kept in memory even if it cannot be written,
+  // the log replays to an earlier state, which is retried
+  // or recovered after a restart
+  fn update(&mut self, record: LogRecord) -> ServiceResult<()> {
+    let res = self.write(&record);
+    self.apply(record);
+    if self.is_stale() {
+      if let Err(error) = self.compact() {
+        eprintln!("Error while compacting event outbox: {}", error);
+      }
+    }
+    res
+  }
+  fn is_stale(&self) -> bool {
+    self.record_count > COMPACT_MIN_RECORDS && self.record_count > 2 * self.entries.len()
+  }
+  // Rewrite the log with the pending events only
+  fn compact(&mut self) -> ServiceResult<()> {
+    self.file = write_log(
+      &self.path,
+      self.next_id,
+      self.last_source_id,
+      self.entries.values(),
+    )?;
+    self.record_count = self.entries.len() + 1;
+    Ok(())
+  }
+}
+
+// Write log next to path, then atomically replace it
+// Returns the new log opened for appending
+fn write_log<'a>(
+  path: &Path,
+  next_id: u64,
+  last_source_id: u64,
+  entries: impl Iterator<Item = &'a OutboxEntry>,
+) -> ServiceResult<File> {
+  let encode = |record: &LogRecord| -> ServiceResult<Vec<u8>> {
+    let data = bincode::serialize(record).map_err(PackError::from)?;
+    Ok(storage::with_header(data, 0))
+  };
+  let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+  tmp_name.push("_compact");
+  let tmp_path = path.with_file_name(tmp_name);
+  let mut tmp = File::create(&tmp_path)?;
+  tmp.write_all(&encode(&LogRecord::Ids {
+    next_id,
+    last_source_id,
+  })?)?;
+  for entry in entries {
+    tmp.write_all(&encode(&LogRecord::Stored {
+      entries: vec![entry.clone()],
+      source_id: None,
+    })?)?;
+  }
+  tmp.sync_all()?;
+  std::fs::rename(&tmp_path, path)?;
+  Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+// Move the events of the legacy Pack file to a new log
+// The Pack file is removed by the caller, after the log is in place
+fn migrate(legacy: &Path, path: &Path) -> ServiceResult<()> {
+  let data = Pack::<OutboxData>::try_load_from_path(legacy.to_path_buf())?
+    .unpack()
+    .clone();
+  let entries = data
+    .entries
+    .into_iter()
+    .map(|e| OutboxEntry {
+      id: e.id,
+      event: e.event,
+      attempts: e.attempts,
+      next_attempt: e.next_attempt,
+      delivered: e.delivered,
+      staged: false,
+    })
+    .collect::<Vec<OutboxEntry>>();
+  write_log(path, data.next_id, data.last_source_id, entries.iter())?;
+  Ok(())
+}
+
+// Event delivery target
+// The ID identifies the event in its outbox, every attempt
+// to deliver the event has the same ID
+#[tonic::async_trait]
+pub trait EventSink: Send + Sync {
+  async fn deliver(&self, id: u64, event: &CustomerEvent) -> Result<(), String>;
+}
+
+// In-process subscribers (Watch RPC)
+#[tonic::async_trait]
+impl EventSink for Events {
+  async fn deliver(&self, _id: u64, event: &CustomerEvent) -> Result<(), String> {
+    self.publish(&event.tenant, event.kind, &event.customer);
+    Ok(())
+  }
+}
+
+// Persistent event outbox
+//
+// Events are appended to data/outbox/events.log, and a background
+// task delivers them to the event sinks retrying with exponential
+// backoff. Pending events survive restarts, so sink outages do not
+// lose events. Deliveries are tracked per sink, a failed event is
+// only retried on the sinks it was not delivered to. Without dead
+// letters, events are retried until they are delivered.
+// Customer changes stage their events in the same commit, see
+// CustomerDb::set_outbox, so an event is delivered if and only if
+// its change is stored. Over max_pending pending events, new events
+// and the changes staging them are rejected.
+pub struct Outbox {
+  log: std::sync::Mutex<OutboxLog>,
+  notify: Notify,
+  dead_letters: Option<DeadLetters>,
+  max_pending: usize,
+}
+
+impl Outbox {
+  pub fn load(data_dir: &Path) -> ServiceResult<Self> {
+    Self::load_from_path(data_dir.join("outbox"))
+  }
+  pub fn load_from_path(path: PathBuf) -> ServiceResult<Self> {
+    Ok(Self {
+      log: std::sync::Mutex::new(OutboxLog::open(&path)?),
+      notify: Notify::new(),
+      dead_letters: None,
+      max_pending: DEFAULT_MAX_PENDING,
+    })
+  }
+  // Give up on events after max_attempts failed deliveries,
+  // and append them to the JSON lines log at path
+  pub fn with_dead_letters(self, max_attempts: u32, path: PathBuf) -> Self {
+    Self {
+      dead_letters: Some(DeadLetters { max_attempts, path }),
+      ..self
+    }
+  }
+  // Reject new events over max_pending pending events
+  pub fn with_max_pending(self, max_pending: usize) -> Self {
+    Self {
+      max_pending,
+      ..self
+    }
+  }
+  fn log(&self) -> MutexGuard<'_, OutboxLog> {
+    self.log.lock().unwrap_or_else(|e| e.into_inner())
+  }
+  // Store event for delivery
+  pub fn push(
+    &self,
+    tenant: &str,
+    kind: CustomerEventKind,
+    customer: &Customer,
+  ) -> ServiceResult<()> {
+    self.store(false, None, tenant, &[(kind, customer)])?;
+    Ok(())
+  }
+  // Store event forwarded from an other outbox, with its ID there
+  // An event stored right before with the same source ID is not
+  // stored again, so retries of the other outbox do not duplicate it
+  pub fn push_once(
+    &self,
+    source_id: u64,
+    tenant: &str,
+    kind: CustomerEventKind,
+    customer: &Customer,
+  ) -> ServiceResult<()> {
+    self.store(false, Some(source_id), tenant, &[(kind, customer)])?;
+    Ok(())
+  }
+  // Store events of customer changes about to be stored
+  // They are delivered once confirmed, returns their IDs
+  pub fn stage(
+    &self,
+    tenant: &str,
+    events: &[(CustomerEventKind, &Customer)],
+  ) -> ServiceResult<Vec<u64>> {
+    self.store(true, None, tenant, events)
+  }
+  fn store(
+    &self,
+    staged: bool,
+    source_id: Option<u64>,
+    tenant: &str,
+    events: &[(CustomerEventKind, &Customer)],
+  ) -> ServiceResult<Vec<u64>> {
+    let mut log = self.log();
+    if source_id.is_some() && source_id == Some(log.last_source_id) {
+      return Ok(Vec::new());
+    }
+    if log.entries.len() + events.len() > self.max_pending {
+      return Err(ServiceError::resource_exhausted(
+        "Túl sok kézbesítésre váró esemény, próbálja újra később",
+      ));
+    }
+    let now = Utc::now();
+    let entries = (log.next_id + 1..)
+      .zip(events)
+      .map(|(id, (kind, customer))| OutboxEntry {
+        id,
+        event: CustomerEvent {
+          tenant: tenant.to_string(),
+          kind: *kind,
+          customer: Customer::clone(customer),
+        },
+        attempts: 0,
+        next_attempt: now,
+        delivered: Vec::new(),
+        staged,
+      })
+      .collect::<Vec<OutboxEntry>>();
+    let ids = entries.iter().map(|e| e.id).collect::<Vec<u64>>();
+    // Nothing is kept if the events cannot be written
+    let record = LogRecord::Stored { entries, source_id };
+    log.write(&record)?;
+    log.apply(record);
+    if !staged {
+      self.notify.notify_one();
+    }
+    Ok(ids)
+  }
+  // Deliver staged events, their changes are stored
+  pub fn confirm(&self, ids: &[u64]) {
+    if ids.is_empty() {
+      return;
+    }
+    if let Err(error) = self.log().update(LogRecord::Confirmed(ids.to_vec())) {
+      eprintln!("Error while confirming outbox events: {}", error);
+    }
+    self.notify.notify_one();
+  }
+  // Drop staged events, their changes are not stored
+  pub fn cancel(&self, ids: &[u64]) {
+    if ids.is_empty() {
+      return;
+    }
+    if let Err(error) = self.log().update(LogRecord::Removed(ids.to_vec())) {
+      eprintln!("Error while cancelling outbox events: {}", error);
+    }
+    self.notify.notify_one();
+  }
+  // Resolve the events of a tenant staged before the restart
+  // An event is confirmed if its customer is stored with the
+  // modification date of the event, otherwise it is cancelled
+  pub fn recover(&self, tenant: &str, stored: impl Fn(u32) -> Option<DateTime<Utc>>) {
+    let ids = |entries: Vec<&OutboxEntry>| entries.iter().map(|e| e.id).collect::<Vec<u64>>();
+    let (confirmed, cancelled) = {
+      let log = self.log();
+      let (confirmed, cancelled) = log
+        .recovering
+        .iter()
+        .filter_map(|id| log.entries.get(id))
+        .filter(|e| e.event.tenant == tenant)
+        .partition(|e| stored(e.event.customer.id) == Some(e.event.customer.last_modified));
+      (ids(confirmed), ids(cancelled))
+    };
+    self.confirm(&confirmed);
+    self.cancel(&cancelled);
+  }
+  // Cancel the events staged before the restart, whose tenant
+  // was not recovered
+  pub fn cancel_unrecovered(&self) {
+    let ids = self.log().recovering.iter().copied().collect::<Vec<u64>>();
+    self.cancel(&ids);
+  }
+  // Deliver events due at now to every sink, in the order they
+  // were stored. Events delivered to every sink are removed.
+  // A failed event is rescheduled for the sinks it was not
+  // delivered to, and the events after it wait for it to keep
+  // the order. Events given up on are moved to the dead letter log.
+  // Staged events and the ones after them wait for the confirmation.
+  // Returns when the next pending event is due.
+  pub async fn deliver_due(
+    &self,
+    sinks: &[Arc<dyn EventSink>],
+    now: DateTime<Utc>,
+  ) -> ServiceResult<Option<DateTime<Utc>>> {
+    let due = self
+      .log()
+      .entries
+      .values()
+      .take_while(|e| !e.staged && e.next_attempt <= now)
+      .cloned()
+      .collect::<Vec<OutboxEntry>>();
+    for entry in due {
+      let mut delivered = entry.delivered.clone();
+      let mut res = Ok(());
+      for (position, sink) in (0..).zip(sinks) {
+        if delivered.contains(&position) {
+          continue;
+        }
+        match sink.deliver(entry.id, &entry.event).await {
+          Ok(()) => delivered.push(position),
+          Err(error) => res = Err(error),
+        }
+      }
+      let attempts = entry.attempts + 1;
+      // Dead letters are written before the event is removed
+      let given_up = match (&res, &self.dead_letters) {
+        (Err(error), Some(dead_letters)) if attempts >= dead_letters.max_attempts => {
+          dead_letters.write(&DeadLetter {
+            date: now,
+            attempts,
+            error: error.clone(),
+            event: entry.event.clone(),
+          })?;
+          true
+        }
+        _ => false,
+      };
+      self.log().update(match res.is_ok() || given_up {
+        true => LogRecord::Removed(vec![entry.id]),
+        false => LogRecord::Attempted {
+          id: entry.id,
+          attempts,
+          next_attempt: now + chrono::Duration::from_std(retry_delay(attempts)).unwrap(),
+          delivered,
+        },
+      })?;
+      if let Err(error) = res {
+        eprintln!("Event {} delivery failed: {}", entry.id, error);
+        if !given_up {
+          break;
+        }
+        eprintln!(
+          "Event {} moved to dead letters after {} attempts",
+          entry.id, attempts
+        );
+      }
+    }
+    Ok(
+      self
+        .log()
+        .entries
+        .values()
+        .next()
+        .filter(|e| !e.staged)
+        .map(|e| e.next_attempt),
+    )
+  }
+}
+
+// Retry delay after the given number of failed attempts
+pub fn retry_delay(attempts: u32) -> Duration {
+  let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+  RETRY_BASE_DELAY
+    .checked_mul(factor)
+    .unwrap_or(RETRY_MAX_DELAY)
+    .min(RETRY_MAX_DELAY)
+}
+
+// Deliver outbox events until the process stops
+pub async fn run_delivery(outbox: Arc<Outbox>, sinks: Vec<Arc<dyn EventSink>>) {
+  loop {
+    let now = Utc::now();
+    let wait = match outbox.deliver_due(&sinks, now).await {
+      Ok(Some(next)) => (next - now).to_std().unwrap_or_default(),
+      Ok(None) => RETRY_MAX_DELAY,
+      Err(error) => {
+        eprintln!("Error while updating event outbox: {}", error);
+        RETRY_BASE_DELAY
+      }
+    };
+    tokio::select! {
+      _ = outbox.notify.notified() => {}
+      _ = tokio::time::sleep(wait) => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicBool, Ordering};
+
+  struct TestSink {
+    up: AtomicBool,
+    delivered: std::sync::Mutex<Vec<u32>>,
+  }
+
+  #[tonic::async_trait]
+  impl EventSink for TestSink {
+    async fn deliver(&self, _id: u64, event: &CustomerEvent) -> Result<(), String> {
+      if !self.up.load(Ordering::SeqCst) {
+        return Err("sink is down".to_string());
+      }
+      self.delivered.lock().unwrap().push(event.customer.id);
+      Ok(())
+    }
+  }
+
+  fn test_sink(up: bool) -> Arc<TestSink> {
+    Arc::new(TestSink {
+      up: AtomicBool::new(up),
+      delivered: std::sync::Mutex::new(Vec::new()),
+    })
+  }
+
+  fn pending(outbox: &Outbox) -> usize {
+    outbox.log().entries.len()
+  }
+
+  fn customer(id: u32) -> Customer {
+    Customer {
+      id,
+      ..Customer::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_delivery_with_retry() {
+    let dir = tempfile::tempdir().unwrap();
+    let sink = test_sink(false);
+    let sinks: Vec<Arc<dyn EventSink>> = vec![sink.clone()];
+    let outbox = Outbox::load(dir.path()).unwrap();
+    outbox
+      .push("", CustomerEventKind::Created, &customer(1))
+      .unwrap();
+    outbox
+      .push("", CustomerEventKind::Created, &customer(2))
+      .unwrap();
+    // Sink is down, first event is rescheduled
+    let now = Utc::now();
+    let next = outbox.deliver_due(&sinks, now).await.unwrap().unwrap();
+    assert_eq!(pending(&outbox), 2);
+    assert!(next > now);
+
+    // Pending events survive restart
+    drop(outbox);
+    let outbox = Outbox::load(dir.path()).unwrap();
+    assert_eq!(pending(&outbox), 2);
+
+    // Event 2 waits for the rescheduled event 1
+    sink.up.store(true, Ordering::SeqCst);
+    outbox.deliver_due(&sinks, now).await.unwrap();
+    assert!(sink.delivered.lock().unwrap().is_empty());
+    let next = outbox.deliver_due(&sinks, next).await.unwrap();
+    assert_eq!(*sink.delivered.lock().unwrap(), [1, 2]);
+    assert!(next.is_none());
+    assert_eq!(pending(&outbox), 0);
+  }
+
+  #[tokio::test]
+  async fn test_dead_letters() {
+    let dir = tempfile::tempdir().unwrap();
+    let sink = test_sink(false);
+    let sinks: Vec<Arc<dyn EventSink>> = vec![sink.clone()];
+    let path = dir.path().join("dead_letters.jsonl");
+    let outbox = Outbox::load(dir.path())
+      .unwrap()
+      .with_dead_letters(2, path.clone());
+    for id in [1, 2] {
+      outbox
+        .push("", CustomerEventKind::Updated, &customer(id))
+        .unwrap();
+    }
+    let now = Utc::now();
+    let next = outbox.deliver_due(&sinks, now).await.unwrap().unwrap();
+    assert!(!path.exists());
+    // Second failure gives up on event 1, event 2 is tried next
+    let next = outbox.deliver_due(&sinks, next).await.unwrap().unwrap();
+    assert_eq!(pending(&outbox), 1);
+    let letters = std::fs::read_to_string(&path)
+      .unwrap()
+      .lines()
+      .map(|l| serde_json::from_str::<DeadLetter>(l).unwrap())
+      .collect::<Vec<DeadLetter>>();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].event.customer.id, 1);
+    assert_eq!(letters[0].attempts, 2);
+    assert_eq!(letters[0].error, "sink is down");
+    sink.up.store(true, Ordering::SeqCst);
+    assert!(outbox.deliver_due(&sinks, next).await.unwrap().is_none());
+    assert_eq!(*sink.delivered.lock().unwrap(), [2]);
+  }
+
+  #[tokio::test]
+  async fn test_delivery_per_sink() {
+    let dir = tempfile::tempdir().unwrap();
+    let (up, down) = (test_sink(true), test_sink(false));
+    let sinks: Vec<Arc<dyn EventSink>> = vec![up.clone(), down.clone()];
+    let outbox = Outbox::load(dir.path()).unwrap();
+    outbox
+      .push("", CustomerEventKind::Created, &customer(1))
+      .unwrap();
+    let now = Utc::now();
+    let next = outbox.deliver_due(&sinks, now).await.unwrap().unwrap();
+    assert_eq!(*up.delivered.lock().unwrap(), [1]);
+    // Retries skip the sinks the event is delivered to,
+    // even after restart
+    drop(outbox);
+    let outbox = Outbox::load(dir.path()).unwrap();
+    down.up.store(true, Ordering::SeqCst);
+    assert!(outbox.deliver_due(&sinks, next).await.unwrap().is_none());
+    assert_eq!(*up.delivered.lock().unwrap(), [1]);
+    assert_eq!(*down.delivered.lock().unwrap(), [1]);
+  }
+
+  #[tokio::test]
+  async fn test_push_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let outbox = Outbox::load(dir.path()).unwrap();
+    for source_id in [4, 4, 5] {
+      outbox
+        .push_once(source_id, "", CustomerEventKind::Updated, &customer(1))
+        .unwrap();
+    }
+    assert_eq!(pending(&outbox), 2);
+  }
+
+  #[tokio::test]
+  async fn test_legacy_outbox() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut legacy: Pack<OutboxDataV1> =
+      Pack::load_or_init(dir.path().join("outbox"), "events").unwrap();
+    legacy
+      .update(|data| {
+        data.next_id = 1;
+        data.entries.push(OutboxEntryV1 {
+          id: 1,
+          event: CustomerEvent {
+            tenant: String::new(),
+            kind: CustomerEventKind::Created,
+            customer: customer(1),
+          },
+          attempts: 0,
+          next_attempt: Utc::now(),
+        });
+      })
+      .unwrap();
+    drop(legacy);
+    let outbox = Outbox::load(dir.path()).unwrap();
+    // The events are moved to the log
+    assert!(!dir.path().join("outbox").join("events").exists());
+    let sink = test_sink(true);
+    let sinks: Vec<Arc<dyn EventSink>> = vec![sink.clone()];
+    assert!(outbox
+      .deliver_due(&sinks, Utc::now())
+      .await
+      .unwrap()
+      .is_none());
+    assert_eq!(*sink.delivered.lock().unwrap(), [1]);
+    drop(outbox);
+    let outbox = Outbox::load(dir.path()).unwrap();
+    assert_eq!(pending(&outbox), 0);
+    assert_eq!(outbox.log().next_id, 1);
+  }
+
+  #[tokio::test]
+  async fn test_staged_events() {
+    let dir = tempfile::tempdir().unwrap();
+    let sink = test_sink(true);
+    let sinks: Vec<Arc<dyn EventSink>> = vec![sink.clone()];
+    let outbox = Outbox::load(dir.path()).unwrap();
+    let (first, second) = (customer(1), customer(2));
+    let events = [
+      (CustomerEventKind::Created, &first),
+      (CustomerEventKind::Created, &second),
+    ];
+    let ids = outbox.stage("", &events).unwrap();
+    outbox
+      .push("", CustomerEventKind::Updated, &customer(3))
+      .unwrap();
+    // Staged events and the ones after them wait
+    let now = Utc::now();
+    assert!(outbox.deliver_due(&sinks, now).await.unwrap().is_none());
+    assert!(sink.delivered.lock().unwrap().is_empty());
+    outbox.confirm(&ids[..1]);
+    outbox.cancel(&ids[1..]);
+    assert!(outbox.deliver_due(&sinks, now).await.unwrap().is_none());
+    assert_eq!(*sink.delivered.lock().unwrap(), [1, 3]);
+  }
+
+  #[tokio::test]
+  async fn test_recover() {
+    let dir = tempfile::tempdir().unwrap();
+    let outbox = Outbox::load(dir.path()).unwrap();
+    let customers = [customer(1), customer(2)];
+    for c in &customers {
+      outbox
+        .stage("shop_a", &[(CustomerEventKind::Updated, c)])
+        .unwrap();
+    }
+    outbox
+      .stage("shop_b", &[(CustomerEventKind::Created, &customer(3))])
+      .unwrap();
+    // Restart before the events are confirmed, only
+    // customer 1 is stored as it is in its event
+    drop(outbox);
+    let outbox = Outbox::load(dir.path()).unwrap();
+    let stored = customers[0].last_modified;
+    outbox.recover("shop_a", |id| match id {
+      1 => Some(stored),
+      _ => Some(stored - chrono::Duration::seconds(1)),
+    });
+    assert_eq!(pending(&outbox), 2);
+    outbox.cancel_unrecovered();
+    assert_eq!(pending(&outbox), 1);
+    let sink = test_sink(true);
+    let sinks: Vec<Arc<dyn EventSink>> = vec![sink.clone()];
+    outbox.deliver_due(&sinks, Utc::now()).await.unwrap();
+    assert_eq!(*sink.delivered.lock().unwrap(), [1]);
+    // Events staged after the restart are not cancelled
+    let id = outbox
+      .stage("shop_b", &[(CustomerEventKind::Created, &customer(4))])
+      .unwrap();
+    outbox.cancel_unrecovered();
+    outbox.confirm(&id);
+    outbox.deliver_due(&sinks, Utc::now()).await.unwrap();
+    assert_eq!(*sink.delivered.lock().unwrap(), [1, 4]);
+  }
+
+  #[test]
+  fn test_max_pending() {
+    let dir = tempfile::tempdir().unwrap();
+    let outbox = Outbox::load(dir.path()).unwrap().with_max_pending(2);
+    let code = |res: ServiceResult<()>| res.err().map(|e| e.code());
+    let push = |id| outbox.push("", CustomerEventKind::Created, &customer(id));
+    assert_eq!(code(push(1)), None);
+    let (second, third) = (customer(2), customer(3));
+    let events = [
+      (CustomerEventKind::Created, &second),
+      (CustomerEventKind::Created, &third),
+    ];
+    assert_eq!(
+      code(outbox.stage("", &events).map(|_| ())),
+      Some(tonic::Code::ResourceExhausted)
+    );
+    assert_eq!(code(push(2)), None);
+    assert_eq!(code(push(3)), Some(tonic::Code::ResourceExhausted));
+    assert_eq!(pending(&outbox), 2);
+  }
+
+  #[tokio::test]
+  async fn test_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("outbox").join("events.log");
+    let sink = test_sink(true);
+    let sinks: Vec<Arc<dyn EventSink>> = vec![sink.clone()];
+    let outbox = Outbox::load(dir.path()).unwrap();
+    outbox
+      .push_once(7, "", CustomerEventKind::Created, &customer(1))
+      .unwrap();
+    // Torn record at the end is dropped
+    drop(outbox);
+    let size = std::fs::metadata(&path).unwrap().len();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+    drop(file);
+    let outbox = Outbox::load(dir.path()).unwrap();
+    assert_eq!(pending(&outbox), 1);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    // Delivered events are compacted away
+    for id in 2..=COMPACT_MIN_RECORDS as u32 {
+      outbox
+        .push("", CustomerEventKind::Updated, &customer(id))
+        .unwrap();
+      outbox.deliver_due(&sinks, Utc::now()).await.unwrap();
+    }
+    assert_eq!(pending(&outbox), 0);
+    assert!(outbox.log().record_count < COMPACT_MIN_RECORDS);
+    assert_eq!(sink.delivered.lock().unwrap().len(), COMPACT_MIN_RECORDS);
+    // The IDs are kept
+    drop(outbox);
+    let outbox = Outbox::load(dir.path()).unwrap();
+    assert_eq!(outbox.log().next_id, COMPACT_MIN_RECORDS as u64);
+    outbox
+      .push_once(7, "", CustomerEventKind::Created, &customer(1))
+      .unwrap();
+    assert_eq!(pending(&outbox), 0);
+  }
+
+  #[test]
+  fn test_retry_delay() {
+    assert_eq!(retry_delay(1), Duration::from_secs(1));
+    assert_eq!(retry_delay(2), Duration::from_secs(2));
+    assert_eq!(retry_delay(4), Duration::from_secs(8));
+    assert_eq!(retry_delay(20), RETRY_MAX_DELAY);
+    assert_eq!(retry_delay(u32::MAX), RETRY_MAX_DELAY);
+  }
+}
@@ -0,0 +1,214 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Request payload guard
+//
+// Tonic reads requests of any size, and reports undecodable
+// messages, e.g. strings with invalid UTF-8 from a broken gateway,
+// as INTERNAL errors. The generated service is wrapped, so requests
+// over the size limit are rejected with RESOURCE_EXHAUSTED before
+// they are read as a whole, and decode errors are answered with
+// INVALID_ARGUMENT instead.
+// The body is read once, by the outermost layer reading it, and
+// handed to the layers inside in the request extensions.
+
+use crate::prelude::*;
+use hyper::body::{Bytes, HttpBody};
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::transport::{Body, NamedService};
+use tonic::{Code, Status};
+
+// Default max request size
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 16 * 1024 * 1024;
+
+// Prefix of prost decode error messages
+const DECODE_ERROR_PREFIX: &str = "failed to decode Protobuf message";
+
+#[derive(Clone)]
+pub struct PayloadGuard<S> {
+  inner: S,
+  max_request_bytes: usize,
+}
+
+impl<S> PayloadGuard<S> {
+  pub fn new(inner: S, max_request_bytes: usize) -> Self {
+    Self {
+      inner,
+      max_request_bytes,
+    }
+  }
+}
+
+impl<S: NamedService> NamedService for PayloadGuard<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+// Request over the size limit
+pub(crate) fn too_large(max_bytes: usize) -> ServiceError {
+  ServiceError::resource_exhausted(&format!("A kérés max {} bájt lehet", max_bytes))
+}
+
+// Content-Length of the request is over max bytes
+// Requests without it are limited while they are read
+pub(crate) fn declared_too_large(headers: &http::HeaderMap, max_bytes: usize) -> bool {
+  headers
+    .get(http::header::CONTENT_LENGTH)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<u64>().ok())
+    .is_some_and(|len| len > max_bytes as u64)
+}
+
+// Request body read as a whole by an outer layer
+#[derive(Clone)]
+pub(crate) struct BufferedBody(pub Bytes);
+
+// Body of a unary request, read as a whole, or None if it
+// is over max bytes. The body read by an outer layer is
+// taken from the extensions instead of reading it again.
+pub(crate) async fn buffer(
+  parts: &mut http::request::Parts,
+  body: Body,
+  max_bytes: usize,
+) -> Result<Option<Bytes>, hyper::Error> {
+  if let Some(BufferedBody(body)) = parts.extensions.get() {
+    return Ok(Some(body.clone()).filter(|body| body.len() <= max_bytes));
+  }
+  let body = match read_body(body, max_bytes).await? {
+    Some(body) => Bytes::from(body),
+    None => return Ok(None),
+  };
+  parts.extensions.insert(BufferedBody(body.clone()));
+  Ok(Some(body))
+}
+
+// Body of a request to a layer inside of the guard, see buffer
+// Errors are returned as the response to send
+pub(crate) async fn buffered(
+  parts: &mut http::request::Parts,
+  body: Body,
+) -> Result<Bytes, http::Response<BoxBody>> {
+  match buffer(parts, body, usize::MAX).await {
+    Ok(body) => Ok(body.unwrap_or_default()),
+    Err(error) => Err(read_error(error)),
+  }
+}
+
+// Response to a request that could not be read
+pub(crate) fn read_error(error: hyper::Error) -> http::Response<BoxBody> {
+  Status::internal(format!("Error while reading request: {}", error)).to_http()
+}
+
+// Read body, or None if it is over max bytes
+pub(crate) async fn read_body(
+  mut body: Body,
+  max_bytes: usize,
+) -> Result<Option<Vec<u8>>, hyper::Error> {
+  let mut res = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk?;
+    if res.len() + chunk.len() > max_bytes {
+      return Ok(None);
+    }
+    res.extend_from_slice(&chunk);
+  }
+  Ok(Some(res))
+}
+
+// Replace decode errors of the generated service
+fn map_decode_error(response: http::Response<BoxBody>) -> http::Response<BoxBody> {
+  match Status::from_header_map(response.headers()) {
+    Some(status)
+      if status.code() == Code::Internal && status.message().starts_with(DECODE_ERROR_PREFIX) =>
+    {
+      let msg = match status.message().contains("UTF-8") {
+        true => "A kérés érvénytelen UTF-8 szöveget tartalmaz",
+        false => "A kérés nem dekódolható",
+      };
+      Status::from(ServiceError::bad_request(msg)).to_http()
+    }
+    _ => response,
+  }
+}
+
+impl<S> Service<http::Request<Body>> for PayloadGuard<S>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  type Response = http::Response<BoxBody>;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+    let max_bytes = self.max_request_bytes;
+    // Errors of the guard run outside of the locale negotiation
+    let locale = Locale::from_accept_language(
+      request
+        .headers()
+        .get(ACCEPT_LANGUAGE_METADATA_KEY)
+        .and_then(|v| v.to_str().ok()),
+    );
+    // Take the service that was polled ready
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    Box::pin(with_locale(locale, async move {
+      let (mut parts, body) = request.into_parts();
+      let too_large = || Status::from(too_large(max_bytes)).to_http();
+      if declared_too_large(&parts.headers, max_bytes) {
+        return Ok(too_large());
+      }
+      // Requests are unary, so they are read as a whole
+      let body = match buffer(&mut parts, body, max_bytes).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return Ok(too_large()),
+        Err(error) => return Ok(read_error(error)),
+      };
+      let request = http::Request::from_parts(parts, Body::from(body));
+      let response = inner.call(request).await?;
+      Ok(map_decode_error(response))
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_buffer() {
+    let (mut parts, body) = http::Request::new(Body::from("abcd")).into_parts();
+    assert_eq!(buffer(&mut parts, body, 3).await.unwrap(), None);
+    let (mut parts, body) = http::Request::new(Body::from("abcd")).into_parts();
+    assert_eq!(
+      buffer(&mut parts, body, 4).await.unwrap(),
+      Some(Bytes::from("abcd"))
+    );
+    // Inner layers take the body read by the outer one
+    assert_eq!(
+      buffered(&mut parts, Body::empty()).await.unwrap(),
+      Bytes::from("abcd")
+    );
+    assert_eq!(buffer(&mut parts, Body::empty(), 3).await.unwrap(), None);
+  }
+}
@@ -0,0 +1,251 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+// Customer fields that can be set as required
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+  Email,
+  Phone,
+  TaxNumber,
+  AddressZip,
+  AddressLocation,
+  AddressStreet,
+}
+
+impl Field {
+  // Proto field name
+  pub fn path(&self) -> &'static str {
+    match self {
+      Field::Email => "email",
+      Field::Phone => "phone",
+      Field::TaxNumber => "tax_number",
+      Field::AddressZip => "address_zip",
+      Field::AddressLocation => "address_location",
+      Field::AddressStreet => "address_street",
+    }
+  }
+  pub fn display_name(&self) -> &'static str {
+    match self {
+      Field::Email => "email cím",
+      Field::Phone => "telefonszám",
+      Field::TaxNumber => "adószám",
+      Field::AddressZip => "irányítószám",
+      Field::AddressLocation => "település",
+      Field::AddressStreet => "utca, házszám",
+    }
+  }
+}
+
+// Customer validation policy
+//
+// Loaded from a YAML file at startup, so deployments can
+// tune the rules without recompiling. Missing keys use
+// the default values. Lengths are counted in characters.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationPolicy {
+  pub name_min_len: usize,
+  pub name_max_len: usize,
+  pub email_max_len: usize,
+  pub phone_max_len: usize,
+  pub address_zip_max_len: usize,
+  pub address_location_max_len: usize,
+  pub address_street_max_len: usize,
+  pub required_fields: Vec<Field>,
+  pub company_tax_number_required: bool,
+  // Invoiceable companies and institutions must have a tax number
+  pub strict_invoicing: bool,
+  // Reject zip codes not matching the settlement,
+  // otherwise they are returned as warnings
+  pub strict_zip: bool,
+  // Reject customers with the email or tax number
+  // of an other customer
+  pub unique_email: bool,
+  pub unique_tax_number: bool,
+  // Updates are only checked against the rules of the fields
+  // they change, so legacy records failing newer rules can
+  // still be fixed one field at a time
+  pub lenient_updates: bool,
+}
+
+impl Default for ValidationPolicy {
+  fn default() -> Self {
+    Self {
+      name_min_len: 2,
+      name_max_len: 200,
+      email_max_len: 254,
+      phone_max_len: 50,
+      address_zip_max_len: 10,
+      address_location_max_len: 100,
+      address_street_max_len: 200,
+      required_fields: Vec::new(),
+      company_tax_number_required: true,
+      strict_invoicing: false,
+      strict_zip: true,
+      unique_email: false,
+      unique_tax_number: false,
+      lenient_updates: false,
+    }
+  }
+}
+
+impl ValidationPolicy {
+  // Load policy from YAML file
+  // If the file does not exist, returns the default policy
+  pub fn load_or_default(path: &Path) -> ServiceResult<Self> {
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+    let content = std::fs::read_to_string(path)?;
+    Self::from_yaml(&content)
+  }
+  pub fn from_yaml(content: &str) -> ServiceResult<Self> {
+    let policy: Self = serde_yaml::from_str(content).map_err(|e| {
+      ServiceError::internal_error(&format!("Error while parsing validation policy: {}", e))
+    })?;
+    if policy.name_min_len > policy.name_max_len {
+      return Err(ServiceError::internal_error(
+        "Validation policy error: name_min_len is greater than name_max_len",
+      ));
+    }
+    Ok(policy)
+  }
+}
+
+// Current validation policy of the service
+//
+// The policy file can be reloaded at runtime. Requests take the
+// current policy with get(), and keep using it until they finish,
+// even if a reload swaps it in the meantime.
+pub struct PolicyHolder {
+  path: PathBuf,
+  current: RwLock<Arc<ValidationPolicy>>,
+}
+
+impl PolicyHolder {
+  // Load policy from YAML file, and reload it from the same file later
+  pub fn load(path: &Path) -> ServiceResult<Self> {
+    Ok(Self {
+      path: path.to_path_buf(),
+      current: RwLock::new(Arc::new(ValidationPolicy::load_or_default(path)?)),
+    })
+  }
+  pub fn get(&self) -> Arc<ValidationPolicy> {
+    self
+      .current
+      .read()
+      .expect("Validation policy lock poisoned")
+      .clone()
+  }
+  // Reload policy from its file
+  // If the file cannot be loaded, the current policy is kept
+  pub fn reload(&self) -> ServiceResult<Arc<ValidationPolicy>> {
+    let policy = Arc::new(ValidationPolicy::load_or_default(&self.path)?);
+    *self
+      .current
+      .write()
+      .expect("Validation policy lock poisoned") = policy.clone();
+    Ok(policy)
+  }
+}
+
+// Reload validation policy on SIGHUP
+pub async fn reload_on_hangup(policy: Arc<PolicyHolder>) {
+  let mut hangup = signal(SignalKind::hangup()).expect("Error while setting SIGHUP handler");
+  while hangup.recv().await.is_some() {
+    match policy.reload() {
+      Ok(_) => println!("Validation policy reloaded"),
+      Err(error) => eprintln!("Error while reloading validation policy: {}", error),
+    }
+  }
+}
+
+// Check max field length
+pub fn check_max_len(value: &str, max_len: usize, field_name: &str) -> ServiceResult<()> {
+  if value.chars().count() > max_len {
+    return Err(ServiceError::bad_request(&format!(
+      "A(z) {} hosszúsága max {} karakter",
+      field_name, max_len
+    )));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_from_yaml() {
+    let policy = ValidationPolicy::from_yaml(
+      "name_max_len: 100\nrequired_fields: [email, address_zip]\ncompany_tax_number_required: false\nstrict_invoicing: true\nstrict_zip: false\nlenient_updates: true\n",
+    )
+    .unwrap();
+    assert_eq!(policy.name_min_len, 2);
+    assert_eq!(policy.name_max_len, 100);
+    assert_eq!(policy.required_fields, [Field::Email, Field::AddressZip]);
+    assert!(!policy.company_tax_number_required);
+    assert!(policy.strict_invoicing);
+    assert!(!policy.strict_zip);
+    assert!(ValidationPolicy::default().strict_zip);
+    assert!(policy.lenient_updates);
+    assert!(!ValidationPolicy::default().lenient_updates);
+  }
+
+  #[test]
+  fn test_from_yaml_errors() {
+    assert!(ValidationPolicy::from_yaml("name_min_len: 10\nname_max_len: 5\n").is_err());
+    assert!(ValidationPolicy::from_yaml("unknown_key: 1\n").is_err());
+    assert!(ValidationPolicy::from_yaml("required_fields: [nickname]\n").is_err());
+  }
+
+  #[test]
+  fn test_policy_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("validation_policy.yaml");
+    std::fs::write(&path, "unique_email: true\n").unwrap();
+    let holder = PolicyHolder::load(&path).unwrap();
+    let before = holder.get();
+    assert!(before.unique_email);
+    std::fs::write(&path, "unique_email: false\nrequired_fields: [phone]\n").unwrap();
+    holder.reload().unwrap();
+    assert!(!holder.get().unique_email);
+    assert_eq!(holder.get().required_fields, [Field::Phone]);
+    // Policies taken before the reload are not changed
+    assert!(before.unique_email);
+    // Invalid file keeps the current policy
+    std::fs::write(&path, "name_min_len: 10\nname_max_len: 5\n").unwrap();
+    assert!(holder.reload().is_err());
+    assert_eq!(holder.get().required_fields, [Field::Phone]);
+    // Removed file means the default policy
+    std::fs::remove_file(&path).unwrap();
+    assert!(holder.reload().unwrap().required_fields.is_empty());
+  }
+
+  #[test]
+  fn test_check_max_len() {
+    assert!(check_max_len("árvíztűrő", 9, "név").is_ok());
+    assert!(check_max_len("árvíztűrő", 8, "név").is_err());
+  }
+}
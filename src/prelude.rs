@@ -1,12 +1,106 @@
-use gzlib::proto::customer::CustomerObj;
+use crate::anomaly::{ChangeReason, SuspiciousChange};
+use crate::approval::PendingChange;
+use crate::audit_sink::MutationAction;
+use crate::auth::{shape, Role};
+use crate::communication::{Communication, Direction};
+use crate::custom_field::{CustomFieldDef, CustomFieldType};
+use crate::customer::{
+  ActivityKind, Attachment, ContactChannel, ContactKind, Customer, CustomerKind, DocumentKind,
+  InvoiceAddress, PrivacyFlags, ReasonCode, StatusChange,
+};
+use crate::db::{Blocked, SortBy};
+use crate::edit_lock::EditLock;
+use crate::erasure::{ErasureRequest, ErasureStatus};
+use crate::events::{CustomerEvent, CustomerEventKind};
+use crate::history::FieldChange;
+use crate::import::{ImportKey, ImportRow, RowAction, RowResult};
+use crate::integrity::CorruptRecord;
+use crate::merge::Merge;
+use crate::metrics::MethodStats;
+use crate::policy::{Field, ValidationPolicy};
+use crate::proto::customer::customer_event::EventKind;
+use crate::proto::customer::{
+  ActivityKind as ActivityKindObj, AttachmentObj, BlockedStatusObj, CommunicationChannel,
+  CommunicationDirection, CommunicationObj, ContactKind as ContactKindObj, CountByKey, CountByKind,
+  CustomFieldObj, CustomFieldType as CustomFieldTypeObj, CustomerEvent as CustomerEventObj,
+  CustomerKind as CustomerKindObj, CustomerObj, CustomerSummary, CustomerTaxDataIssues,
+  DocumentKind as DocumentKindObj, EditLockObj, ErasureRequestObj,
+  ErasureStatus as ErasureStatusObj, FieldChangeObj, IdReservationObj,
+  ImportAction as ImportActionObj, ImportKey as ImportKeyObj, ImportReport as ImportReportObj,
+  ImportRowObj, ImportRowResult, MergeConflict as MergeConflictObj, PendingChangeObj,
+  PreferredContact as PreferredContactObj, PreviewMergeResponse, PreviousContactObj,
+  PrivacyFlagsObj, ProfileObj, QuarantinedRecordObj, QuotaUsageObj, ReasonCode as ReasonCodeObj,
+  RestoreConflictObj, RestoreReport as RestoreReportObj, RestoreSkippedObj,
+  RetentionActionKind as RetentionActionKindObj, RetentionActionObj, RpcMetricsObj, SegmentObj,
+  SetInvoiceDetailsRequest, SortBy as SortByObj, StatsResponse, StatusChangeObj,
+  SuspiciousChangeObj, SuspiciousChangeReason, TaxDataIssueKind, TaxDataIssueObj,
+  ValidationPolicyObj, ValidationWarning, VatPeriodObj, VatTreatment as VatTreatmentObj,
+  WebhookObj,
+};
+use crate::quota::QuotaUsage;
+use crate::reservation::IdReservation;
+use crate::restore::RestoreReport;
+use crate::retention::{RetentionAction, RetentionActionKind};
+use crate::segment::{Membership, Segment};
+use crate::stats::Stats;
+use crate::tax_report::{Issue, IssueKind};
+use crate::taxnumber::TaxNumber;
+use crate::vat::{VatPeriod, VatTreatment};
+use crate::webhook::Webhook;
+use chrono::prelude::*;
 
-use crate::customer::Customer;
+// Response metadata key of the conflicting customer ID
+// in ALREADY_EXISTS errors
+pub const CONFLICT_METADATA_KEY: &str = "conflicting-customer-id";
 
 pub enum ServiceError {
   InternalError(String),
   NotFound(String),
   AlreadyExists(String),
   BadRequest(String),
+  PermissionDenied(String),
+  DeadlineExceeded(String),
+  FailedPrecondition(String),
+  ResourceExhausted(String),
+  InvalidFields(Vec<FieldViolation>),
+  // Already exists, with the conflicting customer ID
+  Conflict(String, u32),
+}
+
+// Invalid request field
+// field is the proto field name
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldViolation {
+  pub field: String,
+  pub description: String,
+}
+
+// Field violations collected during validation
+#[derive(Default)]
+pub struct Violations(Vec<FieldViolation>);
+
+impl Violations {
+  pub fn add(&mut self, field: &str, description: &str) {
+    self.0.push(FieldViolation {
+      field: field.to_string(),
+      description: description.to_string(),
+    });
+  }
+  // Add violation if the check failed
+  pub fn check(&mut self, field: &str, res: ServiceResult<()>) {
+    if let Err(error) = res {
+      self.add(field, &error.to_string());
+    }
+  }
+  pub fn into_vec(self) -> Vec<FieldViolation> {
+    self.0
+  }
+  pub fn into_result(self) -> ServiceResult<()> {
+    match self.0.is_empty() {
+      true => Ok(()),
+      false => Err(ServiceError::InvalidFields(self.0)),
+    }
+  }
 }
 
 impl ServiceError {
@@ -22,6 +116,60 @@ impl ServiceError {
   pub fn bad_request(msg: &str) -> Self {
     ServiceError::BadRequest(msg.to_string())
   }
+  pub fn permission_denied(msg: &str) -> Self {
+    ServiceError::PermissionDenied(msg.to_string())
+  }
+  pub fn deadline_exceeded(msg: &str) -> Self {
+    ServiceError::DeadlineExceeded(msg.to_string())
+  }
+  pub fn failed_precondition(msg: &str) -> Self {
+    ServiceError::FailedPrecondition(msg.to_string())
+  }
+  pub fn resource_exhausted(msg: &str) -> Self {
+    ServiceError::ResourceExhausted(msg.to_string())
+  }
+  pub fn conflict(msg: &str, customer_id: u32) -> Self {
+    ServiceError::Conflict(msg.to_string(), customer_id)
+  }
+  // Customer ID not in the storage
+  pub fn customer_not_found(customer_id: u32) -> Self {
+    Self::not_found(&format!(
+      "Nem található ügyfél ezzel az ID-val: {}",
+      customer_id
+    ))
+  }
+  // Customer ID already in the storage
+  pub fn customer_exists(customer_id: u32) -> Self {
+    Self::conflict("Már létezik ügyfél ezzel az ID-val", customer_id)
+  }
+  pub fn invalid_field(field: &str, msg: &str) -> Self {
+    ServiceError::InvalidFields(vec![FieldViolation {
+      field: field.to_string(),
+      description: msg.to_string(),
+    }])
+  }
+  // gRPC code of the error
+  pub fn code(&self) -> ::tonic::Code {
+    match self {
+      ServiceError::InternalError(_) => ::tonic::Code::Internal,
+      ServiceError::NotFound(_) => ::tonic::Code::NotFound,
+      ServiceError::AlreadyExists(_) | ServiceError::Conflict(_, _) => ::tonic::Code::AlreadyExists,
+      ServiceError::BadRequest(_) | ServiceError::InvalidFields(_) => {
+        ::tonic::Code::InvalidArgument
+      }
+      ServiceError::PermissionDenied(_) => ::tonic::Code::PermissionDenied,
+      ServiceError::DeadlineExceeded(_) => ::tonic::Code::DeadlineExceeded,
+      ServiceError::FailedPrecondition(_) => ::tonic::Code::FailedPrecondition,
+      ServiceError::ResourceExhausted(_) => ::tonic::Code::ResourceExhausted,
+    }
+  }
+  // Attach field to a bad request error
+  pub fn on_field(self, field: &str) -> Self {
+    match self {
+      ServiceError::BadRequest(msg) => Self::invalid_field(field, &msg),
+      error => error,
+    }
+  }
 }
 
 impl std::fmt::Display for ServiceError {
@@ -31,6 +179,22 @@ impl std::fmt::Display for ServiceError {
       ServiceError::NotFound(msg) => write!(f, "{}", msg),
       ServiceError::AlreadyExists(msg) => write!(f, "{}", msg),
       ServiceError::BadRequest(msg) => write!(f, "{}", msg),
+      ServiceError::PermissionDenied(msg) => write!(f, "{}", msg),
+      ServiceError::DeadlineExceeded(msg) => write!(f, "{}", msg),
+      ServiceError::FailedPrecondition(msg) => write!(f, "{}", msg),
+      ServiceError::ResourceExhausted(msg) => write!(f, "{}", msg),
+      ServiceError::Conflict(msg, customer_id) => {
+        write!(f, "{} (ügyfél ID: {})", msg, customer_id)
+      }
+      ServiceError::InvalidFields(violations) => write!(
+        f,
+        "{}",
+        violations
+          .iter()
+          .map(|v| v.description.as_str())
+          .collect::<Vec<&str>>()
+          .join("; ")
+      ),
     }
   }
 }
@@ -44,21 +208,692 @@ impl std::fmt::Debug for ServiceError {
   }
 }
 
+// Messages are translated to the locale of the request being handled
+// Internal errors are not translated
 impl From<ServiceError> for ::tonic::Status {
   fn from(error: ServiceError) -> Self {
+    let locale = current_locale();
+    let t = |msg: &str| translate(msg, locale);
+    let code = error.code();
     match error {
-      ServiceError::InternalError(msg) => ::tonic::Status::internal(msg),
-      ServiceError::NotFound(msg) => ::tonic::Status::not_found(msg),
-      ServiceError::AlreadyExists(msg) => ::tonic::Status::already_exists(msg),
-      ServiceError::BadRequest(msg) => ::tonic::Status::invalid_argument(msg),
+      ServiceError::InternalError(msg) => ::tonic::Status::new(code, msg),
+      ServiceError::Conflict(_, customer_id) => {
+        let mut metadata = ::tonic::metadata::MetadataMap::new();
+        metadata.insert(CONFLICT_METADATA_KEY, customer_id.into());
+        ::tonic::Status::with_metadata(code, t(&error.to_string()), metadata)
+      }
+      ServiceError::InvalidFields(violations) => {
+        let violations = violations
+          .into_iter()
+          .map(|v| FieldViolation {
+            description: t(&v.description),
+            ..v
+          })
+          .collect::<Vec<FieldViolation>>();
+        let msg = ServiceError::InvalidFields(violations.clone()).to_string();
+        crate::error_details::bad_request_status(&msg, &violations)
+      }
+      ServiceError::NotFound(msg)
+      | ServiceError::AlreadyExists(msg)
+      | ServiceError::BadRequest(msg)
+      | ServiceError::PermissionDenied(msg)
+      | ServiceError::DeadlineExceeded(msg)
+      | ServiceError::FailedPrecondition(msg)
+      | ServiceError::ResourceExhausted(msg) => ::tonic::Status::new(code, t(&msg)),
+    }
+  }
+}
+
+// Request metadata key of the client languages
+pub const ACCEPT_LANGUAGE_METADATA_KEY: &str = "accept-language";
+
+// Language of the error messages
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Locale {
+  #[default]
+  Hu,
+  En,
+}
+
+impl Locale {
+  // Pick locale from an Accept-Language value, e.g. en-US,en;q=0.9,hu;q=0.8
+  // The supported language with the highest q wins, Hungarian by default
+  pub fn from_accept_language(value: Option<&str>) -> Self {
+    let mut res = (Locale::Hu, 0.0);
+    for item in value.unwrap_or("").split(',') {
+      let mut parts = item.split(';');
+      let tag = parts.next().unwrap_or("").trim().to_lowercase();
+      let q = parts
+        .find_map(|p| p.trim().strip_prefix("q="))
+        .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+        .unwrap_or(0.0);
+      let locale = match tag.split('-').next().unwrap_or("") {
+        "hu" => Locale::Hu,
+        "en" => Locale::En,
+        _ => continue,
+      };
+      if q > res.1 {
+        res = (locale, q);
+      }
     }
+    res.0
+  }
+}
+
+::tokio::task_local! {
+  // Locale of the request being handled
+  static LOCALE: Locale;
+}
+
+// Run future with the locale of a request
+pub async fn with_locale<F: std::future::Future>(locale: Locale, f: F) -> F::Output {
+  LOCALE.scope(locale, f).await
+}
+
+// Locale of the request being handled, Hungarian outside of requests
+pub fn current_locale() -> Locale {
+  LOCALE.try_with(|locale| *locale).unwrap_or_default()
+}
+
+// Message catalog
+//
+// Hungarian messages with their English translations. {} matches
+// any text, and the matched parts are translated as well, so
+// field names and nested messages are translated too.
+// Messages not in the catalog are returned in Hungarian.
+const CATALOG: &[(&str, &str)] = &[
+  // Field names
+  ("adószám", "tax number"),
+  ("email cím", "email address"),
+  ("irányítószám", "zip code"),
+  ("név", "name"),
+  ("számlázási irányítószám", "invoice zip code"),
+  ("számlázási település", "invoice location"),
+  ("számlázási utca", "invoice street"),
+  ("telefonszám", "phone number"),
+  ("település", "location"),
+  ("utca, házszám", "street"),
+  // Validation
+  ("A(z) {} megadása kötelező", "The {} is required"),
+  (
+    "A(z) {} hosszúsága max {} karakter",
+    "The {} can be max {} characters long",
+  ),
+  (
+    "A név hosszúsága legalább {} max {} karakter",
+    "The name must be {} to {} characters long",
+  ),
+  (
+    "A számlázási név hosszúsága legalább {} max {} karakter",
+    "The invoice name must be {} to {} characters long",
+  ),
+  (
+    "A becenév hosszúsága legalább {} max {} karakter",
+    "The alias must be {} to {} characters long",
+  ),
+  (
+    "Legfeljebb {} becenév adható meg",
+    "Max {} aliases can be set",
+  ),
+  ("Legfeljebb {} címke adható meg", "Max {} tags can be set"),
+  (
+    "Legfeljebb {} áfa időszak tárolható",
+    "Max {} VAT periods can be stored",
+  ),
+  ("Ismeretlen áfa státusz", "Unknown VAT treatment"),
+  (
+    "Magánszemélyre nem állítható be különleges áfa státusz",
+    "Special VAT treatment cannot be set for private customers",
+  ),
+  (
+    "Ez az áfa státusz csak magyar ügyfélre állítható be",
+    "This VAT treatment can be set for Hungarian customers only",
+  ),
+  (
+    "Közösségi fordított adózás csak más EU tagállambeli ügyfélre állítható be",
+    "EU reverse charge can be set for customers of other EU member states only",
+  ),
+  (
+    "Az időszak kezdete kötelező",
+    "Start of the period is required",
+  ),
+  (
+    "Az időszak vége nem lehet korábbi a kezdeténél",
+    "End of the period cannot be before its start",
+  ),
+  (
+    "Hibás {}. Csak betűt, számot, szóközt, kötőjelet és aláhúzást tartalmazhat, max {} karakter",
+    "Invalid {}. It can contain letters, numbers, spaces, '-' and '_' only, max {} characters",
+  ),
+  ("címke", "tag"),
+  ("csoport", "group"),
+  (
+    "A névkezdet megadása kötelező",
+    "The name prefix is required",
+  ),
+  (
+    "Érvénytelen vagy lejárt profil token",
+    "Invalid or expired profile token",
+  ),
+  (
+    "A telefonszám külföldinek tűnik",
+    "The phone number looks foreign",
+  ),
+  (
+    "Az email cím domainje elírásnak tűnik: {}, helyesen: {}?",
+    "The email domain looks like a typo: {}, did you mean {}?",
+  ),
+  (
+    "A név csupa nagybetűvel van megadva",
+    "The name is written in all caps",
+  ),
+  (
+    "Egyszerre legfeljebb {} ügyfél módosítható",
+    "Max {} customers can be updated at once",
+  ),
+  ("Nem megfelelő email cím", "Invalid email address"),
+  (
+    "Nem megfelelő email cím. Legalább @ jelet és pontot kell tartalmaznia",
+    "Invalid email address. It must contain @ and a dot",
+  ),
+  (
+    "Cég esetén az adószám megadása kötelező",
+    "Tax number is required for companies",
+  ),
+  (
+    "Magánszemély esetén nem adható meg adószám",
+    "Private customers cannot have a tax number",
+  ),
+  (
+    "Számlázható ügyfél esetén az adószám megadása kötelező",
+    "Tax number is required for invoiceable customers",
+  ),
+  (
+    "E-mailes kapcsolattartáshoz az email cím megadása kötelező",
+    "Email address is required for email contact",
+  ),
+  (
+    "Telefonos kapcsolattartáshoz a telefonszám megadása kötelező",
+    "Phone number is required for phone and SMS contact",
+  ),
+  (
+    "Postai kapcsolattartáshoz a teljes cím megadása kötelező",
+    "Full address is required for postal contact",
+  ),
+  ("Ismeretlen kapcsolattartási mód", "Unknown contact channel"),
+  ("Hibás országkód: {}", "Invalid country code: {}"),
+  (
+    "Hibás országkód: {}. Kétbetűs ISO 3166 kód szükséges",
+    "Invalid country code: {}. Two letter ISO 3166 code is required",
+  ),
+  (
+    "Hibás irányítószám formátum ({}): {}",
+    "Invalid zip code format ({}): {}",
+  ),
+  ("Ismeretlen irányítószám: {}", "Unknown zip code: {}"),
+  (
+    "Az irányítószám ({}) nem egyezik a településsel. Lehetséges település(ek): {}",
+    "The zip code ({}) does not match the location. Possible location(s): {}",
+  ),
+  (
+    "Hibás hűségkártya azonosító. Csak betűt, számot és kötőjelet tartalmazhat, max 64 karakter",
+    "Invalid loyalty card ID. It can contain letters, digits and dashes only, max 64 characters",
+  ),
+  (
+    "Formai hiba! Az adószám 11 db számot kell, hogy tartalmazzon, xxxxxxxx-y-zz formában.",
+    "Format error! The tax number must contain 11 digits, in xxxxxxxx-y-zz format.",
+  ),
+  (
+    "A megadott adószám formailag megfelelő, de az első 8 számjegy (törzsszám) hibás.",
+    "The tax number format is valid, but its first 8 digits (base number) are wrong.",
+  ),
+  (
+    "A megadott adószám 9. karaktere nem megfelelő 1, 2, 3, 4, vagy 5 lehet.",
+    "The 9th character of the tax number is invalid, it can be 1, 2, 3, 4 or 5.",
+  ),
+  (
+    "A megadott adószám utolsó két számjegye (területi kód) nem megfelelő.",
+    "The last two digits (county code) of the tax number are invalid.",
+  ),
+  // Uniqueness
+  ("{} (ügyfél ID: {})", "{} (customer ID: {})"),
+  (
+    "Ez az email cím már egy másik ügyfélhez tartozik",
+    "This email address belongs to an other customer",
+  ),
+  (
+    "Ez az adószám már egy másik ügyfélhez tartozik",
+    "This tax number belongs to an other customer",
+  ),
+  (
+    "Ez a hűségkártya már egy másik ügyfélhez tartozik",
+    "This loyalty card belongs to an other customer",
+  ),
+  (
+    "Ez a becenév már szerepel az ügyfélnél",
+    "The customer already has this alias",
+  ),
+  (
+    "Ez a dokumentum már csatolva van az ügyfélhez",
+    "This document is already attached to the customer",
+  ),
+  (
+    "Ez a webhook URL már regisztrálva van",
+    "This webhook URL is already registered",
+  ),
+  ("Duplikált ügyfél ID", "Duplicated customer ID"),
+  // Request fields
+  ("A dátum megadása kötelező", "The date is required"),
+  (
+    "Az aktivitás időpontja nem lehet a jövőben",
+    "The activity date cannot be in the future",
+  ),
+  ("Ismeretlen aktivitás típus", "Unknown activity kind"),
+  ("Ismeretlen dokumentum típus", "Unknown document kind"),
+  ("Ismeretlen inaktiválási ok", "Unknown deactivation reason"),
+  ("Ismeretlen rendezési szempont", "Unknown sort order"),
+  (
+    "Az adatvédelmi beállítások megadása kötelező",
+    "Privacy flags are required",
+  ),
+  (
+    "Folyamatos keresés találatai nem rendezhetők",
+    "Streamed search results cannot be sorted",
+  ),
+  ("Ismeretlen ügyfél típus", "Unknown customer kind"),
+  ("Ismeretlen felhasználói szerepkör", "Unknown caller role"),
+  ("Ismeretlen hívó token", "Unknown caller token"),
+  (
+    "A szerepkör nem engedélyezett a hívó számára",
+    "The role is not allowed for the caller",
+  ),
+  ("Hibás esemény típus", "Invalid event kind"),
+  ("Hibás tenant azonosító", "Invalid tenant ID"),
+  ("Hibás tenant azonosító: {}", "Invalid tenant ID: {}"),
+  ("Hibás szerkesztő azonosító", "Invalid editor ID"),
+  (
+    "A szerkesztő azonosító megadása kötelező",
+    "The editor ID is required",
+  ),
+  ("Hibás idempotencia kulcs", "Invalid idempotency key"),
+  (
+    "Az idempotencia kulcs hossza 1 és {} karakter között lehet",
+    "The idempotency key must be 1 to {} characters long",
+  ),
+  (
+    "Egy kérésben max 1000 név adható meg",
+    "Max 1000 names can be resolved in a request",
+  ),
+  (
+    "Egyszerre 1 és {} közötti ID foglalható",
+    "1 to {} IDs can be reserved at once",
+  ),
+  (
+    "A dokumentum tárolási kulcsa nem lehet üres, és max 1024 karakter lehet",
+    "The document storage key cannot be empty, and can be max 1024 characters long",
+  ),
+  (
+    "Hibás webhook URL. Csak http:// vagy https:// URL adható meg",
+    "Invalid webhook URL. Only http:// or https:// URLs are allowed",
+  ),
+  (
+    "A webhook titkos kulcsa legalább {} karakter",
+    "The webhook secret must be at least {} characters long",
+  ),
+  (
+    "Ügyfél ID lista vagy szűrőfeltétel megadása kötelező, de csak az egyik",
+    "Either a customer ID list or a filter expression is required, but not both",
+  ),
+  ("Hibás mentés fájl: {}", "Invalid backup file: {}"),
+  ("Ismeretlen visszaállítási mód", "Unknown restore strategy"),
+  // Queries
+  ("Hibás lekérdezés: {}", "Invalid query: {}"),
+  (
+    "a lekérdezés max {} karakter lehet",
+    "the query can be max {} characters long",
+  ),
+  (
+    "A(z) {} mezőre nem lehet szűrni",
+    "Filtering on the {} field is not allowed",
+  ),
+  (
+    "a(z) {} mezőre nem használható a(z) {} operátor",
+    "the {} operator cannot be used on the {} field",
+  ),
+  (
+    "hiányzó operátor a(z) {} mező után",
+    "missing operator after the {} field",
+  ),
+  (
+    "hiányzó érték a(z) {} mezőhöz",
+    "missing value for the {} field",
+  ),
+  ("hiányzó )", "missing )"),
+  ("hiányzó feltétel", "missing condition"),
+  ("ismeretlen mező: {}", "unknown field: {}"),
+  ("ismeretlen egyedi mező: {}", "unknown custom field: {}"),
+  ("ismeretlen karakter: {}", "unknown character: {}"),
+  ("ismeretlen operátor: !", "unknown operator: !"),
+  ("ismeretlen ügyfél típus: {}", "unknown customer kind: {}"),
+  ("hibás dátum: {}", "invalid date: {}"),
+  ("hibás logikai érték: {}", "invalid boolean: {}"),
+  ("hibás szám: {}", "invalid number: {}"),
+  ("lezáratlan szöveg", "unterminated string"),
+  ("mezőnév helyett: {}", "instead of a field name: {}"),
+  ("túl mély zárójelezés", "too deeply nested parentheses"),
+  ("váratlan elem: {}", "unexpected token: {}"),
+  // Customer state
+  ("A kérés időkorlátja lejárt", "Request deadline exceeded"),
+  (
+    "Ez a művelet csak adminisztrátor számára engedélyezett",
+    "This operation is allowed for administrators only",
+  ),
+  (
+    "Ez a művelet csak hitelesített munkatárs számára engedélyezett",
+    "This operation is allowed for authenticated staff only",
+  ),
+  (
+    "Az ügyféladatok ezen a példányon csak olvashatók",
+    "Customers are read only on this instance",
+  ),
+  (
+    "A küszöbérték 1 és 100 között lehet",
+    "Threshold must be between 1 and 100",
+  ),
+  (
+    "Adminisztrációs művelet csak az admin porton érhető el",
+    "Admin operations are available on the admin port only",
+  ),
+  (
+    "Nem található ügyfél ezzel a hűségkártyával",
+    "No customer found with this loyalty card",
+  ),
+  ("A dokumentum nem található", "Document not found"),
+  (
+    "Az egyedi mező értéke max {} karakter lehet",
+    "The custom field value can be max {} characters long",
+  ),
+  (
+    "Hibás érték a(z) {} egyedi mezőhöz: {}",
+    "Invalid value for the {} custom field: {}",
+  ),
+  ("Ismeretlen egyedi mező: {}", "Unknown custom field: {}"),
+  (
+    "Az egyedi mező neve kisbetűvel kezdődik, csak kisbetűt, számot és _ jelet tartalmazhat, max {} karakter",
+    "The custom field name starts with a lowercase letter, and contains lowercase letters, digits and _ only, max {} characters",
+  ),
+  (
+    "Választó mezőhöz legalább egy választás megadása kötelező",
+    "A choice field needs at least one choice",
+  ),
+  (
+    "Választások csak választó mezőhöz adhatók meg",
+    "Choices can be given for choice fields only",
+  ),
+  (
+    "A(z) {} egyedi mező típusa nem módosítható",
+    "The type of the {} custom field cannot be changed",
+  ),
+  ("Max {} egyedi mező adható meg", "Max {} custom fields can be defined"),
+  ("Ismeretlen egyedi mező típus", "Unknown custom field type"),
+  (
+    "A szegmens neve kötelező, max {} karakter",
+    "The segment name is required, max {} characters",
+  ),
+  (
+    "Már létezik szegmens ezzel a névvel",
+    "A segment with this name already exists",
+  ),
+  ("Nem található ilyen szegmens", "Segment not found"),
+  (
+    "Az összefoglaló kötelező, max {} karakter",
+    "The summary is required, max {} characters",
+  ),
+  ("Ismeretlen kommunikációs csatorna", "Unknown communication channel"),
+  ("Ismeretlen kommunikációs irány", "Unknown communication direction"),
+  ("Az érték max {} bájt lehet", "The value can be max {} bytes long"),
+  (
+    "Láthatatlan vagy írásirány-váltó karakter nem adható meg",
+    "Invisible or text direction override characters are not allowed",
+  ),
+  ("A kérés max {} bájt lehet", "The request can be max {} bytes long"),
+  (
+    "Túl sok módosítás, próbálja újra {} másodperc múlva",
+    "Too many changes, try again in {} seconds",
+  ),
+  (
+    "Túl sok kézbesítésre váró esemény, próbálja újra később",
+    "Too many events waiting for delivery, try again later",
+  ),
+  (
+    "Számlázási adat csak adminisztrátori jóváhagyással módosítható, de nincs admin token beállítva",
+    "Billing data can only be changed with admin approval, but no admin token is set",
+  ),
+  (
+    "A kedvezményt csak a szerkesztő felhasználó nevében lehet beállítani",
+    "The discount can only be set in the name of the editor",
+  ),
+  ("Hibás ügyfél azonosító", "Invalid customer ID"),
+  ("Hibás szám paraméter", "Invalid number parameter"),
+  ("Ismeretlen paraméter: {}", "Unknown parameter: {}"),
+  ("Hibás JSON kérés: {}", "Invalid JSON request: {}"),
+  ("Nem támogatott HTTP metódus", "HTTP method not supported"),
+  ("Nem található ilyen végpont", "Endpoint not found"),
+  (
+    "A gRPC-web kérés nem dekódolható",
+    "The gRPC-web request cannot be decoded",
+  ),
+  (
+    "A kedvezmény 0 és {} százalék között lehet",
+    "The discount must be between 0 and {} percent",
+  ),
+  (
+    "A kedvezményt beállító felhasználó megadása kötelező",
+    "The user setting the discount is required",
+  ),
+  (
+    "A kérés érvénytelen UTF-8 szöveget tartalmaz",
+    "The request contains invalid UTF-8 text",
+  ),
+  ("A kérés nem dekódolható", "The request cannot be decoded"),
+  (
+    "A szegmens tagjai még nincsenek kiértékelve",
+    "The segment members are not evaluated yet",
+  ),
+  (
+    "Nem található ügyfél ezzel az ID-val: {}",
+    "No customer found with ID: {}",
+  ),
+  (
+    "Már létezik ügyfél ezzel az ID-val",
+    "A customer with this ID already exists",
+  ),
+  (
+    "A keresett elem nem található",
+    "The requested item was not found",
+  ),
+  ("Az azonosító már foglalt", "The ID is already taken"),
+  ("A webhook nem található", "Webhook not found"),
+  (
+    "Nem található ilyen gyanús módosítás",
+    "Suspicious change not found",
+  ),
+  (
+    "Nem található ilyen jóváhagyásra váró módosítás",
+    "Pending change not found",
+  ),
+  (
+    "A módosítás adminisztrátori jóváhagyásra vár",
+    "The change is waiting for admin approval",
+  ),
+  (
+    "A törlési kérelem indoka kötelező",
+    "The erasure request needs a reason",
+  ),
+  (
+    "Az ügyfélnek már van folyamatban lévő törlési kérelme",
+    "The customer already has an open erasure request",
+  ),
+  (
+    "Nem található ilyen törlési kérelem",
+    "Erasure request not found",
+  ),
+  ("Ismeretlen ügyfél mező: {}", "Unknown customer field: {}"),
+  (
+    "A törlési kérelemről már döntöttek",
+    "The erasure request is already decided",
+  ),
+  (
+    "Az ügyfél adatai már anonimizálva vannak",
+    "The customer is already anonymized",
+  ),
+  (
+    "Nincs ilyen becenév az ügyfélnél",
+    "The customer has no such alias",
+  ),
+  ("Az ügyfél már aktív", "The customer is already active"),
+  ("Az ügyfél már inaktív", "The customer is already inactive"),
+  (
+    "Az ügyfél nem vonható össze saját magával",
+    "A customer cannot be merged with itself",
+  ),
+  (
+    "Az ügyfelet jelenleg a(z) {} azonosítójú felhasználó szerkeszti",
+    "The customer is being edited by user {}",
+  ),
+  (
+    "A foglalt ügyfél ID már használatban van",
+    "The reserved customer ID is already in use",
+  ),
+  (
+    "A(z) {} ügyfél ID nincs lefoglalva, vagy a foglalás lejárt",
+    "Customer ID {} is not reserved, or its reservation expired",
+  ),
+  (
+    "Ez a külső azonosító már egy másik ügyfélhez tartozik",
+    "This external ID belongs to an other customer",
+  ),
+  ("Ismeretlen import kulcs", "Unknown import key"),
+  (
+    "A külső azonosító megadása kötelező",
+    "The external ID is required",
+  ),
+  (
+    "Az adószám több ügyfélhez is tartozik, a sor nem azonosítható",
+    "The tax number belongs to more than one customer, the row cannot be matched",
+  ),
+  (
+    "Ez az ügyfél már szerepel az import egy korábbi sorában",
+    "This customer is already in an earlier row of the import",
+  ),
+  (
+    "Egyszerre legfeljebb {} sor importálható",
+    "At most {} rows can be imported at once",
+  ),
+  (
+    "Legalább egy címrész megadása kötelező",
+    "At least one part of the address is required",
+  ),
+  (
+    "Az ügyfélkvóta {}%-a felhasználva ({} / {})",
+    "{}% of the customer quota is used ({} / {})",
+  ),
+  (
+    "Az ügyfélkvóta betelt, új ügyfél nem hozható létre",
+    "The customer quota is full, no new customers can be created",
+  ),
+  (
+    "Az ügyfélnek már van kiállított számlája, az adószáma csak kényszerítve módosítható",
+    "The customer already has issued invoices, its tax number can only be changed by force",
+  ),
+  (
+    "A számlázó szolgáltatás nem érhető el, az adószám csak kényszerítve módosítható",
+    "The invoice service is unavailable, the tax number can only be changed by force",
+  ),
+];
+
+// Translate message to the locale
+pub fn translate(msg: &str, locale: Locale) -> String {
+  match locale {
+    Locale::Hu => msg.to_string(),
+    Locale::En => translate_en(msg, 0),
+  }
+}
+
+fn translate_en(msg: &str, depth: usize) -> String {
+  if let Some((_, en)) = CATALOG.iter().find(|(hu, _)| *hu == msg) {
+    return en.to_string();
+  }
+  // Nested messages are translated up to a few levels
+  if depth > 2 {
+    return msg.to_string();
+  }
+  // The pattern with the longest fixed text wins
+  let found = CATALOG
+    .iter()
+    .filter(|(hu, _)| hu.contains("{}"))
+    .filter_map(|(hu, en)| match_pattern(hu, msg).map(|args| (hu, en, args)))
+    .max_by_key(|(hu, _, _)| hu.len());
+  match found {
+    Some((hu, en, args)) => {
+      let args = args
+        .iter()
+        .map(|a| translate_en(a, depth + 1))
+        .collect::<Vec<String>>();
+      // Arguments are in the same order in a few messages only,
+      // otherwise they are placed by their position in the pattern
+      fill_pattern(en, &reorder(hu, en, args))
+    }
+    None => msg.to_string(),
+  }
+}
+
+// Match message to a pattern, returns the texts matched by {}
+fn match_pattern<'a>(pattern: &str, msg: &'a str) -> Option<Vec<&'a str>> {
+  let parts = pattern.split("{}").collect::<Vec<&str>>();
+  let mut rest = msg.strip_prefix(parts[0])?;
+  let mut args = Vec::new();
+  for (i, part) in parts.iter().enumerate().skip(1) {
+    let end = match i == parts.len() - 1 {
+      // Last part must be the end of the message
+      true => rest.strip_suffix(part).map(|r| r.len())?,
+      false => rest.find(part).filter(|_| !part.is_empty())?,
+    };
+    args.push(&rest[..end]);
+    rest = &rest[end + part.len()..];
+  }
+  Some(args)
+}
+
+fn fill_pattern(pattern: &str, args: &[String]) -> String {
+  let mut res = String::new();
+  for (i, part) in pattern.split("{}").enumerate() {
+    if i > 0 {
+      res.push_str(args.get(i - 1).map_or("", |a| a.as_str()));
+    }
+    res.push_str(part);
+  }
+  res
+}
+
+// Argument order of translations that swap their arguments
+fn reorder(hu: &str, en: &str, args: Vec<String>) -> Vec<String> {
+  match (hu, en) {
+    (
+      "a(z) {} mezőre nem használható a(z) {} operátor",
+      "the {} operator cannot be used on the {} field",
+    ) if args.len() == 2 => vec![args[1].clone(), args[0].clone()],
+    _ => args,
   }
 }
 
 impl From<::packman::PackError> for ServiceError {
   fn from(error: ::packman::PackError) -> Self {
     match error {
-      ::packman::PackError::ObjectNotFound => ServiceError::not_found(&error.to_string()),
+      ::packman::PackError::ObjectNotFound => {
+        ServiceError::not_found("A keresett elem nem található")
+      }
+      ::packman::PackError::IDTaken => ServiceError::already_exist("Az azonosító már foglalt"),
       _ => ServiceError::internal_error(&error.to_string()),
     }
   }
@@ -72,22 +907,1082 @@ impl From<std::env::VarError> for ServiceError {
   }
 }
 
-impl From<Customer> for CustomerObj {
+impl From<::rusqlite::Error> for ServiceError {
+  fn from(error: ::rusqlite::Error) -> Self {
+    ServiceError::internal_error(&format!("SQLite error. {}", error))
+  }
+}
+
+impl From<std::io::Error> for ServiceError {
+  fn from(error: std::io::Error) -> Self {
+    ServiceError::internal_error(&format!("IO error. {}", error))
+  }
+}
+
+// Parse date from RFC3339 or YYYY-MM-DD format
+// Empty input is considered as None
+pub fn parse_date_opt(s: &str) -> ServiceResult<Option<DateTime<Utc>>> {
+  let s = s.trim();
+  if s.is_empty() {
+    return Ok(None);
+  }
+  if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+    return Ok(Some(date.with_timezone(&Utc)));
+  }
+  match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+    Ok(date) => Ok(Some(
+      Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()),
+    )),
+    Err(_) => Err(ServiceError::bad_request(&format!(
+      "Hibás dátum formátum: {}. RFC3339 vagy YYYY-MM-DD formátum szükséges",
+      s
+    ))),
+  }
+}
+
+// Convert customer to proto object
+// shaped by the field policy of the caller role
+pub fn customer_to_summary(u: &Customer, role: Role) -> CustomerSummary {
+  CustomerSummary {
+    id: u.id,
+    name: u.name.clone(),
+    city: shape(role, Field::AddressLocation, u.address_location.clone()),
+    phone: shape(role, Field::Phone, u.phone.clone()),
+  }
+}
+
+// Customer response with the name of its creator, if it is known
+pub fn with_created_by_name(
+  customer: CustomerObj,
+  names: &std::collections::HashMap<u32, String>,
+) -> CustomerObj {
+  CustomerObj {
+    created_by_name: names.get(&customer.created_by).cloned().unwrap_or_default(),
+    ..customer
+  }
+}
+
+pub fn customer_to_obj(u: Customer, role: Role) -> CustomerObj {
+  let completeness_score = u.completeness_score();
+  let invoice_name = u.invoice_name().to_string();
+  let invoice_address = u.invoice_address();
+  let tax_number = match u.tax_number {
+    Some(tax_number) => tax_number.to_string(),
+    None => "".to_string(),
+  };
+  let shape = |field: Field, value: String| shape(role, field, value);
+  CustomerObj {
+    id: u.id,
+    date_created: u.date_created.to_rfc3339(),
+    created_by: u.created_by,
+    name: u.name,
+    display_name: u.display_name,
+    privacy_flags: Some(u.privacy.into()),
+    vat_periods: u.vat_periods.into_iter().map(VatPeriodObj::from).collect(),
+    address_zip: shape(Field::AddressZip, u.address_zip),
+    address_location: shape(Field::AddressLocation, u.address_location),
+    address_street: shape(Field::AddressStreet, u.address_street),
+    country: u.country,
+    email: shape(Field::Email, u.email),
+    phone: shape(Field::Phone, u.phone),
+    tax_number: shape(Field::TaxNumber, tax_number),
+    kind: CustomerKindObj::from(u.kind) as i32,
+    loyalty_card_id: u.loyalty_card_id.unwrap_or_default(),
+    external_id: u.external_id.unwrap_or_default(),
+    force_tax_number_change: false,
+    custom_fields: u.custom_fields.into_iter().collect(),
+    default_discount_percent: u.default_discount_percent,
+    discount_set_by: u.discount_set_by,
+    // Set by the expanded view
+    created_by_name: String::new(),
+    discount_set_at: u
+      .discount_set_at
+      .map(|d| d.to_rfc3339())
+      .unwrap_or_default(),
+    active: u.active,
+    status_history: u.status_history.into_iter().map(|c| c.into()).collect(),
+    last_activity: u.last_activity.map(|d| d.to_rfc3339()).unwrap_or_default(),
+    last_purchase_at: u
+      .last_purchase_at
+      .map(|d| d.to_rfc3339())
+      .unwrap_or_default(),
+    invoice_name,
+    invoice_address_zip: shape(Field::AddressZip, invoice_address.zip),
+    invoice_address_location: shape(Field::AddressLocation, invoice_address.location),
+    invoice_address_street: shape(Field::AddressStreet, invoice_address.street),
+    last_modified: u.last_modified.to_rfc3339(),
+    last_modified_by: u.last_modified_by,
+    invoice_name_override: u.invoice_name.is_some(),
+    invoice_address_override: u.invoice_address.is_some(),
+    previous_contacts: u
+      .previous_contacts
+      .into_iter()
+      .map(|c| {
+        let field = match c.kind {
+          ContactKind::Email => Field::Email,
+          ContactKind::Phone => Field::Phone,
+        };
+        PreviousContactObj {
+          kind: ContactKindObj::from(c.kind) as i32,
+          value: shape(field, c.value),
+          date_replaced: c.date_replaced.to_rfc3339(),
+        }
+      })
+      .collect(),
+    incomplete_profile: u.incomplete_profile,
+    last_invoice_at: u
+      .last_invoice_at
+      .map(|d| d.to_rfc3339())
+      .unwrap_or_default(),
+    anonymized_at: u.anonymized_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+    aliases: u.aliases,
+    invoiceable: u.invoiceable,
+    preferred_contact: u
+      .preferred_contact
+      .map(|c| PreferredContactObj::from(c) as i32)
+      .unwrap_or_default(),
+    tags: u.tags,
+    group: u.group.unwrap_or_default(),
+    warnings: Vec::new(),
+    completeness_score,
+  }
+}
+
+impl From<QuotaUsage> for QuotaUsageObj {
+  fn from(u: QuotaUsage) -> Self {
+    Self {
+      customer_count: u.customer_count as u32,
+      limit: u.limit.unwrap_or_default() as u32,
+      warn_at: u.warn_at().unwrap_or_default() as u32,
+      used_percent: u.used_percent(),
+      exhausted: u.is_exhausted(),
+    }
+  }
+}
+
+impl From<FieldViolation> for ValidationWarning {
+  fn from(v: FieldViolation) -> Self {
+    Self {
+      field: v.field,
+      description: translate(&v.description, current_locale()),
+    }
+  }
+}
+
+impl From<Customer> for ProfileObj {
   fn from(u: Customer) -> Self {
     Self {
-      id: u.id,
-      date_created: u.date_created.to_rfc3339(),
-      created_by: u.created_by,
       name: u.name,
+      email: u.email,
+      phone: u.phone,
+      tax_number: u.tax_number.map(|t| t.to_string()).unwrap_or_default(),
       address_zip: u.address_zip,
       address_location: u.address_location,
       address_street: u.address_street,
-      email: u.email,
-      phone: u.phone,
-      tax_number: match u.tax_number {
-        Some(tax_number) => tax_number.to_string(),
-        None => "".to_string(),
+      country: u.country,
+      preferred_contact: u
+        .preferred_contact
+        .map(|c| PreferredContactObj::from(c) as i32)
+        .unwrap_or_default(),
+      warnings: Vec::new(),
+    }
+  }
+}
+
+// Shaped for the most restrictive role
+impl From<Customer> for CustomerObj {
+  fn from(u: Customer) -> Self {
+    customer_to_obj(u, Role::default())
+  }
+}
+
+impl From<ContactKind> for ContactKindObj {
+  fn from(kind: ContactKind) -> Self {
+    match kind {
+      ContactKind::Email => ContactKindObj::ContactEmail,
+      ContactKind::Phone => ContactKindObj::ContactPhone,
+    }
+  }
+}
+
+impl From<CustomerKind> for CustomerKindObj {
+  fn from(kind: CustomerKind) -> Self {
+    match kind {
+      CustomerKind::Private => CustomerKindObj::KindPrivate,
+      CustomerKind::Company => CustomerKindObj::KindCompany,
+      CustomerKind::Institution => CustomerKindObj::KindInstitution,
+    }
+  }
+}
+
+impl From<ContactChannel> for PreferredContactObj {
+  fn from(channel: ContactChannel) -> Self {
+    match channel {
+      ContactChannel::Email => PreferredContactObj::PreferredEmail,
+      ContactChannel::Phone => PreferredContactObj::PreferredPhone,
+      ContactChannel::Sms => PreferredContactObj::PreferredSms,
+      ContactChannel::Post => PreferredContactObj::PreferredPost,
+    }
+  }
+}
+
+impl From<PrivacyFlags> for PrivacyFlagsObj {
+  fn from(flags: PrivacyFlags) -> Self {
+    Self {
+      do_not_photograph: flags.do_not_photograph,
+      do_not_profile: flags.do_not_profile,
+      processing_restricted: flags.processing_restricted,
+    }
+  }
+}
+
+impl From<PrivacyFlagsObj> for PrivacyFlags {
+  fn from(flags: PrivacyFlagsObj) -> Self {
+    Self {
+      do_not_photograph: flags.do_not_photograph,
+      do_not_profile: flags.do_not_profile,
+      processing_restricted: flags.processing_restricted,
+    }
+  }
+}
+
+impl From<VatTreatment> for VatTreatmentObj {
+  fn from(treatment: VatTreatment) -> Self {
+    match treatment {
+      VatTreatment::SubjectExempt => VatTreatmentObj::VatSubjectExempt,
+      VatTreatment::DomesticReverseCharge => VatTreatmentObj::VatDomesticReverseCharge,
+      VatTreatment::EuReverseCharge => VatTreatmentObj::VatEuReverseCharge,
+    }
+  }
+}
+
+// Try to convert proto VAT treatment
+// VatNormal is mapped to None
+pub fn vat_treatment_from_proto(treatment: i32) -> ServiceResult<Option<VatTreatment>> {
+  match VatTreatmentObj::from_i32(treatment) {
+    Some(VatTreatmentObj::VatNormal) => Ok(None),
+    Some(VatTreatmentObj::VatSubjectExempt) => Ok(Some(VatTreatment::SubjectExempt)),
+    Some(VatTreatmentObj::VatDomesticReverseCharge) => {
+      Ok(Some(VatTreatment::DomesticReverseCharge))
+    }
+    Some(VatTreatmentObj::VatEuReverseCharge) => Ok(Some(VatTreatment::EuReverseCharge)),
+    None => Err(ServiceError::invalid_field(
+      "treatment",
+      "Ismeretlen áfa státusz",
+    )),
+  }
+}
+
+// Dates of VAT periods in YYYY-MM-DD format, empty if unbounded
+pub fn vat_date_to_proto(date: Option<NaiveDate>) -> String {
+  date
+    .map(|d| d.format("%Y-%m-%d").to_string())
+    .unwrap_or_default()
+}
+
+impl From<VatPeriod> for VatPeriodObj {
+  fn from(p: VatPeriod) -> Self {
+    Self {
+      treatment: VatTreatmentObj::from(p.treatment) as i32,
+      valid_from: vat_date_to_proto(Some(p.valid_from)),
+      valid_until: vat_date_to_proto(p.valid_until),
+    }
+  }
+}
+
+// Try to convert proto preferred contact
+// PreferredUnspecified is mapped to None
+pub fn preferred_contact_from_proto(channel: i32) -> ServiceResult<Option<ContactChannel>> {
+  match PreferredContactObj::from_i32(channel) {
+    Some(PreferredContactObj::PreferredUnspecified) => Ok(None),
+    Some(PreferredContactObj::PreferredEmail) => Ok(Some(ContactChannel::Email)),
+    Some(PreferredContactObj::PreferredPhone) => Ok(Some(ContactChannel::Phone)),
+    Some(PreferredContactObj::PreferredSms) => Ok(Some(ContactChannel::Sms)),
+    Some(PreferredContactObj::PreferredPost) => Ok(Some(ContactChannel::Post)),
+    None => Err(ServiceError::bad_request("Ismeretlen kapcsolattartási mód")),
+  }
+}
+
+// Try to convert proto customer kind
+// KindUnspecified is mapped to None
+pub fn customer_kind_from_proto(kind: i32) -> ServiceResult<Option<CustomerKind>> {
+  match CustomerKindObj::from_i32(kind) {
+    Some(CustomerKindObj::KindUnspecified) => Ok(None),
+    Some(CustomerKindObj::KindPrivate) => Ok(Some(CustomerKind::Private)),
+    Some(CustomerKindObj::KindCompany) => Ok(Some(CustomerKind::Company)),
+    Some(CustomerKindObj::KindInstitution) => Ok(Some(CustomerKind::Institution)),
+    None => Err(ServiceError::bad_request("Ismeretlen ügyfél típus")),
+  }
+}
+
+// Try to convert proto sort order
+// SortUnspecified is mapped to None
+pub fn sort_by_from_proto(sort_by: i32) -> ServiceResult<Option<SortBy>> {
+  match SortByObj::from_i32(sort_by) {
+    Some(SortByObj::SortUnspecified) => Ok(None),
+    Some(SortByObj::SortName) => Ok(Some(SortBy::Name)),
+    Some(SortByObj::SortCreated) => Ok(Some(SortBy::Created)),
+    Some(SortByObj::SortZip) => Ok(Some(SortBy::Zip)),
+    Some(SortByObj::SortModified) => Ok(Some(SortBy::Modified)),
+    None => Err(ServiceError::bad_request("Ismeretlen rendezési szempont")),
+  }
+}
+
+// Try to convert proto import key
+pub fn import_key_from_proto(key: i32) -> ServiceResult<ImportKey> {
+  match ImportKeyObj::from_i32(key) {
+    Some(ImportKeyObj::ImportByExternalId) => Ok(ImportKey::ExternalId),
+    Some(ImportKeyObj::ImportByTaxNumber) => Ok(ImportKey::TaxNumber),
+    None => Err(ServiceError::invalid_field(
+      "key",
+      "Ismeretlen import kulcs",
+    )),
+  }
+}
+
+// Convert proto import row, checking its fields
+pub fn import_row_from_proto(r: ImportRowObj) -> ServiceResult<ImportRow> {
+  let u = r.customer.unwrap_or_default();
+  let tax_number = match u.tax_number.trim() {
+    "" => None,
+    x => Some(TaxNumber::new(x).map_err(|e| ServiceError::from(e).on_field("tax_number"))?),
+  };
+  let country = match u.country.trim() {
+    "" => None,
+    x => Some(crate::country::normalize_country(x).map_err(|e| e.on_field("country"))?),
+  };
+  Ok(ImportRow {
+    external_id: r.external_id,
+    kind: customer_kind_from_proto(u.kind).map_err(|e| e.on_field("kind"))?,
+    preferred_contact: preferred_contact_from_proto(u.preferred_contact)
+      .map_err(|e| e.on_field("preferred_contact"))?,
+    loyalty_card_id: loyalty_card_id_from_proto(&u.loyalty_card_id),
+    name: u.name,
+    email: u.email,
+    phone: u.phone,
+    tax_number,
+    address_zip: u.address_zip,
+    address_location: u.address_location,
+    address_street: u.address_street,
+    country,
+    invoiceable: u.invoiceable,
+    created_by: u.created_by,
+  })
+}
+
+impl From<RowResult> for ImportRowResult {
+  fn from(r: RowResult) -> Self {
+    let action = match r.action {
+      RowAction::Created => ImportActionObj::ImportCreated,
+      RowAction::Updated => ImportActionObj::ImportUpdated,
+      RowAction::Skipped => ImportActionObj::ImportSkipped,
+      RowAction::Error => ImportActionObj::ImportError,
+    };
+    let (error, fields) = match r.error {
+      Some(ServiceError::InvalidFields(violations)) => (
+        violations
+          .iter()
+          .map(|v| translate(&v.description, current_locale()))
+          .collect::<Vec<String>>()
+          .join("; "),
+        violations.into_iter().map(|v| v.field).collect(),
+      ),
+      Some(error) => (translate(&error.to_string(), current_locale()), Vec::new()),
+      None => (String::new(), Vec::new()),
+    };
+    Self {
+      row: r.row as u32,
+      action: action as i32,
+      customer_id: r.customer_id,
+      error,
+      fields,
+    }
+  }
+}
+
+impl From<Vec<RowResult>> for ImportReportObj {
+  fn from(results: Vec<RowResult>) -> Self {
+    let count = |action| results.iter().filter(|r| r.action == action).count() as u32;
+    Self {
+      created_count: count(RowAction::Created),
+      updated_count: count(RowAction::Updated),
+      skipped_count: count(RowAction::Skipped),
+      error_count: count(RowAction::Error),
+      rows: results.into_iter().map(ImportRowResult::from).collect(),
+    }
+  }
+}
+
+impl From<&ValidationPolicy> for ValidationPolicyObj {
+  fn from(p: &ValidationPolicy) -> Self {
+    Self {
+      name_min_len: p.name_min_len as u32,
+      name_max_len: p.name_max_len as u32,
+      email_max_len: p.email_max_len as u32,
+      phone_max_len: p.phone_max_len as u32,
+      address_zip_max_len: p.address_zip_max_len as u32,
+      address_location_max_len: p.address_location_max_len as u32,
+      address_street_max_len: p.address_street_max_len as u32,
+      required_fields: p
+        .required_fields
+        .iter()
+        .map(|f| f.path().to_string())
+        .collect(),
+      company_tax_number_required: p.company_tax_number_required,
+      strict_invoicing: p.strict_invoicing,
+      strict_zip: p.strict_zip,
+      unique_email: p.unique_email,
+      unique_tax_number: p.unique_tax_number,
+      lenient_updates: p.lenient_updates,
+    }
+  }
+}
+
+impl From<EditLock> for EditLockObj {
+  fn from(l: EditLock) -> Self {
+    Self {
+      customer_id: l.customer_id,
+      editor_uid: l.editor_uid,
+      expires_at: l.expires_at.to_rfc3339(),
+    }
+  }
+}
+
+// Convert merge preview, shaping values for the caller role
+pub fn merge_to_obj(m: Merge, role: Role) -> PreviewMergeResponse {
+  let shape = |field: Option<Field>, value: String| match field {
+    Some(field) => shape(role, field, value),
+    None => value,
+  };
+  PreviewMergeResponse {
+    merged: Some(customer_to_obj(m.merged, role)),
+    conflicts: m
+      .conflicts
+      .into_iter()
+      .map(|c| MergeConflictObj {
+        field: c.field.to_string(),
+        kept_value: shape(c.policy_field, c.kept_value),
+        duplicate_value: shape(c.policy_field, c.duplicate_value),
+      })
+      .collect(),
+  }
+}
+
+impl From<Attachment> for AttachmentObj {
+  fn from(a: Attachment) -> Self {
+    Self {
+      id: a.id,
+      document_kind: DocumentKindObj::from(a.document_kind) as i32,
+      storage_key: a.storage_key,
+      uploaded_by: a.uploaded_by,
+      date_uploaded: a.date_uploaded.to_rfc3339(),
+    }
+  }
+}
+
+impl From<DocumentKind> for DocumentKindObj {
+  fn from(kind: DocumentKind) -> Self {
+    match kind {
+      DocumentKind::Contract => DocumentKindObj::DocumentContract,
+      DocumentKind::IdScan => DocumentKindObj::DocumentIdScan,
+      DocumentKind::Avatar => DocumentKindObj::DocumentAvatar,
+      DocumentKind::Other => DocumentKindObj::DocumentOther,
+    }
+  }
+}
+
+// Try to convert proto document kind
+// DocumentUnspecified is invalid
+pub fn document_kind_from_proto(kind: i32) -> ServiceResult<DocumentKind> {
+  match DocumentKindObj::from_i32(kind) {
+    Some(DocumentKindObj::DocumentContract) => Ok(DocumentKind::Contract),
+    Some(DocumentKindObj::DocumentIdScan) => Ok(DocumentKind::IdScan),
+    Some(DocumentKindObj::DocumentAvatar) => Ok(DocumentKind::Avatar),
+    Some(DocumentKindObj::DocumentOther) => Ok(DocumentKind::Other),
+    _ => Err(ServiceError::bad_request("Ismeretlen dokumentum típus")),
+  }
+}
+
+impl From<StatusChange> for StatusChangeObj {
+  fn from(c: StatusChange) -> Self {
+    Self {
+      active: c.active,
+      reason_code: match c.reason {
+        Some(reason) => ReasonCodeObj::from(reason) as i32,
+        None => ReasonCodeObj::ReasonUnspecified as i32,
+      },
+      comment: c.comment,
+      changed_by: c.changed_by,
+      date_changed: c.date_changed.to_rfc3339(),
+    }
+  }
+}
+
+impl From<ReasonCode> for ReasonCodeObj {
+  fn from(reason: ReasonCode) -> Self {
+    match reason {
+      ReasonCode::Debt => ReasonCodeObj::ReasonDebt,
+      ReasonCode::Fraud => ReasonCodeObj::ReasonFraud,
+      ReasonCode::Duplicate => ReasonCodeObj::ReasonDuplicate,
+      ReasonCode::CustomerRequest => ReasonCodeObj::ReasonCustomerRequest,
+      ReasonCode::Other => ReasonCodeObj::ReasonOther,
+    }
+  }
+}
+
+// Blocked status of a customer, blocked is None if it is active
+pub fn blocked_to_obj(customer_id: u32, blocked: Option<Blocked>) -> BlockedStatusObj {
+  let blocked = match blocked {
+    Some(blocked) => blocked,
+    None => {
+      return BlockedStatusObj {
+        customer_id,
+        ..BlockedStatusObj::default()
+      }
+    }
+  };
+  BlockedStatusObj {
+    customer_id,
+    blocked: true,
+    reason_code: match blocked.reason {
+      Some(reason) => ReasonCodeObj::from(reason) as i32,
+      None => ReasonCodeObj::ReasonUnspecified as i32,
+    },
+    blocked_since: blocked
+      .since
+      .map(|since| since.to_rfc3339())
+      .unwrap_or_default(),
+  }
+}
+
+// Try to convert proto reason code
+// ReasonUnspecified is invalid
+pub fn reason_code_from_proto(reason: i32) -> ServiceResult<ReasonCode> {
+  match ReasonCodeObj::from_i32(reason) {
+    Some(ReasonCodeObj::ReasonDebt) => Ok(ReasonCode::Debt),
+    Some(ReasonCodeObj::ReasonFraud) => Ok(ReasonCode::Fraud),
+    Some(ReasonCodeObj::ReasonDuplicate) => Ok(ReasonCode::Duplicate),
+    Some(ReasonCodeObj::ReasonCustomerRequest) => Ok(ReasonCode::CustomerRequest),
+    Some(ReasonCodeObj::ReasonOther) => Ok(ReasonCode::Other),
+    _ => Err(ServiceError::bad_request("Ismeretlen inaktiválási ok")),
+  }
+}
+
+// Try to convert proto activity kind
+// ActivityUnspecified is invalid
+pub fn activity_kind_from_proto(kind: i32) -> ServiceResult<ActivityKind> {
+  match ActivityKindObj::from_i32(kind) {
+    Some(ActivityKindObj::ActivityPurchase) => Ok(ActivityKind::Purchase),
+    Some(ActivityKindObj::ActivityInvoice) => Ok(ActivityKind::Invoice),
+    Some(ActivityKindObj::ActivityOther) => Ok(ActivityKind::Other),
+    _ => Err(ServiceError::bad_request("Ismeretlen aktivitás típus")),
+  }
+}
+
+// Loyalty card ID from proto
+// Empty means no loyalty card ID
+pub fn loyalty_card_id_from_proto(loyalty_card_id: &str) -> Option<String> {
+  match loyalty_card_id.trim() {
+    "" => None,
+    x => Some(x.to_string()),
+  }
+}
+
+// Invoice overrides from proto
+// Empty name or address means no override
+pub fn invoice_details_from_proto(
+  r: &SetInvoiceDetailsRequest,
+) -> (Option<String>, Option<InvoiceAddress>) {
+  let invoice_name = match r.invoice_name.trim() {
+    "" => None,
+    x => Some(x.to_string()),
+  };
+  let address = InvoiceAddress {
+    zip: r.invoice_address_zip.trim().to_string(),
+    location: r.invoice_address_location.trim().to_string(),
+    street: r.invoice_address_street.trim().to_string(),
+  };
+  let invoice_address =
+    match address.zip.is_empty() && address.location.is_empty() && address.street.is_empty() {
+      true => None,
+      false => Some(address),
+    };
+  (invoice_name, invoice_address)
+}
+
+impl From<Stats> for StatsResponse {
+  fn from(s: Stats) -> Self {
+    let by_key = |map: std::collections::BTreeMap<String, u32>| {
+      map
+        .into_iter()
+        .map(|(key, count)| CountByKey { key, count })
+        .collect::<Vec<CountByKey>>()
+    };
+    Self {
+      customer_count: s.customer_count,
+      created_per_month: by_key(s.created_per_month),
+      by_kind: s
+        .by_kind
+        .into_iter()
+        .map(|(kind, count)| CountByKind {
+          kind: CustomerKindObj::from(kind) as i32,
+          count,
+        })
+        .collect(),
+      by_zip_prefix: by_key(s.by_zip_prefix),
+    }
+  }
+}
+
+// Convert customer event to proto object
+// shaped by the field policy of the caller role
+pub fn customer_event_to_obj(e: CustomerEvent, role: Role) -> CustomerEventObj {
+  CustomerEventObj {
+    kind: EventKind::from(e.kind) as i32,
+    customer: Some(customer_to_obj(e.customer, role)),
+    segment_id: match e.kind {
+      CustomerEventKind::SegmentEntered(id) | CustomerEventKind::SegmentLeft(id) => id,
+      _ => 0,
+    },
+  }
+}
+
+impl From<CustomerEventKind> for EventKind {
+  fn from(kind: CustomerEventKind) -> Self {
+    match kind {
+      CustomerEventKind::Created => EventKind::Created,
+      CustomerEventKind::Updated => EventKind::Updated,
+      CustomerEventKind::SegmentEntered(_) => EventKind::SegmentEntered,
+      CustomerEventKind::SegmentLeft(_) => EventKind::SegmentLeft,
+    }
+  }
+}
+
+// Get event kind from proto enum value
+// Segment kinds are for every segment
+pub fn event_kind_from_proto(kind: i32) -> ServiceResult<CustomerEventKind> {
+  match EventKind::from_i32(kind) {
+    Some(EventKind::Created) => Ok(CustomerEventKind::Created),
+    Some(EventKind::Updated) => Ok(CustomerEventKind::Updated),
+    Some(EventKind::SegmentEntered) => Ok(CustomerEventKind::SegmentEntered(0)),
+    Some(EventKind::SegmentLeft) => Ok(CustomerEventKind::SegmentLeft(0)),
+    None => Err(ServiceError::bad_request("Hibás esemény típus")),
+  }
+}
+
+impl From<IdReservation> for IdReservationObj {
+  fn from(r: IdReservation) -> Self {
+    Self {
+      first_id: r.first_id,
+      count: r.count,
+      expires_at: r.expires_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<RetentionAction> for RetentionActionObj {
+  fn from(a: RetentionAction) -> Self {
+    let action = match a.action {
+      RetentionActionKind::Anonymize => RetentionActionKindObj::RetentionAnonymize,
+    };
+    Self {
+      customer_id: a.customer_id,
+      rule: a.rule,
+      action: action as i32,
+      last_seen: a.last_seen.to_rfc3339(),
+    }
+  }
+}
+
+impl From<RestoreReport> for RestoreReportObj {
+  fn from(r: RestoreReport) -> Self {
+    Self {
+      restored_ids: r.restored_ids,
+      replaced_ids: r.replaced_ids,
+      conflicts: r
+        .conflicts
+        .into_iter()
+        .map(|c| RestoreConflictObj {
+          customer_id: c.customer_id,
+          backup_modified: c.backup_modified.to_rfc3339(),
+          current_modified: c.current_modified.to_rfc3339(),
+          fields: c.fields,
+          took_backup: c.took_backup,
+        })
+        .collect(),
+      skipped: r
+        .skipped
+        .into_iter()
+        .map(|(customer_id, reason)| RestoreSkippedObj {
+          customer_id,
+          reason: translate(&reason, current_locale()),
+        })
+        .collect(),
+    }
+  }
+}
+
+impl From<(String, MethodStats)> for RpcMetricsObj {
+  fn from((method, s): (String, MethodStats)) -> Self {
+    let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+    Self {
+      method,
+      calls: s.calls,
+      errors: s.errors,
+      avg_duration_ms: match s.calls {
+        0 => 0.0,
+        calls => ms(s.total_duration) / calls as f64,
       },
+      max_duration_ms: ms(s.max_duration),
+      request_bytes: s.request_bytes,
+      max_request_bytes: s.max_request_bytes,
+      response_bytes: s.response_bytes,
+      max_response_bytes: s.max_response_bytes,
+      throttled: s.throttled,
+    }
+  }
+}
+
+impl From<Webhook> for WebhookObj {
+  fn from(w: Webhook) -> Self {
+    Self {
+      id: w.id,
+      url: w.url,
+      event_kinds: w
+        .event_kinds
+        .into_iter()
+        .map(|k| EventKind::from(k) as i32)
+        .collect(),
+      created_by: w.created_by,
+      date_created: w.date_created.to_rfc3339(),
+    }
+  }
+}
+
+impl From<CorruptRecord> for QuarantinedRecordObj {
+  fn from(r: CorruptRecord) -> Self {
+    Self {
+      date: r.date.to_rfc3339(),
+      source: r.source,
+      offset: r.offset,
+      size: r.size,
+      reason: r.reason,
+      file_name: r.file_name,
+    }
+  }
+}
+
+impl From<SuspiciousChange> for SuspiciousChangeObj {
+  fn from(c: SuspiciousChange) -> Self {
+    Self {
+      id: c.id,
+      date: c.date.to_rfc3339(),
+      reason: match c.reason {
+        ChangeReason::TaxNumberChurn => SuspiciousChangeReason::SuspiciousTaxNumberChurn,
+        ChangeReason::MassAddressEdits => SuspiciousChangeReason::SuspiciousMassAddressEdits,
+      } as i32,
+      editor_uid: c.editor_uid,
+      customer_ids: c.customer_ids,
+      reviewed_by: c.reviewed_by.unwrap_or_default(),
+      reviewed_at: c.reviewed_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+    }
+  }
+}
+
+impl From<PendingChange> for PendingChangeObj {
+  fn from(c: PendingChange) -> Self {
+    let address = c.invoice_address.clone().flatten();
+    Self {
+      id: c.id,
+      customer_id: c.customer_id,
+      requested_by: c.requested_by,
+      date_requested: c.date_requested.to_rfc3339(),
+      name_changed: c.name.is_some(),
+      name: c.name.unwrap_or_default(),
+      tax_number_changed: c.tax_number.is_some(),
+      tax_number: c
+        .tax_number
+        .flatten()
+        .map(|t| t.to_string())
+        .unwrap_or_default(),
+      invoice_name_changed: c.invoice_name.is_some(),
+      invoice_name: c.invoice_name.flatten().unwrap_or_default(),
+      invoice_address_changed: c.invoice_address.is_some(),
+      invoice_address_zip: address.as_ref().map(|a| a.zip.clone()).unwrap_or_default(),
+      invoice_address_location: address
+        .as_ref()
+        .map(|a| a.location.clone())
+        .unwrap_or_default(),
+      invoice_address_street: address.map(|a| a.street).unwrap_or_default(),
+    }
+  }
+}
+
+impl From<FieldChange> for FieldChangeObj {
+  fn from(c: FieldChange) -> Self {
+    Self {
+      date: c.date.to_rfc3339(),
+      created: c.action == MutationAction::Create,
+      user_id: c.user_id,
+      path: c.diff.path,
+      old_value: c.diff.old.to_string(),
+      new_value: c.diff.new.to_string(),
+    }
+  }
+}
+
+impl From<CustomFieldType> for CustomFieldTypeObj {
+  fn from(field_type: CustomFieldType) -> Self {
+    match field_type {
+      CustomFieldType::Text => CustomFieldTypeObj::CustomFieldText,
+      CustomFieldType::Number => CustomFieldTypeObj::CustomFieldNumber,
+      CustomFieldType::Bool => CustomFieldTypeObj::CustomFieldBool,
+      CustomFieldType::Date => CustomFieldTypeObj::CustomFieldDate,
+      CustomFieldType::Choice => CustomFieldTypeObj::CustomFieldChoice,
+    }
+  }
+}
+
+// Try to convert proto custom field type
+// CustomFieldUnspecified is invalid
+pub fn custom_field_type_from_proto(field_type: i32) -> ServiceResult<CustomFieldType> {
+  match CustomFieldTypeObj::from_i32(field_type) {
+    Some(CustomFieldTypeObj::CustomFieldText) => Ok(CustomFieldType::Text),
+    Some(CustomFieldTypeObj::CustomFieldNumber) => Ok(CustomFieldType::Number),
+    Some(CustomFieldTypeObj::CustomFieldBool) => Ok(CustomFieldType::Bool),
+    Some(CustomFieldTypeObj::CustomFieldDate) => Ok(CustomFieldType::Date),
+    Some(CustomFieldTypeObj::CustomFieldChoice) => Ok(CustomFieldType::Choice),
+    _ => Err(ServiceError::bad_request("Ismeretlen egyedi mező típus")),
+  }
+}
+
+impl From<CustomFieldDef> for CustomFieldObj {
+  fn from(d: CustomFieldDef) -> Self {
+    Self {
+      name: d.name,
+      label: d.label,
+      field_type: CustomFieldTypeObj::from(d.field_type) as i32,
+      choices: d.choices,
+      created_by: d.created_by,
+      date_created: d.date_created.to_rfc3339(),
+    }
+  }
+}
+
+// Try to convert proto communication channel
+pub fn communication_channel_from_proto(channel: i32) -> ServiceResult<ContactChannel> {
+  match CommunicationChannel::from_i32(channel) {
+    Some(CommunicationChannel::ChannelEmail) => Ok(ContactChannel::Email),
+    Some(CommunicationChannel::ChannelPhone) => Ok(ContactChannel::Phone),
+    Some(CommunicationChannel::ChannelSms) => Ok(ContactChannel::Sms),
+    Some(CommunicationChannel::ChannelPost) => Ok(ContactChannel::Post),
+    _ => Err(ServiceError::bad_request(
+      "Ismeretlen kommunikációs csatorna",
+    )),
+  }
+}
+
+// Try to convert proto communication direction
+pub fn direction_from_proto(direction: i32) -> ServiceResult<Direction> {
+  match CommunicationDirection::from_i32(direction) {
+    Some(CommunicationDirection::DirectionOutbound) => Ok(Direction::Outbound),
+    Some(CommunicationDirection::DirectionInbound) => Ok(Direction::Inbound),
+    _ => Err(ServiceError::bad_request("Ismeretlen kommunikációs irány")),
+  }
+}
+
+impl From<Communication> for CommunicationObj {
+  fn from(c: Communication) -> Self {
+    Self {
+      customer_id: c.customer_id,
+      channel: match c.channel {
+        ContactChannel::Email => CommunicationChannel::ChannelEmail,
+        ContactChannel::Phone => CommunicationChannel::ChannelPhone,
+        ContactChannel::Sms => CommunicationChannel::ChannelSms,
+        ContactChannel::Post => CommunicationChannel::ChannelPost,
+      } as i32,
+      direction: match c.direction {
+        Direction::Outbound => CommunicationDirection::DirectionOutbound,
+        Direction::Inbound => CommunicationDirection::DirectionInbound,
+      } as i32,
+      summary: c.summary,
+      created_by: c.created_by,
+      date: c.date.to_rfc3339(),
     }
   }
 }
+
+// Convert tax data issues of a customer, with the tax number shaped for the role
+pub fn tax_data_issues_to_obj(
+  c: &Customer,
+  issues: Vec<Issue>,
+  role: Role,
+) -> CustomerTaxDataIssues {
+  let tax_number = match &c.tax_number {
+    Some(tax_number) => tax_number.to_string(),
+    None => "".to_string(),
+  };
+  CustomerTaxDataIssues {
+    customer_id: c.id,
+    name: c.name.clone(),
+    kind: CustomerKindObj::from(c.kind) as i32,
+    tax_number: shape(role, Field::TaxNumber, tax_number),
+    issues: issues
+      .into_iter()
+      .map(|i| TaxDataIssueObj {
+        kind: match i.kind {
+          IssueKind::Invalid => TaxDataIssueKind::TaxIssueInvalidTaxNumber,
+          IssueKind::Missing => TaxDataIssueKind::TaxIssueMissingTaxNumber,
+          IssueKind::NotAllowed => TaxDataIssueKind::TaxIssuePrivateWithTaxNumber,
+          IssueKind::Duplicate => TaxDataIssueKind::TaxIssueDuplicateTaxNumber,
+        } as i32,
+        description: translate(&i.description, current_locale()),
+      })
+      .collect(),
+  }
+}
+
+// Convert segment to proto object, with its cached members if any
+pub fn segment_to_obj(s: Segment, membership: Option<Membership>) -> SegmentObj {
+  SegmentObj {
+    id: s.id,
+    name: s.name,
+    description: s.description,
+    query: s.query,
+    created_by: s.created_by,
+    date_created: s.date_created.to_rfc3339(),
+    member_count: membership
+      .as_ref()
+      .map(|m| m.members.len() as u32)
+      .unwrap_or_default(),
+    last_evaluated: membership
+      .map(|m| m.evaluated_at.to_rfc3339())
+      .unwrap_or_default(),
+  }
+}
+
+impl From<ErasureRequest> for ErasureRequestObj {
+  fn from(r: ErasureRequest) -> Self {
+    Self {
+      id: r.id,
+      customer_id: r.customer_id,
+      requested_by: r.requested_by,
+      reason: r.reason,
+      date_requested: r.date_requested.to_rfc3339(),
+      execute_after: r.execute_after.to_rfc3339(),
+      status: match r.status {
+        ErasureStatus::Pending => ErasureStatusObj::ErasurePending,
+        ErasureStatus::Approved => ErasureStatusObj::ErasureApproved,
+        ErasureStatus::Rejected => ErasureStatusObj::ErasureRejected,
+        ErasureStatus::Done => ErasureStatusObj::ErasureDone,
+      } as i32,
+      decided_by: r.decided_by.unwrap_or_default(),
+      date_decided: r.date_decided.map(|d| d.to_rfc3339()).unwrap_or_default(),
+      date_done: r.date_done.map(|d| d.to_rfc3339()).unwrap_or_default(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_from_accept_language() {
+    assert_eq!(Locale::from_accept_language(None), Locale::Hu);
+    assert_eq!(Locale::from_accept_language(Some("en")), Locale::En);
+    assert_eq!(
+      Locale::from_accept_language(Some("en-US,en;q=0.9")),
+      Locale::En
+    );
+    assert_eq!(
+      Locale::from_accept_language(Some("hu-HU,en;q=0.8")),
+      Locale::Hu
+    );
+    assert_eq!(
+      Locale::from_accept_language(Some("de,en;q=0.5,hu;q=0.7")),
+      Locale::Hu
+    );
+    assert_eq!(Locale::from_accept_language(Some("de, fr")), Locale::Hu);
+    assert_eq!(Locale::from_accept_language(Some("EN;q=0.3")), Locale::En);
+    assert_eq!(Locale::from_accept_language(Some("en;q=0")), Locale::Hu);
+  }
+
+  #[test]
+  fn test_translate() {
+    let en = |msg: &str| translate(msg, Locale::En);
+    assert_eq!(
+      translate("A(z) telefonszám megadása kötelező", Locale::Hu),
+      "A(z) telefonszám megadása kötelező"
+    );
+    assert_eq!(en("Az ügyfél már aktív"), "The customer is already active");
+    // Field names are translated too
+    assert_eq!(
+      en("A(z) telefonszám megadása kötelező"),
+      "The phone number is required"
+    );
+    assert_eq!(
+      en("A(z) utca, házszám hosszúsága max 200 karakter"),
+      "The street can be max 200 characters long"
+    );
+    // Nested messages
+    assert_eq!(
+      en("Hibás lekérdezés: ismeretlen mező: foo"),
+      "Invalid query: unknown field: foo"
+    );
+    assert_eq!(
+      en("Ez az adószám már egy másik ügyfélhez tartozik (ügyfél ID: 12)"),
+      "This tax number belongs to an other customer (customer ID: 12)"
+    );
+    assert_eq!(
+      en("Hibás lekérdezés: a(z) Active mezőre nem használható a(z) Lt operátor"),
+      "Invalid query: the Lt operator cannot be used on the Active field"
+    );
+    // Unknown messages are kept
+    assert_eq!(en("Ismeretlen hiba"), "Ismeretlen hiba");
+  }
+
+  #[test]
+  fn test_status_codes() {
+    use ::tonic::Code;
+    let status = |error: ServiceError| ::tonic::Status::from(error);
+    let not_found = status(ServiceError::customer_not_found(7));
+    assert_eq!(not_found.code(), Code::NotFound);
+    assert_eq!(
+      not_found.message(),
+      "Nem található ügyfél ezzel az ID-val: 7"
+    );
+    let conflict = status(ServiceError::customer_exists(7));
+    assert_eq!(conflict.code(), Code::AlreadyExists);
+    assert_eq!(conflict.metadata().get(CONFLICT_METADATA_KEY).unwrap(), "7");
+    assert_eq!(
+      status(ServiceError::invalid_field("phone", "x")).code(),
+      Code::InvalidArgument
+    );
+    assert_eq!(
+      status(ServiceError::bad_request("x")).code(),
+      Code::InvalidArgument
+    );
+    let internal = status(ServiceError::internal_error("Broken record"));
+    assert_eq!(
+      (internal.code(), internal.message()),
+      (Code::Internal, "Broken record")
+    );
+    // Storage errors
+    let pack = |error: ::packman::PackError| ServiceError::from(error).code();
+    assert_eq!(pack(::packman::PackError::ObjectNotFound), Code::NotFound);
+    assert_eq!(pack(::packman::PackError::IDTaken), Code::AlreadyExists);
+    assert_eq!(pack(::packman::PackError::PckflDataError), Code::Internal);
+    let io = std::io::Error::new(std::io::ErrorKind::NotFound, "x");
+    assert_eq!(ServiceError::from(io).code(), Code::Internal);
+  }
+
+  #[tokio::test]
+  async fn test_status_locale() {
+    let error = || ServiceError::invalid_field("phone", "A(z) telefonszám megadása kötelező");
+    let status: ::tonic::Status = error().into();
+    assert_eq!(status.message(), "A(z) telefonszám megadása kötelező");
+    let status = with_locale(Locale::En, async { ::tonic::Status::from(error()) }).await;
+    assert_eq!(status.message(), "The phone number is required");
+  }
+}
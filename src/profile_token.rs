@@ -0,0 +1,175 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Customer self-service profile tokens
+//
+// The public webshop gets an opaque token for a customer, and lets
+// the customer view and correct their own data with it, without
+// knowing the customer ID. A customer has one token at a time, a new
+// token replaces the previous one. Only the SHA-256 hashes of the
+// tokens are stored, next to the customer storage of the tenant.
+
+use crate::prelude::*;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// Default and max token TTL in seconds
+pub const DEFAULT_TTL_SECS: u32 = 24 * 60 * 60;
+pub const MAX_TTL_SECS: u32 = 30 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProfileToken {
+  // Hex encoded SHA-256 hash of the token
+  pub token_hash: String,
+  pub customer_id: u32,
+  pub created_by: u32,
+  pub date_created: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProfileTokenData {
+  tokens: Vec<ProfileToken>,
+}
+
+// Profile tokens of every tenant
+pub struct ProfileTokens {
+  data_dir: PathBuf,
+  packs: HashMap<String, Pack<ProfileTokenData>>,
+}
+
+impl ProfileTokens {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+      packs: HashMap::new(),
+    }
+  }
+  // Tokens pack of a tenant, loaded on first use
+  fn pack(&mut self, tenant: &str) -> ServiceResult<&mut Pack<ProfileTokenData>> {
+    if !self.packs.contains_key(tenant) {
+      let path = tenant_path(&self.data_dir, tenant).with_file_name("profile_tokens");
+      let pack = Pack::load_or_init(path, "profile_tokens")?;
+      self.packs.insert(tenant.to_string(), pack);
+    }
+    Ok(self.packs.get_mut(tenant).expect("Loaded above"))
+  }
+  // Generate token for a customer, replacing its previous token
+  // Returns the token, it cannot be looked up later
+  pub fn generate(
+    &mut self,
+    tenant: &str,
+    customer_id: u32,
+    ttl_secs: u32,
+    created_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<(String, ProfileToken)> {
+    let ttl_secs = match ttl_secs {
+      0 => DEFAULT_TTL_SECS,
+      x => x.min(MAX_TTL_SECS),
+    };
+    let token = random_token()?;
+    let profile_token = ProfileToken {
+      token_hash: hash(&token),
+      customer_id,
+      created_by,
+      date_created: now,
+      expires_at: now + chrono::Duration::seconds(ttl_secs as i64),
+    };
+    // Expired tokens are removed
+    self.pack(tenant)?.update(|data| {
+      data
+        .tokens
+        .retain(|t| t.customer_id != customer_id && t.expires_at > now);
+      data.tokens.push(profile_token.clone());
+    })?;
+    Ok((token, profile_token))
+  }
+  // Customer ID of an active token
+  pub fn resolve(&mut self, tenant: &str, token: &str, now: DateTime<Utc>) -> ServiceResult<u32> {
+    let token_hash = hash(token.trim());
+    self
+      .pack(tenant)?
+      .unpack()
+      .tokens
+      .iter()
+      .find(|t| t.token_hash == token_hash && t.expires_at > now)
+      .map(|t| t.customer_id)
+      .ok_or_else(|| ServiceError::permission_denied("Érvénytelen vagy lejárt profil token"))
+  }
+}
+
+fn hash(token: &str) -> String {
+  Sha256::digest(token.as_bytes())
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+// 64 hex characters, 256 bits from the OS random source
+fn random_token() -> ServiceResult<String> {
+  let mut bytes = [0u8; 32];
+  getrandom::getrandom(&mut bytes).map_err(|e| {
+    ServiceError::internal_error(&format!("Error while generating profile token: {}", e))
+  })?;
+  Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_profile_tokens() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let mut tokens = ProfileTokens::new(dir.path());
+    let (token, profile_token) = tokens.generate("", 7, 0, 1, now).unwrap();
+    assert_eq!(token.len(), 64);
+    assert_ne!(profile_token.token_hash, token);
+    assert_eq!(
+      profile_token.expires_at,
+      now + chrono::Duration::seconds(DEFAULT_TTL_SECS as i64)
+    );
+    assert_eq!(tokens.resolve("", &token, now).unwrap(), 7);
+    assert!(tokens.resolve("", "rossz", now).is_err());
+    // Tenants are separate
+    assert!(tokens.resolve("shop_a", &token, now).is_err());
+    // Tokens survive restart
+    let mut tokens = ProfileTokens::new(dir.path());
+    assert_eq!(tokens.resolve("", &token, now).unwrap(), 7);
+    // A new token replaces the previous one
+    let (second, _) = tokens.generate("", 7, 60, 1, now).unwrap();
+    assert_ne!(second, token);
+    assert!(tokens.resolve("", &token, now).is_err());
+    assert_eq!(tokens.resolve("", &second, now).unwrap(), 7);
+    // Expired
+    let later = now + chrono::Duration::seconds(61);
+    assert!(tokens.resolve("", &second, later).is_err());
+    // TTL is capped
+    let (_, capped) = tokens.generate("", 8, u32::MAX, 1, now).unwrap();
+    assert_eq!(
+      capped.expires_at,
+      now + chrono::Duration::seconds(MAX_TTL_SECS as i64)
+    );
+  }
+}
@@ -0,0 +1,70 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Customer service proto
+//
+// Generated code is not all used by the server side,
+// and is not subject to our lints.
+#[allow(dead_code, clippy::all)]
+pub mod customer {
+  tonic::include_proto!("customer");
+
+  // Encoded file descriptor set, served by the reflection service
+  pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("customer_descriptor");
+}
+
+// Shipping service proto, client side only
+#[allow(dead_code, clippy::all)]
+pub mod shipping {
+  tonic::include_proto!("shipping");
+}
+
+// Audit service proto, client side only
+#[allow(dead_code, clippy::all)]
+pub mod audit {
+  tonic::include_proto!("audit");
+}
+
+// Invoice service proto, client side only
+#[allow(dead_code, clippy::all)]
+pub mod invoice {
+  tonic::include_proto!("invoice");
+}
+
+// Purchase service proto, client side only
+#[allow(dead_code, clippy::all)]
+pub mod purchase {
+  tonic::include_proto!("purchase");
+}
+
+// Document store proto, client side only
+#[allow(dead_code, clippy::all)]
+pub mod document {
+  tonic::include_proto!("document");
+}
+
+// User service proto, client side only
+#[allow(dead_code, clippy::all)]
+pub mod user {
+  tonic::include_proto!("user");
+}
+
+// gRPC health checking protocol, server side only
+#[allow(dead_code, clippy::all)]
+pub mod health {
+  tonic::include_proto!("grpc.health.v1");
+}
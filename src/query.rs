@@ -0,0 +1,670 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Customer query language
+//
+// Small filter expressions for reporting tools, e.g.
+//   zip = "1111" AND (kind = company OR name ~ kft)
+//
+// Grammar:
+//   expr       := and ("OR" and)*
+//   and        := unary ("AND" unary)*
+//   unary      := "NOT" unary | "(" expr ")" | comparison
+//   comparison := field op value
+//
+// Operators are =, !=, ~ (contains) for text fields, and
+// =, !=, <, <=, >, >= for IDs and dates. Text comparisons
+// are case insensitive. Keywords can be lower or upper case.
+// Values are bare words or double quoted strings.
+// Custom fields are named custom.<name>, and compared by their
+// defined type, e.g. custom.greenhouse_size > 100.
+
+use crate::auth::{visibility, Role, Visibility};
+use crate::custom_field::{CustomFieldDef, CustomFieldType};
+use crate::customer::{Customer, CustomerKind};
+use crate::policy::Field;
+use crate::prelude::*;
+use crate::search;
+use chrono::prelude::*;
+
+// Max query length in characters
+const MAX_QUERY_LEN: usize = 1000;
+// Max nesting of NOT and parentheses
+const MAX_DEPTH: usize = 32;
+
+// Filterable customer field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryField {
+  Id,
+  Name,
+  Email,
+  Phone,
+  TaxNumber,
+  Zip,
+  Location,
+  Street,
+  Country,
+  Kind,
+  Active,
+  LoyaltyCardId,
+  InvoiceName,
+  Created,
+  LastActivity,
+}
+
+// Field value types, they decide the allowed operators
+#[derive(Clone, Copy, PartialEq)]
+enum FieldType {
+  Text,
+  Number,
+  Date,
+  Kind,
+  Bool,
+}
+
+impl QueryField {
+  fn from_name(name: &str) -> Option<Self> {
+    let field = match name.to_lowercase().as_str() {
+      "id" => QueryField::Id,
+      "name" => QueryField::Name,
+      "email" => QueryField::Email,
+      "phone" => QueryField::Phone,
+      "tax_number" => QueryField::TaxNumber,
+      "zip" | "address_zip" => QueryField::Zip,
+      "location" | "address_location" => QueryField::Location,
+      "street" | "address_street" => QueryField::Street,
+      "country" => QueryField::Country,
+      "kind" => QueryField::Kind,
+      "active" => QueryField::Active,
+      "loyalty_card_id" => QueryField::LoyaltyCardId,
+      "invoice_name" => QueryField::InvoiceName,
+      "created" | "date_created" => QueryField::Created,
+      "last_activity" => QueryField::LastActivity,
+      _ => return None,
+    };
+    Some(field)
+  }
+  fn field_type(&self) -> FieldType {
+    match self {
+      QueryField::Id => FieldType::Number,
+      QueryField::Kind => FieldType::Kind,
+      QueryField::Active => FieldType::Bool,
+      QueryField::Created | QueryField::LastActivity => FieldType::Date,
+      _ => FieldType::Text,
+    }
+  }
+  // Policy field, if the field is shaped by caller roles
+  fn policy_field(&self) -> Option<Field> {
+    match self {
+      QueryField::Email => Some(Field::Email),
+      QueryField::Phone => Some(Field::Phone),
+      QueryField::TaxNumber => Some(Field::TaxNumber),
+      QueryField::Zip => Some(Field::AddressZip),
+      QueryField::Location => Some(Field::AddressLocation),
+      QueryField::Street => Some(Field::AddressStreet),
+      _ => None,
+    }
+  }
+  fn text<'a>(&self, c: &'a Customer) -> Option<std::borrow::Cow<'a, str>> {
+    let value = match self {
+      QueryField::Name => c.name.as_str().into(),
+      QueryField::Email => c.email.as_str().into(),
+      QueryField::Phone => c.phone.as_str().into(),
+      QueryField::TaxNumber => c.tax_number.as_ref()?.to_string().into(),
+      QueryField::Zip => c.address_zip.as_str().into(),
+      QueryField::Location => c.address_location.as_str().into(),
+      QueryField::Street => c.address_street.as_str().into(),
+      QueryField::Country => c.country.as_str().into(),
+      QueryField::LoyaltyCardId => c.loyalty_card_id.as_deref()?.into(),
+      QueryField::InvoiceName => c.invoice_name().into(),
+      _ => return None,
+    };
+    Some(value)
+  }
+  fn date(&self, c: &Customer) -> Option<DateTime<Utc>> {
+    match self {
+      QueryField::Created => Some(c.date_created),
+      QueryField::LastActivity => c.last_activity,
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+  Eq,
+  Ne,
+  Contains,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+impl Op {
+  fn is_allowed(&self, field_type: FieldType) -> bool {
+    match self {
+      Op::Eq | Op::Ne => true,
+      Op::Contains => field_type == FieldType::Text,
+      Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+        field_type == FieldType::Number || field_type == FieldType::Date
+      }
+    }
+  }
+  fn compare<T: PartialOrd>(&self, a: T, b: T) -> bool {
+    match self {
+      Op::Eq => a == b,
+      Op::Ne => a != b,
+      Op::Lt => a < b,
+      Op::Le => a <= b,
+      Op::Gt => a > b,
+      Op::Ge => a >= b,
+      Op::Contains => false,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  Text(String),
+  Number(u32),
+  // Custom number fields
+  Decimal(f64),
+  Date(DateTime<Utc>),
+  Kind(CustomerKind),
+  Bool(bool),
+}
+
+// Parsed query
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+  And(Box<Query>, Box<Query>),
+  Or(Box<Query>, Box<Query>),
+  Not(Box<Query>),
+  Compare(QueryField, Op, Value),
+  // Custom field, by name
+  CompareCustom(String, Op, Value),
+}
+
+impl Query {
+  // Parse query expression
+  // custom_fields are the custom field definitions of the tenant
+  pub fn parse(input: &str, custom_fields: &[CustomFieldDef]) -> ServiceResult<Self> {
+    if input.chars().count() > MAX_QUERY_LEN {
+      return Err(error(&format!(
+        "a lekérdezés max {} karakter lehet",
+        MAX_QUERY_LEN
+      )));
+    }
+    let mut parser = Parser {
+      tokens: tokenize(input)?,
+      position: 0,
+      depth: 0,
+      custom_fields,
+    };
+    let query = parser.expr()?;
+    match parser.peek() {
+      None => Ok(query),
+      Some(token) => Err(error(&format!("váratlan elem: {}", token))),
+    }
+  }
+  // Check the role can filter on every field of the query
+  // Fields not shown in full to the role cannot be filtered,
+  // as their hidden parts could be guessed by queries
+  pub fn check_role(&self, role: Role) -> ServiceResult<()> {
+    match self {
+      Query::And(a, b) | Query::Or(a, b) => {
+        a.check_role(role)?;
+        b.check_role(role)
+      }
+      Query::Not(q) => q.check_role(role),
+      Query::Compare(field, _, _) => match field.policy_field() {
+        Some(f) if visibility(role, f) != Visibility::Full => Err(ServiceError::permission_denied(
+          &format!("A(z) {} mezőre nem lehet szűrni", f.display_name()),
+        )),
+        _ => Ok(()),
+      },
+      Query::CompareCustom(_, _, _) => Ok(()),
+    }
+  }
+  // Check customer matches the query
+  pub fn matches(&self, c: &Customer) -> bool {
+    match self {
+      Query::And(a, b) => a.matches(c) && b.matches(c),
+      Query::Or(a, b) => a.matches(c) || b.matches(c),
+      Query::Not(q) => !q.matches(c),
+      Query::Compare(field, op, value) => compare(c, *field, *op, value),
+      Query::CompareCustom(name, op, value) => compare_custom(c, name, *op, value),
+    }
+  }
+}
+
+fn compare_text(text: &str, op: Op, value: &str) -> bool {
+  let text = search::fold(text);
+  match op {
+    Op::Contains => text.contains(value),
+    _ => op.compare(text.as_str(), value),
+  }
+}
+
+fn compare(c: &Customer, field: QueryField, op: Op, value: &Value) -> bool {
+  match value {
+    // Missing values are empty
+    Value::Text(value) => compare_text(&field.text(c).unwrap_or_default(), op, value),
+    Value::Number(value) => op.compare(c.id, *value),
+    Value::Decimal(_) => false,
+    // Missing dates only differ from anything
+    Value::Date(value) => match field.date(c) {
+      Some(date) => op.compare(date, *value),
+      None => op == Op::Ne,
+    },
+    Value::Kind(kind) => op.compare(c.kind == *kind, true),
+    Value::Bool(value) => op.compare(c.active, *value),
+  }
+}
+
+fn compare_custom(c: &Customer, name: &str, op: Op, value: &Value) -> bool {
+  let stored = c.custom_fields.get(name);
+  if let Value::Text(value) = value {
+    return compare_text(stored.map_or("", |s| s.as_str()), op, value);
+  }
+  // Missing values only differ from anything
+  stored
+    .and_then(|s| match value {
+      Value::Decimal(value) => s.parse::<f64>().ok().map(|n| op.compare(n, *value)),
+      Value::Date(value) => parse_date_opt(s)
+        .ok()
+        .flatten()
+        .map(|d| op.compare(d, *value)),
+      Value::Bool(value) => s.parse::<bool>().ok().map(|b| op.compare(b, *value)),
+      _ => None,
+    })
+    .unwrap_or(op == Op::Ne)
+}
+
+fn error(msg: &str) -> ServiceError {
+  ServiceError::bad_request(&format!("Hibás lekérdezés: {}", msg))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Word(String),
+  Str(String),
+  Op(Op),
+  LParen,
+  RParen,
+}
+
+impl std::fmt::Display for Token {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Token::Word(word) => write!(f, "{}", word),
+      Token::Str(s) => write!(f, "\"{}\"", s),
+      Token::Op(op) => write!(f, "{:?}", op),
+      Token::LParen => write!(f, "("),
+      Token::RParen => write!(f, ")"),
+    }
+  }
+}
+
+fn is_word_char(c: char) -> bool {
+  c.is_alphanumeric() || "-_.:@+/".contains(c)
+}
+
+fn tokenize(input: &str) -> ServiceResult<Vec<Token>> {
+  let mut tokens = Vec::new();
+  let mut chars = input.chars().peekable();
+  while let Some(&c) = chars.peek() {
+    match c {
+      c if c.is_whitespace() => {
+        chars.next();
+      }
+      '(' => {
+        chars.next();
+        tokens.push(Token::LParen);
+      }
+      ')' => {
+        chars.next();
+        tokens.push(Token::RParen);
+      }
+      '"' => {
+        chars.next();
+        let mut s = String::new();
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+              Some(c) => s.push(c),
+              None => return Err(error("lezáratlan szöveg")),
+            },
+            Some(c) => s.push(c),
+            None => return Err(error("lezáratlan szöveg")),
+          }
+        }
+        tokens.push(Token::Str(s));
+      }
+      '=' | '~' => {
+        chars.next();
+        tokens.push(Token::Op(match c {
+          '=' => Op::Eq,
+          _ => Op::Contains,
+        }));
+      }
+      '!' | '<' | '>' => {
+        chars.next();
+        let with_eq = chars.peek() == Some(&'=');
+        if with_eq {
+          chars.next();
+        }
+        let op = match (c, with_eq) {
+          ('!', true) => Op::Ne,
+          ('<', false) => Op::Lt,
+          ('<', true) => Op::Le,
+          ('>', false) => Op::Gt,
+          ('>', true) => Op::Ge,
+          _ => return Err(error("ismeretlen operátor: !")),
+        };
+        tokens.push(Token::Op(op));
+      }
+      c if is_word_char(c) => {
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+          if !is_word_char(c) {
+            break;
+          }
+          word.push(c);
+          chars.next();
+        }
+        tokens.push(Token::Word(word));
+      }
+      c => return Err(error(&format!("ismeretlen karakter: {}", c))),
+    }
+  }
+  Ok(tokens)
+}
+
+struct Parser<'a> {
+  tokens: Vec<Token>,
+  position: usize,
+  depth: usize,
+  custom_fields: &'a [CustomFieldDef],
+}
+
+impl Parser<'_> {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.position)
+  }
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.position).cloned();
+    self.position += 1;
+    token
+  }
+  // Consume keyword if it is the next token
+  fn keyword(&mut self, keyword: &str) -> bool {
+    match self.peek() {
+      Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+        self.position += 1;
+        true
+      }
+      _ => false,
+    }
+  }
+  fn expr(&mut self) -> ServiceResult<Query> {
+    let mut query = self.and()?;
+    while self.keyword("OR") {
+      query = Query::Or(Box::new(query), Box::new(self.and()?));
+    }
+    Ok(query)
+  }
+  fn and(&mut self) -> ServiceResult<Query> {
+    let mut query = self.unary()?;
+    while self.keyword("AND") {
+      query = Query::And(Box::new(query), Box::new(self.unary()?));
+    }
+    Ok(query)
+  }
+  fn unary(&mut self) -> ServiceResult<Query> {
+    self.depth += 1;
+    if self.depth > MAX_DEPTH {
+      return Err(error("túl mély zárójelezés"));
+    }
+    let query = if self.keyword("NOT") {
+      Query::Not(Box::new(self.unary()?))
+    } else if self.peek() == Some(&Token::LParen) {
+      self.next();
+      let query = self.expr()?;
+      match self.next() {
+        Some(Token::RParen) => query,
+        _ => return Err(error("hiányzó )")),
+      }
+    } else {
+      self.comparison()?
+    };
+    self.depth -= 1;
+    Ok(query)
+  }
+  fn comparison(&mut self) -> ServiceResult<Query> {
+    let name = match self.next() {
+      Some(Token::Word(name)) => name,
+      Some(token) => return Err(error(&format!("mezőnév helyett: {}", token))),
+      None => return Err(error("hiányzó feltétel")),
+    };
+    // Custom fields are typed by their definition
+    let (field, field_type, label) = match name.strip_prefix("custom.") {
+      Some(custom) => {
+        let def = self
+          .custom_fields
+          .iter()
+          .find(|d| d.name == custom)
+          .ok_or_else(|| error(&format!("ismeretlen egyedi mező: {}", custom)))?;
+        let field_type = match def.field_type {
+          CustomFieldType::Text | CustomFieldType::Choice => FieldType::Text,
+          CustomFieldType::Number => FieldType::Number,
+          CustomFieldType::Bool => FieldType::Bool,
+          CustomFieldType::Date => FieldType::Date,
+        };
+        (None, field_type, name.clone())
+      }
+      None => {
+        let field = QueryField::from_name(&name)
+          .ok_or_else(|| error(&format!("ismeretlen mező: {}", name)))?;
+        (Some(field), field.field_type(), format!("{:?}", field))
+      }
+    };
+    let op = match self.next() {
+      Some(Token::Op(op)) if op.is_allowed(field_type) => op,
+      Some(Token::Op(op)) => {
+        return Err(error(&format!(
+          "a(z) {} mezőre nem használható a(z) {:?} operátor",
+          label, op
+        )))
+      }
+      _ => return Err(error(&format!("hiányzó operátor a(z) {} mező után", label))),
+    };
+    let raw = match self.next() {
+      Some(Token::Word(value)) | Some(Token::Str(value)) => value,
+      _ => return Err(error(&format!("hiányzó érték a(z) {} mezőhöz", label))),
+    };
+    let value = match field_type {
+      FieldType::Text => Value::Text(search::fold(&raw)),
+      FieldType::Number if field.is_none() => Value::Decimal(
+        raw
+          .replace(',', ".")
+          .parse()
+          .map_err(|_| error(&format!("hibás szám: {}", raw)))?,
+      ),
+      FieldType::Number => Value::Number(
+        raw
+          .parse()
+          .map_err(|_| error(&format!("hibás szám: {}", raw)))?,
+      ),
+      FieldType::Date => match parse_date_opt(&raw) {
+        Ok(Some(date)) => Value::Date(date),
+        _ => return Err(error(&format!("hibás dátum: {}", raw))),
+      },
+      FieldType::Kind => Value::Kind(match raw.to_lowercase().as_str() {
+        "private" => CustomerKind::Private,
+        "company" => CustomerKind::Company,
+        "institution" => CustomerKind::Institution,
+        _ => return Err(error(&format!("ismeretlen ügyfél típus: {}", raw))),
+      }),
+      FieldType::Bool => Value::Bool(match raw.to_lowercase().as_str() {
+        "true" => true,
+        "false" => false,
+        _ => return Err(error(&format!("hibás logikai érték: {}", raw))),
+      }),
+    };
+    Ok(match field {
+      Some(field) => Query::Compare(field, op, value),
+      None => Query::CompareCustom(name["custom.".len()..].to_string(), op, value),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn customer(id: u32, name: &str, zip: &str, kind: CustomerKind) -> Customer {
+    Customer {
+      id,
+      name: name.to_string(),
+      address_zip: zip.to_string(),
+      kind,
+      ..Customer::default()
+    }
+  }
+
+  fn ids(query: &str, customers: &[Customer]) -> Vec<u32> {
+    let query = Query::parse(query, &[]).unwrap();
+    customers
+      .iter()
+      .filter(|c| query.matches(c))
+      .map(|c| c.id)
+      .collect()
+  }
+
+  #[test]
+  fn test_query() {
+    let customers = [
+      customer(1, "Kiss Béla", "1111", CustomerKind::Private),
+      customer(2, "Kertész Kft", "1111", CustomerKind::Company),
+      customer(3, "Virág Bt", "6000", CustomerKind::Company),
+    ];
+    assert_eq!(ids("zip = \"1111\"", &customers), [1, 2]);
+    assert_eq!(ids("zip = 1111 AND kind = company", &customers), [2]);
+    assert_eq!(
+      ids("zip = 6000 or (kind = private and name ~ kiss)", &customers),
+      [1, 3]
+    );
+    assert_eq!(ids("NOT kind = company", &customers), [1]);
+    assert_eq!(ids("id >= 2 AND name ~ \"KFT\"", &customers), [2]);
+    // Accents and extra spaces do not matter either
+    assert_eq!(ids("name ~ \"KISS  BELA\"", &customers), [1]);
+    assert_eq!(ids("name = \"virag bt\"", &customers), [3]);
+    assert_eq!(ids("active = true AND id != 3", &customers), [1, 2]);
+    assert_eq!(ids("created > 2000-01-01", &customers), [1, 2, 3]);
+    assert!(ids("last_activity > 2000-01-01", &customers).is_empty());
+  }
+
+  #[test]
+  fn test_query_errors() {
+    for query in [
+      "",
+      "group = wholesale",
+      "zip > 1111",
+      "id ~ 1",
+      "id = x",
+      "kind = shop",
+      "zip = \"1111",
+      "(zip = 1111",
+      "zip = 1111 zip = 2222",
+      "zip 1111",
+      "zip = 1111 AND",
+    ] {
+      assert!(Query::parse(query, &[]).is_err(), "{}", query);
+    }
+    let deep = format!("{}zip = 1{}", "(".repeat(100), ")".repeat(100));
+    assert!(Query::parse(&deep, &[]).is_err());
+  }
+
+  #[test]
+  fn test_query_custom_fields() {
+    let def = |name: &str, field_type: CustomFieldType| CustomFieldDef {
+      name: name.to_string(),
+      label: name.to_string(),
+      field_type,
+      choices: Vec::new(),
+      created_by: 1,
+      date_created: Utc::now(),
+    };
+    let defs = [
+      def("greenhouse_size", CustomFieldType::Number),
+      def("day", CustomFieldType::Choice),
+      def("since", CustomFieldType::Date),
+      def("organic", CustomFieldType::Bool),
+    ];
+    let mut customers = [
+      customer(1, "Kiss Béla", "1111", CustomerKind::Private),
+      customer(2, "Kertész Kft", "1111", CustomerKind::Company),
+      customer(3, "Virág Bt", "6000", CustomerKind::Company),
+    ];
+    for (c, size, day) in [(0, "120.5", "Hétfő"), (1, "80", "Kedd")] {
+      let values = &mut customers[c].custom_fields;
+      values.insert("greenhouse_size".to_string(), size.to_string());
+      values.insert("day".to_string(), day.to_string());
+    }
+    customers[1]
+      .custom_fields
+      .insert("since".to_string(), "2020-05-01".to_string());
+    let ids = |query: &str| {
+      let query = Query::parse(query, &defs).unwrap();
+      customers
+        .iter()
+        .filter(|c| query.matches(c))
+        .map(|c| c.id)
+        .collect::<Vec<u32>>()
+    };
+    // Numbers are compared as numbers, not as text
+    assert_eq!(ids("custom.greenhouse_size > 100"), [1]);
+    assert_eq!(ids("custom.greenhouse_size <= \"100,5\""), [2]);
+    assert_eq!(ids("custom.day = hetfo OR custom.day ~ ked"), [1, 2]);
+    assert_eq!(ids("custom.since < 2021-01-01"), [2]);
+    // Missing values only differ
+    assert_eq!(ids("custom.since != 2021-01-01"), [1, 2, 3]);
+    assert_eq!(ids("custom.organic = false"), Vec::<u32>::new());
+    assert_eq!(ids("custom.day = \"\""), [3]);
+    for query in [
+      "custom.color = zöld",
+      "custom.greenhouse_size ~ 1",
+      "custom.greenhouse_size = sok",
+      "custom.day > a",
+    ] {
+      assert!(Query::parse(query, &defs).is_err(), "{}", query);
+    }
+    assert!(Query::parse("custom.day = a", &[]).is_err());
+  }
+
+  #[test]
+  fn test_query_role() {
+    let query = Query::parse("name ~ kft OR tax_number = 12345678-2-41", &[]).unwrap();
+    assert!(query.check_role(Role::Billing).is_ok());
+    assert!(query.check_role(Role::Sales).is_err());
+    let query = Query::parse("zip = 1111", &[]).unwrap();
+    assert!(query.check_role(Role::Sales).is_ok());
+  }
+}
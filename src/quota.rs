@@ -0,0 +1,188 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Customer quotas
+//
+// Soft limits on the number of customers per tenant, loaded from
+// a YAML file at startup. Above warn_percent of its limit a tenant
+// gets a warning with every new customer, at the limit new customers
+// are rejected. Existing customers are never touched, and tenants
+// without a limit can grow without bounds.
+
+use crate::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaPolicy {
+  // Limit of the tenants not listed, 0 means no limit
+  pub default_limit: usize,
+  pub warn_percent: u32,
+  // Limit per tenant ID, 0 means no limit
+  pub tenants: HashMap<String, usize>,
+}
+
+impl Default for QuotaPolicy {
+  fn default() -> Self {
+    Self {
+      default_limit: 0,
+      warn_percent: 80,
+      tenants: HashMap::new(),
+    }
+  }
+}
+
+// Customer count of a tenant compared to its limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuotaUsage {
+  pub customer_count: usize,
+  pub limit: Option<usize>,
+  pub warn_percent: u32,
+}
+
+impl QuotaUsage {
+  // Customer count from which the tenant gets warnings
+  pub fn warn_at(&self) -> Option<usize> {
+    self
+      .limit
+      .map(|limit| (limit * self.warn_percent as usize).div_ceil(100))
+  }
+  pub fn is_warning(&self) -> bool {
+    matches!(self.warn_at(), Some(warn_at) if self.customer_count >= warn_at)
+  }
+  pub fn is_exhausted(&self) -> bool {
+    matches!(self.limit, Some(limit) if self.customer_count >= limit)
+  }
+  // Used part of the limit in percent, 0 without limit
+  pub fn used_percent(&self) -> u32 {
+    match self.limit {
+      Some(limit) => (self.customer_count * 100 / limit) as u32,
+      None => 0,
+    }
+  }
+  // Warning returned with new customers above the warning level
+  pub fn warning(&self) -> Option<FieldViolation> {
+    match (self.is_warning(), self.limit) {
+      (true, Some(limit)) => Some(FieldViolation {
+        field: String::new(),
+        description: format!(
+          "Az ügyfélkvóta {}%-a felhasználva ({} / {})",
+          self.used_percent(),
+          self.customer_count,
+          limit
+        ),
+      }),
+      _ => None,
+    }
+  }
+}
+
+impl QuotaPolicy {
+  // Load policy from YAML file
+  // If the file does not exist, returns the default policy
+  pub fn load_or_default(path: &Path) -> ServiceResult<Self> {
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+    let content = std::fs::read_to_string(path)?;
+    Self::from_yaml(&content)
+  }
+  pub fn from_yaml(content: &str) -> ServiceResult<Self> {
+    let policy: Self = serde_yaml::from_str(content).map_err(|e| {
+      ServiceError::internal_error(&format!("Error while parsing quota policy: {}", e))
+    })?;
+    if policy.warn_percent == 0 || policy.warn_percent > 100 {
+      return Err(ServiceError::internal_error(
+        "Quota policy error: warn_percent must be between 1 and 100",
+      ));
+    }
+    Ok(policy)
+  }
+  // Limit of a tenant, None if it has no limit
+  pub fn limit(&self, tenant: &str) -> Option<usize> {
+    match self
+      .tenants
+      .get(tenant)
+      .copied()
+      .unwrap_or(self.default_limit)
+    {
+      0 => None,
+      limit => Some(limit),
+    }
+  }
+  pub fn usage(&self, tenant: &str, customer_count: usize) -> QuotaUsage {
+    QuotaUsage {
+      customer_count,
+      limit: self.limit(tenant),
+      warn_percent: self.warn_percent,
+    }
+  }
+  // Check a new customer fits into the quota of the tenant
+  pub fn check_new(&self, tenant: &str, customer_count: usize) -> ServiceResult<()> {
+    match self.usage(tenant, customer_count).is_exhausted() {
+      true => Err(ServiceError::resource_exhausted(
+        "Az ügyfélkvóta betelt, új ügyfél nem hozható létre",
+      )),
+      false => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_from_yaml() {
+    let policy =
+      QuotaPolicy::from_yaml("default_limit: 1000\ntenants:\n  shop_a: 10\n  shop_b: 0\n").unwrap();
+    assert_eq!(policy.warn_percent, 80);
+    assert_eq!(policy.limit("shop_a"), Some(10));
+    assert_eq!(policy.limit("shop_b"), None);
+    assert_eq!(policy.limit(""), Some(1000));
+    assert_eq!(QuotaPolicy::default().limit(""), None);
+    assert!(QuotaPolicy::from_yaml("warn_percent: 0").is_err());
+    assert!(QuotaPolicy::from_yaml("warn_percent: 101").is_err());
+    assert!(QuotaPolicy::from_yaml("limit: 10").is_err());
+  }
+
+  #[test]
+  fn test_usage() {
+    let policy = QuotaPolicy::from_yaml("tenants:\n  shop_a: 10\n").unwrap();
+    let usage = policy.usage("shop_a", 7);
+    assert_eq!(usage.warn_at(), Some(8));
+    assert!(!usage.is_warning());
+    assert!(usage.warning().is_none());
+    let usage = policy.usage("shop_a", 8);
+    assert!(usage.is_warning());
+    assert!(!usage.is_exhausted());
+    assert_eq!(
+      usage.warning().map(|w| w.description).as_deref(),
+      Some("Az ügyfélkvóta 80%-a felhasználva (8 / 10)")
+    );
+    assert!(policy.check_new("shop_a", 9).is_ok());
+    assert!(policy.usage("shop_a", 10).is_exhausted());
+    assert!(policy.check_new("shop_a", 10).is_err());
+    // Without limit
+    let usage = policy.usage("shop_b", 1_000_000);
+    assert!(!usage.is_warning());
+    assert_eq!(usage.used_percent(), 0);
+    assert!(policy.check_new("shop_b", 1_000_000).is_ok());
+  }
+}
@@ -0,0 +1,464 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Replication to a standby instance
+//
+// The primary records every customer write in an in-memory
+// journal, and streams it to the standby with the admin Replicate
+// RPC. A standby started with REPLICATE_FROM follows the primary:
+// it applies the records to its own storage as they are, and
+// rejects customer writes of its clients. A new standby, or one
+// too far behind, first gets a snapshot of every customer of every
+// tenant. Standbys resume from the last applied record after a
+// reconnect, as long as the primary is running and still has it.
+
+use crate::customer::Customer;
+use crate::prelude::*;
+use crate::proto::customer::customer_client::CustomerClient;
+use crate::proto::customer::{ReplicateRequest, ReplicationKind, ReplicationRecord};
+use crate::tenant::Tenants;
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tonic::{Request, Status};
+
+// Records kept for standbys catching up
+// Older records are dropped, standbys missing them get a snapshot
+const JOURNAL_CAPACITY: usize = 10_000;
+
+// Max delay between standby reconnects
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct JournalRecord {
+  pub seq: u64,
+  pub tenant: String,
+  pub customer: Customer,
+}
+
+#[derive(Default)]
+struct JournalData {
+  // Seq of the last record
+  last_seq: u64,
+  records: VecDeque<JournalRecord>,
+}
+
+// Customer writes of every tenant, in the order they were stored
+pub struct Journal {
+  // ID of this journal, seqs of an other one are not comparable
+  epoch: String,
+  data: Mutex<JournalData>,
+  // New record seqs
+  sender: broadcast::Sender<u64>,
+}
+
+impl Journal {
+  pub fn new() -> Self {
+    let (sender, _) = broadcast::channel(1024);
+    Self {
+      epoch: format!(
+        "{}-{}",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default()
+      ),
+      data: Mutex::new(JournalData::default()),
+      sender,
+    }
+  }
+  pub fn epoch(&self) -> &str {
+    &self.epoch
+  }
+  fn data(&self) -> std::sync::MutexGuard<'_, JournalData> {
+    self.data.lock().expect("Replication journal lock poisoned")
+  }
+  // Record stored customer
+  // Called while the customer db of the tenant is locked
+  pub fn record(&self, tenant: &str, customer: &Customer) {
+    let seq = {
+      let mut data = self.data();
+      data.last_seq += 1;
+      let seq = data.last_seq;
+      data.records.push_back(JournalRecord {
+        seq,
+        tenant: tenant.to_string(),
+        customer: customer.clone(),
+      });
+      if data.records.len() > JOURNAL_CAPACITY {
+        data.records.pop_front();
+      }
+      seq
+    };
+    let _ = self.sender.send(seq);
+  }
+  // Drop every record, e.g. after the storage was reloaded
+  // from disk, so standbys get a new snapshot
+  pub fn invalidate(&self) {
+    let seq = {
+      let mut data = self.data();
+      data.records.clear();
+      data.last_seq += 1;
+      data.last_seq
+    };
+    let _ = self.sender.send(seq);
+  }
+  pub fn last_seq(&self) -> u64 {
+    self.data().last_seq
+  }
+  // Records after seq
+  // None if some of them are not kept any more
+  pub fn after(&self, seq: u64) -> Option<Vec<JournalRecord>> {
+    let data = self.data();
+    let first_seq = data.last_seq + 1 - data.records.len() as u64;
+    if seq > data.last_seq || seq + 1 < first_seq {
+      return None;
+    }
+    Some(
+      data
+        .records
+        .iter()
+        .skip((seq + 1 - first_seq) as usize)
+        .cloned()
+        .collect(),
+    )
+  }
+  pub fn subscribe(&self) -> broadcast::Receiver<u64> {
+    self.sender.subscribe()
+  }
+}
+
+fn to_obj(
+  kind: ReplicationKind,
+  epoch: &str,
+  seq: u64,
+  tenant: &str,
+  customer: Option<&Customer>,
+) -> ServiceResult<ReplicationRecord> {
+  let customer = match customer {
+    Some(customer) => bincode::serialize(customer).map_err(|e| {
+      ServiceError::internal_error(&format!("Error while encoding customer: {}", e))
+    })?,
+    None => Vec::new(),
+  };
+  Ok(ReplicationRecord {
+    kind: kind as i32,
+    epoch: epoch.to_string(),
+    seq,
+    tenant: tenant.to_string(),
+    customer,
+  })
+}
+
+// Send every customer of every tenant
+// Returns the journal seq the snapshot was taken at
+async fn send_snapshot(
+  tenants: &Tenants,
+  journal: &Journal,
+  tx: &mpsc::Sender<Result<ReplicationRecord, Status>>,
+) -> ServiceResult<Option<u64>> {
+  // Writes after this seq are sent after the snapshot,
+  // even if the snapshot has them already
+  let seq = journal.last_seq();
+  for tenant in tenants.ids().await? {
+    let customers = tenants
+      .get(&tenant)
+      .await?
+      .lock()
+      .await
+      .iter()
+      .cloned()
+      .collect::<Vec<Customer>>();
+    for customer in &customers {
+      let record = to_obj(
+        ReplicationKind::ReplicationSnapshot,
+        journal.epoch(),
+        seq,
+        &tenant,
+        Some(customer),
+      )?;
+      if tx.send(Ok(record)).await.is_err() {
+        return Ok(None);
+      }
+    }
+  }
+  let end = to_obj(
+    ReplicationKind::ReplicationSnapshotEnd,
+    journal.epoch(),
+    seq,
+    "",
+    None,
+  )?;
+  match tx.send(Ok(end)).await {
+    Ok(_) => Ok(Some(seq)),
+    Err(_) => Ok(None),
+  }
+}
+
+// Stream journal records to a standby until it disconnects
+// Starts with a snapshot, unless the standby can resume
+pub async fn serve(
+  tenants: Arc<Tenants>,
+  journal: Arc<Journal>,
+  r: ReplicateRequest,
+  tx: mpsc::Sender<Result<ReplicationRecord, Status>>,
+) {
+  let mut updates = journal.subscribe();
+  let mut seq = match r.epoch == journal.epoch() {
+    true => Some(r.since_seq),
+    false => None,
+  };
+  loop {
+    let records = match seq.and_then(|seq| journal.after(seq)) {
+      Some(records) => records,
+      None => {
+        match send_snapshot(&tenants, &journal, &tx).await {
+          Ok(Some(snapshot_seq)) => seq = Some(snapshot_seq),
+          Ok(None) => return,
+          Err(error) => {
+            let _ = tx.send(Err(error.into())).await;
+            return;
+          }
+        }
+        continue;
+      }
+    };
+    for record in records {
+      seq = Some(record.seq);
+      let obj = to_obj(
+        ReplicationKind::ReplicationChange,
+        journal.epoch(),
+        record.seq,
+        &record.tenant,
+        Some(&record.customer),
+      );
+      let sent = match obj {
+        Ok(obj) => tx.send(Ok(obj)).await,
+        Err(error) => tx.send(Err(error.into())).await,
+      };
+      if sent.is_err() {
+        return;
+      }
+    }
+    match updates.recv().await {
+      Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => (),
+      Err(broadcast::error::RecvError::Closed) => return,
+    }
+  }
+}
+
+// Last applied record of the primary journal
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Position {
+  pub epoch: String,
+  pub seq: u64,
+}
+
+// Apply record received from the primary
+pub async fn apply(
+  tenants: &Tenants,
+  record: ReplicationRecord,
+  position: &mut Position,
+) -> ServiceResult<()> {
+  let kind = ReplicationKind::from_i32(record.kind)
+    .ok_or_else(|| ServiceError::internal_error("Unknown replication record kind"))?;
+  if kind != ReplicationKind::ReplicationSnapshotEnd {
+    let customer: Customer = bincode::deserialize(&record.customer).map_err(|e| {
+      ServiceError::internal_error(&format!("Error while decoding customer: {}", e))
+    })?;
+    tenants
+      .get(&record.tenant)
+      .await?
+      .lock()
+      .await
+      .put(customer)?;
+  }
+  // Snapshot records only count once the whole snapshot is applied
+  if kind != ReplicationKind::ReplicationSnapshot {
+    *position = Position {
+      epoch: record.epoch,
+      seq: record.seq,
+    };
+  }
+  Ok(())
+}
+
+// Stream records from the primary once
+async fn follow_once(
+  primary: &str,
+  admin_token: Option<&str>,
+  tenants: &Tenants,
+  position: &mut Position,
+  attempts: &mut u32,
+) -> ServiceResult<()> {
+  let mut client = CustomerClient::connect(primary.to_string())
+    .await
+    .map_err(|e| ServiceError::internal_error(&format!("Error while connecting: {}", e)))?;
+  let mut request = Request::new(ReplicateRequest {
+    epoch: position.epoch.clone(),
+    since_seq: position.seq,
+  });
+  if let Some(token) = admin_token {
+    let token = token
+      .parse()
+      .map_err(|_| ServiceError::internal_error("Invalid admin token"))?;
+    request.metadata_mut().insert("admin-token", token);
+  }
+  let status = |e: Status| ServiceError::internal_error(&format!("{}", e));
+  let mut stream = client
+    .replicate(request)
+    .await
+    .map_err(status)?
+    .into_inner();
+  println!("Replicating from {}", primary);
+  while let Some(record) = stream.message().await.map_err(status)? {
+    apply(tenants, record, position).await?;
+    *attempts = 0;
+  }
+  Ok(())
+}
+
+// Follow the primary until the process stops
+// Reconnects with backoff after errors
+pub async fn follow(primary: String, admin_token: Option<String>, tenants: Arc<Tenants>) {
+  let mut position = Position::default();
+  let mut attempts = 0;
+  loop {
+    match follow_once(
+      &primary,
+      admin_token.as_deref(),
+      &tenants,
+      &mut position,
+      &mut attempts,
+    )
+    .await
+    {
+      Ok(()) => eprintln!("Replication stream of {} closed", primary),
+      Err(error) => eprintln!("Error while replicating from {}: {}", primary, error),
+    }
+    attempts += 1;
+    tokio::time::sleep(crate::outbox::retry_delay(attempts).min(RECONNECT_MAX_DELAY)).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tenant::Backend;
+
+  fn customer(id: u32, name: &str) -> Customer {
+    Customer {
+      id,
+      name: name.to_string(),
+      ..Customer::default()
+    }
+  }
+
+  #[test]
+  fn test_journal() {
+    let journal = Journal::new();
+    assert!(journal.after(0).unwrap().is_empty());
+    assert!(journal.after(1).is_none());
+    journal.record("", &customer(1, "Kiss Béla"));
+    journal.record("shop_a", &customer(1, "Nagy Anna"));
+    let records = journal.after(0).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].tenant, "shop_a");
+    assert_eq!(journal.after(1).unwrap()[0].seq, 2);
+    assert!(journal.after(2).unwrap().is_empty());
+    // Dropped records need a snapshot
+    journal.invalidate();
+    assert!(journal.after(2).is_none());
+    assert!(journal.after(journal.last_seq()).unwrap().is_empty());
+    for i in 0..JOURNAL_CAPACITY as u32 + 1 {
+      journal.record("", &customer(i, "Kiss Béla"));
+    }
+    let last_seq = journal.last_seq();
+    assert!(journal
+      .after(last_seq - JOURNAL_CAPACITY as u64 - 1)
+      .is_none());
+    assert_eq!(
+      journal
+        .after(last_seq - JOURNAL_CAPACITY as u64)
+        .unwrap()
+        .len(),
+      JOURNAL_CAPACITY
+    );
+  }
+
+  // Standby applying the records sent by the primary
+  async fn replicate(
+    primary: Arc<Tenants>,
+    journal: Arc<Journal>,
+    standby: &Tenants,
+    position: &mut Position,
+    count: usize,
+  ) {
+    let (tx, mut rx) = mpsc::channel(10);
+    let request = ReplicateRequest {
+      epoch: position.epoch.clone(),
+      since_seq: position.seq,
+    };
+    let task = tokio::spawn(serve(primary, journal, request, tx));
+    for _ in 0..count {
+      let record = rx.recv().await.unwrap().unwrap();
+      apply(standby, record, position).await.unwrap();
+    }
+    // Standby disconnects
+    drop(rx);
+    task.abort();
+  }
+
+  #[tokio::test]
+  async fn test_replication() {
+    let journal = Arc::new(Journal::new());
+    let mut primary = Tenants::load("data".into(), Backend::Memory).unwrap();
+    primary.set_journal(journal.clone());
+    let primary = Arc::new(primary);
+    let mut standby = Tenants::load("data".into(), Backend::Memory).unwrap();
+    standby.set_read_only(true);
+    for (tenant, id) in [("", 1), ("", 2), ("shop_a", 1)] {
+      let db = primary.get(tenant).await.unwrap();
+      db.lock().await.insert(customer(id, "Kiss Béla")).unwrap();
+    }
+    // Snapshot of 3 customers and its end
+    let mut position = Position::default();
+    replicate(primary.clone(), journal.clone(), &standby, &mut position, 4).await;
+    assert_eq!(position.epoch, journal.epoch());
+    let db = standby.get("shop_a").await.unwrap();
+    assert_eq!(db.lock().await.find_id(&1).unwrap().name, "Kiss Béla");
+    assert_eq!(standby.get("").await.unwrap().lock().await.len(), 2);
+    // Standby resumes with the changes only
+    let db = primary.get("").await.unwrap();
+    db.lock()
+      .await
+      .update(&2, 1, |c| {
+        c.name = "Nagy Anna".to_string();
+        Ok(())
+      })
+      .unwrap();
+    let updated = db.lock().await.find_id(&2).unwrap().clone();
+    replicate(primary.clone(), journal.clone(), &standby, &mut position, 1).await;
+    let db = standby.get("").await.unwrap();
+    let replicated = db.lock().await.find_id(&2).unwrap().clone();
+    assert_eq!(replicated.name, "Nagy Anna");
+    assert_eq!(replicated.last_modified, updated.last_modified);
+    assert_eq!(position.seq, journal.last_seq());
+    // Standby rejects writes of its clients
+    assert!(db.lock().await.insert(customer(3, "Kiss Béla")).is_err());
+  }
+}
@@ -0,0 +1,207 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Customer ID reservations
+//
+// Offline POS terminals reserve a block of customer IDs, create
+// customers locally, and sync them later with CreateNew using the
+// reserved IDs. Reserved IDs are skipped by the ID allocation until
+// the reservation expires, then its unused IDs are released.
+// Reservations are stored next to the customer storage of the tenant.
+
+use crate::prelude::*;
+use crate::tenant::tenant_path;
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const MAX_COUNT: u32 = 1000;
+
+// Default and max reservation TTL in seconds
+pub const DEFAULT_TTL_SECS: u32 = 7 * 24 * 60 * 60;
+pub const MAX_TTL_SECS: u32 = 30 * 24 * 60 * 60;
+
+// Block of reserved customer IDs
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IdReservation {
+  pub first_id: u32,
+  pub count: u32,
+  pub reserved_by: u32,
+  pub date_reserved: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+}
+
+impl IdReservation {
+  pub fn last_id(&self) -> u32 {
+    self.first_id + self.count - 1
+  }
+  pub fn contains(&self, id: u32) -> bool {
+    (self.first_id..=self.last_id()).contains(&id)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReservationData {
+  reservations: Vec<IdReservation>,
+}
+
+// ID reservations of every tenant
+// Use it while the customer storage of the tenant is locked,
+// so allocations do not race
+pub struct IdReservations {
+  data_dir: PathBuf,
+  packs: HashMap<String, Pack<ReservationData>>,
+}
+
+impl IdReservations {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+      packs: HashMap::new(),
+    }
+  }
+  // Reservations pack of a tenant, loaded on first use
+  // Expired reservations are removed
+  fn pack(
+    &mut self,
+    tenant: &str,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<&mut Pack<ReservationData>> {
+    if !self.packs.contains_key(tenant) {
+      let path = tenant_path(&self.data_dir, tenant).with_file_name("id_reservations");
+      let pack = Pack::load_or_init(path, "reservations")?;
+      self.packs.insert(tenant.to_string(), pack);
+    }
+    let pack = self.packs.get_mut(tenant).expect("Loaded above");
+    if pack
+      .unpack()
+      .reservations
+      .iter()
+      .any(|r| r.expires_at <= now)
+    {
+      pack.update(|data| data.reservations.retain(|r| r.expires_at > now))?;
+    }
+    Ok(pack)
+  }
+  // Reserve IDs after next_id, the next free customer ID
+  pub fn reserve(
+    &mut self,
+    tenant: &str,
+    next_id: u32,
+    count: u32,
+    ttl_secs: u32,
+    reserved_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<IdReservation> {
+    if count == 0 || count > MAX_COUNT {
+      return Err(ServiceError::invalid_field(
+        "count",
+        &format!("Egyszerre 1 és {} közötti ID foglalható", MAX_COUNT),
+      ));
+    }
+    let ttl_secs = match ttl_secs {
+      0 => DEFAULT_TTL_SECS,
+      x => x.min(MAX_TTL_SECS),
+    };
+    let first_id = next_id.max(self.next_free_id(tenant, now)?);
+    if first_id.checked_add(count).is_none() {
+      return Err(ServiceError::internal_error("Customer IDs are exhausted"));
+    }
+    let reservation = IdReservation {
+      first_id,
+      count,
+      reserved_by,
+      date_reserved: now,
+      expires_at: now + chrono::Duration::seconds(ttl_secs as i64),
+    };
+    self
+      .pack(tenant, now)?
+      .update(|data| data.reservations.push(reservation.clone()))?;
+    Ok(reservation)
+  }
+  // First ID after the active reservations
+  // 0 if there is no active reservation
+  pub fn next_free_id(&mut self, tenant: &str, now: DateTime<Utc>) -> ServiceResult<u32> {
+    Ok(
+      self
+        .pack(tenant, now)?
+        .unpack()
+        .reservations
+        .iter()
+        .map(|r| r.last_id() + 1)
+        .max()
+        .unwrap_or(0),
+    )
+  }
+  // Check customer ID is reserved by an active reservation
+  pub fn check_reserved(&mut self, tenant: &str, id: u32, now: DateTime<Utc>) -> ServiceResult<()> {
+    match self
+      .pack(tenant, now)?
+      .unpack()
+      .reservations
+      .iter()
+      .any(|r| r.contains(id))
+    {
+      true => Ok(()),
+      false => Err(ServiceError::failed_precondition(&format!(
+        "A(z) {} ügyfél ID nincs lefoglalva, vagy a foglalás lejárt",
+        id
+      ))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reserve() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let mut reservations = IdReservations::new(dir.path());
+    assert_eq!(reservations.next_free_id("", now).unwrap(), 0);
+    assert!(reservations.reserve("", 11, 0, 0, 1, now).is_err());
+    assert!(reservations.reserve("", 11, 1001, 0, 1, now).is_err());
+
+    let first = reservations.reserve("", 11, 10, 60, 1, now).unwrap();
+    assert_eq!((first.first_id, first.last_id()), (11, 20));
+    // Next block starts after the first one
+    let second = reservations.reserve("", 11, 5, 0, 2, now).unwrap();
+    assert_eq!((second.first_id, second.last_id()), (21, 25));
+    assert_eq!(
+      second.expires_at,
+      now + chrono::Duration::seconds(DEFAULT_TTL_SECS as i64)
+    );
+    assert_eq!(reservations.next_free_id("", now).unwrap(), 26);
+    assert!(reservations.check_reserved("", 15, now).is_ok());
+    assert!(reservations.check_reserved("", 26, now).is_err());
+    // Tenants are separate
+    assert_eq!(reservations.next_free_id("shop_a", now).unwrap(), 0);
+
+    // Reservations survive restart
+    let mut reservations = IdReservations::new(dir.path());
+    assert_eq!(reservations.next_free_id("", now).unwrap(), 26);
+
+    // Expired reservations are released
+    let later = now + chrono::Duration::seconds(61);
+    assert!(reservations.check_reserved("", 15, later).is_err());
+    assert!(reservations.check_reserved("", 21, later).is_ok());
+  }
+}
@@ -0,0 +1,388 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// REST/JSON gateway
+//
+// Integrations that cannot speak gRPC get a small HTTP/JSON facade,
+// served on REST_LISTEN_ADDR. Every endpoint is turned into a unary
+// gRPC call of the wrapped service, so auth, throttling, metrics and
+// the error messages are the same as on the gRPC listeners. Request
+// headers are passed on as metadata, e.g. tenant-id or caller-token.
+// Bodies over MAX_REQUEST_BYTES are rejected with 413 before they
+// are parsed.
+//
+//   GET  /customers/{id}                       GetById
+//   GET  /customers?query=&kind=&max_results=  FindCustomer
+//   POST /customers                            CreateNew
+
+use crate::error_details;
+use crate::payload;
+use crate::prelude::*;
+use crate::proto::customer::*;
+use hyper::body::HttpBody;
+use percent_encoding::percent_decode_str;
+use prost::Message;
+use serde::Serialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service};
+use tonic::transport::Body;
+use tonic::{Code, Status};
+
+// Length prefixed message header size
+const HEADER_LEN: usize = 5;
+
+// Not passed on as metadata
+// Responses are read uncompressed
+const HOP_HEADERS: &[&str] = &[
+  "host",
+  "connection",
+  "content-length",
+  "content-type",
+  "te",
+  "grpc-encoding",
+  "grpc-accept-encoding",
+];
+
+// HTTP status of a gRPC code, as google.api.http maps them
+fn http_status(code: Code) -> http::StatusCode {
+  use http::StatusCode;
+  match code {
+    Code::Ok => StatusCode::OK,
+    Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => StatusCode::BAD_REQUEST,
+    Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+    Code::PermissionDenied => StatusCode::FORBIDDEN,
+    Code::NotFound => StatusCode::NOT_FOUND,
+    Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+    Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+    Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+    Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+    Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+    _ => StatusCode::INTERNAL_SERVER_ERROR,
+  }
+}
+
+fn json_response(status: http::StatusCode, value: &serde_json::Value) -> http::Response<Body> {
+  http::Response::builder()
+    .status(status)
+    .header("content-type", "application/json")
+    .body(Body::from(value.to_string()))
+    .expect("Valid response parts")
+}
+
+// Error response, with the field violations of validation errors
+// and the conflicting customer of ALREADY_EXISTS errors
+fn error_response(status: &Status) -> http::Response<Body> {
+  let violations = error_details::field_violations(status)
+    .into_iter()
+    .map(|v| json!({"field": v.field, "description": v.description}))
+    .collect::<Vec<_>>();
+  let mut body = json!({
+    "code": format!("{:?}", status.code()),
+    "message": status.message(),
+    "field_violations": violations,
+  });
+  if let Some(id) = status
+    .metadata()
+    .get(CONFLICT_METADATA_KEY)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<u32>().ok())
+  {
+    body["conflicting_customer_id"] = json!(id);
+  }
+  json_response(http_status(status.code()), &body)
+}
+
+// Gateway error, translated like the service errors
+fn gateway_error(error: ServiceError, locale: Locale) -> http::Response<Body> {
+  let status = Status::from(error);
+  let message = translate(status.message(), locale);
+  error_response(&Status::new(status.code(), message))
+}
+
+// Request body over the size limit
+fn too_large(max_bytes: usize, locale: Locale) -> http::Response<Body> {
+  let mut response = gateway_error(payload::too_large(max_bytes), locale);
+  *response.status_mut() = http::StatusCode::PAYLOAD_TOO_LARGE;
+  response
+}
+
+// Query parameter values by name, + and percent decoded
+fn query_params(query: &str) -> Vec<(String, String)> {
+  let decode = |s: &str| {
+    percent_decode_str(&s.replace('+', " "))
+      .decode_utf8_lossy()
+      .into_owned()
+  };
+  query
+    .split('&')
+    .filter(|p| !p.is_empty())
+    .map(|p| match p.split_once('=') {
+      Some((name, value)) => (decode(name), decode(value)),
+      None => (decode(p), String::new()),
+    })
+    .collect()
+}
+
+fn find_request(query: &str) -> ServiceResult<FindCustomerRequest> {
+  let mut res = FindCustomerRequest::default();
+  for (name, value) in query_params(query) {
+    let number = || {
+      value
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| ServiceError::invalid_field(&name, "Hibás szám paraméter"))
+    };
+    match name.as_str() {
+      "query" => res.query = value.clone(),
+      "kind" => res.kind = number()? as i32,
+      "max_results" => res.max_results = number()?,
+      _ => {
+        return Err(ServiceError::bad_request(&format!(
+          "Ismeretlen paraméter: {}",
+          name
+        )))
+      }
+    }
+  }
+  Ok(res)
+}
+
+// Unary gRPC call of the service
+// Returns the response message, or the status of a failed call
+async fn call<S, Req, Resp>(
+  service: &mut S,
+  method: &str,
+  headers: &http::HeaderMap,
+  message: Req,
+) -> Result<Resp, Status>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>>,
+  S::Error: std::fmt::Display,
+  Req: Message,
+  Resp: Message + Default,
+{
+  let mut body = vec![0];
+  body.extend_from_slice(&(message.encoded_len() as u32).to_be_bytes());
+  // Vec grows as needed, so encoding cannot fail
+  message.encode(&mut body).unwrap();
+  let mut request = http::Request::post(format!("/customer.Customer/{}", method))
+    .header("content-type", "application/grpc")
+    .header("te", "trailers")
+    .body(Body::from(body))
+    .expect("Valid request parts");
+  for (name, value) in headers {
+    if !HOP_HEADERS.contains(&name.as_str()) {
+      request.headers_mut().append(name, value.clone());
+    }
+  }
+  std::future::poll_fn(|cx| service.poll_ready(cx))
+    .await
+    .map_err(|e| Status::internal(format!("Service is not ready: {}", e)))?;
+  let response = service
+    .call(request)
+    .await
+    .map_err(|e| Status::internal(format!("Error while calling the service: {}", e)))?;
+  // Errors without a response message are sent in the headers
+  if let Some(status) = Status::from_header_map(response.headers()) {
+    if status.code() != Code::Ok {
+      return Err(status);
+    }
+  }
+  let mut body = response.into_body();
+  let mut data = Vec::new();
+  while let Some(chunk) = body.data().await {
+    data.extend_from_slice(&chunk?);
+  }
+  if let Some(trailers) = body.trailers().await? {
+    if let Some(status) = Status::from_header_map(&trailers) {
+      if status.code() != Code::Ok {
+        return Err(status);
+      }
+    }
+  }
+  if data.len() < HEADER_LEN || data[0] != 0 {
+    return Err(Status::internal("Unexpected gRPC response"));
+  }
+  Resp::decode(&data[HEADER_LEN..])
+    .map_err(|e| Status::internal(format!("Error while decoding response: {}", e)))
+}
+
+fn to_json<M: Serialize>(res: Result<M, Status>) -> http::Response<Body> {
+  match res {
+    Ok(message) => match serde_json::to_value(&message) {
+      Ok(value) => json_response(http::StatusCode::OK, &value),
+      Err(e) => error_response(&Status::internal(format!("JSON error: {}", e))),
+    },
+    Err(status) => error_response(&status),
+  }
+}
+
+// Handle a gateway request
+pub async fn handle<S>(
+  mut service: S,
+  request: http::Request<Body>,
+  max_bytes: usize,
+) -> http::Response<Body>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>>,
+  S::Error: std::fmt::Display,
+{
+  let locale = Locale::from_accept_language(
+    request
+      .headers()
+      .get(ACCEPT_LANGUAGE_METADATA_KEY)
+      .and_then(|v| v.to_str().ok()),
+  );
+  let (parts, body) = request.into_parts();
+  let path = parts
+    .uri
+    .path()
+    .trim_end_matches('/')
+    .split('/')
+    .skip(1)
+    .collect::<Vec<&str>>();
+  let headers = &parts.headers;
+  match (&parts.method, path.as_slice()) {
+    (&http::Method::GET, ["customers", id]) => match id.parse::<u32>() {
+      Ok(customer_id) => to_json::<CustomerObj>(
+        call(
+          &mut service,
+          "GetById",
+          headers,
+          GetByIdRequest {
+            customer_id,
+            expanded: false,
+          },
+        )
+        .await,
+      ),
+      Err(_) => gateway_error(ServiceError::bad_request("Hibás ügyfél azonosító"), locale),
+    },
+    (&http::Method::GET, ["customers"]) => match find_request(parts.uri.query().unwrap_or("")) {
+      Ok(request) => {
+        to_json::<CustomerIds>(call(&mut service, "FindCustomer", headers, request).await)
+      }
+      Err(error) => gateway_error(error, locale),
+    },
+    (&http::Method::POST, ["customers"]) => {
+      if payload::declared_too_large(headers, max_bytes) {
+        return too_large(max_bytes, locale);
+      }
+      let body = match payload::read_body(body, max_bytes).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return too_large(max_bytes, locale),
+        Err(e) => {
+          return error_response(&Status::internal(format!(
+            "Error while reading request: {}",
+            e
+          )))
+        }
+      };
+      match serde_json::from_slice::<NewCustomerObj>(&body) {
+        Ok(request) => {
+          to_json::<CustomerObj>(call(&mut service, "CreateNew", headers, request).await)
+        }
+        Err(e) => gateway_error(
+          ServiceError::bad_request(&format!("Hibás JSON kérés: {}", e)),
+          locale,
+        ),
+      }
+    }
+    (_, ["customers", ..]) => gateway_error(
+      ServiceError::bad_request("Nem támogatott HTTP metódus"),
+      locale,
+    ),
+    _ => gateway_error(
+      ServiceError::not_found("Nem található ilyen végpont"),
+      locale,
+    ),
+  }
+}
+
+// Serve the gateway until shutdown
+pub async fn serve<S, F>(
+  addr: SocketAddr,
+  service: S,
+  max_bytes: usize,
+  shutdown: F,
+) -> Result<(), hyper::Error>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: std::fmt::Display,
+  F: std::future::Future<Output = ()>,
+{
+  let make_service = hyper::service::make_service_fn(move |_| {
+    let service = service.clone();
+    async move {
+      Ok::<_, Infallible>(hyper::service::service_fn(move |request| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(handle(service, request, max_bytes).await) }
+      }))
+    }
+  });
+  hyper::Server::try_bind(&addr)?
+    .serve(make_service)
+    .with_graceful_shutdown(shutdown)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_find_request() {
+    let request = find_request("query=Kiss+B%C3%A9la&kind=2&max_results=10").unwrap();
+    assert_eq!(request.query, "Kiss Béla");
+    assert_eq!(request.kind, CustomerKind::KindCompany as i32);
+    assert_eq!(request.max_results, 10);
+    assert_eq!(find_request("").unwrap(), FindCustomerRequest::default());
+    assert!(find_request("max_results=sok").is_err());
+    assert!(find_request("name=Kiss").is_err());
+  }
+
+  #[test]
+  fn test_error_response() {
+    assert_eq!(http_status(Code::NotFound), http::StatusCode::NOT_FOUND);
+    assert_eq!(
+      http_status(Code::ResourceExhausted),
+      http::StatusCode::TOO_MANY_REQUESTS
+    );
+    let status = error_details::bad_request_status(
+      "Nem megfelelő email cím",
+      &[FieldViolation {
+        field: "email".to_string(),
+        description: "Nem megfelelő email cím".to_string(),
+      }],
+    );
+    let response = error_response(&status);
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+    assert_eq!(
+      response.headers().get("content-type").unwrap(),
+      "application/json"
+    );
+    let response = gateway_error(
+      ServiceError::bad_request("Hibás ügyfél azonosító"),
+      Locale::En,
+    );
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+  }
+}
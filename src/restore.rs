@@ -0,0 +1,281 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Backup restore
+//
+// A backup is a YAML export of a tenant (see the export CLI command).
+// It is merged into the current storage: customers missing from the
+// storage are restored, unchanged ones are skipped. Customers changed
+// since the backup are conflicts, the resolver decides which version
+// is kept, e.g. the newer one.
+
+use crate::audit::*;
+use crate::customer::Customer;
+use crate::db::CustomerDb;
+use crate::prelude::*;
+use chrono::prelude::*;
+use serde_json::Value;
+
+// Customer changed in the backup or in the storage
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestoreConflict {
+  pub customer_id: u32,
+  pub backup_modified: DateTime<Utc>,
+  pub current_modified: DateTime<Utc>,
+  // Fields with different values
+  pub fields: Vec<String>,
+  // Backup version was taken
+  pub took_backup: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RestoreReport {
+  pub restored_ids: Vec<u32>,
+  pub replaced_ids: Vec<u32>,
+  pub conflicts: Vec<RestoreConflict>,
+  // Customers that could not be restored, with the reason
+  pub skipped: Vec<(u32, String)>,
+}
+
+// Parse backup file
+pub fn parse_backup(content: &str) -> ServiceResult<Vec<Customer>> {
+  serde_yaml::from_str(content)
+    .map_err(|e| ServiceError::bad_request(&format!("Hibás mentés fájl: {}", e)).on_field("backup"))
+}
+
+// Newer version wins, the current one on equal dates
+pub fn newer_wins(conflict: &RestoreConflict) -> bool {
+  conflict.backup_modified > conflict.current_modified
+}
+
+// Fields of the current customer different in the backup
+pub fn changed_fields(current: &Customer, backup: &Customer) -> ServiceResult<Vec<String>> {
+  let to_map = |c: &Customer| match serde_json::to_value(c) {
+    Ok(Value::Object(map)) => Ok(map),
+    _ => Err(ServiceError::internal_error(
+      "Error while comparing customers",
+    )),
+  };
+  let (current, backup) = (to_map(current)?, to_map(backup)?);
+  Ok(
+    current
+      .iter()
+      // Fields set by the customer db are not compared
+      .filter(|(field, _)| !["last_modified", "display_name", "sort_key"].contains(&field.as_str()))
+      .filter(|(field, value)| backup.get(*field) != Some(value))
+      .map(|(field, _)| field.clone())
+      .collect(),
+  )
+}
+
+// Merge backup into the customer db
+// Conflicts are decided by take_backup, with dry_run nothing is changed.
+// The backup is restored as a whole, or not at all if storing fails,
+// customers conflicting with others are skipped.
+// The restored and replaced customers are returned with the report,
+// and are logged to the audit trail as actor.
+pub fn restore(
+  customers: &mut CustomerDb,
+  backup: Vec<Customer>,
+  mut take_backup: impl FnMut(&RestoreConflict) -> bool,
+  dry_run: bool,
+  audit: Option<(&AuditLog, &str, &str)>,
+) -> ServiceResult<(RestoreReport, Vec<Customer>, Vec<Customer>)> {
+  let mut report = RestoreReport::default();
+  let (mut restored, mut replaced) = (Vec::new(), Vec::new());
+  let log = |customer_id: u32| match audit {
+    Some((audit, tenant, actor)) => audit.append(
+      tenant,
+      &AuditEntry {
+        date: Utc::now(),
+        customer_id,
+        action: AuditAction::Restore,
+        actor: actor.to_string(),
+      },
+    ),
+    None => Ok(()),
+  };
+  // Changes are staged, and stored together at the end
+  let mut tx = customers.transaction();
+  for customer in backup {
+    let id = customer.id;
+    let current = match tx.find_id(&id) {
+      Ok(current) => current.clone(),
+      Err(_) => {
+        if let Err(error) = tx.insert(customer) {
+          report.skipped.push((id, error.to_string()));
+          continue;
+        }
+        restored.push(tx.find_id(&id)?.clone());
+        report.restored_ids.push(id);
+        continue;
+      }
+    };
+    let fields = changed_fields(&current, &customer)?;
+    if fields.is_empty() {
+      continue;
+    }
+    let mut conflict = RestoreConflict {
+      customer_id: id,
+      backup_modified: customer.last_modified,
+      current_modified: current.last_modified,
+      fields,
+      took_backup: false,
+    };
+    conflict.took_backup = take_backup(&conflict);
+    if conflict.took_backup {
+      let res = tx.update(&id, 0, |c| {
+        *c = customer;
+        Ok(())
+      });
+      if let Err(error) = res {
+        report.skipped.push((id, error.to_string()));
+        continue;
+      }
+      replaced.push(tx.find_id(&id)?.clone());
+      report.replaced_ids.push(id);
+    }
+    report.conflicts.push(conflict);
+  }
+  if dry_run {
+    return Ok((report, Vec::new(), Vec::new()));
+  }
+  // Nothing is restored if the storage write fails
+  tx.commit()?;
+  for customer in restored.iter().chain(&replaced) {
+    log(customer.id)?;
+  }
+  Ok((report, restored, replaced))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::MemoryStore;
+
+  fn date(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  fn customer(id: u32, name: &str, modified: &str) -> Customer {
+    Customer {
+      id,
+      name: name.to_string(),
+      date_created: date("2020-01-01T00:00:00Z"),
+      last_modified: date(modified),
+      ..Customer::default()
+    }
+  }
+
+  fn db() -> CustomerDb {
+    let mut db = CustomerDb::new(MemoryStore::new());
+    db.insert(customer(1, "Kiss Béla", "2021-01-01T00:00:00Z"))
+      .unwrap();
+    db.insert(customer(2, "Nagy Anna", "2021-03-01T00:00:00Z"))
+      .unwrap();
+    db.insert(customer(3, "Tóth Ede", "2021-01-01T00:00:00Z"))
+      .unwrap();
+    db
+  }
+
+  fn backup() -> Vec<Customer> {
+    vec![
+      // Older in the storage
+      customer(1, "Kiss Béla Péter", "2021-02-01T00:00:00Z"),
+      // Newer in the storage
+      customer(2, "Nagy Anikó", "2021-02-01T00:00:00Z"),
+      // Unchanged
+      customer(3, "Tóth Ede", "2021-01-01T00:00:00Z"),
+      // Lost
+      customer(4, "Szabó Éva", "2021-01-01T00:00:00Z"),
+    ]
+  }
+
+  #[test]
+  fn test_restore_newer_wins() {
+    let mut db = db();
+    let (report, restored, replaced) = restore(&mut db, backup(), newer_wins, false, None).unwrap();
+    assert_eq!(report.restored_ids, [4]);
+    assert_eq!(report.replaced_ids, [1]);
+    assert_eq!(
+      report
+        .conflicts
+        .iter()
+        .map(|c| (c.customer_id, c.took_backup))
+        .collect::<Vec<(u32, bool)>>(),
+      [(1, true), (2, false)]
+    );
+    assert_eq!(report.conflicts[0].fields, ["name"]);
+    assert_eq!(restored.len(), 1);
+    assert_eq!(replaced[0].name, "Kiss Béla Péter");
+    assert_eq!(db.find_id(&1).unwrap().name, "Kiss Béla Péter");
+    assert_eq!(db.find_id(&2).unwrap().name, "Nagy Anna");
+    assert_eq!(db.find_id(&4).unwrap().name, "Szabó Éva");
+  }
+
+  #[test]
+  fn test_restore_dry_run() {
+    let mut db = db();
+    let dir = tempfile::tempdir().unwrap();
+    let audit = AuditLog::new(dir.path());
+    let (report, _, _) = restore(
+      &mut db,
+      backup(),
+      |c| c.customer_id == 2,
+      true,
+      Some((&audit, "", "restore")),
+    )
+    .unwrap();
+    assert_eq!(report.restored_ids, [4]);
+    assert_eq!(report.replaced_ids, [2]);
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.find_id(&2).unwrap().name, "Nagy Anna");
+    assert!(audit.read("").unwrap().is_empty());
+    // Applied with the same choices
+    restore(
+      &mut db,
+      backup(),
+      |c| c.customer_id == 2,
+      false,
+      Some((&audit, "", "restore")),
+    )
+    .unwrap();
+    assert_eq!(db.find_id(&2).unwrap().name, "Nagy Anikó");
+    assert_eq!(audit.read("").unwrap().len(), 2);
+  }
+
+  #[test]
+  fn test_restore_skipped() {
+    let mut db = db();
+    db.insert(Customer {
+      id: 5,
+      loyalty_card_id: Some("A1".to_string()),
+      ..Customer::default()
+    })
+    .unwrap();
+    let backup = vec![Customer {
+      id: 6,
+      loyalty_card_id: Some("A1".to_string()),
+      ..Customer::default()
+    }];
+    let (report, restored, _) = restore(&mut db, backup, newer_wins, false, None).unwrap();
+    assert!(report.restored_ids.is_empty());
+    assert!(restored.is_empty());
+    assert_eq!(report.skipped[0].0, 6);
+    assert!(parse_backup("- id: x").is_err());
+  }
+}
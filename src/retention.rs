@@ -0,0 +1,586 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Data retention
+//
+// Retention rules are loaded from a YAML file at startup,
+// and applied to every tenant periodically. The first matching
+// rule of a customer is applied, anonymized customers and customers
+// without recorded activity are skipped. Approved erasure requests
+// are executed by the same run. Every action is logged to the audit
+// trail, stored documents of anonymized customers are deleted.
+
+use crate::audit::*;
+use crate::customer::Customer;
+use crate::db::CustomerDb;
+use crate::documents::DocumentStore;
+use crate::erasure::ErasureRequests;
+use crate::prelude::*;
+use crate::tenant::*;
+use chrono::prelude::*;
+use chrono::Months;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionActionKind {
+  Anonymize,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionRule {
+  pub name: String,
+  pub action: RetentionActionKind,
+  // Years without activity
+  pub inactive_years: u32,
+  // Only customers who were never invoiced
+  #[serde(default)]
+  pub without_invoices: bool,
+}
+
+impl RetentionRule {
+  fn matches(&self, customer: &Customer, now: DateTime<Utc>) -> bool {
+    let cutoff = now
+      .checked_sub_months(Months::new(self.inactive_years.saturating_mul(12)))
+      .unwrap_or(DateTime::<Utc>::MIN_UTC);
+    // Customers created before activities were recorded would
+    // look inactive since their creation
+    customer.anonymized_at.is_none()
+      && customer.last_activity.is_some()
+      && customer.is_inactive_since(cutoff)
+      && (!self.without_invoices || customer.last_invoice_at.is_none())
+  }
+}
+
+// Retention policy
+// Without rules only erasure requests are executed
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+  pub interval_hours: u64,
+  // Years invoiced customers are kept after their last invoice,
+  // even if they asked for erasure
+  pub erasure_hold_years: u32,
+  pub rules: Vec<RetentionRule>,
+}
+
+impl Default for RetentionPolicy {
+  fn default() -> Self {
+    Self {
+      interval_hours: 24,
+      // Invoices must be kept for 8 years by the accounting law
+      erasure_hold_years: 8,
+      rules: Vec::new(),
+    }
+  }
+}
+
+impl RetentionPolicy {
+  // Load policy from YAML file
+  // If the file does not exist, returns the default policy
+  pub fn load_or_default(path: &Path) -> ServiceResult<Self> {
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+    let content = std::fs::read_to_string(path)?;
+    Self::from_yaml(&content)
+  }
+  pub fn from_yaml(content: &str) -> ServiceResult<Self> {
+    let policy: Self = serde_yaml::from_str(content).map_err(|e| {
+      ServiceError::internal_error(&format!("Error while parsing retention policy: {}", e))
+    })?;
+    if policy.interval_hours == 0 {
+      return Err(ServiceError::internal_error(
+        "Retention policy error: interval_hours must be positive",
+      ));
+    }
+    let mut names = HashSet::new();
+    for rule in &policy.rules {
+      if rule.name.trim().is_empty() || !names.insert(rule.name.as_str()) {
+        return Err(ServiceError::internal_error(&format!(
+          "Retention policy error: rule names must be unique and not empty: '{}'",
+          rule.name
+        )));
+      }
+      if rule.inactive_years == 0 {
+        return Err(ServiceError::internal_error(&format!(
+          "Retention policy error: inactive_years of rule '{}' must be positive",
+          rule.name
+        )));
+      }
+    }
+    Ok(policy)
+  }
+  // Actions a retention run would do, in customer ID order
+  pub fn plan<'a>(
+    &self,
+    customers: impl Iterator<Item = &'a Customer>,
+    now: DateTime<Utc>,
+  ) -> Vec<RetentionAction> {
+    let mut res = customers
+      .filter_map(|c| {
+        self
+          .rules
+          .iter()
+          .find(|rule| rule.matches(c, now))
+          .map(|rule| RetentionAction {
+            customer_id: c.id,
+            rule: rule.name.clone(),
+            action: rule.action,
+            last_seen: c.last_seen(),
+          })
+      })
+      .collect::<Vec<RetentionAction>>();
+    res.sort_by_key(|a| a.customer_id);
+    res
+  }
+  // Earliest date the customer can be erased at
+  pub fn erasure_due(&self, customer: &Customer, now: DateTime<Utc>) -> DateTime<Utc> {
+    match customer.last_invoice_at {
+      Some(at) => at
+        .checked_add_months(Months::new(self.erasure_hold_years.saturating_mul(12)))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+        .max(now),
+      None => now,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetentionAction {
+  pub customer_id: u32,
+  pub rule: String,
+  pub action: RetentionActionKind,
+  pub last_seen: DateTime<Utc>,
+}
+
+// Anonymize customer, deleting its stored documents first
+// The audit entry is written before the change is committed, so no
+// anonymization is stored without it. If the commit fails, the next
+// run anonymizes the customer again, with a new entry.
+#[allow(clippy::too_many_arguments)]
+async fn anonymize(
+  tenant: &str,
+  customers: &mut CustomerDb,
+  documents: &DocumentStore,
+  audit: &AuditLog,
+  customer_id: u32,
+  comment: &str,
+  actor: String,
+  now: DateTime<Utc>,
+) -> ServiceResult<()> {
+  documents
+    .delete(tenant, customers.find_id(&customer_id)?)
+    .await?;
+  customers.atomic(|tx| {
+    tx.update(&customer_id, 0, |c| {
+      c.anonymize(comment, now);
+      Ok(())
+    })?;
+    audit.append(
+      tenant,
+      &AuditEntry {
+        date: now,
+        customer_id,
+        action: AuditAction::Anonymize,
+        actor,
+      },
+    )
+  })
+}
+
+// Apply retention rules to the customers of a tenant
+// Customers whose documents cannot be deleted are left for the next run
+// Returns the number of changed customers
+pub async fn apply(
+  policy: &RetentionPolicy,
+  tenant: &str,
+  customers: &mut CustomerDb,
+  documents: &DocumentStore,
+  audit: &AuditLog,
+  now: DateTime<Utc>,
+) -> ServiceResult<usize> {
+  let mut count = 0;
+  for action in policy.plan(customers.iter(), now) {
+    let res = match action.action {
+      RetentionActionKind::Anonymize => {
+        let comment = format!("Adatmegőrzési szabály: {}", action.rule);
+        let actor = format!("retention:{}", action.rule);
+        anonymize(
+          tenant,
+          customers,
+          documents,
+          audit,
+          action.customer_id,
+          &comment,
+          actor,
+          now,
+        )
+        .await
+      }
+    };
+    match res {
+      Ok(()) => count += 1,
+      Err(error) => eprintln!(
+        "Error while applying retention rule '{}' to customer {}: {}",
+        action.rule, action.customer_id, error
+      ),
+    }
+  }
+  Ok(count)
+}
+
+// Execute the due erasure requests of a tenant
+// Customers invoiced since the approval are rescheduled, customers
+// whose documents cannot be deleted are left for the next run
+// Returns the number of changed customers
+#[allow(clippy::too_many_arguments)]
+pub async fn apply_erasures(
+  policy: &RetentionPolicy,
+  tenant: &str,
+  customers: &mut CustomerDb,
+  erasures: &mut ErasureRequests,
+  documents: &DocumentStore,
+  audit: &AuditLog,
+  now: DateTime<Utc>,
+) -> ServiceResult<usize> {
+  let mut count = 0;
+  for request in erasures.due(tenant, now)? {
+    let customer = customers.find_id(&request.customer_id)?;
+    if customer.anonymized_at.is_none() {
+      let due = policy.erasure_due(customer, now);
+      if due > now {
+        erasures.reschedule(tenant, request.id, due)?;
+        continue;
+      }
+      let comment = format!("Törlési kérelem: {}", request.id);
+      let actor = format!("erasure:{}", request.id);
+      if let Err(error) = anonymize(
+        tenant,
+        customers,
+        documents,
+        audit,
+        request.customer_id,
+        &comment,
+        actor,
+        now,
+      )
+      .await
+      {
+        eprintln!(
+          "Error while executing erasure request {}: {}",
+          request.id, error
+        );
+        continue;
+      }
+      count += 1;
+    }
+    erasures.done(tenant, request.id, now)?;
+  }
+  Ok(count)
+}
+
+// Apply retention rules and erasure requests to every tenant
+// periodically. The first run is one interval after the startup,
+// so the actions can be checked with PreviewRetentionRun first
+pub async fn run(
+  policy: Arc<RetentionPolicy>,
+  tenants: Arc<Tenants>,
+  erasures: Arc<Mutex<ErasureRequests>>,
+  documents: Arc<DocumentStore>,
+  audit: AuditLog,
+) {
+  let period = Duration::from_secs(policy.interval_hours * 60 * 60);
+  let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+  loop {
+    interval.tick().await;
+    let tenant_ids = match tenants.ids().await {
+      Ok(tenant_ids) => tenant_ids,
+      Err(error) => {
+        eprintln!("Error while listing tenants for retention: {}", error);
+        continue;
+      }
+    };
+    for tenant in tenant_ids {
+      let res = match tenants.get(&tenant).await {
+        Ok(customers) => {
+          let mut customers = customers.lock().await;
+          let now = Utc::now();
+          match apply(&policy, &tenant, &mut customers, &documents, &audit, now).await {
+            Ok(count) => {
+              let mut erasures = erasures.lock().await;
+              apply_erasures(
+                &policy,
+                &tenant,
+                &mut customers,
+                &mut erasures,
+                &documents,
+                &audit,
+                now,
+              )
+              .await
+              .map(|erased| count + erased)
+            }
+            Err(error) => Err(error),
+          }
+        }
+        Err(error) => Err(error),
+      };
+      match res {
+        Ok(0) => (),
+        Ok(count) => println!("Retention run of tenant '{}': {} customers", tenant, count),
+        Err(error) => eprintln!("Error in retention run of tenant '{}': {}", tenant, error),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::{ActivityKind, DocumentKind, ANONYMIZED_NAME};
+  use packman::*;
+
+  const POLICY: &str = "
+rules:
+  - name: legal
+    action: anonymize
+    inactive_years: 8
+    without_invoices: true
+  - name: invoiced
+    action: anonymize
+    inactive_years: 10
+";
+
+  fn date(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  fn customers() -> Vec<Customer> {
+    let customer = |id: u32, created: &str, invoiced: Option<&str>| {
+      let mut c = Customer {
+        id,
+        name: "Kiss Béla".to_string(),
+        email: format!("kiss{}@example.com", id),
+        date_created: date(created),
+        ..Customer::default()
+      };
+      c.record_activity(ActivityKind::Purchase, date(created));
+      if let Some(at) = invoiced {
+        c.record_activity(ActivityKind::Invoice, date(at));
+      }
+      c
+    };
+    vec![
+      customer(1, "2012-01-01T00:00:00Z", None),
+      customer(2, "2016-01-01T00:00:00Z", None),
+      customer(3, "2010-01-01T00:00:00Z", Some("2012-01-01T00:00:00Z")),
+      customer(4, "2010-01-01T00:00:00Z", Some("2018-01-01T00:00:00Z")),
+      // Created before activities were recorded
+      Customer {
+        id: 5,
+        name: "Nagy Béla".to_string(),
+        date_created: date("2010-01-01T00:00:00Z"),
+        ..Customer::default()
+      },
+    ]
+  }
+
+  #[test]
+  fn test_from_yaml() {
+    let policy = RetentionPolicy::from_yaml(POLICY).unwrap();
+    assert_eq!(policy.interval_hours, 24);
+    assert_eq!(policy.erasure_hold_years, 8);
+    assert_eq!(policy.rules.len(), 2);
+    assert!(policy.rules[0].without_invoices);
+    assert!(!policy.rules[1].without_invoices);
+    assert!(RetentionPolicy::from_yaml("interval_hours: 0\n").is_err());
+    assert!(RetentionPolicy::from_yaml(
+      "rules: [{name: a, action: anonymize, inactive_years: 0}]\n"
+    )
+    .is_err());
+    assert!(
+      RetentionPolicy::from_yaml("rules: [{name: a, action: delete, inactive_years: 1}]\n")
+        .is_err()
+    );
+  }
+
+  #[test]
+  fn test_plan() {
+    let policy = RetentionPolicy::from_yaml(POLICY).unwrap();
+    let customers = customers();
+    let res = policy.plan(customers.iter(), date("2023-06-01T00:00:00Z"));
+    assert_eq!(
+      res
+        .iter()
+        .map(|a| (a.customer_id, a.rule.as_str()))
+        .collect::<Vec<(u32, &str)>>(),
+      [(1, "legal"), (3, "invoiced")]
+    );
+    assert_eq!(res[1].last_seen, date("2012-01-01T00:00:00Z"));
+    // Anonymized customers are skipped
+    let customers = customers
+      .into_iter()
+      .map(|mut c| {
+        c.anonymize("", Utc::now());
+        c
+      })
+      .collect::<Vec<Customer>>();
+    assert!(policy
+      .plan(customers.iter(), date("2023-06-01T00:00:00Z"))
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_apply() {
+    let dir = tempfile::tempdir().unwrap();
+    let policy = RetentionPolicy::from_yaml(POLICY).unwrap();
+    let mut db = CustomerDb::new(VecPack::load_or_init(dir.path().join("customers")).unwrap());
+    for c in customers() {
+      db.insert(c).unwrap();
+    }
+    let audit = AuditLog::new(dir.path());
+    let documents = DocumentStore::new(None).unwrap();
+    let now = date("2023-06-01T00:00:00Z");
+    assert_eq!(
+      apply(&policy, "", &mut db, &documents, &audit, now)
+        .await
+        .unwrap(),
+      2
+    );
+    let customer = db.find_id(&1).unwrap().clone();
+    assert_eq!(customer.name, ANONYMIZED_NAME);
+    assert!(customer.email.is_empty());
+    assert_eq!(customer.anonymized_at, Some(now));
+    assert_eq!(db.find_id(&2).unwrap().name, "Kiss Béla");
+    let entries = audit.read("").unwrap();
+    assert_eq!(
+      entries
+        .iter()
+        .map(|e| (e.customer_id, e.actor.as_str()))
+        .collect::<Vec<(u32, &str)>>(),
+      [(1, "retention:legal"), (3, "retention:invoiced")]
+    );
+    // Next run has nothing to do
+    assert_eq!(
+      apply(&policy, "", &mut db, &documents, &audit, now)
+        .await
+        .unwrap(),
+      0
+    );
+    // Documents cannot be deleted without document store,
+    // so their customer is not anonymized
+    let mut customer = customers().remove(0);
+    customer.id = 6;
+    customer.email = "kiss6@example.com".to_string();
+    customer
+      .attach_document(DocumentKind::Contract, "contracts/6.pdf".to_string(), 1)
+      .unwrap();
+    db.insert(customer).unwrap();
+    assert_eq!(
+      apply(&policy, "", &mut db, &documents, &audit, now)
+        .await
+        .unwrap(),
+      0
+    );
+    assert_eq!(db.find_id(&6).unwrap().anonymized_at, None);
+    assert_eq!(db.find_id(&6).unwrap().attachments.len(), 1);
+    assert_eq!(audit.read("").unwrap().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_apply_erasures() {
+    let dir = tempfile::tempdir().unwrap();
+    let policy = RetentionPolicy::default();
+    let mut db = CustomerDb::new(VecPack::load_or_init(dir.path().join("customers")).unwrap());
+    for c in customers() {
+      db.insert(c).unwrap();
+    }
+    let audit = AuditLog::new(dir.path());
+    let documents = DocumentStore::new(None).unwrap();
+    let mut erasures = ErasureRequests::new(dir.path());
+    let now = date("2023-06-01T00:00:00Z");
+    // Never invoiced customers can be erased right away
+    assert_eq!(policy.erasure_due(db.find_id(&2).unwrap(), now), now);
+    // Invoices are kept for 8 years
+    let due = policy.erasure_due(db.find_id(&4).unwrap(), now);
+    assert_eq!(due, date("2026-01-01T00:00:00Z"));
+    for id in [2, 4] {
+      let execute_after = policy.erasure_due(db.find_id(&id).unwrap(), now);
+      let request = erasures
+        .request("", id, 5, "Kérte", execute_after, now)
+        .unwrap();
+      erasures.decide("", request.id, true, 9, now).unwrap();
+    }
+    assert_eq!(
+      apply_erasures(&policy, "", &mut db, &mut erasures, &documents, &audit, now)
+        .await
+        .unwrap(),
+      1
+    );
+    // Invoiced after the approval, the request waits longer
+    db.update(&4, 0, |c| {
+      c.record_activity(ActivityKind::Invoice, date("2025-01-01T00:00:00Z"));
+      Ok(c.clone())
+    })
+    .unwrap();
+    assert_eq!(
+      apply_erasures(&policy, "", &mut db, &mut erasures, &documents, &audit, due)
+        .await
+        .unwrap(),
+      0
+    );
+    assert_eq!(
+      erasures.list("", false).unwrap()[0].execute_after,
+      date("2033-01-01T00:00:00Z")
+    );
+    let later = date("2033-01-01T00:00:00Z");
+    assert_eq!(
+      apply_erasures(
+        &policy,
+        "",
+        &mut db,
+        &mut erasures,
+        &documents,
+        &audit,
+        later
+      )
+      .await
+      .unwrap(),
+      1
+    );
+    assert_eq!(db.find_id(&2).unwrap().name, ANONYMIZED_NAME);
+    assert_eq!(db.find_id(&4).unwrap().anonymized_at, Some(later));
+    assert_eq!(db.find_id(&1).unwrap().name, "Kiss Béla");
+    assert!(erasures.list("", false).unwrap().is_empty());
+    assert_eq!(
+      audit
+        .read("")
+        .unwrap()
+        .iter()
+        .map(|e| (e.customer_id, e.actor.as_str()))
+        .collect::<Vec<(u32, &str)>>(),
+      [(2, "erasure:1"), (4, "erasure:2")]
+    );
+  }
+}
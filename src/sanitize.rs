@@ -0,0 +1,146 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Input sanitization
+//
+// Pasted data often brings invisible characters, which break search
+// and duplicate checks. Customer text inputs are cleaned before they
+// are validated: control characters are removed (tabs and line breaks
+// become spaces), and the value is trimmed. Names have their internal
+// whitespace collapsed too. Zero-width and text direction override
+// characters are rejected instead of removed, as they can hide what
+// the value really says. Values over MAX_FIELD_BYTES are rejected
+// before they are cleaned, whatever the character limit of the field.
+
+use crate::prelude::*;
+
+// Max UTF-8 length of a text input
+pub const MAX_FIELD_BYTES: usize = 2048;
+
+// Zero-width and text direction control characters
+fn is_invisible(c: char) -> bool {
+  matches!(
+    c,
+    '\u{200B}'..='\u{200F}'
+      | '\u{202A}'..='\u{202E}'
+      | '\u{2060}'
+      | '\u{2066}'..='\u{2069}'
+      | '\u{061C}'
+      | '\u{FEFF}'
+  )
+}
+
+// Single line text, trimmed, without control characters
+pub fn line(value: &str) -> ServiceResult<String> {
+  if value.len() > MAX_FIELD_BYTES {
+    return Err(ServiceError::bad_request(&format!(
+      "Az érték max {} bájt lehet",
+      MAX_FIELD_BYTES
+    )));
+  }
+  if value.chars().any(is_invisible) {
+    return Err(ServiceError::bad_request(
+      "Láthatatlan vagy írásirány-váltó karakter nem adható meg",
+    ));
+  }
+  let res = value
+    .chars()
+    .filter_map(|c| match c {
+      '\t' | '\n' | '\r' => Some(' '),
+      c if c.is_control() => None,
+      c => Some(c),
+    })
+    .collect::<String>();
+  Ok(res.trim().to_string())
+}
+
+// Name, as a line with single spaces between its words
+pub fn name(value: &str) -> ServiceResult<String> {
+  Ok(
+    line(value)?
+      .split_whitespace()
+      .collect::<Vec<&str>>()
+      .join(" "),
+  )
+}
+
+// Sanitizes the inputs of a create or update,
+// collecting the rejected fields as violations
+#[derive(Default)]
+pub struct Sanitizer {
+  violations: Violations,
+}
+
+impl Sanitizer {
+  // Rejected values are returned as they are
+  fn clean(&mut self, field: &str, value: String, f: fn(&str) -> ServiceResult<String>) -> String {
+    match f(&value) {
+      Ok(res) => res,
+      Err(error) => {
+        self.violations.add(field, &error.to_string());
+        value
+      }
+    }
+  }
+  pub fn line(&mut self, field: &str, value: String) -> String {
+    self.clean(field, value, line)
+  }
+  pub fn name(&mut self, field: &str, value: String) -> String {
+    self.clean(field, value, name)
+  }
+  pub fn opt_line(&mut self, field: &str, value: Option<String>) -> Option<String> {
+    value.map(|value| self.line(field, value))
+  }
+  pub fn finish(self) -> ServiceResult<()> {
+    self.violations.into_result()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sanitize() {
+    assert_eq!(line("  1111\t").unwrap(), "1111");
+    assert_eq!(line("Fő utca\n12.\u{7}").unwrap(), "Fő utca 12.");
+    // Internal whitespace of lines is kept
+    assert_eq!(line(" a  b ").unwrap(), "a  b");
+    assert_eq!(name(" Kiss \u{a0} Kert\t Kft ").unwrap(), "Kiss Kert Kft");
+    assert_eq!(name("Ürmös Éva").unwrap(), "Ürmös Éva");
+    assert!(line("kiss\u{200B}@kert.hu").is_err());
+    assert!(name("Kiss \u{202E}tfK").is_err());
+    assert!(name("\u{FEFF}Kiss").is_err());
+    // Byte limit, not character limit
+    assert!(line(&"ő".repeat(MAX_FIELD_BYTES / 2)).is_ok());
+    assert!(line(&"ő".repeat(MAX_FIELD_BYTES / 2 + 1)).is_err());
+    let mut sanitizer = Sanitizer::default();
+    assert_eq!(
+      sanitizer.name("name", " Kiss  Béla ".to_string()),
+      "Kiss Béla"
+    );
+    assert_eq!(sanitizer.opt_line("loyalty_card_id", None), None);
+    assert!(sanitizer.finish().is_ok());
+    let mut sanitizer = Sanitizer::default();
+    sanitizer.line("email", "a\u{200D}b@c.hu".to_string());
+    sanitizer.name("name", "\u{2066}x".to_string());
+    match sanitizer.finish() {
+      Err(ServiceError::InvalidFields(violations)) => assert_eq!(violations.len(), 2),
+      _ => panic!("Expected invalid fields"),
+    }
+  }
+}
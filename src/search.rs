@@ -0,0 +1,451 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeSet;
+
+// Candidates below this confidence are not returned
+const MIN_CONFIDENCE: f32 = 0.5;
+
+// Normalize name for matching
+// Lowercase, without accents and punctuation, single spaces
+pub fn normalize(name: &str) -> String {
+  name
+    .to_lowercase()
+    .chars()
+    .map(|c| match strip_accent(c) {
+      c if c.is_alphanumeric() => c,
+      _ => ' ',
+    })
+    .collect::<String>()
+    .split_whitespace()
+    .collect::<Vec<&str>>()
+    .join(" ")
+}
+
+// Fold text for case and accent insensitive lookups
+// Lowercase, without accents, single spaces. Unlike normalize
+// it keeps punctuation, so it fits emails and addresses too.
+pub fn fold(text: &str) -> String {
+  text
+    .to_lowercase()
+    .chars()
+    .map(strip_accent)
+    .collect::<String>()
+    .split_whitespace()
+    .collect::<Vec<&str>>()
+    .join(" ")
+}
+
+// Distinct 3 character pieces of a folded text
+// Texts containing a query contain all of its trigrams
+pub fn trigrams(folded: &str) -> BTreeSet<String> {
+  let chars = folded.chars().collect::<Vec<char>>();
+  chars
+    .windows(3)
+    .map(|w| w.iter().collect::<String>())
+    .collect()
+}
+
+// Lowercase letter without its accent
+fn strip_accent(c: char) -> char {
+  match c {
+    'á' | 'à' | 'ä' => 'a',
+    'é' | 'è' | 'ë' => 'e',
+    'í' | 'ì' | 'ï' => 'i',
+    'ó' | 'ò' | 'ö' | 'ő' => 'o',
+    'ú' | 'ù' | 'ü' | 'ű' => 'u',
+    c => c,
+  }
+}
+
+// Free text search query
+// The query and the searched texts are normalized the same way,
+// so case, accents and extra whitespace do not matter
+pub struct TextQuery {
+  folded: String,
+  normalized: String,
+  phone: String,
+}
+
+impl TextQuery {
+  pub fn new(query: &str) -> Self {
+    Self {
+      folded: fold(query),
+      normalized: normalize(query),
+      phone: normalize_phone(query),
+    }
+  }
+  pub fn is_empty(&self) -> bool {
+    self.folded.is_empty()
+  }
+  // Text contains the query
+  // An empty query is contained by every text
+  pub fn contained_in(&self, text: &str) -> bool {
+    fold(text).contains(&self.folded)
+  }
+  // Name contains the query, punctuation does not matter
+  // e.g. "kft" finds "Zöld Kert Kft."
+  pub fn matches_name(&self, name: &str) -> bool {
+    self.contained_in(name)
+      || (!self.normalized.is_empty() && normalize(name).contains(&self.normalized))
+  }
+  // Phone number contains the digits of the query
+  pub fn matches_phone(&self, phone: &str) -> bool {
+    self.phone.len() >= MIN_PHONE_DIGITS && normalize_phone(phone).contains(&self.phone)
+  }
+}
+
+// Hungarian alphabet, digraphs are letters of their own
+const ALPHABET: &[&str] = &[
+  "a", "b", "c", "cs", "d", "dz", "dzs", "e", "f", "g", "gy", "h", "i", "j", "k", "l", "ly", "m",
+  "n", "ny", "o", "ö", "p", "q", "r", "s", "sz", "t", "ty", "u", "ü", "v", "w", "x", "y", "z",
+  "zs",
+];
+
+// Letters are encoded as chars from here, in alphabet order
+// Spaces and digits sort before them, other letters after them
+const LETTER_BASE: u32 = 0x41;
+
+// Separates the letters from the accents in sort keys
+const ACCENT_SEPARATOR: char = '\u{1}';
+
+// Letter without length mark, and whether it was long
+// á and a are the same letter, but ö and o are not
+fn base_letter(c: char) -> (char, bool) {
+  match c {
+    'á' => ('a', true),
+    'à' | 'ä' | 'â' => ('a', false),
+    'é' => ('e', true),
+    'è' | 'ë' | 'ê' => ('e', false),
+    'í' => ('i', true),
+    'ì' | 'ï' | 'î' => ('i', false),
+    'ó' => ('o', true),
+    'ò' | 'ô' => ('o', false),
+    'ő' => ('ö', true),
+    'ú' => ('u', true),
+    'ù' | 'û' => ('u', false),
+    'ű' => ('ü', true),
+    c => (c, false),
+  }
+}
+
+fn letter_code(letter: &str) -> Option<char> {
+  ALPHABET
+    .iter()
+    .position(|l| *l == letter)
+    .and_then(|i| std::char::from_u32(LETTER_BASE + i as u32))
+}
+
+// Split name into collation elements, with their length marks
+// Doubled digraphs count twice, ssz => sz sz
+fn collation_elements(name: &str) -> Vec<(char, bool)> {
+  let chars = name
+    .to_lowercase()
+    .chars()
+    .map(|c| match c.is_alphanumeric() {
+      true => base_letter(c),
+      false => (' ', false),
+    })
+    .collect::<Vec<(char, bool)>>();
+  let letters = |from: usize, len: usize| {
+    chars
+      .get(from..from + len)
+      .map(|l| l.iter().map(|(c, _)| c).collect::<String>())
+  };
+  let mut res = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let (c, long) = chars[i];
+    if c == ' ' {
+      if res.last().is_some_and(|(l, _)| *l != ' ') {
+        res.push((' ', false));
+      }
+      i += 1;
+      continue;
+    }
+    if c.is_ascii_digit() {
+      res.push((c, false));
+      i += 1;
+      continue;
+    }
+    // Doubled digraph
+    if chars.get(i + 1).map(|(n, _)| *n) == Some(c) {
+      let doubled = [3, 2]
+        .iter()
+        .find_map(|len| letters(i + 1, *len).and_then(|l| letter_code(&l).map(|code| (code, len))));
+      if let Some((code, len)) = doubled {
+        res.push((code, false));
+        res.push((code, false));
+        i += 1 + len;
+        continue;
+      }
+    }
+    let letter = [3, 2, 1]
+      .iter()
+      .find_map(|len| letters(i, *len).and_then(|l| letter_code(&l).map(|code| (code, len))));
+    match letter {
+      Some((code, len)) => {
+        res.push((code, long));
+        i += len;
+      }
+      None => {
+        res.push((c, false));
+        i += 1;
+      }
+    }
+  }
+  if res.last().is_some_and(|(l, _)| *l == ' ') {
+    res.pop();
+  }
+  res
+}
+
+// Sort key of a name in Hungarian alphabetical order
+// Letters are compared first, without case and length marks,
+// then short vowels come before long ones
+pub fn sort_key(name: &str) -> String {
+  let elements = collation_elements(name);
+  let mut key = elements.iter().map(|(c, _)| *c).collect::<String>();
+  key.push(ACCENT_SEPARATOR);
+  key.extend(
+    elements
+      .iter()
+      .map(|(_, long)| if *long { '1' } else { '0' }),
+  );
+  key
+}
+
+// Sort key prefixes matching the names starting with prefix
+// A trailing letter matches the digraphs starting with it too,
+// so "Kovács C" finds "Kovács Csaba"
+pub fn sort_key_prefixes(prefix: &str) -> Vec<String> {
+  let elements = collation_elements(prefix);
+  let key = elements.iter().map(|(c, _)| *c).collect::<String>();
+  let last = match elements.last() {
+    Some((c, _)) => *c,
+    None => return Vec::new(),
+  };
+  let mut res = vec![key.clone()];
+  if let Some(last) = ALPHABET.get((last as u32).wrapping_sub(LETTER_BASE) as usize) {
+    let stem = key.chars().take(elements.len() - 1).collect::<String>();
+    for letter in ALPHABET
+      .iter()
+      .filter(|l| l.len() > last.len() && l.starts_with(last))
+    {
+      if let Some(code) = letter_code(letter) {
+        res.push(format!("{}{}", stem, code));
+      }
+    }
+  }
+  res
+}
+
+// Min number of digits to search phone numbers by
+pub const MIN_PHONE_DIGITS: usize = 6;
+
+// Normalize phone number for matching
+// Keeps the digits only, +36 30 123-4567 => 36301234567
+// Hungarian 06 prefix is the same as +36
+pub fn normalize_phone(phone: &str) -> String {
+  let digits = phone
+    .chars()
+    .filter(|c| c.is_ascii_digit())
+    .collect::<String>();
+  match digits.strip_prefix("06") {
+    Some(rest) => format!("36{}", rest),
+    None => digits,
+  }
+}
+
+// Edit distance of two strings
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+  let mut prev: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.iter().enumerate() {
+    let mut current = vec![i + 1; b.len() + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let cost = if ca == cb { 0 } else { 1 };
+      current[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(current[j] + 1);
+    }
+    prev = current;
+  }
+  prev[b.len()]
+}
+
+// Similarity of two normalized names
+// 1.0 means equal, 0.0 means nothing in common
+pub fn similarity(a: &str, b: &str) -> f32 {
+  let a = a.chars().collect::<Vec<char>>();
+  let b = b.chars().collect::<Vec<char>>();
+  let len = a.len().max(b.len());
+  if len == 0 {
+    return 0.0;
+  }
+  1.0 - levenshtein(&a, &b) as f32 / len as f32
+}
+
+// Get best match candidates for a name
+// Customer names must be normalized already.
+// Returns (customer ID, confidence) pairs, best first
+pub fn best_matches<'a, I>(name: &str, customers: I, max_candidates: usize) -> Vec<(u32, f32)>
+where
+  I: Iterator<Item = (u32, &'a str)>,
+{
+  let name = normalize(name);
+  let mut res = customers
+    .map(|(id, customer_name)| (id, similarity(&name, customer_name)))
+    .filter(|(_, confidence)| *confidence >= MIN_CONFIDENCE)
+    .collect::<Vec<(u32, f32)>>();
+  res.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+  res.truncate(max_candidates);
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_normalize() {
+    assert_eq!(normalize("  Kiss-Béla  Kft. "), "kiss bela kft");
+    assert_eq!(normalize("ŐRÜLT Ügyfél"), "orult ugyfel");
+  }
+
+  #[test]
+  fn test_fold() {
+    assert_eq!(fold("  Kiss-Béla  Kft. "), "kiss-bela kft.");
+    assert_eq!(fold("KISS.BELA@Példa.hu"), "kiss.bela@pelda.hu");
+  }
+
+  #[test]
+  fn test_trigrams() {
+    assert_eq!(
+      trigrams(&fold("Fő u."))
+        .into_iter()
+        .collect::<Vec<String>>(),
+      [" u.", "fo ", "o u"]
+    );
+    assert_eq!(trigrams("aaaa").len(), 1);
+    assert!(trigrams("ab").is_empty());
+  }
+
+  #[test]
+  fn test_text_query() {
+    // Uppercase, accented and extra spaced queries
+    for query in ["KISS BÉLA", "kiss bela", "  Kiss   Béla ", "BÉLA"] {
+      assert!(TextQuery::new(query).matches_name("Kiss Béla"));
+    }
+    assert!(!TextQuery::new("kiss pál").matches_name("Kiss Béla"));
+    assert!(TextQuery::new("ZÖLD KERT KFT").matches_name("Zöld Kert Kft."));
+    assert!(TextQuery::new("kert, kft").matches_name("Zöld Kert Kft."));
+    assert!(!TextQuery::new("virág").matches_name("Zöld Kert Kft."));
+    // Emails keep their punctuation
+    let query = TextQuery::new("KISS.BELA@");
+    assert!(query.contained_in("kiss.bela@example.com"));
+    assert!(!query.contained_in("kiss bela@example.com"));
+    assert!(TextQuery::new("").contained_in("bármi"));
+    assert!(TextQuery::new("06 30 123").matches_phone("+36301234567"));
+    assert!(!TextQuery::new("123").matches_phone("+36301234567"));
+  }
+
+  #[test]
+  fn test_sort_key() {
+    let mut names = vec![
+      "Szabó Anna",
+      "Sütő Béla",
+      "Csala Péter",
+      "Cukor Ede",
+      "Ödön Kft",
+      "Oszlopos Simeon",
+      "Péter Pál",
+      "Ábel János",
+      "Adorján Ilona",
+      "Abel János",
+      "Kis Béla",
+      "Kisfaludy Károly",
+      "Zsák Gyula",
+      "Zúzmara Kft",
+    ];
+    names.sort_by_key(|n| sort_key(n));
+    assert_eq!(
+      names,
+      [
+        "Abel János",
+        "Ábel János",
+        "Adorján Ilona",
+        "Cukor Ede",
+        "Csala Péter",
+        "Kis Béla",
+        "Kisfaludy Károly",
+        "Oszlopos Simeon",
+        "Ödön Kft",
+        "Péter Pál",
+        "Sütő Béla",
+        "Szabó Anna",
+        "Zúzmara Kft",
+        "Zsák Gyula",
+      ]
+    );
+    // Case, punctuation and spacing are ignored
+    assert_eq!(sort_key("KISS-béla"), sort_key(" Kiss  Béla "));
+    // Doubled digraphs
+    assert_eq!(sort_key("Hosszú"), sort_key("Hoszszú"));
+  }
+
+  #[test]
+  fn test_sort_key_prefixes() {
+    let matches = |prefix: &str, name: &str| {
+      sort_key_prefixes(prefix)
+        .iter()
+        .any(|p| sort_key(name).starts_with(p.as_str()))
+    };
+    assert!(matches("kovacs", "Kovács Béla"));
+    assert!(matches("Kovács B", "Kovács Béla"));
+    assert!(matches("c", "Csala Péter"));
+    assert!(matches("c", "Cukor Ede"));
+    assert!(!matches("cs", "Cukor Ede"));
+    assert!(matches("d", "Dzsida Jenő"));
+    assert!(!matches("o", "Ödön Kft"));
+    assert!(!matches("kovacs bela", "Kovács"));
+    assert!(sort_key_prefixes(" ").is_empty());
+  }
+
+  #[test]
+  fn test_normalize_phone() {
+    assert_eq!(normalize_phone("+36 (30) 123-4567"), "36301234567");
+    assert_eq!(normalize_phone("06-30/123-4567"), "36301234567");
+    assert_eq!(normalize_phone("kiss@example.com"), "");
+  }
+
+  #[test]
+  fn test_similarity() {
+    assert_eq!(similarity("kiss bela", "kiss bela"), 1.0);
+    assert_eq!(similarity("", ""), 0.0);
+    assert!(similarity("kiss bela", "kis bela") > 0.8);
+    assert!(similarity("kiss bela", "nagy anna") < 0.5);
+  }
+
+  #[test]
+  fn test_best_matches() {
+    let customers = [(1, "kiss bela"), (2, "kis bela"), (3, "nagy anna")];
+    let res = best_matches("kiss bela", customers.iter().copied(), 5);
+    assert_eq!(res.len(), 2);
+    assert_eq!(res[0], (1, 1.0));
+    assert_eq!(res[1].0, 2);
+    let res = best_matches("kiss bela", customers.iter().copied(), 1);
+    assert_eq!(res.len(), 1);
+  }
+}
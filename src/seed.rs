@@ -0,0 +1,259 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Test fixtures
+//
+// Generates realistic fake customers for staging environments
+// and load tests. The same seed always generates the same
+// customers, so test runs can be compared.
+
+use crate::customer::{Customer, CustomerKind};
+use crate::search;
+use crate::taxnumber::TaxNumber;
+use chrono::prelude::*;
+
+const SURNAMES: &[&str] = &[
+  "Nagy",
+  "Kovács",
+  "Tóth",
+  "Szabó",
+  "Horváth",
+  "Varga",
+  "Kiss",
+  "Molnár",
+  "Németh",
+  "Farkas",
+  "Balogh",
+  "Papp",
+  "Takács",
+  "Juhász",
+  "Lakatos",
+  "Mészáros",
+  "Oláh",
+  "Simon",
+  "Rácz",
+  "Fekete",
+];
+
+const GIVEN_NAMES: &[&str] = &[
+  "Béla",
+  "László",
+  "István",
+  "József",
+  "Zoltán",
+  "Péter",
+  "Gábor",
+  "Ferenc",
+  "Anna",
+  "Mária",
+  "Erzsébet",
+  "Katalin",
+  "Ildikó",
+  "Judit",
+  "Éva",
+  "Zsuzsanna",
+  "Gergő",
+  "Réka",
+  "Ágnes",
+  "Ödön",
+];
+
+const COMPANY_WORDS: &[&str] = &[
+  "Kertész",
+  "Virág",
+  "Zöldség",
+  "Agro",
+  "Gyümölcs",
+  "Faiskola",
+  "Öntöző",
+  "Mező",
+];
+
+const COMPANY_FORMS: &[&str] = &["Kft", "Bt", "Zrt"];
+
+const INSTITUTIONS: &[&str] = &[
+  "Általános Iskola",
+  "Óvoda",
+  "Polgármesteri Hivatal",
+  "Alapítvány",
+];
+
+// Real zip codes with their settlement
+const ZIPS: &[(&str, &str)] = &[
+  ("1011", "Budapest"),
+  ("1052", "Budapest"),
+  ("1117", "Budapest"),
+  ("4024", "Debrecen"),
+  ("6720", "Szeged"),
+  ("7621", "Pécs"),
+  ("9021", "Győr"),
+  ("3530", "Miskolc"),
+  ("6000", "Kecskemét"),
+  ("8000", "Székesfehérvár"),
+  ("2400", "Dunaújváros"),
+  ("5600", "Békéscsaba"),
+];
+
+const STREETS: &[&str] = &[
+  "Petőfi Sándor utca",
+  "Kossuth Lajos utca",
+  "Rákóczi út",
+  "Dózsa György út",
+  "Fő utca",
+  "Béke tér",
+  "Arany János utca",
+  "Szent István körút",
+];
+
+const PHONE_PREFIXES: &[&str] = &["20", "30", "70"];
+
+// SplitMix64, simple and the same on every platform
+struct Rng(u64);
+
+impl Rng {
+  fn next(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+  fn below(&mut self, n: u32) -> u32 {
+    (self.next() % n as u64) as u32
+  }
+  fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+    items[self.below(items.len() as u32) as usize]
+  }
+}
+
+// Valid tax number with random digits
+fn tax_number(rng: &mut Rng) -> TaxNumber {
+  let mut base = (0..7).map(|_| rng.below(10)).collect::<Vec<u32>>();
+  // First digit cannot be 0
+  base[0] = rng.below(9) + 1;
+  let sum =
+    base[0] * 9 + base[1] * 7 + base[2] * 3 + base[3] + base[4] * 9 + base[5] * 7 + base[6] * 3;
+  let check = (10 - sum % 10) % 10;
+  let vat = rng.below(5) + 1;
+  let county = rng.below(19) + 2;
+  let digits = base.iter().map(|d| d.to_string()).collect::<String>();
+  TaxNumber::new(&format!("{}{}-{}-{:02}", digits, check, vat, county))
+    .expect("Generated tax number must be valid")
+}
+
+// Generate count customers, IDs starting at first_id
+pub fn generate(count: usize, seed: u64, first_id: u32) -> Vec<Customer> {
+  let mut rng = Rng(seed);
+  let start = Utc.with_ymd_and_hms(2020, 1, 1, 8, 0, 0).unwrap();
+  (0..count as u32)
+    .map(|i| {
+      let id = first_id + i;
+      let person = format!("{} {}", rng.pick(SURNAMES), rng.pick(GIVEN_NAMES));
+      let (kind, name) = match rng.below(20) {
+        0..=14 => (CustomerKind::Private, person.clone()),
+        15..=18 => (
+          CustomerKind::Company,
+          format!(
+            "{} {} {}",
+            person.split(' ').next().unwrap_or_default(),
+            rng.pick(COMPANY_WORDS),
+            rng.pick(COMPANY_FORMS)
+          ),
+        ),
+        _ => (CustomerKind::Institution, String::new()),
+      };
+      let (zip, location) = ZIPS[rng.below(ZIPS.len() as u32) as usize];
+      let name = match kind {
+        CustomerKind::Institution => format!("{}i {}", location, rng.pick(INSTITUTIONS)),
+        _ => name,
+      };
+      let tax_number = match kind {
+        CustomerKind::Private => None,
+        _ => Some(tax_number(&mut rng)),
+      };
+      let phone = format!(
+        "+36{}{:07}",
+        rng.pick(PHONE_PREFIXES),
+        rng.below(9_000_000) + 1_000_000
+      );
+      let email = match rng.below(4) {
+        0 => String::new(),
+        _ => format!(
+          "{}.{}@example.com",
+          search::normalize(&person).replace(' ', "."),
+          id
+        ),
+      };
+      let date_created = start + chrono::Duration::minutes(rng.below(2 * 365 * 24 * 60) as i64);
+      let customer = Customer {
+        id,
+        name,
+        email,
+        phone,
+        tax_number,
+        address_zip: zip.to_string(),
+        address_location: location.to_string(),
+        address_street: format!("{} {}.", rng.pick(STREETS), rng.below(120) + 1),
+        date_created,
+        created_by: rng.below(5) + 1,
+        kind,
+        last_modified: date_created,
+        ..Customer::default()
+      };
+      Customer {
+        last_modified_by: customer.created_by,
+        ..customer
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::policy::ValidationPolicy;
+  use crate::zip::ZipDb;
+
+  #[test]
+  fn test_generate() {
+    let customers = generate(500, 42, 10);
+    assert_eq!(customers.len(), 500);
+    assert_eq!(customers[0].id, 10);
+    assert_eq!(customers[499].id, 509);
+    let csv = ZIPS
+      .iter()
+      .map(|(zip, location)| format!("{},{}", zip, location))
+      .collect::<Vec<String>>()
+      .join("\n");
+    let zip_db = ZipDb::from_csv(&csv);
+    for c in &customers {
+      assert!(c.validate(&ValidationPolicy::default()).is_ok(), "{:?}", c);
+      assert!(zip_db.validate(&c.address_zip, &c.address_location).is_ok());
+    }
+    assert!(customers.iter().any(|c| c.kind == CustomerKind::Company));
+    assert!(customers
+      .iter()
+      .any(|c| c.kind == CustomerKind::Institution));
+    // Same seed, same customers
+    let again = generate(500, 42, 10);
+    assert!(customers
+      .iter()
+      .zip(again.iter())
+      .all(|(a, b)| a.name == b.name && a.tax_number == b.tax_number && a.phone == b.phone));
+    assert_ne!(generate(1, 43, 10)[0].phone, customers[0].phone);
+  }
+}
@@ -0,0 +1,439 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Segments
+//
+// Saved customer filters, e.g. wholesale customers in Pest county,
+// so marketing can reuse them without rebuilding the filter. A segment
+// stores the filter expression, its members are evaluated on demand,
+// as in QueryCustomers, and periodically by a background task. The
+// task caches the members, and publishes SegmentEntered and
+// SegmentLeft events for the customers starting or stopping to match.
+// The first evaluation of a segment publishes no events. Segments and
+// their cached members are stored next to the customer storage of the
+// tenant.
+
+use crate::custom_field::{CustomFieldDef, CustomFields};
+use crate::db::CustomerDb;
+use crate::events::CustomerEventKind;
+use crate::outbox::Outbox;
+use crate::prelude::*;
+use crate::query::Query;
+use crate::sanitize;
+use crate::tenant::{tenant_path, Tenants};
+use chrono::prelude::*;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+// Max segment name length in characters
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Segment {
+  pub id: u32,
+  pub name: String,
+  pub description: String,
+  // Filter expression, as in QueryCustomers
+  pub query: String,
+  pub created_by: u32,
+  pub date_created: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SegmentData {
+  segments: Vec<Segment>,
+  next_id: u32,
+}
+
+// Members found by the last scheduled evaluation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Membership {
+  // Customer IDs, ascending
+  pub members: Vec<u32>,
+  pub evaluated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MembershipData {
+  // By segment ID
+  memberships: BTreeMap<u32, Membership>,
+}
+
+// Segments of every tenant
+pub struct Segments {
+  data_dir: PathBuf,
+  packs: HashMap<String, Pack<SegmentData>>,
+  member_packs: HashMap<String, Pack<MembershipData>>,
+}
+
+impl Segments {
+  pub fn new(data_dir: &Path) -> Self {
+    Self {
+      data_dir: data_dir.to_path_buf(),
+      packs: HashMap::new(),
+      member_packs: HashMap::new(),
+    }
+  }
+  // Segments pack of a tenant, loaded on first use
+  fn pack(&mut self, tenant: &str) -> ServiceResult<&mut Pack<SegmentData>> {
+    if !self.packs.contains_key(tenant) {
+      let path = tenant_path(&self.data_dir, tenant).with_file_name("segments");
+      let pack = Pack::load_or_init(path, "segments")?;
+      self.packs.insert(tenant.to_string(), pack);
+    }
+    Ok(self.packs.get_mut(tenant).expect("Loaded above"))
+  }
+  // Cached members pack of a tenant, loaded on first use
+  fn member_pack(&mut self, tenant: &str) -> ServiceResult<&mut Pack<MembershipData>> {
+    if !self.member_packs.contains_key(tenant) {
+      let path = tenant_path(&self.data_dir, tenant).with_file_name("segment_members");
+      let pack = Pack::load_or_init(path, "segment_members")?;
+      self.member_packs.insert(tenant.to_string(), pack);
+    }
+    Ok(self.member_packs.get_mut(tenant).expect("Loaded above"))
+  }
+  // Save segment
+  // The query must be checked by the caller
+  pub fn create(
+    &mut self,
+    tenant: &str,
+    name: &str,
+    description: &str,
+    query: &str,
+    created_by: u32,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<Segment> {
+    let name = name.trim();
+    let description = sanitize::line(description).map_err(|e| e.on_field("description"))?;
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+      return Err(ServiceError::invalid_field(
+        "name",
+        &format!("A szegmens neve kötelező, max {} karakter", MAX_NAME_LEN),
+      ));
+    }
+    let pack = self.pack(tenant)?;
+    // Names are unique without case
+    if pack
+      .unpack()
+      .segments
+      .iter()
+      .any(|s| s.name.to_lowercase() == name.to_lowercase())
+    {
+      return Err(ServiceError::already_exist(
+        "Már létezik szegmens ezzel a névvel",
+      ));
+    }
+    let segment = pack.update(|data| {
+      data.next_id += 1;
+      let segment = Segment {
+        id: data.next_id,
+        name: name.to_string(),
+        description: description.clone(),
+        query: query.trim().to_string(),
+        created_by,
+        date_created: now,
+      };
+      data.segments.push(segment.clone());
+      segment
+    })?;
+    Ok(segment)
+  }
+  // Segments, in creation order
+  pub fn list(&mut self, tenant: &str) -> ServiceResult<Vec<Segment>> {
+    Ok(self.pack(tenant)?.unpack().segments.clone())
+  }
+  pub fn get(&mut self, tenant: &str, id: u32) -> ServiceResult<Segment> {
+    self
+      .pack(tenant)?
+      .unpack()
+      .segments
+      .iter()
+      .find(|s| s.id == id)
+      .cloned()
+      .ok_or_else(|| ServiceError::not_found("Nem található ilyen szegmens"))
+  }
+  // Members of the last scheduled evaluation, None before the first one
+  pub fn membership(&mut self, tenant: &str, id: u32) -> ServiceResult<Option<Membership>> {
+    Ok(
+      self
+        .member_pack(tenant)?
+        .unpack()
+        .memberships
+        .get(&id)
+        .cloned(),
+    )
+  }
+  // Store members found by an evaluation
+  pub fn record(
+    &mut self,
+    tenant: &str,
+    id: u32,
+    members: Vec<u32>,
+    now: DateTime<Utc>,
+  ) -> ServiceResult<()> {
+    self.member_pack(tenant)?.update(|data| {
+      data.memberships.insert(
+        id,
+        Membership {
+          members,
+          evaluated_at: now,
+        },
+      );
+    })?;
+    Ok(())
+  }
+}
+
+// Evaluate every segment of a tenant, and cache their members
+// Events are stored before the members, so a failed run publishes
+// them again in the next one. Returns the number of events.
+// Customers are borrowed mutably, as CustomerDb is not Sync.
+pub async fn refresh(
+  tenant: &str,
+  customers: &mut CustomerDb,
+  custom_fields: &[CustomFieldDef],
+  segments: &mut Segments,
+  outbox: &Outbox,
+  now: DateTime<Utc>,
+) -> ServiceResult<usize> {
+  let mut count = 0;
+  for segment in segments.list(tenant)? {
+    // Definitions cannot be removed, so saved queries keep parsing
+    let query = match Query::parse(&segment.query, custom_fields) {
+      Ok(query) => query,
+      Err(error) => {
+        eprintln!(
+          "Error while evaluating segment {} of tenant '{}': {}",
+          segment.id, tenant, error
+        );
+        continue;
+      }
+    };
+    let members = customers
+      .iter()
+      .filter(|c| query.matches(c))
+      .map(|c| c.id)
+      .collect::<Vec<u32>>();
+    if let Some(previous) = segments.membership(tenant, segment.id)? {
+      let before = previous.members.iter().collect::<HashSet<&u32>>();
+      let after = members.iter().collect::<HashSet<&u32>>();
+      for id in members.iter().filter(|id| !before.contains(id)) {
+        let customer = customers.find_id(id)?.clone();
+        let kind = CustomerEventKind::SegmentEntered(segment.id);
+        outbox.push(tenant, kind, &customer)?;
+        count += 1;
+      }
+      for id in previous.members.iter().filter(|id| !after.contains(id)) {
+        let customer = customers.find_id(id)?.clone();
+        let kind = CustomerEventKind::SegmentLeft(segment.id);
+        outbox.push(tenant, kind, &customer)?;
+        count += 1;
+      }
+    }
+    let mut members = members;
+    members.sort_unstable();
+    segments.record(tenant, segment.id, members, now)?;
+  }
+  Ok(count)
+}
+
+// Evaluate the segments of every tenant periodically
+pub async fn run(
+  interval: Duration,
+  tenants: Arc<Tenants>,
+  custom_fields: Arc<Mutex<CustomFields>>,
+  segments: Arc<Mutex<Segments>>,
+  outbox: Arc<Outbox>,
+) {
+  let mut interval = tokio::time::interval(interval);
+  loop {
+    interval.tick().await;
+    let tenant_ids = match tenants.ids().await {
+      Ok(tenant_ids) => tenant_ids,
+      Err(error) => {
+        eprintln!("Error while listing tenants for segments: {}", error);
+        continue;
+      }
+    };
+    for tenant in tenant_ids {
+      let res = match tenants.get(&tenant).await {
+        // Events are stored while the customers are locked
+        Ok(customers) => {
+          let mut customers = customers.lock().await;
+          match custom_fields.lock().await.list(&tenant) {
+            Ok(defs) => {
+              let mut segments = segments.lock().await;
+              refresh(
+                &tenant,
+                &mut customers,
+                &defs,
+                &mut segments,
+                &outbox,
+                Utc::now(),
+              )
+              .await
+            }
+            Err(error) => Err(error),
+          }
+        }
+        Err(error) => Err(error),
+      };
+      match res {
+        Ok(0) => (),
+        Ok(count) => println!(
+          "Segment evaluation of tenant '{}': {} membership changes",
+          tenant, count
+        ),
+        Err(error) => eprintln!(
+          "Error in segment evaluation of tenant '{}': {}",
+          tenant, error
+        ),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::Customer;
+  use crate::events::CustomerEvent;
+  use crate::outbox::EventSink;
+
+  #[derive(Default)]
+  struct TestSink {
+    delivered: std::sync::Mutex<Vec<(CustomerEventKind, u32)>>,
+  }
+
+  #[tonic::async_trait]
+  impl EventSink for TestSink {
+    async fn deliver(&self, _id: u64, event: &CustomerEvent) -> Result<(), String> {
+      let mut delivered = self.delivered.lock().unwrap();
+      delivered.push((event.kind, event.customer.id));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let mut segments = Segments::new(dir.path());
+    assert!(segments
+      .create("", " ", "", "kind = company", 1, now)
+      .is_err());
+    let wholesale = segments
+      .create(
+        "",
+        " Nagykereskedők ",
+        "Pest megye",
+        "kind = company",
+        1,
+        now,
+      )
+      .unwrap();
+    assert_eq!(wholesale.name, "Nagykereskedők");
+    // Names are unique without case
+    assert!(segments
+      .create("", "NAGYKERESKEDŐK", "", "active = true", 1, now)
+      .is_err());
+    // Tenants are separate
+    assert!(segments.list("shop_a").unwrap().is_empty());
+    assert!(segments.get("shop_a", wholesale.id).is_err());
+    // Segments survive restart
+    let mut segments = Segments::new(dir.path());
+    assert_eq!(segments.get("", wholesale.id).unwrap(), wholesale);
+    let second = segments
+      .create("", "Aktívak", "", "active = true", 2, now)
+      .unwrap();
+    assert_ne!(second.id, wholesale.id);
+    assert_eq!(segments.list("").unwrap(), [wholesale, second]);
+    assert!(segments.get("", 99).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_refresh() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Utc::now();
+    let mut db = CustomerDb::new(VecPack::load_or_init(dir.path().join("customers")).unwrap());
+    for (id, name) in [(1, "Kiss Kert Kft"), (2, "Nagy Anna")] {
+      db.insert(Customer {
+        id,
+        name: name.to_string(),
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let outbox = Outbox::load(dir.path()).unwrap();
+    let sink = Arc::new(TestSink::default());
+    let mut segments = Segments::new(dir.path());
+    let kft = segments
+      .create("", "Kft-k", "", "name ~ kft", 1, now)
+      .unwrap();
+    assert!(segments.membership("", kft.id).unwrap().is_none());
+    // The first evaluation publishes no events
+    assert_eq!(
+      refresh("", &mut db, &[], &mut segments, &outbox, now)
+        .await
+        .unwrap(),
+      0
+    );
+    let membership = segments.membership("", kft.id).unwrap().unwrap();
+    assert_eq!(membership.members, [1]);
+    assert_eq!(membership.evaluated_at, now);
+    db.update(&1, 1, |c| {
+      c.name = "Kiss Kert Bt".to_string();
+      Ok(())
+    })
+    .unwrap();
+    db.update(&2, 1, |c| {
+      c.name = "Nagy Kft".to_string();
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(
+      refresh("", &mut db, &[], &mut segments, &outbox, now)
+        .await
+        .unwrap(),
+      2
+    );
+    let sinks: Vec<Arc<dyn EventSink>> = vec![sink.clone()];
+    outbox.deliver_due(&sinks, Utc::now()).await.unwrap();
+    assert_eq!(
+      *sink.delivered.lock().unwrap(),
+      [
+        (CustomerEventKind::SegmentEntered(kft.id), 2),
+        (CustomerEventKind::SegmentLeft(kft.id), 1)
+      ]
+    );
+    // Cached members survive restart
+    let mut segments = Segments::new(dir.path());
+    let membership = segments.membership("", kft.id).unwrap().unwrap();
+    assert_eq!(membership.members, [2]);
+    assert_eq!(
+      refresh("", &mut db, &[], &mut segments, &outbox, now)
+        .await
+        .unwrap(),
+      0
+    );
+  }
+}
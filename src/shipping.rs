@@ -0,0 +1,140 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Shipping notifications
+//
+// When the address of a customer changes, the shipping service is
+// called to flag the open deliveries of the customer, so parcels
+// are not sent to the old address. Notifications are staged in
+// data/shipping_outbox by the commit storing the change (see
+// CustomerDb::set_shipping), so they are sent if and only if the
+// change is stored, and retried with backoff. After MAX_ATTEMPTS
+// failed calls they are dropped and written to the dead letter log
+// data/shipping_dead_letters.jsonl, to be handled by hand.
+
+use crate::customer::Customer;
+use crate::events::CustomerEvent;
+use crate::outbox::{run_delivery, EventSink, Outbox};
+use crate::prelude::*;
+use crate::proto::shipping::shipping_client::ShippingClient;
+use crate::proto::shipping::FlagOpenDeliveriesRequest;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+
+// Failed calls before a notification is given up,
+// with the outbox backoff about an hour
+pub const MAX_ATTEMPTS: u32 = 20;
+
+// Call fails if the shipping service does not respond in time
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Check the address fields changed
+pub fn address_changed(before: &Customer, after: &Customer) -> bool {
+  before.address_zip != after.address_zip
+    || before.address_location != after.address_location
+    || before.address_street != after.address_street
+}
+
+fn request(event: &CustomerEvent) -> FlagOpenDeliveriesRequest {
+  let c = &event.customer;
+  FlagOpenDeliveriesRequest {
+    tenant: event.tenant.clone(),
+    customer_id: c.id,
+    address_zip: c.address_zip.clone(),
+    address_location: c.address_location.clone(),
+    address_street: c.address_street.clone(),
+    country: c.country.clone(),
+    changed_at: c.last_modified.to_rfc3339(),
+  }
+}
+
+// Calls the shipping service
+struct ShippingSink {
+  client: ShippingClient<Channel>,
+}
+
+#[tonic::async_trait]
+impl EventSink for ShippingSink {
+  async fn deliver(&self, _id: u64, event: &CustomerEvent) -> Result<(), String> {
+    // Clients share the channel
+    let mut client = self.client.clone();
+    tokio::time::timeout(CALL_TIMEOUT, client.flag_open_deliveries(request(event)))
+      .await
+      .map_err(|_| "Shipping service timed out".to_string())?
+      .map_err(|e| format!("Shipping service: {}", e))?;
+    Ok(())
+  }
+}
+
+// Load pending notifications and start their delivery to endpoint
+// Returns the outbox the address changes are staged in
+// Read only instances do not deliver, see instance_lock
+// Must be called from the tokio runtime
+pub fn load(data_dir: &Path, endpoint: &str, deliver: bool) -> ServiceResult<Arc<Outbox>> {
+  let channel = Endpoint::from_shared(endpoint.to_string())
+    .map_err(|e| ServiceError::internal_error(&format!("Invalid shipping endpoint: {}", e)))?
+    .connect_lazy()
+    .map_err(|e| ServiceError::internal_error(&format!("Invalid shipping endpoint: {}", e)))?;
+  let outbox = Arc::new(
+    Outbox::load_from_path(data_dir.join("shipping_outbox"))?
+      .with_dead_letters(MAX_ATTEMPTS, data_dir.join("shipping_dead_letters.jsonl")),
+  );
+  if deliver {
+    let sink = Arc::new(ShippingSink {
+      client: ShippingClient::new(channel),
+    });
+    tokio::spawn(run_delivery(outbox.clone(), vec![sink]));
+  }
+  Ok(outbox)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::events::CustomerEventKind;
+
+  fn customer() -> Customer {
+    Customer {
+      id: 3,
+      address_zip: "6000".to_string(),
+      address_location: "Kecskemét".to_string(),
+      address_street: "Fő utca 1.".to_string(),
+      ..Customer::default()
+    }
+  }
+
+  #[test]
+  fn test_address_changed() {
+    let before = customer();
+    let mut after = before.clone();
+    after.email = "kiss.bela@example.com".to_string();
+    assert!(!address_changed(&before, &after));
+    after.address_street = "Fő utca 2.".to_string();
+    assert!(address_changed(&before, &after));
+    let event = CustomerEvent {
+      tenant: "shop_a".to_string(),
+      kind: CustomerEventKind::Updated,
+      customer: after,
+    };
+    let request = request(&event);
+    assert_eq!(request.tenant, "shop_a");
+    assert_eq!(request.customer_id, 3);
+    assert_eq!(request.address_street, "Fő utca 2.");
+  }
+}
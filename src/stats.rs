@@ -0,0 +1,103 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::customer::{Customer, CustomerKind};
+use std::collections::BTreeMap;
+
+// Customer statistics
+// Keys are sorted ascending
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub customer_count: u32,
+  pub created_per_month: BTreeMap<String, u32>, // Key: YYYY-MM
+  pub by_kind: Vec<(CustomerKind, u32)>,
+  pub by_zip_prefix: BTreeMap<String, u32>, // Customers without zip are not counted
+}
+
+// Compute customer statistics
+pub fn compute<'a>(customers: impl Iterator<Item = &'a Customer>, zip_prefix_len: usize) -> Stats {
+  let mut stats = Stats::default();
+  let mut by_kind: Vec<(CustomerKind, u32)> = vec![
+    (CustomerKind::Private, 0),
+    (CustomerKind::Company, 0),
+    (CustomerKind::Institution, 0),
+  ];
+  for customer in customers {
+    stats.customer_count += 1;
+    *stats
+      .created_per_month
+      .entry(customer.date_created.format("%Y-%m").to_string())
+      .or_insert(0) += 1;
+    if let Some(kind) = by_kind.iter_mut().find(|(kind, _)| *kind == customer.kind) {
+      kind.1 += 1;
+    }
+    let zip = customer.address_zip.trim();
+    if !zip.is_empty() {
+      let prefix = zip.chars().take(zip_prefix_len).collect::<String>();
+      *stats.by_zip_prefix.entry(prefix).or_insert(0) += 1;
+    }
+  }
+  stats.by_kind = by_kind;
+  stats
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::prelude::*;
+
+  fn customer(kind: CustomerKind, zip: &str, created: &str) -> Customer {
+    Customer {
+      kind,
+      address_zip: zip.to_string(),
+      date_created: DateTime::parse_from_rfc3339(created)
+        .unwrap()
+        .with_timezone(&Utc),
+      ..Customer::default()
+    }
+  }
+
+  #[test]
+  fn test_compute() {
+    let customers = [
+      customer(CustomerKind::Private, "6000", "2020-11-02T10:00:00Z"),
+      customer(CustomerKind::Company, "6031", "2020-11-30T10:00:00Z"),
+      customer(CustomerKind::Company, "1114", "2020-12-01T10:00:00Z"),
+      customer(CustomerKind::Private, "", "2021-01-01T10:00:00Z"),
+    ];
+    let stats = compute(customers.iter(), 1);
+    assert_eq!(stats.customer_count, 4);
+    assert_eq!(stats.created_per_month.get("2020-11"), Some(&2));
+    assert_eq!(stats.created_per_month.get("2020-12"), Some(&1));
+    assert_eq!(stats.created_per_month.get("2021-01"), Some(&1));
+    assert_eq!(
+      stats.by_kind,
+      vec![
+        (CustomerKind::Private, 2),
+        (CustomerKind::Company, 2),
+        (CustomerKind::Institution, 0)
+      ]
+    );
+    assert_eq!(stats.by_zip_prefix.get("6"), Some(&2));
+    assert_eq!(stats.by_zip_prefix.get("1"), Some(&1));
+    assert_eq!(stats.by_zip_prefix.len(), 2);
+
+    let stats = compute(customers.iter(), 2);
+    assert_eq!(stats.by_zip_prefix.get("60"), Some(&2));
+    assert_eq!(stats.by_zip_prefix.get("11"), Some(&1));
+  }
+}
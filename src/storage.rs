@@ -0,0 +1,1958 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::customer::{decode_unversioned, decode_unversioned_batch, record_id, Customer};
+use crate::prelude::*;
+use packman::fs::PackFile;
+use packman::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+// Customer storage backend
+//
+// CustomerDb keeps its in-memory indexes on top of it,
+// so a backend only stores customers by ID. Changes
+// must be persisted when insert or update returns.
+pub trait CustomerStore: Send {
+  // Customers in storage order
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_>;
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer>;
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()>;
+  // Replace the stored customer with the same ID
+  fn update(&mut self, customer: Customer) -> ServiceResult<()>;
+  // Store customers together, inserting the new IDs and
+  // replacing the existing ones. Either every customer
+  // is stored or none of them.
+  fn write_batch(&mut self, batch: Vec<Customer>) -> ServiceResult<()>;
+  fn len(&self) -> usize;
+  fn compact(&mut self) -> ServiceResult<CompactReport>;
+  // Reads of a shadow storage not matching the primary one
+  fn shadow_divergences(&self) -> Option<u64> {
+    None
+  }
+  // Call f with every customer in storage order
+  // Lazy storages read them without keeping them in memory
+  fn scan(&self, f: &mut dyn FnMut(&Customer)) {
+    for customer in self.iter() {
+      f(customer);
+    }
+  }
+}
+
+// Unreadable record found while loading a storage
+// It is handed over before it is removed from the storage
+pub struct BrokenRecord {
+  // Storage file of the record
+  pub path: PathBuf,
+  // Byte offset of the record in the file
+  pub offset: u64,
+  pub data: Vec<u8>,
+  pub reason: String,
+}
+
+// Called with every unreadable record, loading fails if it fails
+pub type OnBroken<'a> = &'a mut dyn FnMut(BrokenRecord) -> ServiceResult<()>;
+
+// Load VecPack storage, skipping the unreadable files
+// VecPack loading would panic on them. Skipped files
+// are removed from the storage directory.
+pub fn load_vecpack(path: &Path, on_broken: OnBroken) -> ServiceResult<VecPack<Customer>> {
+  let mut pack = VecPack::new(path.to_path_buf())?;
+  let mut files = std::fs::read_dir(path)?
+    .map(|entry| entry.map(|e| e.path()))
+    .collect::<Result<Vec<PathBuf>, std::io::Error>>()?;
+  files.sort();
+  for file in files {
+    match read_customer_file(&file) {
+      Ok((_, false)) => pack.insert_pack(Pack::<Customer>::load_from_path(file)?)?,
+      // Stored again in the current layout
+      Ok((customer, true)) => VecPack::insert(&mut pack, customer)?,
+      Err(error) => {
+        on_broken(BrokenRecord {
+          data: std::fs::read(&file)?,
+          path: file.clone(),
+          offset: 0,
+          reason: error.to_string(),
+        })?;
+        std::fs::remove_file(&file)?;
+      }
+    }
+  }
+  recover_batch(&mut pack)?;
+  Ok(pack)
+}
+
+// Read a customer packfile
+// Returns whether the record has no schema version,
+// and needs to be stored again
+pub fn read_customer_file(path: &Path) -> ServiceResult<(Customer, bool)> {
+  let data = PackFile::open(path)?.load_data()?;
+  match bincode::deserialize::<Customer>(&data) {
+    Ok(customer) => Ok((customer, false)),
+    Err(error) => match decode_unversioned(&data) {
+      Some(customer) => Ok((customer, true)),
+      None => Err(PackError::from(error).into()),
+    },
+  }
+}
+
+// VecPack storage, one packfile per customer
+impl CustomerStore for VecPack<Customer> {
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_> {
+    Box::new(self.as_vec().iter().map(|c| c.unpack()))
+  }
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    match VecPack::find_id(self, id) {
+      Ok(customer) => Ok(customer.unpack()),
+      Err(PackError::ObjectNotFound) => Err(ServiceError::customer_not_found(*id)),
+      Err(error) => Err(error.into()),
+    }
+  }
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    let id = customer.id;
+    match VecPack::insert(self, customer) {
+      Err(PackError::IDTaken) => Err(ServiceError::customer_exists(id)),
+      res => Ok(res?),
+    }
+  }
+  fn update(&mut self, customer: Customer) -> ServiceResult<()> {
+    let id = customer.id;
+    match self.find_id_mut(&id) {
+      Ok(c) => Ok(c.update(|c| *c = customer)?),
+      Err(PackError::ObjectNotFound) => Err(ServiceError::customer_not_found(id)),
+      Err(error) => Err(error.into()),
+    }
+  }
+  // Packfiles are written one by one, the written ones are
+  // rolled back if a later one fails. The previous versions are
+  // saved first into an undo file, so a batch interrupted by a crash
+  // is rolled back on the next load.
+  fn write_batch(&mut self, batch: Vec<Customer>) -> ServiceResult<()> {
+    let undo: Previous = batch
+      .iter()
+      .map(|c| {
+        let previous = VecPack::find_id(self, &c.id).ok();
+        (c.id, previous.map(|c| c.unpack().clone()))
+      })
+      .collect();
+    let undo_path = undo_path(self.get_path());
+    write_undo(&undo_path, &undo)?;
+    // (stored customer, previous version if it was an update)
+    let mut written: Vec<(u32, Option<Customer>)> = Vec::new();
+    for customer in batch {
+      let id = customer.id;
+      let previous = VecPack::find_id(self, &id).ok().map(|c| c.unpack().clone());
+      let res = match previous {
+        Some(_) => CustomerStore::update(self, customer),
+        None => CustomerStore::insert(self, customer),
+      };
+      if let Err(error) = res {
+        // Best effort, the original error is returned
+        // The undo file is kept if the rollback fails
+        if undo_batch(self, written).is_ok() {
+          let _ = std::fs::remove_file(&undo_path);
+        }
+        return Err(error);
+      }
+      written.push((id, previous));
+    }
+    std::fs::remove_file(&undo_path)?;
+    Ok(())
+  }
+  fn len(&self) -> usize {
+    self.as_vec().len()
+  }
+  fn compact(&mut self) -> ServiceResult<CompactReport> {
+    compact(self)
+  }
+}
+
+// Undo file of the VecPack batch being written
+// It is next to the VecPack directory, as every file inside of it
+// is loaded as a record.
+fn undo_path(dir: &Path) -> PathBuf {
+  let mut name = dir.file_name().unwrap_or_default().to_os_string();
+  name.push(".batch_undo");
+  dir.with_file_name(name)
+}
+
+fn write_undo(path: &Path, undo: &Previous) -> ServiceResult<()> {
+  let data = bincode::serialize(undo).map_err(PackError::from)?;
+  let mut file = File::create(path)?;
+  file.write_all(&with_header(data, 0))?;
+  file.sync_all()?;
+  Ok(())
+}
+
+// Put back the previous versions of a batch, newest first
+fn undo_batch(pack: &mut VecPack<Customer>, undo: Previous) -> ServiceResult<()> {
+  for (id, previous) in undo.into_iter().rev() {
+    match (previous, VecPack::find_id(pack, &id).is_ok()) {
+      (Some(previous), true) => CustomerStore::update(pack, previous)?,
+      (Some(previous), false) => CustomerStore::insert(pack, previous)?,
+      (None, true) => pack.remove_pack(&id).map(|_| ())?,
+      (None, false) => (),
+    }
+  }
+  Ok(())
+}
+
+// Roll back the batch interrupted by a crash
+// An unreadable undo file was torn while it was written,
+// before any packfile was changed.
+fn recover_batch(pack: &mut VecPack<Customer>) -> ServiceResult<()> {
+  let path = undo_path(pack.get_path());
+  if let Some(undo) = read_undo(&path)? {
+    undo_batch(pack, undo)?;
+  }
+  remove_undo(&path)
+}
+
+// Previous versions of the customers of an interrupted batch,
+// None if there is no undo file, or it was torn
+fn read_undo(path: &Path) -> ServiceResult<Option<Previous>> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  let data = std::fs::read(path)?;
+  match read_raw_record(&data, 0) {
+    RawRecord::Data(record, false, _) => Ok(bincode::deserialize::<Previous>(record).ok()),
+    _ => Ok(None),
+  }
+}
+
+fn remove_undo(path: &Path) -> ServiceResult<()> {
+  match path.exists() {
+    true => Ok(std::fs::remove_file(path)?),
+    false => Ok(()),
+  }
+}
+
+// In-memory storage
+// Nothing is written to disk, used by tests
+#[derive(Default)]
+pub struct MemoryStore {
+  customers: BTreeMap<u32, Customer>,
+}
+
+impl MemoryStore {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl CustomerStore for MemoryStore {
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_> {
+    Box::new(self.customers.values())
+  }
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    self
+      .customers
+      .get(id)
+      .ok_or_else(|| ServiceError::customer_not_found(*id))
+  }
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    if self.customers.contains_key(&customer.id) {
+      return Err(ServiceError::customer_exists(customer.id));
+    }
+    self.customers.insert(customer.id, customer);
+    Ok(())
+  }
+  fn update(&mut self, customer: Customer) -> ServiceResult<()> {
+    match self.customers.get_mut(&customer.id) {
+      Some(c) => {
+        *c = customer;
+        Ok(())
+      }
+      None => Err(ServiceError::customer_not_found(customer.id)),
+    }
+  }
+  fn write_batch(&mut self, batch: Vec<Customer>) -> ServiceResult<()> {
+    for customer in batch {
+      self.customers.insert(customer.id, customer);
+    }
+    Ok(())
+  }
+  fn len(&self) -> usize {
+    self.customers.len()
+  }
+  fn compact(&mut self) -> ServiceResult<CompactReport> {
+    Ok(CompactReport {
+      record_count: self.customers.len() as u32,
+      size_before: 0,
+      size_after: 0,
+    })
+  }
+}
+
+// Storage compaction report
+pub struct CompactReport {
+  pub record_count: u32,
+  pub size_before: u64,
+  pub size_after: u64,
+}
+
+// Append only log storage
+//
+// Every insert and update appends the whole customer to a single
+// log file, so a change is one write and one sync, and loading
+// reads one file instead of one file per customer. Customers are
+// kept in memory, the last record of a customer wins.
+// Records are prefixed with their length and CRC32. A torn record
+// at the end of the log, e.g. after a crash, is dropped on load.
+// A batch of customers is written as one record, so it is loaded
+// either as a whole or not at all.
+// Broken records inside the log are skipped, and the log is compacted
+// without them. Compaction rewrites the log with the latest records only, it is
+// done on load as well when most of the records are stale.
+pub struct LogStore {
+  path: PathBuf,
+  file: File,
+  record_count: usize,
+  customers: MemoryStore,
+}
+
+// Length and CRC32 of a log record
+const RECORD_HEADER_SIZE: usize = 8;
+// Set in the length of batch records
+const BATCH_FLAG: u32 = 1 << 31;
+
+impl LogStore {
+  // Open log, creates it if it does not exist
+  pub fn open(path: &Path, on_broken: OnBroken) -> ServiceResult<Self> {
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let data = match path.exists() {
+      true => std::fs::read(path)?,
+      false => Vec::new(),
+    };
+    let mut customers = MemoryStore::new();
+    let mut record_count = 0;
+    let mut position = 0;
+    let mut broken = false;
+    let mut on_broken = |position: usize, end: usize, reason: &str| {
+      on_broken(BrokenRecord {
+        path: path.to_path_buf(),
+        offset: position as u64,
+        data: data[position..end].to_vec(),
+        reason: reason.to_string(),
+      })
+    };
+    loop {
+      match read_record(&data, position) {
+        Record::Customers(batch, next) => {
+          record_count += batch.len();
+          for customer in batch {
+            customers.customers.insert(customer.id, customer);
+          }
+          position = next;
+        }
+        Record::Broken(next, reason) => {
+          on_broken(position, next, reason)?;
+          broken = true;
+          position = next;
+        }
+        Record::End => break,
+      }
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    // Drop torn record
+    if position < data.len() {
+      on_broken(position, data.len(), "Torn record at the end of the log")?;
+      file.set_len(position as u64)?;
+      file.sync_data()?;
+    }
+    let mut store = Self {
+      path: path.to_path_buf(),
+      file,
+      record_count,
+      customers,
+    };
+    if broken || store.record_count > 2 * store.customers.len() {
+      store.compact()?;
+    }
+    Ok(store)
+  }
+  // Put back the customers changed by a batch, and drop the ones it
+  // inserted, then rewrite the log without the batch
+  fn restore(&mut self, previous: Previous) -> ServiceResult<()> {
+    for (id, customer) in previous {
+      match customer {
+        Some(customer) => self.customers.customers.insert(id, customer),
+        None => self.customers.customers.remove(&id),
+      };
+    }
+    self.compact().map(|_| ())
+  }
+  fn append(&mut self, record: &[u8], customer_count: usize) -> ServiceResult<()> {
+    append_record(&mut self.file, record)?;
+    self.record_count += customer_count;
+    Ok(())
+  }
+}
+
+// Customers of a batch before it was written, None if it was new
+type Previous = Vec<(u32, Option<Customer>)>;
+
+// Append record to the log and sync it
+// Returns the position of the record
+pub(crate) fn append_record(file: &mut File, record: &[u8]) -> ServiceResult<u64> {
+  let len = file.metadata()?.len();
+  if let Err(error) = file.write_all(record).and_then(|_| file.sync_data()) {
+    // Do not leave a part of the record behind
+    let _ = file.set_len(len);
+    return Err(error.into());
+  }
+  Ok(len)
+}
+
+impl CustomerStore for LogStore {
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_> {
+    self.customers.iter()
+  }
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    self.customers.find_id(id)
+  }
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    if self.customers.find_id(&customer.id).is_ok() {
+      return Err(ServiceError::customer_exists(customer.id));
+    }
+    self.append(&encode_record(&customer)?, 1)?;
+    self.customers.insert(customer)
+  }
+  fn update(&mut self, customer: Customer) -> ServiceResult<()> {
+    self.customers.find_id(&customer.id)?;
+    self.append(&encode_record(&customer)?, 1)?;
+    self.customers.update(customer)
+  }
+  fn write_batch(&mut self, batch: Vec<Customer>) -> ServiceResult<()> {
+    let record = match batch.as_slice() {
+      [customer] => encode_record(customer)?,
+      batch => encode_batch_record(batch)?,
+    };
+    self.append(&record, batch.len())?;
+    self.customers.write_batch(batch)
+  }
+  fn len(&self) -> usize {
+    self.customers.len()
+  }
+  // Rewrite the log next to the current one,
+  // then atomically replace it
+  fn compact(&mut self) -> ServiceResult<CompactReport> {
+    let size_before = std::fs::metadata(&self.path)?.len();
+    let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push("_compact");
+    let tmp_path = self.path.with_file_name(tmp_name);
+    let mut tmp = File::create(&tmp_path)?;
+    for customer in self.customers.iter() {
+      tmp.write_all(&encode_record(customer)?)?;
+    }
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, &self.path)?;
+    self.file = OpenOptions::new().append(true).open(&self.path)?;
+    self.record_count = self.customers.len();
+    Ok(CompactReport {
+      record_count: self.record_count as u32,
+      size_before,
+      size_after: std::fs::metadata(&self.path)?.len(),
+    })
+  }
+}
+
+// SQLite storage
+//
+// Customers are the rows of a customers table, keyed by ID, with the
+// same versioned record as the logs (without the log header, SQLite
+// frames its pages itself). Every change is an SQL transaction in
+// WAL mode with full sync, so it is stored as a whole or not at all,
+// also across a crash, and a batch is stored in one transaction.
+// Customers are kept in memory, like in LogStore. Rows that cannot
+// be decoded are handed over and deleted on load.
+pub struct SqliteStore {
+  path: PathBuf,
+  conn: rusqlite::Connection,
+  customers: MemoryStore,
+}
+
+impl SqliteStore {
+  // Open database, creates it if it does not exist
+  pub fn open(path: &Path, on_broken: OnBroken) -> ServiceResult<Self> {
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let conn = rusqlite::Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "FULL")?;
+    conn.execute(
+      "CREATE TABLE IF NOT EXISTS customers (id INTEGER PRIMARY KEY, data BLOB NOT NULL)",
+      [],
+    )?;
+    let mut customers = MemoryStore::new();
+    let mut broken = Vec::new();
+    {
+      let mut statement = conn.prepare("SELECT id, data FROM customers ORDER BY id")?;
+      let mut rows = statement.query([])?;
+      while let Some(row) = rows.next()? {
+        let id: u32 = row.get(0)?;
+        let data: Vec<u8> = row.get(1)?;
+        match decode_customer(&data) {
+          Some(customer) if customer.id == id => {
+            customers.customers.insert(id, customer);
+          }
+          _ => {
+            on_broken(BrokenRecord {
+              path: path.to_path_buf(),
+              offset: id as u64,
+              data,
+              reason: "Cannot deserialize customer".to_string(),
+            })?;
+            broken.push(id);
+          }
+        }
+      }
+    }
+    for id in broken {
+      conn.execute("DELETE FROM customers WHERE id = ?1", [id])?;
+    }
+    Ok(Self {
+      path: path.to_path_buf(),
+      conn,
+      customers,
+    })
+  }
+  // Size of the database and its write-ahead log
+  fn size(&self) -> ServiceResult<u64> {
+    let mut wal_name = self.path.file_name().unwrap_or_default().to_os_string();
+    wal_name.push("-wal");
+    let mut size = std::fs::metadata(&self.path)?.len();
+    if let Ok(wal) = std::fs::metadata(self.path.with_file_name(wal_name)) {
+      size += wal.len();
+    }
+    Ok(size)
+  }
+}
+
+// Customer record of an SQLite row
+fn encode_row(customer: &Customer) -> ServiceResult<Vec<u8>> {
+  Ok(bincode::serialize(customer).map_err(PackError::from)?)
+}
+
+impl CustomerStore for SqliteStore {
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_> {
+    self.customers.iter()
+  }
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    self.customers.find_id(id)
+  }
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    if self.customers.find_id(&customer.id).is_ok() {
+      return Err(ServiceError::customer_exists(customer.id));
+    }
+    self.conn.execute(
+      "INSERT INTO customers (id, data) VALUES (?1, ?2)",
+      rusqlite::params![customer.id, encode_row(&customer)?],
+    )?;
+    self.customers.insert(customer)
+  }
+  fn update(&mut self, customer: Customer) -> ServiceResult<()> {
+    self.customers.find_id(&customer.id)?;
+    self.conn.execute(
+      "UPDATE customers SET data = ?2 WHERE id = ?1",
+      rusqlite::params![customer.id, encode_row(&customer)?],
+    )?;
+    self.customers.update(customer)
+  }
+  fn write_batch(&mut self, batch: Vec<Customer>) -> ServiceResult<()> {
+    // Dropped without commit on error, so nothing is stored
+    let tx = self.conn.transaction()?;
+    for customer in &batch {
+      tx.execute(
+        "INSERT OR REPLACE INTO customers (id, data) VALUES (?1, ?2)",
+        rusqlite::params![customer.id, encode_row(customer)?],
+      )?;
+    }
+    tx.commit()?;
+    self.customers.write_batch(batch)
+  }
+  fn len(&self) -> usize {
+    self.customers.len()
+  }
+  // Move the write-ahead log into the database, then rebuild it
+  fn compact(&mut self) -> ServiceResult<CompactReport> {
+    let size_before = self.size()?;
+    self
+      .conn
+      .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    self.conn.execute("VACUUM", [])?;
+    self
+      .conn
+      .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(CompactReport {
+      record_count: self.customers.len() as u32,
+      size_before,
+      size_after: self.size()?,
+    })
+  }
+}
+
+// Sharded log storage
+//
+// Customers are split by ID range into logs of shard_size IDs each,
+// named after the first ID of the range, e.g. 0.log, 10000.log.
+// A change appends to the log of its shard only, compaction rewrites
+// the shards one by one, and the shards are loaded in parallel.
+// A batch is written shard by shard, each part as one record. The
+// previous versions of a batch spanning more shards are written to an
+// undo file first, like for VecPack, so the written parts are rolled
+// back if a later one fails, and on load after a crash in the middle.
+// Customers stored in the wrong shard, i.e. after the shard size was
+// changed, are moved to the right ones on load.
+pub struct ShardedStore {
+  dir: PathBuf,
+  shard_size: u32,
+  // Shards by the first ID of their range
+  shards: BTreeMap<u32, LogStore>,
+}
+
+// Called with the unreadable records of every shard
+// Shards are loaded in parallel, so it must be Send
+pub type OnBrokenShard<'a> = &'a mut (dyn FnMut(BrokenRecord) -> ServiceResult<()> + Send);
+
+impl ShardedStore {
+  // Open shards in dir, creates it if it does not exist
+  pub fn open(dir: &Path, shard_size: u32, on_broken: OnBrokenShard) -> ServiceResult<Self> {
+    let old_dir = sibling(dir, "old");
+    // Interrupted resharding, the old shards are complete
+    // until the new ones are swapped in, then they are not needed
+    match (dir.exists(), old_dir.exists()) {
+      (false, true) => std::fs::rename(&old_dir, dir)?,
+      (true, true) => std::fs::remove_dir_all(&old_dir)?,
+      _ => (),
+    }
+    std::fs::create_dir_all(dir)?;
+    let mut store = Self {
+      dir: dir.to_path_buf(),
+      shard_size: shard_size.max(1),
+      shards: open_shards(dir, on_broken)?,
+    };
+    store.recover_batch()?;
+    let misplaced = store
+      .shards
+      .iter()
+      .any(|(first, shard)| shard.iter().any(|c| store.shard_of(c.id) != *first));
+    if !misplaced {
+      return Ok(store);
+    }
+    // New shards are written next to the old ones, then swapped in
+    let tmp_dir = sibling(dir, "reshard");
+    if tmp_dir.exists() {
+      std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    let mut resharded = Self {
+      dir: tmp_dir.clone(),
+      shard_size: store.shard_size,
+      shards: BTreeMap::new(),
+    };
+    // One record per customer, as a batch record is read or dropped as a whole
+    for customer in store.iter() {
+      resharded.insert(customer.clone())?;
+    }
+    drop((store, resharded));
+    std::fs::rename(dir, &old_dir)?;
+    std::fs::rename(&tmp_dir, dir)?;
+    std::fs::remove_dir_all(&old_dir)?;
+    Self::open(dir, shard_size, on_broken)
+  }
+  // Whether there is a sharded storage in dir,
+  // including one left behind by an interrupted resharding
+  pub fn exists(dir: &Path) -> bool {
+    dir.is_dir() || sibling(dir, "old").is_dir()
+  }
+  // Roll back the batch interrupted by a crash
+  fn recover_batch(&mut self) -> ServiceResult<()> {
+    let path = sibling(&self.dir, "batch_undo");
+    if let Some(undo) = read_undo(&path)? {
+      // Customers are put back into the shard holding them,
+      // the shard size may have changed since
+      let mut parts: BTreeMap<u32, Previous> = BTreeMap::new();
+      for (id, previous) in undo {
+        let first = self
+          .shards
+          .iter()
+          .find(|(_, shard)| shard.find_id(&id).is_ok())
+          .map_or(self.shard_of(id), |(first, _)| *first);
+        parts.entry(first).or_default().push((id, previous));
+      }
+      for (first, previous) in parts {
+        self.shard_mut(first)?.restore(previous)?;
+      }
+    }
+    remove_undo(&path)
+  }
+  // First ID of the shard of a customer
+  fn shard_of(&self, id: u32) -> u32 {
+    id / self.shard_size * self.shard_size
+  }
+  // Shard of a customer, the log is created if it does not exist
+  fn shard_mut(&mut self, id: u32) -> ServiceResult<&mut LogStore> {
+    let first = self.shard_of(id);
+    if !self.shards.contains_key(&first) {
+      let shard = LogStore::open(&self.dir.join(format!("{}.log", first)), &mut |_| Ok(()))?;
+      self.shards.insert(first, shard);
+    }
+    Ok(self.shards.get_mut(&first).expect("Shard inserted above"))
+  }
+}
+
+// Path next to dir with a suffix, that is not a valid tenant ID
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+  let mut name = dir.file_name().unwrap_or_default().to_os_string();
+  name.push(".");
+  name.push(suffix);
+  dir.with_file_name(name)
+}
+
+// Open the shard logs of a directory
+// They are split between as many threads as there are CPUs
+fn open_shards(dir: &Path, on_broken: OnBrokenShard) -> ServiceResult<BTreeMap<u32, LogStore>> {
+  let mut paths = Vec::new();
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    // Temp files of the compaction are left out
+    let first = match (path.extension(), path.file_stem()) {
+      (Some(extension), Some(stem)) if extension == "log" => {
+        stem.to_str().and_then(|s| s.parse().ok())
+      }
+      _ => None,
+    };
+    if let Some(first) = first {
+      paths.push((first, path));
+    }
+  }
+  let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+  let chunk_size = paths.len().div_ceil(threads).max(1);
+  let on_broken = std::sync::Mutex::new(on_broken);
+  std::thread::scope(|scope| {
+    let handles = paths
+      .chunks(chunk_size)
+      .map(|chunk| {
+        let on_broken = &on_broken;
+        scope.spawn(move || {
+          let mut on_broken = |record| (*on_broken.lock().unwrap())(record);
+          chunk
+            .iter()
+            .map(|(first, path)| Ok((*first, LogStore::open(path, &mut on_broken)?)))
+            .collect::<ServiceResult<Vec<(u32, LogStore)>>>()
+        })
+      })
+      .collect::<Vec<_>>();
+    let mut shards = BTreeMap::new();
+    for handle in handles {
+      let chunk = handle
+        .join()
+        .unwrap_or_else(|_| Err(ServiceError::internal_error("Shard loading panicked")))?;
+      shards.extend(chunk);
+    }
+    Ok(shards)
+  })
+}
+
+impl CustomerStore for ShardedStore {
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_> {
+    Box::new(self.shards.values().flat_map(|shard| shard.iter()))
+  }
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    match self.shards.get(&self.shard_of(*id)) {
+      Some(shard) => shard.find_id(id),
+      None => Err(ServiceError::customer_not_found(*id)),
+    }
+  }
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    self.shard_mut(customer.id)?.insert(customer)
+  }
+  fn update(&mut self, customer: Customer) -> ServiceResult<()> {
+    match self.shards.get_mut(&self.shard_of(customer.id)) {
+      Some(shard) => shard.update(customer),
+      None => Err(ServiceError::customer_not_found(customer.id)),
+    }
+  }
+  fn write_batch(&mut self, batch: Vec<Customer>) -> ServiceResult<()> {
+    let mut parts: BTreeMap<u32, Vec<Customer>> = BTreeMap::new();
+    for customer in batch {
+      parts
+        .entry(self.shard_of(customer.id))
+        .or_default()
+        .push(customer);
+    }
+    // A single part is one record, stored as a whole or not at all
+    let undo_path = sibling(&self.dir, "batch_undo");
+    if parts.len() > 1 {
+      let undo: Previous = parts
+        .values()
+        .flatten()
+        .map(|c| (c.id, self.find_id(&c.id).ok().cloned()))
+        .collect();
+      write_undo(&undo_path, &undo)?;
+    }
+    // (shard, previous versions of its part)
+    let mut written: Vec<(u32, Previous)> = Vec::new();
+    for (first, part) in parts {
+      let res = self.shard_mut(first).and_then(|shard| {
+        let previous = part
+          .iter()
+          .map(|c| (c.id, shard.find_id(&c.id).ok().cloned()))
+          .collect();
+        shard.write_batch(part).map(|_| previous)
+      });
+      match res {
+        Ok(previous) => written.push((first, previous)),
+        Err(error) => {
+          // Best effort, the original error is returned
+          // The undo file is kept if the rollback fails
+          let mut restored = true;
+          for (first, previous) in written.into_iter().rev() {
+            if let Some(shard) = self.shards.get_mut(&first) {
+              restored &= shard.restore(previous).is_ok();
+            }
+          }
+          if restored {
+            let _ = remove_undo(&undo_path);
+          }
+          return Err(error);
+        }
+      }
+    }
+    remove_undo(&undo_path)
+  }
+  fn len(&self) -> usize {
+    self.shards.values().map(|shard| shard.len()).sum()
+  }
+  fn compact(&mut self) -> ServiceResult<CompactReport> {
+    let mut report = CompactReport {
+      record_count: 0,
+      size_before: 0,
+      size_after: 0,
+    };
+    for shard in self.shards.values_mut() {
+      let shard_report = shard.compact()?;
+      report.record_count += shard_report.record_count;
+      report.size_before += shard_report.size_before;
+      report.size_after += shard_report.size_after;
+    }
+    Ok(report)
+  }
+}
+
+// Lazily loaded log storage
+//
+// Uses the log file of LogStore, so a storage can be switched between
+// the two. Loading checks every record, but decodes only the customer
+// IDs of the single customer records, and keeps the position of the
+// latest record of every customer in memory. Customers are read from
+// the log when they are first asked for, and stay in memory until they
+// change. Customers of batch records are decoded on load, as their
+// positions inside the batch are not known.
+pub struct LazyLogStore {
+  path: PathBuf,
+  // Appends and positioned reads
+  file: File,
+  record_count: usize,
+  slots: BTreeMap<u32, Slot>,
+}
+
+// Latest version of a customer
+struct Slot {
+  // Position and length of the record data in the log,
+  // None for customers of batch records
+  data: Option<(u64, usize)>,
+  customer: OnceLock<Box<Customer>>,
+}
+
+impl Slot {
+  fn new(data: Option<(u64, usize)>, customer: Option<Customer>) -> Self {
+    let cell = OnceLock::new();
+    if let Some(customer) = customer {
+      let _ = cell.set(Box::new(customer));
+    }
+    Self {
+      data,
+      customer: cell,
+    }
+  }
+}
+
+impl LazyLogStore {
+  // Open log, creates it if it does not exist
+  pub fn open(path: &Path, on_broken: OnBroken) -> ServiceResult<Self> {
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let data = match path.exists() {
+      true => std::fs::read(path)?,
+      false => Vec::new(),
+    };
+    let mut slots = BTreeMap::new();
+    let mut record_count = 0;
+    let mut position = 0;
+    let mut broken = false;
+    let mut on_broken = |position: usize, end: usize, reason: &str| {
+      on_broken(BrokenRecord {
+        path: path.to_path_buf(),
+        offset: position as u64,
+        data: data[position..end].to_vec(),
+        reason: reason.to_string(),
+      })
+    };
+    loop {
+      match read_raw_record(&data, position) {
+        RawRecord::Data(record, true, next) => match decode_batch(record) {
+          Some(batch) => {
+            record_count += batch.len();
+            for customer in batch {
+              slots.insert(customer.id, Slot::new(None, Some(customer)));
+            }
+            position = next;
+          }
+          None => {
+            on_broken(position, next, "Cannot deserialize customer batch")?;
+            broken = true;
+            position = next;
+          }
+        },
+        RawRecord::Data(record, false, next) => match record_id(record) {
+          Some(id) => {
+            let start = (position + RECORD_HEADER_SIZE) as u64;
+            slots.insert(id, Slot::new(Some((start, record.len())), None));
+            record_count += 1;
+            position = next;
+          }
+          None => {
+            on_broken(position, next, "Cannot deserialize customer")?;
+            broken = true;
+            position = next;
+          }
+        },
+        RawRecord::Broken(next, reason) => {
+          on_broken(position, next, reason)?;
+          broken = true;
+          position = next;
+        }
+        RawRecord::End => break,
+      }
+    }
+    let file = OpenOptions::new()
+      .create(true)
+      .read(true)
+      .append(true)
+      .open(path)?;
+    // Drop torn record
+    if position < data.len() {
+      on_broken(position, data.len(), "Torn record at the end of the log")?;
+      file.set_len(position as u64)?;
+      file.sync_data()?;
+    }
+    let mut store = Self {
+      path: path.to_path_buf(),
+      file,
+      record_count,
+      slots,
+    };
+    if broken || store.record_count > 2 * store.slots.len() {
+      store.compact()?;
+    }
+    Ok(store)
+  }
+  // Read customer from the log, without keeping it in memory
+  fn read(&self, id: u32, slot: &Slot) -> ServiceResult<Customer> {
+    let (position, len) = slot
+      .data
+      .ok_or_else(|| ServiceError::internal_error(&format!("Customer {} is not in the log", id)))?;
+    let mut data = vec![0; len];
+    self.file.read_exact_at(&mut data, position)?;
+    decode_customer(&data).ok_or_else(|| {
+      ServiceError::internal_error(&format!("Cannot deserialize customer {} of the log", id))
+    })
+  }
+  // Customer of a slot, read from the log on first use
+  fn load<'a>(&self, id: u32, slot: &'a Slot) -> ServiceResult<&'a Customer> {
+    if let Some(customer) = slot.customer.get() {
+      return Ok(customer);
+    }
+    let customer = self.read(id, slot)?;
+    Ok(slot.customer.get_or_init(|| Box::new(customer)))
+  }
+  // Append single customer record, the customer is kept in memory
+  fn append(&mut self, customer: Customer) -> ServiceResult<()> {
+    let record = encode_record(&customer)?;
+    let position = append_record(&mut self.file, &record)?;
+    let data = (
+      position + RECORD_HEADER_SIZE as u64,
+      record.len() - RECORD_HEADER_SIZE,
+    );
+    self.record_count += 1;
+    self
+      .slots
+      .insert(customer.id, Slot::new(Some(data), Some(customer)));
+    Ok(())
+  }
+}
+
+impl CustomerStore for LazyLogStore {
+  // Unreadable customers are reported and skipped
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_> {
+    Box::new(
+      self
+        .slots
+        .iter()
+        .filter_map(move |(id, slot)| match self.load(*id, slot) {
+          Ok(customer) => Some(customer),
+          Err(error) => {
+            eprintln!("Error while reading customer {}: {}", id, error);
+            None
+          }
+        }),
+    )
+  }
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    let slot = self
+      .slots
+      .get(id)
+      .ok_or_else(|| ServiceError::customer_not_found(*id))?;
+    self.load(*id, slot)
+  }
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    if self.slots.contains_key(&customer.id) {
+      return Err(ServiceError::customer_exists(customer.id));
+    }
+    self.append(customer)
+  }
+  fn update(&mut self, customer: Customer) -> ServiceResult<()> {
+    if !self.slots.contains_key(&customer.id) {
+      return Err(ServiceError::customer_not_found(customer.id));
+    }
+    self.append(customer)
+  }
+  fn write_batch(&mut self, mut batch: Vec<Customer>) -> ServiceResult<()> {
+    if batch.len() == 1 {
+      return self.append(batch.remove(0));
+    }
+    append_record(&mut self.file, &encode_batch_record(&batch)?)?;
+    self.record_count += batch.len();
+    for customer in batch {
+      self
+        .slots
+        .insert(customer.id, Slot::new(None, Some(customer)));
+    }
+    Ok(())
+  }
+  fn len(&self) -> usize {
+    self.slots.len()
+  }
+  // Rewrite the log next to the current one, then atomically
+  // replace it. Records are copied as they are, customers of
+  // batch records get a record of their own.
+  fn compact(&mut self) -> ServiceResult<CompactReport> {
+    let size_before = std::fs::metadata(&self.path)?.len();
+    let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push("_compact");
+    let tmp_path = self.path.with_file_name(tmp_name);
+    let mut tmp = File::create(&tmp_path)?;
+    let mut positions = Vec::with_capacity(self.slots.len());
+    let mut position = 0;
+    for (id, slot) in &self.slots {
+      let record = match (slot.data, slot.customer.get()) {
+        (Some((start, len)), _) => {
+          let mut record = vec![0; RECORD_HEADER_SIZE + len];
+          self
+            .file
+            .read_exact_at(&mut record, start - RECORD_HEADER_SIZE as u64)?;
+          record
+        }
+        (None, Some(customer)) => encode_record(customer)?,
+        (None, None) => encode_record(&self.read(*id, slot)?)?,
+      };
+      tmp.write_all(&record)?;
+      positions.push((
+        *id,
+        (
+          position + RECORD_HEADER_SIZE as u64,
+          record.len() - RECORD_HEADER_SIZE,
+        ),
+      ));
+      position += record.len() as u64;
+    }
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, &self.path)?;
+    self.file = OpenOptions::new()
+      .read(true)
+      .append(true)
+      .open(&self.path)?;
+    for (id, data) in positions {
+      if let Some(slot) = self.slots.get_mut(&id) {
+        slot.data = Some(data);
+      }
+    }
+    self.record_count = self.slots.len();
+    Ok(CompactReport {
+      record_count: self.record_count as u32,
+      size_before,
+      size_after: std::fs::metadata(&self.path)?.len(),
+    })
+  }
+  // The log is read at once, instead of a read per customer
+  fn scan(&self, f: &mut dyn FnMut(&Customer)) {
+    let log = match std::fs::read(&self.path) {
+      Ok(log) => log,
+      Err(error) => {
+        eprintln!("Error while reading {}: {}", self.path.display(), error);
+        return;
+      }
+    };
+    for (id, slot) in &self.slots {
+      if let Some(customer) = slot.customer.get() {
+        f(customer);
+        continue;
+      }
+      let customer = slot
+        .data
+        .and_then(|(position, len)| log.get(position as usize..position as usize + len))
+        .and_then(decode_customer);
+      match customer {
+        Some(customer) => f(&customer),
+        None => eprintln!("Error while reading customer {}", id),
+      }
+    }
+  }
+}
+
+// Shadow storage for backend migrations
+//
+// Every write is stored in the primary storage, then in the shadow
+// one, reads are served by the primary storage and compared with the
+// shadow one. Failed shadow writes and differing reads are logged as
+// divergences, the primary storage is not affected by them.
+pub struct ShadowStore {
+  tenant: String,
+  primary: Box<dyn CustomerStore>,
+  shadow: Box<dyn CustomerStore>,
+  divergences: AtomicU64,
+}
+
+impl ShadowStore {
+  // An empty shadow storage is filled from the primary one
+  pub fn new(
+    tenant: &str,
+    primary: Box<dyn CustomerStore>,
+    mut shadow: Box<dyn CustomerStore>,
+  ) -> ServiceResult<Self> {
+    if shadow.len() == 0 && primary.len() > 0 {
+      for customer in primary.iter() {
+        shadow.insert(customer.clone())?;
+      }
+      println!(
+        "Shadow storage of tenant '{}' filled with {} customers",
+        tenant,
+        shadow.len()
+      );
+    }
+    let store = Self {
+      tenant: tenant.to_string(),
+      primary,
+      shadow,
+      divergences: AtomicU64::new(0),
+    };
+    if store.primary.len() != store.shadow.len() {
+      store.diverged(
+        0,
+        &format!(
+          "{} customers in primary, {} in shadow storage",
+          store.primary.len(),
+          store.shadow.len()
+        ),
+      );
+    }
+    Ok(store)
+  }
+  fn diverged(&self, id: u32, reason: &str) {
+    self.divergences.fetch_add(1, Ordering::Relaxed);
+    eprintln!(
+      "Shadow storage divergence in tenant '{}', customer {}: {}",
+      self.tenant, id, reason
+    );
+  }
+  // Compare a customer read from the primary storage with the shadow one
+  fn compare(&self, customer: &Customer) {
+    match self.shadow.find_id(&customer.id) {
+      Ok(shadow) => {
+        if bincode::serialize(shadow).ok() != bincode::serialize(customer).ok() {
+          let fields = crate::history::diff(Some(shadow), customer)
+            .map(|d| d.into_iter().map(|d| d.path).collect::<Vec<String>>())
+            .unwrap_or_default();
+          self.diverged(
+            customer.id,
+            &match fields.is_empty() {
+              true => "modification data differs".to_string(),
+              false => format!("differs in {}", fields.join(", ")),
+            },
+          );
+        }
+      }
+      Err(_) => self.diverged(customer.id, "missing from shadow storage"),
+    }
+  }
+  fn shadow_write(&self, id: u32, res: ServiceResult<()>) {
+    if let Err(error) = res {
+      self.diverged(id, &format!("shadow write failed: {}", error));
+    }
+  }
+}
+
+impl CustomerStore for ShadowStore {
+  fn iter(&self) -> Box<dyn Iterator<Item = &Customer> + '_> {
+    Box::new(self.primary.iter().inspect(move |c| self.compare(c)))
+  }
+  fn find_id(&self, id: &u32) -> ServiceResult<&Customer> {
+    match self.primary.find_id(id) {
+      Ok(customer) => {
+        self.compare(customer);
+        Ok(customer)
+      }
+      Err(error) => {
+        if self.shadow.find_id(id).is_ok() {
+          self.diverged(*id, "missing from primary storage");
+        }
+        Err(error)
+      }
+    }
+  }
+  fn insert(&mut self, customer: Customer) -> ServiceResult<()> {
+    let id = customer.id;
+    self.primary.insert(customer.clone())?;
+    let res = self.shadow.insert(customer);
+    self.shadow_write(id, res);
+    Ok(())
+  }
+  fn update(&mut self, customer: Customer) -> ServiceResult<()> {
+    let id = customer.id;
+    self.primary.update(customer.clone())?;
+    let res = self.shadow.update(customer);
+    self.shadow_write(id, res);
+    Ok(())
+  }
+  fn write_batch(&mut self, batch: Vec<Customer>) -> ServiceResult<()> {
+    self.primary.write_batch(batch.clone())?;
+    let id = batch.first().map(|c| c.id).unwrap_or_default();
+    let res = self.shadow.write_batch(batch);
+    self.shadow_write(id, res);
+    Ok(())
+  }
+  fn len(&self) -> usize {
+    self.primary.len()
+  }
+  fn compact(&mut self) -> ServiceResult<CompactReport> {
+    let report = self.primary.compact()?;
+    if let Err(error) = self.shadow.compact() {
+      eprintln!(
+        "Error while compacting shadow storage of tenant '{}': {}",
+        self.tenant, error
+      );
+    }
+    Ok(report)
+  }
+  fn shadow_divergences(&self) -> Option<u64> {
+    Some(self.divergences.load(Ordering::Relaxed))
+  }
+  // Index builds are not compared
+  fn scan(&self, f: &mut dyn FnMut(&Customer)) {
+    self.primary.scan(f)
+  }
+}
+
+fn encode_record(customer: &Customer) -> ServiceResult<Vec<u8>> {
+  let data = bincode::serialize(customer).map_err(PackError::from)?;
+  Ok(with_header(data, 0))
+}
+
+fn encode_batch_record(batch: &[Customer]) -> ServiceResult<Vec<u8>> {
+  let data = bincode::serialize(batch).map_err(PackError::from)?;
+  Ok(with_header(data, BATCH_FLAG))
+}
+
+pub(crate) fn with_header(data: Vec<u8>, flags: u32) -> Vec<u8> {
+  let mut crc = flate2::Crc::new();
+  crc.update(&data);
+  let mut res = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+  res.extend_from_slice(&(data.len() as u32 | flags).to_le_bytes());
+  res.extend_from_slice(&crc.sum().to_le_bytes());
+  res.extend_from_slice(&data);
+  res
+}
+
+// Log record read at a position
+enum Record {
+  // Customers of the record and the position of the next record
+  Customers(Vec<Customer>, usize),
+  // Position of the next record and the reason
+  Broken(usize, &'static str),
+  // End of the log, or a torn last record
+  End,
+}
+
+fn read_record(log: &[u8], position: usize) -> Record {
+  match read_raw_record(log, position) {
+    RawRecord::Data(data, true, next) => match decode_batch(data) {
+      Some(batch) => Record::Customers(batch, next),
+      None => Record::Broken(next, "Cannot deserialize customer batch"),
+    },
+    RawRecord::Data(data, false, next) => match decode_customer(data) {
+      Some(customer) => Record::Customers(vec![customer], next),
+      None => Record::Broken(next, "Cannot deserialize customer"),
+    },
+    RawRecord::Broken(next, reason) => Record::Broken(next, reason),
+    RawRecord::End => Record::End,
+  }
+}
+
+// Log record with a checked checksum, not decoded yet
+pub(crate) enum RawRecord<'a> {
+  // Record data, batch flag and the position of the next record
+  Data(&'a [u8], bool, usize),
+  // Position of the next record and the reason
+  Broken(usize, &'static str),
+  // End of the log, or a torn last record
+  End,
+}
+
+pub(crate) fn read_raw_record(log: &[u8], position: usize) -> RawRecord<'_> {
+  let header = match log.get(position..position + RECORD_HEADER_SIZE) {
+    Some(header) => header,
+    None => return RawRecord::End,
+  };
+  let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+  let batch = len & BATCH_FLAG != 0;
+  let len = (len & !BATCH_FLAG) as usize;
+  let sum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+  let start = position + RECORD_HEADER_SIZE;
+  let data = match log.get(start..start + len) {
+    Some(data) => data,
+    None => return RawRecord::End,
+  };
+  let mut crc = flate2::Crc::new();
+  crc.update(data);
+  if crc.sum() != sum {
+    // Only the last record can be torn
+    return match start + len == log.len() {
+      true => RawRecord::End,
+      false => RawRecord::Broken(start + len, "Checksum mismatch"),
+    };
+  }
+  RawRecord::Data(data, batch, start + len)
+}
+
+// Every customer of a batch has its own schema version
+fn decode_batch(data: &[u8]) -> Option<Vec<Customer>> {
+  bincode::deserialize::<Vec<Customer>>(data)
+    .ok()
+    .or_else(|| decode_unversioned_batch(data))
+}
+
+// Records written without schema version are decoded
+// the same way as VecPack does
+fn decode_customer(data: &[u8]) -> Option<Customer> {
+  bincode::deserialize::<Customer>(data)
+    .ok()
+    .or_else(|| decode_unversioned(data))
+}
+
+// Compact VecPack storage
+//
+// Packfiles keep the latest and the backup version of the data,
+// and grow when an updated record needs more space. Here we rewrite
+// every record into a fresh packfile, then atomically replace the
+// original one. The new file is created next to the VecPack directory,
+// as every file inside of it is loaded as a record.
+pub fn compact<T>(pack: &VecPack<T>) -> ServiceResult<CompactReport>
+where
+  for<'de> T: VecPackMember + Deserialize<'de> + Default,
+{
+  let dir = pack.get_path();
+  let tmp_dir = compact_tmp_dir(dir);
+  std::fs::create_dir_all(&tmp_dir)?;
+  let mut report = CompactReport {
+    record_count: 0,
+    size_before: 0,
+    size_after: 0,
+  };
+  for item in pack.iter() {
+    let file_name = item.get_id().to_string();
+    let path = dir.join(&file_name);
+    let tmp_path = tmp_dir.join(&file_name);
+    report.size_before += std::fs::metadata(&path)?.len();
+    // Remove leftover from a previous broken run
+    if tmp_path.exists() {
+      std::fs::remove_file(&tmp_path)?;
+    }
+    PackFile::init(&tmp_path, 0, None, None, None)?;
+    PackFile::open(&tmp_path)?
+      .write_data(&bincode::serialize(item.unpack()).map_err(PackError::from)?)?;
+    std::fs::rename(&tmp_path, &path)?;
+    report.size_after += std::fs::metadata(&path)?.len();
+    report.record_count += 1;
+  }
+  std::fs::remove_dir(&tmp_dir)?;
+  Ok(report)
+}
+
+// Temp dir to build compacted packfiles in
+// e.g. data/customers -> data/customers_compact
+fn compact_tmp_dir(dir: &Path) -> PathBuf {
+  let mut name = dir.file_name().unwrap_or_default().to_os_string();
+  name.push("_compact");
+  dir.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::CustomerKind;
+  use crate::policy::ValidationPolicy;
+
+  fn customer(id: u32, name: &str) -> Customer {
+    Customer::new(
+      id,
+      name.to_string(),
+      "".to_string(),
+      "".to_string(),
+      None,
+      "".to_string(),
+      "".to_string(),
+      "".to_string(),
+      "HU".to_string(),
+      None,
+      1,
+      CustomerKind::Private,
+      false,
+      None,
+      &ValidationPolicy::default(),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_compact() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers");
+    let mut pack: VecPack<Customer> = VecPack::new(path.clone()).unwrap();
+    pack.insert(customer(1, "Kiss Béla")).unwrap();
+    pack.insert(customer(2, "Nagy Anna")).unwrap();
+    // Grow the first record, so its packfile grows
+    pack
+      .find_id_mut(&1)
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .address_street = "x".repeat(4096);
+    pack
+      .find_id_mut(&1)
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .address_street = "y".repeat(8192);
+
+    let report = compact(&pack).unwrap();
+    assert_eq!(report.record_count, 2);
+    assert!(report.size_after < report.size_before);
+    assert!(!compact_tmp_dir(&path).exists());
+
+    // Compacted files load back with the same content
+    let loaded: VecPack<Customer> = load_vecpack(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(
+      loaded.find_id(&1).unwrap().unpack().address_street,
+      "y".repeat(8192)
+    );
+  }
+
+  #[test]
+  fn test_memory_store() {
+    let mut store = MemoryStore::new();
+    assert_eq!(store.len(), 0);
+    store.insert(customer(2, "Nagy Anna")).unwrap();
+    store.insert(customer(1, "Kiss Béla")).unwrap();
+    assert!(store.insert(customer(1, "Kiss Béla")).is_err());
+    let mut c = store.find_id(&1).unwrap().clone();
+    c.name = "Kiss Péter".to_string();
+    store.update(c).unwrap();
+    assert_eq!(store.find_id(&1).unwrap().name, "Kiss Péter");
+    assert!(store.update(customer(3, "Tóth Ede")).is_err());
+    assert!(store.find_id(&3).is_err());
+    assert_eq!(store.iter().map(|c| c.id).collect::<Vec<u32>>(), [1, 2]);
+    assert_eq!(store.compact().unwrap().record_count, 2);
+  }
+
+  #[test]
+  fn test_log_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers.log");
+    let mut store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    store.insert(customer(1, "Kiss Béla")).unwrap();
+    store.insert(customer(2, "Nagy Anna")).unwrap();
+    assert!(store.insert(customer(1, "Kiss Béla")).is_err());
+    let mut c = store.find_id(&1).unwrap().clone();
+    c.name = "Kiss Péter".to_string();
+    store.update(c).unwrap();
+    assert!(store.update(customer(3, "Tóth Ede")).is_err());
+    drop(store);
+
+    // Torn record at the end is dropped
+    let size = std::fs::metadata(&path).unwrap().len();
+    OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap()
+      .write_all(&encode_record(&customer(3, "Tóth Ede")).unwrap()[..20])
+      .unwrap();
+    let mut store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.find_id(&1).unwrap().name, "Kiss Péter");
+    assert_eq!(store.record_count, 3);
+
+    let report = store.compact().unwrap();
+    assert_eq!(report.record_count, 2);
+    assert!(report.size_after < report.size_before);
+    store.insert(customer(3, "Tóth Ede")).unwrap();
+    drop(store);
+    let store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(
+      store.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(),
+      ["Kiss Péter", "Nagy Anna", "Tóth Ede"]
+    );
+  }
+
+  #[test]
+  fn test_sqlite_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers.sqlite");
+    let mut store = SqliteStore::open(&path, &mut |_| Ok(())).unwrap();
+    store.insert(customer(1, "Kiss Béla")).unwrap();
+    store.insert(customer(2, "Nagy Anna")).unwrap();
+    assert!(store.insert(customer(1, "Kiss Béla")).is_err());
+    let mut c = store.find_id(&1).unwrap().clone();
+    c.name = "Kiss Péter".to_string();
+    store.update(c).unwrap();
+    assert!(store.update(customer(3, "Tóth Ede")).is_err());
+    store
+      .write_batch(vec![customer(2, "Nagy Éva"), customer(3, "Tóth Ede")])
+      .unwrap();
+    drop(store);
+    let mut store = SqliteStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(
+      store.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(),
+      ["Kiss Péter", "Nagy Éva", "Tóth Ede"]
+    );
+    assert_eq!(store.compact().unwrap().record_count, 3);
+
+    // Undecodable rows are handed over and deleted
+    store
+      .conn
+      .execute("UPDATE customers SET data = x'00' WHERE id = 2", [])
+      .unwrap();
+    drop(store);
+    let mut broken = Vec::new();
+    let store = SqliteStore::open(&path, &mut |r| {
+      broken.push(r);
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(store.iter().map(|c| c.id).collect::<Vec<u32>>(), [1, 3]);
+    assert_eq!((broken.len(), broken[0].offset), (1, 2));
+    drop(store);
+    let store = SqliteStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(store.len(), 2);
+  }
+
+  #[test]
+  fn test_sharded_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers_shards");
+    let files = || {
+      let mut files = std::fs::read_dir(&path)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<String>>();
+      files.sort();
+      files
+    };
+    let mut store = ShardedStore::open(&path, 10, &mut |_| Ok(())).unwrap();
+    store.insert(customer(1, "Kiss Béla")).unwrap();
+    store.insert(customer(12, "Nagy Anna")).unwrap();
+    assert!(store.insert(customer(1, "Kiss Béla")).is_err());
+    assert!(store.find_id(&15).is_err());
+    assert!(store.update(customer(35, "Tóth Ede")).is_err());
+    store
+      .write_batch(vec![customer(1, "Kiss Péter"), customer(25, "Tóth Ede")])
+      .unwrap();
+    assert_eq!(files(), ["0.log", "10.log", "20.log"]);
+    drop(store);
+    let mut store = ShardedStore::open(&path, 10, &mut |_| Ok(())).unwrap();
+    assert_eq!(store.len(), 3);
+    assert_eq!(
+      store.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(),
+      ["Kiss Péter", "Nagy Anna", "Tóth Ede"]
+    );
+    assert_eq!(store.compact().unwrap().record_count, 3);
+    drop(store);
+
+    // Customers are moved to the shards of the new size
+    let store = ShardedStore::open(&path, 5, &mut |_| Ok(())).unwrap();
+    assert_eq!(files(), ["0.log", "10.log", "25.log"]);
+    let data = std::fs::read(path.join("10.log")).unwrap();
+    assert!(matches!(
+      read_raw_record(&data, 0),
+      RawRecord::Data(_, false, next) if next == data.len()
+    ));
+    assert_eq!(store.find_id(&25).unwrap().name, "Tóth Ede");
+    assert!(!sibling(&path, "old").exists());
+    drop(store);
+
+    // Interrupted resharding keeps the old shards
+    std::fs::rename(&path, sibling(&path, "old")).unwrap();
+    assert!(ShardedStore::exists(&path));
+    let store = ShardedStore::open(&path, 5, &mut |_| Ok(())).unwrap();
+    assert_eq!(store.len(), 3);
+    drop(store);
+
+    // Old shards left behind after the new ones were swapped in are removed
+    std::fs::create_dir(sibling(&path, "old")).unwrap();
+    std::fs::write(sibling(&path, "old").join("0.log"), b"").unwrap();
+    let store = ShardedStore::open(&path, 5, &mut |_| Ok(())).unwrap();
+    assert!(!sibling(&path, "old").exists());
+    assert_eq!(store.len(), 3);
+  }
+
+  #[test]
+  fn test_sharded_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers_shards");
+    let undo_path = sibling(&path, "batch_undo");
+    let mut store = ShardedStore::open(&path, 10, &mut |_| Ok(())).unwrap();
+    store.insert(customer(1, "Kiss Béla")).unwrap();
+    store
+      .write_batch(vec![customer(1, "Kiss Péter"), customer(12, "Nagy Anna")])
+      .unwrap();
+    assert!(!undo_path.exists());
+    // Crash after the first part of a batch spanning two shards
+    write_undo(
+      &undo_path,
+      &vec![(1, Some(customer(1, "Kiss Péter"))), (25, None)],
+    )
+    .unwrap();
+    store.update(customer(1, "Kiss Ádám")).unwrap();
+    drop(store);
+    // The written part is rolled back on load
+    let store = ShardedStore::open(&path, 10, &mut |_| Ok(())).unwrap();
+    assert!(!undo_path.exists());
+    assert_eq!(store.find_id(&1).unwrap().name, "Kiss Péter");
+    assert!(store.find_id(&25).is_err());
+    assert_eq!(store.len(), 2);
+    drop(store);
+    // Torn undo file was written before any part
+    std::fs::write(&undo_path, b"torn").unwrap();
+    let store = ShardedStore::open(&path, 10, &mut |_| Ok(())).unwrap();
+    assert!(!undo_path.exists());
+    assert_eq!(store.len(), 2);
+  }
+
+  #[test]
+  fn test_write_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers.log");
+    let mut store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    store.insert(customer(1, "Kiss Béla")).unwrap();
+    store
+      .write_batch(vec![
+        customer(1, "Kiss Péter"),
+        customer(2, "Nagy Anna"),
+        customer(3, "Tóth Ede"),
+      ])
+      .unwrap();
+    assert_eq!(store.record_count, 4);
+    drop(store);
+    let store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(
+      store.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(),
+      ["Kiss Péter", "Nagy Anna", "Tóth Ede"]
+    );
+    drop(store);
+
+    // Torn batch is dropped as a whole
+    let size = std::fs::metadata(&path).unwrap().len();
+    let batch =
+      encode_batch_record(&[customer(4, "Szabó Éva"), customer(5, "Kovács Ádám")]).unwrap();
+    OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap()
+      .write_all(&batch[..batch.len() - 10])
+      .unwrap();
+    let store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    assert_eq!(store.len(), 3);
+
+    // VecPack storage
+    let mut pack: VecPack<Customer> = VecPack::new(dir.path().join("customers")).unwrap();
+    pack.insert(customer(1, "Kiss Béla")).unwrap();
+    CustomerStore::write_batch(
+      &mut pack,
+      vec![customer(1, "Kiss Péter"), customer(2, "Nagy Anna")],
+    )
+    .unwrap();
+    assert_eq!(
+      CustomerStore::find_id(&pack, &1).unwrap().name,
+      "Kiss Péter"
+    );
+    assert_eq!(pack.len(), 2);
+    let undo_path = undo_path(&dir.path().join("customers"));
+    assert!(!undo_path.exists());
+
+    // Crash in the middle of a batch changing 1 and adding 3
+    write_undo(
+      &undo_path,
+      &vec![(1, Some(customer(1, "Kiss Péter"))), (3, None)],
+    )
+    .unwrap();
+    CustomerStore::update(&mut pack, customer(1, "Kiss Ádám")).unwrap();
+    CustomerStore::insert(&mut pack, customer(3, "Tóth Ede")).unwrap();
+    drop(pack);
+    let pack = load_vecpack(&dir.path().join("customers"), &mut |_| Ok(())).unwrap();
+    assert_eq!(
+      CustomerStore::find_id(&pack, &1).unwrap().name,
+      "Kiss Péter"
+    );
+    assert!(CustomerStore::find_id(&pack, &3).is_err());
+    assert!(!dir.path().join("customers/3").exists());
+    assert!(!undo_path.exists());
+    drop(pack);
+    // Torn undo file, the packfiles were not changed yet
+    std::fs::write(&undo_path, b"torn").unwrap();
+    let pack = load_vecpack(&dir.path().join("customers"), &mut |_| Ok(())).unwrap();
+    assert_eq!(pack.len(), 2);
+    assert!(!undo_path.exists());
+  }
+
+  #[test]
+  fn test_lazy_log_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers.log");
+    let mut store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    store.insert(customer(1, "Kiss Béla")).unwrap();
+    store
+      .write_batch(vec![customer(2, "Nagy Anna"), customer(3, "Tóth Ede")])
+      .unwrap();
+    drop(store);
+
+    // Opens the log of LogStore, single records are not read yet
+    let mut store = LazyLogStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(store.len(), 3);
+    assert!(store.slots[&1].customer.get().is_none());
+    assert!(store.slots[&2].customer.get().is_some());
+    let mut names = Vec::new();
+    store.scan(&mut |c| names.push(c.name.clone()));
+    assert_eq!(names, ["Kiss Béla", "Nagy Anna", "Tóth Ede"]);
+    assert!(store.slots[&1].customer.get().is_none());
+    let mut c = store.find_id(&1).unwrap().clone();
+    assert!(store.slots[&1].customer.get().is_some());
+    c.name = "Kiss Péter".to_string();
+    store.update(c).unwrap();
+    assert!(store.insert(customer(2, "Nagy Anna")).is_err());
+    assert!(store.update(customer(4, "Szabó Éva")).is_err());
+    assert!(store.find_id(&4).is_err());
+    drop(store);
+
+    // Torn record at the end is dropped
+    let size = std::fs::metadata(&path).unwrap().len();
+    OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap()
+      .write_all(&encode_record(&customer(4, "Szabó Éva")).unwrap()[..20])
+      .unwrap();
+    let mut store = LazyLogStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    assert_eq!(store.find_id(&1).unwrap().name, "Kiss Péter");
+    assert_eq!(store.record_count, 4);
+
+    // Batch customers get records of their own
+    let report = store.compact().unwrap();
+    assert_eq!(report.record_count, 3);
+    assert!(store.slots.values().all(|s| s.data.is_some()));
+    assert_eq!(store.find_id(&3).unwrap().name, "Tóth Ede");
+    store.insert(customer(4, "Szabó Éva")).unwrap();
+    drop(store);
+    let store = LazyLogStore::open(&path, &mut |_| Ok(())).unwrap();
+    assert!(store.slots.values().all(|s| s.customer.get().is_none()));
+    assert_eq!(
+      store.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(),
+      ["Kiss Péter", "Nagy Anna", "Tóth Ede", "Szabó Éva"]
+    );
+    // LogStore reads the lazy written log
+    drop(store);
+    assert_eq!(LogStore::open(&path, &mut |_| Ok(())).unwrap().len(), 4);
+  }
+
+  #[test]
+  fn test_shadow_store() {
+    let mut primary = MemoryStore::new();
+    primary.insert(customer(1, "Kiss Béla")).unwrap();
+    let mut store = ShadowStore::new("", Box::new(primary), Box::new(MemoryStore::new())).unwrap();
+    // Empty shadow storage is filled
+    assert_eq!(store.shadow.len(), 1);
+    store.insert(customer(2, "Nagy Anna")).unwrap();
+    let mut c = store.find_id(&2).unwrap().clone();
+    c.name = "Nagy Éva".to_string();
+    store.update(c).unwrap();
+    assert_eq!(store.shadow.find_id(&2).unwrap().name, "Nagy Éva");
+    assert_eq!(store.shadow_divergences(), Some(0));
+    // Differing read
+    store.shadow.update(customer(1, "Kiss Péter")).unwrap();
+    assert_eq!(store.find_id(&1).unwrap().name, "Kiss Béla");
+    assert_eq!(store.shadow_divergences(), Some(1));
+    // Missing from shadow
+    store.primary.insert(customer(3, "Tóth Ede")).unwrap();
+    assert_eq!(store.iter().count(), 3);
+    assert_eq!(store.shadow_divergences(), Some(3));
+    // Failed shadow write, the primary one is kept
+    store.shadow.insert(customer(4, "Szabó Éva")).unwrap();
+    store.insert(customer(4, "Szabó Éva")).unwrap();
+    assert_eq!(store.shadow_divergences(), Some(4));
+    assert!(store.find_id(&5).is_err());
+    // Index builds are not compared
+    store.scan(&mut |_| ());
+    assert_eq!(store.shadow_divergences(), Some(4));
+    // Shadow storage with other customer count
+    let mut shadow = MemoryStore::new();
+    shadow.insert(customer(1, "Kiss Béla")).unwrap();
+    let store = ShadowStore::new("", store.primary, Box::new(shadow)).unwrap();
+    assert_eq!(store.shadow_divergences(), Some(1));
+  }
+
+  #[test]
+  fn test_store_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let stores: Vec<Box<dyn CustomerStore>> = vec![
+      Box::new(VecPack::<Customer>::new(dir.path().join("customers")).unwrap()),
+      Box::new(MemoryStore::new()),
+      Box::new(LogStore::open(&dir.path().join("customers.log"), &mut |_| Ok(())).unwrap()),
+      Box::new(LazyLogStore::open(&dir.path().join("lazy.log"), &mut |_| Ok(())).unwrap()),
+      Box::new(SqliteStore::open(&dir.path().join("customers.sqlite"), &mut |_| Ok(())).unwrap()),
+    ];
+    for mut store in stores {
+      store.insert(customer(1, "Kiss Béla")).unwrap();
+      // Missing IDs are not found, taken ones conflict
+      let code = |res: ServiceResult<()>| res.err().map(|e| e.code());
+      assert_eq!(
+        code(store.find_id(&2).map(|_| ())),
+        Some(::tonic::Code::NotFound)
+      );
+      assert_eq!(
+        code(store.update(customer(2, "Nagy Anna"))),
+        Some(::tonic::Code::NotFound)
+      );
+      match store.insert(customer(1, "Kiss Béla")) {
+        Err(ServiceError::Conflict(_, customer_id)) => assert_eq!(customer_id, 1),
+        _ => panic!("Taken ID is not a conflict"),
+      }
+    }
+  }
+
+  #[test]
+  fn test_log_store_broken() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers.log");
+    let mut store = LogStore::open(&path, &mut |_| Ok(())).unwrap();
+    let first = customer(1, "Kiss Béla");
+    let first_len = encode_record(&first).unwrap().len();
+    store.insert(first).unwrap();
+    store.insert(customer(2, "Nagy Anna")).unwrap();
+    drop(store);
+    // Broken record inside the log is skipped
+    let mut data = std::fs::read(&path).unwrap();
+    data[RECORD_HEADER_SIZE + 2] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    let mut broken = Vec::new();
+    let store = LogStore::open(&path, &mut |r| {
+      broken.push(r);
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(store.iter().map(|c| c.id).collect::<Vec<u32>>(), [2]);
+    assert_eq!(broken.len(), 1);
+    assert_eq!(broken[0].offset, 0);
+    assert_eq!(broken[0].data, data[..first_len]);
+    drop(store);
+    // Log is compacted without the broken record
+    assert_eq!(std::fs::read(&path).unwrap(), data[first_len..]);
+    // Loading fails if the broken record cannot be handed over
+    std::fs::write(&path, &data).unwrap();
+    assert!(LogStore::open(&path, &mut |_| Err(ServiceError::internal_error("x"))).is_err());
+  }
+
+  #[test]
+  fn test_load_vecpack() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("customers");
+    let mut pack: VecPack<Customer> = VecPack::new(path.clone()).unwrap();
+    pack.insert(customer(1, "Kiss Béla")).unwrap();
+    pack.insert(customer(2, "Nagy Anna")).unwrap();
+    drop(pack);
+    std::fs::write(path.join("2"), b"broken").unwrap();
+    // Written before schema versions
+    let old = crate::customer::CustomerOld {
+      id: 3,
+      name: "Tóth Ede".to_string(),
+      ..Default::default()
+    };
+    PackFile::open_or_init(&path.join("3"), 0, None, None, None)
+      .unwrap()
+      .write_data(&bincode::serialize(&old).unwrap())
+      .unwrap();
+    assert!(read_customer_file(&path.join("3")).unwrap().1);
+    let mut broken = Vec::new();
+    let pack = load_vecpack(&path, &mut |r| {
+      broken.push(r);
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(pack.len(), 2);
+    assert_eq!(broken.len(), 1);
+    assert_eq!(broken[0].path, path.join("2"));
+    assert_eq!(broken[0].data, b"broken");
+    assert!(!path.join("2").exists());
+    // Stored again with schema version
+    let (customer, unversioned) = read_customer_file(&path.join("3")).unwrap();
+    assert_eq!(customer.name, "Tóth Ede");
+    assert!(!unversioned);
+  }
+}
@@ -0,0 +1,167 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Tax number change guard
+//
+// Invoices keep the tax number they were issued with, so changing the
+// tax number of an invoiced customer is legally sensitive. With the
+// guard on, such changes are rejected unless they are forced, and
+// forced changes are written to the audit trail. A customer counts as
+// invoiced if an invoice activity was recorded for it, or, with an
+// invoice service endpoint, if the invoice service has invoices for
+// it. Setting the first tax number of a customer is not guarded.
+
+use crate::customer::Customer;
+use crate::prelude::*;
+use crate::proto::invoice::invoice_client::InvoiceClient;
+use crate::proto::invoice::CountCustomerInvoicesRequest;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+
+// Call fails if the invoice service does not respond in time
+// The tenant is locked while waiting
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Check the tax number of a customer having one changed
+pub fn tax_number_changed(before: &Customer, after: &Customer) -> bool {
+  before.tax_number.is_some() && before.tax_number != after.tax_number
+}
+
+pub struct TaxNumberGuard {
+  // Invoice service, None to check recorded invoice activity only
+  client: Option<InvoiceClient<Channel>>,
+}
+
+impl TaxNumberGuard {
+  // Must be called from the tokio runtime when endpoint is set
+  pub fn new(endpoint: Option<&str>) -> ServiceResult<Self> {
+    let client = match endpoint {
+      Some(endpoint) => Some(InvoiceClient::new(
+        Endpoint::from_shared(endpoint.to_string())
+          .map_err(|e| ServiceError::internal_error(&format!("Invalid invoice endpoint: {}", e)))?
+          .connect_lazy()
+          .map_err(|e| ServiceError::internal_error(&format!("Invalid invoice endpoint: {}", e)))?,
+      )),
+      None => None,
+    };
+    Ok(Self { client })
+  }
+  // Check whether the customer was invoiced
+  async fn invoiced(&self, tenant: &str, customer: &Customer) -> Result<bool, String> {
+    if customer.last_invoice_at.is_some() {
+      return Ok(true);
+    }
+    let mut client = match &self.client {
+      // Clients share the channel
+      Some(client) => client.clone(),
+      None => return Ok(false),
+    };
+    let request = CountCustomerInvoicesRequest {
+      tenant: tenant.to_string(),
+      customer_id: customer.id,
+      tax_number: customer
+        .tax_number
+        .as_ref()
+        .map(|t| t.to_string())
+        .unwrap_or_default(),
+    };
+    let res = tokio::time::timeout(CALL_TIMEOUT, client.count_customer_invoices(request))
+      .await
+      .map_err(|_| "Invoice service timed out".to_string())?
+      .map_err(|e| format!("Invoice service: {}", e))?;
+    Ok(res.into_inner().invoice_count > 0)
+  }
+  // Check a customer change, before is the stored version
+  // Returns true if the change is allowed only because it is forced
+  pub async fn check(
+    &self,
+    tenant: &str,
+    before: &Customer,
+    after: &Customer,
+    force: bool,
+  ) -> ServiceResult<bool> {
+    if !tax_number_changed(before, after) {
+      return Ok(false);
+    }
+    let error = match self.invoiced(tenant, before).await {
+      Ok(false) => return Ok(false),
+      Ok(true) => ServiceError::failed_precondition(
+        "Az ügyfélnek már van kiállított számlája, az adószáma csak kényszerítve módosítható",
+      ),
+      Err(error) => {
+        eprintln!(
+          "Error while checking invoices of customer {}: {}",
+          before.id, error
+        );
+        ServiceError::failed_precondition(
+          "A számlázó szolgáltatás nem érhető el, az adószám csak kényszerítve módosítható",
+        )
+      }
+    };
+    match force {
+      true => Ok(true),
+      false => Err(error.on_field("tax_number")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::taxnumber::TaxNumber;
+
+  #[tokio::test]
+  async fn test_tax_number_guard() {
+    let guard = TaxNumberGuard::new(None).unwrap();
+    let before = Customer {
+      id: 1,
+      tax_number: TaxNumber::new("66064590-2-35").ok(),
+      ..Customer::default()
+    };
+    let mut after = before.clone();
+    after.name = "Kiss Kert Kft".to_string();
+    // Tax number unchanged
+    assert!(!guard.check("", &before, &after, false).await.unwrap());
+    // Not invoiced yet
+    after.tax_number = TaxNumber::new("23127182-2-15").ok();
+    assert!(!guard.check("", &before, &after, false).await.unwrap());
+    let mut invoiced = before.clone();
+    invoiced.last_invoice_at = Some(chrono::Utc::now());
+    assert!(guard.check("", &invoiced, &after, false).await.is_err());
+    assert!(guard.check("", &invoiced, &after, true).await.unwrap());
+    // Removing the tax number is a change too
+    after.tax_number = None;
+    assert!(guard.check("", &invoiced, &after, false).await.is_err());
+    // Setting the first one is not
+    assert!(!guard.check("", &after, &invoiced, false).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_invoice_service_down() {
+    // Nothing listens on the port
+    let guard = TaxNumberGuard::new(Some("http://127.0.0.1:9")).unwrap();
+    let before = Customer {
+      id: 1,
+      tax_number: TaxNumber::new("66064590-2-35").ok(),
+      ..Customer::default()
+    };
+    let mut after = before.clone();
+    after.tax_number = TaxNumber::new("23127182-2-15").ok();
+    assert!(guard.check("", &before, &after, false).await.is_err());
+    assert!(guard.check("", &before, &after, true).await.unwrap());
+  }
+}
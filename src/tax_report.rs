@@ -0,0 +1,231 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Tax data report
+//
+// Finds customers whose tax data fails the current validation, e.g.
+// stored before a rule was tightened, or restored and imported from
+// old data: tax numbers with a wrong check digit, VAT or county code,
+// companies without a tax number, private persons with one, and with
+// unique_tax_number, tax numbers shared by more customers.
+// Anonymized customers are skipped. Reported in customer ID order,
+// one page at a time.
+
+use crate::customer::{Customer, CustomerKind};
+use crate::taxnumber::TaxNumber;
+use std::collections::{HashMap, HashSet};
+
+// Default and max page size
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IssueKind {
+  // Check digit, VAT or county code of the tax number is wrong
+  Invalid,
+  // Company without tax number
+  Missing,
+  // Private person with tax number
+  NotAllowed,
+  // Tax number of an other customer too
+  Duplicate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Issue {
+  pub kind: IssueKind,
+  // Validation error, in Hungarian
+  pub description: String,
+}
+
+impl Issue {
+  fn new(kind: IssueKind, description: &str) -> Self {
+    Self {
+      kind,
+      description: description.to_string(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomerIssues {
+  pub customer_id: u32,
+  pub issues: Vec<Issue>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+  pub customers: Vec<CustomerIssues>,
+  // Customers with issues, on every page
+  pub total: usize,
+  // Cursor of the next page, 0 on the last one
+  pub next_after_id: u32,
+}
+
+// Tax numbers of more than one customer
+pub fn shared_tax_numbers<'a>(customers: impl Iterator<Item = &'a Customer>) -> HashSet<String> {
+  let mut counts: HashMap<String, usize> = HashMap::new();
+  for customer in customers {
+    if let Some(tax_number) = &customer.tax_number {
+      *counts.entry(tax_number.to_string()).or_default() += 1;
+    }
+  }
+  counts
+    .into_iter()
+    .filter(|(_, count)| *count > 1)
+    .map(|(tax_number, _)| tax_number)
+    .collect()
+}
+
+// Tax data issues of a customer
+// Shared is empty, unless tax numbers must be unique
+pub fn issues(customer: &Customer, shared: &HashSet<String>) -> Vec<Issue> {
+  let mut res = Vec::new();
+  if customer.anonymized_at.is_some() {
+    return res;
+  }
+  match (&customer.kind, &customer.tax_number) {
+    (CustomerKind::Company, None) => res.push(Issue::new(
+      IssueKind::Missing,
+      "Cég esetén az adószám megadása kötelező",
+    )),
+    (CustomerKind::Private, Some(_)) => res.push(Issue::new(
+      IssueKind::NotAllowed,
+      "Magánszemély esetén nem adható meg adószám",
+    )),
+    _ => (),
+  }
+  if let Some(tax_number) = &customer.tax_number {
+    // Stored tax numbers are validated again
+    let tax_number = tax_number.to_string();
+    if let Err(error) = TaxNumber::new(&tax_number) {
+      res.push(Issue::new(IssueKind::Invalid, &error.to_string()));
+    }
+    if shared.contains(&tax_number) {
+      res.push(Issue::new(
+        IssueKind::Duplicate,
+        "Ez az adószám már egy másik ügyfélhez tartozik",
+      ));
+    }
+  }
+  res
+}
+
+// Page of the found customers after the given customer ID
+pub fn page(mut found: Vec<CustomerIssues>, after_id: u32, limit: usize) -> Page {
+  found.sort_by_key(|c| c.customer_id);
+  let total = found.len();
+  let mut customers = found
+    .into_iter()
+    .filter(|c| c.customer_id > after_id)
+    .collect::<Vec<CustomerIssues>>();
+  let next_after_id = match customers.len() > limit {
+    true => customers[limit - 1].customer_id,
+    false => 0,
+  };
+  customers.truncate(limit);
+  Page {
+    customers,
+    total,
+    next_after_id,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+
+  fn customer(id: u32, kind: CustomerKind, tax_number: Option<&str>) -> Customer {
+    Customer {
+      id,
+      kind,
+      // Stored without validation, e.g. by an old version
+      tax_number: tax_number.map(|t| serde_json::from_str(t).unwrap()),
+      ..Customer::default()
+    }
+  }
+
+  fn kinds(customer: &Customer, shared: &HashSet<String>) -> Vec<IssueKind> {
+    issues(customer, shared).iter().map(|i| i.kind).collect()
+  }
+
+  #[test]
+  fn test_issues() {
+    let valid = "[6,6,0,6,4,5,9,0,2,3,5]";
+    // Wrong check digit
+    let invalid = "[6,6,0,6,4,5,9,1,2,3,5]";
+    let none = HashSet::new();
+    assert!(kinds(&customer(1, CustomerKind::Company, Some(valid)), &none).is_empty());
+    assert!(kinds(&customer(1, CustomerKind::Private, None), &none).is_empty());
+    assert_eq!(
+      kinds(&customer(1, CustomerKind::Company, None), &none),
+      [IssueKind::Missing]
+    );
+    assert_eq!(
+      kinds(&customer(1, CustomerKind::Private, Some(invalid)), &none),
+      [IssueKind::NotAllowed, IssueKind::Invalid]
+    );
+    let invalid = issues(&customer(1, CustomerKind::Company, Some(invalid)), &none);
+    assert_eq!(
+      invalid[0].description,
+      "A megadott adószám formailag megfelelő, de az első 8 számjegy (törzsszám) hibás."
+    );
+    // Duplicates
+    let customers = [
+      customer(1, CustomerKind::Company, Some(valid)),
+      customer(2, CustomerKind::Company, Some(valid)),
+      customer(3, CustomerKind::Institution, None),
+    ];
+    let shared = shared_tax_numbers(customers.iter());
+    assert_eq!(shared.len(), 1);
+    assert_eq!(kinds(&customers[1], &shared), [IssueKind::Duplicate]);
+    // Anonymized customers are skipped
+    let mut anonymized = customer(4, CustomerKind::Company, None);
+    anonymized.anonymized_at = Some(Utc::now());
+    assert!(kinds(&anonymized, &none).is_empty());
+  }
+
+  #[test]
+  fn test_page() {
+    let found = [9, 2, 5, 7]
+      .iter()
+      .map(|id| CustomerIssues {
+        customer_id: *id,
+        issues: Vec::new(),
+      })
+      .collect::<Vec<CustomerIssues>>();
+    let ids = |page: &Page| {
+      page
+        .customers
+        .iter()
+        .map(|c| c.customer_id)
+        .collect::<Vec<u32>>()
+    };
+    let first = page(found.clone(), 0, 2);
+    assert_eq!(
+      (ids(&first), first.total, first.next_after_id),
+      (vec![2, 5], 4, 5)
+    );
+    let last = page(found.clone(), first.next_after_id, 2);
+    assert_eq!(
+      (ids(&last), last.total, last.next_after_id),
+      (vec![7, 9], 4, 0)
+    );
+    assert!(page(found, 9, 2).customers.is_empty());
+  }
+}
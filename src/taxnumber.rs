@@ -18,9 +18,52 @@
 use crate::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct TaxNumber([u32; 11]);
 
+// Tax number validation error
+// Tells which part of the 8-1-2 structure failed
+#[derive(Debug, PartialEq)]
+pub enum TaxNumberError {
+  // Not 11 digits, or not in the xxxxxxxx-y-zz form
+  Format,
+  // First 8 digits (törzsszám) has a wrong check digit
+  CheckDigit,
+  // 9th digit (ÁFA kód) is not 1, 2, 3, 4 or 5
+  VatCode,
+  // Last 2 digits (területi kód) is not a valid county code
+  CountyCode,
+}
+
+impl std::fmt::Display for TaxNumberError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TaxNumberError::Format => write!(
+        f,
+        "Formai hiba! Az adószám 11 db számot kell, hogy tartalmazzon, xxxxxxxx-y-zz formában."
+      ),
+      TaxNumberError::CheckDigit => write!(
+        f,
+        "A megadott adószám formailag megfelelő, de az első 8 számjegy (törzsszám) hibás."
+      ),
+      TaxNumberError::VatCode => write!(
+        f,
+        "A megadott adószám 9. karaktere nem megfelelő 1, 2, 3, 4, vagy 5 lehet."
+      ),
+      TaxNumberError::CountyCode => write!(
+        f,
+        "A megadott adószám utolsó két számjegye (területi kód) nem megfelelő."
+      ),
+    }
+  }
+}
+
+impl From<TaxNumberError> for ServiceError {
+  fn from(error: TaxNumberError) -> Self {
+    ServiceError::bad_request(&error.to_string())
+  }
+}
+
 impl std::fmt::Display for TaxNumber {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(
@@ -41,41 +84,27 @@ impl std::fmt::Display for TaxNumber {
   }
 }
 
-impl Into<String> for TaxNumber {
-  fn into(self) -> String {
-    format!("{}", self)
+impl From<TaxNumber> for String {
+  fn from(tax_number: TaxNumber) -> Self {
+    format!("{}", tax_number)
   }
 }
 
 impl TaxNumber {
-  pub fn new(tax_number: &str) -> ServiceResult<Self> {
-    // Create an own copy of the input string
-    let s = clean_characters(tax_number)
-      .chars()
-      .into_iter()
-      .map(|c| c.to_digit(10).unwrap())
-      .collect::<Vec<u32>>();
-    if s.len() != 11 {
-      return Err(ServiceError::BadRequest(String::from(
-        "Formai hiba! Az adószám 11 db számot kell, hogy tartalmazzon.",
-      )));
-    }
+  pub fn new(tax_number: &str) -> Result<Self, TaxNumberError> {
+    let s = parse_digits(tax_number)?;
     // Slice!
     let slice: [u32; 8] = [s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]];
-    let result: TaxNumber = Self([
-      s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7], s[8], s[9], s[10],
-    ]);
     if !is_valid_checksum(&slice) {
-      return Err(ServiceError::BadRequest(String::from(
-        "A megadott adószám formailag megfelelő, de a beírt számok hibásak.",
-      )));
+      return Err(TaxNumberError::CheckDigit);
+    }
+    if !is_valid_vat_code(s[8]) {
+      return Err(TaxNumberError::VatCode);
     }
-    if s[8] == 0 || s[8] > 5 {
-      return Err(ServiceError::BadRequest(String::from(
-        "A megadott adószám 9. karaktere nem megfelelő 1, 2, 3, 4, vagy 5 lehet.",
-      )));
+    if !is_valid_county_code(s[9] * 10 + s[10]) {
+      return Err(TaxNumberError::CountyCode);
     }
-    Ok(result)
+    Ok(Self(s))
   }
 }
 
@@ -83,50 +112,81 @@ impl TaxNumber {
 // based on the algorithm found here:
 // https://hu.wikipedia.org/wiki/Ad%C3%B3sz%C3%A1m
 fn is_valid_checksum(s: &[u32; 8]) -> bool {
-  let sum = s[0] * 9 + s[1] * 7 + s[2] * 3 + s[3] * 1 + s[4] * 9 + s[5] * 7 + s[6] * 3;
+  let sum = s[0] * 9 + s[1] * 7 + s[2] * 3 + s[3] + s[4] * 9 + s[5] * 7 + s[6] * 3;
   let last = sum % 10;
   if last == 0 {
-    if s[7] != 0 {
-      return false;
-    }
+    s[7] == 0
   } else {
-    if s[7] != (10 - last) {
-      return false;
-    }
+    s[7] == (10 - last)
   }
-  return true;
+}
+
+// ÁFA kód
+// 1 - alanyi adómentes, 2 - általános, 3 - EVA,
+// 4 - csoportos adóalany, 5 - csoporttag
+fn is_valid_vat_code(code: u32) -> bool {
+  (1..=5).contains(&code)
+}
+
+// Területi kód
+// 02-20 counties, 22-44 Budapest and regional
+// directorates, 51 Kiemelt Adózók
+fn is_valid_county_code(code: u32) -> bool {
+  matches!(code, 2..=20 | 22..=44 | 51)
+}
+
+// Parse the 11 digits of the tax number
+// Accepts 11 digits in a row, or the 8-1-2 hyphenated form.
+// Whitespace is ignored, anything else is a format error.
+fn parse_digits(s: &str) -> Result<[u32; 11], TaxNumberError> {
+  let cleaned = clean_characters(s);
+  let parts = cleaned.split('-').collect::<Vec<&str>>();
+  let is_valid_structure = match parts.as_slice() {
+    [digits] => digits.len() == 11,
+    [base, vat, county] => base.len() == 8 && vat.len() == 1 && county.len() == 2,
+    _ => false,
+  };
+  if !is_valid_structure {
+    return Err(TaxNumberError::Format);
+  }
+  let mut result = [0; 11];
+  for (i, c) in parts.concat().chars().enumerate() {
+    result[i] = c.to_digit(10).ok_or(TaxNumberError::Format)?;
+  }
+  Ok(result)
 }
 
 fn clean_characters(s: &str) -> String {
   let mut result = s.to_string();
-  // Remove everything but numbers
-  result.retain(|c| c.is_numeric());
+  // Remove whitespaces
+  result.retain(|c| !c.is_whitespace());
   result
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use proptest::prelude::*;
 
   #[test]
   fn test_clean_format() {
     assert_eq!(clean_characters("123 "), String::from("123"));
     assert_eq!(clean_characters("1 2 3 "), String::from("123"));
-    assert_eq!(clean_characters("123-"), String::from("123"));
-    assert_eq!(clean_characters("1a2b3c - "), String::from("123"));
+    assert_eq!(clean_characters("123-"), String::from("123-"));
+    assert_eq!(clean_characters("1a2b3c - "), String::from("1a2b3c-"));
     assert_eq!(
       clean_characters("23127182-2-15 "),
-      String::from("23127182215")
+      String::from("23127182-2-15")
     );
   }
 
   #[test]
   fn test_checksum() {
-    assert_eq!(is_valid_checksum(&[2, 3, 1, 2, 7, 1, 8, 2]), true); // Valid example
-    assert_eq!(is_valid_checksum(&[2, 3, 1, 2, 7, 1, 8, 3]), false); // Wrong
-    assert_eq!(is_valid_checksum(&[2, 3, 1, 2, 7, 1, 9, 2]), false); // Wrong
-    assert_eq!(is_valid_checksum(&[2, 5, 5, 7, 2, 2, 0, 3]), true); // Valid example
-    assert_eq!(is_valid_checksum(&[1, 5, 7, 3, 1, 9, 7, 9]), true); // Valid example
+    assert!(is_valid_checksum(&[2, 3, 1, 2, 7, 1, 8, 2])); // Valid example
+    assert!(!is_valid_checksum(&[2, 3, 1, 2, 7, 1, 8, 3])); // Wrong
+    assert!(!is_valid_checksum(&[2, 3, 1, 2, 7, 1, 9, 2])); // Wrong
+    assert!(is_valid_checksum(&[2, 5, 5, 7, 2, 2, 0, 3])); // Valid example
+    assert!(is_valid_checksum(&[1, 5, 7, 3, 1, 9, 7, 9])); // Valid example
   }
   #[test]
   fn test_taxnumber_display() {
@@ -134,10 +194,6 @@ mod tests {
       format!("{}", TaxNumber::new("23127182-2-15").unwrap()),
       String::from("23127182-2-15")
     );
-    assert_eq!(
-      format!("{}", TaxNumber::new("231271822-15").unwrap()),
-      String::from("23127182-2-15")
-    );
     assert_eq!(
       format!("{}", TaxNumber::new("23127182215").unwrap()),
       String::from("23127182-2-15")
@@ -151,8 +207,112 @@ mod tests {
       String::from("66064590-2-35")
     );
     assert_eq!(
-      format!("{}", TaxNumber::new(" 55405625 asd - 1 - 33 ").unwrap()),
+      format!("{}", TaxNumber::new(" 55405625 - 1 - 33 ").unwrap()),
       String::from("55405625-1-33")
     );
   }
+  #[test]
+  fn test_taxnumber_failed_part() {
+    // Wrong structure
+    assert_eq!(
+      TaxNumber::new("231271822-15").unwrap_err(),
+      TaxNumberError::Format
+    );
+    assert_eq!(
+      TaxNumber::new(" 55405625 asd - 1 - 33 ").unwrap_err(),
+      TaxNumberError::Format
+    );
+    assert_eq!(
+      TaxNumber::new("2312718221").unwrap_err(),
+      TaxNumberError::Format
+    );
+    assert_eq!(
+      TaxNumber::new("23127182-2-15-").unwrap_err(),
+      TaxNumberError::Format
+    );
+    assert_eq!(
+      TaxNumber::new("2312718²-2-15").unwrap_err(),
+      TaxNumberError::Format
+    );
+    // Wrong check digit
+    assert_eq!(
+      TaxNumber::new("23127183-2-15").unwrap_err(),
+      TaxNumberError::CheckDigit
+    );
+    // Wrong VAT code
+    assert_eq!(
+      TaxNumber::new("23127182-0-15").unwrap_err(),
+      TaxNumberError::VatCode
+    );
+    assert_eq!(
+      TaxNumber::new("23127182-6-15").unwrap_err(),
+      TaxNumberError::VatCode
+    );
+    // Wrong county code
+    assert_eq!(
+      TaxNumber::new("23127182-2-01").unwrap_err(),
+      TaxNumberError::CountyCode
+    );
+    assert_eq!(
+      TaxNumber::new("23127182-2-21").unwrap_err(),
+      TaxNumberError::CountyCode
+    );
+    assert_eq!(
+      TaxNumber::new("23127182-2-99").unwrap_err(),
+      TaxNumberError::CountyCode
+    );
+  }
+
+  // Build the check digit for the first 7 digits
+  fn check_digit(s: &[u32]) -> u32 {
+    let sum = s[0] * 9 + s[1] * 7 + s[2] * 3 + s[3] + s[4] * 9 + s[5] * 7 + s[6] * 3;
+    (10 - sum % 10) % 10
+  }
+
+  fn county_code() -> impl Strategy<Value = u32> {
+    prop_oneof![2..=20u32, 22..=44u32, Just(51u32)]
+  }
+
+  proptest! {
+    #[test]
+    fn prop_valid_taxnumber_roundtrip(
+      base in proptest::collection::vec(0..10u32, 7),
+      vat in 1..=5u32,
+      county in county_code(),
+    ) {
+      let input = format!(
+        "{}{}-{}-{:02}",
+        base.iter().map(|d| d.to_string()).collect::<String>(),
+        check_digit(&base),
+        vat,
+        county
+      );
+      let tax_number = TaxNumber::new(&input).unwrap();
+      prop_assert_eq!(tax_number.to_string(), input.clone());
+      // Display form parses back to the same tax number
+      prop_assert_eq!(TaxNumber::new(&tax_number.to_string()).unwrap().to_string(), input);
+    }
+
+    #[test]
+    fn prop_wrong_check_digit_fails(
+      base in proptest::collection::vec(0..10u32, 7),
+      offset in 1..10u32,
+      vat in 1..=5u32,
+      county in county_code(),
+    ) {
+      let input = format!(
+        "{}{}{}{:02}",
+        base.iter().map(|d| d.to_string()).collect::<String>(),
+        (check_digit(&base) + offset) % 10,
+        vat,
+        county
+      );
+      prop_assert_eq!(TaxNumber::new(&input).unwrap_err(), TaxNumberError::CheckDigit);
+    }
+
+    #[test]
+    fn prop_arbitrary_input_does_not_panic(input in "\\PC*") {
+      let _ = TaxNumber::new(&input);
+    }
+  }
 }
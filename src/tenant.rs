@@ -0,0 +1,1110 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::audit_sink::MutationLog;
+use crate::customer::Customer;
+use crate::db::CustomerDb;
+use crate::history::FieldHistory;
+use crate::integrity;
+use crate::outbox::Outbox;
+use crate::prelude::*;
+use crate::replication::Journal;
+use crate::storage::{
+  load_vecpack, BrokenRecord, CustomerStore, LazyLogStore, LogStore, MemoryStore, ShadowStore,
+  ShardedStore, SqliteStore,
+};
+use packman::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+use tonic::metadata::MetadataMap;
+
+// Request metadata key of the tenant ID
+pub const TENANT_METADATA_KEY: &str = "tenant-id";
+
+// Default tenant
+// Used when request has no tenant ID,
+// and stored in data/customers as before multi tenant support.
+pub const DEFAULT_TENANT: &str = "";
+
+// Tenant names we cannot use, as they are
+// storage directories of the default tenant
+// or of the event outbox, webhooks and shipping notifications
+const RESERVED_TENANTS: [&str; 16] = [
+  "custom_fields",
+  "customers",
+  "customers_compact",
+  "customers_corrupt",
+  "customers_quarantine",
+  "customers_shards",
+  "erasure_requests",
+  "id_reservations",
+  "outbox",
+  "pending_changes",
+  "profile_tokens",
+  "segment_members",
+  "segments",
+  "shipping_outbox",
+  "webhooks",
+  "webhook_outbox",
+];
+
+pub type CustomerPack = Arc<Mutex<CustomerDb>>;
+
+// Default customer IDs per shard of the sharded backend
+pub const DEFAULT_SHARD_SIZE: u32 = 10_000;
+
+// Customer storage backend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+  // VecPack under data/<tenant>/customers
+  VecPack,
+  // Append only log in data/<tenant>/customers.log
+  Log,
+  // Same log as Log, customers are read from it on demand
+  Lazy,
+  // Logs by ID range in data/<tenant>/customers_shards,
+  // with the given number of IDs per shard
+  Sharded(u32),
+  // SQLite database in data/<tenant>/customers.sqlite
+  Sqlite,
+  // Customers are kept in memory only, and lost on restart
+  // For tests, nothing is written to the data directory
+  Memory,
+}
+
+impl Backend {
+  pub fn from_name(name: &str) -> ServiceResult<Self> {
+    match name {
+      "vecpack" => Ok(Backend::VecPack),
+      "log" => Ok(Backend::Log),
+      "lazy" => Ok(Backend::Lazy),
+      "sharded" => Ok(Backend::Sharded(DEFAULT_SHARD_SIZE)),
+      "sqlite" => Ok(Backend::Sqlite),
+      "memory" => Ok(Backend::Memory),
+      _ => Err(ServiceError::internal_error(&format!(
+        "Unknown storage backend: {}",
+        name
+      ))),
+    }
+  }
+  // Shadow storage must not share its files with the primary one
+  pub fn check_shadow(&self, shadow: Backend) -> ServiceResult<()> {
+    let is_log = |b: Backend| b == Backend::Log || b == Backend::Lazy;
+    match self.name() == shadow.name() || (is_log(*self) && is_log(shadow)) {
+      true => Err(ServiceError::internal_error(&format!(
+        "Shadow storage {} uses the files of storage {}",
+        shadow.name(),
+        self.name()
+      ))),
+      false => Ok(()),
+    }
+  }
+  pub fn name(&self) -> &'static str {
+    match self {
+      Backend::VecPack => "vecpack",
+      Backend::Log => "log",
+      Backend::Lazy => "lazy",
+      Backend::Sharded(_) => "sharded",
+      Backend::Sqlite => "sqlite",
+      Backend::Memory => "memory",
+    }
+  }
+  // Set the IDs per shard of the sharded backend,
+  // other backends are returned as they are
+  pub fn with_shard_size(self, shard_size: u32) -> ServiceResult<Self> {
+    match (self, shard_size) {
+      (_, 0) => Err(ServiceError::internal_error(
+        "Shard size must be greater than 0",
+      )),
+      (Backend::Sharded(_), _) => Ok(Backend::Sharded(shard_size)),
+      (backend, _) => Ok(backend),
+    }
+  }
+}
+
+// Startup load of a tenant
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantWarmUp {
+  pub tenant: String,
+  pub customer_count: usize,
+  // Opening the storage
+  pub load_time: Duration,
+  // Building the in-memory indexes
+  pub index_time: Duration,
+}
+
+// Startup load progress of the tenants
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WarmUp {
+  // Tenants found in the data directory
+  pub tenants_total: usize,
+  // Loaded tenants, in load order
+  pub tenants: Vec<TenantWarmUp>,
+  pub ready: bool,
+}
+
+// Per tenant customer storages
+//
+// Every tenant has its own storage, e.g. VecPack under
+// data/<tenant>/customers, so customers of a tenant are
+// never visible from an other one.
+pub struct Tenants {
+  data_dir: PathBuf,
+  backend: Backend,
+  // Shadow storage backend of a migration
+  shadow: Option<Backend>,
+  packs: Mutex<HashMap<String, CustomerPack>>,
+  // Replication journal of every tenant
+  journal: Option<Arc<Journal>>,
+  // Mutation audit log of every tenant
+  mutation_log: Option<Arc<MutationLog>>,
+  // Field history of every tenant
+  history: Option<Arc<FieldHistory>>,
+  // Event outbox of every tenant
+  outbox: Option<Arc<Outbox>>,
+  // Shipping notification outbox of every tenant
+  shipping: Option<Arc<Outbox>>,
+  // Standby mode, customers are written by replication only
+  read_only: bool,
+  // Startup load progress
+  warm_up: std::sync::Mutex<WarmUp>,
+  // Set once every tenant is loaded
+  ready: watch::Sender<bool>,
+}
+
+impl Tenants {
+  // Tenants without loaded customers
+  // Requests wait until warm_up loads them
+  pub fn new(data_dir: PathBuf, backend: Backend) -> Self {
+    Self {
+      data_dir,
+      backend,
+      shadow: None,
+      packs: Mutex::new(HashMap::new()),
+      journal: None,
+      mutation_log: None,
+      history: None,
+      outbox: None,
+      shipping: None,
+      read_only: false,
+      warm_up: std::sync::Mutex::new(WarmUp::default()),
+      ready: watch::channel(false).0,
+    }
+  }
+  // Load default tenant and all the tenants
+  // found in the data directory, before sharing them
+  #[cfg(any(test, feature = "bench"))]
+  pub fn load(data_dir: PathBuf, backend: Backend) -> ServiceResult<Self> {
+    let mut tenants = Self::new(data_dir, backend);
+    let mut warm_up = WarmUp::default();
+    if backend != Backend::Memory {
+      for tenant in tenant_ids(&tenants.data_dir)? {
+        let (db, progress) = load_tenant(&tenants.data_dir, &tenant, backend, None)?;
+        tenants
+          .packs
+          .get_mut()
+          .insert(tenant, Arc::new(Mutex::new(db)));
+        warm_up.tenants.push(progress);
+      }
+    }
+    warm_up.tenants_total = warm_up.tenants.len();
+    warm_up.ready = true;
+    *tenants.warm_up.get_mut().expect("Not shared yet") = warm_up;
+    tenants.ready.send_replace(true);
+    Ok(tenants)
+  }
+  // Load default tenant and all the tenants found in the data directory,
+  // while the service is already running. Progress is logged per tenant.
+  // Storages are loaded one by one, in blocking threads.
+  pub async fn warm_up(&self) -> ServiceResult<()> {
+    let started = Instant::now();
+    let tenant_ids = match self.backend {
+      Backend::Memory => Vec::new(),
+      _ => tenant_ids(&self.data_dir)?,
+    };
+    self.update_warm_up(|w| w.tenants_total = tenant_ids.len());
+    for (i, tenant) in tenant_ids.into_iter().enumerate() {
+      println!(
+        "Loading tenant '{}' ({}/{})",
+        tenant,
+        i + 1,
+        self.warm_up_state().tenants_total
+      );
+      let (data_dir, backend, shadow, id) = (
+        self.data_dir.clone(),
+        self.backend,
+        self.shadow,
+        tenant.clone(),
+      );
+      let (mut db, progress) =
+        tokio::task::spawn_blocking(move || load_tenant(&data_dir, &id, backend, shadow))
+          .await
+          // VecPack panics on broken files
+          .unwrap_or_else(|_| {
+            Err(ServiceError::internal_error(&format!(
+              "Broken customer storage of tenant '{}', check it with --verify",
+              tenant
+            )))
+          })?;
+      println!(
+        "Tenant '{}': {} customers loaded in {:.2}s, indexes built in {:.2}s",
+        tenant,
+        progress.customer_count,
+        progress.load_time.as_secs_f64(),
+        progress.index_time.as_secs_f64()
+      );
+      self.setup(&mut db, &tenant);
+      self
+        .packs
+        .lock()
+        .await
+        .insert(tenant, Arc::new(Mutex::new(db)));
+      self.update_warm_up(|w| w.tenants.push(progress));
+    }
+    // Customers of the staged events are not stored
+    for outbox in self.outbox.iter().chain(&self.shipping) {
+      outbox.cancel_unrecovered();
+    }
+    self.update_warm_up(|w| w.ready = true);
+    self.ready.send_replace(true);
+    let warm_up = self.warm_up_state();
+    println!(
+      "Ready: {} tenants, {} customers loaded in {:.2}s",
+      warm_up.tenants.len(),
+      warm_up
+        .tenants
+        .iter()
+        .map(|t| t.customer_count)
+        .sum::<usize>(),
+      started.elapsed().as_secs_f64()
+    );
+    Ok(())
+  }
+  fn update_warm_up(&self, f: impl FnOnce(&mut WarmUp)) {
+    f(&mut self.warm_up.lock().unwrap_or_else(|e| e.into_inner()));
+  }
+  pub fn warm_up_state(&self) -> WarmUp {
+    self
+      .warm_up
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .clone()
+  }
+  // Changes to true once every tenant is loaded
+  pub fn ready(&self) -> watch::Receiver<bool> {
+    self.ready.subscribe()
+  }
+  async fn wait_ready(&self) {
+    // The sender lives as long as self
+    let _ = self.ready().wait_for(|ready| *ready).await;
+  }
+  // Record the customer writes of every tenant in the journal
+  pub fn set_journal(&mut self, journal: Arc<Journal>) {
+    self.journal = Some(journal);
+    self.setup_all();
+  }
+  // Log the customer changes of every tenant
+  pub fn set_mutation_log(&mut self, mutation_log: Arc<MutationLog>) {
+    self.mutation_log = Some(mutation_log);
+    self.setup_all();
+  }
+  // Record the field history of every tenant
+  pub fn set_history(&mut self, history: Arc<FieldHistory>) {
+    self.history = Some(history);
+    self.setup_all();
+  }
+  // Stage the events of the customer changes of every tenant
+  pub fn set_outbox(&mut self, outbox: Arc<Outbox>) {
+    self.outbox = Some(outbox);
+    self.setup_all();
+  }
+  // Stage the shipping notifications of address changes of every tenant
+  pub fn set_shipping(&mut self, outbox: Arc<Outbox>) {
+    self.shipping = Some(outbox);
+    self.setup_all();
+  }
+  // Reject customer writes of every tenant, except replication
+  pub fn set_read_only(&mut self, read_only: bool) {
+    self.read_only = read_only;
+    self.setup_all();
+  }
+  pub fn read_only(&self) -> bool {
+    self.read_only
+  }
+  fn setup_all(&mut self) {
+    for (tenant, pack) in self.packs.get_mut().clone() {
+      let mut db = pack.try_lock().expect("Tenants are not shared yet");
+      self.setup(&mut db, &tenant);
+    }
+  }
+  // Set up the customer db of a tenant loaded by the service
+  fn setup(&self, db: &mut CustomerDb, tenant: &str) {
+    if let Some(journal) = &self.journal {
+      db.set_journal(tenant, journal.clone());
+    }
+    if let Some(mutation_log) = &self.mutation_log {
+      db.set_mutation_log(tenant, mutation_log.clone());
+    }
+    if let Some(history) = &self.history {
+      db.set_history(tenant, history.clone());
+    }
+    // Resolve the events staged before a restart
+    if let Some(outbox) = &self.outbox {
+      outbox.recover(tenant, |id| db.find_id(&id).ok().map(|c| c.last_modified));
+      db.set_outbox(tenant, outbox.clone());
+    }
+    if let Some(shipping) = &self.shipping {
+      shipping.recover(tenant, |id| db.find_id(&id).ok().map(|c| c.last_modified));
+      db.set_shipping(tenant, shipping.clone());
+    }
+    db.set_read_only(self.read_only);
+  }
+  pub fn data_dir(&self) -> &Path {
+    &self.data_dir
+  }
+  pub fn backend(&self) -> Backend {
+    self.backend
+  }
+  // Keep a shadow storage of every tenant, loaded by warm_up
+  pub fn set_shadow(&mut self, shadow: Backend) -> ServiceResult<()> {
+    self.backend.check_shadow(shadow)?;
+    self.shadow = Some(shadow);
+    Ok(())
+  }
+  pub fn shadow(&self) -> Option<Backend> {
+    self.shadow
+  }
+  // Get customer storage of a tenant
+  // Inits storage for new tenants
+  // Waits for the startup load
+  pub async fn get(&self, tenant: &str) -> ServiceResult<CustomerPack> {
+    self.wait_ready().await;
+    let mut packs = self.packs.lock().await;
+    if let Some(pack) = packs.get(tenant) {
+      return Ok(pack.clone());
+    }
+    let pack = load_pack(&self.data_dir, tenant, self.backend, self.shadow)?;
+    self.setup(&mut *pack.lock().await, tenant);
+    packs.insert(tenant.to_string(), pack.clone());
+    Ok(pack)
+  }
+  // IDs of the tenants
+  pub async fn ids(&self) -> ServiceResult<Vec<String>> {
+    match self.backend {
+      Backend::VecPack | Backend::Log | Backend::Lazy | Backend::Sharded(_) | Backend::Sqlite => {
+        tenant_ids(&self.data_dir)
+      }
+      Backend::Memory => {
+        let mut res = self
+          .packs
+          .lock()
+          .await
+          .keys()
+          .cloned()
+          .collect::<Vec<String>>();
+        res.sort();
+        Ok(res)
+      }
+    }
+  }
+  // Loaded tenants, ordered by ID
+  pub async fn loaded(&self) -> Vec<(String, CustomerPack)> {
+    let mut res = self
+      .packs
+      .lock()
+      .await
+      .iter()
+      .map(|(tenant, pack)| (tenant.clone(), pack.clone()))
+      .collect::<Vec<(String, CustomerPack)>>();
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    res
+  }
+  // Size of the customer storage of a tenant in bytes
+  pub fn storage_bytes(&self, tenant: &str) -> ServiceResult<u64> {
+    let path = tenant_path(&self.data_dir, tenant);
+    match self.backend {
+      Backend::VecPack => dir_size(&path),
+      Backend::Log | Backend::Lazy => match path.with_file_name("customers.log") {
+        path if path.is_file() => Ok(std::fs::metadata(path)?.len()),
+        _ => Ok(0),
+      },
+      Backend::Sharded(_) => dir_size(&path.with_file_name("customers_shards")),
+      Backend::Sqlite => {
+        let mut size = 0;
+        for name in ["customers.sqlite", "customers.sqlite-wal"] {
+          if let Ok(metadata) = std::fs::metadata(path.with_file_name(name)) {
+            size += metadata.len();
+          }
+        }
+        Ok(size)
+      }
+      Backend::Memory => Ok(0),
+    }
+  }
+  // Reload customer storage of a tenant from disk
+  // The fresh db is loaded while holding the tenant lock,
+  // so no concurrent change is lost, then swapped in.
+  // Requests of other tenants are not blocked.
+  // Returns the number of loaded customers.
+  // In-memory storages have nothing to reload.
+  pub async fn reload(&self, tenant: &str) -> ServiceResult<usize> {
+    let pack = self.get(tenant).await?;
+    let mut db = pack.lock().await;
+    if self.backend != Backend::Memory {
+      *db = try_load_db(&self.data_dir, tenant, self.backend, self.shadow)?;
+      self.setup(&mut db, tenant);
+      // Customers changed out of band, standbys need a new snapshot
+      if let Some(journal) = &self.journal {
+        journal.invalidate();
+      }
+    }
+    Ok(db.len())
+  }
+  // Reload all tenants, including the ones
+  // created on disk since they were loaded
+  pub async fn reload_all(&self) -> ServiceResult<Vec<(String, ServiceResult<usize>)>> {
+    let mut res = Vec::new();
+    for tenant in self.ids().await? {
+      let count = self.reload(&tenant).await;
+      res.push((tenant, count));
+    }
+    Ok(res)
+  }
+}
+
+// Reload storage of all tenants on every SIGHUP
+pub async fn reload_on_hangup(tenants: Arc<Tenants>) {
+  let mut hangup = signal(SignalKind::hangup()).expect("Error while setting SIGHUP handler");
+  while hangup.recv().await.is_some() {
+    match tenants.reload_all().await {
+      Ok(res) => {
+        for (tenant, count) in res {
+          match count {
+            Ok(count) => println!("Tenant '{}' reloaded: {} customers", tenant, count),
+            Err(error) => eprintln!("Error while reloading tenant '{}': {}", tenant, error),
+          }
+        }
+      }
+      Err(error) => eprintln!("Error while reloading storage: {}", error),
+    }
+  }
+}
+
+// Get tenant ID from request metadata
+// Returns DEFAULT_TENANT if there is no tenant ID provided
+pub fn tenant_from_metadata(metadata: &MetadataMap) -> ServiceResult<String> {
+  match metadata.get(TENANT_METADATA_KEY) {
+    Some(value) => {
+      let tenant = value
+        .to_str()
+        .map_err(|_| ServiceError::bad_request("Hibás tenant azonosító"))?;
+      validate_tenant(tenant)?;
+      Ok(tenant.to_string())
+    }
+    None => Ok(DEFAULT_TENANT.to_string()),
+  }
+}
+
+// Check tenant ID
+pub fn validate_tenant(tenant: &str) -> ServiceResult<()> {
+  match is_valid_tenant(tenant) {
+    true => Ok(()),
+    false => Err(ServiceError::bad_request(&format!(
+      "Hibás tenant azonosító: {}",
+      tenant
+    ))),
+  }
+}
+
+// Load customer db of a tenant
+#[cfg(test)]
+pub fn load_db(data_dir: &Path, tenant: &str) -> ServiceResult<CustomerDb> {
+  Ok(CustomerDb::new(load_customer_pack(data_dir, tenant)?))
+}
+
+// Load VecPack storage of a tenant, unreadable files are quarantined
+fn load_customer_pack(data_dir: &Path, tenant: &str) -> ServiceResult<VecPack<Customer>> {
+  load_vecpack(
+    &tenant_path(data_dir, tenant),
+    &mut quarantine(data_dir, tenant),
+  )
+}
+
+fn quarantine<'a>(
+  data_dir: &'a Path,
+  tenant: &'a str,
+) -> impl FnMut(BrokenRecord) -> ServiceResult<()> + Send + 'a {
+  move |record| integrity::quarantine_corrupt(data_dir, tenant, record).map(|_| ())
+}
+
+// Open customer db of a tenant with the given backend,
+// and its shadow storage if there is one
+pub fn open_db(
+  data_dir: &Path,
+  tenant: &str,
+  backend: Backend,
+  shadow: Option<Backend>,
+) -> ServiceResult<CustomerDb> {
+  Ok(CustomerDb::from_store(open_shadowed(
+    data_dir, tenant, backend, shadow,
+  )?))
+}
+
+fn open_shadowed(
+  data_dir: &Path,
+  tenant: &str,
+  backend: Backend,
+  shadow: Option<Backend>,
+) -> ServiceResult<Box<dyn CustomerStore>> {
+  let primary = open_store(data_dir, tenant, backend)?;
+  match shadow {
+    Some(shadow) => Ok(Box::new(ShadowStore::new(
+      tenant,
+      primary,
+      open_store(data_dir, tenant, shadow)?,
+    )?)),
+    None => Ok(primary),
+  }
+}
+
+fn open_store(
+  data_dir: &Path,
+  tenant: &str,
+  backend: Backend,
+) -> ServiceResult<Box<dyn CustomerStore>> {
+  Ok(match backend {
+    Backend::VecPack => Box::new(load_customer_pack(data_dir, tenant)?),
+    Backend::Log => Box::new(LogStore::open(
+      &log_path(data_dir, tenant)?,
+      &mut quarantine(data_dir, tenant),
+    )?),
+    Backend::Lazy => Box::new(LazyLogStore::open(
+      &log_path(data_dir, tenant)?,
+      &mut quarantine(data_dir, tenant),
+    )?),
+    Backend::Sharded(shard_size) => Box::new(ShardedStore::open(
+      &shards_path(data_dir, tenant, shard_size)?,
+      shard_size,
+      &mut quarantine(data_dir, tenant),
+    )?),
+    Backend::Sqlite => Box::new(open_sqlite(data_dir, tenant)?),
+    Backend::Memory => Box::new(MemoryStore::new()),
+  })
+}
+
+// Open customer db of a tenant, timing the storage open
+// and the index build separately
+fn load_tenant(
+  data_dir: &Path,
+  tenant: &str,
+  backend: Backend,
+  shadow: Option<Backend>,
+) -> ServiceResult<(CustomerDb, TenantWarmUp)> {
+  let started = Instant::now();
+  let store = open_shadowed(data_dir, tenant, backend, shadow)?;
+  let load_time = started.elapsed();
+  let db = CustomerDb::from_store(store);
+  let progress = TenantWarmUp {
+    tenant: tenant.to_string(),
+    customer_count: db.len(),
+    load_time,
+    index_time: started.elapsed() - load_time,
+  };
+  Ok((db, progress))
+}
+
+// Size of the files in a directory and its subdirectories
+fn dir_size(path: &Path) -> ServiceResult<u64> {
+  let mut size = 0;
+  if path.is_dir() {
+    for entry in std::fs::read_dir(path)? {
+      let entry = entry?;
+      size += match entry.file_type()?.is_dir() {
+        true => dir_size(&entry.path())?,
+        false => entry.metadata()?.len(),
+      };
+    }
+  }
+  Ok(size)
+}
+
+// Customer log path of a tenant
+// When there is no log yet, the customers are imported
+// from the VecPack storage, which is left untouched
+fn log_path(data_dir: &Path, tenant: &str) -> ServiceResult<PathBuf> {
+  let pack_path = tenant_path(data_dir, tenant);
+  let path = pack_path.with_file_name("customers.log");
+  if !path.exists() && pack_path.is_dir() {
+    // Imported into a temp log, so a broken import is not used
+    let tmp_path = pack_path.with_file_name("customers.log_import");
+    if tmp_path.exists() {
+      std::fs::remove_file(&tmp_path)?;
+    }
+    let mut log = LogStore::open(&tmp_path, &mut quarantine(data_dir, tenant))?;
+    let pack = load_customer_pack(data_dir, tenant)?;
+    for customer in CustomerStore::iter(&pack) {
+      log.insert(customer.clone())?;
+    }
+    drop(log);
+    std::fs::rename(&tmp_path, &path)?;
+  }
+  Ok(path)
+}
+
+// Sharded storage directory of a tenant
+// When there is no sharded storage yet, the customers are imported
+// from the log, or from the VecPack storage if there is no log.
+// The imported storage is left untouched.
+fn shards_path(data_dir: &Path, tenant: &str, shard_size: u32) -> ServiceResult<PathBuf> {
+  let pack_path = tenant_path(data_dir, tenant);
+  let path = pack_path.with_file_name("customers_shards");
+  let log_path = pack_path.with_file_name("customers.log");
+  if !ShardedStore::exists(&path) && (log_path.is_file() || pack_path.is_dir()) {
+    // Imported into a temp directory, so a broken import is not used
+    let tmp_path = pack_path.with_file_name("customers_shards.import");
+    if tmp_path.exists() {
+      std::fs::remove_dir_all(&tmp_path)?;
+    }
+    let mut shards = ShardedStore::open(&tmp_path, shard_size, &mut quarantine(data_dir, tenant))?;
+    let source: Box<dyn CustomerStore> = match log_path.is_file() {
+      true => Box::new(LogStore::open(
+        &log_path,
+        &mut quarantine(data_dir, tenant),
+      )?),
+      false => Box::new(load_customer_pack(data_dir, tenant)?),
+    };
+    // One record per customer, as a batch record is read or dropped as a whole
+    for customer in source.iter() {
+      shards.insert(customer.clone())?;
+    }
+    drop(shards);
+    std::fs::rename(&tmp_path, &path)?;
+  }
+  Ok(path)
+}
+
+// Open SQLite database of a tenant
+// When there is no database yet, the customers are imported from
+// the log, or from the VecPack storage if there is no log.
+// The imported storage is left untouched.
+fn open_sqlite(data_dir: &Path, tenant: &str) -> ServiceResult<SqliteStore> {
+  let pack_path = tenant_path(data_dir, tenant);
+  let path = pack_path.with_file_name("customers.sqlite");
+  let log_path = pack_path.with_file_name("customers.log");
+  if !path.exists() && (log_path.is_file() || pack_path.is_dir()) {
+    // Imported into a temp database, so a broken import is not used
+    let tmp_path = pack_path.with_file_name("customers.sqlite_import");
+    for suffix in ["", "-wal", "-shm"] {
+      let mut name = tmp_path.as_os_str().to_os_string();
+      name.push(suffix);
+      if Path::new(&name).exists() {
+        std::fs::remove_file(&name)?;
+      }
+    }
+    let mut db = SqliteStore::open(&tmp_path, &mut quarantine(data_dir, tenant))?;
+    let customers = match log_path.is_file() {
+      true => LogStore::open(&log_path, &mut quarantine(data_dir, tenant))?
+        .iter()
+        .cloned()
+        .collect(),
+      false => CustomerStore::iter(&load_customer_pack(data_dir, tenant)?)
+        .cloned()
+        .collect(),
+    };
+    db.write_batch(customers)?;
+    // Checkpointed, so the database is a single file
+    db.compact()?;
+    drop(db);
+    std::fs::rename(&tmp_path, &path)?;
+  }
+  SqliteStore::open(&path, &mut quarantine(data_dir, tenant))
+}
+
+// Load customer db of a tenant while the service is running
+// VecPack panics on broken files, here we return an error instead,
+// and keep the current db
+fn try_load_db(
+  data_dir: &Path,
+  tenant: &str,
+  backend: Backend,
+  shadow: Option<Backend>,
+) -> ServiceResult<CustomerDb> {
+  std::panic::catch_unwind(|| open_db(data_dir, tenant, backend, shadow)).unwrap_or_else(|_| {
+    Err(ServiceError::internal_error(&format!(
+      "Broken customer storage of tenant '{}', check it with --verify",
+      tenant
+    )))
+  })
+}
+
+// Tenant ID is used as a directory name,
+// so only lowercase letters, numbers, - and _ are allowed
+fn is_valid_tenant(tenant: &str) -> bool {
+  !tenant.is_empty()
+    && tenant.len() <= 64
+    && tenant
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    && !RESERVED_TENANTS.contains(&tenant)
+}
+
+// Get the default tenant and all the tenants
+// found in the data directory
+pub fn tenant_ids(data_dir: &Path) -> ServiceResult<Vec<String>> {
+  let mut tenants = vec![DEFAULT_TENANT.to_string()];
+  if data_dir.is_dir() {
+    for entry in std::fs::read_dir(data_dir)? {
+      let entry = entry?;
+      let tenant = entry.file_name().to_string_lossy().to_string();
+      if is_valid_tenant(&tenant)
+        && (entry.path().join("customers").is_dir()
+          || entry.path().join("customers.log").is_file()
+          || entry.path().join("customers_shards").is_dir()
+          || entry.path().join("customers.sqlite").is_file())
+      {
+        tenants.push(tenant);
+      }
+    }
+  }
+  Ok(tenants)
+}
+
+// Customer storage directory of a tenant
+pub fn tenant_path(data_dir: &Path, tenant: &str) -> PathBuf {
+  match tenant {
+    DEFAULT_TENANT => data_dir.join("customers"),
+    _ => data_dir.join(tenant).join("customers"),
+  }
+}
+
+fn load_pack(
+  data_dir: &Path,
+  tenant: &str,
+  backend: Backend,
+  shadow: Option<Backend>,
+) -> ServiceResult<CustomerPack> {
+  Ok(Arc::new(Mutex::new(open_db(
+    data_dir, tenant, backend, shadow,
+  )?)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::CustomerKind;
+  use crate::policy::ValidationPolicy;
+
+  #[test]
+  fn test_valid_tenant() {
+    assert!(is_valid_tenant("kecskemet"));
+    assert!(is_valid_tenant("shop_2"));
+    assert!(!is_valid_tenant(""));
+    assert!(!is_valid_tenant("../customers"));
+    assert!(!is_valid_tenant("Shop"));
+    assert!(!is_valid_tenant("customers"));
+  }
+
+  #[tokio::test]
+  async fn test_tenant_isolation() {
+    let dir = tempfile::tempdir().unwrap();
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::VecPack).unwrap();
+    let customer = Customer::new(
+      1,
+      "Kiss Béla".to_string(),
+      "".to_string(),
+      "".to_string(),
+      None,
+      "".to_string(),
+      "".to_string(),
+      "".to_string(),
+      "HU".to_string(),
+      None,
+      1,
+      CustomerKind::Private,
+      false,
+      None,
+      &ValidationPolicy::default(),
+    )
+    .unwrap();
+    tenants
+      .get("shop_a")
+      .await
+      .unwrap()
+      .lock()
+      .await
+      .insert(customer)
+      .unwrap();
+    assert_eq!(tenants.get("shop_a").await.unwrap().lock().await.len(), 1);
+    assert_eq!(tenants.get("shop_b").await.unwrap().lock().await.len(), 0);
+    assert_eq!(
+      tenants
+        .get(DEFAULT_TENANT)
+        .await
+        .unwrap()
+        .lock()
+        .await
+        .len(),
+      0
+    );
+    assert!(dir
+      .path()
+      .join("shop_a")
+      .join("customers")
+      .join("1")
+      .exists());
+
+    // Tenants are found at startup
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::VecPack).unwrap();
+    assert_eq!(tenants.packs.lock().await.len(), 3);
+  }
+
+  #[tokio::test]
+  async fn test_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::VecPack).unwrap();
+    let pack = tenants.get("shop_a").await.unwrap();
+    // Out of band change on disk
+    load_db(dir.path(), "shop_a")
+      .unwrap()
+      .insert(Customer {
+        id: 1,
+        ..Customer::default()
+      })
+      .unwrap();
+    assert_eq!(pack.lock().await.len(), 0);
+    assert_eq!(tenants.reload("shop_a").await.unwrap(), 1);
+    // Swapped in the shared pack
+    assert_eq!(pack.lock().await.len(), 1);
+    // New tenants are loaded too
+    load_db(dir.path(), "shop_b").unwrap();
+    let res = tenants.reload_all().await.unwrap();
+    assert_eq!(res.len(), 3);
+    assert!(res.iter().all(|(_, count)| count.is_ok()));
+  }
+
+  #[tokio::test]
+  async fn test_in_memory() {
+    let dir = tempfile::tempdir().unwrap();
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::Memory).unwrap();
+    let pack = tenants.get("shop_a").await.unwrap();
+    pack
+      .lock()
+      .await
+      .insert(Customer {
+        id: 1,
+        ..Customer::default()
+      })
+      .unwrap();
+    assert_eq!(tenants.get("shop_b").await.unwrap().lock().await.len(), 0);
+    assert_eq!(tenants.ids().await.unwrap(), ["shop_a", "shop_b"]);
+    // Reload keeps the data
+    assert_eq!(tenants.reload("shop_a").await.unwrap(), 1);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    assert_eq!(Backend::from_name("memory").unwrap(), Backend::Memory);
+    assert_eq!(Backend::from_name("log").unwrap(), Backend::Log);
+    assert_eq!(Backend::from_name("lazy").unwrap(), Backend::Lazy);
+    assert!(Backend::VecPack.check_shadow(Backend::Lazy).is_ok());
+    assert!(Backend::Log.check_shadow(Backend::VecPack).is_ok());
+    assert!(Backend::Log.check_shadow(Backend::Lazy).is_err());
+    assert!(Backend::VecPack.check_shadow(Backend::VecPack).is_err());
+    assert_eq!(
+      Backend::from_name("sharded").unwrap(),
+      Backend::Sharded(DEFAULT_SHARD_SIZE)
+    );
+    assert_eq!(
+      Backend::Sharded(DEFAULT_SHARD_SIZE)
+        .with_shard_size(500)
+        .unwrap(),
+      Backend::Sharded(500)
+    );
+    assert_eq!(Backend::Log.with_shard_size(500).unwrap(), Backend::Log);
+    assert!(Backend::Sharded(500).with_shard_size(0).is_err());
+    assert!(Backend::Sharded(500)
+      .check_shadow(Backend::Sharded(1000))
+      .is_err());
+    assert!(Backend::Log.check_shadow(Backend::Sharded(500)).is_ok());
+    assert_eq!(Backend::from_name("sqlite").unwrap(), Backend::Sqlite);
+    assert!(Backend::Log.check_shadow(Backend::Sqlite).is_ok());
+    assert!(Backend::from_name("sled").is_err());
+  }
+
+  #[tokio::test]
+  async fn test_warm_up() {
+    let dir = tempfile::tempdir().unwrap();
+    load_db(dir.path(), "shop_a")
+      .unwrap()
+      .insert(Customer {
+        id: 1,
+        ..Customer::default()
+      })
+      .unwrap();
+    let tenants = Arc::new(Tenants::new(dir.path().to_path_buf(), Backend::VecPack));
+    let ready = tenants.ready();
+    assert!(!*ready.borrow());
+    // Requests wait for the warm-up
+    let get = tokio::spawn({
+      let tenants = tenants.clone();
+      async move { tenants.get("shop_a").await.unwrap().lock().await.len() }
+    });
+    tenants.warm_up().await.unwrap();
+    assert_eq!(get.await.unwrap(), 1);
+    assert!(*ready.borrow());
+    let warm_up = tenants.warm_up_state();
+    assert_eq!((warm_up.tenants_total, warm_up.ready), (2, true));
+    assert_eq!(
+      warm_up
+        .tenants
+        .iter()
+        .map(|t| (t.tenant.as_str(), t.customer_count))
+        .collect::<Vec<_>>(),
+      [("", 0), ("shop_a", 1)]
+    );
+    assert!(tenants.storage_bytes("shop_a").unwrap() > 0);
+    assert_eq!(tenants.storage_bytes("").unwrap(), 0);
+    assert_eq!(tenants.loaded().await.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_log_import() {
+    let dir = tempfile::tempdir().unwrap();
+    load_db(dir.path(), "shop_a")
+      .unwrap()
+      .insert(Customer {
+        id: 1,
+        ..Customer::default()
+      })
+      .unwrap();
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::Log).unwrap();
+    let pack = tenants.get("shop_a").await.unwrap();
+    assert_eq!(pack.lock().await.len(), 1);
+    pack
+      .lock()
+      .await
+      .insert(Customer {
+        id: 2,
+        ..Customer::default()
+      })
+      .unwrap();
+    assert!(dir.path().join("shop_a/customers.log").is_file());
+    // VecPack storage is left untouched
+    assert_eq!(load_db(dir.path(), "shop_a").unwrap().len(), 1);
+    assert_eq!(tenants.reload("shop_a").await.unwrap(), 2);
+    // Tenants with log only are found too
+    std::fs::remove_dir_all(dir.path().join("shop_a/customers")).unwrap();
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::Log).unwrap();
+    assert_eq!(tenants.ids().await.unwrap(), ["", "shop_a"]);
+    assert_eq!(tenants.get("shop_a").await.unwrap().lock().await.len(), 2);
+    // Lazy storage uses the same log
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::Lazy).unwrap();
+    assert_eq!(tenants.get("shop_a").await.unwrap().lock().await.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_shard_import() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = load_db(dir.path(), "shop_a").unwrap();
+    for id in [1, 15000] {
+      db.insert(Customer {
+        id,
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::Sharded(10_000)).unwrap();
+    assert_eq!(tenants.get("shop_a").await.unwrap().lock().await.len(), 2);
+    assert!(dir
+      .path()
+      .join("shop_a/customers_shards/10000.log")
+      .is_file());
+    // Tenants with shards only are found too
+    std::fs::remove_dir_all(dir.path().join("shop_a/customers")).unwrap();
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::Sharded(1000)).unwrap();
+    assert_eq!(tenants.ids().await.unwrap(), ["", "shop_a"]);
+    assert_eq!(tenants.get("shop_a").await.unwrap().lock().await.len(), 2);
+    assert!(dir
+      .path()
+      .join("shop_a/customers_shards/15000.log")
+      .is_file());
+    assert!(tenants.storage_bytes("shop_a").unwrap() > 0);
+  }
+
+  #[tokio::test]
+  async fn test_sqlite_import() {
+    let dir = tempfile::tempdir().unwrap();
+    load_db(dir.path(), "shop_a")
+      .unwrap()
+      .insert(Customer {
+        id: 1,
+        ..Customer::default()
+      })
+      .unwrap();
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::Sqlite).unwrap();
+    let pack = tenants.get("shop_a").await.unwrap();
+    assert_eq!(pack.lock().await.len(), 1);
+    pack
+      .lock()
+      .await
+      .insert(Customer {
+        id: 2,
+        ..Customer::default()
+      })
+      .unwrap();
+    assert!(dir.path().join("shop_a/customers.sqlite").is_file());
+    assert!(!dir.path().join("shop_a/customers.sqlite_import").exists());
+    assert!(tenants.storage_bytes("shop_a").unwrap() > 0);
+    // VecPack storage is left untouched
+    assert_eq!(load_db(dir.path(), "shop_a").unwrap().len(), 1);
+    assert_eq!(tenants.reload("shop_a").await.unwrap(), 2);
+    // Tenants with a database only are found too
+    std::fs::remove_dir_all(dir.path().join("shop_a/customers")).unwrap();
+    assert_eq!(tenant_ids(dir.path()).unwrap(), ["", "shop_a"]);
+  }
+
+  #[test]
+  fn test_corrupt_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = load_db(dir.path(), "shop_a").unwrap();
+    for id in [1, 2] {
+      db.insert(Customer {
+        id,
+        ..Customer::default()
+      })
+      .unwrap();
+    }
+    drop(db);
+    std::fs::write(dir.path().join("shop_a/customers/2"), b"broken").unwrap();
+    // Service starts with the readable customers
+    let tenants = Tenants::load(dir.path().to_path_buf(), Backend::VecPack).unwrap();
+    drop(tenants);
+    assert_eq!(load_db(dir.path(), "shop_a").unwrap().len(), 1);
+    let records = integrity::corrupt_records(dir.path(), "shop_a").unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].source, "customers/2");
+    assert_eq!(records[0].size, 6);
+    assert_eq!(
+      std::fs::read(dir.path().join("shop_a/customers_corrupt/1")).unwrap(),
+      b"broken"
+    );
+    assert!(integrity::corrupt_records(dir.path(), DEFAULT_TENANT)
+      .unwrap()
+      .is_empty());
+  }
+}
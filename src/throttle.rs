@@ -0,0 +1,320 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Write throttling
+//
+// Mutation RPCs are limited per caller with a token bucket, so a
+// runaway import script cannot starve interactive users. The caller
+// is the editor-uid metadata, or the user field of the request, e.g.
+// created_by of CreateNew. Callers are separate per tenant, requests
+// without a caller share the bucket of UID 0. The generated service
+// is wrapped, so throttled requests are rejected with
+// RESOURCE_EXHAUSTED before they reach the service.
+// The caller is supplied by the client and is not authenticated, so
+// the throttle only protects against well behaved clients going
+// wrong. A client can send writes in the name of other callers to
+// get more buckets, or to use up the bucket of an other caller.
+
+use crate::edit_lock::EDITOR_METADATA_KEY;
+use crate::metrics::Metrics;
+use crate::payload;
+use crate::prelude::*;
+use crate::proto::customer::*;
+use crate::tenant::TENANT_METADATA_KEY;
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::transport::{Body, NamedService};
+use tonic::Status;
+
+// Default bucket size, the writes a caller can send at once
+pub const DEFAULT_BURST: u32 = 20;
+
+// Above this many callers, the full buckets are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+// Length prefixed message header size
+const HEADER_LEN: usize = 5;
+
+// User field of an encoded request
+type UserField = fn(&[u8]) -> Option<u32>;
+
+// Mutation RPCs, each with the user field of its request
+// None if the caller is only known from the editor-uid metadata
+const MUTATIONS: &[(&str, Option<UserField>)] = &[
+  (
+    "CreateNew",
+    Some(|m| uid::<NewCustomerObj>(m, |r| r.created_by)),
+  ),
+  (
+    "QuickCreate",
+    Some(|m| uid::<QuickCreateRequest>(m, |r| r.created_by)),
+  ),
+  (
+    "ReserveCustomerIds",
+    Some(|m| uid::<ReserveCustomerIdsRequest>(m, |r| r.reserved_by)),
+  ),
+  ("UpdateById", None),
+  (
+    "Deactivate",
+    Some(|m| uid::<DeactivateRequest>(m, |r| r.changed_by)),
+  ),
+  (
+    "Reactivate",
+    Some(|m| uid::<ReactivateRequest>(m, |r| r.changed_by)),
+  ),
+  (
+    "AttachDocument",
+    Some(|m| uid::<AttachDocumentRequest>(m, |r| r.uploaded_by)),
+  ),
+  ("RemoveDocument", None),
+  ("SetInvoiceDetails", None),
+  ("SetVatStatus", None),
+  (
+    "SetDiscount",
+    Some(|m| uid::<SetDiscountRequest>(m, |r| r.set_by)),
+  ),
+  ("AddAlias", None),
+  ("RemoveAlias", None),
+  (
+    "GenerateProfileToken",
+    Some(|m| uid::<GenerateProfileTokenRequest>(m, |r| r.created_by)),
+  ),
+  ("UpdateByProfileToken", None),
+  ("AddTagBulk", None),
+  ("RemoveTagBulk", None),
+  ("AssignGroupBulk", None),
+  ("SetPrivacyFlags", None),
+  ("SetCustomFields", None),
+  ("RecordActivity", None),
+  (
+    "AppendCommunication",
+    Some(|m| uid::<AppendCommunicationRequest>(m, |r| r.created_by)),
+  ),
+  ("RequestErasure", None),
+  ("ImportCustomers", None),
+];
+
+fn uid<M: Message + Default>(message: &[u8], f: fn(&M) -> u32) -> Option<u32> {
+  M::decode(message).ok().map(|m| f(&m))
+}
+
+// Caller of a mutation RPC, None for other RPCs
+fn caller(method: &str, headers: &http::HeaderMap, body: &[u8]) -> Option<u32> {
+  let (_, user_field) = MUTATIONS.iter().find(|(m, _)| *m == method)?;
+  let editor = headers
+    .get(EDITOR_METADATA_KEY)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.trim().parse().ok());
+  // Compressed or incomplete messages are not decoded
+  let from_request = || match (user_field, body.len() >= HEADER_LEN && body[0] == 0) {
+    (Some(f), true) => f(&body[HEADER_LEN..]),
+    _ => None,
+  };
+  Some(editor.or_else(from_request).unwrap_or(0))
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+// Token buckets of the callers
+// Every caller gets rate_per_sec writes per second, and can send
+// burst writes at once after being idle
+pub struct Limiter {
+  rate_per_sec: f64,
+  burst: f64,
+  buckets: Mutex<HashMap<(String, u32), Bucket>>,
+}
+
+impl Limiter {
+  pub fn new(rate_per_sec: f64, burst: u32) -> Self {
+    Self {
+      rate_per_sec,
+      burst: burst.max(1) as f64,
+      buckets: Mutex::new(HashMap::new()),
+    }
+  }
+  fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * self.rate_per_sec).min(self.burst)
+  }
+  // Take a token of the caller
+  // Returns how long to wait for the next token if there is none
+  pub fn check(&self, tenant: &str, caller: u32, now: Instant) -> Result<(), Duration> {
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.len() >= MAX_BUCKETS {
+      buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+    }
+    let bucket = buckets
+      .entry((tenant.to_string(), caller))
+      .or_insert(Bucket {
+        tokens: self.burst,
+        updated: now,
+      });
+    bucket.tokens = self.refill(bucket, now);
+    bucket.updated = now;
+    match bucket.tokens >= 1.0 {
+      true => {
+        bucket.tokens -= 1.0;
+        Ok(())
+      }
+      false => Err(Duration::from_secs_f64(
+        (1.0 - bucket.tokens) / self.rate_per_sec,
+      )),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct Throttle<S> {
+  inner: S,
+  // None if writes are not throttled
+  limiter: Option<Arc<Limiter>>,
+  metrics: Arc<Metrics>,
+}
+
+impl<S> Throttle<S> {
+  pub fn new(inner: S, limiter: Option<Arc<Limiter>>, metrics: Arc<Metrics>) -> Self {
+    Self {
+      inner,
+      limiter,
+      metrics,
+    }
+  }
+}
+
+impl<S: NamedService> NamedService for Throttle<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for Throttle<S>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  type Response = http::Response<BoxBody>;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+    let limiter = self.limiter.clone();
+    let metrics = self.metrics.clone();
+    // Errors of the throttle run outside of the locale negotiation
+    let locale = Locale::from_accept_language(
+      request
+        .headers()
+        .get(ACCEPT_LANGUAGE_METADATA_KEY)
+        .and_then(|v| v.to_str().ok()),
+    );
+    // Take the service that was polled ready
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    Box::pin(with_locale(locale, async move {
+      let limiter = match limiter {
+        Some(limiter) => limiter,
+        None => return inner.call(request).await,
+      };
+      let (mut parts, body) = request.into_parts();
+      // Requests are unary, so they are read as a whole
+      let body = match payload::buffered(&mut parts, body).await {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+      };
+      let method = parts.uri.path().rsplit('/').next().unwrap_or("");
+      if let Some(caller) = caller(method, &parts.headers, &body) {
+        let tenant = parts
+          .headers
+          .get(TENANT_METADATA_KEY)
+          .and_then(|v| v.to_str().ok())
+          .unwrap_or("");
+        if let Err(wait) = limiter.check(tenant, caller, Instant::now()) {
+          metrics.record_throttled(parts.uri.path());
+          let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+          return Ok(
+            Status::from(ServiceError::resource_exhausted(&format!(
+              "Túl sok módosítás, próbálja újra {} másodperc múlva",
+              secs
+            )))
+            .to_http(),
+          );
+        }
+      }
+      inner
+        .call(http::Request::from_parts(parts, Body::from(body)))
+        .await
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_limiter() {
+    let limiter = Limiter::new(2.0, 3);
+    let now = Instant::now();
+    for _ in 0..3 {
+      assert!(limiter.check("", 7, now).is_ok());
+    }
+    assert_eq!(limiter.check("", 7, now), Err(Duration::from_millis(500)));
+    // Other callers and tenants have their own buckets
+    assert!(limiter.check("", 8, now).is_ok());
+    assert!(limiter.check("shop_a", 7, now).is_ok());
+    // Refilled by the rate, up to the burst
+    let later = now + Duration::from_millis(500);
+    assert!(limiter.check("", 7, later).is_ok());
+    assert!(limiter.check("", 7, later).is_err());
+    let idle = later + Duration::from_secs(60);
+    for _ in 0..3 {
+      assert!(limiter.check("", 7, idle).is_ok());
+    }
+    assert!(limiter.check("", 7, idle).is_err());
+  }
+
+  #[test]
+  fn test_caller() {
+    let request = QuickCreateRequest {
+      created_by: 5,
+      ..QuickCreateRequest::default()
+    };
+    let mut body = vec![0, 0, 0, 0, request.encoded_len() as u8];
+    request.encode(&mut body).unwrap();
+    let mut headers = http::HeaderMap::new();
+    assert_eq!(caller("QuickCreate", &headers, &body), Some(5));
+    // Queries are not throttled
+    assert_eq!(caller("GetById", &headers, &body), None);
+    // Without user field
+    assert_eq!(caller("UpdateById", &headers, &body), Some(0));
+    // The editor-uid metadata wins
+    headers.insert(EDITOR_METADATA_KEY, "9".parse().unwrap());
+    assert_eq!(caller("QuickCreate", &headers, &body), Some(9));
+    assert_eq!(caller("UpdateById", &headers, &body), Some(9));
+  }
+}
@@ -0,0 +1,165 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// User names
+//
+// created_by and the other user fields are UIDs of the user service.
+// The expanded view of a customer also returns the display name of
+// its creator, asked from the user service for every missing user at
+// once, and cached for the configured time. Names are soft references:
+// without endpoint, or when the user service does not answer in time,
+// names are left empty and the customer is returned anyway. Unknown
+// users are cached as empty names too, failed calls are not cached.
+
+use crate::enrichment::channel;
+use crate::prelude::*;
+use crate::proto::user::user_client::UserClient;
+use crate::proto::user::GetDisplayNamesRequest;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+
+// Calls not answered in time leave the names empty
+const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Default time names are cached for
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+// Above this many cached names, the expired ones are dropped
+const MAX_ENTRIES: usize = 10_000;
+
+pub struct UserNames {
+  client: Option<UserClient<Channel>>,
+  ttl: Duration,
+  // (tenant, uid) => (display name, cached at)
+  cache: Mutex<HashMap<(String, u32), (String, Instant)>>,
+}
+
+impl UserNames {
+  // Must be called from the tokio runtime when an endpoint is set
+  pub fn new(endpoint: Option<&str>, ttl: Duration) -> ServiceResult<Self> {
+    Ok(Self {
+      client: match endpoint {
+        Some(endpoint) => Some(UserClient::new(channel("user", endpoint)?)),
+        None => None,
+      },
+      ttl,
+      cache: Mutex::new(HashMap::new()),
+    })
+  }
+  // Cached names, and the users missing from the cache
+  fn cached(&self, tenant: &str, uids: &[u32], now: Instant) -> (HashMap<u32, String>, Vec<u32>) {
+    let cache = self.cache.lock().unwrap();
+    let (mut names, mut missing) = (HashMap::new(), Vec::new());
+    for uid in uids {
+      match cache.get(&(tenant.to_string(), *uid)) {
+        Some((name, cached_at)) if now.saturating_duration_since(*cached_at) < self.ttl => {
+          names.insert(*uid, name.clone());
+        }
+        _ => missing.push(*uid),
+      }
+    }
+    (names, missing)
+  }
+  // Cache the names of the asked users, the unknown ones as empty
+  fn store(&self, tenant: &str, asked: &[u32], names: &HashMap<u32, String>, now: Instant) {
+    let mut cache = self.cache.lock().unwrap();
+    if cache.len() >= MAX_ENTRIES {
+      let ttl = self.ttl;
+      cache.retain(|_, (_, cached_at)| now.saturating_duration_since(*cached_at) < ttl);
+    }
+    for uid in asked {
+      let name = names.get(uid).cloned().unwrap_or_default();
+      cache.insert((tenant.to_string(), *uid), (name, now));
+    }
+  }
+  async fn fetch(&self, tenant: &str, uids: &[u32]) -> Result<HashMap<u32, String>, String> {
+    let mut client = match &self.client {
+      // Clients share the channel
+      Some(client) => client.clone(),
+      None => return Err("No user service endpoint".to_string()),
+    };
+    let request = GetDisplayNamesRequest {
+      tenant: tenant.to_string(),
+      uids: uids.to_vec(),
+    };
+    let res = tokio::time::timeout(CALL_TIMEOUT, client.get_display_names(request))
+      .await
+      .map_err(|_| "User service timed out".to_string())?
+      .map_err(|e| format!("User service: {}", e))?
+      .into_inner();
+    Ok(res.display_names.into_iter().collect())
+  }
+  // Display names of users, unknown ones are missing
+  // UID 0 is the service itself, it is never asked
+  pub async fn names(&self, tenant: &str, uids: &[u32]) -> HashMap<u32, String> {
+    let mut uids = uids
+      .iter()
+      .copied()
+      .filter(|uid| *uid != 0)
+      .collect::<Vec<u32>>();
+    uids.sort_unstable();
+    uids.dedup();
+    let (mut names, missing) = self.cached(tenant, &uids, Instant::now());
+    if !missing.is_empty() && self.client.is_some() {
+      match self.fetch(tenant, &missing).await {
+        Ok(fetched) => {
+          self.store(tenant, &missing, &fetched, Instant::now());
+          names.extend(fetched);
+        }
+        Err(error) => eprintln!("Error while getting user names: {}", error),
+      }
+    }
+    names.retain(|_, name| !name.is_empty());
+    names
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cache() {
+    let names = UserNames::new(None, Duration::from_secs(60)).unwrap();
+    let now = Instant::now();
+    let fetched = vec![(7, "Kiss Béla".to_string())].into_iter().collect();
+    names.store("", &[7, 8], &fetched, now);
+    let (cached, missing) = names.cached("", &[7, 8, 9], now + Duration::from_secs(30));
+    assert_eq!(cached.get(&7).map(|n| n.as_str()), Some("Kiss Béla"));
+    // Unknown users are cached as empty names
+    assert_eq!(cached.get(&8).map(|n| n.as_str()), Some(""));
+    assert_eq!(missing, [9]);
+    // Tenants are cached separately
+    assert_eq!(names.cached("shop_a", &[7], now).1, [7]);
+    // Expired
+    assert_eq!(names.cached("", &[7], now + Duration::from_secs(60)).1, [7]);
+  }
+
+  #[tokio::test]
+  async fn test_names_without_service() {
+    let names = UserNames::new(None, DEFAULT_TTL).unwrap();
+    assert!(names.names("", &[7]).await.is_empty());
+    // Nothing listens on the port
+    let names = UserNames::new(Some("http://127.0.0.1:9"), DEFAULT_TTL).unwrap();
+    assert!(names.names("", &[0, 7, 7]).await.is_empty());
+    // Failed calls are not cached
+    assert_eq!(names.cached("", &[7], Instant::now()).1, [7]);
+    assert!(UserNames::new(Some("nem url"), DEFAULT_TTL).is_err());
+  }
+}
@@ -0,0 +1,311 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Special VAT treatment
+//
+// Customers can be subject to a special VAT treatment for a date
+// range: subject tax exemption (alanyi adómentesség), domestic
+// reverse charge (fordított áfa) or EU reverse charge. Periods
+// are kept as a history, so invoicing can look up the treatment
+// of any past date. Customers without a period on a date are
+// charged normal VAT.
+
+use crate::country;
+use crate::prelude::*;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Max stored periods of a customer
+pub const MAX_VAT_PERIODS: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VatTreatment {
+  // Alanyi adómentes
+  SubjectExempt,
+  // Belföldi fordított adózás
+  DomesticReverseCharge,
+  // Közösségen belüli fordított adózás
+  EuReverseCharge,
+}
+
+// Both dates are inclusive, open ended without valid_until
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VatPeriod {
+  pub treatment: VatTreatment,
+  pub valid_from: NaiveDate,
+  pub valid_until: Option<NaiveDate>,
+}
+
+// VAT treatment on a date, with the range it is valid for
+// Treatment is None for normal VAT, its range is the gap
+// between the special periods
+#[derive(Clone, Debug, PartialEq)]
+pub struct VatStatus {
+  pub treatment: Option<VatTreatment>,
+  pub valid_from: Option<NaiveDate>,
+  pub valid_until: Option<NaiveDate>,
+}
+
+impl VatPeriod {
+  fn contains(&self, date: NaiveDate) -> bool {
+    self.valid_from <= date && self.valid_until.is_none_or(|until| date <= until)
+  }
+}
+
+// Check the treatment can be used for the customer
+pub fn check_treatment(
+  treatment: VatTreatment,
+  customer_country: &str,
+  private: bool,
+) -> ServiceResult<()> {
+  if private {
+    return Err(ServiceError::invalid_field(
+      "treatment",
+      "Magánszemélyre nem állítható be különleges áfa státusz",
+    ));
+  }
+  let domestic = customer_country == country::DEFAULT_COUNTRY;
+  match treatment {
+    VatTreatment::SubjectExempt | VatTreatment::DomesticReverseCharge if !domestic => {
+      Err(ServiceError::invalid_field(
+        "treatment",
+        "Ez az áfa státusz csak magyar ügyfélre állítható be",
+      ))
+    }
+    VatTreatment::EuReverseCharge if domestic || !country::is_eu(customer_country) => {
+      Err(ServiceError::invalid_field(
+        "treatment",
+        "Közösségi fordított adózás csak más EU tagállambeli ügyfélre állítható be",
+      ))
+    }
+    _ => Ok(()),
+  }
+}
+
+// Set treatment from valid_from until valid_until
+// None sets normal VAT for the range. The overlapping parts of
+// other periods are cut, so the latest setting wins for its range.
+pub fn set_period(
+  periods: &mut Vec<VatPeriod>,
+  treatment: Option<VatTreatment>,
+  valid_from: NaiveDate,
+  valid_until: Option<NaiveDate>,
+) -> ServiceResult<()> {
+  if valid_until.is_some_and(|until| until < valid_from) {
+    return Err(ServiceError::invalid_field(
+      "valid_until",
+      "Az időszak vége nem lehet korábbi a kezdeténél",
+    ));
+  }
+  let mut res = Vec::new();
+  for p in periods.iter() {
+    // Part before the new range
+    if p.valid_from < valid_from {
+      let day_before = valid_from.pred_opt().unwrap_or(valid_from);
+      res.push(VatPeriod {
+        valid_until: Some(p.valid_until.map_or(day_before, |u| u.min(day_before))),
+        ..p.clone()
+      });
+    }
+    // Part after the new range
+    if let Some(until) = valid_until {
+      if p.valid_until.is_none_or(|u| u > until) {
+        let day_after = until.succ_opt().unwrap_or(until);
+        res.push(VatPeriod {
+          valid_from: p.valid_from.max(day_after),
+          ..p.clone()
+        });
+      }
+    }
+  }
+  if let Some(treatment) = treatment {
+    res.push(VatPeriod {
+      treatment,
+      valid_from,
+      valid_until,
+    });
+  }
+  if res.len() > MAX_VAT_PERIODS {
+    return Err(ServiceError::bad_request(&format!(
+      "Legfeljebb {} áfa időszak tárolható",
+      MAX_VAT_PERIODS
+    )));
+  }
+  res.sort_by_key(|p| p.valid_from);
+  *periods = res;
+  Ok(())
+}
+
+// VAT treatment on a date
+pub fn status_at(periods: &[VatPeriod], date: NaiveDate) -> VatStatus {
+  if let Some(p) = periods.iter().find(|p| p.contains(date)) {
+    return VatStatus {
+      treatment: Some(p.treatment),
+      valid_from: Some(p.valid_from),
+      valid_until: p.valid_until,
+    };
+  }
+  // Normal VAT between the previous and the next period
+  VatStatus {
+    treatment: None,
+    valid_from: periods
+      .iter()
+      .filter_map(|p| p.valid_until)
+      .filter(|until| *until < date)
+      .max()
+      .and_then(|until| until.succ_opt()),
+    valid_until: periods
+      .iter()
+      .map(|p| p.valid_from)
+      .filter(|from| *from > date)
+      .min()
+      .and_then(|from| from.pred_opt()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn day(s: &str) -> NaiveDate {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+  }
+
+  fn ranges(periods: &[VatPeriod]) -> Vec<(VatTreatment, String, String)> {
+    periods
+      .iter()
+      .map(|p| {
+        (
+          p.treatment,
+          p.valid_from.to_string(),
+          p.valid_until.map(|u| u.to_string()).unwrap_or_default(),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_set_period() {
+    use VatTreatment::*;
+    let mut periods = Vec::new();
+    set_period(&mut periods, Some(SubjectExempt), day("2020-01-01"), None).unwrap();
+    // Open ended period is closed by the next one
+    set_period(
+      &mut periods,
+      Some(DomesticReverseCharge),
+      day("2021-07-01"),
+      None,
+    )
+    .unwrap();
+    assert_eq!(
+      ranges(&periods),
+      [
+        (SubjectExempt, "2020-01-01".into(), "2021-06-30".into()),
+        (DomesticReverseCharge, "2021-07-01".into(), "".into()),
+      ]
+    );
+    // Normal VAT cut out of the middle
+    set_period(
+      &mut periods,
+      None,
+      day("2022-01-01"),
+      Some(day("2022-03-31")),
+    )
+    .unwrap();
+    assert_eq!(
+      ranges(&periods),
+      [
+        (SubjectExempt, "2020-01-01".into(), "2021-06-30".into()),
+        (
+          DomesticReverseCharge,
+          "2021-07-01".into(),
+          "2021-12-31".into()
+        ),
+        (DomesticReverseCharge, "2022-04-01".into(), "".into()),
+      ]
+    );
+    // Covering periods replace them
+    set_period(
+      &mut periods,
+      Some(SubjectExempt),
+      day("2019-01-01"),
+      Some(day("2021-12-31")),
+    )
+    .unwrap();
+    assert_eq!(
+      ranges(&periods),
+      [
+        (SubjectExempt, "2019-01-01".into(), "2021-12-31".into()),
+        (DomesticReverseCharge, "2022-04-01".into(), "".into()),
+      ]
+    );
+    assert!(set_period(
+      &mut periods,
+      None,
+      day("2022-01-02"),
+      Some(day("2022-01-01"))
+    )
+    .is_err());
+    assert_eq!(periods.len(), 2);
+  }
+
+  #[test]
+  fn test_status_at() {
+    use VatTreatment::*;
+    let mut periods = Vec::new();
+    set_period(
+      &mut periods,
+      Some(SubjectExempt),
+      day("2020-01-01"),
+      Some(day("2020-12-31")),
+    )
+    .unwrap();
+    set_period(&mut periods, Some(EuReverseCharge), day("2022-01-01"), None).unwrap();
+    let status = status_at(&periods, day("2020-06-15"));
+    assert_eq!(status.treatment, Some(SubjectExempt));
+    assert_eq!(status.valid_until, Some(day("2020-12-31")));
+    let status = status_at(&periods, day("2021-05-01"));
+    assert_eq!(
+      status,
+      VatStatus {
+        treatment: None,
+        valid_from: Some(day("2021-01-01")),
+        valid_until: Some(day("2021-12-31")),
+      }
+    );
+    assert_eq!(
+      status_at(&periods, day("2030-01-01")).treatment,
+      Some(EuReverseCharge)
+    );
+    let status = status_at(&periods, day("2019-01-01"));
+    assert_eq!(
+      (status.valid_from, status.valid_until),
+      (None, Some(day("2019-12-31")))
+    );
+  }
+
+  #[test]
+  fn test_check_treatment() {
+    use VatTreatment::*;
+    assert!(check_treatment(SubjectExempt, "HU", false).is_ok());
+    assert!(check_treatment(SubjectExempt, "HU", true).is_err());
+    assert!(check_treatment(DomesticReverseCharge, "AT", false).is_err());
+    assert!(check_treatment(EuReverseCharge, "AT", false).is_ok());
+    assert!(check_treatment(EuReverseCharge, "HU", false).is_err());
+    assert!(check_treatment(EuReverseCharge, "US", false).is_err());
+  }
+}
@@ -0,0 +1,165 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Validation warnings
+//
+// Soft checks of saved customers. Unlike validation errors,
+// warnings do not block saving, they are returned with the
+// create and update responses so the client can ask the
+// user to double check the data.
+
+use crate::country;
+use crate::customer::Customer;
+use crate::policy::ValidationPolicy;
+use crate::prelude::*;
+use crate::zip::ZipDb;
+
+// Frequent misspellings of common email domains
+const EMAIL_DOMAIN_TYPOS: &[(&str, &str)] = &[
+  ("gmial.com", "gmail.com"),
+  ("gamil.com", "gmail.com"),
+  ("gmail.hu", "gmail.com"),
+  ("gmai.com", "gmail.com"),
+  ("fremail.hu", "freemail.hu"),
+  ("freemial.hu", "freemail.hu"),
+  ("citromial.hu", "citromail.hu"),
+  ("hotmial.com", "hotmail.com"),
+];
+
+// Run all soft checks of a customer
+pub fn check(
+  customer: &Customer,
+  zip_db: &ZipDb,
+  policy: &ValidationPolicy,
+) -> Vec<FieldViolation> {
+  let mut warnings = Violations::default();
+  if customer.country == country::DEFAULT_COUNTRY {
+    // Zip mismatches are errors in strict zip mode
+    if !policy.strict_zip {
+      warnings.check(
+        "address_location",
+        zip_db.validate(&customer.address_zip, &customer.address_location),
+      );
+      if let Some(address) = &customer.invoice_address {
+        warnings.check(
+          "invoice_address_location",
+          zip_db.validate(&address.zip, &address.location),
+        );
+      }
+    }
+    if looks_foreign(&customer.phone) {
+      warnings.add("phone", "A telefonszám külföldinek tűnik");
+    }
+  }
+  if let Some((typo, domain)) = email_domain_typo(&customer.email) {
+    warnings.add(
+      "email",
+      &format!(
+        "Az email cím domainje elírásnak tűnik: {}, helyesen: {}?",
+        typo, domain
+      ),
+    );
+  }
+  let letters = customer
+    .name
+    .chars()
+    .filter(|c| c.is_alphabetic())
+    .collect::<Vec<char>>();
+  if letters.len() > 3 && letters.iter().all(|c| c.is_uppercase()) {
+    warnings.add("name", "A név csupa nagybetűvel van megadva");
+  }
+  warnings.into_vec()
+}
+
+// Phone number with a non Hungarian country code
+fn looks_foreign(phone: &str) -> bool {
+  let phone = phone.trim().replace([' ', '-', '/', '(', ')'], "");
+  let international = phone.strip_prefix('+').or_else(|| phone.strip_prefix("00"));
+  match international {
+    Some(number) => !number.starts_with("36"),
+    None => false,
+  }
+}
+
+fn email_domain_typo(email: &str) -> Option<(&'static str, &'static str)> {
+  if !email.contains('@') {
+    return None;
+  }
+  let domain = email.trim().rsplit('@').next()?.to_lowercase();
+  EMAIL_DOMAIN_TYPOS
+    .iter()
+    .find(|(typo, _)| *typo == domain)
+    .copied()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn customer() -> Customer {
+    Customer {
+      name: "Kiss Béla".to_string(),
+      email: "kiss.bela@gmail.com".to_string(),
+      phone: "+36 30 123 4567".to_string(),
+      address_zip: "6000".to_string(),
+      address_location: "Kecskemét".to_string(),
+      country: country::DEFAULT_COUNTRY.to_string(),
+      ..Customer::default()
+    }
+  }
+
+  fn fields(warnings: Vec<FieldViolation>) -> Vec<String> {
+    warnings.into_iter().map(|w| w.field).collect()
+  }
+
+  #[test]
+  fn test_check() {
+    let zip_db = ZipDb::from_csv("6000;Kecskemét\n1011;Budapest\n");
+    let relaxed = ValidationPolicy {
+      strict_zip: false,
+      ..ValidationPolicy::default()
+    };
+    assert!(check(&customer(), &zip_db, &relaxed).is_empty());
+    let mut c = customer();
+    c.address_location = "Budapest".to_string();
+    c.phone = "0043 664 1234567".to_string();
+    c.email = "kiss.bela@GMIAL.com".to_string();
+    c.name = "KISS BÉLA".to_string();
+    assert_eq!(
+      fields(check(&c, &zip_db, &relaxed)),
+      ["address_location", "phone", "email", "name"]
+    );
+    // Zip mismatches are errors in strict mode, not warnings
+    assert_eq!(
+      fields(check(&c, &zip_db, &ValidationPolicy::default())),
+      ["phone", "email", "name"]
+    );
+    // Foreign phones are expected from foreign customers
+    c.country = "AT".to_string();
+    assert_eq!(fields(check(&c, &zip_db, &relaxed)), ["email", "name"]);
+  }
+
+  #[test]
+  fn test_looks_foreign() {
+    assert!(!looks_foreign("+36 30 123 4567"));
+    assert!(!looks_foreign("06-30/123-4567"));
+    assert!(!looks_foreign("0036301234567"));
+    assert!(!looks_foreign(""));
+    assert!(looks_foreign("+43 664 1234567"));
+    assert!(looks_foreign("0049 151 1234567"));
+  }
+}
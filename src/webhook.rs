@@ -0,0 +1,501 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Webhook subscriptions
+//
+// External systems register a URL to get customer events pushed,
+// instead of polling. Events are posted as JSON, signed with the
+// webhook secret. The customer is shaped for the most restrictive
+// role, like for callers without caller token. Every webhook has its
+// own outbox in data/webhook_outbox/<id>, so a webhook being down
+// does not hold back the others. Webhook IDs are never reused.
+
+use crate::auth::Role;
+use crate::events::{CustomerEvent, CustomerEventKind};
+use crate::outbox::{run_delivery, EventSink, Outbox};
+use crate::prelude::*;
+use crate::proto::customer::CustomerObj;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use packman::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+// Request headers of the webhook deliveries
+pub const EVENT_HEADER: &str = "x-gardenzilla-event";
+pub const TIMESTAMP_HEADER: &str = "x-gardenzilla-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-gardenzilla-signature";
+
+const MIN_SECRET_LEN: usize = 16;
+const MAX_URL_LEN: usize = 2048;
+
+// Delivery fails if the receiver does not respond in time
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Client = hyper::Client<HttpsConnector<HttpConnector>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Webhook {
+  pub id: u32,
+  pub tenant: String,
+  pub url: String,
+  pub secret: String,
+  // Empty means all event kinds
+  pub event_kinds: Vec<CustomerEventKind>,
+  pub created_by: u32,
+  pub date_created: DateTime<Utc>,
+}
+
+impl VecPackMember for Webhook {
+  type Out = u32;
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+impl Webhook {
+  // Check webhook subscribes to the event
+  pub fn matches(&self, event: &CustomerEvent) -> bool {
+    self.tenant == event.tenant
+      && (self.event_kinds.is_empty() || self.event_kinds.iter().any(|k| k.covers(&event.kind)))
+  }
+  pub fn validate(&self) -> ServiceResult<()> {
+    let is_valid_url = self.url.len() <= MAX_URL_LEN
+      && match self.url.parse::<hyper::Uri>() {
+        Ok(uri) => matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some(),
+        Err(_) => false,
+      };
+    if !is_valid_url {
+      return Err(ServiceError::invalid_field(
+        "url",
+        "Hibás webhook URL. Csak http:// vagy https:// URL adható meg",
+      ));
+    }
+    if self.secret.chars().count() < MIN_SECRET_LEN {
+      return Err(ServiceError::invalid_field(
+        "secret",
+        &format!(
+          "A webhook titkos kulcsa legalább {} karakter",
+          MIN_SECRET_LEN
+        ),
+      ));
+    }
+    Ok(())
+  }
+}
+
+// Signature of a delivery
+// Hex encoded HMAC-SHA256 of "<timestamp>.<body>"
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+  mac.update(format!("{}.{}", timestamp, body).as_bytes());
+  mac
+    .finalize()
+    .into_bytes()
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+// Event kind header value
+fn event_kind_name(kind: CustomerEventKind) -> &'static str {
+  match kind {
+    CustomerEventKind::Created => "created",
+    CustomerEventKind::Updated => "updated",
+    CustomerEventKind::SegmentEntered(_) => "segment_entered",
+    CustomerEventKind::SegmentLeft(_) => "segment_left",
+  }
+}
+
+// Request body of a delivery
+#[derive(Serialize)]
+struct Payload<'a> {
+  tenant: &'a str,
+  kind: CustomerEventKind,
+  customer: CustomerObj,
+}
+
+impl<'a> Payload<'a> {
+  fn new(event: &'a CustomerEvent) -> Self {
+    Self {
+      tenant: &event.tenant,
+      kind: event.kind,
+      customer: customer_to_obj(event.customer.clone(), Role::default()),
+    }
+  }
+}
+
+// Last webhook ID given out, kept after the webhook is deleted
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct WebhookIds {
+  last_id: u32,
+}
+
+// Posts events to a webhook URL
+struct WebhookSink {
+  webhook: Webhook,
+  client: Client,
+}
+
+#[tonic::async_trait]
+impl EventSink for WebhookSink {
+  async fn deliver(&self, _id: u64, event: &CustomerEvent) -> Result<(), String> {
+    let body = serde_json::to_string(&Payload::new(event)).map_err(|e| e.to_string())?;
+    let timestamp = Utc::now().timestamp();
+    let signature = sign(&self.webhook.secret, timestamp, &body);
+    let request = hyper::Request::post(&self.webhook.url)
+      .header("content-type", "application/json")
+      .header(EVENT_HEADER, event_kind_name(event.kind))
+      .header(TIMESTAMP_HEADER, timestamp)
+      .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+      .body(hyper::Body::from(body))
+      .map_err(|e| e.to_string())?;
+    let response = tokio::time::timeout(DELIVERY_TIMEOUT, self.client.request(request))
+      .await
+      .map_err(|_| format!("Webhook {} timed out", self.webhook.id))?
+      .map_err(|e| format!("Webhook {}: {}", self.webhook.id, e))?;
+    match response.status().is_success() {
+      true => Ok(()),
+      false => Err(format!(
+        "Webhook {} responded {}",
+        self.webhook.id,
+        response.status()
+      )),
+    }
+  }
+}
+
+// Delivery queue of a webhook
+struct Queue {
+  webhook: Webhook,
+  outbox: Arc<Outbox>,
+  task: JoinHandle<()>,
+}
+
+// Webhook registry
+// Webhooks are stored in data/webhooks
+pub struct Webhooks {
+  data_dir: PathBuf,
+  pack: Mutex<VecPack<Webhook>>,
+  ids: Mutex<Pack<WebhookIds>>,
+  queues: Mutex<HashMap<u32, Queue>>,
+  client: Client,
+}
+
+impl Webhooks {
+  // Load webhooks and start their deliveries
+  // Read only instances do not deliver, see instance_lock
+  // Must be called from the tokio runtime
+  pub fn load(data_dir: &Path, deliver: bool) -> ServiceResult<Self> {
+    let pack: VecPack<Webhook> = VecPack::load_or_init(data_dir.join("webhooks"))?;
+    let registered = pack
+      .iter()
+      .map(|w| w.unpack().clone())
+      .collect::<Vec<Webhook>>();
+    // Webhooks registered before the IDs were stored
+    // continue from the last registered one
+    let mut ids: Pack<WebhookIds> = Pack::load_or_init(data_dir.join("webhook_ids"), "ids")?;
+    let last_id = registered.iter().map(|w| w.id).max().unwrap_or(0);
+    if ids.unpack().last_id < last_id {
+      ids.update(|ids| ids.last_id = last_id)?;
+    }
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+      .with_webpki_roots()
+      .https_or_http()
+      .enable_http1()
+      .build();
+    let mut webhooks = Self {
+      data_dir: data_dir.to_path_buf(),
+      pack: Mutex::new(pack),
+      ids: Mutex::new(ids),
+      queues: Mutex::new(HashMap::new()),
+      client: hyper::Client::builder().build(connector),
+    };
+    if deliver {
+      for webhook in registered {
+        let queue = webhooks.start(webhook)?;
+        webhooks.queues.get_mut().insert(queue.webhook.id, queue);
+      }
+    }
+    Ok(webhooks)
+  }
+  // Open webhook outbox and spawn its delivery task
+  fn start(&self, webhook: Webhook) -> ServiceResult<Queue> {
+    let outbox = Arc::new(Outbox::load_from_path(self.outbox_path(webhook.id))?);
+    let sink = Arc::new(WebhookSink {
+      webhook: webhook.clone(),
+      client: self.client.clone(),
+    });
+    Ok(Queue {
+      webhook,
+      outbox: outbox.clone(),
+      task: tokio::spawn(run_delivery(outbox, vec![sink])),
+    })
+  }
+  fn outbox_path(&self, webhook_id: u32) -> PathBuf {
+    self
+      .data_dir
+      .join("webhook_outbox")
+      .join(webhook_id.to_string())
+  }
+  // Register new webhook
+  pub async fn register(
+    &self,
+    tenant: &str,
+    url: String,
+    secret: String,
+    event_kinds: Vec<CustomerEventKind>,
+    created_by: u32,
+  ) -> ServiceResult<Webhook> {
+    let mut pack = self.pack.lock().await;
+    let mut ids = self.ids.lock().await;
+    let webhook = Webhook {
+      id: ids.unpack().last_id + 1,
+      tenant: tenant.to_string(),
+      url: url.trim().to_string(),
+      secret,
+      event_kinds,
+      created_by,
+      date_created: Utc::now(),
+    };
+    webhook.validate()?;
+    if pack
+      .iter()
+      .any(|w| w.unpack().tenant == webhook.tenant && w.unpack().url == webhook.url)
+    {
+      return Err(ServiceError::already_exist(
+        "Ez a webhook URL már regisztrálva van",
+      ));
+    }
+    // The ID is used up even if storing the webhook fails
+    ids.update(|ids| ids.last_id = webhook.id)?;
+    pack.insert(webhook.clone())?;
+    let queue = self.start(webhook.clone())?;
+    self.queues.lock().await.insert(webhook.id, queue);
+    Ok(webhook)
+  }
+  // Webhooks of a tenant
+  pub async fn list(&self, tenant: &str) -> Vec<Webhook> {
+    self
+      .pack
+      .lock()
+      .await
+      .iter()
+      .map(|w| w.unpack())
+      .filter(|w| w.tenant == tenant)
+      .cloned()
+      .collect()
+  }
+  // Delete webhook
+  // Its pending events are dropped
+  pub async fn delete(&self, tenant: &str, webhook_id: u32) -> ServiceResult<Webhook> {
+    let mut pack = self.pack.lock().await;
+    match pack.find_id(&webhook_id) {
+      Ok(w) if w.unpack().tenant == tenant => (),
+      _ => return Err(ServiceError::not_found("A webhook nem található")),
+    }
+    let webhook = pack.remove_pack(&webhook_id)?;
+    if let Some(queue) = self.queues.lock().await.remove(&webhook_id) {
+      queue.task.abort();
+    }
+    let _ = std::fs::remove_dir_all(self.outbox_path(webhook_id));
+    Ok(webhook)
+  }
+}
+
+// Queue customer events for the subscribed webhooks
+// Retries do not queue the event again for the webhooks it is
+// already queued for
+#[tonic::async_trait]
+impl EventSink for Webhooks {
+  async fn deliver(&self, id: u64, event: &CustomerEvent) -> Result<(), String> {
+    let mut res = Ok(());
+    for queue in self.queues.lock().await.values() {
+      if queue.webhook.matches(event) {
+        if let Err(error) = queue
+          .outbox
+          .push_once(id, &event.tenant, event.kind, &event.customer)
+        {
+          res = Err(format!("Webhook {}: {}", queue.webhook.id, error));
+        }
+      }
+    }
+    res
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::customer::Customer;
+  use crate::taxnumber::TaxNumber;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+
+  // Accept one HTTP request, respond 200 and return the request
+  async fn receive(listener: &TcpListener) -> String {
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+      let n = socket.read(&mut buf).await.unwrap();
+      request.extend_from_slice(&buf[..n]);
+      let text = String::from_utf8_lossy(&request).to_string();
+      if let Some((head, body)) = text.split_once("\r\n\r\n") {
+        let len = head
+          .lines()
+          .find_map(|l| l.strip_prefix("content-length: "))
+          .map_or(0, |l| l.parse().unwrap());
+        if body.len() >= len {
+          break;
+        }
+      }
+    }
+    socket
+      .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+      .await
+      .unwrap();
+    String::from_utf8(request).unwrap()
+  }
+
+  #[test]
+  fn test_validate() {
+    let webhook = |url: &str, secret: &str| Webhook {
+      url: url.to_string(),
+      secret: secret.to_string(),
+      ..Webhook::default()
+    };
+    let secret = "0123456789abcdef";
+    assert!(webhook("https://shop.example.com/hooks", secret)
+      .validate()
+      .is_ok());
+    assert!(webhook("http://10.0.0.2:8080/hooks", secret)
+      .validate()
+      .is_ok());
+    assert!(webhook("ftp://shop.example.com", secret)
+      .validate()
+      .is_err());
+    assert!(webhook("/hooks", secret).validate().is_err());
+    assert!(webhook("https://shop.example.com", "short")
+      .validate()
+      .is_err());
+  }
+
+  #[test]
+  fn test_sign() {
+    let signature = sign("secret", 1, "{}");
+    assert_eq!(signature.len(), 64);
+    assert!(signature.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(sign("secret", 1, "{}"), sign("secret", 1, "{}"));
+    assert_ne!(sign("secret", 1, "{}"), sign("secret", 2, "{}"));
+    assert_ne!(sign("secret", 1, "{}"), sign("other", 1, "{}"));
+  }
+
+  #[tokio::test]
+  async fn test_delivery() {
+    let dir = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+    let webhooks = Webhooks::load(dir.path(), true).unwrap();
+    let secret = "0123456789abcdef".to_string();
+    let webhook = webhooks
+      .register(
+        "shop_a",
+        url.clone(),
+        secret.clone(),
+        vec![CustomerEventKind::Updated],
+        1,
+      )
+      .await
+      .unwrap();
+    assert!(webhooks
+      .register("shop_a", url.clone(), secret.clone(), vec![], 1)
+      .await
+      .is_err());
+    assert_eq!(webhooks.list("shop_a").await.len(), 1);
+    assert!(webhooks.list("").await.is_empty());
+
+    let event = |tenant: &str, kind, id| CustomerEvent {
+      tenant: tenant.to_string(),
+      kind,
+      customer: Customer {
+        id,
+        tax_number: TaxNumber::new("23127182-2-15").ok(),
+        ..Customer::default()
+      },
+    };
+    // Only the subscribed events are delivered
+    webhooks
+      .deliver(1, &event("", CustomerEventKind::Updated, 1))
+      .await
+      .unwrap();
+    webhooks
+      .deliver(2, &event("shop_a", CustomerEventKind::Created, 2))
+      .await
+      .unwrap();
+    // Retries of the event are queued once
+    for _ in 0..2 {
+      webhooks
+        .deliver(3, &event("shop_a", CustomerEventKind::Updated, 3))
+        .await
+        .unwrap();
+    }
+    let request = receive(&listener).await;
+    assert!(request.starts_with("POST /hooks HTTP/1.1"));
+    let header = |name: &str| {
+      request
+        .lines()
+        .find_map(|l| l.strip_prefix(&format!("{}: ", name)))
+        .unwrap()
+        .to_string()
+    };
+    let (_, body) = request.split_once("\r\n\r\n").unwrap();
+    let delivered: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(delivered["tenant"], "shop_a");
+    assert_eq!(delivered["kind"], "Updated");
+    assert_eq!(delivered["customer"]["id"], 3);
+    // Customers are shaped like for callers without caller token
+    assert_eq!(delivered["customer"]["tax_number"], "********-*-15");
+    assert_eq!(header(EVENT_HEADER), "updated");
+    let timestamp = header(TIMESTAMP_HEADER).parse().unwrap();
+    assert_eq!(
+      header(SIGNATURE_HEADER),
+      format!("sha256={}", sign(&secret, timestamp, body))
+    );
+
+    webhooks.delete("shop_a", webhook.id).await.unwrap();
+    assert!(webhooks.delete("shop_a", webhook.id).await.is_err());
+    assert!(webhooks.list("shop_a").await.is_empty());
+    // IDs of deleted webhooks are not reused, even after restart
+    drop(webhooks);
+    let webhooks = Webhooks::load(dir.path(), false).unwrap();
+    let next = webhooks
+      .register("shop_a", url, secret, vec![], 1)
+      .await
+      .unwrap();
+    assert_eq!(next.id, webhook.id + 1);
+  }
+}
@@ -0,0 +1,131 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Hungarian postal code database
+//
+// Loaded from a CSV file with zip,settlement lines
+// (comma or semicolon separated). One zip code can
+// belong to multiple settlements.
+#[derive(Default)]
+pub struct ZipDb {
+  zips: HashMap<String, Vec<String>>,
+}
+
+impl ZipDb {
+  // Load zip db from a CSV file
+  // If the file does not exist, returns an empty db
+  pub fn load_or_empty(path: &Path) -> ServiceResult<Self> {
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+    let content = std::fs::read_to_string(path).map_err(|e| {
+      ServiceError::internal_error(&format!("Error while reading zip db file: {}", e))
+    })?;
+    Ok(Self::from_csv(&content))
+  }
+  // Parse CSV content
+  // Lines without a 4 digit zip code (e.g. header) are skipped
+  pub fn from_csv(content: &str) -> Self {
+    let mut zips: HashMap<String, Vec<String>> = HashMap::new();
+    content
+      .lines()
+      .filter_map(|line| {
+        let mut parts = line.splitn(2, [',', ';']);
+        match (parts.next(), parts.next()) {
+          (Some(zip), Some(settlement)) => Some((zip.trim(), settlement.trim())),
+          _ => None,
+        }
+      })
+      .filter(|(zip, settlement)| is_valid_zip(zip) && !settlement.is_empty())
+      .for_each(|(zip, settlement)| {
+        let settlements = zips.entry(zip.to_string()).or_default();
+        if !settlements.iter().any(|s| s == settlement) {
+          settlements.push(settlement.to_string());
+        }
+      });
+    Self { zips }
+  }
+  // Lookup settlement names by zip code
+  pub fn lookup(&self, zip: &str) -> Option<&Vec<String>> {
+    self.zips.get(zip.trim())
+  }
+  // Check zip code and location consistency
+  // Skipped when the db is empty, or zip or location is not provided
+  pub fn validate(&self, zip: &str, location: &str) -> ServiceResult<()> {
+    if self.zips.is_empty() || zip.trim().is_empty() || location.trim().is_empty() {
+      return Ok(());
+    }
+    match self.lookup(zip) {
+      Some(settlements) => {
+        let location = location.trim().to_lowercase();
+        if settlements.iter().any(|s| s.to_lowercase() == location) {
+          Ok(())
+        } else {
+          Err(ServiceError::bad_request(&format!(
+            "Az irányítószám ({}) nem egyezik a településsel. Lehetséges település(ek): {}",
+            zip.trim(),
+            settlements.join(", ")
+          )))
+        }
+      }
+      None => Err(ServiceError::bad_request(&format!(
+        "Ismeretlen irányítószám: {}",
+        zip.trim()
+      ))),
+    }
+  }
+}
+
+fn is_valid_zip(zip: &str) -> bool {
+  zip.len() == 4 && zip.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CSV: &str =
+    "zip;settlement\n1011;Budapest\n6000;Kecskemét\n2336;Dunavarsány\n2336;Délegyháza\n";
+
+  #[test]
+  fn test_from_csv() {
+    let db = ZipDb::from_csv(CSV);
+    assert_eq!(db.lookup("1011").unwrap(), &vec!["Budapest".to_string()]);
+    assert_eq!(db.lookup(" 6000 ").unwrap(), &vec!["Kecskemét".to_string()]);
+    assert_eq!(db.lookup("2336").unwrap().len(), 2);
+    assert!(db.lookup("zip").is_none());
+    assert!(db.lookup("9999").is_none());
+  }
+
+  #[test]
+  fn test_validate() {
+    let db = ZipDb::from_csv(CSV);
+    assert!(db.validate("6000", "Kecskemét").is_ok());
+    assert!(db.validate("6000", " kecskemét ").is_ok());
+    assert!(db.validate("2336", "Délegyháza").is_ok());
+    assert!(db.validate("6000", "Budapest").is_err());
+    assert!(db.validate("9999", "Budapest").is_err());
+    assert!(db.validate("", "Budapest").is_ok());
+    assert!(db.validate("6000", "").is_ok());
+    // Empty db skips validation
+    assert!(ZipDb::default().validate("9999", "Budapest").is_ok());
+  }
+}